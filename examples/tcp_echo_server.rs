@@ -2,7 +2,7 @@ extern crate rotor;
 
 use std::io::{Write, stderr};
 
-use rotor::{EventSet, PollOpt, Loop, Config, Void};
+use rotor::{EventSet, PollOpt, Evented, Loop, Config, Void};
 use rotor::mio::deprecated::{TryRead, TryWrite};
 use rotor::mio::tcp::{TcpListener, TcpStream};
 use rotor::{Machine, Response, EarlyScope, Scope};
@@ -45,11 +45,16 @@ impl Machine for Echo {
     type Context = Context;
     type Seed = TcpStream;
 
-    fn create(conn: TcpStream, scope: &mut Scope<Context>)
-        -> Response<Self, Void>
+    fn register_seed(conn: &TcpStream)
+        -> Option<(&Evented, EventSet, PollOpt)>
     {
-        scope.register(&conn, EventSet::readable(), PollOpt::level())
-            .unwrap();
+        Some((conn, EventSet::readable(), PollOpt::level()))
+    }
+
+    fn create(conn: TcpStream, _scope: &mut Scope<Context>)
+        -> Response<Self, Self::Seed>
+    {
+        // The socket is already registered, see `register_seed`
         Response::ok(Echo::Connection(conn))
     }
 
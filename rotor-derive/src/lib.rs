@@ -0,0 +1,374 @@
+//! The derive macro composing rotor state machines
+//!
+//! Enable the `derive` feature of `rotor` to use it, the macro is
+//! re-exported as `rotor::Machine`:
+//!
+//! ```ignore
+//! #[derive(Machine)]
+//! #[rotor(seed="Seed")]
+//! pub enum Fsm {
+//!     Http(HttpMachine),
+//!     Dns(DnsMachine),
+//! }
+//! ```
+//!
+//! This implements `rotor::Machine` for `Fsm` and creates the `Seed` enum
+//! with the same variants wrapping `<HttpMachine as Machine>::Seed` and so
+//! on. The seed type is named `FsmSeed` if the attribute is omitted. The
+//! context is the context of the first variant, the others must have the
+//! same one. Generic enums are not supported.
+extern crate proc_macro;
+
+use std::fmt::Write;
+
+use proc_macro::{TokenStream, TokenTree, Delimiter};
+
+
+struct Variant {
+    name: String,
+    ty: String,
+}
+
+struct Input {
+    vis: String,
+    name: String,
+    seed: String,
+    variants: Vec<Variant>,
+}
+
+#[proc_macro_derive(Machine, attributes(rotor))]
+pub fn derive_machine(input: TokenStream) -> TokenStream {
+    match parse(input) {
+        Ok(input) => generate(&input).parse()
+            .expect("generated code is valid"),
+        Err(message) => format!("compile_error!({:?});", message).parse()
+            .expect("error is valid"),
+    }
+}
+
+/// Returns the seed name from the `#[rotor(seed="Name")]` attribute
+fn seed_attr(attr: TokenStream) -> Result<Option<String>, String> {
+    let mut tokens = attr.into_iter();
+    match tokens.next() {
+        Some(TokenTree::Ident(ref x)) if x.to_string() == "rotor" => {}
+        _ => return Ok(None),
+    }
+    let args = match tokens.next() {
+        Some(TokenTree::Group(ref g))
+            if g.delimiter() == Delimiter::Parenthesis
+            => g.stream().into_iter().map(|x| x.to_string())
+                .collect::<Vec<_>>(),
+        _ => return Err(String::from("expected #[rotor(seed=\"Name\")]")),
+    };
+    match args.len() {
+        3 if args[0] == "seed" && args[1] == "=" && args[2].len() > 2 &&
+             args[2].starts_with('"') && args[2].ends_with('"')
+        => Ok(Some(args[2][1..args[2].len()-1].to_string())),
+        _ => Err(String::from("expected #[rotor(seed=\"Name\")]")),
+    }
+}
+
+/// Returns the type of the single unnamed field
+fn field_type(fields: TokenStream) -> Option<String> {
+    let mut depth = 0;
+    let mut ty = Vec::new();
+    for token in fields {
+        if let TokenTree::Punct(ref p) = token {
+            match p.as_char() {
+                '<' => depth += 1,
+                '>' => depth -= 1,
+                ',' if depth == 0 => return None,
+                _ => {}
+            }
+        }
+        ty.push(token);
+    }
+    if ty.is_empty() {
+        None
+    } else {
+        Some(ty.into_iter().collect::<TokenStream>().to_string())
+    }
+}
+
+fn parse_variants(body: TokenStream) -> Result<Vec<Variant>, String> {
+    let mut variants = Vec::new();
+    let mut tokens = body.into_iter();
+    while let Some(token) = tokens.next() {
+        let name = match token {
+            // Attributes of the variant, e.g. doc comments
+            TokenTree::Punct(ref p) if p.as_char() == '#' => {
+                tokens.next();
+                continue;
+            }
+            TokenTree::Ident(ref x) => x.to_string(),
+            _ => return Err(format!("unexpected {} in the enum", token)),
+        };
+        let ty = match tokens.next() {
+            Some(TokenTree::Group(ref g))
+                if g.delimiter() == Delimiter::Parenthesis
+                => field_type(g.stream()),
+            _ => None,
+        };
+        let ty = match ty {
+            Some(ty) => ty,
+            None => {
+                return Err(format!("variant {} must wrap a single state \
+                    machine", name));
+            }
+        };
+        match tokens.next() {
+            Some(TokenTree::Punct(ref p)) if p.as_char() == ',' => {}
+            None => {}
+            Some(token) => {
+                return Err(format!("unexpected {} after variant {}",
+                    token, name));
+            }
+        }
+        variants.push(Variant { name: name, ty: ty });
+    }
+    Ok(variants)
+}
+
+fn parse(input: TokenStream) -> Result<Input, String> {
+    let mut tokens = input.into_iter();
+    let mut seed = None;
+    let mut vis = String::new();
+    loop {
+        match tokens.next() {
+            Some(TokenTree::Punct(ref p)) if p.as_char() == '#' => {
+                match tokens.next() {
+                    Some(TokenTree::Group(ref g)) => {
+                        if let Some(name) = try!(seed_attr(g.stream())) {
+                            seed = Some(name);
+                        }
+                    }
+                    _ => return Err(String::from("malformed attribute")),
+                }
+            }
+            Some(TokenTree::Ident(ref x)) if x.to_string() == "enum" => break,
+            Some(TokenTree::Ident(ref x))
+                if x.to_string() == "struct" || x.to_string() == "union"
+            => {
+                return Err(String::from(
+                    "#[derive(Machine)] is only supported for enums"));
+            }
+            // Visibility, e.g. `pub` or `pub(crate)`
+            Some(token) => {
+                vis.push_str(&token.to_string());
+                vis.push(' ');
+            }
+            None => return Err(String::from("expected enum")),
+        }
+    }
+    let name = match tokens.next() {
+        Some(TokenTree::Ident(ref x)) => x.to_string(),
+        _ => return Err(String::from("expected the name of the enum")),
+    };
+    let variants = match tokens.next() {
+        Some(TokenTree::Group(ref g)) if g.delimiter() == Delimiter::Brace
+        => try!(parse_variants(g.stream())),
+        _ => {
+            return Err(String::from(
+                "generic enums are not supported by #[derive(Machine)]"));
+        }
+    };
+    if variants.is_empty() {
+        return Err(String::from("the enum must have at least one variant"));
+    }
+    Ok(Input {
+        vis: vis,
+        seed: seed.unwrap_or_else(|| format!("{}Seed", name)),
+        name: name,
+        variants: variants,
+    })
+}
+
+/// A method which calls the same method of the wrapped state machine
+fn delegate(out: &mut String, input: &Input, method: &str, args: &str,
+    call: &str)
+{
+    writeln!(out, "fn {}(self, {}scope: &mut ::rotor::Scope<Self::Context>)
+        -> ::rotor::Response<Self, Self::Seed>
+    {{
+        match self {{", method, args).unwrap();
+    for v in &input.variants {
+        writeln!(out, "{name}::{var}(m) => m.{method}({call}scope)
+            .map_many({name}::{var}, {seed}::{var}),",
+            name=input.name, seed=input.seed, var=v.name,
+            method=method, call=call).unwrap();
+    }
+    out.push_str("}\n}\n");
+}
+
+fn generate(input: &Input) -> String {
+    let name = &input.name;
+    let seed = &input.seed;
+    let mut out = String::new();
+    writeln!(out, "{}enum {} {{", input.vis, seed).unwrap();
+    for v in &input.variants {
+        writeln!(out, "{}(<{} as ::rotor::Machine>::Seed),",
+            v.name, v.ty).unwrap();
+    }
+    out.push_str("}\n");
+
+    writeln!(out, "impl ::rotor::Machine for {} {{
+        type Context = <{} as ::rotor::Machine>::Context;
+        type Seed = {};", name, input.variants[0].ty, seed).unwrap();
+
+    out.push_str("fn create(seed: Self::Seed,
+        scope: &mut ::rotor::Scope<Self::Context>)
+        -> ::rotor::Response<Self, Self::Seed>
+    {
+        match seed {");
+    for v in &input.variants {
+        writeln!(out, "{seed}::{var}(x) => <{ty} as ::rotor::Machine>
+            ::create(x, scope).map_many({name}::{var}, {seed}::{var}),",
+            name=name, seed=seed, var=v.name, ty=v.ty).unwrap();
+    }
+    out.push_str("}\n}\n");
+
+    out.push_str("fn register_seed(seed: &Self::Seed)
+        -> Option<(&::rotor::Evented, ::rotor::EventSet, ::rotor::PollOpt)>
+    {
+        match *seed {");
+    for v in &input.variants {
+        writeln!(out, "{seed}::{var}(ref x) => <{ty} as ::rotor::Machine>
+            ::register_seed(x),",
+            seed=seed, var=v.name, ty=v.ty).unwrap();
+    }
+    out.push_str("}\n}\n");
+
+    delegate(&mut out, input, "ready", "events: ::rotor::EventSet, ",
+        "events, ");
+    delegate(&mut out, input, "spawned", "", "");
+
+    // Every child spawns the seeds of its own variant only
+    out.push_str("fn spawn_error(self,
+        scope: &mut ::rotor::Scope<Self::Context>,
+        error: ::rotor::SpawnError<Self::Seed>)
+        -> ::rotor::Response<Self, Self::Seed>
+    {
+        match self {");
+    for v in &input.variants {
+        writeln!(out, "{name}::{var}(m) => {{
+            let error = error.map(|seed| {{
+                #[allow(unreachable_patterns)]
+                match seed {{
+                    {seed}::{var}(x) => x,
+                    _ => unreachable!(),
+                }}
+            }});
+            m.spawn_error(scope, error)
+                .map_many({name}::{var}, {seed}::{var})
+        }}",
+            name=name, seed=seed, var=v.name).unwrap();
+    }
+    out.push_str("}\n}\n");
+
+    out.push_str("fn destroyed(self,
+        scope: &mut ::rotor::Scope<Self::Context>)
+    {
+        match self {");
+    for v in &input.variants {
+        writeln!(out, "{}::{}(m) => m.destroyed(scope),",
+            name, v.name).unwrap();
+    }
+    out.push_str("}\n}\n");
+
+    out.push_str("fn freeze(self, scope: &mut ::rotor::Scope<Self::Context>)
+        -> Result<Self::Seed, Self>
+    {
+        match self {");
+    for v in &input.variants {
+        writeln!(out, "{name}::{var}(m) => m.freeze(scope)
+            .map({seed}::{var}).map_err({name}::{var}),",
+            name=name, seed=seed, var=v.name).unwrap();
+    }
+    out.push_str("}\n}\n");
+
+    out.push_str("fn thaw(seed: Self::Seed,
+        scope: &mut ::rotor::Scope<Self::Context>)
+        -> ::rotor::Response<Self, Self::Seed>
+    {
+        match seed {");
+    for v in &input.variants {
+        writeln!(out, "{seed}::{var}(x) => <{ty} as ::rotor::Machine>
+            ::thaw(x, scope).map_many({name}::{var}, {seed}::{var}),",
+            name=name, seed=seed, var=v.name, ty=v.ty).unwrap();
+    }
+    out.push_str("}\n}\n");
+
+    delegate(&mut out, input, "timeout", "", "");
+    delegate(&mut out, input, "wakeup", "", "");
+    delegate(&mut out, input, "exit_signal", "peer: ::rotor::mio::Token, ",
+        "peer, ");
+    delegate(&mut out, input, "tick", "", "");
+    delegate(&mut out, input, "config_changed", "", "");
+    out.push_str("fn interest(&self) -> Option<::rotor::EventSet> {
+        match *self {");
+    for v in &input.variants {
+        writeln!(out, "{}::{}(ref m) => m.interest(),",
+            input.name, v.name).unwrap();
+    }
+    out.push_str("}\n}\n");
+    out.push_str("fn error(self, err: Box<::std::error::Error>,
+        scope: &mut ::rotor::Scope<Self::Context>)
+        -> Box<::std::error::Error>
+    {
+        match self {");
+    for v in &input.variants {
+        writeln!(out, "{}::{}(m) => m.error(err, scope),",
+            input.name, v.name).unwrap();
+    }
+    out.push_str("}\n}\n");
+    out.push_str("}\n");
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Input, Variant, generate};
+
+    fn input() -> Input {
+        Input {
+            vis: String::from("pub "),
+            name: String::from("Fsm"),
+            seed: String::from("Seed"),
+            variants: vec![
+                Variant { name: String::from("A"), ty: String::from("A") },
+                Variant { name: String::from("B"), ty: String::from("B") },
+            ],
+        }
+    }
+
+    /// Text of the generated method up to the next one
+    fn method<'a>(code: &'a str, name: &str) -> &'a str {
+        let start = code.find(&format!("fn {}(", name))
+            .expect("method is generated");
+        let end = code[start+3..].find("fn ").map(|x| start + 3 + x)
+            .unwrap_or(code.len());
+        &code[start..end]
+    }
+
+    #[test]
+    fn spawn_many() {
+        // `Response::map` panics on the seeds of `Response::spawn_many`
+        let code = generate(&input());
+        for name in &["create", "ready", "spawned", "spawn_error", "thaw",
+                      "timeout", "wakeup", "exit_signal", "tick",
+                      "config_changed"]
+        {
+            let body = method(&code, name);
+            assert_eq!(body.matches(".map_many(").count(), 2, "{}", name);
+            assert!(!body.contains(".map(Fsm::"), "{}", name);
+        }
+    }
+
+    #[test]
+    fn config_changed() {
+        let code = generate(&input());
+        let body = method(&code, "config_changed");
+        assert!(body.contains("Fsm::A(m) => m.config_changed(scope)"));
+        assert!(body.contains("Fsm::B(m) => m.config_changed(scope)"));
+    }
+}
@@ -0,0 +1,342 @@
+use std::io;
+use std::time::Duration;
+use std::error::Error;
+
+use mio::Token;
+use void::Void;
+
+use {Machine, Scope, GenericScope, Response, EventSet, PollOpt};
+use {SpawnError, Time, LinkedExit, SocketOptions, Backoff, Listener};
+use error::is_fd_limit;
+use diagnostics::Diagnostic;
+
+
+/// Delay before accepting again after an accept error
+const ERROR_DELAY_MS: u64 = 100;
+/// Longest delay of the default backoff when out of file descriptors
+const FD_LIMIT_MAX_DELAY_MS: u64 = 5000;
+
+struct Accepting<L: Listener, S> {
+    sock: L,
+    make_seed: fn(L::Stream, L::Address) -> S,
+    options: SocketOptions,
+    /// Socket is deregistered because of slab space or accept error
+    paused: bool,
+    /// Sleeping after an accept error until this time
+    retry_at: Option<Time>,
+    /// Delays while the process is out of file descriptors
+    backoff: Backoff,
+}
+
+enum Inner<L: Listener, M: Machine> {
+    Listener(Accepting<L, M::Seed>),
+    Child(M),
+}
+
+/// A state machine which accepts connections and spawns a child for each
+///
+/// Connections are accepted until `WouldBlock` on every event and turned
+/// into the seeds of the child state machine `M` with the `make_seed`
+/// function. The aborted connections are skipped. On other errors the
+/// listener deregisters the socket and sleeps for a while instead of
+/// spinning on the readiness of it, and when the state machine slab is
+/// full it waits until some child is removed.
+///
+/// When the process or the system is out of file descriptors (`EMFILE`
+/// or `ENFILE`) the sleep grows with every failed attempt, from 100ms up
+/// to 5 seconds by default (see `with_backoff`), and every pause is
+/// reported as `Diagnostic::FdLimit`. The backoff is reset when a
+/// connection is accepted.
+///
+/// The listener closes its socket when it's retiring (see
+/// `Scope::is_retiring`) or the loop is shutting down. Use
+/// `LimitedListener` if the number of concurrent connections must be
+/// limited, and `with_options` to set `TCP_NODELAY` and the like on the
+/// accepted connections.
+pub struct Accept<L: Listener, M: Machine>(Inner<L, M>);
+
+impl<L: Listener, M: Machine> Accept<L, M> {
+    pub fn new<S: GenericScope>(sock: L,
+        make_seed: fn(L::Stream, L::Address) -> M::Seed, scope: &mut S)
+        -> Response<Accept<L, M>, Void>
+    {
+        Accept::with_options(sock, SocketOptions::new(), make_seed, scope)
+    }
+    /// Create the listener setting the `options` on every connection
+    ///
+    /// The connections which fail to set the options are closed.
+    pub fn with_options<S: GenericScope>(sock: L, options: SocketOptions,
+        make_seed: fn(L::Stream, L::Address) -> M::Seed, scope: &mut S)
+        -> Response<Accept<L, M>, Void>
+    {
+        let backoff = Backoff::new(Duration::from_millis(ERROR_DELAY_MS),
+            Duration::from_millis(FD_LIMIT_MAX_DELAY_MS));
+        Accept::with_backoff(sock, options, backoff, make_seed, scope)
+    }
+    /// Create the listener with the backoff used when the process is out
+    /// of file descriptors
+    ///
+    /// Add jitter to the backoff when many processes share the limit, so
+    /// they don't retry at once.
+    pub fn with_backoff<S: GenericScope>(sock: L, options: SocketOptions,
+        backoff: Backoff,
+        make_seed: fn(L::Stream, L::Address) -> M::Seed, scope: &mut S)
+        -> Response<Accept<L, M>, Void>
+    {
+        if let Err(e) = scope.register(&sock, EventSet::readable(),
+                                       PollOpt::level())
+        {
+            return Response::error(Box::new(e));
+        }
+        Response::ok(Accept(Inner::Listener(Accepting {
+            sock: sock,
+            make_seed: make_seed,
+            options: options,
+            paused: false,
+            retry_at: None,
+            backoff: backoff,
+        })))
+    }
+}
+
+impl<L: Listener, S> Accepting<L, S> {
+    fn pause<C>(&mut self, scope: &mut Scope<C>) -> io::Result<()> {
+        if !self.paused {
+            try!(scope.deregister(&self.sock));
+            self.paused = true;
+        }
+        Ok(())
+    }
+    fn resume<C>(&mut self, scope: &mut Scope<C>) -> io::Result<()> {
+        if self.paused {
+            try!(scope.register(&self.sock, EventSet::readable(),
+                                PollOpt::level()));
+            self.paused = false;
+        }
+        Ok(())
+    }
+}
+
+fn accept<L: Listener, M: Machine>(mut me: Accepting<L, M::Seed>,
+    scope: &mut Scope<M::Context>)
+    -> Response<Accept<L, M>, M::Seed>
+{
+    if scope.queued_spawns() > 0 {
+        // Connections wait for the slab space (see `SlabFull::Queue`),
+        // `spawned` resumes accepting when the last of them is created
+        return match me.pause(scope) {
+            Ok(()) => Response::ok(Accept(Inner::Listener(me))),
+            Err(e) => Response::error(Box::new(e)),
+        };
+    }
+    let mut seeds = Vec::new();
+    loop {
+        match me.sock.accept() {
+            Ok(Some((sock, addr))) => {
+                me.backoff.reset();
+                if let Err(e) = me.sock.configure(&sock, &me.options) {
+                    debug!("Error setting socket options: {}", e);
+                    continue;
+                }
+                seeds.push((me.make_seed)(sock, addr));
+            }
+            Ok(None) => break,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted ||
+                          e.kind() == io::ErrorKind::ConnectionAborted ||
+                          e.kind() == io::ErrorKind::ConnectionReset
+            => continue,
+            Err(e) => {
+                let delay = if is_fd_limit(&e) {
+                    let delay = me.backoff.next_delay();
+                    let token = scope.notifier().token();
+                    scope.diagnose(Diagnostic::FdLimit {
+                        token: token,
+                        error: &e,
+                        delay: delay,
+                    });
+                    delay
+                } else {
+                    debug!("Error accepting connection: {}", e);
+                    Duration::from_millis(ERROR_DELAY_MS)
+                };
+                if let Err(e) = me.pause(scope) {
+                    return Response::error(Box::new(e));
+                }
+                let retry = scope.now() + delay;
+                me.retry_at = Some(retry);
+                if seeds.is_empty() {
+                    return Response::ok(Accept(Inner::Listener(me)))
+                        .deadline(retry);
+                }
+                // The deadline is set in `spawned`
+                break;
+            }
+        }
+    }
+    Response::spawn_many(Accept(Inner::Listener(me)), seeds)
+}
+
+fn resume<L: Listener, M: Machine>(mut me: Accepting<L, M::Seed>,
+    scope: &mut Scope<M::Context>)
+    -> Response<Accept<L, M>, M::Seed>
+{
+    if let Some(retry) = me.retry_at {
+        if retry > scope.now() {
+            return Response::ok(Accept(Inner::Listener(me))).deadline(retry);
+        }
+        me.retry_at = None;
+    }
+    if scope.queued_spawns() > 0 {
+        return Response::ok(Accept(Inner::Listener(me)));
+    }
+    match me.resume(scope) {
+        Ok(()) => accept(me, scope),
+        Err(e) => Response::error(Box::new(e)),
+    }
+}
+
+impl<L: Listener, M: Machine> Machine for Accept<L, M> {
+    type Context = M::Context;
+    type Seed = M::Seed;
+
+    fn create(seed: Self::Seed, scope: &mut Scope<M::Context>)
+        -> Response<Self, Self::Seed>
+    {
+        M::create(seed, scope).wrap(|m| Accept(Inner::Child(m)))
+    }
+    fn ready(self, events: EventSet, scope: &mut Scope<M::Context>)
+        -> Response<Self, Self::Seed>
+    {
+        match self.0 {
+            Inner::Listener(me) => accept(me, scope),
+            Inner::Child(m) => {
+                m.ready(events, scope).wrap(|m| Accept(Inner::Child(m)))
+            }
+        }
+    }
+    fn spawned(self, scope: &mut Scope<M::Context>)
+        -> Response<Self, Self::Seed>
+    {
+        match self.0 {
+            Inner::Listener(me) => resume(me, scope),
+            Inner::Child(m) => {
+                m.spawned(scope).wrap(|m| Accept(Inner::Child(m)))
+            }
+        }
+    }
+    fn spawn_error(self, scope: &mut Scope<M::Context>,
+                   error: SpawnError<Self::Seed>)
+        -> Response<Self, Self::Seed>
+    {
+        match self.0 {
+            Inner::Listener(mut me) => match error {
+                SpawnError::NoSlabSpace(seed) => {
+                    // The seed is queued by the loop, and `spawned` is
+                    // called when it's finally created
+                    match me.pause(scope) {
+                        Ok(()) => Response::spawn(
+                            Accept(Inner::Listener(me)), seed),
+                        Err(e) => Response::error(Box::new(e)),
+                    }
+                }
+                e => {
+                    debug!("Error creating connection: {}", e);
+                    Response::ok(Accept(Inner::Listener(me)))
+                }
+            },
+            Inner::Child(m) => {
+                m.spawn_error(scope, error).wrap(|m| Accept(Inner::Child(m)))
+            }
+        }
+    }
+    fn destroyed(self, scope: &mut Scope<M::Context>) {
+        match self.0 {
+            Inner::Listener(..) => {}
+            Inner::Child(m) => m.destroyed(scope),
+        }
+    }
+    fn freeze(self, scope: &mut Scope<M::Context>)
+        -> Result<Self::Seed, Self>
+    {
+        match self.0 {
+            me @ Inner::Listener(..) => Err(Accept(me)),
+            Inner::Child(m) => {
+                m.freeze(scope).map_err(|m| Accept(Inner::Child(m)))
+            }
+        }
+    }
+    fn thaw(seed: Self::Seed, scope: &mut Scope<M::Context>)
+        -> Response<Self, Self::Seed>
+    {
+        M::thaw(seed, scope).wrap(|m| Accept(Inner::Child(m)))
+    }
+    fn timeout(self, scope: &mut Scope<M::Context>)
+        -> Response<Self, Self::Seed>
+    {
+        match self.0 {
+            Inner::Listener(me) => resume(me, scope),
+            Inner::Child(m) => {
+                m.timeout(scope).wrap(|m| Accept(Inner::Child(m)))
+            }
+        }
+    }
+    fn wakeup(self, scope: &mut Scope<M::Context>)
+        -> Response<Self, Self::Seed>
+    {
+        match self.0 {
+            // Connections in progress are served by their own machines
+            Inner::Listener(_)
+                if scope.is_retiring() || scope.is_shutting_down()
+                => Response::done(),
+            me @ Inner::Listener(..) => Response::ok(Accept(me)),
+            Inner::Child(m) => {
+                m.wakeup(scope).wrap(|m| Accept(Inner::Child(m)))
+            }
+        }
+    }
+    fn exit_signal(self, peer: Token, scope: &mut Scope<M::Context>)
+        -> Response<Self, Self::Seed>
+    {
+        match self.0 {
+            Inner::Listener(..) => Response::error(Box::new(LinkedExit(peer))),
+            Inner::Child(m) => {
+                m.exit_signal(peer, scope).wrap(|m| Accept(Inner::Child(m)))
+            }
+        }
+    }
+    fn tick(self, scope: &mut Scope<M::Context>)
+        -> Response<Self, Self::Seed>
+    {
+        match self.0 {
+            me @ Inner::Listener(..) => Response::ok(Accept(me)),
+            Inner::Child(m) => {
+                m.tick(scope).wrap(|m| Accept(Inner::Child(m)))
+            }
+        }
+    }
+    fn config_changed(self, scope: &mut Scope<M::Context>)
+        -> Response<Self, Self::Seed>
+    {
+        match self.0 {
+            me @ Inner::Listener(..) => Response::ok(Accept(me)),
+            Inner::Child(m) => {
+                m.config_changed(scope)
+                    .wrap(|m| Accept(Inner::Child(m)))
+            }
+        }
+    }
+    fn interest(&self) -> Option<EventSet> {
+        match self.0 {
+            Inner::Listener(..) => None,
+            Inner::Child(ref m) => m.interest(),
+        }
+    }
+    fn error(self, err: Box<Error>, scope: &mut Scope<M::Context>)
+        -> Box<Error>
+    {
+        match self.0 {
+            Inner::Listener(..) => err,
+            Inner::Child(m) => m.error(err, scope),
+        }
+    }
+}
@@ -0,0 +1,46 @@
+use std::io;
+
+
+/// Pins the current thread to the CPU
+#[cfg(all(feature="affinity", target_os="linux"))]
+pub fn pin_current_thread(cpu: usize) -> io::Result<()> {
+    use std::mem;
+    use libc;
+    // CPU_SET indexes the fixed size set, and panics for the CPUs beyond
+    if cpu >= libc::CPU_SETSIZE as usize {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput,
+            "CPU number exceeds CPU_SETSIZE"));
+    }
+    unsafe {
+        let mut set: libc::cpu_set_t = mem::zeroed();
+        libc::CPU_ZERO(&mut set);
+        libc::CPU_SET(cpu, &mut set);
+        let res = libc::sched_setaffinity(0,
+            mem::size_of::<libc::cpu_set_t>(), &set);
+        if res != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// Pins the current thread to the CPU
+#[cfg(not(all(feature="affinity", target_os="linux")))]
+pub fn pin_current_thread(_cpu: usize) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Other,
+        "CPU pinning requires the `affinity` feature (linux only)"))
+}
+
+#[cfg(all(test, feature="affinity", target_os="linux"))]
+mod test {
+    use std::io;
+    use libc;
+    use super::pin_current_thread;
+
+    #[test]
+    fn cpu_out_of_range() {
+        let err = pin_current_thread(libc::CPU_SETSIZE as usize)
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+}
@@ -0,0 +1,134 @@
+use std::time::Duration;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+
+use loop_time::millis;
+
+
+/// Exponential backoff for retrying failed operations
+///
+/// The first delay is `initial`, every next one is `multiplier` times
+/// longer up to the `cap`. With jitter each delay is shortened by a random
+/// fraction of up to `jitter`, so that many clients failing at the same
+/// time don't retry at the same time too. Usually used with
+/// `Scope::after_backoff`:
+///
+/// ```ignore
+/// Err(e) => {
+///     let retry_at = scope.after_backoff(&mut self.backoff);
+///     Response::ok(self).deadline(retry_at)
+/// }
+/// ```
+///
+/// And `reset` is called when the operation succeeds.
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    initial: Duration,
+    multiplier: f64,
+    cap: Duration,
+    jitter: f64,
+    attempts: u32,
+    current: Duration,
+    random: RandomState,
+    /// Number of random values taken, it's not reset
+    draws: u64,
+}
+
+impl Backoff {
+    /// Create a backoff doubling delays from `initial` up to `cap`
+    /// without jitter
+    pub fn new(initial: Duration, cap: Duration) -> Backoff {
+        Backoff {
+            initial: initial,
+            multiplier: 2.0,
+            cap: cap,
+            jitter: 0.0,
+            attempts: 0,
+            current: initial,
+            random: RandomState::new(),
+            draws: 0,
+        }
+    }
+    /// Set the factor each next delay is multiplied by
+    ///
+    /// # Panics
+    ///
+    /// When `multiplier` is less than one
+    pub fn multiplier(mut self, multiplier: f64) -> Backoff {
+        assert!(multiplier >= 1.0, "multiplier must be at least one");
+        self.multiplier = multiplier;
+        self
+    }
+    /// Set the maximum fraction of the delay which is randomly cut off
+    ///
+    /// # Panics
+    ///
+    /// When `jitter` is not within `0.0..1.0`
+    pub fn jitter(mut self, jitter: f64) -> Backoff {
+        assert!(jitter >= 0.0 && jitter <= 1.0,
+            "jitter must be within 0.0..1.0");
+        self.jitter = jitter;
+        self
+    }
+    /// Returns the delay before the next attempt and advances the backoff
+    pub fn next_delay(&mut self) -> Duration {
+        let ms = millis(self.current);
+        let cut = if self.jitter > 0.0 {
+            let mut hasher = self.random.build_hasher();
+            hasher.write_u64(self.draws);
+            self.draws = self.draws.wrapping_add(1);
+            let fraction = (hasher.finish() >> 11) as f64 /
+                (1u64 << 53) as f64;
+            (ms as f64 * self.jitter * fraction) as u64
+        } else {
+            0
+        };
+        self.attempts = self.attempts.saturating_add(1);
+        let next = (ms as f64 * self.multiplier) as u64;
+        self.current = Duration::from_millis(next).min(self.cap);
+        Duration::from_millis(ms - cut)
+    }
+    /// Start from the initial delay, e.g. after a successful attempt
+    pub fn reset(&mut self) {
+        self.attempts = 0;
+        self.current = self.initial;
+    }
+    /// Number of delays returned since the last reset
+    pub fn attempts(&self) -> u32 {
+        self.attempts
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+    use super::Backoff;
+
+    #[test]
+    fn exponential() {
+        let mut b = Backoff::new(Duration::from_millis(100),
+                                 Duration::from_secs(1));
+        assert_eq!(b.next_delay(), Duration::from_millis(100));
+        assert_eq!(b.next_delay(), Duration::from_millis(200));
+        assert_eq!(b.next_delay(), Duration::from_millis(400));
+        assert_eq!(b.next_delay(), Duration::from_millis(800));
+        assert_eq!(b.next_delay(), Duration::from_secs(1));
+        assert_eq!(b.next_delay(), Duration::from_secs(1));
+        assert_eq!(b.attempts(), 6);
+        b.reset();
+        assert_eq!(b.next_delay(), Duration::from_millis(100));
+    }
+
+    #[test]
+    fn jitter() {
+        let mut b = Backoff::new(Duration::from_millis(1000),
+                                 Duration::from_secs(10))
+            .multiplier(1.5).jitter(0.5);
+        let d = b.next_delay();
+        assert!(d >= Duration::from_millis(500));
+        assert!(d <= Duration::from_millis(1000));
+        let d = b.next_delay();
+        assert!(d >= Duration::from_millis(750));
+        assert!(d <= Duration::from_millis(1500));
+    }
+}
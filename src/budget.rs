@@ -0,0 +1,181 @@
+use std::rc::Rc;
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+use mio::Token;
+
+use notify::{Channel, create_notifier};
+
+
+/// Limits of concurrent outgoing connections of the loop
+///
+/// Connector state machines take a `ConnectSlot` for the destination before
+/// connecting (see `Scope::connect_slot`). State machines which can't get
+/// a slot are queued and woken up when some slot for their destination
+/// is freed.
+#[doc(hidden)]
+pub struct ConnectBudget {
+    total_limit: Option<usize>,
+    destination_limit: Option<usize>,
+    total: usize,
+    active: HashMap<String, usize>,
+    waiting: Vec<(Token, u64, String)>,
+}
+
+/// A permission to have an outgoing connection in progress
+///
+/// Returned from `Scope::connect_slot`. Keep it while the connection is
+/// being established (or for the whole lifetime of the connection if you
+/// want to limit the number of connections rather than connection
+/// attempts). Dropping the slot frees it and wakes up state machines
+/// waiting for the destination.
+pub struct ConnectSlot {
+    budget: Rc<RefCell<ConnectBudget>>,
+    channel: Channel,
+    destination: String,
+}
+
+impl ConnectBudget {
+    pub fn new(total_limit: Option<usize>, destination_limit: Option<usize>)
+        -> ConnectBudget
+    {
+        ConnectBudget {
+            total_limit: total_limit,
+            destination_limit: destination_limit,
+            total: 0,
+            active: HashMap::new(),
+            waiting: Vec::new(),
+        }
+    }
+    fn has_room(&self, destination: &str) -> bool {
+        if self.total_limit.map(|x| self.total >= x).unwrap_or(false) {
+            return false;
+        }
+        let active = self.active.get(destination).map(|x| *x).unwrap_or(0);
+        !self.destination_limit.map(|x| active >= x).unwrap_or(false)
+    }
+    /// Takes a slot, or queues the token if there is no room
+    pub fn acquire(&mut self, token: Token, generation: u64,
+        destination: &str)
+        -> bool
+    {
+        if self.has_room(destination) {
+            self.total += 1;
+            *self.active.entry(destination.to_string()).or_insert(0) += 1;
+            self.waiting.retain(|&(tok, _, _)| tok != token);
+            true
+        } else {
+            if let Some(item) = self.waiting.iter_mut()
+                .find(|&&mut (tok, _, _)| tok == token)
+            {
+                item.1 = generation;
+                item.2 = destination.to_string();
+                return false;
+            }
+            self.waiting.push((token, generation, destination.to_string()));
+            false
+        }
+    }
+    /// Frees a slot and returns the waiters that may proceed now
+    ///
+    /// All the eligible waiters are returned (and removed from the queue),
+    /// because some of them may be already dead.
+    pub fn release(&mut self, destination: &str) -> Vec<(Token, u64)> {
+        self.total -= 1;
+        let empty = match self.active.get_mut(destination) {
+            Some(cnt) => {
+                *cnt -= 1;
+                *cnt == 0
+            }
+            None => false,
+        };
+        if empty {
+            self.active.remove(destination);
+        }
+        let mut ready = Vec::new();
+        let mut i = 0;
+        while i < self.waiting.len() {
+            if self.has_room(&self.waiting[i].2) {
+                let (token, generation, _) = self.waiting.remove(i);
+                ready.push((token, generation));
+            } else {
+                i += 1;
+            }
+        }
+        ready
+    }
+    /// Number of slots taken for the destination
+    pub fn active(&self, destination: &str) -> usize {
+        self.active.get(destination).map(|x| *x).unwrap_or(0)
+    }
+    /// Number of slots taken in total
+    pub fn total(&self) -> usize {
+        self.total
+    }
+}
+
+pub fn create_slot(budget: &Rc<RefCell<ConnectBudget>>,
+    channel: &Channel, token: Token, generation: u64,
+    destination: &str)
+    -> Option<ConnectSlot>
+{
+    if budget.borrow_mut().acquire(token, generation, destination) {
+        Some(ConnectSlot {
+            budget: budget.clone(),
+            channel: channel.clone(),
+            destination: destination.to_string(),
+        })
+    } else {
+        None
+    }
+}
+
+impl ConnectSlot {
+    /// The destination the slot is taken for
+    pub fn destination(&self) -> &str {
+        &self.destination
+    }
+}
+
+impl Drop for ConnectSlot {
+    fn drop(&mut self) {
+        let ready = self.budget.borrow_mut().release(&self.destination);
+        for (token, generation) in ready {
+            // Wakeup errors mean the loop is shutting down
+            create_notifier(token, generation, None, &self.channel)
+                .wakeup().ok();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use mio::Token;
+    use super::ConnectBudget;
+
+    #[test]
+    fn per_destination() {
+        let mut b = ConnectBudget::new(None, Some(2));
+        assert!(b.acquire(Token(1), 0, "a"));
+        assert!(b.acquire(Token(2), 0, "a"));
+        assert!(!b.acquire(Token(3), 0, "a"));
+        assert!(b.acquire(Token(4), 0, "b"));
+        assert_eq!(b.release("b"), vec![]);
+        assert_eq!(b.release("a"), vec![(Token(3), 0)]);
+        assert!(b.acquire(Token(3), 0, "a"));
+        assert_eq!(b.active("a"), 2);
+        assert_eq!(b.total(), 2);
+    }
+
+    #[test]
+    fn total() {
+        let mut b = ConnectBudget::new(Some(1), None);
+        assert!(b.acquire(Token(1), 0, "a"));
+        assert!(!b.acquire(Token(2), 0, "b"));
+        assert!(!b.acquire(Token(3), 0, "c"));
+        // waiting again doesn't duplicate the entry
+        assert!(!b.acquire(Token(2), 0, "b"));
+        assert_eq!(b.release("a"), vec![(Token(2), 0), (Token(3), 0)]);
+        assert_eq!(b.total(), 0);
+    }
+}
@@ -0,0 +1,113 @@
+/// A pool of preallocated buffers owned by the loop
+///
+/// The buffers are allocated and written to in the thread of the loop right
+/// after it's pinned (see `Config::pin_to_cpu`), so with the first-touch
+/// memory policy of linux their pages come from the NUMA node of that CPU.
+/// Small allocations may reuse pages touched by other threads, so the
+/// placement is only reliable for buffers of a page or larger.
+///
+/// Buffers are taken with `Scope::take_buffer` and should be given back
+/// with `Scope::return_buffer` when no longer needed. When the pool is
+/// empty new buffers are allocated on demand.
+#[derive(Debug)]
+pub struct BufferPool {
+    size: usize,
+    count: usize,
+    free: Vec<Vec<u8>>,
+}
+
+impl BufferPool {
+    /// Preallocate `count` buffers of `size` bytes
+    pub fn new(size: usize, count: usize) -> BufferPool {
+        let mut free = Vec::with_capacity(count);
+        for _ in 0..count {
+            free.push(touched(size));
+        }
+        BufferPool {
+            size: size,
+            count: count,
+            free: free,
+        }
+    }
+    /// Take an empty buffer with capacity of at least the buffer size
+    pub fn take(&mut self) -> Vec<u8> {
+        match self.free.pop() {
+            Some(buf) => buf,
+            None => Vec::with_capacity(self.size),
+        }
+    }
+    /// Give the buffer back to the pool
+    ///
+    /// The buffer is cleared. It's dropped if it's smaller than the buffer
+    /// size or the pool already has as many buffers as preallocated.
+    pub fn give_back(&mut self, mut buf: Vec<u8>) {
+        if buf.capacity() >= self.size && self.free.len() < self.count {
+            buf.clear();
+            self.free.push(buf);
+        }
+    }
+    /// Number of buffers which can be taken without allocation
+    pub fn available(&self) -> usize {
+        self.free.len()
+    }
+    /// The size of the buffers in the pool
+    pub fn buffer_size(&self) -> usize {
+        self.size
+    }
+}
+
+/// Allocates the buffer and writes to every page of it
+fn touched(size: usize) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(size);
+    buf.resize(size, 0);
+    buf.clear();
+    buf
+}
+
+#[cfg(test)]
+mod test {
+    use super::BufferPool;
+
+    #[test]
+    fn preallocated() {
+        let mut pool = BufferPool::new(4096, 2);
+        assert_eq!(pool.available(), 2);
+        let a = pool.take();
+        let b = pool.take();
+        assert_eq!(pool.available(), 0);
+        assert!(a.capacity() >= 4096 && a.is_empty());
+        assert!(b.capacity() >= 4096 && b.is_empty());
+        let c = pool.take();
+        assert!(c.capacity() >= 4096);
+        pool.give_back(a);
+        pool.give_back(b);
+        pool.give_back(c);
+        assert_eq!(pool.available(), 2);
+    }
+
+    #[test]
+    fn returned_buffer_is_cleared() {
+        let mut pool = BufferPool::new(16, 1);
+        let mut buf = pool.take();
+        buf.extend_from_slice(b"hello");
+        pool.give_back(buf);
+        assert!(pool.take().is_empty());
+    }
+
+    #[test]
+    fn small_buffer_dropped() {
+        let mut pool = BufferPool::new(4096, 1);
+        let _buf = pool.take();
+        pool.give_back(Vec::with_capacity(10));
+        assert_eq!(pool.available(), 0);
+    }
+
+    #[test]
+    fn empty_pool() {
+        let mut pool = BufferPool::new(1024, 0);
+        assert_eq!(pool.available(), 0);
+        assert!(pool.take().capacity() >= 1024);
+        pool.give_back(Vec::with_capacity(1024));
+        assert_eq!(pool.available(), 0);
+    }
+}
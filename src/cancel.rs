@@ -0,0 +1,120 @@
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use {GenericScope, Notifier};
+
+
+struct Waiters {
+    next_id: u64,
+    list: Vec<(u64, Notifier)>,
+}
+
+struct Inner {
+    cancelled: AtomicBool,
+    waiters: Mutex<Waiters>,
+}
+
+/// A flag to abort a group of related operations
+///
+/// The token is cloned into every state machine (or thread) taking part
+/// in the operation. Any of them may `cancel` it, and the state machines
+/// which have a `CancelGuard` for the token receive a `wakeup`, where they
+/// check `is_cancelled()` and stop. Cancellation is permanent.
+#[derive(Clone)]
+pub struct CancelToken(Arc<Inner>);
+
+/// An interest of the state machine in the cancellation of the token
+///
+/// Created by `Scope::cancel_guard`. The state machine is woken up when
+/// the token is cancelled while the guard is alive, so keep the guard in
+/// the state machine and drop it when the cancellation is no longer
+/// interesting.
+pub struct CancelGuard {
+    token: CancelToken,
+    id: u64,
+}
+
+fn lock(inner: &Inner) -> MutexGuard<Waiters> {
+    match inner.waiters.lock() {
+        Ok(guard) => guard,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
+
+impl CancelToken {
+    pub fn new() -> CancelToken {
+        CancelToken(Arc::new(Inner {
+            cancelled: AtomicBool::new(false),
+            waiters: Mutex::new(Waiters {
+                next_id: 0,
+                list: Vec::new(),
+            }),
+        }))
+    }
+    /// Cancel the operation and wake up the guarding state machines
+    ///
+    /// Cancelling the token the second time does nothing.
+    pub fn cancel(&self) {
+        if self.0.cancelled.swap(true, Ordering::AcqRel) {
+            return;
+        }
+        let waiters = ::std::mem::replace(&mut lock(&self.0).list,
+                                          Vec::new());
+        for (_, notifier) in waiters {
+            // The loop may be already shut down
+            notifier.wakeup().ok();
+        }
+    }
+    /// Returns true if the token is cancelled
+    pub fn is_cancelled(&self) -> bool {
+        self.0.cancelled.load(Ordering::Acquire)
+    }
+}
+
+impl Default for CancelToken {
+    fn default() -> CancelToken {
+        CancelToken::new()
+    }
+}
+
+impl CancelGuard {
+    /// Returns true if the token is cancelled
+    pub fn is_cancelled(&self) -> bool {
+        self.token.is_cancelled()
+    }
+    /// The token this guard watches
+    pub fn token(&self) -> &CancelToken {
+        &self.token
+    }
+}
+
+impl Drop for CancelGuard {
+    fn drop(&mut self) {
+        let id = self.id;
+        lock(&self.token.0).list.retain(|&(x, _)| x != id);
+    }
+}
+
+#[doc(hidden)]
+pub fn create_guard<S: GenericScope>(token: &CancelToken, scope: &mut S)
+    -> CancelGuard
+{
+    let notifier = scope.notifier();
+    let id = {
+        let mut waiters = lock(&token.0);
+        let id = waiters.next_id;
+        waiters.next_id += 1;
+        // Checked under the lock, so `cancel` either sees the notifier,
+        // or has set the flag before
+        if token.is_cancelled() {
+            notifier.wakeup().ok();
+        } else {
+            waiters.list.push((id, notifier));
+        }
+        id
+    };
+    CancelGuard {
+        token: token.clone(),
+        id: id,
+    }
+}
@@ -0,0 +1,94 @@
+use std::cell::Cell;
+use std::sync::mpsc;
+
+use Notifier;
+
+
+/// The sending side of the channel
+///
+/// Created by `Scope::channel()`. May be cloned and sent to other threads
+/// if the value type is `Send`. Every message wakes up the state machine
+/// which owns the `Receiver`.
+pub struct Sender<T> {
+    channel: mpsc::Sender<T>,
+    notifier: Notifier,
+}
+
+/// The receiving side of the channel, owned by the state machine
+///
+/// Drain it on every `wakeup` with `try_recv` (or `drain`) until it returns
+/// nothing. Wakeups are coalesced, so a single wakeup may stand for many
+/// messages, and a message sent while the state machine is draining
+/// always causes another wakeup.
+pub struct Receiver<T> {
+    channel: mpsc::Receiver<T>,
+    closed: Cell<bool>,
+}
+
+pub fn create_channel<T>(notifier: Notifier) -> (Sender<T>, Receiver<T>) {
+    let (tx, rx) = mpsc::channel();
+    let sender = Sender {
+        channel: tx,
+        notifier: notifier,
+    };
+    (sender, Receiver { channel: rx, closed: Cell::new(false) })
+}
+
+impl<T> Sender<T> {
+    /// Send the value and wake up the receiving state machine
+    ///
+    /// Returns the value back if the `Receiver` is dropped. The value is
+    /// queued even if the wakeup can't be sent (i.e. the queue of
+    /// notifications of the loop is full), it's received on the next
+    /// wakeup then.
+    pub fn send(&self, value: T) -> Result<(), T> {
+        try!(self.channel.send(value).map_err(|e| e.0));
+        self.notifier.wakeup().ok();
+        Ok(())
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Sender<T> {
+        Sender {
+            channel: self.channel.clone(),
+            notifier: self.notifier.clone(),
+        }
+    }
+}
+
+impl<T> Receiver<T> {
+    /// Receive a single value, returns `None` if there is nothing queued
+    pub fn try_recv(&self) -> Option<T> {
+        match self.channel.try_recv() {
+            Ok(value) => Some(value),
+            Err(mpsc::TryRecvError::Empty) => None,
+            Err(mpsc::TryRecvError::Disconnected) => {
+                self.closed.set(true);
+                None
+            }
+        }
+    }
+    /// Iterate over all the values queued at the moment
+    pub fn drain(&self) -> Drain<T> {
+        Drain { receiver: self }
+    }
+    /// Returns true if all the senders are dropped and nothing is left
+    ///
+    /// Updated by `try_recv` and `drain`, so check it after draining.
+    pub fn is_closed(&self) -> bool {
+        self.closed.get()
+    }
+}
+
+/// Iterator returned by `Receiver::drain`
+pub struct Drain<'a, T: 'a> {
+    receiver: &'a Receiver<T>,
+}
+
+impl<'a, T> Iterator for Drain<'a, T> {
+    type Item = T;
+    fn next(&mut self) -> Option<T> {
+        self.receiver.try_recv()
+    }
+}
@@ -0,0 +1,99 @@
+use std::rc::Rc;
+use std::cell::Cell;
+use std::time::{Instant, Duration, SystemTime};
+
+
+/// The source of time of the loop
+///
+/// The loop reads the clock once per iteration (see `Scope::now`) and
+/// when arming its timer. The default is `SystemClock`. Install another
+/// one with `LoopCreator::set_clock`, e.g. `ManualClock` in tests, or a
+/// clock backed by `CLOCK_MONOTONIC_COARSE` where reading the time is
+/// expensive.
+pub trait Clock {
+    /// Monotonic time, must never go backwards
+    fn now(&self) -> Instant;
+    /// Wall clock time, used for `Scope::system_time` and friends
+    fn system_time(&self) -> SystemTime {
+        SystemTime::now()
+    }
+    /// How long the loop may block in poll waiting for the `deadline`
+    ///
+    /// Default is the time left until `now()` reaches the deadline.
+    fn sleep_until(&self, deadline: Instant) -> Duration {
+        let now = self.now();
+        if deadline > now {
+            deadline.duration_since(now)
+        } else {
+            Duration::new(0, 0)
+        }
+    }
+}
+
+/// The clock of the operating system, i.e. `Instant::now()`
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+/// The clock which only moves when it's told to
+///
+/// Clones share the time, so keep one in the test and install another
+/// into the loop:
+///
+/// ```ignore
+/// let clock = ManualClock::new();
+/// creator.set_clock(clock.clone());
+/// // ...
+/// clock.advance(Duration::from_secs(30));
+/// loop_inst.run_for(Duration::from_millis(1)).unwrap();
+/// ```
+///
+/// The loop still blocks in poll for the real time, so drive it with
+/// `run_for`: the timeouts due by the clock are fired on every iteration.
+#[derive(Debug, Clone)]
+pub struct ManualClock {
+    now: Rc<Cell<Instant>>,
+}
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+impl ManualClock {
+    /// Create the clock stopped at the current time
+    pub fn new() -> ManualClock {
+        ManualClock {
+            now: Rc::new(Cell::new(Instant::now())),
+        }
+    }
+    /// Move the clock forward
+    pub fn advance(&self, by: Duration) {
+        self.now.set(self.now.get() + by);
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        self.now.get()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+    use super::{Clock, ManualClock};
+
+    #[test]
+    fn manual_clock() {
+        let clock = ManualClock::new();
+        let other = clock.clone();
+        let start = clock.now();
+        other.advance(Duration::from_millis(1500));
+        assert_eq!(clock.now().duration_since(start),
+                   Duration::from_millis(1500));
+        assert_eq!(clock.sleep_until(start + Duration::from_secs(5)),
+                   Duration::from_millis(3500));
+        assert_eq!(clock.sleep_until(start), Duration::new(0, 0));
+    }
+}
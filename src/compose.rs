@@ -1,5 +1,6 @@
-use mio::Ready;
-use void::{Void, unreachable};
+use std::error::Error;
+
+use mio::{Ready, PollOpt, Evented, Token};
 
 use {Machine, Scope, Response};
 
@@ -28,13 +29,21 @@ impl<X, AA, BB> Machine for Compose2<AA, BB>
     type Seed = Compose2Seed<AA::Seed, BB::Seed>;
 
     fn create(seed: Self::Seed, scope: &mut Scope<X>)
-        -> Response<Self, Void>
+        -> Response<Self, Self::Seed>
     {
         use Compose2::*;
         use self::Compose2Seed::*;
         match seed {
-            As(s) => AA::create(s, scope).map(A, |x| unreachable(x)),
-            Bs(s) => BB::create(s, scope).map(B, |x| unreachable(x)),
+            As(s) => AA::create(s, scope).map_many(A, As),
+            Bs(s) => BB::create(s, scope).map_many(B, Bs),
+        }
+    }
+    fn register_seed(seed: &Self::Seed) -> Option<(&Evented, Ready, PollOpt)>
+    {
+        use self::Compose2Seed::*;
+        match *seed {
+            As(ref s) => AA::register_seed(s),
+            Bs(ref s) => BB::register_seed(s),
         }
     }
     fn ready(self, events: Ready, scope: &mut Scope<X>)
@@ -43,8 +52,8 @@ impl<X, AA, BB> Machine for Compose2<AA, BB>
         use Compose2::*;
         use self::Compose2Seed::*;
         match self {
-            A(m) => { m.ready(events, scope).map(A, As) }
-            B(m) => { m.ready(events, scope).map(B, Bs) }
+            A(m) => { m.ready(events, scope).map_many(A, As) }
+            B(m) => { m.ready(events, scope).map_many(B, Bs) }
         }
     }
     fn spawned(self, scope: &mut Scope<X>) -> Response<Self, Self::Seed>
@@ -52,24 +61,91 @@ impl<X, AA, BB> Machine for Compose2<AA, BB>
         use Compose2::*;
         use self::Compose2Seed::*;
         match self {
-            A(m) => { m.spawned(scope).map(A, As) }
-            B(m) => { m.spawned(scope).map(B, Bs) }
+            A(m) => { m.spawned(scope).map_many(A, As) }
+            B(m) => { m.spawned(scope).map_many(B, Bs) }
+        }
+    }
+    fn destroyed(self, scope: &mut Scope<X>) {
+        use Compose2::*;
+        match self {
+            A(m) => m.destroyed(scope),
+            B(m) => m.destroyed(scope),
+        }
+    }
+    fn freeze(self, scope: &mut Scope<X>) -> Result<Self::Seed, Self> {
+        use Compose2::*;
+        use self::Compose2Seed::*;
+        match self {
+            A(m) => m.freeze(scope).map(As).map_err(A),
+            B(m) => m.freeze(scope).map(Bs).map_err(B),
+        }
+    }
+    fn thaw(seed: Self::Seed, scope: &mut Scope<X>)
+        -> Response<Self, Self::Seed>
+    {
+        use Compose2::*;
+        use self::Compose2Seed::*;
+        match seed {
+            As(s) => AA::thaw(s, scope).map_many(A, As),
+            Bs(s) => BB::thaw(s, scope).map_many(B, Bs),
         }
     }
     fn timeout(self, scope: &mut Scope<X>) -> Response<Self, Self::Seed> {
         use Compose2::*;
         use self::Compose2Seed::*;
         match self {
-            A(m) => { m.timeout(scope).map(A, As) }
-            B(m) => { m.timeout(scope).map(B, Bs) }
+            A(m) => { m.timeout(scope).map_many(A, As) }
+            B(m) => { m.timeout(scope).map_many(B, Bs) }
         }
     }
     fn wakeup(self, scope: &mut Scope<X>) -> Response<Self, Self::Seed> {
         use Compose2::*;
         use self::Compose2Seed::*;
         match self {
-            A(m) => { m.wakeup(scope).map(A, As) }
-            B(m) => { m.wakeup(scope).map(B, Bs) }
+            A(m) => { m.wakeup(scope).map_many(A, As) }
+            B(m) => { m.wakeup(scope).map_many(B, Bs) }
+        }
+    }
+    fn exit_signal(self, peer: Token, scope: &mut Scope<X>)
+        -> Response<Self, Self::Seed>
+    {
+        use Compose2::*;
+        use self::Compose2Seed::*;
+        match self {
+            A(m) => { m.exit_signal(peer, scope).map_many(A, As) }
+            B(m) => { m.exit_signal(peer, scope).map_many(B, Bs) }
+        }
+    }
+    fn tick(self, scope: &mut Scope<X>) -> Response<Self, Self::Seed> {
+        use Compose2::*;
+        use self::Compose2Seed::*;
+        match self {
+            A(m) => { m.tick(scope).map_many(A, As) }
+            B(m) => { m.tick(scope).map_many(B, Bs) }
+        }
+    }
+    fn config_changed(self, scope: &mut Scope<X>)
+        -> Response<Self, Self::Seed>
+    {
+        use Compose2::*;
+        use self::Compose2Seed::*;
+        match self {
+            A(m) => { m.config_changed(scope).map_many(A, As) }
+            B(m) => { m.config_changed(scope).map_many(B, Bs) }
+        }
+    }
+    fn interest(&self) -> Option<Ready> {
+        use Compose2::*;
+        match *self {
+            A(ref m) => m.interest(),
+            B(ref m) => m.interest(),
+        }
+    }
+    fn error(self, err: Box<Error>, scope: &mut Scope<X>) -> Box<Error> {
+        use Compose2::*;
+        match self {
+            A(m) => m.error(err, scope),
+            B(m) => m.error(err, scope),
         }
     }
 }
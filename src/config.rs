@@ -1,10 +1,22 @@
 use std::io;
 use std::default::Default;
+use std::time::Duration;
+use std::cmp::{min, max};
 
-use mio::deprecated::{EventLoop, EventLoopBuilder};
+use mio::Token;
+use mio::deprecated::{EventLoop, EventLoopBuilder, Handler};
+#[cfg(all(unix, feature="futures"))] use mio::timer::{self, Timer};
 
-use handler::Handler;
-use {Machine, Slab};
+use pool::ThreadPool;
+use budget::ConnectBudget;
+use affinity::pin_current_thread;
+use trace::EventLog;
+use buffer_pool::BufferPool;
+use loop_time::millis;
+use metrics::LoopLatency;
+use store::MachineStore;
+use shuffle::{Shuffler, random_seed};
+use {Slab, PollOpt};
 
 
 /// Event loop configuration
@@ -14,6 +26,86 @@ use {Machine, Slab};
 pub struct Config {
     mio: EventLoopBuilder,
     slab_capacity: usize,
+    offload_threads: usize,
+    file_io_threads: usize,
+    connect_limit: Option<usize>,
+    destination_connect_limit: Option<usize>,
+    retry_transient_poll_errors: bool,
+    cpu: Option<usize>,
+    buffer_pool: Option<(usize, usize)>,
+    slow_callback_threshold: Option<Duration>,
+    event_history: usize,
+    latency_histograms: bool,
+    poll_opt: PollOpt,
+    slab_growth: SlabGrowth,
+    slab_full: SlabFull,
+    slab_shrink: Option<(usize, Duration)>,
+    spawn_queue: Option<(usize, QueueOverflow)>,
+    notify_capacity: Option<usize>,
+    messages_per_tick: Option<usize>,
+    max_events_per_tick: Option<usize>,
+    transition_budget: Option<u32>,
+    timer_tick: Option<Duration>,
+    coarse_timer_granularity: Duration,
+    token_range: Option<(usize, usize)>,
+    shuffle_seed: Option<u64>,
+    loop_id: Option<String>,
+    watchdog: Option<(Duration, Unresponsive)>,
+    max_poll_timeout: Option<Duration>,
+}
+
+/// What to do when the state machine slab is full
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SlabGrowth {
+    /// Never grow, spawning fails with `SpawnError::NoSlabSpace`
+    Fixed,
+    /// Double the capacity until it reaches the limit
+    Double { limit: usize },
+}
+
+/// What to do with the seed spawned by a state machine when the slab is
+/// full and can't grow any more
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SlabFull {
+    /// Call `Machine::spawn_error` with `SpawnError::NoSlabSpace`
+    Reject,
+    /// Keep the seed and create the child when some state machine is
+    /// removed, see `Scope::queued_spawns`
+    Queue,
+    /// Remove the state machine with the oldest `last_activity` accepted
+    /// by the eviction filter, and create the child in its slot
+    ///
+    /// See `LoopCreator::eviction_filter`. If nothing can be evicted the
+    /// seed is rejected.
+    Evict,
+}
+
+/// What to do with the seed when its parent has `Config::spawn_queue`
+/// seeds queued already
+///
+/// Seeds returned from `Machine::spawn_error` are never reported twice,
+/// so they are dropped on overflow regardless of the policy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QueueOverflow {
+    /// Call `Machine::spawn_error` for the new seed
+    Reject,
+    /// Call `Machine::spawn_error` for the oldest queued seed of the
+    /// parent, and queue the new one
+    DropOldest,
+    /// Queue the seed over the limit, and call `Machine::spawn_error` if
+    /// it's not created within the duration
+    Deadline(Duration),
+}
+
+/// What the watchdog does with the state machine which hasn't
+/// acknowledged the ping, see `Config::watchdog`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Unresponsive {
+    /// Call the hook (`LoopCreator::watchdog_hook`) and ping again
+    Report,
+    /// Call the hook and remove the state machine with
+    /// `Machine::destroyed`
+    Abort,
 }
 
 impl Default for Config {
@@ -21,6 +113,32 @@ impl Default for Config {
         Config {
             mio: Default::default(),
             slab_capacity: 4096,
+            offload_threads: 4,
+            file_io_threads: 2,
+            connect_limit: None,
+            destination_connect_limit: None,
+            retry_transient_poll_errors: false,
+            cpu: None,
+            buffer_pool: None,
+            slow_callback_threshold: None,
+            event_history: 0,
+            latency_histograms: false,
+            poll_opt: default_poll_opt(),
+            slab_growth: SlabGrowth::Fixed,
+            slab_full: SlabFull::Reject,
+            slab_shrink: None,
+            spawn_queue: None,
+            notify_capacity: None,
+            messages_per_tick: None,
+            max_events_per_tick: None,
+            transition_budget: None,
+            timer_tick: None,
+            coarse_timer_granularity: Duration::from_secs(1),
+            token_range: None,
+            shuffle_seed: None,
+            loop_id: None,
+            watchdog: None,
+            max_poll_timeout: None,
         }
     }
 }
@@ -31,6 +149,32 @@ impl Config {
         Config {
             mio: EventLoopBuilder::new(),
             slab_capacity: 4096,
+            offload_threads: 4,
+            file_io_threads: 2,
+            connect_limit: None,
+            destination_connect_limit: None,
+            retry_transient_poll_errors: false,
+            cpu: None,
+            buffer_pool: None,
+            slow_callback_threshold: None,
+            event_history: 0,
+            latency_histograms: false,
+            poll_opt: default_poll_opt(),
+            slab_growth: SlabGrowth::Fixed,
+            slab_full: SlabFull::Reject,
+            slab_shrink: None,
+            spawn_queue: None,
+            notify_capacity: None,
+            messages_per_tick: None,
+            max_events_per_tick: None,
+            transition_budget: None,
+            timer_tick: None,
+            coarse_timer_granularity: Duration::from_secs(1),
+            token_range: None,
+            shuffle_seed: None,
+            loop_id: None,
+            watchdog: None,
+            max_poll_timeout: None,
         }
     }
     /// A mutable reference for ``mio::EventLoopBuilder``
@@ -42,8 +186,279 @@ impl Config {
     /// This limits the number of state machines that application is able
     /// to create. Consequently this limits the number of connections that
     /// server is able to establish.
-    pub fn slab_capacity(&mut self, capacity: usize) {
+    pub fn slab_capacity(&mut self, capacity: usize) -> &mut Config {
         self.slab_capacity = capacity;
+        self
+    }
+    /// Growth policy of the state machine slab
+    ///
+    /// The slab is fixed at `slab_capacity` by default.
+    pub fn slab_growth(&mut self, growth: SlabGrowth) -> &mut Config {
+        self.slab_growth = growth;
+        self
+    }
+    /// Ping the state machines enrolled with `Scope::enable_watchdog`
+    ///
+    /// Every `interval` the enrolled state machines receive a `wakeup`,
+    /// for which `Scope::is_ping` returns true. The default
+    /// `Machine::wakeup` acknowledges it, the state machines overriding
+    /// the method should call `Scope::ack_ping`. The ones which haven't
+    /// acknowledged the ping by the next round are stuck: they are
+    /// reported or aborted according to the `action`. Disabled by default.
+    pub fn watchdog(&mut self, interval: Duration, action: Unresponsive)
+        -> &mut Config
+    {
+        self.watchdog = Some((interval, action));
+        self
+    }
+    /// The longest time the loop may block in poll
+    ///
+    /// The loop returns from the poll at least this often even if there
+    /// are no events and no timeouts, so that the hooks (see
+    /// `LoopInstance::after_poll_hook`) or the code calling
+    /// `LoopInstance::run_once` can interleave periodic work with the IO.
+    /// Use `LoopHandle::interrupt` to return from the poll right away.
+    /// Unlimited by default.
+    pub fn max_poll_timeout(&mut self, timeout: Duration) -> &mut Config {
+        self.max_poll_timeout = Some(timeout);
+        self
+    }
+    /// Name of the loop in the log messages and diagnostics
+    ///
+    /// Useful when the process runs a loop per thread, e.g. `"worker-3"`.
+    /// By default the loop is called `loop-N`, where `N` is the number
+    /// of the loop in the process. See `Scope::loop_id`.
+    pub fn loop_id(&mut self, id: &str) -> &mut Config {
+        self.loop_id = Some(id.to_string());
+        self
+    }
+    /// Limit the number of seeds queued for a single state machine
+    ///
+    /// Seeds are queued when the slab is full with `SlabFull::Queue`, and
+    /// when they are returned from `Machine::spawn_error`. When the parent
+    /// has `limit` seeds queued, the `overflow` policy applies, so that a
+    /// storm of connections degrades predictably instead of piling up
+    /// seeds. Unlimited by default.
+    pub fn spawn_queue(&mut self, limit: usize, overflow: QueueOverflow)
+        -> &mut Config
+    {
+        self.spawn_queue = Some((limit, overflow));
+        self
+    }
+    /// Release the memory of the slab when the occupancy stays low
+    ///
+    /// When fewer than `percent` of the slab slots are used for `delay`,
+    /// the capacity grown by `slab_growth` is halved (but not below the
+    /// initial one), and the memory above the highest used token is
+    /// released. Tokens above it get a generation none of the previous
+    /// state machines had, so the old notifiers stay dead. The `percent`
+    /// must be below 50, so the slab doesn't grow right back. `Slab` can't
+    /// release the memory itself, use `VecStore` (see `Loop::with_store`)
+    /// to reclaim all of it. Disabled by default.
+    pub fn slab_shrink(&mut self, percent: usize, delay: Duration)
+        -> &mut Config
+    {
+        self.slab_shrink = Some((percent, delay));
+        self
+    }
+    /// Policy for the children spawned when the slab is full
+    ///
+    /// Applies after `slab_growth` has reached its limit. Only seeds
+    /// returned in `Response` are affected, `add_machine_with` always
+    /// fails with `SpawnError::NoSlabSpace`. Default is `SlabFull::Reject`.
+    pub fn slab_full(&mut self, policy: SlabFull) -> &mut Config {
+        self.slab_full = policy;
+        self
+    }
+    /// A size of the notification queue (used by `Notifier::wakeup`)
+    ///
+    /// Wakeups fail with `WakeupError::Full` when the queue is full.
+    /// Defaults to the mio default.
+    pub fn notify_capacity(&mut self, capacity: usize) -> &mut Config {
+        self.notify_capacity = Some(capacity);
+        self
+    }
+    /// A maximum number of notifications processed per loop iteration
+    ///
+    /// Defaults to the mio default.
+    pub fn messages_per_tick(&mut self, messages: usize) -> &mut Config {
+        self.messages_per_tick = Some(messages);
+        self
+    }
+    /// A maximum number of ready events dispatched per loop iteration
+    ///
+    /// The rest of the events are dispatched on the next iterations, and
+    /// the loop polls without blocking until all of them are dispatched.
+    /// Timers and notifications are processed in between, so their
+    /// latency is bounded even when lots of sockets are ready at once.
+    /// Unlimited by default.
+    pub fn max_events_per_tick(&mut self, events: usize) -> &mut Config {
+        self.max_events_per_tick = Some(events);
+        self
+    }
+    /// A maximum number of consecutive transitions of a state machine
+    /// per loop iteration
+    ///
+    /// Counts every action of the state machine in the iteration, so a
+    /// machine calling `Scope::run_again` or waking itself up in a loop,
+    /// or spawning the children one batch at a time from `spawned`, is
+    /// resumed on the next iteration when the budget is spent. The
+    /// events and timeouts of the other state machines are dispatched in
+    /// between, so the monopolizing machine can't delay them for more
+    /// than `transitions` of its actions. Unlimited by default.
+    pub fn transition_budget(&mut self, transitions: u32) -> &mut Config {
+        self.transition_budget = Some(transitions);
+        self
+    }
+    /// Resolution of the mio timer
+    ///
+    /// Timeouts of the state machines are kept in the loop's own timer
+    /// wheel, so there is no timer capacity to configure. The tick only
+    /// defines how precisely the loop wakes up for the earliest timeout.
+    /// Defaults to the mio default.
+    pub fn timer_tick(&mut self, tick: Duration) -> &mut Config {
+        self.timer_tick = Some(tick);
+        self
+    }
+    /// Rounding of the coarse deadlines
+    ///
+    /// Deadlines of the state machines which opted in with
+    /// `Scope::set_coarse_deadline` are rounded up to the multiple of
+    /// `granularity`, so moving the deadline within the same interval (e.g.
+    /// on every request of a keep-alive connection) doesn't touch the
+    /// timer. Defaults to a second.
+    pub fn coarse_timer_granularity(&mut self, granularity: Duration)
+        -> &mut Config
+    {
+        self.coarse_timer_granularity = granularity;
+        self
+    }
+    /// Register sockets in mio with tokens from `base..base+len` only
+    ///
+    /// Useful when the mio loop is shared with other components which
+    /// allocate their own tokens. Events for tokens outside of the range
+    /// are ignored. Tokens passed to the hooks and returned by `Scope`
+    /// are still counted from zero, only the mio tokens are shifted. The
+    /// slab capacity (including growth) must fit into the range.
+    pub fn token_range(&mut self, base: usize, len: usize) -> &mut Config {
+        self.token_range = Some((base, len));
+        self
+    }
+    /// A number of threads used for `Scope::execute`
+    ///
+    /// Threads are started on first use. Zero disables offloading, so
+    /// `Scope::execute` panics.
+    pub fn offload_threads(&mut self, threads: usize) -> &mut Config {
+        self.offload_threads = threads;
+        self
+    }
+    /// A number of threads used for the file operations of `Scope`
+    ///
+    /// File operations (`Scope::read_file`, `Scope::write_file`) run in
+    /// their own pool, so slow disks don't delay the other offloaded work
+    /// and vice versa. Threads are started on first use. Zero disables
+    /// file operations, so they panic.
+    pub fn file_io_threads(&mut self, threads: usize) -> &mut Config {
+        self.file_io_threads = threads;
+        self
+    }
+    /// A limit of concurrent outgoing connections of the loop
+    ///
+    /// Enforced for state machines using `Scope::connect_slot`. Unlimited
+    /// by default.
+    pub fn connect_limit(&mut self, limit: usize) -> &mut Config {
+        self.connect_limit = Some(limit);
+        self
+    }
+    /// A limit of concurrent outgoing connections to a single destination
+    ///
+    /// Enforced for state machines using `Scope::connect_slot`. Unlimited
+    /// by default.
+    pub fn destination_connect_limit(&mut self, limit: usize) -> &mut Config {
+        self.destination_connect_limit = Some(limit);
+        self
+    }
+    /// Continue running the loop after transient poll errors
+    ///
+    /// By default any poll error (except EINTR, which is always retried)
+    /// stops the loop and is returned from `run()`. With this option
+    /// enabled, errors like EAGAIN are counted, passed to the
+    /// `poll_error_hook` and the loop continues.
+    pub fn retry_transient_poll_errors(&mut self, value: bool) -> &mut Config {
+        self.retry_transient_poll_errors = value;
+        self
+    }
+    /// Pin the thread which creates the loop to the CPU
+    ///
+    /// The thread is pinned in `Loop::new`, so create the loop in the
+    /// thread that runs it (which is the only way anyway). Requires the
+    /// `affinity` feature and works on linux only, otherwise `Loop::new`
+    /// returns an error.
+    pub fn pin_to_cpu(&mut self, cpu: usize) -> &mut Config {
+        self.cpu = Some(cpu);
+        self
+    }
+    /// Preallocate `buffers` buffers of `buffer_size` bytes for the loop
+    ///
+    /// The buffers are allocated in the thread of the loop after it's
+    /// pinned with `pin_to_cpu`, so they are local to the NUMA node of the
+    /// CPU. Take them with `Scope::take_buffer` and give back with
+    /// `Scope::return_buffer`. See `BufferPool` for the details. There are
+    /// no preallocated buffers by default.
+    pub fn buffer_pool(&mut self, buffer_size: usize, buffers: usize)
+        -> &mut Config
+    {
+        self.buffer_pool = Some((buffer_size, buffers));
+        self
+    }
+    /// Report state machine actions which take longer than `threshold`
+    ///
+    /// Slow actions are passed to `Loop::slow_callback_hook`, or logged
+    /// with the `log_errors` feature if there is no hook. Useful to find
+    /// synchronous work that blocks the loop. Disabled by default.
+    pub fn slow_callback_threshold(&mut self, threshold: Duration)
+        -> &mut Config
+    {
+        self.slow_callback_threshold = Some(threshold);
+        self
+    }
+    /// A number of recently dispatched events kept for diagnostics
+    ///
+    /// See `Scope::event_cursor`. Zero (the default) disables the history.
+    pub fn event_history(&mut self, size: usize) -> &mut Config {
+        self.event_history = size;
+        self
+    }
+    /// Collect the latency histograms of the loop, see `LoopLatency`
+    ///
+    /// Measuring takes a clock read for every ready event, so it's
+    /// disabled by default.
+    pub fn latency_histograms(&mut self, enable: bool) -> &mut Config {
+        self.latency_histograms = enable;
+        self
+    }
+    /// Poll options used by `Scope::register_default`
+    ///
+    /// Defaults to edge-triggered mode on unix (where both epoll and kqueue
+    /// support it natively) and level-triggered mode elsewhere. The
+    /// edge-triggered mode avoids reregistration churn, but the state
+    /// machine must read (or write) until `WouldBlock` on every event.
+    pub fn poll_opt(&mut self, opt: PollOpt) -> &mut Config {
+        self.poll_opt = opt;
+        self
+    }
+    /// Dispatch the events in random order, for testing only
+    ///
+    /// Ready events of the same priority, yielded and same-thread wakeups,
+    /// exit signals and the timeouts expiring in the same loop iteration
+    /// are shuffled, so that the tests find the state machines relying on
+    /// the order the loop happens to use. `Scope::run_again` requests are
+    /// still served in order. The seed is logged when the loop is created
+    /// and returned by `LoopInstance::shuffle_seed`, pass it here to
+    /// reproduce the failure. With `None` a random seed is chosen.
+    pub fn shuffle_dispatch(&mut self, seed: Option<u64>) -> &mut Config {
+        self.shuffle_seed = Some(seed.unwrap_or_else(random_seed));
+        self
     }
 }
 
@@ -51,12 +466,279 @@ impl Config {
 // The functions are probably don't belong here, but they accept otherwise
 // private parts of the config structure
 
+#[cfg(unix)]
+fn default_poll_opt() -> PollOpt {
+    PollOpt::edge()
+}
+
+#[cfg(not(unix))]
+fn default_poll_opt() -> PollOpt {
+    PollOpt::level()
+}
+
+pub fn poll_opt(cfg: &Config) -> PollOpt {
+    cfg.poll_opt
+}
+
+pub fn slab_full(cfg: &Config) -> SlabFull {
+    cfg.slab_full
+}
+
+pub fn watchdog(cfg: &Config) -> Option<(Duration, Unresponsive)> {
+    cfg.watchdog
+}
+
+pub fn spawn_queue(cfg: &Config) -> Option<(usize, QueueOverflow)> {
+    cfg.spawn_queue
+}
+
+pub fn slab_shrink(cfg: &Config) -> Option<(usize, Duration)> {
+    cfg.slab_shrink
+}
+
+pub fn max_poll_timeout(cfg: &Config) -> Option<Duration> {
+    cfg.max_poll_timeout
+}
+
 pub fn create_slab<M:Sized>(cfg: &Config) -> Slab<M> {
     Slab::with_capacity(cfg.slab_capacity)
 }
 
-pub fn create_loop<M: Machine>(cfg: &Config)
-    -> Result<EventLoop<Handler<M>>, io::Error>
+/// Grows the slab if it's full and the growth policy allows
+pub fn grow_slab<T, S: MachineStore<T>>(slab: &mut S, cfg: &Config) {
+    if slab.len() < slab.capacity() {
+        return;
+    }
+    if let SlabGrowth::Double { limit } = cfg.slab_growth {
+        let capacity = slab.capacity();
+        let add = min(max(capacity, 1), limit.saturating_sub(capacity));
+        if add > 0 {
+            slab.reserve(add);
+        }
+    }
+}
+
+/// Number of state machines which fit into the slab, including growth
+pub fn slab_room<T, S: MachineStore<T>>(slab: &S, cfg: &Config) -> usize {
+    let capacity = match cfg.slab_growth {
+        SlabGrowth::Fixed => slab.capacity(),
+        SlabGrowth::Double { limit } => max(limit, slab.capacity()),
+    };
+    capacity.saturating_sub(slab.len())
+}
+
+/// Shrinks the slab down to the `initial` capacity and at least `len`
+pub fn shrink_slab<T, S: MachineStore<T>>(slab: &mut S, initial: usize) {
+    let capacity = slab.capacity();
+    let target = max(max(capacity / 2, initial), slab.len());
+    slab.shrink(min(target, capacity));
+}
+
+/// The tokens starting from this one are reserved
+const RESERVED_TOKENS: usize = ::std::usize::MAX - 3;
+
+fn invalid(message: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+/// Checks the options that can't be validated in setters
+pub fn validate(cfg: &Config) -> io::Result<()> {
+    if cfg.slab_capacity == 0 {
+        return Err(invalid("slab capacity must be positive"));
+    }
+    if let SlabGrowth::Double { limit } = cfg.slab_growth {
+        if limit < cfg.slab_capacity {
+            return Err(invalid("slab growth limit is less than capacity"));
+        }
+    }
+    if cfg.notify_capacity == Some(0) {
+        return Err(invalid("notify capacity must be positive"));
+    }
+    if cfg.slab_shrink.map(|(p, _)| p == 0 || p >= 50).unwrap_or(false) {
+        return Err(invalid("slab shrink percent must be within 1..50"));
+    }
+    if cfg.messages_per_tick == Some(0) {
+        return Err(invalid("messages per tick must be positive"));
+    }
+    if cfg.max_events_per_tick == Some(0) {
+        return Err(invalid("events per tick must be positive"));
+    }
+    if cfg.transition_budget == Some(0) {
+        return Err(invalid("transition budget must be positive"));
+    }
+    if let Some((base, len)) = cfg.token_range {
+        let limit = match cfg.slab_growth {
+            SlabGrowth::Fixed => cfg.slab_capacity,
+            SlabGrowth::Double { limit } => limit,
+        };
+        if limit > len {
+            return Err(invalid("slab capacity exceeds the token range"));
+        }
+        // The topmost tokens are used by mio and the waker
+        if base.checked_add(len).map(|end| end > RESERVED_TOKENS)
+            .unwrap_or(true)
+        {
+            return Err(invalid("token range overlaps reserved tokens"));
+        }
+    }
+    let tick = cfg.timer_tick;
+    if tick.map(|x| x < Duration::from_millis(1)).unwrap_or(false) {
+        return Err(invalid("timer tick must be at least a millisecond"));
+    }
+    if cfg.coarse_timer_granularity < Duration::from_millis(1) {
+        return Err(invalid(
+            "coarse timer granularity must be at least a millisecond"));
+    }
+    Ok(())
+}
+
+/// Granularity of the coarse deadlines in milliseconds
+pub fn coarse_granularity_ms(cfg: &Config) -> u64 {
+    millis(cfg.coarse_timer_granularity)
+}
+
+pub fn max_events_per_tick(cfg: &Config) -> Option<usize> {
+    cfg.max_events_per_tick
+}
+
+pub fn transition_budget(cfg: &Config) -> Option<u32> {
+    cfg.transition_budget
+}
+
+/// Capacity of the notification queue, the default one is mio's
+pub fn notify_capacity(cfg: &Config) -> usize {
+    cfg.notify_capacity.unwrap_or(4096)
+}
+
+/// Notifications processed per tick, the default one is mio's
+pub fn messages_per_tick(cfg: &Config) -> usize {
+    cfg.messages_per_tick.unwrap_or(256)
+}
+
+/// Token range and the maximum number of state machines
+pub fn token_range(cfg: &Config) -> Option<((usize, usize), usize)> {
+    cfg.token_range.map(|range| {
+        let limit = match cfg.slab_growth {
+            SlabGrowth::Fixed => cfg.slab_capacity,
+            SlabGrowth::Double { limit } => limit,
+        };
+        (range, limit)
+    })
+}
+
+/// The mio token of the first state machine
+pub fn token_base(cfg: &Config) -> usize {
+    cfg.token_range.map(|(base, _)| base).unwrap_or(0)
+}
+
+/// Converts the mio token to the state machine token, if it's in range
+pub fn local_token(cfg: &Config, token: Token) -> Option<Token> {
+    match cfg.token_range {
+        Some((base, len)) if token.0 >= base && token.0 - base < len => {
+            Some(Token(token.0 - base))
+        }
+        Some(_) => None,
+        None => Some(token),
+    }
+}
+
+pub fn create_pool(cfg: &Config) -> ThreadPool {
+    ThreadPool::new(cfg.offload_threads, "rotor-offload", "offload_threads")
+}
+
+pub fn create_file_pool(cfg: &Config) -> ThreadPool {
+    ThreadPool::new(cfg.file_io_threads, "rotor-file-io", "file_io_threads")
+}
+
+pub fn retry_transient_poll_errors(cfg: &Config) -> bool {
+    cfg.retry_transient_poll_errors
+}
+
+pub fn pin_thread(cfg: &Config) -> io::Result<()> {
+    match cfg.cpu {
+        Some(cpu) => pin_current_thread(cpu),
+        None => Ok(()),
+    }
+}
+
+/// Forked loops run in their own thread, so pinning is set explicitly
+pub fn fork_config(cfg: &Config, cpu: Option<usize>) -> Config {
+    let mut cfg = cfg.clone();
+    cfg.cpu = cpu;
+    // The forked loop gets a number of its own
+    cfg.loop_id = None;
+    cfg
+}
+
+/// The `Config::loop_id` of the loop started by `reuseport_loops`
+pub fn indexed_config(cfg: &Config, index: usize) -> Config {
+    let mut cfg = cfg.clone();
+    if let Some(ref mut id) = cfg.loop_id {
+        id.push_str(&format!("-{}", index));
+    }
+    cfg
+}
+
+pub fn loop_id(cfg: &Config) -> Option<&str> {
+    cfg.loop_id.as_ref().map(|x| &x[..])
+}
+
+pub fn slow_callback_threshold(cfg: &Config) -> Option<Duration> {
+    cfg.slow_callback_threshold
+}
+
+pub fn create_buffer_pool(cfg: &Config) -> BufferPool {
+    match cfg.buffer_pool {
+        Some((size, count)) => BufferPool::new(size, count),
+        None => BufferPool::new(0, 0),
+    }
+}
+
+pub fn create_event_log(cfg: &Config) -> EventLog {
+    EventLog::new(cfg.event_history)
+}
+
+pub fn create_shuffler(cfg: &Config, loop_id: &str) -> Option<Shuffler> {
+    cfg.shuffle_seed.map(|seed| {
+        info!("{}: Dispatch order is shuffled with seed {}", loop_id, seed);
+        Shuffler::new(seed)
+    })
+}
+
+pub fn create_latency(cfg: &Config) -> Option<LoopLatency> {
+    if cfg.latency_histograms {
+        Some(LoopLatency::new())
+    } else {
+        None
+    }
+}
+
+pub fn create_budget(cfg: &Config) -> ConnectBudget {
+    ConnectBudget::new(cfg.connect_limit, cfg.destination_connect_limit)
+}
+
+pub fn create_loop<H: Handler>(cfg: &Config)
+    -> Result<EventLoop<H>, io::Error>
 {
-    cfg.mio.clone().build()
+    let mut builder = cfg.mio.clone();
+    if let Some(capacity) = cfg.notify_capacity {
+        builder.notify_capacity(capacity);
+    }
+    if let Some(messages) = cfg.messages_per_tick {
+        builder.messages_per_tick(messages);
+    }
+    if let Some(tick) = cfg.timer_tick {
+        builder.timer_tick(tick);
+    }
+    builder.build()
+}
+
+/// The timer of `LoopFuture`, which has no `EventLoop` to carry it
+#[cfg(all(unix, feature="futures"))]
+pub fn create_timer<T>(cfg: &Config) -> Timer<T> {
+    let mut builder = timer::Builder::default();
+    if let Some(tick) = cfg.timer_tick {
+        builder = builder.tick_duration(tick);
+    }
+    builder.build()
 }
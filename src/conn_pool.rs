@@ -0,0 +1,339 @@
+use std::mem;
+use std::rc::Rc;
+use std::cell::RefCell;
+use std::error::Error;
+use std::ops::{Deref, DerefMut};
+use std::collections::VecDeque;
+use std::time::Duration;
+
+use void::Void;
+
+use {Machine, Scope, EarlyScope, Response, EventSet, Time};
+use {Notifier, Promise, Answer};
+
+
+/// Delay before reconnecting after the first failure, doubled on every
+/// subsequent failure
+const BACKOFF_MIN_MS: u64 = 100;
+const BACKOFF_MAX_MS: u64 = 10000;
+
+/// Establishes connections for the `PoolMachine`
+pub trait Connector {
+    type Context;
+    type Connection;
+    /// Start a new connection
+    ///
+    /// Called in the context of the pool state machine. Connection usually
+    /// completes asynchronously, so the errors of a connection attempt are
+    /// reported by the user of the lease with `Lease::discard`.
+    fn connect(&mut self, scope: &mut Scope<Self::Context>)
+        -> Result<Self::Connection, Box<Error>>;
+}
+
+struct Pool<T> {
+    size: usize,
+    /// Number of connections both idle and leased
+    live: usize,
+    /// Idle connections, the most recently used are at the back. The time
+    /// is set by the pool state machine when the connection is returned
+    idle: VecDeque<(T, Option<Time>)>,
+    waiters: VecDeque<Answer<Lease<T>>>,
+    /// Number of failures since the last successful lease
+    failures: u32,
+}
+
+/// A handle to the connection pool state machine
+///
+/// Usually kept in the context. Created together with the state machine
+/// by `PoolMachine::new`. Leases are handed out via promises, so the
+/// requesting state machine receives a `wakeup` when the connection is
+/// available.
+pub struct ConnectionPool<T> {
+    pool: Rc<RefCell<Pool<T>>>,
+    notifier: Notifier,
+}
+
+/// A connection borrowed from the pool
+///
+/// Dereferences to the connection. The connection is returned to the pool
+/// when the lease is dropped, so drop the lease only when the connection
+/// is ready for the next request. Use `discard` for the broken ones.
+pub struct Lease<T> {
+    conn: Option<T>,
+    pool: Rc<RefCell<Pool<T>>>,
+    notifier: Notifier,
+}
+
+/// The connection pool state machine
+///
+/// Keeps up to `size` connections established by the `Connector`.
+/// Connections are established on demand, closed after staying idle for
+/// `idle_timeout`, and after failures reconnection is delayed with
+/// exponential backoff.
+pub struct PoolMachine<N: Connector> {
+    connector: N,
+    pool: Rc<RefCell<Pool<N::Connection>>>,
+    notifier: Notifier,
+    idle_timeout: Duration,
+    retry_at: Option<Time>,
+    /// Value of `Pool::failures` at the last action
+    failures_seen: u32,
+}
+
+impl<T> Clone for ConnectionPool<T> {
+    fn clone(&self) -> ConnectionPool<T> {
+        ConnectionPool {
+            pool: self.pool.clone(),
+            notifier: self.notifier.clone(),
+        }
+    }
+}
+
+impl<T> ConnectionPool<T> {
+    /// Request a connection from the pool
+    ///
+    /// Requests are served in order. An idle connection is handed out
+    /// immediately (the promise is ready on return), otherwise the pool
+    /// state machine establishes a new one or waits for some lease to be
+    /// returned.
+    pub fn lease<C>(&self, scope: &mut Scope<C>) -> Promise<Lease<T>> {
+        let (promise, answer) = scope.promise();
+        let conn = {
+            let mut pool = self.pool.borrow_mut();
+            if pool.waiters.is_empty() {
+                pool.idle.pop_back().map(|(conn, _)| conn)
+            } else {
+                None
+            }
+        };
+        match conn {
+            Some(conn) => answer.send(Lease {
+                conn: Some(conn),
+                pool: self.pool.clone(),
+                notifier: self.notifier.clone(),
+            }),
+            None => {
+                self.pool.borrow_mut().waiters.push_back(answer);
+                // The request is served anyway when anything else wakes
+                // the pool
+                self.notifier.wakeup().ok();
+            }
+        }
+        promise
+    }
+    /// Number of connections, both idle and leased
+    pub fn live(&self) -> usize {
+        self.pool.borrow().live
+    }
+    /// Number of idle connections
+    pub fn idle(&self) -> usize {
+        self.pool.borrow().idle.len()
+    }
+}
+
+impl<T> Lease<T> {
+    /// Close the connection instead of returning it to the pool
+    ///
+    /// Counts as a connection failure, i.e. the pool will wait a bit
+    /// before establishing a new connection.
+    pub fn discard(mut self) {
+        self.conn.take();
+        let mut pool = self.pool.borrow_mut();
+        pool.live -= 1;
+        pool.failures = pool.failures.saturating_add(1);
+        // The pool may need to establish a new connection for waiters
+        self.notifier.wakeup().ok();
+    }
+}
+
+impl<T> Deref for Lease<T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        self.conn.as_ref().expect("connection is not discarded")
+    }
+}
+
+impl<T> DerefMut for Lease<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.conn.as_mut().expect("connection is not discarded")
+    }
+}
+
+impl<T> Drop for Lease<T> {
+    fn drop(&mut self) {
+        if let Some(conn) = self.conn.take() {
+            let mut pool = self.pool.borrow_mut();
+            pool.idle.push_back((conn, None));
+            pool.failures = 0;
+            // Wakeup errors mean the loop is shutting down
+            self.notifier.wakeup().ok();
+        }
+    }
+}
+
+fn backoff(failures: u32) -> Duration {
+    let ms = BACKOFF_MIN_MS << (failures.saturating_sub(1).min(16));
+    Duration::from_millis(ms.min(BACKOFF_MAX_MS))
+}
+
+impl<N: Connector> PoolMachine<N> {
+    /// Create a pool state machine and a handle to it
+    pub fn new(connector: N, size: usize, idle_timeout: Duration,
+        scope: &mut EarlyScope)
+        -> (Response<PoolMachine<N>, Void>, ConnectionPool<N::Connection>)
+    {
+        let pool = Rc::new(RefCell::new(Pool {
+            size: size,
+            live: 0,
+            idle: VecDeque::new(),
+            waiters: VecDeque::new(),
+            failures: 0,
+        }));
+        let handle = ConnectionPool {
+            pool: pool.clone(),
+            notifier: scope.notifier(),
+        };
+        let machine = PoolMachine {
+            connector: connector,
+            pool: pool,
+            notifier: scope.notifier(),
+            idle_timeout: idle_timeout,
+            retry_at: None,
+            failures_seen: 0,
+        };
+        (Response::ok(machine), handle)
+    }
+
+    fn action(mut self, scope: &mut Scope<N::Context>)
+        -> Response<Self, Void>
+    {
+        let now = scope.now();
+        if self.retry_at.map(|x| x <= now).unwrap_or(false) {
+            self.retry_at = None;
+        }
+        let mut handoff = Vec::new();
+        {
+            let mut pool = self.pool.borrow_mut();
+            let pool = &mut *pool;
+            for &mut (_, ref mut time) in pool.idle.iter_mut() {
+                if time.is_none() {
+                    *time = Some(now);
+                }
+            }
+            while !pool.waiters.is_empty() && !pool.idle.is_empty() {
+                let waiter = pool.waiters.pop_front().unwrap();
+                let (conn, _) = pool.idle.pop_back().unwrap();
+                handoff.push((waiter, conn));
+            }
+            while pool.idle.front()
+                .and_then(|&(_, time)| time)
+                .map(|time| time + self.idle_timeout <= now)
+                .unwrap_or(false)
+            {
+                pool.idle.pop_front();
+                pool.live -= 1;
+            }
+            if pool.failures > self.failures_seen && self.retry_at.is_none() {
+                // Connections were discarded by the users
+                self.retry_at = Some(now + backoff(pool.failures));
+            }
+        }
+        while self.retry_at.is_none() {
+            let waiter = {
+                let mut pool = self.pool.borrow_mut();
+                if pool.live >= pool.size {
+                    break;
+                }
+                match pool.waiters.pop_front() {
+                    Some(waiter) => waiter,
+                    None => break,
+                }
+            };
+            // The pool is not borrowed here because the connector may use
+            // the pool handle from the context
+            match self.connector.connect(scope) {
+                Ok(conn) => {
+                    self.pool.borrow_mut().live += 1;
+                    handoff.push((waiter, conn));
+                }
+                Err(e) => {
+                    debug!("Error connecting pooled connection: {}", e);
+                    let mut pool = self.pool.borrow_mut();
+                    pool.waiters.push_front(waiter);
+                    pool.failures = pool.failures.saturating_add(1);
+                    self.retry_at = Some(now + backoff(pool.failures));
+                }
+            }
+        }
+        self.failures_seen = self.pool.borrow().failures;
+        // Leases are sent when the pool is not borrowed, as a lease is
+        // returned immediately if the requester is gone
+        for (waiter, conn) in handoff {
+            waiter.send(Lease {
+                conn: Some(conn),
+                pool: self.pool.clone(),
+                notifier: self.notifier.clone(),
+            });
+        }
+        let expire = self.pool.borrow().idle.front()
+            .and_then(|&(_, time)| time)
+            .map(|time| time + self.idle_timeout);
+        let deadline = match (self.retry_at, expire) {
+            (Some(x), Some(y)) => Some(x.min(y)),
+            (x, y) => x.or(y),
+        };
+        match deadline {
+            Some(deadline) => Response::ok(self).deadline(deadline),
+            None => Response::ok(self),
+        }
+    }
+}
+
+impl<N: Connector> Machine for PoolMachine<N> {
+    type Context = N::Context;
+    type Seed = Void;
+
+    fn create(seed: Void, _scope: &mut Scope<N::Context>)
+        -> Response<Self, Void>
+    {
+        ::void::unreachable(seed)
+    }
+    fn ready(self, _events: EventSet, _scope: &mut Scope<N::Context>)
+        -> Response<Self, Void>
+    {
+        // The pool has no sockets of its own
+        Response::ok(self)
+    }
+    fn spawned(self, _scope: &mut Scope<N::Context>) -> Response<Self, Void> {
+        unreachable!();
+    }
+    fn timeout(self, scope: &mut Scope<N::Context>) -> Response<Self, Void> {
+        self.action(scope)
+    }
+    fn wakeup(self, scope: &mut Scope<N::Context>) -> Response<Self, Void> {
+        self.action(scope)
+    }
+}
+
+impl<N: Connector> Drop for PoolMachine<N> {
+    fn drop(&mut self) {
+        // Waiters will never be served, dropping the answers wakes them up
+        let waiters = mem::replace(&mut self.pool.borrow_mut().waiters,
+                                   VecDeque::new());
+        drop(waiters);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::backoff;
+    use std::time::Duration;
+
+    #[test]
+    fn test_backoff() {
+        assert_eq!(backoff(1), Duration::from_millis(100));
+        assert_eq!(backoff(2), Duration::from_millis(200));
+        assert_eq!(backoff(4), Duration::from_millis(800));
+        assert_eq!(backoff(100), Duration::from_secs(10));
+    }
+}
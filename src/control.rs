@@ -0,0 +1,896 @@
+use std::io;
+use std::fmt;
+use std::str;
+use std::marker::PhantomData;
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::time::{Duration, UNIX_EPOCH};
+
+use mio::deprecated::{TryRead, TryWrite, TryAccept};
+use mio::deprecated::unix::{UnixListener, UnixStream};
+use void::Void;
+
+use loop_time::millis;
+use trace::{EventCursor, DispatchEvent, EventKind};
+use {Machine, Scope, EarlyScope, Response, EventSet, PollOpt, Evented, Time};
+
+
+/// Version of the control protocol
+const PROTOCOL_VERSION: u64 = 1;
+
+/// Maximum size of the request frame, larger requests close the connection
+const MAX_FRAME: usize = 1 << 20;
+
+/// Maximum nesting of arrays and objects in the request
+const MAX_DEPTH: usize = 64;
+
+/// How often the events are sent to a subscribed connection
+const EVENTS_INTERVAL_MS: u64 = 100;
+
+/// No events are added to the output buffer larger than this
+const MAX_OUTPUT: usize = 4 * MAX_FRAME;
+
+/// JSON value used in requests and replies of the control protocol
+#[derive(Debug, Clone, PartialEq)]
+pub enum JsonValue {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<JsonValue>),
+    Object(BTreeMap<String, JsonValue>),
+}
+
+/// Context of the loop running the `Control` state machine
+///
+/// All methods have default implementations, so an empty `impl` is enough
+/// to get only the built-in commands.
+pub trait ControlContext: Sized {
+    /// Execute an application-defined command
+    ///
+    /// Called for commands that are not built in. Return `None` if the
+    /// command is unknown.
+    fn control_command(_scope: &mut Scope<Self>, _command: &str,
+        _args: &[JsonValue])
+        -> Option<Result<JsonValue, String>>
+    {
+        None
+    }
+    /// Change the log level of the application
+    ///
+    /// Rotor can't change the level itself because the logger is
+    /// installed by the application.
+    fn set_log_level(&mut self, _level: &str) -> Result<(), String> {
+        Err("changing log level is not supported".to_string())
+    }
+    /// Stop (`pause` is true) or resume accepting connections
+    fn pause_accept(&mut self, _pause: bool) -> Result<(), String> {
+        Err("pausing accept is not supported".to_string())
+    }
+}
+
+/// Administrative control socket state machine
+///
+/// The state machine listens on a unix socket and answers
+/// administrative requests: statistics, list of state machines, shutdown
+/// and so on.
+///
+/// Protocol is framed JSON. Every frame is a 4-byte big-endian length
+/// followed by the UTF-8 JSON payload. A request looks like:
+///
+/// ```json
+/// {"version": 1, "command": "stats", "args": []}
+/// ```
+///
+/// And the reply is either `{"version": 1, "ok": true, "result": ...}` or
+/// `{"version": 1, "ok": false, "error": "..."}`. Requests of a newer
+/// protocol version are rejected. Any number of requests may be sent over
+/// a single connection, replies are sent in the same order.
+///
+/// Built-in commands are:
+///
+/// * `version` -- protocol version
+/// * `stats` -- number of state machines, poll errors and latency metrics
+/// * `dump` -- list of state machines of the loop
+/// * `set_log_level <level>` -- see `ControlContext::set_log_level`
+/// * `pause_accept`, `resume_accept` -- see `ControlContext::pause_accept`
+/// * `shutdown` -- shutdown the loop
+/// * `subscribe [replay]` -- stream the events dispatched by the loop,
+///   starting `replay` events back (zero by default)
+/// * `unsubscribe` -- stop streaming the events
+///
+/// Events of a subscribed connection are sent every 100 ms in the frames
+/// like `{"version": 1, "events": [...], "missed": 0}`, interleaved with
+/// replies. Where `missed` is the number of events dropped from the
+/// history before they were sent (see `EventCursor::missed`). No events
+/// are sent while the client lags behind by more than 4 MiB of output,
+/// they are counted as missed if dropped from the history meanwhile. The
+/// history is only kept if `Config::event_history` is set.
+///
+/// Other commands are passed to `ControlContext::control_command`.
+pub struct Control<C>(State, PhantomData<*const C>);
+
+enum State {
+    Listener(UnixListener),
+    Connection(Connection),
+}
+
+struct Connection {
+    sock: UnixStream,
+    input: Vec<u8>,
+    output: Vec<u8>,
+    closed: bool,
+    interest: EventSet,
+    /// Set by the `subscribe` command
+    events: Option<EventCursor>,
+}
+
+impl<C: ControlContext> Control<C> {
+    /// Create a state machine listening on the socket
+    pub fn new(sock: UnixListener, scope: &mut EarlyScope)
+        -> Response<Control<C>, Void>
+    {
+        match scope.register(&sock, EventSet::readable(), PollOpt::level()) {
+            Ok(()) => Response::ok(Control(State::Listener(sock),
+                                           PhantomData)),
+            Err(e) => Response::error(Box::new(e)),
+        }
+    }
+    /// Bind a socket at the path and create a state machine listening on it
+    pub fn bind<P: AsRef<Path> + ?Sized>(path: &P, scope: &mut EarlyScope)
+        -> Response<Control<C>, Void>
+    {
+        match UnixListener::bind(path) {
+            Ok(sock) => Control::new(sock, scope),
+            Err(e) => Response::error(Box::new(e)),
+        }
+    }
+    fn accept(sock: UnixListener) -> Response<Control<C>, UnixStream> {
+        match TryAccept::accept(&sock) {
+            Ok(Some(conn)) => {
+                Response::spawn(Control(State::Listener(sock), PhantomData),
+                                conn)
+            }
+            Ok(None) => Response::ok(Control(State::Listener(sock),
+                                             PhantomData)),
+            Err(e) => {
+                debug!("Error accepting control connection: {}", e);
+                Response::ok(Control(State::Listener(sock), PhantomData))
+            }
+        }
+    }
+}
+
+impl Connection {
+    fn read(&mut self) -> io::Result<()> {
+        let mut buf = [0u8; 4096];
+        loop {
+            match try!(self.sock.try_read(&mut buf)) {
+                Some(0) => {
+                    self.closed = true;
+                    return Ok(());
+                }
+                Some(x) => self.input.extend_from_slice(&buf[..x]),
+                None => return Ok(()),
+            }
+            // Either a complete frame is read or the frame is too large,
+            // the rest is read on the next event (level-triggered)
+            if self.input.len() > 4 + MAX_FRAME {
+                return Ok(());
+            }
+        }
+    }
+    fn write(&mut self) -> io::Result<()> {
+        while !self.output.is_empty() {
+            match try!(self.sock.try_write(&self.output)) {
+                Some(x) => {
+                    self.output.drain(..x);
+                }
+                None => break,
+            }
+        }
+        Ok(())
+    }
+    /// Returns the next complete request frame
+    fn next_frame(&mut self) -> Result<Option<Vec<u8>>, ()> {
+        if self.input.len() < 4 {
+            return Ok(None);
+        }
+        let len = self.input[..4].iter()
+            .fold(0usize, |acc, &x| (acc << 8) | x as usize);
+        if len > MAX_FRAME {
+            return Err(());
+        }
+        if self.input.len() < 4 + len {
+            return Ok(None);
+        }
+        let frame = self.input[4..4+len].to_vec();
+        self.input.drain(..4+len);
+        Ok(Some(frame))
+    }
+    fn reply(&mut self, value: &JsonValue) {
+        let data = value.to_string();
+        let len = data.len();
+        self.output.extend_from_slice(&[
+            (len >> 24) as u8, (len >> 16) as u8, (len >> 8) as u8, len as u8,
+        ]);
+        self.output.extend_from_slice(data.as_bytes());
+    }
+    /// Sends the events dispatched since the last call, if subscribed
+    fn send_events<C>(&mut self, scope: &mut Scope<C>) {
+        let (events, missed) = match self.events {
+            Some(ref mut cursor) => {
+                let events = take_events(&self.output, cursor,
+                    |cursor| scope.read_events(cursor));
+                (events, cursor.missed())
+            }
+            None => return,
+        };
+        if events.is_empty() {
+            return;
+        }
+        let events = events.iter().map(|e| dispatch_event(scope, e)).collect();
+        self.reply(&object(vec![
+            ("version", JsonValue::Number(PROTOCOL_VERSION as f64)),
+            ("events", JsonValue::Array(events)),
+            ("missed", JsonValue::Number(missed as f64)),
+        ]));
+    }
+    fn desired_interest(&self) -> EventSet {
+        if self.closed {
+            EventSet::writable()
+        } else if self.output.is_empty() {
+            EventSet::readable()
+        } else {
+            EventSet::readable() | EventSet::writable()
+        }
+    }
+}
+
+/// Reads the events unless the client doesn't read the output
+///
+/// The events are left in the history meanwhile, so the ones dropped
+/// from it are counted as `missed` when the client catches up.
+fn take_events<F>(output: &[u8], cursor: &mut EventCursor, read: F)
+    -> Vec<DispatchEvent>
+    where F: FnOnce(&mut EventCursor) -> Vec<DispatchEvent>
+{
+    if output.len() > MAX_OUTPUT {
+        Vec::new()
+    } else {
+        read(cursor)
+    }
+}
+
+fn object(items: Vec<(&str, JsonValue)>) -> JsonValue {
+    JsonValue::Object(items.into_iter()
+        .map(|(k, v)| (k.to_string(), v)).collect())
+}
+
+fn timestamp<C>(scope: &Scope<C>, time: Time) -> JsonValue {
+    match scope.estimate_system_time(time).duration_since(UNIX_EPOCH) {
+        Ok(dur) => JsonValue::Number(millis(dur) as f64),
+        Err(_) => JsonValue::Null,
+    }
+}
+
+fn ready_flags(events: EventSet) -> JsonValue {
+    let flags = [(events.is_readable(), "readable"),
+                 (events.is_writable(), "writable"),
+                 (events.is_error(), "error"),
+                 (events.is_hup(), "hup")];
+    JsonValue::Array(flags.iter().filter(|&&(set, _)| set)
+        .map(|&(_, name)| JsonValue::String(name.to_string())).collect())
+}
+
+fn dispatch_event<C>(scope: &Scope<C>, event: &DispatchEvent)
+    -> JsonValue
+{
+    let (kind, ready) = match event.kind {
+        EventKind::Ready(events) => ("ready", ready_flags(events)),
+        EventKind::Timeout => ("timeout", JsonValue::Null),
+        EventKind::Wakeup => ("wakeup", JsonValue::Null),
+    };
+    object(vec![
+        ("seq", JsonValue::Number(event.seq as f64)),
+        ("time", timestamp(scope, event.time)),
+        ("token", JsonValue::Number(event.token.0 as f64)),
+        ("kind", JsonValue::String(kind.to_string())),
+        ("ready", ready),
+    ])
+}
+
+/// Argument of the `subscribe` command
+fn replay_count(args: &[JsonValue]) -> Result<usize, String> {
+    match args.first() {
+        None | Some(&JsonValue::Null) => Ok(0),
+        Some(&JsonValue::Number(x)) if x >= 0. && x.fract() == 0. => {
+            Ok(x as usize)
+        }
+        _ => Err("subscribe expects a non-negative integer".to_string()),
+    }
+}
+
+fn stats<C>(scope: &mut Scope<C>) -> JsonValue {
+    let mut machines = 0;
+    let mut labels = BTreeMap::new();
+    scope.for_each_machine(|_, meta| {
+        machines += 1;
+        let label = meta.label().unwrap_or("").to_string();
+        *labels.entry(label).or_insert(0u64) += 1;
+    });
+    let mut metrics = BTreeMap::new();
+    scope.for_each_timer_metric(|label, hist| {
+        metrics.insert(label.to_string(), object(vec![
+            ("count", JsonValue::Number(hist.count() as f64)),
+            ("min", JsonValue::Number(hist.min() as f64)),
+            ("max", JsonValue::Number(hist.max() as f64)),
+            ("mean", JsonValue::Number(hist.mean())),
+            ("p99", JsonValue::Number(hist.percentile(99.) as f64)),
+        ]));
+    });
+    let errors = scope.poll_errors();
+    let slab = scope.slab_occupancy();
+    object(vec![
+        ("machines", JsonValue::Number(machines as f64)),
+        ("slab", object(vec![
+            ("capacity", JsonValue::Number(slab.capacity as f64)),
+            ("high_water", JsonValue::Number(slab.high_water as f64)),
+        ])),
+        ("labels", JsonValue::Object(labels.into_iter()
+            .map(|(k, v)| (k, JsonValue::Number(v as f64))).collect())),
+        ("poll_errors", object(vec![
+            ("interrupted", JsonValue::Number(errors.interrupted as f64)),
+            ("transient", JsonValue::Number(errors.transient as f64)),
+            ("fatal", JsonValue::Number(errors.fatal as f64)),
+        ])),
+        ("dropped_wakeups",
+            JsonValue::Number(scope.dropped_wakeups() as f64)),
+        ("timer_metrics", JsonValue::Object(metrics)),
+    ])
+}
+
+fn dump<C>(scope: &mut Scope<C>) -> JsonValue {
+    let mut items = Vec::new();
+    scope.for_each_machine(|token, meta| {
+        items.push((token, meta.label(), meta.created(),
+                    meta.last_activity(), meta.deadline(),
+                    meta.is_reading_paused()));
+    });
+    JsonValue::Array(items.into_iter()
+        .map(|(token, label, created, activity, deadline, paused)| {
+            object(vec![
+                ("token", JsonValue::Number(token.0 as f64)),
+                ("label", label.map(|x| JsonValue::String(x.to_string()))
+                    .unwrap_or(JsonValue::Null)),
+                ("created", timestamp(scope, created)),
+                ("last_activity", timestamp(scope, activity)),
+                ("deadline", deadline.map(|x| timestamp(scope, x))
+                    .unwrap_or(JsonValue::Null)),
+                ("reading_paused", JsonValue::Bool(paused)),
+            ])
+        }).collect())
+}
+
+fn execute<C: ControlContext>(scope: &mut Scope<C>,
+    events: &mut Option<EventCursor>, command: &str, args: &[JsonValue])
+    -> Result<JsonValue, String>
+{
+    match command {
+        "version" => Ok(JsonValue::Number(PROTOCOL_VERSION as f64)),
+        "stats" => Ok(stats(scope)),
+        "dump" => Ok(dump(scope)),
+        "set_log_level" => match args.first() {
+            Some(&JsonValue::String(ref level)) => {
+                scope.set_log_level(level).map(|()| JsonValue::Null)
+            }
+            _ => Err("set_log_level expects a string argument".to_string()),
+        },
+        "pause_accept" => scope.pause_accept(true).map(|()| JsonValue::Null),
+        "resume_accept" => {
+            scope.pause_accept(false).map(|()| JsonValue::Null)
+        }
+        "shutdown" => {
+            scope.shutdown_loop();
+            Ok(JsonValue::Null)
+        }
+        "subscribe" => {
+            let replay = try!(replay_count(args));
+            *events = Some(scope.event_cursor(replay));
+            Ok(JsonValue::Null)
+        }
+        "unsubscribe" => {
+            *events = None;
+            Ok(JsonValue::Null)
+        }
+        _ => match C::control_command(scope, command, args) {
+            Some(result) => result,
+            None => Err(format!("unknown command {:?}", command)),
+        },
+    }
+}
+
+fn handle<C: ControlContext>(scope: &mut Scope<C>,
+    events: &mut Option<EventCursor>, frame: &[u8])
+    -> JsonValue
+{
+    let result = str::from_utf8(frame)
+        .map_err(|_| "request is not valid utf-8".to_string())
+        .and_then(|text| JsonValue::parse(text))
+        .and_then(|request| {
+            let version = match request.get("version") {
+                Some(&JsonValue::Number(x)) => x,
+                _ => return Err("protocol version is required".to_string()),
+            };
+            if version > PROTOCOL_VERSION as f64 {
+                return Err(format!("unsupported protocol version {}",
+                                   version));
+            }
+            let command = match request.get("command") {
+                Some(&JsonValue::String(ref x)) => x.clone(),
+                _ => return Err("command is required".to_string()),
+            };
+            let args = match request.get("args") {
+                Some(&JsonValue::Array(ref x)) => x.clone(),
+                None | Some(&JsonValue::Null) => Vec::new(),
+                _ => return Err("args must be an array".to_string()),
+            };
+            execute(scope, events, &command, &args)
+        });
+    let version = ("version", JsonValue::Number(PROTOCOL_VERSION as f64));
+    match result {
+        Ok(value) => object(vec![version,
+            ("ok", JsonValue::Bool(true)),
+            ("result", value)]),
+        Err(text) => object(vec![version,
+            ("ok", JsonValue::Bool(false)),
+            ("error", JsonValue::String(text))]),
+    }
+}
+
+impl<C: ControlContext> Machine for Control<C> {
+    type Context = C;
+    type Seed = UnixStream;
+
+    fn register_seed(conn: &UnixStream)
+        -> Option<(&Evented, EventSet, PollOpt)>
+    {
+        Some((conn, EventSet::readable(), PollOpt::level()))
+    }
+
+    fn create(conn: UnixStream, _scope: &mut Scope<C>)
+        -> Response<Self, Self::Seed>
+    {
+        Response::ok(Control(State::Connection(Connection {
+            sock: conn,
+            input: Vec::new(),
+            output: Vec::new(),
+            closed: false,
+            interest: EventSet::readable(),
+            events: None,
+        }), PhantomData))
+    }
+
+    fn ready(self, events: EventSet, scope: &mut Scope<C>)
+        -> Response<Self, UnixStream>
+    {
+        let mut conn = match self.0 {
+            State::Listener(sock) => return Control::accept(sock),
+            State::Connection(conn) => conn,
+        };
+        if events.is_readable() && !conn.closed {
+            if let Err(e) = conn.read() {
+                debug!("Error reading control connection: {}", e);
+                return Response::done();
+            }
+        }
+        loop {
+            match conn.next_frame() {
+                Ok(Some(frame)) => {
+                    let reply = handle(scope, &mut conn.events, &frame);
+                    conn.reply(&reply);
+                }
+                Ok(None) => break,
+                Err(()) => {
+                    debug!("Control request is too large");
+                    return Response::done();
+                }
+            }
+        }
+        flush(conn, scope)
+    }
+
+    fn spawned(self, _scope: &mut Scope<C>) -> Response<Self, UnixStream> {
+        match self.0 {
+            State::Listener(sock) => Control::accept(sock),
+            State::Connection(conn) => {
+                Response::ok(Control(State::Connection(conn), PhantomData))
+            }
+        }
+    }
+
+    fn timeout(self, scope: &mut Scope<C>) -> Response<Self, UnixStream> {
+        match self.0 {
+            State::Listener(sock) => {
+                Response::ok(Control(State::Listener(sock), PhantomData))
+            }
+            State::Connection(conn) => flush(conn, scope),
+        }
+    }
+
+    fn wakeup(self, scope: &mut Scope<C>) -> Response<Self, UnixStream> {
+        match self.0 {
+            State::Listener(sock) => {
+                Response::ok(Control(State::Listener(sock), PhantomData))
+            }
+            // Also restores the deadline of a subscribed connection
+            State::Connection(conn) => flush(conn, scope),
+        }
+    }
+}
+
+/// Sends the pending events and replies, and updates the interest
+fn flush<C>(mut conn: Connection, scope: &mut Scope<C>)
+    -> Response<Control<C>, UnixStream>
+{
+    if !conn.closed {
+        conn.send_events(scope);
+    }
+    if let Err(e) = conn.write() {
+        debug!("Error writing control connection: {}", e);
+        return Response::done();
+    }
+    if conn.closed && conn.output.is_empty() {
+        return Response::done();
+    }
+    let interest = conn.desired_interest();
+    if interest != conn.interest {
+        if let Err(e) = scope.reregister(&conn.sock, interest,
+                                         PollOpt::level())
+        {
+            debug!("Error reregistering control connection: {}", e);
+            return Response::done();
+        }
+        conn.interest = interest;
+    }
+    let subscribed = conn.events.is_some();
+    let res = Response::ok(Control(State::Connection(conn), PhantomData));
+    if subscribed {
+        let interval = Duration::from_millis(EVENTS_INTERVAL_MS);
+        res.deadline(scope.now() + interval)
+    } else {
+        res
+    }
+}
+
+impl JsonValue {
+    /// Parse JSON text
+    pub fn parse(text: &str) -> Result<JsonValue, String> {
+        let mut parser = Parser { data: text.as_bytes(), pos: 0, depth: 0 };
+        let value = try!(parser.value());
+        parser.skip_whitespace();
+        if parser.pos != parser.data.len() {
+            return Err(parser.error("trailing characters"));
+        }
+        Ok(value)
+    }
+    /// Get the field of the object
+    pub fn get(&self, key: &str) -> Option<&JsonValue> {
+        match *self {
+            JsonValue::Object(ref map) => map.get(key),
+            _ => None,
+        }
+    }
+}
+
+struct Parser<'a> {
+    data: &'a [u8],
+    pos: usize,
+    /// Nesting of the value being parsed, see `MAX_DEPTH`
+    depth: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn error(&self, message: &str) -> String {
+        format!("bad json at {}: {}", self.pos, message)
+    }
+    fn skip_whitespace(&mut self) {
+        while self.pos < self.data.len() &&
+            (self.data[self.pos] as char).is_whitespace()
+        {
+            self.pos += 1;
+        }
+    }
+    fn peek(&mut self) -> Option<u8> {
+        self.skip_whitespace();
+        self.data.get(self.pos).map(|x| *x)
+    }
+    fn literal(&mut self, text: &str, value: JsonValue)
+        -> Result<JsonValue, String>
+    {
+        if self.data[self.pos..].starts_with(text.as_bytes()) {
+            self.pos += text.len();
+            Ok(value)
+        } else {
+            Err(self.error("unexpected character"))
+        }
+    }
+    fn value(&mut self) -> Result<JsonValue, String> {
+        if self.depth >= MAX_DEPTH {
+            return Err(self.error("nesting is too deep"));
+        }
+        self.depth += 1;
+        let value = self.nested_value();
+        self.depth -= 1;
+        value
+    }
+    fn nested_value(&mut self) -> Result<JsonValue, String> {
+        match self.peek() {
+            Some(b'n') => self.literal("null", JsonValue::Null),
+            Some(b't') => self.literal("true", JsonValue::Bool(true)),
+            Some(b'f') => self.literal("false", JsonValue::Bool(false)),
+            Some(b'"') => self.string().map(JsonValue::String),
+            Some(b'[') => {
+                self.pos += 1;
+                let mut items = Vec::new();
+                if self.peek() == Some(b']') {
+                    self.pos += 1;
+                    return Ok(JsonValue::Array(items));
+                }
+                loop {
+                    items.push(try!(self.value()));
+                    match self.peek() {
+                        Some(b',') => self.pos += 1,
+                        Some(b']') => {
+                            self.pos += 1;
+                            return Ok(JsonValue::Array(items));
+                        }
+                        _ => return Err(self.error("expected , or ]")),
+                    }
+                }
+            }
+            Some(b'{') => {
+                self.pos += 1;
+                let mut items = BTreeMap::new();
+                if self.peek() == Some(b'}') {
+                    self.pos += 1;
+                    return Ok(JsonValue::Object(items));
+                }
+                loop {
+                    if self.peek() != Some(b'"') {
+                        return Err(self.error("expected key"));
+                    }
+                    let key = try!(self.string());
+                    if self.peek() != Some(b':') {
+                        return Err(self.error("expected :"));
+                    }
+                    self.pos += 1;
+                    items.insert(key, try!(self.value()));
+                    match self.peek() {
+                        Some(b',') => self.pos += 1,
+                        Some(b'}') => {
+                            self.pos += 1;
+                            return Ok(JsonValue::Object(items));
+                        }
+                        _ => return Err(self.error("expected , or }")),
+                    }
+                }
+            }
+            Some(c) if c == b'-' || (c as char).is_digit(10) => self.number(),
+            Some(_) => Err(self.error("unexpected character")),
+            None => Err(self.error("unexpected end of input")),
+        }
+    }
+    fn number(&mut self) -> Result<JsonValue, String> {
+        let start = self.pos;
+        while self.pos < self.data.len() {
+            match self.data[self.pos] as char {
+                '0'|'1'|'2'|'3'|'4'|'5'|'6'|'7'|'8'|'9' |
+                '-' | '+' | '.' | 'e' | 'E' => self.pos += 1,
+                _ => break,
+            }
+        }
+        str::from_utf8(&self.data[start..self.pos]).ok()
+            .and_then(|x| x.parse().ok())
+            .map(JsonValue::Number)
+            .ok_or_else(|| self.error("bad number"))
+    }
+    fn string(&mut self) -> Result<String, String> {
+        // skip opening quote
+        self.pos += 1;
+        let mut result = Vec::new();
+        loop {
+            let c = match self.data.get(self.pos) {
+                Some(&c) => c,
+                None => return Err(self.error("unterminated string")),
+            };
+            self.pos += 1;
+            match c {
+                b'"' => break,
+                b'\\' => {
+                    let e = match self.data.get(self.pos) {
+                        Some(&e) => e,
+                        None => return Err(self.error("unterminated string")),
+                    };
+                    self.pos += 1;
+                    let ch = match e {
+                        b'"' => '"',
+                        b'\\' => '\\',
+                        b'/' => '/',
+                        b'b' => '\x08',
+                        b'f' => '\x0c',
+                        b'n' => '\n',
+                        b'r' => '\r',
+                        b't' => '\t',
+                        b'u' => try!(self.unicode_escape()),
+                        _ => return Err(self.error("bad escape")),
+                    };
+                    let mut buf = [0u8; 4];
+                    result.extend_from_slice(ch.encode_utf8(&mut buf)
+                                             .as_bytes());
+                }
+                c => result.push(c),
+            }
+        }
+        String::from_utf8(result).map_err(|_| self.error("bad utf-8"))
+    }
+    fn hex4(&mut self) -> Result<u32, String> {
+        let code = self.data.get(self.pos..self.pos+4)
+            .and_then(|x| str::from_utf8(x).ok())
+            .and_then(|x| u32::from_str_radix(x, 16).ok());
+        match code {
+            Some(code) => {
+                self.pos += 4;
+                Ok(code)
+            }
+            None => Err(self.error("bad unicode escape")),
+        }
+    }
+    fn unicode_escape(&mut self) -> Result<char, String> {
+        let mut code = try!(self.hex4());
+        if code >= 0xD800 && code < 0xDC00 &&
+            self.data[self.pos..].starts_with(b"\\u")
+        {
+            self.pos += 2;
+            let low = try!(self.hex4());
+            code = 0x10000 + ((code - 0xD800) << 10) +
+                low.wrapping_sub(0xDC00);
+        }
+        ::std::char::from_u32(code)
+            .ok_or_else(|| self.error("bad unicode escape"))
+    }
+}
+
+fn write_string(f: &mut fmt::Formatter, value: &str) -> fmt::Result {
+    try!(f.write_str("\""));
+    for c in value.chars() {
+        match c {
+            '"' => try!(f.write_str("\\\"")),
+            '\\' => try!(f.write_str("\\\\")),
+            '\n' => try!(f.write_str("\\n")),
+            '\r' => try!(f.write_str("\\r")),
+            '\t' => try!(f.write_str("\\t")),
+            c if (c as u32) < 0x20 => try!(write!(f, "\\u{:04x}", c as u32)),
+            c => try!(write!(f, "{}", c)),
+        }
+    }
+    f.write_str("\"")
+}
+
+impl fmt::Display for JsonValue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            JsonValue::Null => f.write_str("null"),
+            JsonValue::Bool(x) => write!(f, "{}", x),
+            // JSON has no representation for NaN and infinity
+            JsonValue::Number(x) if !x.is_finite() => f.write_str("null"),
+            JsonValue::Number(x) => write!(f, "{}", x),
+            JsonValue::String(ref x) => write_string(f, x),
+            JsonValue::Array(ref items) => {
+                try!(f.write_str("["));
+                for (idx, item) in items.iter().enumerate() {
+                    if idx > 0 {
+                        try!(f.write_str(","));
+                    }
+                    try!(write!(f, "{}", item));
+                }
+                f.write_str("]")
+            }
+            JsonValue::Object(ref items) => {
+                try!(f.write_str("{"));
+                for (idx, (key, item)) in items.iter().enumerate() {
+                    if idx > 0 {
+                        try!(f.write_str(","));
+                    }
+                    try!(write_string(f, key));
+                    try!(write!(f, ":{}", item));
+                }
+                f.write_str("}")
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use mio::Token;
+    use trace::{EventLog, EventKind};
+    use {EventSet, Time};
+    use super::{JsonValue, ready_flags, replay_count, take_events};
+    use super::MAX_OUTPUT;
+    use super::JsonValue::*;
+
+    #[test]
+    fn parse() {
+        let value = JsonValue::parse(
+            r#" {"version": 1, "command": "stats", "args": [true, null,
+                 -1.5e1, "a\"é😀"]} "#).unwrap();
+        assert_eq!(value.get("version"), Some(&Number(1.)));
+        assert_eq!(value.get("command"), Some(&String("stats".into())));
+        assert_eq!(value.get("args"), Some(&Array(vec![
+            Bool(true), Null, Number(-15.), String("a\"é😀".into()),
+        ])));
+    }
+
+    #[test]
+    fn errors() {
+        assert!(JsonValue::parse("").is_err());
+        assert!(JsonValue::parse("[1,]").is_err());
+        assert!(JsonValue::parse("{\"a\" 1}").is_err());
+        assert!(JsonValue::parse("\"abc").is_err());
+        assert!(JsonValue::parse("1 2").is_err());
+    }
+
+    #[test]
+    fn depth() {
+        let nested = |n| format!("{}{}", "[".repeat(n), "]".repeat(n));
+        assert!(JsonValue::parse(&nested(64)).is_ok());
+        assert!(JsonValue::parse(&nested(65)).is_err());
+        assert!(JsonValue::parse(&"[".repeat(1 << 20)).is_err());
+        assert!(JsonValue::parse(&"{\"a\":".repeat(100)).is_err());
+    }
+
+    #[test]
+    fn subscribe_args() {
+        assert_eq!(replay_count(&[]), Ok(0));
+        assert_eq!(replay_count(&[Null]), Ok(0));
+        assert_eq!(replay_count(&[Number(100.)]), Ok(100));
+        assert!(replay_count(&[Number(-1.)]).is_err());
+        assert!(replay_count(&[Number(1.5)]).is_err());
+        assert!(replay_count(&[String("10".into())]).is_err());
+    }
+
+    #[test]
+    fn slow_subscriber() {
+        let mut log = EventLog::new(4);
+        let mut cursor = log.cursor(0);
+        for _ in 0..10 {
+            log.push(Time::zero(), Token(1), EventKind::Wakeup);
+        }
+        // The client has stopped reading
+        let output = vec![0u8; MAX_OUTPUT + 1];
+        assert!(take_events(&output, &mut cursor,
+                            |c| log.read(c)).is_empty());
+        assert_eq!(cursor.missed(), 0);
+        // And catches up
+        let events = take_events(&[], &mut cursor, |c| log.read(c));
+        assert_eq!(events.iter().map(|e| e.seq).collect::<Vec<_>>(),
+                   vec![6, 7, 8, 9]);
+        assert_eq!(cursor.missed(), 6);
+    }
+
+    #[test]
+    fn ready() {
+        assert_eq!(ready_flags(EventSet::readable() | EventSet::hup()),
+            Array(vec![String("readable".into()), String("hup".into())]));
+        assert_eq!(ready_flags(EventSet::empty()), Array(vec![]));
+    }
+
+    #[test]
+    fn roundtrip() {
+        let text = r#"{"a":[1,2.5,"x\n\"y\u0001"],"b":{},"c":null}"#;
+        let value = JsonValue::parse(text).unwrap();
+        assert_eq!(value.to_string(), text);
+        assert_eq!(JsonValue::parse(&value.to_string()).unwrap(), value);
+    }
+}
@@ -1,14 +1,34 @@
-use std::io;
+use std::io::{self, Read, Write};
+use std::thread::{self, JoinHandle};
+use std::error::Error;
+use std::fmt::Debug;
+use std::time::{Duration, Instant};
+use std::rc::Rc;
 
+use mio::Token;
 use mio::deprecated::EventLoop;
 use void::{Void, unreachable};
 
-use config::{create_slab, create_loop};
-use handler::{Handler, create_handler, set_timeout_opt};
-use scope::{early_scope, EarlyScope, Scope};
-use {Machine, Config, SpawnError, Timeout, Time, Response, Slab};
+use config::{create_slab, create_loop, pin_thread, validate, grow_slab};
+use config::{token_base, slab_room};
+use handler::{Handler, create_handler};
+use store::MachineStore;
+use remote::{RemoteQueue, LoopHandle, create_handle};
+use remote::{ActionQueue, ContextHandle, create_context_handle};
+use notify::{ShutdownHandle, Channel, create_shutdown_handle};
+use loop_state::{LoopState, replace_timeout, clear_pause, update_interest};
+use loop_api::loop_handle;
+use timer::Timers;
+use trace::{TraceWriter, TraceReader};
+use scope::{early_scope, run_deferred, EarlyScope, Scope, LoopScope};
+use {Machine, Config, SpawnError, LoopError, PollErrors, MigrateError};
+use {Time, Response, Slab, SharedContext, Registry, LoopLatency};
+use {Notifier, MachineMeta, Clock, SlabOccupancy};
+#[cfg(feature="machine_stats")] use MachineStats;
 use SpawnError::NoSlabSpace;
-use response::decompose;
+use response::{decompose, constructor_error, take_deferred};
+use diagnostics::{Diagnostics, Diagnostic};
+use loop_thread::{LoopThread, spawn_thread};
 
 
 /// An object that is used to construct a loop
@@ -53,78 +73,754 @@ use response::decompose;
 /// The [the guide] for more information.
 ///
 /// [the guide]: http://rotor.readthedocs.org/en/latest/loop_init.html
-pub struct LoopCreator<M: Machine> {
-    slab: Slab<(Option<(Timeout, Time)>, M)>,
-    mio: EventLoop<Handler<M>>,
+pub struct LoopCreator<M: Machine, S = Slab<Option<M>>>
+    where S: MachineStore<Option<M>>,
+{
+    slab: S,
+    mio: EventLoop<Handler<M, S>>,
+    state: LoopState,
+    timers: Timers,
+    remote: RemoteQueue<M::Seed>,
+    actions: ActionQueue<M::Context>,
 }
 /// Second stage of loop creation
 ///
 /// See the docs of `LoopCreator` or [the guide] for more information.
 ///
 /// [the guide]: http://rotor.readthedocs.org/en/latest/loop_init.html
-pub struct LoopInstance<M: Machine> {
-    mio: EventLoop<Handler<M>>,
-    handler: Handler<M>,
+pub struct LoopInstance<M: Machine, S = Slab<Option<M>>>
+    where S: MachineStore<Option<M>>,
+{
+    mio: EventLoop<Handler<M, S>>,
+    handler: Handler<M, S>,
 }
 
 impl<M: Machine> LoopCreator<M> {
     pub fn new(cfg: &Config) -> Result<LoopCreator<M>, io::Error> {
-        let slab = create_slab(&cfg);
+        LoopCreator::with_store(cfg, create_slab(cfg))
+    }
+}
+
+impl<M: Machine, S: MachineStore<Option<M>>> LoopCreator<M, S> {
+    /// Create a loop which keeps the state machines in the `slab`
+    ///
+    /// Use it to replace the default `Slab` with a different backend, see
+    /// `MachineStore`. `Config::slab_capacity` is not used in this case,
+    /// while `Config::slab_growth` still applies.
+    pub fn with_store(cfg: &Config, slab: S)
+        -> Result<LoopCreator<M, S>, io::Error>
+    {
+        try!(validate(&cfg));
+        try!(pin_thread(&cfg));
         let eloop = try!(create_loop(&cfg));
+        let mut state = LoopState::new(Channel::new_loop(eloop.channel()),
+                                       cfg);
+        state.occupancy.capacity = slab.capacity();
+        let timers = Timers::new(state.start,
+            state.time_source.clone());
         Ok(LoopCreator {
             slab: slab,
             mio: eloop,
+            state: state,
+            timers: timers,
+            remote: RemoteQueue::new(),
+            actions: ActionQueue::new(),
         })
     }
 
-    pub fn add_machine_with<F>(&mut self, fun: F) -> Result<(), SpawnError<()>>
+    /// Add the state machine created by `fun`
+    ///
+    /// Returns the notifier of the new state machine, its token is
+    /// `Notifier::token()`. Put it into the context to wire state machines
+    /// together. If `fun` returns `Response::done()` the notifier is
+    /// returned too, but `Notifier::is_alive` is false.
+    pub fn add_machine_with<F>(&mut self, fun: F)
+        -> Result<Notifier, SpawnError<()>>
         where F: FnOnce(&mut EarlyScope) -> Response<M, Void>
     {
-        let ref mut chan = self.mio.channel();
-        let ref mut mio = self.mio;
-        let res = self.slab.vacant_entry().map(|entry| {
-            let token = entry.index();
-            entry.insert({
-              let ref mut scope = early_scope(token, chan, mio);
-              let (mach, void, timeout) =  decompose(token, fun(scope));
-              void.map(|x| unreachable(x));
-              let m = mach.expect("You can't return Response::done() \
-                    from Machine::create() until new release of slab crate. \
-                    (requires insert_with_opt)");
-              let to = set_timeout_opt(timeout, scope);
-              (to, m)
-            })
+        let ref mut state = self.state;
+        let ref mut api = loop_handle(&mut self.mio, &mut self.timers,
+                                      token_base(&state.config));
+        let ref mut slab = self.slab;
+        grow_slab(slab, &state.config);
+        state.occupancy.capacity = slab.capacity();
+        let res = slab.vacancy().map(|token| {
+            // Early scope is always at the start of the loop
+            state.add(token, Time::zero());
+            let notifier = state.notifier(token);
+            let (resp, deferred) = {
+                let ref mut scope = early_scope(token, state, api);
+                take_deferred(fun(scope))
+            };
+            state.defer(token, deferred);
+            let (mach, void, timeout, yielded) = decompose(resp);
+            if let Some(x) = void.into_iter().flat_map(|x| x).next() {
+                unreachable(x)
+            }
+            let m = match mach {
+                Ok(m) => m,
+                Err(err) => {
+                    if let Some(mut meta) = state.remove(token) {
+                        clear_pause(&mut meta, api);
+                        state.deregister_tracked(&mut meta, api);
+                    }
+                    return constructor_error(token, err).map(|()| notifier);
+                }
+            };
+            if let Some(meta) = state.get_mut(token) {
+                replace_timeout(meta, token, timeout, Time::zero(), api);
+                update_interest(meta, token, m.interest(), api);
+            }
+            if yielded {
+                state.add_yielded(token);
+            }
+            slab.insert(token, Some(m));
+            let label = state.get(token).and_then(|meta| meta.label());
+            state.diagnose(Time::zero(), Diagnostic::Created {
+                token: token,
+                label: label,
+            });
+            Ok(notifier)
         });
-        if res.is_some() {
-            Ok(())
-        } else {
-            // TODO(tailhook) propagate error from state machine construtor
-            Err(NoSlabSpace(()))
+        run_deferred(state, api);
+        res.unwrap_or(Err(NoSlabSpace(())))
+    }
+
+    /// Add `n` identical state machines created by the `factory`
+    ///
+    /// The factory is called with the index of each state machine (`0` to
+    /// `n - 1`), e.g. to pick the queue of the worker. Returns the
+    /// notifiers in the same order, see `add_machine_with`.
+    ///
+    /// If the slab can't fit all `n` state machines, none is created and
+    /// `NoSlabSpace` is returned. Otherwise they are created one by one,
+    /// and if some constructor fails, the ones already created are
+    /// aborted (and removed as soon as the loop starts) and the error is
+    /// returned. Note their constructors have run by then, so undo any
+    /// side effects in `Machine::destroyed`.
+    pub fn spawn_uniform<F>(&mut self, n: usize, mut factory: F)
+        -> Result<Vec<Notifier>, SpawnError<()>>
+        where F: FnMut(usize, &mut EarlyScope) -> Response<M, Void>
+    {
+        if slab_room(&self.slab, &self.state.config) < n {
+            return Err(NoSlabSpace(()));
+        }
+        let mut notifiers = Vec::with_capacity(n);
+        for idx in 0..n {
+            match self.add_machine_with(|scope| factory(idx, scope)) {
+                Ok(notifier) => notifiers.push(notifier),
+                Err(e) => {
+                    for notifier in &notifiers {
+                        notifier.abort().ok();
+                    }
+                    return Err(e);
+                }
+            }
         }
+        Ok(notifiers)
+    }
+
+    /// Set a filter which decides which state machine errors are fatal
+    ///
+    /// The filter is called for every error returned with
+    /// `Response::error()`. If it returns true, the loop is shut down and
+    /// `run()` returns `LoopError::Machine` with the error. By default no
+    /// errors are fatal, they are just logged (with `log_errors` feature).
+    pub fn fatal_error_filter<F>(&mut self, filter: F)
+        where F: Fn(Token, &Error) -> bool + 'static
+    {
+        self.state.fatal_filter = Some(Box::new(filter));
+    }
+
+    /// Set the hook called for the state machines which haven't
+    /// acknowledged the ping of the watchdog
+    ///
+    /// See `Config::watchdog`. Without the hook such state machines are
+    /// logged (with `log_errors` feature).
+    pub fn watchdog_hook<F>(&mut self, hook: F)
+        where F: Fn(Token, &MachineMeta) + 'static
+    {
+        self.state.watchdog_hook = Some(Box::new(hook));
+    }
+
+    /// Set a filter which decides which state machines may be evicted
+    ///
+    /// Used with `SlabFull::Evict`: when a child is spawned into the full
+    /// slab, the state machine with the oldest `last_activity` accepted by
+    /// the filter is removed (with `Machine::destroyed`) to make room. The
+    /// spawning state machine itself is never evicted. Without a filter
+    /// any other state machine may be evicted, including listeners, so
+    /// it's usually set to match the connections by label.
+    pub fn eviction_filter<F>(&mut self, filter: F)
+        where F: Fn(Token, &MachineMeta) -> bool + 'static
+    {
+        self.state.eviction_filter = Some(Box::new(filter));
+    }
+
+    /// Replace the source of time of the loop, see `Clock`
+    ///
+    /// The loop time starts over from the current time of the clock, so
+    /// set the clock before adding state machines.
+    pub fn set_clock<C: Clock + 'static>(&mut self, clock: C) {
+        let clock = Rc::new(clock) as Rc<Clock>;
+        self.timers.set_clock(clock.clone());
+        self.state.start = self.timers.start();
+        self.state.time_source = clock;
+        self.state.reset_clock();
+    }
+
+    /// Install the receiver of the structured events of the loop
+    ///
+    /// See `Diagnostics` for more info.
+    pub fn set_diagnostics<D: Diagnostics + 'static>(&mut self,
+        diagnostics: D)
+    {
+        self.state.set_diagnostics(Box::new(diagnostics));
+    }
+
+    /// Set a hook which is called for every state machine error
+    ///
+    /// The hook receives the token of the state machine and the error
+    /// returned with `Response::error()`, before the error is checked by
+    /// `fatal_error_filter`. Use it to count, report or escalate errors,
+    /// which are otherwise only logged (with `log_errors` feature).
+    pub fn machine_error_hook<F>(&mut self, hook: F)
+        where F: FnMut(Token, &Error) + 'static
+    {
+        self.state.error_hook = Some(Box::new(hook));
+    }
+
+    /// Set a hook which is called on every poll error except EINTR
+    ///
+    /// Use it to log or count errors. Whether the loop continues after
+    /// the error is defined by `Config::retry_transient_poll_errors`.
+    pub fn poll_error_hook<F>(&mut self, hook: F)
+        where F: FnMut(&io::Error) + 'static
+    {
+        self.state.poll_error_hook = Some(Box::new(hook));
+    }
+
+    /// Set a hook which is called when a state machine action is slow
+    ///
+    /// The hook receives the token and the label of the state machine
+    /// and the time the action took. The threshold is set by
+    /// `Config::slow_callback_threshold`, the hook is never called
+    /// without it.
+    pub fn slow_callback_hook<F>(&mut self, hook: F)
+        where F: FnMut(Token, Option<&'static str>, Duration) + 'static
+    {
+        self.state.slow_hook = Some(Box::new(hook));
+    }
+
+    /// Record every event dispatched to the state machines to `out`
+    ///
+    /// The trace is a compact binary log of the token, kind, readiness
+    /// and loop time of the events, it's flushed at the end of every loop
+    /// iteration. Recording stops on the first write error. Read it with
+    /// `TraceReader`, or feed it back with `LoopInstance::replay`.
+    pub fn record_events<W: Write + 'static>(&mut self, out: W)
+        -> io::Result<()>
+    {
+        self.state.recorder = Some(try!(TraceWriter::new(Box::new(out))));
+        Ok(())
+    }
+
+    /// Returns a handle that may be used to spawn state machines from
+    /// other threads
+    pub fn handle(&self) -> LoopHandle<M::Seed> {
+        create_handle(&self.remote, &self.state.channel)
+    }
+
+    /// Returns a handle that may be used to stop the loop from other
+    /// threads
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        create_shutdown_handle(&self.state.channel)
     }
 
-    pub fn instantiate(self, context: M::Context) -> LoopInstance<M> {
-        let LoopCreator { slab, mio } = self;
-        let handler = create_handler(slab, context, mio.channel());
+    /// Returns a handle that may be used to change the context from
+    /// other threads
+    pub fn context_handle(&self) -> ContextHandle<M::Context> {
+        create_context_handle(&self.actions, &self.state.channel)
+    }
+
+    /// Returns the registry of the named state machines
+    ///
+    /// See `Scope::register_name`.
+    pub fn registry(&self) -> Registry {
+        self.state.registry.clone()
+    }
+
+    pub fn instantiate(self, context: M::Context) -> LoopInstance<M, S> {
+        let LoopCreator { slab, mut mio, state, timers, remote, actions } =
+            self;
+        let mut handler = create_handler(slab, context, state, timers,
+                                         remote, actions);
+        handler.start_watchdog(&mut mio);
         LoopInstance { mio: mio, handler: handler }
     }
 
-    pub fn run(self, context: M::Context) -> Result<(), io::Error> {
+    /// Run the loop with the context until it's shut down
+    ///
+    /// Returns the context back, see `LoopInstance::run`.
+    pub fn run(self, context: M::Context) -> Result<M::Context, LoopError> {
         self.instantiate(context).run()
     }
 }
 
-impl<M: Machine> LoopInstance<M> {
+impl<C, M, S> LoopCreator<M, S>
+    where M: Machine<Context=SharedContext<C>>, S: MachineStore<Option<M>>,
+{
+    /// Create a loop instance with the context shared with other threads
+    ///
+    /// Returns the instance and the handle to the context which may be
+    /// used while the loop is running.
+    pub fn instantiate_shared(self, context: C)
+        -> (LoopInstance<M, S>, SharedContext<C>)
+    {
+        let shared = SharedContext::new(context);
+        (self.instantiate(shared.clone()), shared)
+    }
+}
+
+impl<M: Machine, S: MachineStore<Option<M>>> LoopInstance<M, S> {
 
-    pub fn add_machine_with<F>(&mut self, fun: F) -> Result<(), SpawnError<()>>
+    /// Add the state machine created by `fun`
+    ///
+    /// Returns the notifier of the new state machine.
+    pub fn add_machine_with<F>(&mut self, fun: F)
+        -> Result<Notifier, SpawnError<()>>
         where F: FnOnce(&mut Scope<M::Context>) -> Response<M, Void>
     {
         self.handler.add_machine_with(&mut self.mio, fun)
     }
 
-    pub fn run(mut self) -> Result<(), io::Error> {
-        let ref mut handler = self.handler;
-        let ref mut mio = self.mio;
-        mio.run(handler)
+    /// Create a state machine from the seed
+    ///
+    /// This works the same as spawning a state machine from another one,
+    /// except that nobody is notified when `Machine::create` is finished.
+    ///
+    /// Returns the notifier of the new state machine (its token is
+    /// `Notifier::token()`), or `None` if `Machine::create` has returned
+    /// `Response::done()`.
+    pub fn add_seed(&mut self, seed: M::Seed)
+        -> Result<Option<Notifier>, SpawnError<M::Seed>>
+    {
+        self.handler.add_seed(&mut self.mio, seed)
+    }
+
+    /// Remove the state machine from the loop converting it into a seed
+    ///
+    /// See `Machine::freeze` and `LoopHandle::migrate`. The seed may be
+    /// passed to `thaw` of another loop.
+    pub fn freeze(&mut self, token: Token) -> Result<M::Seed, MigrateError>
+    {
+        self.handler.freeze(&mut self.mio, token)
+    }
+
+    /// Create a state machine from the seed returned by `freeze`
+    pub fn thaw(&mut self, seed: M::Seed) -> Result<(), SpawnError<M::Seed>>
+    {
+        self.handler.thaw(&mut self.mio, seed)
+    }
+
+    /// Replace the group of state machines with the new ones
+    ///
+    /// See `LoopHandle::replace_group` for more info.
+    pub fn replace_group(&mut self, label: &'static str, seeds: Vec<M::Seed>)
+        -> Vec<Result<(), SpawnError<M::Seed>>>
+    {
+        self.handler.replace_group(&mut self.mio, label, seeds)
+    }
+
+    /// Set a filter which decides which state machine errors are fatal
+    ///
+    /// See `LoopCreator::fatal_error_filter` for more info.
+    pub fn fatal_error_filter<F>(&mut self, filter: F)
+        where F: Fn(Token, &Error) -> bool + 'static
+    {
+        self.handler.set_fatal_filter(Box::new(filter));
+    }
+
+    /// Set the hook called for the state machines which haven't
+    /// acknowledged the ping of the watchdog
+    ///
+    /// See `LoopCreator::watchdog_hook` for more info.
+    pub fn watchdog_hook<F>(&mut self, hook: F)
+        where F: Fn(Token, &MachineMeta) + 'static
+    {
+        self.handler.set_watchdog_hook(Box::new(hook));
+    }
+
+    /// Set a filter which decides which state machines may be evicted
+    ///
+    /// See `LoopCreator::eviction_filter` for more info.
+    pub fn eviction_filter<F>(&mut self, filter: F)
+        where F: Fn(Token, &MachineMeta) -> bool + 'static
+    {
+        self.handler.set_eviction_filter(Box::new(filter));
+    }
+
+    /// Install the receiver of the structured events of the loop
+    ///
+    /// See `Diagnostics` for more info.
+    pub fn set_diagnostics<D: Diagnostics + 'static>(&mut self,
+        diagnostics: D)
+    {
+        self.handler.set_diagnostics(Box::new(diagnostics));
+    }
+
+    /// Set a hook which is called for every state machine error
+    ///
+    /// See `LoopCreator::machine_error_hook` for more info.
+    pub fn machine_error_hook<F>(&mut self, hook: F)
+        where F: FnMut(Token, &Error) + 'static
+    {
+        self.handler.set_error_hook(Box::new(hook));
+    }
+
+    /// Set a hook which is called on every poll error except EINTR
+    ///
+    /// See `LoopCreator::poll_error_hook` for more info.
+    pub fn poll_error_hook<F>(&mut self, hook: F)
+        where F: FnMut(&io::Error) + 'static
+    {
+        self.handler.set_poll_error_hook(Box::new(hook));
+    }
+
+    /// Record every event dispatched to the state machines to `out`
+    ///
+    /// See `LoopCreator::record_events` for more info.
+    pub fn record_events<W: Write + 'static>(&mut self, out: W)
+        -> io::Result<()>
+    {
+        self.handler.set_recorder(try!(TraceWriter::new(Box::new(out))));
+        Ok(())
+    }
+
+    /// Dispatch the events of the trace to the state machines
+    ///
+    /// Every event is delivered to the state machine with the recorded
+    /// token at the recorded loop time, without polling. Set the loop up
+    /// the same way as the recorded one (the same seeds added in the same
+    /// order, so the tokens match), and replace the sockets with something
+    /// like `SimStream`: the data the machines read is not in the trace,
+    /// only the events. Events for the missing tokens are skipped.
+    ///
+    /// Returns the number of events replayed.
+    pub fn replay<R: Read>(&mut self, input: R) -> io::Result<u64> {
+        let mut count = 0;
+        for event in try!(TraceReader::new(input)) {
+            let event = try!(event);
+            self.handler.replay_event(&mut self.mio, &event);
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    /// Set a hook which is called when a state machine action is slow
+    ///
+    /// See `LoopCreator::slow_callback_hook` for more info.
+    pub fn slow_callback_hook<F>(&mut self, hook: F)
+        where F: FnMut(Token, Option<&'static str>, Duration) + 'static
+    {
+        self.handler.set_slow_callback_hook(Box::new(hook));
+    }
+
+    /// Statistics of all the state machines of the loop
+    #[cfg(feature="machine_stats")]
+    pub fn machine_stats(&self) -> Vec<(Token, MachineStats)> {
+        self.handler.machine_stats()
+    }
+
+    /// Set a serializer of state machines for the loop snapshots
+    ///
+    /// The function must write a single JSON value describing the state
+    /// machine. Without the serializer state machines are written as
+    /// `null` in the snapshot.
+    pub fn set_snapshot_serializer<F>(&mut self, fun: F)
+        where F: FnMut(&M, &mut io::Write) -> io::Result<()> + 'static
+    {
+        self.handler.set_snapshot_serializer(Box::new(fun));
+    }
+
+    /// Set a hook which is called right before the loop blocks in poll
+    ///
+    /// Useful for flushing the writes batched in the context during the
+    /// loop iteration, or other maintenance. State machines woken from the
+    /// hook (e.g. with a `Notifier`) are run on the next iteration.
+    pub fn before_poll_hook<F>(&mut self, hook: F)
+        where F: FnMut(&mut M::Context) + 'static
+    {
+        self.handler.set_before_poll(Box::new(hook));
+    }
+
+    /// Set a hook which is called after poll returns
+    ///
+    /// The hook receives the number of I/O events returned by poll. It's
+    /// called before the ready events are dispatched to the state
+    /// machines, but after wakeups and timers of the iteration.
+    pub fn after_poll_hook<F>(&mut self, hook: F)
+        where F: FnMut(&mut M::Context, usize) + 'static
+    {
+        self.handler.set_after_poll(Box::new(hook));
+    }
+
+    /// Call `fun` every `interval` until it cancels itself
+    ///
+    /// For periodic work of the loop itself, e.g. flushing statistics or
+    /// cleaning up the context, which doesn't need a state machine (and
+    /// a slot in the slab) of its own. The timer is cancelled or
+    /// rescheduled with the `LoopScope`. It's fired along with the timers
+    /// of the state machines, after the ones expiring at the same
+    /// millisecond. Timers don't keep the loop running when there are no
+    /// state machines left.
+    ///
+    /// ```ignore
+    /// inst.add_loop_timer(Duration::from_secs(10), |ctx, scope| {
+    ///     ctx.stats.flush(scope.now());
+    /// });
+    /// ```
+    pub fn add_loop_timer<F>(&mut self, interval: Duration, fun: F)
+        where F: FnMut(&mut M::Context, &mut LoopScope) + 'static
+    {
+        self.handler.add_loop_timer(&mut self.mio, interval, Box::new(fun));
+    }
+
+    /// Write a snapshot of the loop as a JSON object
+    ///
+    /// The snapshot contains the current time, slab occupancy, the number
+    /// of queued seeds and timers, and for every state machine its token,
+    /// metadata and the state written by the serializer. All times are in
+    /// milliseconds since the start of the loop. Useful to inspect a stuck
+    /// server after the fact; see also `LoopHandle::snapshot`.
+    pub fn write_snapshot(&mut self, out: &mut io::Write) -> io::Result<()> {
+        self.handler.write_snapshot(out)
+    }
+
+    /// Counters of poll errors since the loop is created
+    pub fn poll_errors(&self) -> PollErrors {
+        self.handler.poll_errors()
+    }
+
+    /// Number of notifications dropped because the queue of the loop was
+    /// full, see `FullPolicy`
+    pub fn dropped_wakeups(&self) -> u64 {
+        self.handler.dropped_wakeups()
+    }
+
+    /// The seed of the random dispatch order
+    ///
+    /// Returns `None` unless `Config::shuffle_dispatch` is enabled. Print
+    /// it when the test fails to reproduce the order.
+    pub fn shuffle_seed(&self) -> Option<u64> {
+        self.handler.shuffle_seed()
+    }
+
+    /// Latency histograms of the loop
+    ///
+    /// Returns `None` unless `Config::latency_histograms` is enabled.
+    pub fn latency(&self) -> Option<LoopLatency> {
+        self.handler.latency()
+    }
+
+    /// Number of state machines, the capacity and the high-water mark
+    pub fn slab_occupancy(&self) -> SlabOccupancy {
+        self.handler.slab_occupancy()
+    }
+
+    /// Returns a handle that may be used to spawn state machines from
+    /// other threads
+    pub fn handle(&self) -> LoopHandle<M::Seed> {
+        self.handler.remote_handle()
+    }
+
+    /// Returns a handle that may be used to stop the loop from other
+    /// threads
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.handler.shutdown_handle()
     }
+
+    /// Returns a handle that may be used to change the context from
+    /// other threads
+    pub fn context_handle(&self) -> ContextHandle<M::Context> {
+        self.handler.context_handle()
+    }
+
+    /// Returns the registry of the named state machines
+    ///
+    /// See `Scope::register_name`.
+    pub fn registry(&self) -> Registry {
+        self.handler.registry()
+    }
+
+    /// Run the loop until it's shut down
+    ///
+    /// Returns the context back when the loop is finished, or an error
+    /// if a state machine has returned a fatal error.
+    pub fn run(mut self) -> Result<M::Context, LoopError> {
+        while self.mio.is_running() {
+            try!(self.poll(None));
+        }
+        self.handler.destroy_all(&mut self.mio);
+        try!(self.check_fatal());
+        Ok(self.handler.into_context())
+    }
+
+    fn poll(&mut self, timeout: Option<Duration>) -> Result<(), LoopError> {
+        let timeout = self.handler.poll_timeout(timeout);
+        // The time may be read outside of the loop iteration, e.g. by
+        // `add_machine_with`, so the poll always starts with a fresh clock
+        self.handler.reset_clock();
+        self.handler.before_poll();
+        match self.mio.run_once(&mut self.handler, timeout) {
+            Ok(()) => Ok(()),
+            Err(e) => {
+                if self.handler.poll_error(&e) {
+                    Ok(())
+                } else {
+                    Err(LoopError::Io(e))
+                }
+            }
+        }
+    }
+
+    fn check_fatal(&mut self) -> Result<(), LoopError> {
+        match self.handler.take_fatal_error() {
+            Some((token, err)) => Err(LoopError::Machine(token, err)),
+            None => Ok(()),
+        }
+    }
+
+    /// Run a single iteration of the loop
+    ///
+    /// Blocks in the poll for at most `timeout` (and at most
+    /// `Config::max_poll_timeout`), then dispatches the events. Returns
+    /// right away if the loop is shut down. Use it to drive the loop from
+    /// the main loop of the application, e.g. between the steps of a
+    /// simulation.
+    pub fn run_once(&mut self, timeout: Option<Duration>)
+        -> Result<(), LoopError>
+    {
+        if self.mio.is_running() {
+            try!(self.poll(timeout));
+        }
+        self.check_fatal()
+    }
+
+    /// Run the loop until the predicate returns true
+    ///
+    /// The predicate is checked after each poll iteration. The method also
+    /// returns when the loop is shut down (e.g. when there are no state
+    /// machines left). The loop may be continued by calling any of the run
+    /// methods again.
+    pub fn run_until<F>(&mut self, mut predicate: F)
+        -> Result<(), LoopError>
+        where F: FnMut(&M::Context) -> bool
+    {
+        while self.mio.is_running() {
+            try!(self.poll(None));
+            if predicate(self.handler.context()) {
+                break;
+            }
+        }
+        self.check_fatal()
+    }
+
+    /// Run the loop for the specified duration
+    ///
+    /// Returns earlier if the loop is shut down. The loop may be continued
+    /// by calling any of the run methods again.
+    pub fn run_for(&mut self, duration: Duration) -> Result<(), LoopError> {
+        let deadline = Instant::now() + duration;
+        while self.mio.is_running() {
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+            try!(self.poll(Some(deadline.duration_since(now))));
+        }
+        self.check_fatal()
+    }
+}
+
+impl<M, S> LoopInstance<M, S>
+    where M: Machine + 'static,
+          M::Context: Send + 'static,
+          M::Seed: Send + 'static,
+          S: MachineStore<Option<M>> + 'static,
+{
+    /// Run the loop in a new thread
+    ///
+    /// The loop isn't `Send` (neither are the state machines, usually),
+    /// so it's created in the new thread by `build`. The call returns when
+    /// the loop is created, with the handles to spawn state machines,
+    /// change the context and stop the loop, and `LoopThread::join` to
+    /// get the context back when the loop is finished. The error of
+    /// `build` is returned from here.
+    pub fn spawn_thread<F>(build: F) -> io::Result<LoopThread<M>>
+        where F: FnOnce() -> io::Result<LoopInstance<M, S>> + Send + 'static
+    {
+        spawn_thread(build)
+    }
+}
+
+impl<M, S> LoopInstance<M, S>
+    where M: Machine + Debug, S: MachineStore<Option<M>>,
+{
+    /// Returns a debug dump of all the state machines
+    ///
+    /// The dump has a line per state machine with the token, label,
+    /// creation and activity time, pending deadline, whether reading is
+    /// paused, and the `Debug` representation of the state machine.
+    pub fn dump_machines(&self) -> String {
+        self.handler.dump_machines()
+    }
+
+    /// Allow `LoopHandle::dump_machines` requests
+    ///
+    /// Dumping the state may expose sensitive data (e.g. buffers of the
+    /// connections), so it's disabled by default.
+    pub fn enable_remote_dump(&mut self) {
+        self.handler.enable_dump();
+    }
+}
+
+/// Starts a loop in a new thread, see `Scope::fork_loop`
+#[doc(hidden)]
+pub fn fork_loop<M>(cfg: Config, context: M::Context, seeds: Vec<M::Seed>)
+    -> io::Result<JoinHandle<Option<M::Context>>>
+    where M: Machine + 'static,
+          M::Context: Send + 'static,
+          M::Seed: Send + 'static,
+{
+    thread::Builder::new()
+    .name(String::from("rotor-fork"))
+    .spawn(move || {
+        let creator = match LoopCreator::<M>::new(&cfg) {
+            Ok(creator) => creator,
+            Err(e) => {
+                if cfg!(feature = "log_errors") {
+                    warn!("Can't create forked loop: {}", e);
+                }
+                return None;
+            }
+        };
+        let mut inst = creator.instantiate(context);
+        for seed in seeds {
+            if let Err(e) = inst.add_seed(seed) {
+                if cfg!(feature = "log_errors") {
+                    warn!("Can't create machine in forked loop: {}", e);
+                }
+            }
+        }
+        match inst.run() {
+            Ok(context) => Some(context),
+            Err(e) => {
+                if cfg!(feature = "log_errors") {
+                    warn!("Forked loop exited with error: {}", e);
+                }
+                None
+            }
+        }
+    })
 }
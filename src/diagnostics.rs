@@ -0,0 +1,95 @@
+use std::error::Error;
+use std::time::Duration;
+
+use mio::Token;
+
+use {Time};
+use loop_time::millis;
+
+
+/// A structured event of the loop, see `Diagnostics`
+#[derive(Debug)]
+pub enum Diagnostic<'a> {
+    /// State machine is created and inserted into the loop
+    Created {
+        token: Token,
+        label: Option<&'static str>,
+    },
+    /// State machine is removed from the loop
+    ///
+    /// Either it has returned `Response::done()` or `Response::error()`,
+    /// or it's destroyed on shutdown, or moved to another loop.
+    Removed {
+        token: Token,
+        label: Option<&'static str>,
+    },
+    /// Creating a state machine spawned by `parent` has failed
+    ///
+    /// The `reason` is the description of the `SpawnError`. The error is
+    /// passed to the `spawn_error` of the parent afterwards.
+    SpawnFailed {
+        parent: Token,
+        reason: &'a str,
+        error: Option<&'a Error>,
+    },
+    /// The deadline of the state machine is set, changed or cleared
+    DeadlineChanged {
+        token: Token,
+        deadline: Option<Time>,
+    },
+    /// State machine has returned `Response::error()`
+    Error {
+        token: Token,
+        error: &'a Error,
+    },
+    /// The listener is out of file descriptors (`EMFILE` or `ENFILE`)
+    ///
+    /// Accepting is paused for the `delay`, which grows while the error
+    /// repeats, see `Accept::with_backoff`.
+    FdLimit {
+        token: Token,
+        error: &'a Error,
+        delay: Duration,
+    },
+}
+
+/// A receiver of the structured events of the loop
+///
+/// Installed with `Loop::set_diagnostics`. Useful to integrate with the
+/// structured logging or metrics libraries. When no diagnostics are
+/// installed, errors of the state machines are logged with the `log`
+/// crate (with `log_errors` feature) and spawn failures at debug level.
+pub trait Diagnostics {
+    fn event(&mut self, time: Time, event: Diagnostic);
+    /// Called when installed into the loop with its `Scope::loop_id`
+    ///
+    /// So the events may be attributed to the loop when the diagnostics
+    /// of multiple loops are written to the same place.
+    fn attach(&mut self, loop_id: &str) {
+        let _ = loop_id;
+    }
+}
+
+/// The default diagnostics, when none are installed
+#[doc(hidden)]
+pub fn log_event(loop_id: &str, event: Diagnostic) {
+    match event {
+        Diagnostic::Error { token, error } => {
+            if cfg!(feature = "log_errors") {
+                warn!("{}: State machine {:?} exited with error: {}",
+                    loop_id, token, error);
+            }
+        }
+        Diagnostic::FdLimit { token, error, delay } => {
+            if cfg!(feature = "log_errors") {
+                warn!("{}: Listener {:?} can't accept: {}, retrying in {}ms",
+                    loop_id, token, error, millis(delay));
+            }
+        }
+        Diagnostic::SpawnFailed { parent, reason, .. } => {
+            debug!("{}: State machine {:?} failed to spawn: {}",
+                loop_id, parent, reason);
+        }
+        _ => {}
+    }
+}
@@ -0,0 +1,542 @@
+use std::io;
+use std::str;
+use std::rc::Rc;
+use std::cell::RefCell;
+use std::marker::PhantomData;
+use std::collections::{HashMap, VecDeque};
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+
+use mio::udp::UdpSocket;
+use void::Void;
+
+use {Machine, Scope, EarlyScope, Response, EventSet, PollOpt, Time};
+use {Notifier, Promise, Answer};
+
+
+/// Time to wait for the reply before retrying the query
+const QUERY_TIMEOUT_MS: u64 = 2000;
+/// Number of times the query is sent before giving up
+const ATTEMPTS: u32 = 3;
+
+const TYPE_A: u16 = 1;
+const TYPE_AAAA: u16 = 28;
+const CLASS_IN: u16 = 1;
+
+quick_error! {
+    /// Error resolving the name
+    #[derive(Debug)]
+    pub enum ResolveError {
+        /// I/O error on the resolver socket
+        Io(err: io::Error) {
+            description("I/O error when resolving the name")
+            display("I/O error when resolving the name: {}", err)
+            from()
+        }
+        /// The name has no addresses of the requested type
+        NotFound {
+            description("name not found")
+        }
+        /// No name server has replied in time
+        Timeout {
+            description("timeout resolving the name")
+        }
+        /// The name can't be put into the query
+        BadName {
+            description("invalid domain name")
+        }
+        /// Name server has returned an error code
+        ServerFailure(code: u8) {
+            description("name server failure")
+            display("name server failure, code {}", code)
+        }
+        /// The resolver state machine is not running
+        Closed {
+            description("resolver is not running")
+        }
+    }
+}
+
+/// Result of the name resolution
+pub type ResolveResult = Result<Vec<IpAddr>, ResolveError>;
+
+struct Query {
+    name: String,
+    qtype: u16,
+    answer: Answer<ResolveResult>,
+    attempts: u32,
+    deadline: Time,
+}
+
+struct Queue {
+    new: VecDeque<Query>,
+    sent: HashMap<u16, Query>,
+    ids: RandomState,
+    counter: u64,
+    closed: bool,
+}
+
+/// A handle to the resolver state machine
+///
+/// Usually kept in the context. Created together with the state machine
+/// by `ResolverMachine::new`. Requests are answered via promises, so the
+/// requesting state machine receives a `wakeup` when the name is resolved.
+#[derive(Clone)]
+pub struct Resolver {
+    queue: Rc<RefCell<Queue>>,
+    notifier: Notifier,
+}
+
+/// A stub resolver state machine
+///
+/// Sends queries over UDP to recursive name servers (see
+/// `system_name_servers`), retrying on timeout and rotating the servers.
+pub struct ResolverMachine<C> {
+    sock: UdpSocket,
+    servers: Vec<SocketAddr>,
+    queue: Rc<RefCell<Queue>>,
+    writable: bool,
+    phantom: PhantomData<*const C>,
+}
+
+impl Resolver {
+    /// Resolve the name to IPv4 addresses
+    pub fn resolve<C>(&self, scope: &mut Scope<C>, name: &str)
+        -> Promise<ResolveResult>
+    {
+        self.query(scope, name, TYPE_A)
+    }
+    /// Resolve the name to IPv6 addresses
+    pub fn resolve_v6<C>(&self, scope: &mut Scope<C>, name: &str)
+        -> Promise<ResolveResult>
+    {
+        self.query(scope, name, TYPE_AAAA)
+    }
+    fn query<C>(&self, scope: &mut Scope<C>, name: &str, qtype: u16)
+        -> Promise<ResolveResult>
+    {
+        let (promise, answer) = scope.promise();
+        if let Ok(ip) = name.parse::<IpAddr>() {
+            answer.send(Ok(vec![ip]));
+            return promise;
+        }
+        let mut queue = self.queue.borrow_mut();
+        if queue.closed {
+            answer.send(Err(ResolveError::Closed));
+            return promise;
+        }
+        queue.new.push_back(Query {
+            name: name.to_string(),
+            qtype: qtype,
+            answer: answer,
+            attempts: 0,
+            deadline: scope.now(),
+        });
+        // The query is sent anyway when anything else wakes the resolver
+        self.notifier.wakeup().ok();
+        promise
+    }
+}
+
+/// Returns name servers listed in `/etc/resolv.conf`
+#[cfg(unix)]
+pub fn system_name_servers() -> io::Result<Vec<SocketAddr>> {
+    use std::fs::File;
+    use std::io::Read;
+
+    let mut data = String::new();
+    try!(try!(File::open("/etc/resolv.conf")).read_to_string(&mut data));
+    Ok(data.lines().filter_map(|line| {
+        let mut words = line.split_whitespace();
+        match (words.next(), words.next()) {
+            (Some("nameserver"), Some(addr)) => {
+                addr.parse().ok().map(|ip| SocketAddr::new(ip, 53))
+            }
+            _ => None,
+        }
+    }).collect())
+}
+
+impl<C> ResolverMachine<C> {
+    /// Create a resolver state machine and a handle to it
+    pub fn new(servers: Vec<SocketAddr>, scope: &mut EarlyScope)
+        -> (Response<ResolverMachine<C>, Void>, Resolver)
+    {
+        let queue = Rc::new(RefCell::new(Queue {
+            new: VecDeque::new(),
+            sent: HashMap::new(),
+            ids: RandomState::new(),
+            counter: 0,
+            closed: false,
+        }));
+        let resolver = Resolver {
+            queue: queue.clone(),
+            notifier: scope.notifier(),
+        };
+        if servers.is_empty() {
+            queue.borrow_mut().closed = true;
+            return (Response::error(Box::new(io::Error::new(
+                io::ErrorKind::InvalidInput, "no name servers"))), resolver);
+        }
+        let bind = if servers[0].is_ipv4() {
+            "0.0.0.0:0".parse().unwrap()
+        } else {
+            "[::]:0".parse().unwrap()
+        };
+        let sock = match UdpSocket::bind(&bind) {
+            Ok(sock) => sock,
+            Err(e) => {
+                queue.borrow_mut().closed = true;
+                return (Response::error(Box::new(e)), resolver);
+            }
+        };
+        if let Err(e) = scope.register(&sock, EventSet::readable(),
+                                       PollOpt::level())
+        {
+            queue.borrow_mut().closed = true;
+            return (Response::error(Box::new(e)), resolver);
+        }
+        let machine = ResolverMachine {
+            sock: sock,
+            servers: servers,
+            queue: queue,
+            writable: false,
+            phantom: PhantomData,
+        };
+        (Response::ok(machine), resolver)
+    }
+
+    fn send_queries(&mut self, now: Time) {
+        let mut queue = self.queue.borrow_mut();
+        while let Some(mut query) = queue.new.pop_front() {
+            // Random ids make spoofing replies harder
+            let id = loop {
+                let mut hasher = queue.ids.build_hasher();
+                hasher.write_u64(queue.counter);
+                queue.counter += 1;
+                let id = hasher.finish() as u16;
+                if !queue.sent.contains_key(&id) {
+                    break id;
+                }
+            };
+            let packet = match encode_query(id, &query.name, query.qtype) {
+                Some(packet) => packet,
+                None => {
+                    query.answer.send(Err(ResolveError::BadName));
+                    continue;
+                }
+            };
+            let server = self.servers[
+                query.attempts as usize % self.servers.len()];
+            match self.sock.send_to(&packet, &server) {
+                Ok(_) => {}
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    queue.new.push_front(query);
+                    self.writable = true;
+                    return;
+                }
+                Err(e) => {
+                    query.answer.send(Err(ResolveError::Io(e)));
+                    continue;
+                }
+            }
+            query.attempts += 1;
+            query.deadline = now + Duration::from_millis(QUERY_TIMEOUT_MS);
+            queue.sent.insert(id, query);
+        }
+        self.writable = false;
+    }
+
+    fn receive(&mut self) -> io::Result<()> {
+        let mut buf = [0u8; 4096];
+        loop {
+            let (len, addr) = match self.sock.recv_from(&mut buf) {
+                Ok(x) => x,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    return Ok(());
+                }
+                Err(e) => return Err(e),
+            };
+            if !self.servers.contains(&addr) {
+                continue;
+            }
+            let (id, result) = match parse_response(&buf[..len]) {
+                Some(x) => x,
+                None => continue,
+            };
+            let mut queue = self.queue.borrow_mut();
+            let matches = queue.sent.get(&id)
+                .map(|q| result.qtype == q.qtype &&
+                         result.name.eq_ignore_ascii_case(
+                            q.name.trim_end_matches('.')))
+                .unwrap_or(false);
+            if matches {
+                let query = queue.sent.remove(&id).unwrap();
+                query.answer.send(result.addresses);
+            }
+        }
+    }
+
+    fn expire(&mut self, now: Time) {
+        let mut queue = self.queue.borrow_mut();
+        let expired: Vec<u16> = queue.sent.iter()
+            .filter(|&(_, q)| q.deadline <= now)
+            .map(|(&id, _)| id)
+            .collect();
+        for id in expired {
+            let query = queue.sent.remove(&id).unwrap();
+            if query.attempts >= ATTEMPTS {
+                query.answer.send(Err(ResolveError::Timeout));
+            } else {
+                queue.new.push_back(query);
+            }
+        }
+    }
+
+    fn action(mut self, scope: &mut Scope<C>) -> Response<Self, Void> {
+        self.send_queries(scope.now());
+        let interest = if self.writable {
+            EventSet::readable() | EventSet::writable()
+        } else {
+            EventSet::readable()
+        };
+        if let Err(e) = scope.reregister(&self.sock, interest,
+                                         PollOpt::level())
+        {
+            return Response::error(Box::new(e));
+        }
+        let deadline = self.queue.borrow().sent.values()
+            .map(|q| q.deadline).min();
+        match deadline {
+            Some(deadline) => Response::ok(self).deadline(deadline),
+            None => Response::ok(self),
+        }
+    }
+}
+
+impl<C> Drop for ResolverMachine<C> {
+    fn drop(&mut self) {
+        // Pending answers are dropped, so requesters see abandoned promises
+        let mut queue = self.queue.borrow_mut();
+        queue.closed = true;
+        queue.new.clear();
+        queue.sent.clear();
+    }
+}
+
+impl<C> Machine for ResolverMachine<C> {
+    type Context = C;
+    type Seed = Void;
+
+    fn create(seed: Void, _scope: &mut Scope<C>) -> Response<Self, Void> {
+        ::void::unreachable(seed)
+    }
+    fn ready(mut self, events: EventSet, scope: &mut Scope<C>)
+        -> Response<Self, Void>
+    {
+        if events.is_readable() {
+            if let Err(e) = self.receive() {
+                return Response::error(Box::new(e));
+            }
+        }
+        self.action(scope)
+    }
+    fn spawned(self, _scope: &mut Scope<C>) -> Response<Self, Void> {
+        unreachable!();
+    }
+    fn timeout(mut self, scope: &mut Scope<C>) -> Response<Self, Void> {
+        self.expire(scope.now());
+        self.action(scope)
+    }
+    fn wakeup(self, scope: &mut Scope<C>) -> Response<Self, Void> {
+        self.action(scope)
+    }
+}
+
+struct Reply {
+    name: String,
+    qtype: u16,
+    addresses: ResolveResult,
+}
+
+fn encode_query(id: u16, name: &str, qtype: u16) -> Option<Vec<u8>> {
+    let mut buf = Vec::with_capacity(name.len() + 18);
+    buf.extend_from_slice(&[
+        (id >> 8) as u8, id as u8,
+        0x01, 0x00,  // recursion desired
+        0, 1,  // one question
+        0, 0, 0, 0, 0, 0,
+    ]);
+    let name = name.trim_end_matches('.');
+    if name.is_empty() || name.len() > 253 {
+        return None;
+    }
+    for label in name.split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return None;
+        }
+        buf.push(label.len() as u8);
+        buf.extend_from_slice(label.as_bytes());
+    }
+    buf.push(0);
+    buf.extend_from_slice(&[
+        (qtype >> 8) as u8, qtype as u8,
+        (CLASS_IN >> 8) as u8, CLASS_IN as u8,
+    ]);
+    Some(buf)
+}
+
+macro_rules! opt {
+    ($e:expr) => {
+        match $e {
+            Some(x) => x,
+            None => return None,
+        }
+    }
+}
+
+fn read_u16(data: &[u8], pos: usize) -> Option<u16> {
+    if pos + 2 > data.len() {
+        return None;
+    }
+    Some(((data[pos] as u16) << 8) | data[pos+1] as u16)
+}
+
+/// Skips the (possibly compressed) name, returns the position after it
+fn skip_name(data: &[u8], mut pos: usize) -> Option<usize> {
+    loop {
+        let len = *opt!(data.get(pos));
+        if len & 0xC0 == 0xC0 {
+            return if pos + 2 <= data.len() { Some(pos + 2) } else { None };
+        } else if len == 0 {
+            return Some(pos + 1);
+        } else {
+            pos += 1 + len as usize;
+        }
+    }
+}
+
+/// Reads the uncompressed name of the question
+fn read_name(data: &[u8], mut pos: usize) -> Option<(String, usize)> {
+    let mut name = String::new();
+    loop {
+        let len = *opt!(data.get(pos)) as usize;
+        if len == 0 {
+            return Some((name, pos + 1));
+        }
+        if len & 0xC0 != 0 || pos + 1 + len > data.len() {
+            return None;
+        }
+        if !name.is_empty() {
+            name.push('.');
+        }
+        name.push_str(opt!(str::from_utf8(&data[pos+1..pos+1+len]).ok()));
+        pos += 1 + len;
+    }
+}
+
+fn parse_response(data: &[u8]) -> Option<(u16, Reply)> {
+    let id = opt!(read_u16(data, 0));
+    let flags = opt!(read_u16(data, 2));
+    if flags & 0x8000 == 0 {
+        // not a response
+        return None;
+    }
+    let qdcount = opt!(read_u16(data, 4));
+    let ancount = opt!(read_u16(data, 6));
+    if qdcount != 1 {
+        return None;
+    }
+    let (name, pos) = opt!(read_name(data, 12));
+    let qtype = opt!(read_u16(data, pos));
+    let mut pos = pos + 4;
+    let rcode = (flags & 0x000F) as u8;
+    let addresses = match rcode {
+        0 => {
+            let mut addresses = Vec::new();
+            for _ in 0..ancount {
+                pos = opt!(skip_name(data, pos));
+                let rtype = opt!(read_u16(data, pos));
+                let rdlen = opt!(read_u16(data, pos + 8)) as usize;
+                let start = pos + 10;
+                if start + rdlen > data.len() {
+                    return None;
+                }
+                let rdata = &data[start..start+rdlen];
+                match (rtype, rdlen) {
+                    (TYPE_A, 4) => {
+                        addresses.push(IpAddr::V4(Ipv4Addr::new(
+                            rdata[0], rdata[1], rdata[2], rdata[3])));
+                    }
+                    (TYPE_AAAA, 16) => {
+                        let mut seg = [0u16; 8];
+                        for i in 0..8 {
+                            seg[i] = ((rdata[i*2] as u16) << 8) |
+                                rdata[i*2+1] as u16;
+                        }
+                        addresses.push(IpAddr::V6(Ipv6Addr::new(
+                            seg[0], seg[1], seg[2], seg[3],
+                            seg[4], seg[5], seg[6], seg[7])));
+                    }
+                    // CNAMEs are followed by the recursive server, so
+                    // final addresses are in the same reply
+                    _ => {}
+                }
+                pos = start + rdlen;
+            }
+            if addresses.is_empty() {
+                Err(ResolveError::NotFound)
+            } else {
+                Ok(addresses)
+            }
+        }
+        3 => Err(ResolveError::NotFound),
+        code => Err(ResolveError::ServerFailure(code)),
+    };
+    Some((id, Reply { name: name, qtype: qtype, addresses: addresses }))
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::IpAddr;
+    use super::{encode_query, parse_response, ResolveError, TYPE_A};
+
+    #[test]
+    fn query() {
+        assert_eq!(encode_query(0x1234, "ab.c.", TYPE_A).unwrap(), vec![
+            0x12, 0x34, 1, 0, 0, 1, 0, 0, 0, 0, 0, 0,
+            2, b'a', b'b', 1, b'c', 0,
+            0, 1, 0, 1]);
+        assert!(encode_query(1, "a..b", TYPE_A).is_none());
+        assert!(encode_query(1, "", TYPE_A).is_none());
+    }
+
+    #[test]
+    fn response() {
+        let mut data = encode_query(7, "ab.c", TYPE_A).unwrap();
+        data[2] = 0x81;
+        data[3] = 0x80;
+        data[7] = 2;
+        // CNAME pointing to the question name, then an A record
+        data.extend_from_slice(&[0xC0, 12, 0, 5, 0, 1, 0, 0, 0, 60, 0, 2,
+                                 0xC0, 12]);
+        data.extend_from_slice(&[0xC0, 12, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4,
+                                 10, 0, 0, 1]);
+        let (id, reply) = parse_response(&data).unwrap();
+        assert_eq!(id, 7);
+        assert_eq!(reply.name, "ab.c");
+        assert_eq!(reply.qtype, TYPE_A);
+        assert_eq!(reply.addresses.unwrap(),
+                   vec!["10.0.0.1".parse::<IpAddr>().unwrap()]);
+        // truncated
+        assert!(parse_response(&data[..data.len()-1]).is_none());
+        // NXDOMAIN
+        data[3] = 0x83;
+        match parse_response(&data).unwrap().1.addresses {
+            Err(ResolveError::NotFound) => {}
+            x => panic!("unexpected {:?}", x),
+        }
+    }
+}
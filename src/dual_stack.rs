@@ -0,0 +1,54 @@
+use std::io;
+use std::net::{SocketAddr, Ipv4Addr, Ipv6Addr};
+
+use mio::tcp::TcpListener;
+
+
+/// Bind listening sockets accepting both IPv4 and IPv6 connections on the
+/// `port` of all the interfaces
+///
+/// Returns one socket when `[::]` accepts IPv4 connections too (the
+/// default on Linux), two sockets when the system binds IPv6 only (e.g.
+/// BSD or Windows, or `net.ipv6.bindv6only` is set), and the IPv4 socket
+/// alone when IPv6 is not available. Run an `Accept` state machine for
+/// every socket with the same seed constructor:
+///
+/// ```ignore
+/// for sock in try!(bind_dual_stack(8080)) {
+///     loop_creator.add_machine_with(|scope| {
+///         Accept::new(sock, Seed::new, scope)
+///     }).expect("listener added");
+/// }
+/// ```
+///
+/// If `port` is zero, the port chosen for the IPv6 socket is used for the
+/// IPv4 one.
+pub fn bind_dual_stack(port: u16) -> io::Result<Vec<TcpListener>> {
+    let v6 = SocketAddr::new(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 0).into(),
+                             port);
+    let v6sock = match TcpListener::bind(&v6) {
+        Ok(sock) => sock,
+        // The port is busy, the IPv4 socket would fail the same way
+        Err(e) if e.kind() == io::ErrorKind::AddrInUse ||
+                  e.kind() == io::ErrorKind::PermissionDenied
+        => return Err(e),
+        Err(e) => {
+            // No IPv6 on the host, usually EAFNOSUPPORT. The errors which
+            // aren't specific to IPv6 are returned by the IPv4 bind
+            debug!("Can't bind {}: {}, listening on IPv4 only", v6, e);
+            let v4 = SocketAddr::new(Ipv4Addr::new(0, 0, 0, 0).into(),
+                                     port);
+            return Ok(vec![try!(TcpListener::bind(&v4))]);
+        }
+    };
+    let port = try!(v6sock.local_addr()).port();
+    let v4 = SocketAddr::new(Ipv4Addr::new(0, 0, 0, 0).into(), port);
+    match TcpListener::bind(&v4) {
+        Ok(v4sock) => Ok(vec![v6sock, v4sock]),
+        // The IPv6 socket has taken the IPv4 port too
+        Err(ref e) if e.kind() == io::ErrorKind::AddrInUse => {
+            Ok(vec![v6sock])
+        }
+        Err(e) => Err(e),
+    }
+}
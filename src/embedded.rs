@@ -0,0 +1,311 @@
+use std::io;
+use std::time::Duration;
+
+use mio::{Token, Ready, PollOpt, Evented};
+use mio::timer::{Timeout as MioTimeout, TimerError};
+use mio::channel::{sync_channel, Receiver};
+use mio::deprecated::{EventLoop, Handler as MioHandler};
+use void::Void;
+
+use config::{create_slab, validate, token_range};
+use config::{notify_capacity, messages_per_tick};
+use handler::{Handler, Timeo, Notify, create_handler};
+use loop_api::MioLoop;
+use loop_state::LoopState;
+use notify::{Channel, ShutdownHandle};
+use remote::{RemoteQueue, ActionQueue, LoopHandle, ContextHandle};
+use store::MachineStore;
+use timer::Timers;
+use {Machine, Config, Scope, Response, SpawnError, LoopError, Notifier};
+use {Slab, Registry};
+
+
+/// The timeout of the embedded loop
+///
+/// The timeout type of the outer handler must be convertible from it, and
+/// the timeouts are passed back to `Embedded::timeout`.
+pub type EmbeddedTimeout = Timeo;
+
+/// A rotor loop driven by the mio loop of the application
+///
+/// Use it when the application already owns the `EventLoop` and only a
+/// part of it is written with rotor state machines. The outer handler
+/// delegates into the embedded loop:
+///
+/// * `ready` -- for the tokens which `owns` returns true
+/// * `timeout` -- for the `EmbeddedTimeout` values
+/// * `tick` -- at the end of every iteration of the outer loop
+///
+/// The embedded loop requires `Config::token_range`, the tokens outside of
+/// it are left to the application. The last token of the range is used
+/// for the notifications of the loop (wakeups, `LoopHandle` requests),
+/// and the one before it for the socket of `Scope::waker`, so the slab
+/// limit must be at least two below the length of the range.
+///
+/// When the loop is shut down, the outer loop keeps running, and
+/// `is_finished` returns true. Call `finish` to destroy the state
+/// machines and get the context back.
+pub struct Embedded<M: Machine, S = Slab<Option<M>>>
+    where S: MachineStore<Option<M>>,
+{
+    handler: Handler<M, S>,
+    receiver: Receiver<Notify>,
+    /// The first token and the length of the token range
+    tokens: (usize, usize),
+    notify_token: Token,
+    messages_per_tick: usize,
+    finished: bool,
+}
+
+/// Passes the operations to the outer loop, except the shutdown which
+/// only finishes the embedded loop
+struct Outer<'a, H: MioHandler + 'a> {
+    eloop: &'a mut EventLoop<H>,
+    finished: &'a mut bool,
+}
+
+impl<'a, H> MioLoop for Outer<'a, H>
+    where H: MioHandler, H::Timeout: From<Timeo>,
+{
+    fn register(&mut self, io: &Evented, token: Token,
+        interest: Ready, opt: PollOpt)
+        -> io::Result<()>
+    {
+        self.eloop.register(io, token, interest, opt)
+    }
+    fn reregister(&mut self, io: &Evented, token: Token,
+        interest: Ready, opt: PollOpt)
+        -> io::Result<()>
+    {
+        self.eloop.reregister(io, token, interest, opt)
+    }
+    fn deregister(&mut self, io: &Evented) -> io::Result<()> {
+        self.eloop.deregister(io)
+    }
+    fn timeout(&mut self, timeo: Timeo, delay: Duration)
+        -> Result<MioTimeout, TimerError>
+    {
+        self.eloop.timeout(timeo.into(), delay)
+    }
+    fn clear_timeout(&mut self, timeout: &MioTimeout) -> bool {
+        self.eloop.clear_timeout(timeout)
+    }
+    fn shutdown(&mut self) {
+        *self.finished = true;
+    }
+}
+
+fn invalid(message: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+impl<M: Machine> Embedded<M> {
+    /// Create the embedded loop registered in the `eloop`
+    pub fn new<H>(cfg: &Config, context: M::Context,
+        eloop: &mut EventLoop<H>)
+        -> Result<Embedded<M>, io::Error>
+        where H: MioHandler, H::Timeout: From<Timeo>,
+    {
+        Embedded::with_store(cfg, context, create_slab(cfg), eloop)
+    }
+}
+
+impl<M, S> Embedded<M, S>
+    where M: Machine, S: MachineStore<Option<M>>,
+{
+    /// Create the embedded loop keeping state machines in the `slab`
+    pub fn with_store<H>(cfg: &Config, context: M::Context, slab: S,
+        eloop: &mut EventLoop<H>)
+        -> Result<Embedded<M, S>, io::Error>
+        where H: MioHandler, H::Timeout: From<Timeo>,
+    {
+        try!(validate(cfg));
+        let ((base, len), limit) = match token_range(cfg) {
+            Some(range) => range,
+            None => {
+                return Err(invalid(
+                    "embedded loop requires the token range"));
+            }
+        };
+        if limit.saturating_add(2) > len {
+            return Err(invalid(
+                "token range has no room for the notification tokens"));
+        }
+        let notify_token = Token(base + len - 1);
+        let (tx, rx) = sync_channel(notify_capacity(cfg));
+        try!(eloop.register(&rx, notify_token,
+            Ready::readable(), PollOpt::level()));
+        #[cfg_attr(not(unix), allow(unused_mut))]
+        let mut state = LoopState::new(Channel::new_embedded(tx), cfg);
+        #[cfg(unix)]
+        {
+            state.waker_token = Token(base + len - 2);
+        }
+        let timers = Timers::new(state.start,
+            state.time_source.clone());
+        Ok(Embedded {
+            handler: create_handler(slab, context, state, timers,
+                                    RemoteQueue::new(), ActionQueue::new()),
+            receiver: rx,
+            tokens: (base, len),
+            notify_token: notify_token,
+            messages_per_tick: messages_per_tick(cfg),
+            finished: false,
+        })
+    }
+
+    /// Add the state machine created by `fun`
+    ///
+    /// Returns the notifier of the new state machine.
+    pub fn add_machine_with<H, F>(&mut self, eloop: &mut EventLoop<H>,
+        fun: F)
+        -> Result<Notifier, SpawnError<()>>
+        where H: MioHandler, H::Timeout: From<Timeo>,
+              F: FnOnce(&mut Scope<M::Context>) -> Response<M, Void>
+    {
+        let res = {
+            let ref mut outer = Outer {
+                eloop: eloop,
+                finished: &mut self.finished,
+            };
+            self.handler.add_machine_with(outer, fun)
+        };
+        // Called between the iterations, so the time must not be cached
+        self.handler.reset_clock();
+        res
+    }
+
+    /// Create a state machine from the seed
+    ///
+    /// Returns the notifier of the new state machine, see
+    /// `LoopInstance::add_seed`.
+    pub fn add_seed<H>(&mut self, eloop: &mut EventLoop<H>, seed: M::Seed)
+        -> Result<Option<Notifier>, SpawnError<M::Seed>>
+        where H: MioHandler, H::Timeout: From<Timeo>,
+    {
+        let res = {
+            let ref mut outer = Outer {
+                eloop: eloop,
+                finished: &mut self.finished,
+            };
+            self.handler.add_seed(outer, seed)
+        };
+        self.handler.reset_clock();
+        res
+    }
+
+    /// Returns true if the event for the token must be passed to `ready`
+    pub fn owns(&self, token: Token) -> bool {
+        let (base, len) = self.tokens;
+        token.0 >= base && token.0 - base < len
+    }
+
+    /// Handle the event of the outer loop for the token which it `owns`
+    ///
+    /// State machines are run in `tick`, except the notifications which
+    /// are processed right away.
+    pub fn ready<H>(&mut self, eloop: &mut EventLoop<H>, token: Token,
+        events: Ready)
+        where H: MioHandler, H::Timeout: From<Timeo>,
+    {
+        if token != self.notify_token {
+            self.handler.on_ready(token, events);
+            return;
+        }
+        let ref mut outer = Outer {
+            eloop: eloop,
+            finished: &mut self.finished,
+        };
+        // The receiver is level-triggered, so the rest is left for the
+        // next iteration
+        for _ in 0..self.messages_per_tick {
+            match self.receiver.try_recv() {
+                Ok(msg) => self.handler.on_notify(outer, msg),
+                Err(_) => break,
+            }
+        }
+    }
+
+    /// Handle the timeout of the embedded loop
+    pub fn timeout<H>(&mut self, eloop: &mut EventLoop<H>,
+        timeout: EmbeddedTimeout)
+        where H: MioHandler, H::Timeout: From<Timeo>,
+    {
+        let ref mut outer = Outer {
+            eloop: eloop,
+            finished: &mut self.finished,
+        };
+        self.handler.on_timeout(outer, timeout);
+    }
+
+    /// Run the state machines, call it from `Handler::tick`
+    pub fn tick<H>(&mut self, eloop: &mut EventLoop<H>)
+        where H: MioHandler, H::Timeout: From<Timeo>,
+    {
+        let ref mut outer = Outer {
+            eloop: eloop,
+            finished: &mut self.finished,
+        };
+        self.handler.on_tick(outer);
+    }
+
+    /// The maximum time the outer loop may block in the poll
+    ///
+    /// Returns zero when some state machines yielded and must be run on
+    /// the next `tick` without waiting for events, and
+    /// `Config::max_poll_timeout` otherwise.
+    pub fn poll_timeout(&self) -> Option<Duration> {
+        self.handler.poll_timeout(None)
+    }
+
+    /// Returns true when the loop was shut down
+    pub fn is_finished(&self) -> bool {
+        self.finished
+    }
+
+    /// Get the handle to control the loop from other threads
+    pub fn handle(&self) -> LoopHandle<M::Seed> {
+        self.handler.remote_handle()
+    }
+
+    /// Get the handle to shut down the loop from other threads
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.handler.shutdown_handle()
+    }
+
+    /// Get the handle to change the context from other threads
+    pub fn context_handle(&self) -> ContextHandle<M::Context> {
+        self.handler.context_handle()
+    }
+
+    /// Get the registry of the state machines of the loop
+    pub fn registry(&self) -> Registry {
+        self.handler.registry()
+    }
+
+    pub fn context(&self) -> &M::Context {
+        self.handler.context()
+    }
+
+    /// Destroy the state machines and unregister the loop
+    ///
+    /// Returns the context, or the error of the state machine which
+    /// failed the loop.
+    pub fn finish<H>(mut self, eloop: &mut EventLoop<H>)
+        -> Result<M::Context, LoopError>
+        where H: MioHandler, H::Timeout: From<Timeo>,
+    {
+        {
+            let ref mut outer = Outer {
+                eloop: eloop,
+                finished: &mut self.finished,
+            };
+            self.handler.destroy_all(outer);
+            try!(outer.deregister(&self.receiver));
+        }
+        if let Some((token, err)) = self.handler.take_fatal_error() {
+            return Err(LoopError::Machine(token, err));
+        }
+        Ok(self.handler.into_context())
+    }
+}
@@ -1,6 +1,10 @@
+use std::io;
 use std::fmt;
+use std::any::Any;
 use std::error::Error;
 
+use mio::Token;
+
 
 /// Error when spawning a new state machine
 pub enum SpawnError<S: Sized> {
@@ -11,8 +15,12 @@ pub enum SpawnError<S: Sized> {
     ///
     /// The item in this struct is the Seed that send to create a machine
     NoSlabSpace(S),
-    /// Error returned from `Machine::create` handler
+    /// Error registering the socket of the seed (see
+    /// `Machine::register_seed`), usually a system limit is reached
     UserError(Box<Error>),
+    /// Error returned from `Machine::create` (or `Machine::thaw`) handler
+    /// of the state machine at the token
+    ConstructorError(Token, Box<Error>),
 }
 
 impl<S> fmt::Display for SpawnError<S> {
@@ -23,7 +31,11 @@ impl<S> fmt::Display for SpawnError<S> {
                 write!(fmt, "state machine slab capacity limit is reached")
             }
             UserError(ref err) => {
-                write!(fmt, "{}", err)
+                write!(fmt, "error registering the seed: {}", err)
+            }
+            ConstructorError(token, ref err) => {
+                write!(fmt, "error creating state machine {:?}: {}",
+                    token, err)
             }
         }
     }
@@ -35,6 +47,7 @@ impl<S> SpawnError<S> {
         match self {
             &NoSlabSpace(_) => "state machine slab capacity limit is reached",
             &UserError(ref err) => err.description(),
+            &ConstructorError(_, ref err) => err.description(),
         }
     }
     pub fn cause(&self) -> Option<&Error> {
@@ -42,6 +55,14 @@ impl<S> SpawnError<S> {
         match self {
             &NoSlabSpace(_) => None,
             &UserError(ref err) => Some(&**err),
+            &ConstructorError(_, ref err) => Some(&**err),
+        }
+    }
+    /// Token of the state machine which has failed to start, if any
+    pub fn token(&self) -> Option<Token> {
+        match *self {
+            SpawnError::ConstructorError(token, _) => Some(token),
+            _ => None,
         }
     }
     pub fn map<T:Sized, F: FnOnce(S) -> T>(self, fun:F) -> SpawnError<T> {
@@ -49,16 +70,39 @@ impl<S> SpawnError<S> {
         match self {
             NoSlabSpace(x) => NoSlabSpace(fun(x)),
             UserError(e) => UserError(e),
+            ConstructorError(t, e) => ConstructorError(t, e),
         }
     }
 }
-impl<S: Error> Error for SpawnError<S> {
+
+/// The seed isn't a part of the error, so any `SpawnError` may be boxed
+/// or returned with `?` from a function returning `Box<Error>`
+impl<S> Error for SpawnError<S> {
     fn description(&self) -> &str {
         self.description()
     }
     fn cause(&self) -> Option<&Error> {
         self.cause()
     }
+    fn source(&self) -> Option<&(Error + 'static)> {
+        use self::SpawnError::*;
+        match *self {
+            NoSlabSpace(_) => None,
+            UserError(ref err) => Some(&**err),
+            ConstructorError(_, ref err) => Some(&**err),
+        }
+    }
+}
+
+/// Allows `?` in the functions returning `io::Result`, the seed is dropped
+impl<S> From<SpawnError<S>> for io::Error {
+    fn from(err: SpawnError<S>) -> io::Error {
+        let kind = match err {
+            SpawnError::NoSlabSpace(_) => io::ErrorKind::WouldBlock,
+            _ => io::ErrorKind::Other,
+        };
+        io::Error::new(kind, RemoteSpawnError::from(err.map(|_| ())))
+    }
 }
 
 impl<S> From<Box<Error>> for SpawnError<S> {
@@ -77,6 +121,271 @@ impl<S> fmt::Debug for SpawnError<S> {
             UserError(ref err) => {
                 write!(fmt, "UserError({:?})", err)
             }
+            ConstructorError(token, ref err) => {
+                write!(fmt, "ConstructorError({:?}, {:?})", token, err)
+            }
+        }
+    }
+}
+
+/// Error creating a state machine requested by `LoopHandle::spawn`
+///
+/// Unlike `SpawnError` it can be sent across threads, so the error
+/// returned from `Machine::create` is converted to a string.
+pub enum RemoteSpawnError<S: Sized> {
+    /// The State Machine Slab capacity is reached
+    NoSlabSpace(S),
+    /// Error registering the socket of the seed
+    UserError(String),
+    /// Error returned from `Machine::create` handler
+    ConstructorError(String),
+}
+
+impl<S> From<SpawnError<S>> for RemoteSpawnError<S> {
+    fn from(err: SpawnError<S>) -> RemoteSpawnError<S> {
+        match err {
+            SpawnError::NoSlabSpace(seed) => {
+                RemoteSpawnError::NoSlabSpace(seed)
+            }
+            SpawnError::UserError(e) => {
+                RemoteSpawnError::UserError(e.to_string())
+            }
+            SpawnError::ConstructorError(token, e) => {
+                RemoteSpawnError::ConstructorError(
+                    format!("state machine {:?}: {}", token, e))
+            }
         }
     }
 }
+
+impl<S> fmt::Display for RemoteSpawnError<S> {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        use self::RemoteSpawnError::*;
+        match *self {
+            NoSlabSpace(_) => {
+                write!(fmt, "state machine slab capacity limit is reached")
+            }
+            UserError(ref err) => {
+                write!(fmt, "error registering the seed: {}", err)
+            }
+            ConstructorError(ref err) => {
+                write!(fmt, "error creating state machine: {}", err)
+            }
+        }
+    }
+}
+
+impl<S> fmt::Debug for RemoteSpawnError<S> {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        use self::RemoteSpawnError::*;
+        match *self {
+            NoSlabSpace(..) => {
+                write!(fmt, "NoSlabSpace(<hidden seed>)")
+            }
+            UserError(ref err) => write!(fmt, "UserError({:?})", err),
+            ConstructorError(ref err) => {
+                write!(fmt, "ConstructorError({:?})", err)
+            }
+        }
+    }
+}
+
+impl<S> Error for RemoteSpawnError<S> {
+    fn description(&self) -> &str {
+        use self::RemoteSpawnError::*;
+        match *self {
+            NoSlabSpace(_) => "state machine slab capacity limit is reached",
+            UserError(ref err) => err,
+            ConstructorError(ref err) => err,
+        }
+    }
+}
+
+/// Counters of errors returned from polling the event loop
+///
+/// See `LoopInstance::poll_errors`
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PollErrors {
+    /// Poll interrupted by a signal (EINTR), always retried
+    pub interrupted: u64,
+    /// Errors that may go away on retry (e.g. EAGAIN)
+    pub transient: u64,
+    /// All other errors
+    pub fatal: u64,
+}
+
+/// Returns true if poll may succeed when retried
+pub fn is_transient(err: &io::Error) -> bool {
+    match err.kind() {
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => true,
+        _ => false,
+    }
+}
+
+/// Returns true if the process or the system is out of file descriptors
+///
+/// I.e. `EMFILE` or `ENFILE`, the numbers are the same on Linux and BSDs.
+#[cfg(unix)]
+#[cfg_attr(feature="no_deadlines", allow(dead_code))]
+pub fn is_fd_limit(err: &io::Error) -> bool {
+    match err.raw_os_error() {
+        Some(23) | Some(24) => true,
+        _ => false,
+    }
+}
+
+/// Returns true if the process is out of socket handles (`WSAEMFILE`)
+#[cfg(windows)]
+#[cfg_attr(feature="no_deadlines", allow(dead_code))]
+pub fn is_fd_limit(err: &io::Error) -> bool {
+    err.raw_os_error() == Some(10024)
+}
+
+/// Error of the state machine migration, see `LoopHandle::migrate`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MigrateError {
+    /// There is no state machine with the token
+    NotFound,
+    /// The state machine has refused to `freeze`
+    Refused,
+    /// The target loop can't be notified, the state machine is thawed
+    /// back in the source loop (probably with another token)
+    Closed,
+}
+
+impl fmt::Display for MigrateError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.write_str(self.description())
+    }
+}
+
+impl Error for MigrateError {
+    fn description(&self) -> &str {
+        use self::MigrateError::*;
+        match *self {
+            NotFound => "no state machine to migrate",
+            Refused => "state machine refused to migrate",
+            Closed => "target loop is closed",
+        }
+    }
+}
+
+/// Error returned when running the loop
+#[derive(Debug)]
+pub enum LoopError {
+    /// I/O error when polling the event loop
+    Io(io::Error),
+    /// A state machine has returned an error that is considered fatal
+    ///
+    /// See `LoopCreator::fatal_error_filter`
+    Machine(Token, Box<Error>),
+}
+
+impl fmt::Display for LoopError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        use self::LoopError::*;
+        match *self {
+            Io(ref err) => write!(fmt, "event loop error: {}", err),
+            Machine(token, ref err) => {
+                write!(fmt, "state machine {:?} failed: {}", token, err)
+            }
+        }
+    }
+}
+
+impl Error for LoopError {
+    fn description(&self) -> &str {
+        use self::LoopError::*;
+        match *self {
+            Io(_) => "event loop error",
+            Machine(..) => "state machine failed",
+        }
+    }
+    fn cause(&self) -> Option<&Error> {
+        use self::LoopError::*;
+        match *self {
+            Io(ref err) => Some(err),
+            Machine(_, ref err) => Some(&**err),
+        }
+    }
+}
+
+impl From<io::Error> for LoopError {
+    fn from(err: io::Error) -> LoopError {
+        LoopError::Io(err)
+    }
+}
+
+/// Error returned by `LoopThread::join`
+///
+/// Same as `LoopError`, but the error of the state machine is converted
+/// to a string to be sent across threads.
+#[derive(Debug)]
+pub enum JoinError {
+    /// I/O error when polling the event loop
+    Io(io::Error),
+    /// A state machine has returned a fatal error
+    Machine(Token, String),
+    /// The thread of the loop has panicked, the payload of the panic
+    Panicked(Box<Any + Send>),
+}
+
+impl fmt::Display for JoinError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        use self::JoinError::*;
+        match *self {
+            Io(ref err) => write!(fmt, "event loop error: {}", err),
+            Machine(token, ref err) => {
+                write!(fmt, "state machine {:?} failed: {}", token, err)
+            }
+            Panicked(_) => write!(fmt, "event loop thread has panicked"),
+        }
+    }
+}
+
+impl Error for JoinError {
+    fn description(&self) -> &str {
+        use self::JoinError::*;
+        match *self {
+            Io(_) => "event loop error",
+            Machine(..) => "state machine failed",
+            Panicked(_) => "event loop thread has panicked",
+        }
+    }
+    fn cause(&self) -> Option<&Error> {
+        match *self {
+            JoinError::Io(ref err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<LoopError> for JoinError {
+    fn from(err: LoopError) -> JoinError {
+        match err {
+            LoopError::Io(err) => JoinError::Io(err),
+            LoopError::Machine(token, err) => {
+                JoinError::Machine(token, err.to_string())
+            }
+        }
+    }
+}
+
+/// The linked state machine has exited with an error
+///
+/// Returned from the default `Machine::exit_signal`, so the error
+/// propagates along the links. See `Scope::link`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LinkedExit(pub Token);
+
+impl fmt::Display for LinkedExit {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "linked state machine {:?} exited with error", self.0)
+    }
+}
+
+impl Error for LinkedExit {
+    fn description(&self) -> &str {
+        "linked state machine exited with error"
+    }
+}
@@ -0,0 +1,344 @@
+use std::io;
+use std::mem;
+use std::error::Error;
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use mio::tcp::TcpStream;
+use void::Void;
+
+use {Machine, Scope, Response, EventSet, PollOpt, Time, Promise};
+use {Client, SocketOptions, Resolver, ResolveResult, ResolveError};
+
+
+/// Delay between the connection attempts recommended by RFC 8305
+const ATTEMPT_DELAY_MS: u64 = 250;
+/// Time to wait for the IPv6 addresses when the IPv4 ones are resolved
+const RESOLUTION_DELAY_MS: u64 = 50;
+
+/// Settings of the `HappyEyeballs` connection
+#[derive(Debug, Clone)]
+pub struct EyeballsConfig {
+    timeout: Duration,
+    attempt_delay: Duration,
+    resolution_delay: Duration,
+    options: SocketOptions,
+}
+
+struct Attempt {
+    sock: TcpStream,
+    addr: SocketAddr,
+}
+
+struct Racing {
+    config: EyeballsConfig,
+    port: u16,
+    v6: Option<Promise<ResolveResult>>,
+    v4: Option<Promise<ResolveResult>>,
+    /// When the IPv4 addresses were resolved before the IPv6 ones
+    v4_resolved: Option<Time>,
+    v6_addrs: VecDeque<SocketAddr>,
+    v4_addrs: VecDeque<SocketAddr>,
+    /// The family of the previous attempt, the next one uses the other
+    last_v6: bool,
+    attempts: Vec<Attempt>,
+    next_attempt: Time,
+    deadline: Time,
+    last_error: Option<Box<Error>>,
+}
+
+enum State<M> {
+    Racing(Racing),
+    Connected(M),
+}
+
+/// A client connection established with the Happy Eyeballs algorithm
+///
+/// Implements RFC 8305: the name is resolved to both IPv6 and IPv4
+/// addresses, and connection attempts are started one after another,
+/// alternating the address families (IPv6 first), every `attempt_delay`
+/// or as soon as the previous attempt fails. The first established
+/// connection is kept and the rest are closed. Then the client state
+/// machine `M` is created (see `Client::connected`).
+///
+/// Connecting fails with the last error when all the addresses are tried,
+/// or with `io::ErrorKind::TimedOut` after the `timeout`. Wrap it with
+/// something like `Persistent` to reconnect.
+pub struct HappyEyeballs<M>(State<M>);
+
+impl EyeballsConfig {
+    /// Connect within the `timeout` with the delays of RFC 8305
+    pub fn new(timeout: Duration) -> EyeballsConfig {
+        EyeballsConfig {
+            timeout: timeout,
+            attempt_delay: Duration::from_millis(ATTEMPT_DELAY_MS),
+            resolution_delay: Duration::from_millis(RESOLUTION_DELAY_MS),
+            options: SocketOptions::new(),
+        }
+    }
+    /// Set the delay before the next connection attempt (250 ms)
+    pub fn attempt_delay(mut self, delay: Duration) -> EyeballsConfig {
+        self.attempt_delay = delay;
+        self
+    }
+    /// Set the time to wait for the IPv6 addresses after the IPv4 ones
+    /// are resolved (50 ms)
+    pub fn resolution_delay(mut self, delay: Duration) -> EyeballsConfig {
+        self.resolution_delay = delay;
+        self
+    }
+    /// Set the options of every socket
+    pub fn socket_options(mut self, options: SocketOptions)
+        -> EyeballsConfig
+    {
+        self.options = options;
+        self
+    }
+}
+
+impl<M: Client> HappyEyeballs<M> {
+    /// Start resolving the `name` to connect to the `port`
+    pub fn new(resolver: &Resolver, name: &str, port: u16,
+        config: EyeballsConfig, scope: &mut Scope<M::Context>)
+        -> Response<HappyEyeballs<M>, Void>
+    {
+        let now = scope.now();
+        let racing = Racing {
+            port: port,
+            v6: Some(resolver.resolve_v6(scope, name)),
+            v4: Some(resolver.resolve(scope, name)),
+            v4_resolved: None,
+            v6_addrs: VecDeque::new(),
+            v4_addrs: VecDeque::new(),
+            last_v6: false,
+            attempts: Vec::new(),
+            next_attempt: now,
+            deadline: now + config.timeout,
+            last_error: None,
+            config: config,
+        };
+        // Literal addresses are resolved right away
+        racing.step(scope)
+    }
+}
+
+/// Returns `Some` when the result is received or the resolver is gone
+fn take_result(promise: &mut Option<Promise<ResolveResult>>)
+    -> Option<ResolveResult>
+{
+    let res = match *promise {
+        Some(ref mut p) if p.is_abandoned() => Err(ResolveError::Closed),
+        Some(ref mut p) if p.is_ready() => {
+            p.take().expect("result is ready")
+        }
+        _ => return None,
+    };
+    *promise = None;
+    Some(res)
+}
+
+/// Returns `Some` when the connection is established or has failed
+fn connection_result(sock: &TcpStream) -> Option<io::Result<()>> {
+    match sock.take_error() {
+        Ok(Some(e)) | Err(e) => return Some(Err(e)),
+        Ok(None) => {}
+    }
+    // Only a connected socket has the peer address
+    sock.peer_addr().ok().map(|_| Ok(()))
+}
+
+impl Racing {
+    fn add_addresses(&mut self, res: ResolveResult, v6: bool) {
+        match res {
+            Ok(ips) => {
+                let port = self.port;
+                let addrs = if v6 {
+                    &mut self.v6_addrs
+                } else {
+                    &mut self.v4_addrs
+                };
+                addrs.extend(ips.into_iter()
+                    .map(|ip| SocketAddr::new(ip, port)));
+            }
+            Err(e) => {
+                debug!("Error resolving {} addresses: {}",
+                    if v6 { "IPv6" } else { "IPv4" }, e);
+                self.last_error = Some(Box::new(e));
+            }
+        }
+    }
+    fn next_address(&mut self) -> Option<SocketAddr> {
+        let v6 = match (self.v6_addrs.is_empty(), self.v4_addrs.is_empty()) {
+            (true, true) => return None,
+            (false, true) => true,
+            (true, false) => false,
+            (false, false) => !self.last_v6,
+        };
+        self.last_v6 = v6;
+        if v6 {
+            self.v6_addrs.pop_front()
+        } else {
+            self.v4_addrs.pop_front()
+        }
+    }
+    /// Returns true if waiting for the IPv6 addresses before connecting
+    fn waiting_v6(&self, now: Time) -> bool {
+        match self.v4_resolved {
+            Some(time) => self.v6.is_some() &&
+                now < time + self.config.resolution_delay,
+            None => false,
+        }
+    }
+    fn start_attempt<C>(&mut self, scope: &mut Scope<C>) {
+        while let Some(addr) = self.next_address() {
+            let res = TcpStream::connect(&addr).and_then(|sock| {
+                try!(self.config.options.apply(&sock));
+                try!(scope.register(&sock, EventSet::writable(),
+                                    PollOpt::level()));
+                Ok(sock)
+            });
+            match res {
+                Ok(sock) => {
+                    self.attempts.push(Attempt { sock: sock, addr: addr });
+                    self.next_attempt = scope.now() +
+                        self.config.attempt_delay;
+                    return;
+                }
+                Err(e) => {
+                    debug!("Error connecting to {}: {}", addr, e);
+                    self.last_error = Some(Box::new(e));
+                }
+            }
+        }
+    }
+    /// Removes the failed attempts and returns the established one
+    fn check_attempts(&mut self) -> Option<Attempt> {
+        let mut idx = 0;
+        while idx < self.attempts.len() {
+            match connection_result(&self.attempts[idx].sock) {
+                None => idx += 1,
+                Some(Ok(())) => return Some(self.attempts.swap_remove(idx)),
+                Some(Err(e)) => {
+                    let failed = self.attempts.swap_remove(idx);
+                    debug!("Error connecting to {}: {}", failed.addr, e);
+                    self.last_error = Some(Box::new(e));
+                }
+            }
+        }
+        None
+    }
+    fn step<M: Client>(mut self, scope: &mut Scope<M::Context>)
+        -> Response<HappyEyeballs<M>, Void>
+    {
+        let now = scope.now();
+        if let Some(res) = take_result(&mut self.v6) {
+            self.add_addresses(res, true);
+        }
+        if let Some(res) = take_result(&mut self.v4) {
+            if self.v6.is_some() {
+                self.v4_resolved = Some(now);
+            }
+            self.add_addresses(res, false);
+        }
+        if let Some(winner) = self.check_attempts() {
+            // The rest of the sockets are deregistered when closed
+            drop(mem::replace(&mut self.attempts, Vec::new()));
+            if let Err(e) = scope.deregister(&winner.sock) {
+                return Response::error(Box::new(e));
+            }
+            return M::connected(winner.sock, scope)
+                .wrap(|m| HappyEyeballs(State::Connected(m)));
+        }
+        if now >= self.deadline {
+            return Response::error(Box::new(io::Error::new(
+                io::ErrorKind::TimedOut, "timed out connecting")));
+        }
+        if !self.waiting_v6(now) &&
+            (self.attempts.is_empty() || now >= self.next_attempt)
+        {
+            self.start_attempt(scope);
+        }
+        let addresses_left = !self.v6_addrs.is_empty() ||
+                             !self.v4_addrs.is_empty();
+        if self.attempts.is_empty() && !addresses_left &&
+            self.v6.is_none() && self.v4.is_none()
+        {
+            let err = self.last_error.take()
+                .unwrap_or_else(|| Box::new(ResolveError::NotFound));
+            return Response::error(err);
+        }
+        let mut deadline = self.deadline;
+        if addresses_left && !self.attempts.is_empty() {
+            deadline = deadline.min(self.next_attempt);
+        }
+        if let Some(time) = self.v4_resolved {
+            if self.waiting_v6(now) {
+                deadline = deadline.min(time + self.config.resolution_delay);
+            }
+        }
+        Response::ok(HappyEyeballs(State::Racing(self))).deadline(deadline)
+    }
+}
+
+impl<M: Client> Machine for HappyEyeballs<M> {
+    type Context = M::Context;
+    type Seed = Void;
+
+    fn create(seed: Void, _scope: &mut Scope<M::Context>)
+        -> Response<Self, Void>
+    {
+        ::void::unreachable(seed)
+    }
+    fn ready(self, events: EventSet, scope: &mut Scope<M::Context>)
+        -> Response<Self, Void>
+    {
+        match self.0 {
+            State::Racing(racing) => racing.step(scope),
+            State::Connected(m) => {
+                m.ready(events, scope)
+                    .wrap(|m| HappyEyeballs(State::Connected(m)))
+            }
+        }
+    }
+    fn spawned(self, _scope: &mut Scope<M::Context>) -> Response<Self, Void>
+    {
+        unreachable!();
+    }
+    fn destroyed(self, scope: &mut Scope<M::Context>) {
+        if let State::Connected(m) = self.0 {
+            m.destroyed(scope);
+        }
+    }
+    fn timeout(self, scope: &mut Scope<M::Context>) -> Response<Self, Void> {
+        match self.0 {
+            State::Racing(racing) => racing.step(scope),
+            State::Connected(m) => {
+                m.timeout(scope).wrap(|m| HappyEyeballs(State::Connected(m)))
+            }
+        }
+    }
+    fn wakeup(self, scope: &mut Scope<M::Context>) -> Response<Self, Void> {
+        match self.0 {
+            // Resolver answers
+            State::Racing(racing) => racing.step(scope),
+            State::Connected(m) => {
+                m.wakeup(scope).wrap(|m| HappyEyeballs(State::Connected(m)))
+            }
+        }
+    }
+    fn interest(&self) -> Option<EventSet> {
+        match self.0 {
+            State::Racing(..) => None,
+            State::Connected(ref m) => m.interest(),
+        }
+    }
+    fn error(self, err: Box<Error>, scope: &mut Scope<M::Context>)
+        -> Box<Error>
+    {
+        match self.0 {
+            State::Racing(..) => err,
+            State::Connected(m) => m.error(err, scope),
+        }
+    }
+}
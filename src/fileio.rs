@@ -0,0 +1,23 @@
+use std::io::{self, Read, Write, Seek, SeekFrom};
+use std::fs::{File, OpenOptions};
+use std::path::Path;
+
+
+/// Reads up to `len` bytes of the file starting at `offset`
+///
+/// Less data is returned only at the end of file.
+pub fn read_at(path: &Path, offset: u64, len: usize) -> io::Result<Vec<u8>> {
+    let mut file = try!(File::open(path));
+    try!(file.seek(SeekFrom::Start(offset)));
+    let mut buf = Vec::with_capacity(len);
+    try!(file.take(len as u64).read_to_end(&mut buf));
+    Ok(buf)
+}
+
+/// Writes the data to the file at `offset`, the file is created if needed
+pub fn write_at(path: &Path, offset: u64, data: &[u8]) -> io::Result<()> {
+    let mut file = try!(OpenOptions::new().write(true).create(true)
+                        .open(path));
+    try!(file.seek(SeekFrom::Start(offset)));
+    file.write_all(data)
+}
@@ -0,0 +1,98 @@
+use std::marker::PhantomData;
+
+use void::Void;
+
+use {NoSpawnMachine, Scope, Response, EventSet};
+
+
+/// The event passed to the closure of `FnMachine`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FnEvent {
+    /// The socket registered with the token of the state machine is ready
+    Ready(EventSet),
+    /// The deadline returned from the previous call is reached
+    Timeout,
+    /// The state machine is woken up with the `Notifier`
+    Wakeup,
+}
+
+/// The closure of the boxed `FnMachine`
+pub type BoxedFn<C> = Box<FnMut(FnEvent, &mut Scope<C>) -> Response<(), Void>>;
+
+/// A state machine made of a closure
+///
+/// Useful for prototypes and tests. The closure is called for every
+/// event, the state lives in its captures (use a `move` closure). It
+/// returns the response for the unit:
+///
+/// * `Response::ok(())` -- continue
+/// * `Response::ok(()).deadline(time)` -- continue and get the
+///   `FnEvent::Timeout` at the `time`. As with any state machine, the
+///   deadline must be returned every time to keep it
+/// * `Response::done()` or `Response::error(..)` -- stop
+///
+/// ```ignore
+/// loop_creator.add_machine_with(|scope| {
+///     let mut left = 3;
+///     let deadline = scope.now() + Duration::from_secs(1);
+///     Response::ok(FnMachine::boxed(move |_event, scope| {
+///         left -= 1;
+///         if left == 0 {
+///             return Response::done();
+///         }
+///         Response::ok(()).deadline(scope.now() + Duration::from_secs(1))
+///     })).deadline(deadline)
+/// });
+/// ```
+///
+/// Every closure has its own type, so to have a few different closures in
+/// the same loop use `FnMachine::boxed`, which erases the type.
+pub struct FnMachine<C, F = BoxedFn<C>> {
+    fun: F,
+    phantom: PhantomData<*const C>,
+}
+
+impl<C, F> FnMachine<C, F>
+    where F: FnMut(FnEvent, &mut Scope<C>) -> Response<(), Void>
+{
+    /// Create a state machine which calls `fun` on every event
+    pub fn new(fun: F) -> FnMachine<C, F> {
+        FnMachine {
+            fun: fun,
+            phantom: PhantomData,
+        }
+    }
+    fn call(mut self, event: FnEvent, scope: &mut Scope<C>)
+        -> Response<Self, Void>
+    {
+        (self.fun)(event, scope).wrap(|()| self)
+    }
+}
+
+impl<C> FnMachine<C> {
+    /// Create a state machine with the boxed closure
+    pub fn boxed<F>(fun: F) -> FnMachine<C>
+        where F: FnMut(FnEvent, &mut Scope<C>) -> Response<(), Void>
+                 + 'static
+    {
+        FnMachine::new(Box::new(fun) as BoxedFn<C>)
+    }
+}
+
+impl<C, F> NoSpawnMachine for FnMachine<C, F>
+    where F: FnMut(FnEvent, &mut Scope<C>) -> Response<(), Void>
+{
+    type Context = C;
+
+    fn ready(self, events: EventSet, scope: &mut Scope<C>)
+        -> Response<Self, Void>
+    {
+        self.call(FnEvent::Ready(events), scope)
+    }
+    fn timeout(self, scope: &mut Scope<C>) -> Response<Self, Void> {
+        self.call(FnEvent::Timeout, scope)
+    }
+    fn wakeup(self, scope: &mut Scope<C>) -> Response<Self, Void> {
+        self.call(FnEvent::Wakeup, scope)
+    }
+}
@@ -0,0 +1,386 @@
+use std::path::{Path, PathBuf};
+
+use void::{Void, unreachable};
+
+use {Machine, Scope, GenericScope, Response, EventSet};
+
+
+/// What has happened to the watched file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileEventKind {
+    /// The contents are changed
+    Modified,
+    /// Permissions, timestamps or other metadata are changed
+    Attributes,
+    /// The file is created in the watched directory
+    Created,
+    /// The file is removed
+    Removed,
+    /// The file is renamed or moved to/from the watched directory
+    Moved,
+    /// Some events are lost, rescan the watched files
+    Overflow,
+}
+
+/// A change of the watched file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileEvent {
+    /// The changed file, a child of the watched path for directories
+    /// watched with inotify, and the watched path itself otherwise
+    pub path: PathBuf,
+    pub kind: FileEventKind,
+}
+
+/// Callbacks of the `FileWatcher`
+pub trait WatchHandler: Sized {
+    type Context;
+    /// Changes read from the descriptor within a single event
+    fn changed(self, events: &[FileEvent], scope: &mut Scope<Self::Context>)
+        -> Self;
+    /// The state machine is woken up
+    fn wakeup(self, _scope: &mut Scope<Self::Context>) -> Self {
+        self
+    }
+}
+
+/// A state machine watching files for changes
+///
+/// Uses inotify on linux and `EVFILT_VNODE` of kqueue on BSD and macOS.
+/// The descriptor is registered in the loop, so there are no threads
+/// involved. Useful to reload configuration or to tail logs.
+///
+/// Requires the `fswatch` feature, on other platforms (or without the
+/// feature) `FileWatcher::new` returns an error.
+pub struct FileWatcher<H> {
+    sys: sys::Watcher,
+    handler: H,
+}
+
+impl<H: WatchHandler> FileWatcher<H> {
+    /// Start watching the paths, which must exist
+    ///
+    /// Watched directories report changes of their children with
+    /// inotify, but only changes of the directory entry itself with
+    /// kqueue.
+    pub fn new<P, S>(paths: &[P], handler: H, scope: &mut S)
+        -> Response<FileWatcher<H>, Void>
+        where P: AsRef<Path>,
+              S: GenericScope,
+    {
+        let res = sys::Watcher::new(paths).and_then(|sys| {
+            try!(sys.register(scope));
+            Ok(sys)
+        });
+        match res {
+            Ok(sys) => {
+                Response::ok(FileWatcher { sys: sys, handler: handler })
+            }
+            Err(e) => Response::error(Box::new(e)),
+        }
+    }
+}
+
+impl<H: WatchHandler> Machine for FileWatcher<H> {
+    type Context = H::Context;
+    type Seed = Void;
+
+    fn create(seed: Void, _scope: &mut Scope<H::Context>)
+        -> Response<Self, Void>
+    {
+        unreachable(seed)
+    }
+    fn ready(self, _events: EventSet, scope: &mut Scope<H::Context>)
+        -> Response<Self, Void>
+    {
+        let FileWatcher { mut sys, handler } = self;
+        let mut events = Vec::new();
+        if let Err(e) = sys.read(&mut events) {
+            return Response::error(Box::new(e));
+        }
+        let handler = if events.is_empty() {
+            handler
+        } else {
+            handler.changed(&events, scope)
+        };
+        Response::ok(FileWatcher { sys: sys, handler: handler })
+    }
+    fn spawned(self, _scope: &mut Scope<H::Context>) -> Response<Self, Void>
+    {
+        unreachable!();
+    }
+    fn timeout(self, _scope: &mut Scope<H::Context>) -> Response<Self, Void>
+    {
+        Response::ok(self)
+    }
+    fn wakeup(self, scope: &mut Scope<H::Context>) -> Response<Self, Void> {
+        let FileWatcher { sys, handler } = self;
+        Response::ok(FileWatcher { sys: sys, handler: handler.wakeup(scope) })
+    }
+}
+
+#[cfg(all(feature="fswatch", target_os="linux"))]
+mod sys {
+    use std::io::{self, Read};
+    use std::ffi::{CString, OsStr};
+    use std::fs::File;
+    use std::mem;
+    use std::ptr;
+    use std::path::{Path, PathBuf};
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::io::{AsRawFd, FromRawFd};
+
+    use libc;
+    use mio::unix::EventedFd;
+
+    use {GenericScope, EventSet, PollOpt};
+    use super::{FileEvent, FileEventKind};
+
+    const MASK: u32 = libc::IN_MODIFY | libc::IN_CLOSE_WRITE |
+        libc::IN_ATTRIB | libc::IN_CREATE | libc::IN_DELETE |
+        libc::IN_DELETE_SELF | libc::IN_MOVED_FROM | libc::IN_MOVED_TO |
+        libc::IN_MOVE_SELF;
+
+    pub struct Watcher {
+        file: File,
+        /// Watch descriptors and their paths
+        watches: Vec<(libc::c_int, PathBuf)>,
+    }
+
+    impl Watcher {
+        pub fn new<P: AsRef<Path>>(paths: &[P]) -> io::Result<Watcher> {
+            let fd = unsafe {
+                libc::inotify_init1(libc::IN_NONBLOCK | libc::IN_CLOEXEC)
+            };
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            // Owns the descriptor, so it's closed on errors
+            let file = unsafe { File::from_raw_fd(fd) };
+            let mut watches = Vec::with_capacity(paths.len());
+            for path in paths {
+                let path = path.as_ref();
+                let cpath = try!(CString::new(path.as_os_str().as_bytes())
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput,
+                                                e)));
+                let wd = unsafe {
+                    libc::inotify_add_watch(fd, cpath.as_ptr(), MASK)
+                };
+                if wd < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                watches.push((wd, path.to_path_buf()));
+            }
+            Ok(Watcher { file: file, watches: watches })
+        }
+        pub fn register<S: GenericScope>(&self, scope: &mut S)
+            -> io::Result<()>
+        {
+            scope.register(&EventedFd(&self.file.as_raw_fd()),
+                           EventSet::readable(), PollOpt::level())
+        }
+        pub fn read(&mut self, events: &mut Vec<FileEvent>)
+            -> io::Result<()>
+        {
+            let mut buf = [0u8; 4096];
+            loop {
+                let n = match self.file.read(&mut buf) {
+                    Ok(n) => n,
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                        return Ok(());
+                    }
+                    Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {
+                        continue;
+                    }
+                    Err(e) => return Err(e),
+                };
+                let mut offset = 0;
+                let header = mem::size_of::<libc::inotify_event>();
+                while offset + header <= n {
+                    // The buffer is not aligned for the structure
+                    let event: libc::inotify_event = unsafe {
+                        ptr::read_unaligned(buf[offset..].as_ptr()
+                                            as *const libc::inotify_event)
+                    };
+                    let name = &buf[offset + header..]
+                        [..event.len as usize];
+                    offset += header + event.len as usize;
+                    self.convert(&event, name, events);
+                }
+            }
+        }
+        fn convert(&self, event: &libc::inotify_event, name: &[u8],
+            events: &mut Vec<FileEvent>)
+        {
+            let kind = if event.mask & libc::IN_Q_OVERFLOW != 0 {
+                FileEventKind::Overflow
+            } else if event.mask & libc::IN_CREATE != 0 {
+                FileEventKind::Created
+            } else if event.mask &
+                (libc::IN_DELETE | libc::IN_DELETE_SELF) != 0
+            {
+                FileEventKind::Removed
+            } else if event.mask & (libc::IN_MOVED_FROM |
+                libc::IN_MOVED_TO | libc::IN_MOVE_SELF) != 0
+            {
+                FileEventKind::Moved
+            } else if event.mask & libc::IN_ATTRIB != 0 {
+                FileEventKind::Attributes
+            } else {
+                FileEventKind::Modified
+            };
+            let base = self.watches.iter()
+                .find(|&&(wd, _)| wd == event.wd)
+                .map(|&(_, ref path)| path.clone());
+            let path = match base {
+                Some(base) => {
+                    // The name is padded with zeros
+                    let len = name.iter().position(|&x| x == 0)
+                        .unwrap_or(name.len());
+                    if len > 0 {
+                        base.join(OsStr::from_bytes(&name[..len]))
+                    } else {
+                        base
+                    }
+                }
+                // Overflow is not bound to any watch
+                None => PathBuf::new(),
+            };
+            events.push(FileEvent { path: path, kind: kind });
+        }
+    }
+}
+
+#[cfg(all(feature="fswatch", any(target_os="macos", target_os="ios",
+    target_os="freebsd", target_os="dragonfly", target_os="openbsd",
+    target_os="netbsd")))]
+mod sys {
+    use std::io;
+    use std::fs::File;
+    use std::mem;
+    use std::ptr;
+    use std::path::{Path, PathBuf};
+    use std::os::unix::io::{AsRawFd, FromRawFd};
+
+    use libc;
+    use mio::unix::EventedFd;
+
+    use {GenericScope, EventSet, PollOpt};
+    use super::{FileEvent, FileEventKind};
+
+    pub struct Watcher {
+        kqueue: File,
+        /// Opened files are kept to keep the watches
+        files: Vec<(File, PathBuf)>,
+    }
+
+    impl Watcher {
+        pub fn new<P: AsRef<Path>>(paths: &[P]) -> io::Result<Watcher> {
+            let kq = unsafe { libc::kqueue() };
+            if kq < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            // Owns the descriptor, so it's closed on errors
+            let kqueue = unsafe { File::from_raw_fd(kq) };
+            let mut files = Vec::with_capacity(paths.len());
+            for path in paths {
+                let path = path.as_ref();
+                let file = try!(File::open(path));
+                let mut change: libc::kevent = unsafe { mem::zeroed() };
+                change.ident = file.as_raw_fd() as _;
+                change.filter = libc::EVFILT_VNODE;
+                change.flags = libc::EV_ADD | libc::EV_CLEAR;
+                change.fflags = libc::NOTE_WRITE | libc::NOTE_EXTEND |
+                    libc::NOTE_ATTRIB | libc::NOTE_DELETE |
+                    libc::NOTE_RENAME;
+                let res = unsafe {
+                    libc::kevent(kq, &change, 1, ptr::null_mut(), 0,
+                                 ptr::null())
+                };
+                if res < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                files.push((file, path.to_path_buf()));
+            }
+            Ok(Watcher { kqueue: kqueue, files: files })
+        }
+        pub fn register<S: GenericScope>(&self, scope: &mut S)
+            -> io::Result<()>
+        {
+            scope.register(&EventedFd(&self.kqueue.as_raw_fd()),
+                           EventSet::readable(), PollOpt::level())
+        }
+        pub fn read(&mut self, events: &mut Vec<FileEvent>)
+            -> io::Result<()>
+        {
+            let mut list: [libc::kevent; 32] = unsafe { mem::zeroed() };
+            let zero = libc::timespec { tv_sec: 0, tv_nsec: 0 };
+            loop {
+                let n = unsafe {
+                    libc::kevent(self.kqueue.as_raw_fd(), ptr::null(), 0,
+                                 list.as_mut_ptr(), list.len() as _, &zero)
+                };
+                if n < 0 {
+                    let e = io::Error::last_os_error();
+                    if e.kind() == io::ErrorKind::Interrupted {
+                        continue;
+                    }
+                    return Err(e);
+                }
+                for ev in &list[..n as usize] {
+                    let path = self.files.iter()
+                        .find(|&&(ref f, _)| f.as_raw_fd() as usize ==
+                                              ev.ident as usize)
+                        .map(|&(_, ref path)| path.clone())
+                        .unwrap_or_else(PathBuf::new);
+                    let kind = if ev.fflags & libc::NOTE_DELETE != 0 {
+                        FileEventKind::Removed
+                    } else if ev.fflags & libc::NOTE_RENAME != 0 {
+                        FileEventKind::Moved
+                    } else if ev.fflags & libc::NOTE_ATTRIB != 0 &&
+                        ev.fflags & (libc::NOTE_WRITE | libc::NOTE_EXTEND)
+                            == 0
+                    {
+                        FileEventKind::Attributes
+                    } else {
+                        FileEventKind::Modified
+                    };
+                    events.push(FileEvent { path: path, kind: kind });
+                }
+                if (n as usize) < list.len() {
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
+
+#[cfg(not(all(feature="fswatch", any(target_os="linux", target_os="macos",
+    target_os="ios", target_os="freebsd", target_os="dragonfly",
+    target_os="openbsd", target_os="netbsd"))))]
+mod sys {
+    use std::io;
+    use std::path::Path;
+
+    use GenericScope;
+    use super::FileEvent;
+
+    pub enum Watcher {}
+
+    impl Watcher {
+        pub fn new<P: AsRef<Path>>(_paths: &[P]) -> io::Result<Watcher> {
+            Err(io::Error::new(io::ErrorKind::Other,
+                "file watching requires the `fswatch` feature \
+                 (linux, BSD or macOS)"))
+        }
+        pub fn register<S: GenericScope>(&self, _scope: &mut S)
+            -> io::Result<()>
+        {
+            match *self {}
+        }
+        pub fn read(&mut self, _events: &mut Vec<FileEvent>)
+            -> io::Result<()>
+        {
+            match *self {}
+        }
+    }
+}
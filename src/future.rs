@@ -1,4 +1,35 @@
-use {Notify, Port, Future};
+use std::sync::{Arc, Mutex};
+
+use notify::WakeupError;
+use Notifier;
+
+
+/// A value that is being computed outside of the state machine
+///
+/// When the value is ready the state machine that created the future
+/// receives a `wakeup`. Since wakeups may be spurious check `done()`
+/// before calling `get()`.
+pub struct Future<T: Sized> {
+    contents: Arc<Mutex<Option<T>>>,
+}
+
+/// The sending side of the `Future`
+///
+/// Port may be sent to another thread, setting the value wakes up the
+/// state machine which owns the `Future`.
+pub struct Port<T: Sized> {
+    contents: Arc<Mutex<Option<T>>>,
+    notifier: Notifier,
+}
+
+pub fn create_future<T:Sized>(notifier: Notifier) -> (Port<T>, Future<T>) {
+    let contents = Arc::new(Mutex::new(None));
+    let port = Port {
+        contents: contents.clone(),
+        notifier: notifier,
+    };
+    (port, Future { contents: contents })
+}
 
 impl<T:Sized> Port<T> {
     /// Set the value of the future
@@ -7,10 +38,17 @@ impl<T:Sized> Port<T> {
     ///
     /// Panics when message to the target main loop can't be sent
     pub fn set(self, value: T) {
+        self.try_set(value)
+            .expect("Target channel for the future is full");
+    }
+    /// Set the value of the future, returning an error if the state machine
+    /// can't be woken up
+    ///
+    /// The value is stored even if wakeup has failed.
+    pub fn try_set(self, value: T) -> Result<(), WakeupError> {
         *self.contents.lock()
             .expect("Lock of the future is poisoned") = Some(value);
-        self.channel.send(Notify::Fsm(self.token))
-            .expect("Target channel for the future is full");
+        self.notifier.wakeup()
     }
 }
 
@@ -29,3 +67,22 @@ impl<T:Sized> Future<T> {
             .expect("Lock of the future is poisoned").is_some()
     }
 }
+
+/// Allows awaiting the value from the futures running in `FutureMachine`
+///
+/// Only available with the `futures` feature. The future must be created
+/// by the scope of the `FutureMachine` (see `FutureMachine::build`), so
+/// that its wakeup polls the task.
+#[cfg(feature="futures")]
+impl<T: Sized> ::futures::Future for Future<T> {
+    type Item = T;
+    type Error = ::void::Void;
+    fn poll(&mut self) -> ::futures::Poll<T, ::void::Void> {
+        let mut contents = self.contents.lock()
+            .expect("Lock of the future is poisoned");
+        match contents.take() {
+            Some(value) => Ok(::futures::Async::Ready(value)),
+            None => Ok(::futures::Async::NotReady),
+        }
+    }
+}
@@ -0,0 +1,104 @@
+use std::sync::{Arc, Mutex};
+use std::marker::PhantomData;
+
+use futures::{Future, Async};
+use futures::executor::{spawn, Spawn, Notify};
+use void::{Void, unreachable};
+
+use {Machine, Scope, Response, EventSet, GenericScope, Notifier};
+
+
+struct Wakeup(Mutex<Notifier>);
+
+impl Notify for Wakeup {
+    fn notify(&self, _id: usize) {
+        // Spurious or lost wakeups are harmless here: future is polled
+        // on every event of the state machine anyway
+        self.0.lock().expect("notifier lock is poisoned").wakeup().ok();
+    }
+}
+
+/// A state machine which drives a `futures::Future` to completion
+///
+/// The future is polled on every event delivered to the state machine.
+/// Task notifications of the future are converted into `wakeup` of the
+/// machine, so futures like channels and oneshots work as expected.
+///
+/// The state machine is stopped (`Response::done()`) when future resolves.
+/// Error of the future is expected to be handled inside the future itself,
+/// so the type is `()`, the same as for executors of the futures crate.
+/// Use `LoopFuture` for the other direction, i.e. to run rotor state
+/// machines on a futures executor.
+///
+/// Only available with the `futures` feature.
+pub struct FutureMachine<F, C>
+    where F: Future<Item=(), Error=()>
+{
+    task: Spawn<F>,
+    notify: Arc<Wakeup>,
+    phantom: PhantomData<*const C>,
+}
+
+impl<F, C> FutureMachine<F, C>
+    where F: Future<Item=(), Error=()>
+{
+    /// Create a state machine for the future
+    ///
+    /// The future is first polled on the next loop iteration
+    pub fn new<S: GenericScope>(future: F, scope: &mut S)
+        -> Response<FutureMachine<F, C>, Void>
+    {
+        let notifier = scope.notifier();
+        notifier.wakeup().expect("can't schedule future to be polled");
+        Response::ok(FutureMachine {
+            task: spawn(future),
+            notify: Arc::new(Wakeup(Mutex::new(notifier))),
+            phantom: PhantomData,
+        })
+    }
+    /// Create a state machine for the future made with its scope
+    ///
+    /// This is how futures code awaits rotor: `Future` (e.g. returned by
+    /// `Scope::execute`) and `Promise` implement `futures::Future`, and
+    /// when they are created with the scope passed to `fun`, resolving
+    /// them polls this state machine. So the code using futures may be
+    /// moved to rotor piece by piece.
+    pub fn build<S, G>(scope: &mut S, fun: G)
+        -> Response<FutureMachine<F, C>, Void>
+        where S: GenericScope, G: FnOnce(&mut S) -> F
+    {
+        let future = fun(scope);
+        FutureMachine::new(future, scope)
+    }
+    fn poll(mut self) -> Response<Self, Void> {
+        match self.task.poll_future_notify(&self.notify, 0) {
+            Ok(Async::NotReady) => Response::ok(self),
+            Ok(Async::Ready(())) => Response::done(),
+            Err(()) => Response::done(),
+        }
+    }
+}
+
+impl<F, C> Machine for FutureMachine<F, C>
+    where F: Future<Item=(), Error=()>
+{
+    type Context = C;
+    type Seed = Void;
+    fn create(seed: Void, _scope: &mut Scope<C>) -> Response<Self, Void> {
+        unreachable(seed)
+    }
+    fn ready(self, _events: EventSet, _scope: &mut Scope<C>)
+        -> Response<Self, Void>
+    {
+        self.poll()
+    }
+    fn spawned(self, _scope: &mut Scope<C>) -> Response<Self, Void> {
+        unreachable!();
+    }
+    fn timeout(self, _scope: &mut Scope<C>) -> Response<Self, Void> {
+        self.poll()
+    }
+    fn wakeup(self, _scope: &mut Scope<C>) -> Response<Self, Void> {
+        self.poll()
+    }
+}
@@ -1,29 +1,124 @@
-use std::time::Instant;
+use std::io;
+use std::mem;
+use std::time::{Instant, Duration};
+use std::error::Error;
+use std::collections::VecDeque;
+use std::fmt::{Debug, Write};
+use std::sync::mpsc;
 
 use Slab;
 use mio::{self, Token, Ready};
-use mio::timer::Timeout;
-use mio::deprecated::{EventLoop, Sender};
+use mio::deprecated::{EventLoop};
 use void::{Void, unreachable};
 
-use scope::scope;
-use {SpawnError, Scope, Response, Machine, Time, GenericScope};
-use SpawnError::{NoSlabSpace};
-use loop_time::{make_time, mio_timeout_ms};
-use response::{decompose};
+#[cfg(unix)] use std::os::unix::io::RawFd;
+#[cfg(unix)] use mio::unix::EventedFd;
+
+use scope::{scope, run_deferred, loop_scope};
+use loop_api::{LoopApi, MioLoop, loop_handle};
+use timer::{Timers, arm_timer, rearm_timer, timers_due};
+use config::{grow_slab, max_events_per_tick, token_base, local_token};
+use config::{SlabFull, slab_full, watchdog, Unresponsive};
+use config::{max_poll_timeout, slab_shrink, shrink_slab};
+use config::{spawn_queue, QueueOverflow, slab_room};
+use remote::{RemoteQueue, LoopHandle, MigrateResult, create_handle};
+use remote::{ActionQueue, ContextHandle, create_context_handle};
+use trace::{EventKind, DispatchEvent, TraceWriter};
+use diagnostics::{Diagnostics, Diagnostic};
+use loop_state::{LoopState, touch, replace_timeout, clear_timeout};
+use loop_state::{clear_pause, set_retiring, expire_timeout};
+use loop_state::{reset_idle_timeout, expire_idle_timeout, clear_idle_timeout};
+use loop_state::{MachineMeta, children, update_interest};
+use readiness::split_events;
+#[cfg(unix)] use loop_state::primary_interest;
+use {SpawnError, Scope, Response, Machine, Time, Timeout, PollErrors};
+use {ParentExit, Notifier, LoopScope};
+use error::MigrateError;
+use registry::Registry;
+use metrics::{LoopLatency, SlabOccupancy};
+#[cfg(unix)] use {EventSet, PollOpt};
+use SpawnError::{NoSlabSpace, UserError};
+use loop_time::{make_time, make_instant, mio_timeout_ms};
+use loop_time::millis;
+use response::{decompose, constructor_error, take_deferred, Seeds};
+use response::call_error_hook;
+use snapshot::{StateSerializer, write_snapshot};
+use store::MachineStore;
+use notify::{ShutdownHandle, create_shutdown_handle};
+#[cfg(feature="machine_stats")] use MachineStats;
 
 
 #[doc(hidden)]
 pub enum Timeo {
-    Fsm(Token),
+    Wheel,
+    Fsm(Token, u64),
+    Idle(Token, u64),
+    #[cfg(unix)]
+    Resume(Token, u64, RawFd, EventSet, PollOpt),
+    /// The grace period of `LoopHandle::drain` is over
+    Drain,
+    /// The round of `Config::watchdog`
+    Watchdog,
+    /// Some seed queued with `QueueOverflow::Deadline` expires
+    SpawnDeadline,
+    /// The timer added with `LoopInstance::add_loop_timer`
+    Loop(usize),
+}
+
+impl Timeo {
+    /// Token of the state machine, timeouts are dispatched in this order
+    /// within a single millisecond
+    pub fn token(&self) -> usize {
+        match *self {
+            Timeo::Wheel => 0,
+            Timeo::Fsm(token, _) => token.0,
+            Timeo::Idle(token, _) => token.0,
+            #[cfg(unix)]
+            Timeo::Resume(token, _, _, _, _) => token.0,
+            // State machines expiring at the same time finish first
+            Timeo::Drain => !0,
+            Timeo::Watchdog => !0,
+            Timeo::SpawnDeadline => !0,
+            Timeo::Loop(_) => !0,
+        }
+    }
 }
 
 #[doc(hidden)]
 pub enum Notify {
-    Fsm(Token),
+    Fsm(Token, u64),
+    /// Sent by `Notifier::abort`
+    Abort(Token, u64),
+    /// Seeds are queued by `LoopHandle::spawn`
+    Spawn,
+    /// Closures are queued by `ContextHandle::send`
+    Context,
+    Shutdown,
+    GracefulShutdown,
+    /// Sent by `LoopHandle::drain`
+    Drain(Duration),
+    /// Sent by `LoopHandle::dump_machines`
+    Dump(mpsc::Sender<String>),
+    /// Sent by `LoopHandle::snapshot`
+    Snapshot(mpsc::Sender<io::Result<Vec<u8>>>),
+    /// Sent by `LoopHandle::machine_stats`
+    #[cfg(feature="machine_stats")]
+    Stats(mpsc::Sender<Vec<(Token, MachineStats)>>),
+    /// Sent by `LoopHandle::latency`
+    Latency(mpsc::Sender<Option<LoopLatency>>),
+    /// Sent by `LoopHandle::slab_occupancy`
+    Occupancy(mpsc::Sender<SlabOccupancy>),
+    /// Sent by `LoopHandle::interrupt`, only wakes up the poll
+    Interrupt,
 }
 
 
+/// The timer of the loop itself, see `LoopInstance::add_loop_timer`
+struct LoopTimer<C> {
+    interval: Duration,
+    fun: Box<FnMut(&mut C, &mut LoopScope)>,
+}
+
 /// Standard mio loop handler
 ///
 ///
@@ -41,181 +136,1760 @@ pub enum Notify {
 /// assert!(conn.is_ok());
 /// event_loop.run(&mut handler).unwrap();
 /// ```
-pub struct Handler<M: Machine>
+pub struct Handler<M: Machine, S = Slab<Option<M>>>
 {
-    slab: Slab<(Option<(Timeout, Time)>, M)>,
+    /// The slot is `None` while the state machine is being dispatched,
+    /// so the slab isn't touched on every transition
+    slab: S,
     context: M::Context,
-    channel: Sender<Notify>,
-    start_time: Instant,
+    state: LoopState,
+    /// Seeds waiting for a slot: the parent, if it's linked, and the
+    /// deadline of `QueueOverflow::Deadline`
+    pending: VecDeque<(Token, M::Seed, bool, Option<Time>)>,
+    /// Seeds returned from the constructors, spawned right after them
+    constructed: Vec<(Token, u64, Seeds<M::Seed>)>,
+    /// Batches of the state machines out of `Config::transition_budget`,
+    /// continued on the next iteration
+    postponed: Vec<(Token, u64, Vec<Batch<M::Seed>>)>,
+    timers: Timers,
+    remote: RemoteQueue<M::Seed>,
+    actions: ActionQueue<M::Context>,
+    /// Ready events of the current poll, dispatched in `tick`, and the
+    /// ones left over by `Config::max_events_per_tick`
+    ready: Vec<(Token, u64, Ready)>,
+    /// Set by `enable_dump` when state machines implement `Debug`
+    dump: Option<fn(&Handler<M, S>) -> String>,
+    snapshot: Option<StateSerializer<M>>,
+    before_poll: Option<Box<FnMut(&mut M::Context)>>,
+    after_poll: Option<Box<FnMut(&mut M::Context, usize)>>,
+    /// The slot is `None` while the timer runs and after it's cancelled
+    loop_timers: Vec<Option<LoopTimer<M::Context>>>,
+    /// Number of events received from the current poll
+    polled: usize,
+    /// The slab isn't shrunk below the capacity it was created with
+    initial_capacity: usize,
+    /// Since when the slab occupancy is below `Config::slab_shrink`
+    low_occupancy: Option<Time>,
 }
 
-pub fn create_handler<M: Machine>(slab: Slab<(Option<(Timeout, Time)>, M)>,
-    context: M::Context, channel: Sender<Notify>)
-    -> Handler<M>
+pub fn create_handler<M: Machine, S>(slab: S,
+    context: M::Context, mut state: LoopState, timers: Timers,
+    remote: RemoteQueue<M::Seed>, actions: ActionQueue<M::Context>)
+    -> Handler<M, S>
+    where S: MachineStore<Option<M>>,
 {
+    state.occupancy.capacity = slab.capacity();
     Handler {
+        initial_capacity: slab.capacity(),
         slab: slab,
         context: context,
-        channel: channel,
-        start_time: Instant::now(),
+        state: state,
+        pending: VecDeque::new(),
+        constructed: Vec::new(),
+        postponed: Vec::new(),
+        timers: timers,
+        remote: remote,
+        actions: actions,
+        ready: Vec::new(),
+        dump: None,
+        snapshot: None,
+        before_poll: None,
+        after_poll: None,
+        loop_timers: Vec::new(),
+        polled: 0,
+        low_occupancy: None,
     }
 }
-pub fn set_timeout_opt<S: GenericScope>(option: Option<Time>, scope: &mut S)
+#[cfg_attr(feature="no_deadlines", allow(dead_code))]
+pub fn set_timeout_opt<L: LoopApi + ?Sized>(token: Token, generation: u64,
+    option: Option<Time>, now: Time, loop_api: &mut L)
     -> Option<(Timeout, Time)>
 {
     option.map(|new_ts| {
-        let ms = mio_timeout_ms(scope.now(), new_ts);
-        let tok = scope.timeout_ms(ms)
-            .expect("Can't insert a timeout. You need to \
-                     increase the timer capacity");
-        (tok, new_ts)
+        let ms = mio_timeout_ms(now, new_ts);
+        (loop_api.timeout_ms(token, generation, ms), new_ts)
     })
 }
 
-fn replacer<C, M, N>(token: Token,
-    resp: Response<M, N>, old_timeo: Option<(Timeout, Time)>,
-    scope: &mut Scope<C>, creator: &mut Option<N>)
-    -> Option<(Option<(Timeout, Time)>, M)>
+fn replace<M, S, E, F>(handler: &mut Handler<M, S>,
+    eloop: &mut E, time: Time, token: Token, fun: F)
+    -> Option<Seeds<M::Seed>>
+    where M: Machine, S: MachineStore<Option<M>>, E: MioLoop,
+          F: FnOnce(M, &mut Scope<M::Context>) -> Response<M, M::Seed>
 {
-    let (mach, new, newtime) = decompose(token, resp);
-    let rtime = if newtime != old_timeo.clone().map(|(_, x)| x) {
-        if let Some((tok, _)) = old_timeo {
-            scope.clear_timeout(tok);
-        }
-        set_timeout_opt(newtime, scope)
+    let machine = match handler.slab.get_mut(token).and_then(|m| m.take()) {
+        Some(machine) => machine,
+        // Spurious events are ok in mio
+        None => return None,
+    };
+    handler.state.count_transition(token);
+    let ref mut api = loop_handle(eloop, &mut handler.timers,
+                                  token_base(&handler.state.config));
+    let start = if handler.state.slow_threshold.is_some() ||
+        cfg!(feature="machine_stats")
+    {
+        Some(Instant::now())
     } else {
-        old_timeo
+        None
     };
-    *creator = new;
-    mach.map(|m| (rtime, m)).ok() // the error is already logged in decompose()
+    let outer = handler.state.local_wakeups.dispatch(Some(token));
+    let (resp, deferred) = {
+        let ref mut scope = scope(time, token,
+            &mut handler.context, &mut handler.state, api);
+        take_deferred(call_error_hook(fun(machine, scope), scope))
+    };
+    handler.state.local_wakeups.dispatch(outer);
+    handler.state.defer(token, deferred);
+    if let Some(start) = start {
+        #[cfg(feature="machine_stats")]
+        handler.state.record_busy(token, start);
+        handler.state.check_slow(token, start);
+    }
+    let (mach, new, newtime, yielded) = decompose(resp);
+    match mach {
+        Ok(m) => {
+            if yielded {
+                handler.state.add_yielded(token);
+            }
+            let changed = {
+                let meta = handler.state.get_mut(token)
+                    .expect("state machine has no meta");
+                touch(meta, time);
+                let old = meta.deadline();
+                replace_timeout(meta, token, newtime, time, api);
+                update_interest(meta, token, m.interest(), api);
+                old != meta.deadline()
+            };
+            if changed {
+                handler.state.diagnose(time, Diagnostic::DeadlineChanged {
+                    token: token,
+                    deadline: newtime,
+                });
+            }
+            *handler.slab.get_mut(token)
+                .expect("the slot is kept while dispatching") = Some(m);
+        }
+        Err(err) => {
+            handler.slab.remove(token);
+            // Stopped before the creation is finished, so the error is
+            // for the parent, like an error of the constructor
+            let err = if handler.state.is_creating(token) {
+                handler.state.finish_creation(token, Err(err));
+                None
+            } else {
+                err
+            };
+            if let Some(ref err) = err {
+                handler.state.diagnose(time, Diagnostic::Error {
+                    token: token,
+                    error: &**err,
+                });
+            }
+            if err.is_some() {
+                handler.state.signal_links(token);
+            }
+            let generation = handler.state.generation(token);
+            let children = remove_meta(&mut handler.state, time, token, api);
+            // Seeds queued for retry are owned by the parent
+            handler.pending.retain(|&(parent, _, _, _)| parent != token);
+            if let Some(err) = err {
+                if handler.state.check_fatal(token, err) {
+                    api.shutdown();
+                }
+            }
+            if children > 0 {
+                orphan_children(handler, eloop, time, token, generation);
+            }
+            if handler.state.shutting_down && handler.slab.len() == 0 {
+                eloop.shutdown();
+            }
+        }
+    }
+    new
 }
 
-fn replace<M, F>(slab: &mut Slab<(Option<(Timeout, Time)>, M)>, token: Token, fun: F, scope: &mut Scope<M::Context>, creator: &mut Option<M::Seed>)
-    where M: Machine,
-          F: FnOnce(M, &mut Scope<M::Context>) -> Response<M, M::Seed>
+/// Removes the metadata of the state machine and its timers
+///
+/// Returns the number of children left behind
+fn remove_meta<L: LoopApi>(state: &mut LoopState, time: Time, token: Token,
+    api: &mut L)
+    -> usize
+{
+    match state.remove(token) {
+        Some(mut meta) => {
+            clear_timeout(&mut meta, api);
+            clear_idle_timeout(&mut meta, api);
+            clear_pause(&mut meta, api);
+            state.deregister_tracked(&mut meta, api);
+            state.diagnose(time, Diagnostic::Removed {
+                token: token,
+                label: meta.label(),
+            });
+            children(&meta)
+        }
+        None => 0,
+    }
+}
+
+/// Applies the `ParentExit` policy to the children of the removed machine
+///
+/// Stopped children may have children of their own, so this walks the
+/// whole subtree.
+fn orphan_children<M, S, E>(handler: &mut Handler<M, S>,
+    eloop: &mut E, time: Time, parent: Token,
+    generation: u64)
+    where M: Machine, S: MachineStore<Option<M>>, E: MioLoop,
 {
-    slab.entry(token).and_then(|entry| {
-      let (timeo, m) = entry.remove();
-      replacer(token, fun(m, scope), timeo, scope, creator)
-    }).map(|new_val|{
-      let entry = slab.vacant_entry().expect("The entry was just freed.");
-      entry.insert(new_val)
+    let mut stack = vec![(parent, generation)];
+    while let Some((parent, generation)) = stack.pop() {
+        for (token, policy) in handler.state.orphan_children(parent,
+                                                            generation)
+        {
+            match policy {
+                ParentExit::Ignore => {}
+                ParentExit::Notify => handler.state.add_yielded(token),
+                ParentExit::Stop => {
+                    let generation = handler.state.generation(token);
+                    let left = destroy_machine(handler, eloop, time, token);
+                    if left > 0 {
+                        stack.push((token, generation));
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Removes the state machine calling `Machine::destroyed`
+///
+/// Returns the number of children left behind, the caller applies the
+/// `ParentExit` policy to them.
+fn destroy_machine<M, S, E>(handler: &mut Handler<M, S>,
+    eloop: &mut E, time: Time, token: Token)
+    -> usize
+    where M: Machine, S: MachineStore<Option<M>>, E: MioLoop,
+{
+    let machine = match handler.slab.remove(token) {
+        Some(Some(machine)) => machine,
+        _ => return 0,
+    };
+    let ref mut api = loop_handle(eloop, &mut handler.timers,
+        token_base(&handler.state.config));
+    {
+        let ref mut scope = scope(time, token,
+            &mut handler.context, &mut handler.state, api);
+        machine.destroyed(scope);
+    }
+    let left = remove_meta(&mut handler.state, time, token, api);
+    handler.pending.retain(|&(parent, _, _, _)| parent != token);
+    left
+}
+
+fn spawn_failed<S>(state: &mut LoopState, time: Time, parent: Token,
+    err: &SpawnError<S>)
+{
+    state.diagnose(time, Diagnostic::SpawnFailed {
+        parent: parent,
+        reason: err.description(),
+        error: err.cause(),
     });
 }
 
-fn machine_loop<M, F>(handler: &mut Handler<M>,
-    eloop: &mut EventLoop<Handler<M>>, token: Token, fun: F)
-    where M: Machine,
-          F: FnOnce(M, &mut Scope<M::Context>) -> Response<M, M::Seed>
+fn created(state: &mut LoopState, time: Time, token: Token) {
+    let label = state.get(token).and_then(|meta| meta.label());
+    state.diagnose(time, Diagnostic::Created {
+        token: token,
+        label: label,
+    });
+}
+
+fn create<M, S, E>(handler: &mut Handler<M, S>,
+    eloop: &mut E, time: Time, seed: M::Seed)
+    -> Result<(), SpawnError<M::Seed>>
+    where M: Machine, S: MachineStore<Option<M>>, E: MioLoop,
+{
+    let res = create_with(handler, eloop, time, seed, false, None);
+    spawn_constructed(handler, eloop, time);
+    res.map(|_| ())
+}
+
+/// Creates a state machine spawned by the `parent`
+fn create_child<M, S, E>(handler: &mut Handler<M, S>,
+    eloop: &mut E, time: Time, parent: Token,
+    seed: M::Seed, linked: bool)
+    -> Result<Option<Token>, SpawnError<M::Seed>>
+    where M: Machine, S: MachineStore<Option<M>>, E: MioLoop,
+{
+    let child = try!(create_with(handler, eloop, time, seed, false,
+                                 Some(parent)));
+    if let (true, Some(child)) = (linked, child) {
+        // Nothing is dispatched in between, so the child is alive
+        let generation = handler.state.generation(child);
+        handler.state.watch(parent, child, generation);
+    }
+    Ok(child)
+}
+
+/// Creates a child applying `SlabFull::Evict` when the slab is full
+fn spawn_child<M, S, E>(handler: &mut Handler<M, S>,
+    eloop: &mut E, time: Time, parent: Token,
+    seed: M::Seed, linked: bool)
+    -> Result<Option<Token>, SpawnError<M::Seed>>
+    where M: Machine, S: MachineStore<Option<M>>, E: MioLoop,
+{
+    let mut seed = seed;
+    loop {
+        match create_child(handler, eloop, time, parent, seed, linked) {
+            Err(NoSlabSpace(s)) => {
+                if slab_full(&handler.state.config) != SlabFull::Evict ||
+                    !evict_idle(handler, eloop, time, parent) ||
+                    // The parent is a descendant of the evicted one
+                    handler.state.get(parent).is_none()
+                {
+                    return Err(NoSlabSpace(s));
+                }
+                seed = s;
+            }
+            res => return res,
+        }
+    }
+}
+
+/// Removes the longest idle state machine accepted by the eviction filter
+///
+/// Returns false if there is nothing to evict.
+fn evict_idle<M, S, E>(handler: &mut Handler<M, S>,
+    eloop: &mut E, time: Time, parent: Token)
+    -> bool
+    where M: Machine, S: MachineStore<Option<M>>, E: MioLoop,
+{
+    let victim = {
+        let filter = handler.state.eviction_filter.as_ref();
+        let accepts = |token: Token, meta: &MachineMeta| {
+            token != parent && filter.map(|f| f(token, meta)).unwrap_or(true)
+        };
+        handler.state.machines.iter().enumerate()
+            .filter_map(|(idx, meta)| meta.as_ref().map(|m| (Token(idx), m)))
+            .filter(|&(token, meta)| accepts(token, meta))
+            .min_by_key(|&(_, meta)| meta.last_activity())
+            .map(|(token, _)| token)
+    };
+    let token = match victim {
+        Some(token) => token,
+        None => return false,
+    };
+    debug!("{}: Slab is full, evicting {:?} to spawn a child of {:?}",
+        handler.state.loop_id, token, parent);
+    let generation = handler.state.generation(token);
+    if destroy_machine(handler, eloop, time, token) > 0 {
+        orphan_children(handler, eloop, time, token, generation);
+    }
+    true
+}
+
+/// Keeps the seed until some state machine is removed
+///
+/// Returns the seed to report to the parent if the queue of the parent
+/// is full, see `Config::spawn_queue`.
+fn queue_seed<M, S, E>(handler: &mut Handler<M, S>, eloop: &mut E,
+    time: Time, parent: Token, seed: M::Seed, linked: bool,
+    after_error: bool)
+    -> Option<M::Seed>
+    where M: Machine, S: MachineStore<Option<M>>, E: MioLoop,
+{
+    let queued = match handler.state.get(parent) {
+        Some(meta) => meta.queued_spawns(),
+        None => return None,
+    };
+    let mut deadline = None;
+    let mut rejected = None;
+    match spawn_queue(&handler.state.config) {
+        Some((limit, _)) if queued < limit => {}
+        None => {}
+        Some(_) if after_error => {
+            debug!("{}: Spawn queue of {:?} is full, dropping the seed",
+                handler.state.loop_id, parent);
+            return None;
+        }
+        Some((_, QueueOverflow::Reject)) => return Some(seed),
+        Some((_, QueueOverflow::DropOldest)) => {
+            let oldest = handler.pending.iter()
+                .position(|&(token, _, _, _)| token == parent);
+            if let Some(idx) = oldest {
+                let (_, oldest, _, _) = handler.pending.remove(idx)
+                    .expect("the position is valid");
+                handler.state.unqueue_seed(parent);
+                rejected = Some(oldest);
+            }
+        }
+        Some((_, QueueOverflow::Deadline(timeout))) => {
+            deadline = Some(time + timeout);
+            handler.timers.insert(millis(timeout), Timeo::SpawnDeadline);
+            arm_timer(&mut handler.timers, eloop);
+        }
+    }
+    handler.state.queue_seed(parent);
+    handler.pending.push_back((parent, seed, linked, deadline));
+    rejected
+}
+
+/// Reports the queued seeds which have missed their deadline
+fn expire_queued<M, S, E>(handler: &mut Handler<M, S>, eloop: &mut E)
+    where M: Machine, S: MachineStore<Option<M>>, E: MioLoop,
 {
     let time = handler.loop_time();
+    let mut expired = Vec::new();
+    for _ in 0..handler.pending.len() {
+        let item = handler.pending.pop_front().expect("length is checked");
+        if item.3.map(|deadline| deadline <= time).unwrap_or(false) {
+            expired.push(item);
+        } else {
+            handler.pending.push_back(item);
+        }
+    }
+    for (token, seed, _, _) in expired {
+        handler.state.unqueue_seed(token);
+        let err = NoSlabSpace(seed);
+        spawn_failed(&mut handler.state, time, token, &err);
+        let creator = replace(handler, eloop, time, token,
+            |m, scope| m.spawn_error(scope, err));
+        spawn_loop(handler, eloop, time, token, creator);
+    }
+}
+
+/// Creates a state machine with `Machine::thaw` if `thaw` is set
+///
+/// Returns the token of the new state machine, or `None` if the
+/// constructor returned `Response::done()`.
+fn create_with<M, S, E>(handler: &mut Handler<M, S>,
+    eloop: &mut E, time: Time, seed: M::Seed,
+    thaw: bool, parent: Option<Token>)
+    -> Result<Option<Token>, SpawnError<M::Seed>>
+    where M: Machine, S: MachineStore<Option<M>>, E: MioLoop,
+{
     let ref mut context = handler.context;
-    let ref mut channel = handler.channel;
-    let mut creator = None;
+    let ref mut state = handler.state;
+    let ref mut api = loop_handle(eloop, &mut handler.timers,
+                                  token_base(&state.config));
+    let ref mut slab = handler.slab;
+    let ref mut constructed = handler.constructed;
+    let mut seed = Some(seed);
+    grow_slab(slab, &state.config);
+    state.occupancy.capacity = slab.capacity();
+    let ins = slab.vacancy().map(|token| {
+        let seed = seed.take().unwrap();
+        if let Some((io, interest, opt)) = M::register_seed(&seed) {
+            if let Err(e) = api.register(io, token, interest, opt) {
+                return Err(UserError(Box::new(e)));
+            }
+        }
+        // Meta is added first, so `create` can set the label and priority
+        state.add(token, time);
+        if let Some(parent) = parent {
+            state.set_parent(token, parent);
+        }
+        let outer = state.local_wakeups.dispatch(Some(token));
+        let (resp, deferred) = {
+            let ref mut scope = scope(time, token, context, state, api);
+            let resp = if thaw {
+                M::thaw(seed, scope)
+            } else {
+                M::create(seed, scope)
+            };
+            take_deferred(call_error_hook(resp, scope))
+        };
+        state.local_wakeups.dispatch(outer);
+        state.defer(token, deferred);
+        // The result of the constructor is returned right away
+        state.creations.retain(|c| c.child != token);
+        let (mach, newm, newtime, yielded) = decompose(resp);
+        let m = match mach {
+            Ok(m) => m,
+            Err(err) => {
+                // Sockets of the seed are closed together with it
+                if let Some(mut meta) = state.remove(token) {
+                    clear_idle_timeout(&mut meta, api);
+                    clear_pause(&mut meta, api);
+                    state.deregister_tracked(&mut meta, api);
+                }
+                return constructor_error(token, err).map(|()| None);
+            }
+        };
+        if let Some(meta) = state.get_mut(token) {
+            replace_timeout(meta, token, newtime, time, api);
+            update_interest(meta, token, m.interest(), api);
+        }
+        if yielded {
+            state.add_yielded(token);
+        }
+        slab.insert(token, Some(m));
+        created(state, time, token);
+        if let Some(seeds) = newm {
+            constructed.push((token, state.generation(token), seeds));
+        }
+        Ok(Some(token))
+    });
+    match ins {
+        Some(res) => res,
+        None => Err(NoSlabSpace(seed.expect("expecting seed is still here"))),
+    }
+}
+
+/// A batch of seeds returned from the single action of the state machine
+struct Batch<S> {
+    /// The parent watches the children, see `Response::spawn_link`
+    linked: bool,
+    seeds: Seeds<S>,
+    created: bool,
+    /// State machines created from the batch, see `Scope::spawned_children`
+    children: Vec<Token>,
+    // Seeds are returned from `spawn_error`
+    after_error: bool,
+}
+
+fn batch<S>(seeds: Option<Seeds<S>>, after_error: bool) -> Option<Batch<S>> {
+    seeds.map(|seeds| Batch {
+        linked: match seeds {
+            Seeds::Linked(..) => true,
+            _ => false,
+        },
+        seeds: seeds,
+        created: false,
+        children: Vec::new(),
+        after_error: after_error,
+    })
+}
+
+fn spawn_loop<M, S, E>(handler: &mut Handler<M, S>,
+    eloop: &mut E, time: Time, token: Token,
+    creator: Option<Seeds<M::Seed>>)
+    where M: Machine, S: MachineStore<Option<M>>, E: MioLoop,
+{
+    // The stack only allocates when `spawn_error` spawns something
+    // in the middle of the batch
+    let mut stack = Vec::new();
+    stack.extend(batch(creator, false));
+    spawn_batches(handler, eloop, time, token, stack);
+}
+
+fn spawn_batches<M, S, E>(handler: &mut Handler<M, S>,
+    eloop: &mut E, time: Time, token: Token,
+    mut stack: Vec<Batch<M::Seed>>)
+    where M: Machine, S: MachineStore<Option<M>>, E: MioLoop,
+{
+    loop {
+        let next = match stack.last_mut() {
+            Some(top) => top.seeds.next(),
+            None => break,
+        };
+        let seed = match next {
+            Some(seed) => seed,
+            None => {
+                let created = stack.last().map(|b| b.created)
+                    .unwrap_or(false);
+                if created && handler.state.over_budget(token) {
+                    // `spawned` is called on the next iteration, the
+                    // batch is exhausted so it's popped right away
+                    let generation = handler.state.generation(token);
+                    handler.postponed.push((token, generation, stack));
+                    break;
+                }
+                let top = stack.pop().expect("batch is on the stack");
+                if top.created {
+                    let new = spawned(handler, eloop, time, token,
+                        top.children);
+                    stack.extend(batch(new, false));
+                }
+                if handler.state.get(token).is_none() {
+                    // The state machine is stopped, nobody to spawn for
+                    break;
+                }
+                continue;
+            }
+        };
+        let (after_error, linked) = stack.last()
+            .map(|b| (b.after_error, b.linked))
+            .unwrap_or((false, false));
+        match spawn_child(handler, eloop, time, token, seed, linked) {
+            // Reported to the parent when the creation is finished
+            Ok(Some(child)) if handler.state.is_creating(child) => {}
+            Ok(child) => {
+                if let Some(top) = stack.last_mut() {
+                    top.created = true;
+                    top.children.extend(child);
+                }
+            }
+            Err(NoSlabSpace(seed)) => {
+                let queue = slab_full(&handler.state.config) ==
+                    SlabFull::Queue;
+                let rejected = if after_error || queue {
+                    // The seed is returned from `spawn_error` or the policy
+                    // says so, retry it when some state machine is removed
+                    queue_seed(handler, eloop, time, token, seed, linked,
+                        after_error)
+                } else {
+                    Some(seed)
+                };
+                if let Some(seed) = rejected {
+                    let err = NoSlabSpace(seed);
+                    spawn_failed(&mut handler.state, time, token, &err);
+                    let new = replace(handler, eloop, time, token,
+                        |m, scope| m.spawn_error(scope, err));
+                    stack.extend(batch(new, true));
+                }
+            }
+            Err(err) => {
+                spawn_failed(&mut handler.state, time, token, &err);
+                let new = replace(handler, eloop, time, token,
+                    |m, scope| m.spawn_error(scope, err));
+                stack.extend(batch(new, true));
+            }
+        }
+        if handler.state.get(token).is_none() {
+            break;
+        }
+    }
+    if !handler.constructed.is_empty() {
+        spawn_constructed(handler, eloop, time);
+    }
+}
+
+/// Spawns the seeds returned from `Machine::create` of the new children
+fn spawn_constructed<M, S, E>(handler: &mut Handler<M, S>,
+    eloop: &mut E, time: Time)
+    where M: Machine, S: MachineStore<Option<M>>, E: MioLoop,
+{
+    while let Some((token, generation, seeds)) = handler.constructed.pop() {
+        if handler.state.is_current(token, generation) {
+            spawn_loop(handler, eloop, time, token, Some(seeds));
+        }
+    }
+}
+
+/// Calls `Machine::spawned` with the children of the batch
+fn spawned<M, S, E>(handler: &mut Handler<M, S>,
+    eloop: &mut E, time: Time, token: Token,
+    children: Vec<Token>)
+    -> Option<Seeds<M::Seed>>
+    where M: Machine, S: MachineStore<Option<M>>, E: MioLoop,
+{
+    handler.state.spawned_children = children;
+    let new = replace(handler, eloop, time, token,
+        |m, scope| m.spawned(scope));
+    handler.state.spawned_children.clear();
+    new
+}
+
+/// Notifies the parents about the finished suspended creations
+fn finish_creations<M, S, E>(handler: &mut Handler<M, S>,
+    eloop: &mut E, time: Time)
+    where M: Machine, S: MachineStore<Option<M>>, E: MioLoop,
+{
+    while !handler.state.creations.is_empty() {
+        let creations = mem::replace(&mut handler.state.creations,
+                                     Vec::new());
+        for c in creations {
+            if !handler.state.is_current(c.parent, c.generation) {
+                continue;
+            }
+            let creator = match c.result {
+                Ok(()) => spawned(handler, eloop, time, c.parent,
+                                  vec![c.child]),
+                Err(Some(e)) => {
+                    let err = SpawnError::ConstructorError(c.child, e);
+                    spawn_failed(&mut handler.state, time, c.parent, &err);
+                    replace(handler, eloop, time, c.parent,
+                        |m, scope| m.spawn_error(scope, err))
+                }
+                Err(None) => continue,
+            };
+            spawn_loop(handler, eloop, time, c.parent, creator);
+        }
+    }
+}
+
+fn retry_pending<M, S, E>(handler: &mut Handler<M, S>,
+    eloop: &mut E, time: Time)
+    where M: Machine, S: MachineStore<Option<M>>, E: MioLoop,
+{
+    while let Some((token, seed, linked, deadline)) =
+        handler.pending.pop_front()
     {
-        let ref mut scope = scope(time, token, context, channel, eloop);
-        replace(&mut handler.slab, token, fun, scope, &mut creator)
-        // Spurious events are ok in mio
+        handler.state.unqueue_seed(token);
+        let creator = match create_child(handler, eloop, time, token, seed,
+                                         linked)
+        {
+            Ok(Some(child)) if handler.state.is_creating(child) => None,
+            Ok(child) => {
+                spawned(handler, eloop, time, token,
+                    child.into_iter().collect())
+            }
+            Err(NoSlabSpace(seed)) => {
+                handler.pending.push_front((token, seed, linked, deadline));
+                handler.state.queue_seed(token);
+                break;
+            }
+            Err(err) => {
+                spawn_failed(&mut handler.state, time, token, &err);
+                replace(handler, eloop, time, token,
+                    |m, scope| m.spawn_error(scope, err))
+            }
+        };
+        spawn_loop(handler, eloop, time, token, creator);
     }
-    while let Some(new) = creator.take() {
-        let mut new = Some(new);
-        let ins = handler.slab.vacant_entry().map(|entry| {
-            let token = entry.index();
-            entry.insert({
-              let ref mut scope = scope(time, token, context, channel, eloop);
-              let (mach, newm, newtime) = decompose(token,
-                  M::create(new.take().unwrap(), scope));
-              newm.map(|x| unreachable(x));
-              let m = mach.expect("You can't return Response::done() \
-                    from Machine::create() until new release of slab crate. \
-                    (requires insert_with_opt)");
-              let timepair = newtime.map(|new_ts| {
-                  let ms = mio_timeout_ms(scope.now(), new_ts);
-                  let tok = scope.timeout_ms(ms)
-                      .expect("Can't insert a timeout. You need to \
-                               increase the timer capacity");
-                  (tok, new_ts)
-              });
-              (timepair, m)
-            })
-        }).is_none();
-        if ins {
-            // TODO(tailhook) process other errors here, when they can
-            // be returned from handler
-            let err = NoSlabSpace(new.expect("expecting seed is still here"));
-
-            let ref mut scope = scope(time, token, context, channel, eloop);
-            replace(&mut handler.slab, token, |m, scope| m.spawn_error(scope, err), scope, &mut creator)
-        } else {
-            let ref mut scope = scope(time, token, context, channel, eloop);
-            replace(&mut handler.slab, token, |m, scope| m.spawned(scope), scope, &mut creator)
+}
+
+fn machine_loop<M, S, E, F>(handler: &mut Handler<M, S>,
+    eloop: &mut E, token: Token,
+    generation: Option<u64>, kind: EventKind, fun: F)
+    where M: Machine, S: MachineStore<Option<M>>, E: MioLoop,
+          F: FnOnce(M, &mut Scope<M::Context>) -> Response<M, M::Seed>
+{
+    if let Some(generation) = generation {
+        if !handler.state.is_current(token, generation) {
+            // The event is for the state machine that is already removed
+            return;
         }
     }
+    let time = handler.loop_time();
+    if let EventKind::Ready(_) = kind {
+        let base = token_base(&handler.state.config);
+        if let Some(meta) = handler.state.get_mut(token) {
+            let ref mut api = loop_handle(eloop, &mut handler.timers, base);
+            reset_idle_timeout(meta, token, api);
+        }
+    }
+    #[cfg(feature="machine_stats")]
+    handler.state.record_event(token, &kind);
+    handler.state.events.push(time, token, kind);
+    if let Some(ref mut recorder) = handler.state.recorder {
+        recorder.record(time, token, kind);
+    }
+    let creator = replace(handler, eloop, time, token, fun);
+    spawn_loop(handler, eloop, time, token, creator);
+    finish_dispatch(handler, eloop, time);
+}
+
+/// Continues the batches postponed by `Config::transition_budget`
+fn resume_postponed<M, S, E>(handler: &mut Handler<M, S>, eloop: &mut E)
+    where M: Machine, S: MachineStore<Option<M>>, E: MioLoop,
+{
+    let postponed = mem::replace(&mut handler.postponed, Vec::new());
+    for (token, generation, stack) in postponed {
+        if !handler.state.is_current(token, generation) {
+            continue;
+        }
+        let time = handler.loop_time();
+        spawn_batches(handler, eloop, time, token, stack);
+        finish_dispatch(handler, eloop, time);
+    }
+}
+
+/// Processes what the action has left for the loop
+fn finish_dispatch<M, S, E>(handler: &mut Handler<M, S>,
+    eloop: &mut E, time: Time)
+    where M: Machine, S: MachineStore<Option<M>>, E: MioLoop,
+{
+    if !handler.state.creations.is_empty() {
+        finish_creations(handler, eloop, time);
+    }
+    if !handler.pending.is_empty() {
+        retry_pending(handler, eloop, time);
+    }
+    if !handler.state.deferred.is_empty() {
+        let ref mut api = loop_handle(eloop, &mut handler.timers,
+                                      token_base(&handler.state.config));
+        run_deferred(&mut handler.state, api);
+    }
     if handler.slab.is_empty() {
         eloop.shutdown();
     }
 }
 
-impl<M: Machine> Handler<M>
+/// Dispatches buffered ready events by priority
+///
+/// Within the same priority events are dispatched in token order starting
+/// from the cursor, which is moved on every poll. So a busy state machine
+/// with low token can't starve the others. Events over the
+/// `Config::max_events_per_tick` are left for the next iteration.
+fn dispatch_ready<M, S, E>(handler: &mut Handler<M, S>,
+    eloop: &mut E)
+    where M: Machine, S: MachineStore<Option<M>>, E: MioLoop,
+{
+    if handler.ready.is_empty() {
+        return;
+    }
+    let mut events = mem::replace(&mut handler.ready, Vec::new());
+    {
+        let state = &handler.state;
+        let cursor = state.ready_cursor;
+        events.sort_by_key(|&(token, _, _)| {
+            let priority = state.get(token).map(|m| m.priority()).unwrap_or(0);
+            (-(priority as i16), token.0 < cursor, token.0)
+        });
+    }
+    // Left over events may be reported again by the next poll
+    events.dedup_by(|a, b| {
+        if a.0 == b.0 && a.1 == b.1 {
+            b.2 = b.2 | a.2;
+            true
+        } else {
+            false
+        }
+    });
+    if handler.state.shuffler.is_some() {
+        // Priorities are still respected, the sort is stable
+        handler.state.shuffle(&mut events);
+        let state = &handler.state;
+        events.sort_by_key(|&(token, _, _)| {
+            -(state.get(token).map(|m| m.priority()).unwrap_or(0) as i16)
+        });
+    }
+    let rest = match max_events_per_tick(&handler.state.config) {
+        Some(max) if events.len() > max => events.split_off(max),
+        _ => Vec::new(),
+    };
+    handler.state.ready_cursor = match rest.first() {
+        Some(&(token, _, _)) => token.0,
+        None => events[0].0 .0 + 1,
+    };
+    for &(token, generation, events) in &events {
+        // Delivered by `Scope::resume`
+        if handler.state.hold(token, generation, events) {
+            continue;
+        }
+        if let Some(start) = handler.state.poll_returned {
+            if let Some(ref mut latency) = handler.state.latency {
+                let delay = start.elapsed();
+                latency.dispatch.record(delay.as_secs() * 1_000_000 +
+                    (delay.subsec_nanos() / 1000) as u64);
+            }
+        }
+        machine_loop(handler, eloop, token, Some(generation),
+            EventKind::Ready(events),
+            |m, scope| { m.ready(events, scope) })
+    }
+    if !rest.is_empty() {
+        handler.ready.extend(rest);
+        return;
+    }
+    // Keep the allocation for the next poll
+    events.clear();
+    if handler.ready.is_empty() {
+        handler.ready = events;
+    }
+}
+
+/// The shorter of the poll timeouts, `None` means no timeout
+fn min_timeout(a: Option<Duration>, b: Option<Duration>)
+    -> Option<Duration>
+{
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        (a, b) => a.or(b),
+    }
+}
+
+/// Pings the state machines enrolled into the watchdog, and handles the
+/// ones which haven't acknowledged the previous ping
+fn watchdog_round<M, S, E>(handler: &mut Handler<M, S>, eloop: &mut E)
+    where M: Machine, S: MachineStore<Option<M>>, E: MioLoop,
+{
+    let (interval, action) = match watchdog(&handler.state.config) {
+        Some(watchdog) => watchdog,
+        None => return,
+    };
+    handler.timers.insert(millis(interval), Timeo::Watchdog);
+    let time = handler.loop_time();
+    let (ping, stuck) = handler.state.watchdog_round(time);
+    for token in stuck {
+        handler.state.report_stuck(token);
+        if action == Unresponsive::Abort {
+            let generation = handler.state.generation(token);
+            if destroy_machine(handler, eloop, time, token) > 0 {
+                orphan_children(handler, eloop, time, token, generation);
+            }
+        }
+    }
+    for (token, generation) in ping {
+        handler.state.ping_fired = true;
+        machine_loop(handler, eloop, token, Some(generation),
+            EventKind::Wakeup,
+            |m, scope| { m.wakeup(scope) });
+        handler.state.ping_fired = false;
+    }
+    if handler.state.shutting_down && handler.slab.len() == 0 {
+        eloop.shutdown();
+    }
+}
+
+/// Runs the loop timer and schedules it again unless it's cancelled
+fn fire_loop_timer<M, S, E>(handler: &mut Handler<M, S>, eloop: &mut E,
+    index: usize)
+    where M: Machine, S: MachineStore<Option<M>>, E: MioLoop,
+{
+    let mut timer = match handler.loop_timers[index].take() {
+        Some(timer) => timer,
+        None => return,
+    };
+    let time = handler.loop_time();
+    let mut interval = Some(timer.interval);
+    {
+        let ref mut api = loop_handle(eloop, &mut handler.timers,
+                                      token_base(&handler.state.config));
+        let ref mut scope = loop_scope(time, &mut interval,
+                                       &mut handler.state, api);
+        (timer.fun)(&mut handler.context, scope);
+    }
+    if let Some(interval) = interval {
+        timer.interval = interval;
+        handler.timers.insert(millis(interval), Timeo::Loop(index));
+        handler.loop_timers[index] = Some(timer);
+    }
+}
+
+fn fire_timeout<M, S, E>(handler: &mut Handler<M, S>,
+    eloop: &mut E, timeo: Timeo)
+    where M: Machine, S: MachineStore<Option<M>>, E: MioLoop,
 {
+    match timeo {
+        Timeo::Wheel => {}
+        Timeo::Drain => {
+            if handler.slab.len() > 0 {
+                debug!("{}: Grace period is over, destroying {} state \
+                    machines", handler.state.loop_id, handler.slab.len());
+            }
+            handler.destroy_all(eloop);
+            eloop.shutdown();
+        }
+        Timeo::Watchdog => watchdog_round(handler, eloop),
+        Timeo::SpawnDeadline => expire_queued(handler, eloop),
+        Timeo::Loop(index) => fire_loop_timer(handler, eloop, index),
+        Timeo::Fsm(token, generation) => {
+            if handler.state.is_current(token, generation) {
+                let start = handler.timers.start();
+                let ref mut state = handler.state;
+                state.record_lateness(token, start);
+                // So the timer is not cancelled or reused when replaced
+                if let Some(meta) = state.machines[token.0].as_mut() {
+                    expire_timeout(meta);
+                }
+            }
+            machine_loop(handler, eloop, token, Some(generation),
+                EventKind::Timeout,
+                |m, scope| { m.timeout(scope) })
+        }
+        Timeo::Idle(token, generation) => {
+            if !handler.state.is_current(token, generation) {
+                return;
+            }
+            if let Some(meta) = handler.state.get_mut(token) {
+                expire_idle_timeout(meta);
+            }
+            handler.state.idle_fired = true;
+            machine_loop(handler, eloop, token, Some(generation),
+                EventKind::Timeout,
+                |m, scope| { m.timeout(scope) });
+            handler.state.idle_fired = false;
+        }
+        #[cfg(unix)]
+        Timeo::Resume(token, generation, fd, interest, opt) => {
+            if !handler.state.is_current(token, generation) {
+                return;
+            }
+            let paused = handler.state.get_mut(token)
+                .map(|meta| meta.take_pause().is_some())
+                .unwrap_or(false);
+            // The primary socket follows `Machine::interest`, and is
+            // registered by `Scope::resume` if the machine is paused
+            let interest = match handler.state.get(token) {
+                Some(meta) if meta.is_paused() &&
+                    primary_interest(meta, fd).is_some() => EventSet::none(),
+                Some(meta) => primary_interest(meta, fd).unwrap_or(interest),
+                None => interest,
+            };
+            // Otherwise reading is already resumed by state machine
+            if paused {
+                let base = token_base(&handler.state.config);
+                let res = eloop.reregister(&EventedFd(&fd),
+                    Token(base + token.0), interest, opt);
+                if let Err(e) = res {
+                    if cfg!(feature = "log_errors") {
+                        warn!("{}: Can't resume reading of state machine \
+                               {:?}: {}", handler.state.loop_id, token, e);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Wakes up all the state machines to let them see
+/// `Scope::is_shutting_down`, returns false if the loop is stopped since
+/// there are no state machines
+fn graceful_shutdown<M, S, E>(handler: &mut Handler<M, S>,
+    eloop: &mut E)
+    -> bool
+    where M: Machine, S: MachineStore<Option<M>>, E: MioLoop,
+{
+    handler.state.shutting_down = true;
+    if handler.slab.len() == 0 {
+        eloop.shutdown();
+        return false;
+    }
+    let machines: Vec<_> = handler.state.machines.iter()
+        .enumerate()
+        .filter(|&(_, meta)| meta.is_some())
+        .map(|(idx, _)| Token(idx))
+        .collect();
+    for token in machines {
+        let generation = handler.state.generation(token);
+        machine_loop(handler, eloop, token, Some(generation),
+            EventKind::Wakeup,
+            |m, scope| { m.wakeup(scope) })
+    }
+    true
+}
+
+/// Calls `Machine::config_changed` of every state machine, see
+/// `ContextHandle::reconfigure`
+fn broadcast_config<M, S, E>(handler: &mut Handler<M, S>, eloop: &mut E)
+    where M: Machine, S: MachineStore<Option<M>>, E: MioLoop,
+{
+    // The state machines spawned during the round see the new config
+    let machines: Vec<_> = handler.state.machines.iter()
+        .enumerate()
+        .filter(|&(_, meta)| meta.is_some())
+        .map(|(idx, _)| (Token(idx), handler.state.generation(Token(idx))))
+        .collect();
+    for (token, generation) in machines {
+        machine_loop(handler, eloop, token, Some(generation),
+            EventKind::Wakeup,
+            |m, scope| { m.config_changed(scope) })
+    }
+}
+
+impl<M: Machine, S: MachineStore<Option<M>>> Handler<M, S>
+{
+    /// Removes all state machines calling `Machine::destroyed`
+    pub fn destroy_all<E: MioLoop>(&mut self, eloop: &mut E) {
+        let time = self.loop_time();
+        let tokens: Vec<_> = self.state.machines.iter()
+            .enumerate()
+            .filter(|&(_, meta)| meta.is_some())
+            .map(|(idx, _)| Token(idx))
+            .collect();
+        for token in tokens {
+            let machine = match self.slab.remove(token) {
+                Some(Some(machine)) => machine,
+                _ => continue,
+            };
+            let ref mut api = loop_handle(eloop, &mut self.timers,
+                                          token_base(&self.state.config));
+            {
+                let ref mut scope = scope(time, token,
+                    &mut self.context, &mut self.state, api);
+                machine.destroyed(scope);
+            }
+            remove_meta(&mut self.state, time, token, api);
+        }
+        self.pending.clear();
+    }
+    pub fn set_snapshot_serializer(&mut self, fun: StateSerializer<M>) {
+        self.snapshot = Some(fun);
+    }
+    pub fn set_before_poll(&mut self, hook: Box<FnMut(&mut M::Context)>) {
+        self.before_poll = Some(hook);
+    }
+    pub fn set_after_poll(&mut self,
+        hook: Box<FnMut(&mut M::Context, usize)>)
+    {
+        self.after_poll = Some(hook);
+    }
+    pub fn add_loop_timer<E: MioLoop>(&mut self, eloop: &mut E,
+        interval: Duration, fun: Box<FnMut(&mut M::Context, &mut LoopScope)>)
+    {
+        let timer = Some(LoopTimer { interval: interval, fun: fun });
+        // Slots of the cancelled timers are reused
+        let index = match self.loop_timers.iter().position(|t| t.is_none()) {
+            Some(index) => {
+                self.loop_timers[index] = timer;
+                index
+            }
+            None => {
+                self.loop_timers.push(timer);
+                self.loop_timers.len() - 1
+            }
+        };
+        self.timers.insert(millis(interval), Timeo::Loop(index));
+        arm_timer(&mut self.timers, eloop);
+    }
+    pub fn before_poll(&mut self) {
+        self.polled = 0;
+        if let Some(ref mut hook) = self.before_poll {
+            hook(&mut self.context);
+        }
+    }
+    pub fn write_snapshot(&mut self, out: &mut io::Write) -> io::Result<()> {
+        let now = self.loop_time();
+        write_snapshot(out, now, &self.slab, &self.state, self.pending.len(),
+            self.timers.pending(), self.timers.next_deadline(),
+            self.snapshot.as_mut())
+    }
+    pub fn remote_handle(&self) -> LoopHandle<M::Seed> {
+        create_handle(&self.remote, &self.state.channel)
+    }
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        create_shutdown_handle(&self.state.channel)
+    }
+    pub fn context_handle(&self) -> ContextHandle<M::Context> {
+        create_context_handle(&self.actions, &self.state.channel)
+    }
+    /// Time of the current loop iteration
+    ///
+    /// The clock is read once per poll, see `LoopState::clock`
     pub fn loop_time(&self) -> Time {
-        let now = Instant::now();
-        return make_time(self.start_time, now);
+        make_time(self.timers.start(), self.state.clock().0)
+    }
+    /// Forget the time of the previous iteration, called before poll
+    pub fn reset_clock(&self) {
+        self.state.reset_clock();
+    }
+    /// Returns true if some state machine waits to be resumed or some
+    /// ready events are left undispatched, i.e. the loop must not block
+    /// in poll
+    pub fn has_yielded(&self) -> bool {
+        !self.state.yielded.is_empty() || !self.ready.is_empty() ||
+            !self.state.exits.is_empty() ||
+            !self.state.released.is_empty() ||
+            !self.state.immediate.is_empty() ||
+            !self.state.local_wakeups.is_empty() ||
+            !self.postponed.is_empty()
+    }
+    /// Limits the `timeout` of the next poll
+    ///
+    /// Zero if there is work left for the next iteration, and at most
+    /// `Config::max_poll_timeout` otherwise.
+    pub fn poll_timeout(&self, timeout: Option<Duration>)
+        -> Option<Duration>
+    {
+        if self.has_yielded() {
+            return Some(Duration::new(0, 0));
+        }
+        let timeout = min_timeout(timeout, self.timers.unarmed_delay());
+        min_timeout(timeout, max_poll_timeout(&self.state.config))
+    }
+    pub fn context(&self) -> &M::Context {
+        &self.context
+    }
+    pub fn set_fatal_filter(&mut self, filter: Box<Fn(Token, &Error) -> bool>)
+    {
+        self.state.fatal_filter = Some(filter);
+    }
+    /// Schedules the first round of `Config::watchdog`
+    pub fn start_watchdog<E: MioLoop>(&mut self, eloop: &mut E) {
+        if let Some((interval, _)) = watchdog(&self.state.config) {
+            self.timers.insert(millis(interval), Timeo::Watchdog);
+            arm_timer(&mut self.timers, eloop);
+        }
     }
-    pub fn add_machine_with<F>(&mut self, eloop: &mut EventLoop<Self>, fun: F)
-        -> Result<(), SpawnError<()>>
-        where F: FnOnce(&mut Scope<M::Context>) -> Response<M, Void>
+    pub fn set_watchdog_hook(&mut self,
+        hook: Box<Fn(Token, &MachineMeta)>)
+    {
+        self.state.watchdog_hook = Some(hook);
+    }
+    pub fn set_eviction_filter(&mut self,
+        filter: Box<Fn(Token, &MachineMeta) -> bool>)
+    {
+        self.state.eviction_filter = Some(filter);
+    }
+    pub fn set_diagnostics(&mut self, diagnostics: Box<Diagnostics>) {
+        self.state.set_diagnostics(diagnostics);
+    }
+    pub fn set_error_hook(&mut self, hook: Box<FnMut(Token, &Error)>) {
+        self.state.error_hook = Some(hook);
+    }
+    pub fn poll_error(&mut self, err: &io::Error) -> bool {
+        self.state.poll_error(err)
+    }
+    #[cfg(feature="machine_stats")]
+    pub fn machine_stats(&self) -> Vec<(Token, MachineStats)> {
+        self.state.machine_stats()
+    }
+    pub fn latency(&self) -> Option<LoopLatency> {
+        self.state.latency.clone()
+    }
+    pub fn registry(&self) -> Registry {
+        self.state.registry.clone()
+    }
+    pub fn poll_errors(&self) -> PollErrors {
+        self.state.poll_errors
+    }
+    pub fn slab_occupancy(&self) -> SlabOccupancy {
+        self.state.occupancy
+    }
+    pub fn dropped_wakeups(&self) -> u64 {
+        self.state.channel.dropped()
+    }
+    pub fn shuffle_seed(&self) -> Option<u64> {
+        self.state.shuffler.as_ref().map(|s| s.seed())
+    }
+    pub fn set_recorder(&mut self, recorder: TraceWriter) {
+        self.state.recorder = Some(recorder);
+    }
+    pub fn set_poll_error_hook(&mut self, hook: Box<FnMut(&io::Error)>) {
+        self.state.poll_error_hook = Some(hook);
+    }
+    pub fn set_slow_callback_hook(&mut self,
+        hook: Box<FnMut(Token, Option<&'static str>, Duration)>)
+    {
+        self.state.slow_hook = Some(hook);
+    }
+    pub fn take_fatal_error(&mut self) -> Option<(Token, Box<Error>)> {
+        self.state.fatal_error.take()
+    }
+    pub fn into_context(self) -> M::Context {
+        self.context
+    }
+    /// Removes the state machine converting it into a seed
+    pub fn freeze<E: MioLoop>(&mut self, eloop: &mut E, token: Token)
+        -> Result<M::Seed, MigrateError>
+    {
+        if self.state.get(token).is_none() {
+            return Err(MigrateError::NotFound);
+        }
+        let machine = match self.slab.get_mut(token).and_then(|m| m.take()) {
+            Some(machine) => machine,
+            None => return Err(MigrateError::NotFound),
+        };
+        let time = self.loop_time();
+        let generation = self.state.generation(token);
+        let frozen = {
+            let ref mut api = loop_handle(eloop, &mut self.timers,
+                                          token_base(&self.state.config));
+            let res = {
+                let ref mut scope = scope(time, token,
+                    &mut self.context, &mut self.state, api);
+                machine.freeze(scope)
+            };
+            match res {
+                Ok(seed) => {
+                    self.slab.remove(token);
+                    let children = remove_meta(&mut self.state, time,
+                                               token, api);
+                    self.pending.retain(|&(parent, _, _, _)| parent != token);
+                    Some((seed, children))
+                }
+                Err(machine) => {
+                    *self.slab.get_mut(token)
+                        .expect("the slot is kept while freezing") =
+                        Some(machine);
+                    None
+                }
+            }
+        };
+        match frozen {
+            Some((seed, children)) => {
+                if children > 0 {
+                    orphan_children(self, eloop, time, token, generation);
+                }
+                Ok(seed)
+            }
+            None => Err(MigrateError::Refused),
+        }
+    }
+    /// Create a state machine from the seed returned by `freeze`
+    pub fn thaw<E: MioLoop>(&mut self, eloop: &mut E, seed: M::Seed)
+        -> Result<(), SpawnError<M::Seed>>
+    {
+        let time = self.loop_time();
+        let res = create_with(self, eloop, time, seed, true, None);
+        spawn_constructed(self, eloop, time);
+        res.map(|_| ())
+    }
+    fn migrate<E: MioLoop>(&mut self, eloop: &mut E, token: Token,
+        target: &LoopHandle<M::Seed>)
+        -> MigrateResult<M::Seed>
+    {
+        let seed = try!(self.freeze(eloop, token));
+        match target.thaw(seed) {
+            Ok(result) => Ok(result),
+            Err(seed) => {
+                // The slot of the state machine is free, so this fails
+                // only if `thaw` fails
+                if let Err(e) = self.thaw(eloop, seed) {
+                    if cfg!(feature = "log_errors") {
+                        warn!("{}: Can't restore state machine {:?} \
+                               after failed migration: {}",
+                               self.state.loop_id, token, e);
+                    }
+                }
+                Err(MigrateError::Closed)
+            }
+        }
+    }
+    /// Marks the machines with the label as retiring and creates new ones
+    pub fn replace_group<E: MioLoop>(&mut self, eloop: &mut E,
+        label: &'static str, seeds: Vec<M::Seed>)
+        -> Vec<Result<(), SpawnError<M::Seed>>>
+    {
+        let mut old = Vec::new();
+        for (idx, meta) in self.state.machines.iter_mut().enumerate() {
+            if let Some(ref mut meta) = *meta {
+                if meta.label() == Some(label) && !meta.is_retiring() {
+                    set_retiring(meta);
+                    old.push(Token(idx));
+                }
+            }
+        }
+        for token in old {
+            let generation = self.state.generation(token);
+            machine_loop(self, eloop, token, Some(generation),
+                EventKind::Wakeup,
+                |m, scope| { m.wakeup(scope) })
+        }
+        let time = self.loop_time();
+        seeds.into_iter().map(|seed| create(self, eloop, time, seed)).collect()
+    }
+    pub fn add_seed<E: MioLoop>(&mut self, eloop: &mut E, seed: M::Seed)
+        -> Result<Option<Notifier>, SpawnError<M::Seed>>
+    {
+        let time = self.loop_time();
+        let res = create_with(self, eloop, time, seed, false, None)
+            .map(|token| token.map(|t| self.state.notifier(t)));
+        spawn_constructed(self, eloop, time);
+        res
+    }
+
+    /// Creates the state machines from all the seeds
+    ///
+    /// Nothing is created if the slab can't fit all of them. If some
+    /// constructor fails, the state machines already created are aborted.
+    pub fn spawn_uniform<E: MioLoop>(&mut self, eloop: &mut E,
+        seeds: Vec<M::Seed>)
+        -> Result<Vec<Notifier>, SpawnError<M::Seed>>
+    {
+        if slab_room(&self.slab, &self.state.config) < seeds.len() {
+            let seed = seeds.into_iter().next()
+                .expect("the group doesn't fit, so it's not empty");
+            return Err(NoSlabSpace(seed));
+        }
+        let mut notifiers = Vec::with_capacity(seeds.len());
+        for seed in seeds {
+            match self.add_seed(eloop, seed) {
+                Ok(notifier) => notifiers.extend(notifier),
+                Err(e) => {
+                    for notifier in &notifiers {
+                        notifier.abort().ok();
+                    }
+                    return Err(e);
+                }
+            }
+        }
+        Ok(notifiers)
+    }
+
+    pub fn add_machine_with<E, F>(&mut self, eloop: &mut E, fun: F)
+        -> Result<Notifier, SpawnError<()>>
+        where E: MioLoop,
+              F: FnOnce(&mut Scope<M::Context>) -> Response<M, Void>
     {
         let time = self.loop_time();
         let ref mut context = self.context;
-        let ref mut channel = self.channel;
-        let res = self.slab.vacant_entry().map(|entry| {
-          let token = entry.index();
-          entry.insert({
-            let ref mut scope = scope(time, token, context, channel, eloop);
-            let (mach, void, timeout) =  decompose(token, fun(scope));
-            void.map(|x| unreachable(x));
-            let m = mach.expect("You can't return Response::done() or \
-                  Reponse::error() from Machine::create() until new release \
-                  of slab crate. (requires insert_with_opt)");
-            let to = set_timeout_opt(timeout, scope);
-            (to, m)
-          });
+        let ref mut state = self.state;
+        let ref mut api = loop_handle(eloop, &mut self.timers,
+                                      token_base(&state.config));
+        let ref mut slab = self.slab;
+        grow_slab(slab, &state.config);
+        state.occupancy.capacity = slab.capacity();
+        let res = slab.vacancy().map(|token| {
+            state.add(token, time);
+            let notifier = state.notifier(token);
+            let (resp, deferred) = {
+                let ref mut scope = scope(time, token, context, state, api);
+                let resp = fun(scope);
+                take_deferred(call_error_hook(resp, scope))
+            };
+            state.defer(token, deferred);
+            let (mach, void, timeout, yielded) = decompose(resp);
+            if let Some(x) = void.into_iter().flat_map(|x| x).next() {
+                unreachable(x)
+            }
+            let m = match mach {
+                Ok(m) => m,
+                Err(err) => {
+                    if let Some(mut meta) = state.remove(token) {
+                        clear_idle_timeout(&mut meta, api);
+                        clear_pause(&mut meta, api);
+                        state.deregister_tracked(&mut meta, api);
+                    }
+                    return constructor_error(token, err).map(|()| notifier);
+                }
+            };
+            if let Some(meta) = state.get_mut(token) {
+                replace_timeout(meta, token, timeout, time, api);
+                update_interest(meta, token, m.interest(), api);
+            }
+            if yielded {
+                state.add_yielded(token);
+            }
+            slab.insert(token, Some(m));
+            created(state, time, token);
+            Ok(notifier)
         });
-        if res.is_some() {
-            Ok(())
-        } else {
-            // TODO(tailhook) propagate error from state machine construtor
-            Err(NoSlabSpace(()))
+        run_deferred(state, api);
+        res.unwrap_or(Err(NoSlabSpace(())))
+    }
+}
+
+impl<M: Machine + Debug, S: MachineStore<Option<M>>> Handler<M, S> {
+    /// Returns a line per state machine: token, metadata, and the state
+    pub fn dump_machines(&self) -> String {
+        let mut out = String::new();
+        for (idx, meta) in self.state.machines.iter().enumerate() {
+            let meta = match *meta {
+                Some(ref meta) => meta,
+                None => continue,
+            };
+            let machine = match self.slab.get(Token(idx)) {
+                Some(&Some(ref machine)) => machine,
+                // The state machine is being dispatched right now
+                _ => continue,
+            };
+            writeln!(out, "{} label={} created={:?} last_activity={:?} \
+                deadline={:?} reading_paused={} state={:?}",
+                idx, meta.label().unwrap_or("-"), meta.created(),
+                meta.last_activity(), meta.deadline(),
+                meta.is_reading_paused(), machine)
+                .expect("writing to a string never fails");
         }
+        out
+    }
+    /// Allows `LoopHandle::dump_machines`
+    pub fn enable_dump(&mut self) {
+        self.dump = Some(Handler::<M, S>::dump_machines);
     }
 }
 
-impl<M: Machine> mio::deprecated::Handler for Handler<M>
+/// Entry points of the mio handler, which are also called by `Embedded`
+impl<M, S> Handler<M, S>
+    where M: Machine, S: MachineStore<Option<M>>,
 {
-    type Message = Notify;
-    type Timeout = Timeo;
-    fn ready<'x>(&mut self, eloop: &'x mut EventLoop<Self>,
-        token: Token, events: Ready)
-    {
-        machine_loop(self, eloop, token, |m, scope| { m.ready(events, scope) })
+    pub fn on_ready(&mut self, token: Token, events: Ready) {
+        self.polled += 1;
+        #[cfg(unix)]
+        {
+            if token == self.state.waker_token {
+                if let Some(ref mut wakers) = self.state.wakers {
+                    // Delivered in `tick` along with the yielded ones
+                    self.state.yielded.extend(wakers.take());
+                }
+                return;
+            }
+        }
+        let token = match local_token(&self.state.config, token) {
+            Some(token) => token,
+            // Registered by some other component sharing the loop
+            None => return,
+        };
+        if self.state.latency.is_some() && self.state.poll_returned.is_none()
+        {
+            self.state.poll_returned = Some(Instant::now());
+        }
+        // Events for removed state machines are discarded here, the
+        // generation protects from dispatching them to the new ones
+        if self.state.get(token).is_some() {
+            let generation = self.state.generation(token);
+            match self.state.split_writer(token) {
+                Some((writer, writer_generation)) => {
+                    let (read, write) = split_events(events);
+                    if read != Ready::none() {
+                        self.ready.push((token, generation, read));
+                    }
+                    if write != Ready::none() {
+                        self.ready.push((writer, writer_generation, write));
+                    }
+                }
+                None => self.ready.push((token, generation, events)),
+            }
+        }
     }
 
-    fn notify(&mut self, eloop: &mut EventLoop<Self>, msg: Notify) {
+    pub fn on_tick<E: MioLoop>(&mut self, eloop: &mut E) {
+        if let Some(ref mut hook) = self.after_poll {
+            hook(&mut self.context, self.polled);
+        }
+        self.state.iteration = self.state.iteration.wrapping_add(1);
+        if timers_due(&mut self.timers, eloop) {
+            self.on_timeout(eloop, Timeo::Wheel);
+        } else {
+            rearm_timer(&mut self.timers, eloop);
+        }
+        // Machines yielding in this tick are resumed on the next one
+        let mut yielded = mem::replace(&mut self.state.yielded, Vec::new());
+        self.state.shuffle(&mut yielded);
+        let released = mem::replace(&mut self.state.released, Vec::new());
+        self.ready.extend(released);
+        dispatch_ready(self, eloop);
+        if !self.postponed.is_empty() {
+            resume_postponed(self, eloop);
+        }
+        for (token, generation) in yielded {
+            machine_loop(self, eloop, token, Some(generation),
+                EventKind::Wakeup,
+                |m, scope| { m.wakeup(scope) })
+        }
+        // Machines failing on the signal send it further, links are
+        // consumed by the first signal so this terminates
+        if self.ready.is_empty() {
+            // Left over events are measured from the poll they came from
+            self.state.poll_returned = None;
+        }
+        while !self.state.exits.is_empty() {
+            let mut exits = mem::replace(&mut self.state.exits, Vec::new());
+            self.state.shuffle(&mut exits);
+            for (token, generation, peer) in exits {
+                machine_loop(self, eloop, token, Some(generation),
+                    EventKind::Wakeup,
+                    |m, scope| { m.exit_signal(peer, scope) })
+            }
+        }
+        if !self.state.tickers.is_empty() {
+            // Subscriptions changed during the ticks apply to the next one
+            let tickers = self.state.tickers.clone();
+            for (token, generation) in tickers {
+                machine_loop(self, eloop, token, Some(generation),
+                    EventKind::Wakeup,
+                    |m, scope| { m.tick(scope) })
+            }
+            self.state.prune_tickers();
+        }
+        // Both are delivered before the next poll, and may queue more
+        loop {
+            if let Some((token, generation)) = self.state.immediate.pop_front()
+            {
+                if self.state.is_current(token, generation) &&
+                    self.state.over_budget(token)
+                {
+                    // Resumed on the next iteration, see
+                    // `Config::transition_budget`
+                    self.state.schedule_wakeup(token);
+                    continue;
+                }
+                machine_loop(self, eloop, token, Some(generation),
+                    EventKind::Wakeup,
+                    |m, scope| { m.wakeup(scope) });
+                continue;
+            }
+            let mut local = self.state.local_wakeups.take();
+            self.state.shuffle(&mut local);
+            if local.is_empty() {
+                break;
+            }
+            for (token, generation) in local {
+                if self.state.is_current(token, generation) &&
+                    self.state.over_budget(token)
+                {
+                    // Wakeups sent from now on are queued again
+                    self.state.clear_wakeup(token);
+                    self.state.schedule_wakeup(token);
+                    continue;
+                }
+                self.on_notify(eloop, Notify::Fsm(token, generation));
+            }
+        }
+        if let Some(ref mut recorder) = self.state.recorder {
+            recorder.flush();
+        }
+        self.check_occupancy();
+        self.state.reset_clock();
+    }
+
+    /// Shrinks the slab when `Config::slab_shrink` says so
+    fn check_occupancy(&mut self) {
+        let (percent, delay) = match slab_shrink(&self.state.config) {
+            Some(shrink) => shrink,
+            None => return,
+        };
+        let capacity = self.slab.capacity();
+        if self.slab.len().saturating_mul(100) >=
+            capacity.saturating_mul(percent)
+        {
+            self.low_occupancy = None;
+            return;
+        }
+        let now = self.loop_time();
+        let since = *self.low_occupancy.get_or_insert(now);
+        if now < since + delay {
+            return;
+        }
+        self.low_occupancy = None;
+        shrink_slab(&mut self.slab, self.initial_capacity);
+        self.state.occupancy.capacity = self.slab.capacity();
+        self.state.compact();
+        debug!("{}: Slab is shrunk from {} to {} slots ({} used)",
+            self.state.loop_id, capacity, self.slab.capacity(),
+            self.slab.len());
+    }
+
+    pub fn on_notify<E: MioLoop>(&mut self, eloop: &mut E, msg: Notify) {
         match msg {
-            Notify::Fsm(token) => {
-                machine_loop(self, eloop, token,
+            Notify::Fsm(token, generation) => {
+                if self.state.is_current(token, generation) {
+                    // Wakeups sent while the machine runs are delivered
+                    self.state.clear_wakeup(token);
+                }
+                machine_loop(self, eloop, token, Some(generation),
+                    EventKind::Wakeup,
                     |m, scope| { m.wakeup(scope) })
             }
+            Notify::Abort(token, generation) => {
+                if !self.state.is_current(token, generation) {
+                    return;
+                }
+                let time = self.loop_time();
+                if destroy_machine(self, eloop, time, token) > 0 {
+                    orphan_children(self, eloop, time, token, generation);
+                }
+                if self.state.shutting_down && self.slab.len() == 0 {
+                    eloop.shutdown();
+                }
+            }
+            Notify::Spawn => {
+                let time = self.loop_time();
+                for (seed, thaw, reply) in self.remote.take() {
+                    let res = create_with(self, eloop, time, seed, thaw,
+                        None);
+                    spawn_constructed(self, eloop, time);
+                    // The requester may not be interested in the result
+                    reply.send(res.map(|_| ()).map_err(From::from)).ok();
+                }
+                for (seeds, reply) in self.remote.take_uniform() {
+                    let res = self.spawn_uniform(eloop, seeds);
+                    reply.send(res.map_err(From::from)).ok();
+                }
+                for (token, target, reply) in self.remote.take_migrations() {
+                    let res = self.migrate(eloop, token, &target);
+                    reply.send(res).ok();
+                }
+                for (label, seeds, reply) in self.remote.take_groups() {
+                    let res = self.replace_group(eloop, label, seeds);
+                    reply.send(res.into_iter()
+                        .map(|r| r.map_err(From::from))
+                        .collect()).ok();
+                }
+            }
+            Notify::Context => {
+                let mut changed = false;
+                for (action, reconfigure) in self.actions.take() {
+                    action(&mut self.context);
+                    changed |= reconfigure;
+                }
+                if changed {
+                    broadcast_config(self, eloop);
+                }
+            }
+            Notify::Shutdown => eloop.shutdown(),
+            Notify::Dump(reply) => {
+                let dump = match self.dump {
+                    Some(dump) => dump(self),
+                    None => String::from("Dump is not enabled, \
+                        see `LoopInstance::enable_remote_dump`\n"),
+                };
+                // The requester may not be interested in the result
+                reply.send(dump).ok();
+            }
+            Notify::Snapshot(reply) => {
+                let mut buf = Vec::new();
+                let res = self.write_snapshot(&mut buf).map(|()| buf);
+                reply.send(res).ok();
+            }
+            #[cfg(feature="machine_stats")]
+            Notify::Stats(reply) => {
+                // The requester may not be interested in the result
+                reply.send(self.state.machine_stats()).ok();
+            }
+            Notify::Latency(reply) => {
+                reply.send(self.state.latency.clone()).ok();
+            }
+            Notify::GracefulShutdown => {
+                graceful_shutdown(self, eloop);
+            }
+            Notify::Occupancy(reply) => {
+                reply.send(self.state.occupancy).ok();
+            }
+            Notify::Interrupt => {}
+            Notify::Drain(grace) => {
+                if graceful_shutdown(self, eloop) {
+                    self.timers.insert(millis(grace), Timeo::Drain);
+                    arm_timer(&mut self.timers, eloop);
+                }
+            }
         }
     }
 
-    fn timeout(&mut self, eloop: &mut EventLoop<Self>, timeo: Timeo) {
+    pub fn on_timeout<E: MioLoop>(&mut self, eloop: &mut E, timeo: Timeo) {
         match timeo {
-            Timeo::Fsm(token) => {
-                machine_loop(self, eloop, token,
+            Timeo::Wheel => {
+                let mut expired = self.timers.expire();
+                self.state.shuffle(&mut expired);
+                for timeo in expired {
+                    fire_timeout(self, eloop, timeo);
+                }
+                arm_timer(&mut self.timers, eloop);
+            }
+            timeo => fire_timeout(self, eloop, timeo),
+        }
+    }
+
+    /// Dispatch the recorded event at the recorded loop time
+    ///
+    /// Exit signals and ticks are recorded as wakeups, so they are
+    /// replayed as wakeups too.
+    pub fn replay_event<E: MioLoop>(&mut self, eloop: &mut E,
+        event: &DispatchEvent)
+    {
+        let instant = make_instant(self.timers.start(), event.time);
+        let system_time = self.state.time_source.system_time();
+        self.state.clock.set(Some((instant, system_time)));
+        match event.kind {
+            EventKind::Ready(events) => {
+                machine_loop(self, eloop, event.token, None, event.kind,
+                    |m, scope| { m.ready(events, scope) })
+            }
+            EventKind::Timeout => {
+                machine_loop(self, eloop, event.token, None, event.kind,
                     |m, scope| { m.timeout(scope) })
             }
+            EventKind::Wakeup => {
+                machine_loop(self, eloop, event.token, None, event.kind,
+                    |m, scope| { m.wakeup(scope) })
+            }
         }
+        self.state.reset_clock();
+    }
+}
+
+impl<M, S> mio::deprecated::Handler for Handler<M, S>
+    where M: Machine, S: MachineStore<Option<M>>,
+{
+    type Message = Notify;
+    type Timeout = Timeo;
+    fn ready(&mut self, _eloop: &mut EventLoop<Self>,
+        token: Token, events: Ready)
+    {
+        self.on_ready(token, events)
+    }
+    fn tick(&mut self, eloop: &mut EventLoop<Self>) {
+        self.on_tick(eloop)
+    }
+    fn notify(&mut self, eloop: &mut EventLoop<Self>, msg: Notify) {
+        self.on_notify(eloop, msg)
+    }
+    fn interrupted(&mut self, _eloop: &mut EventLoop<Self>) {
+        self.state.poll_errors.interrupted += 1;
+    }
+    fn timeout(&mut self, eloop: &mut EventLoop<Self>, timeo: Timeo) {
+        self.on_timeout(eloop, timeo)
     }
 }
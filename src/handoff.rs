@@ -0,0 +1,252 @@
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::collections::VecDeque;
+use std::error::Error;
+
+use mio::Token;
+use void::Void;
+
+use {Machine, Scope, GenericScope, Response, EventSet, Notifier};
+use {SpawnError, LinkedExit};
+
+
+struct Queue<T> {
+    items: VecDeque<T>,
+    closed: bool,
+}
+
+/// The sending side of the handoff to another loop
+///
+/// Created by `Handoff::new` in the target loop. May be cloned and sent to
+/// other threads (e.g. to the loop running the listener) if the seed type
+/// is `Send`.
+pub struct HandoffSender<T> {
+    queue: Arc<Mutex<Queue<T>>>,
+    notifier: Notifier,
+}
+
+struct Receiver<T>(Arc<Mutex<Queue<T>>>);
+
+enum Inner<M: Machine> {
+    Receiver(Receiver<M::Seed>),
+    Child(M),
+}
+
+/// A state machine which receives seeds from other loops
+///
+/// Seeds sent through the `HandoffSender` (e.g. accepted connections) are
+/// turned into the state machines `M` in the loop of the `Handoff`. This
+/// is how a single listener thread distributes connections between the
+/// worker loops: every worker has a `Handoff` machine and the listener
+/// keeps the senders.
+pub struct Handoff<M: Machine>(Inner<M>);
+
+fn lock<T>(queue: &Mutex<Queue<T>>) -> MutexGuard<Queue<T>> {
+    match queue.lock() {
+        Ok(guard) => guard,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
+
+impl<T> HandoffSender<T> {
+    /// Send the seed to the target loop
+    ///
+    /// Returns the seed back if the receiving state machine is gone.
+    pub fn send(&self, seed: T) -> Result<(), T> {
+        {
+            let mut queue = lock(&self.queue);
+            if queue.closed {
+                return Err(seed);
+            }
+            queue.items.push_back(seed);
+        }
+        // Wakeups are coalesced, and if the queue of notifications is full,
+        // the seed is received with the next wakeup
+        self.notifier.wakeup().ok();
+        Ok(())
+    }
+    /// Number of seeds not yet received by the target loop
+    pub fn queued(&self) -> usize {
+        lock(&self.queue).items.len()
+    }
+}
+
+impl<T> Clone for HandoffSender<T> {
+    fn clone(&self) -> HandoffSender<T> {
+        HandoffSender {
+            queue: self.queue.clone(),
+            notifier: self.notifier.clone(),
+        }
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        let mut queue = lock(&self.0);
+        queue.closed = true;
+        queue.items.clear();
+    }
+}
+
+impl<M: Machine> Handoff<M> {
+    /// Create a receiving state machine and a sender for it
+    pub fn new<S: GenericScope>(scope: &mut S)
+        -> (Response<Handoff<M>, Void>, HandoffSender<M::Seed>)
+    {
+        let queue = Arc::new(Mutex::new(Queue {
+            items: VecDeque::new(),
+            closed: false,
+        }));
+        let sender = HandoffSender {
+            queue: queue.clone(),
+            notifier: scope.notifier(),
+        };
+        (Response::ok(Handoff(Inner::Receiver(Receiver(queue)))), sender)
+    }
+}
+
+fn receive<M: Machine>(me: Receiver<M::Seed>)
+    -> Response<Handoff<M>, M::Seed>
+{
+    let seeds: Vec<_> = lock(&me.0).items.drain(..).collect();
+    Response::spawn_many(Handoff(Inner::Receiver(me)), seeds)
+}
+
+impl<M: Machine> Machine for Handoff<M> {
+    type Context = M::Context;
+    type Seed = M::Seed;
+
+    fn create(seed: Self::Seed, scope: &mut Scope<M::Context>)
+        -> Response<Self, Self::Seed>
+    {
+        M::create(seed, scope).wrap(|m| Handoff(Inner::Child(m)))
+    }
+    fn ready(self, events: EventSet, scope: &mut Scope<M::Context>)
+        -> Response<Self, Self::Seed>
+    {
+        match self.0 {
+            me @ Inner::Receiver(..) => Response::ok(Handoff(me)),
+            Inner::Child(m) => {
+                m.ready(events, scope).wrap(|m| Handoff(Inner::Child(m)))
+            }
+        }
+    }
+    fn spawned(self, scope: &mut Scope<M::Context>)
+        -> Response<Self, Self::Seed>
+    {
+        match self.0 {
+            // Seeds might be sent while the batch was created
+            Inner::Receiver(me) => receive(me),
+            Inner::Child(m) => {
+                m.spawned(scope).wrap(|m| Handoff(Inner::Child(m)))
+            }
+        }
+    }
+    fn spawn_error(self, scope: &mut Scope<M::Context>,
+                   error: SpawnError<Self::Seed>)
+        -> Response<Self, Self::Seed>
+    {
+        match self.0 {
+            Inner::Receiver(me) => match error {
+                // Queued by the loop until some state machine is removed
+                SpawnError::NoSlabSpace(seed) => {
+                    Response::spawn(Handoff(Inner::Receiver(me)), seed)
+                }
+                e => {
+                    debug!("Error creating state machine from handoff: {}",
+                        e);
+                    Response::ok(Handoff(Inner::Receiver(me)))
+                }
+            },
+            Inner::Child(m) => {
+                m.spawn_error(scope, error)
+                    .wrap(|m| Handoff(Inner::Child(m)))
+            }
+        }
+    }
+    fn destroyed(self, scope: &mut Scope<M::Context>) {
+        match self.0 {
+            Inner::Receiver(..) => {}
+            Inner::Child(m) => m.destroyed(scope),
+        }
+    }
+    fn freeze(self, scope: &mut Scope<M::Context>)
+        -> Result<Self::Seed, Self>
+    {
+        match self.0 {
+            me @ Inner::Receiver(..) => Err(Handoff(me)),
+            Inner::Child(m) => {
+                m.freeze(scope).map_err(|m| Handoff(Inner::Child(m)))
+            }
+        }
+    }
+    fn thaw(seed: Self::Seed, scope: &mut Scope<M::Context>)
+        -> Response<Self, Self::Seed>
+    {
+        M::thaw(seed, scope).wrap(|m| Handoff(Inner::Child(m)))
+    }
+    fn timeout(self, scope: &mut Scope<M::Context>)
+        -> Response<Self, Self::Seed>
+    {
+        match self.0 {
+            me @ Inner::Receiver(..) => Response::ok(Handoff(me)),
+            Inner::Child(m) => {
+                m.timeout(scope).wrap(|m| Handoff(Inner::Child(m)))
+            }
+        }
+    }
+    fn wakeup(self, scope: &mut Scope<M::Context>)
+        -> Response<Self, Self::Seed>
+    {
+        match self.0 {
+            Inner::Receiver(me) => receive(me),
+            Inner::Child(m) => {
+                m.wakeup(scope).wrap(|m| Handoff(Inner::Child(m)))
+            }
+        }
+    }
+    fn exit_signal(self, peer: Token, scope: &mut Scope<M::Context>)
+        -> Response<Self, Self::Seed>
+    {
+        match self.0 {
+            Inner::Receiver(..) => Response::error(Box::new(LinkedExit(peer))),
+            Inner::Child(m) => {
+                m.exit_signal(peer, scope).wrap(|m| Handoff(Inner::Child(m)))
+            }
+        }
+    }
+    fn tick(self, scope: &mut Scope<M::Context>)
+        -> Response<Self, Self::Seed>
+    {
+        match self.0 {
+            me @ Inner::Receiver(..) => Response::ok(Handoff(me)),
+            Inner::Child(m) => {
+                m.tick(scope).wrap(|m| Handoff(Inner::Child(m)))
+            }
+        }
+    }
+    fn config_changed(self, scope: &mut Scope<M::Context>)
+        -> Response<Self, Self::Seed>
+    {
+        match self.0 {
+            me @ Inner::Receiver(..) => Response::ok(Handoff(me)),
+            Inner::Child(m) => {
+                m.config_changed(scope)
+                    .wrap(|m| Handoff(Inner::Child(m)))
+            }
+        }
+    }
+    fn interest(&self) -> Option<EventSet> {
+        match self.0 {
+            Inner::Receiver(..) => None,
+            Inner::Child(ref m) => m.interest(),
+        }
+    }
+    fn error(self, err: Box<Error>, scope: &mut Scope<M::Context>)
+        -> Box<Error>
+    {
+        match self.0 {
+            Inner::Receiver(..) => err,
+            Inner::Child(m) => m.error(err, scope),
+        }
+    }
+}
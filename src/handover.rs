@@ -0,0 +1,260 @@
+use std::env;
+use std::io;
+use std::net as inet;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+
+use std::process::{self, Command};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use mio::tcp::TcpListener;
+use mio::deprecated::unix::UnixListener;
+
+
+/// The environment variable which lists the inherited listening sockets
+///
+/// The value is a comma-separated list of `name=fd` pairs.
+const LISTEN_FDS_VAR: &str = "ROTOR_LISTEN_FDS";
+
+/// Listening sockets to hand over to the next version of the process
+///
+/// Add every listening socket here when it's bound, then on upgrade spawn
+/// the new binary with `pass_to`, which lets the new process inherit the
+/// sockets. The new process picks them up with `inherited_listeners`
+/// instead of binding, so connections wait in the kernel's backlog
+/// instead of being refused while the processes are switched. When the
+/// new process is ready, shut down the old loop (e.g. with
+/// `LoopHandle::drain`).
+///
+/// Sockets may also be passed to an already running process over a unix
+/// socket with `send_fd`, in the order of `sockets()`, and adopted with
+/// `adopt_listener` on the other side.
+///
+/// The set is cloneable and may be sent to other threads. It doesn't own
+/// the sockets, so remove the socket when it's closed.
+#[derive(Clone, Debug)]
+pub struct Handover {
+    sockets: Arc<Mutex<Vec<(String, RawFd)>>>,
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<T> {
+    match mutex.lock() {
+        Ok(guard) => guard,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
+
+impl Handover {
+    pub fn new() -> Handover {
+        Handover { sockets: Arc::new(Mutex::new(Vec::new())) }
+    }
+    /// Add the listening socket, replacing the one with the same name
+    ///
+    /// Names must not contain `=` and `,`.
+    pub fn add<S: AsRawFd>(&self, name: &str, sock: &S) {
+        debug_assert!(!name.contains('=') && !name.contains(','));
+        let mut sockets = lock(&self.sockets);
+        sockets.retain(|&(ref n, _)| n != name);
+        sockets.push((name.to_string(), sock.as_raw_fd()));
+    }
+    /// Remove the socket by name
+    pub fn remove(&self, name: &str) {
+        lock(&self.sockets).retain(|&(ref n, _)| n != name);
+    }
+    /// Names and descriptors of the sockets in the order of adding
+    pub fn sockets(&self) -> Vec<(String, RawFd)> {
+        lock(&self.sockets).clone()
+    }
+    /// Make the process spawned by the `command` inherit the sockets
+    ///
+    /// The descriptors are left open across `exec` in the child only, and
+    /// the list is passed in the environment. Requires the `handover`
+    /// feature, otherwise an error is returned.
+    #[cfg(feature="handover")]
+    pub fn pass_to(&self, command: &mut Command) -> io::Result<()> {
+        use std::os::unix::process::CommandExt;
+
+        let sockets = self.sockets();
+        let value = sockets.iter()
+            .map(|&(ref name, fd)| format!("{}={}", name, fd))
+            .collect::<Vec<_>>()
+            .join(",");
+        let fds: Vec<_> = sockets.iter().map(|&(_, fd)| fd).collect();
+        command.env(LISTEN_FDS_VAR, value);
+        unsafe {
+            // Runs in the forked child, the parent's descriptors are intact
+            command.pre_exec(move || {
+                for &fd in &fds {
+                    try!(set_cloexec(fd, false));
+                }
+                Ok(())
+            });
+        }
+        Ok(())
+    }
+    /// Make the process spawned by the `command` inherit the sockets
+    #[cfg(not(feature="handover"))]
+    pub fn pass_to(&self, _command: &mut Command) -> io::Result<()> {
+        Err(io::Error::new(io::ErrorKind::Other,
+            "passing sockets requires the `handover` feature"))
+    }
+}
+
+#[cfg(feature="handover")]
+fn set_cloexec(fd: RawFd, cloexec: bool) -> io::Result<()> {
+    use libc;
+
+    unsafe {
+        let flags = libc::fcntl(fd, libc::F_GETFD);
+        if flags < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let flags = if cloexec {
+            flags | libc::FD_CLOEXEC
+        } else {
+            flags & !libc::FD_CLOEXEC
+        };
+        if libc::fcntl(fd, libc::F_SETFD, flags) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// Wrap the descriptor of a listening TCP socket
+///
+/// The socket is switched to the non-blocking mode. Use it for the
+/// descriptors received with `FdReceiver`.
+///
+/// # Safety
+///
+/// The descriptor must be a listening socket which is not owned by
+/// anything else, it's closed when the listener is dropped.
+pub unsafe fn adopt_listener(fd: RawFd) -> io::Result<TcpListener> {
+    // Owns the descriptor, so it's closed on errors
+    let sock = inet::TcpListener::from_raw_fd(fd);
+    let addr = try!(sock.local_addr());
+    TcpListener::from_listener(sock, &addr)
+}
+
+/// Take the listening sockets passed by the previous process
+///
+/// Returns sockets passed with `Handover::pass_to` by name, or nothing if
+/// the process is started normally. The environment variable is removed,
+/// so the sockets are taken once and are not passed to the children.
+/// With the `handover` feature the descriptors are also closed on `exec`
+/// again.
+pub fn inherited_listeners() -> io::Result<Vec<(String, TcpListener)>> {
+    let value = match env::var(LISTEN_FDS_VAR) {
+        Ok(value) => value,
+        Err(_) => return Ok(Vec::new()),
+    };
+    env::remove_var(LISTEN_FDS_VAR);
+    let mut result = Vec::new();
+    for item in value.split(',').filter(|x| !x.is_empty()) {
+        let mut pair = item.splitn(2, '=');
+        let name = pair.next().unwrap_or("");
+        let fd = match pair.next().and_then(|x| x.parse::<RawFd>().ok()) {
+            Some(fd) => fd,
+            None => {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                    format!("bad item {:?} in {}", item, LISTEN_FDS_VAR)));
+            }
+        };
+        #[cfg(feature="handover")]
+        try!(set_cloexec(fd, true));
+        let sock = try!(unsafe { adopt_listener(fd) });
+        result.push((name.to_string(), sock));
+    }
+    Ok(result)
+}
+
+/// A socket passed by the service manager, see `activated_sockets`
+#[derive(Debug)]
+pub enum Activated {
+    /// A listening TCP socket
+    Tcp(TcpListener),
+    /// A listening unix socket
+    Unix(UnixListener),
+    /// Any other descriptor, e.g. a datagram socket, a FIFO or a
+    /// connection passed with `Accept=yes`, owned by the caller
+    Other(RawFd),
+}
+
+/// The first descriptor passed by the service manager
+const LISTEN_FDS_START: RawFd = 3;
+
+/// Take the sockets passed by systemd (socket activation)
+///
+/// Reads `LISTEN_PID`, `LISTEN_FDS` and `LISTEN_FDNAMES`, and removes
+/// them from the environment, like `sd_listen_fds` does. Returns nothing
+/// if the process is not socket-activated. Names are `FileDescriptorName=`
+/// of the socket units (`"unknown"` if systemd doesn't pass the names).
+///
+/// Listening sockets are switched to the non-blocking mode and are ready
+/// to be passed to `Accept` (or `LimitedListener`). The descriptors are
+/// closed on `exec`. Requires the `handover` feature, otherwise an error
+/// is returned for the socket-activated process.
+pub fn activated_sockets() -> io::Result<Vec<(String, Activated)>> {
+    let pid = env::var("LISTEN_PID").ok()
+        .and_then(|x| x.parse::<u32>().ok());
+    let count = env::var("LISTEN_FDS").ok()
+        .and_then(|x| x.parse::<RawFd>().ok());
+    let names = env::var("LISTEN_FDNAMES").unwrap_or_else(|_| String::new());
+    for var in &["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+        env::remove_var(var);
+    }
+    let count = match (pid, count) {
+        // The variables are inherited by the children of the activated
+        // process, which must not take the sockets
+        (Some(pid), Some(count)) if pid == process::id() => count,
+        _ => return Ok(Vec::new()),
+    };
+    let mut names = names.split(':').filter(|x| !x.is_empty());
+    let mut result = Vec::new();
+    for fd in LISTEN_FDS_START..LISTEN_FDS_START + count {
+        let name = names.next().unwrap_or("unknown").to_string();
+        result.push((name, try!(adopt_activated(fd))));
+    }
+    Ok(result)
+}
+
+#[cfg(feature="handover")]
+fn adopt_activated(fd: RawFd) -> io::Result<Activated> {
+    use std::mem;
+    use std::os::unix::io::IntoRawFd;
+    use std::os::unix::net;
+    use libc;
+
+    try!(set_cloexec(fd, true));
+    let mut listening: libc::c_int = 0;
+    let mut len = mem::size_of::<libc::c_int>() as libc::socklen_t;
+    let res = unsafe {
+        libc::getsockopt(fd, libc::SOL_SOCKET, libc::SO_ACCEPTCONN,
+            &mut listening as *mut libc::c_int as *mut libc::c_void,
+            &mut len)
+    };
+    // Fails with ENOTSOCK for files and FIFOs
+    if res < 0 || listening == 0 {
+        return Ok(Activated::Other(fd));
+    }
+    // The wrappers take the ownership, so the descriptor is released back
+    // when the address family doesn't match
+    let tcp = unsafe { inet::TcpListener::from_raw_fd(fd) };
+    if tcp.local_addr().is_ok() {
+        return unsafe { adopt_listener(fd) }.map(Activated::Tcp);
+    }
+    let fd = tcp.into_raw_fd();
+    let unix = unsafe { net::UnixListener::from_raw_fd(fd) };
+    if unix.local_addr().is_ok() {
+        try!(unix.set_nonblocking(true));
+        let fd = unix.into_raw_fd();
+        return Ok(Activated::Unix(unsafe { UnixListener::from_raw_fd(fd) }));
+    }
+    Ok(Activated::Other(unix.into_raw_fd()))
+}
+
+#[cfg(not(feature="handover"))]
+fn adopt_activated(_fd: RawFd) -> io::Result<Activated> {
+    Err(io::Error::new(io::ErrorKind::Other,
+        "socket activation requires the `handover` feature"))
+}
@@ -9,6 +9,13 @@ pub extern crate mio as mio_original;
 pub extern crate slab;
 #[macro_use] extern crate log;
 #[macro_use] extern crate quick_error;
+#[cfg(feature="futures")] extern crate futures;
+#[cfg(feature="derive")] extern crate rotor_derive;
+#[cfg(any(feature="affinity", feature="reuseport", feature="fswatch",
+          feature="handover", feature="sendfile", feature="serial",
+          feature="netlink", feature="packet"))]
+extern crate libc;
+#[cfg(all(windows, feature="named_pipes"))] extern crate miow;
 
 mod handler;
 mod scope;
@@ -22,15 +29,148 @@ mod config;
 mod creator;
 mod error;
 mod loop_time;
+mod future;
+mod pool;
+mod promise;
+mod channel;
+mod rpc;
+mod loop_state;
+mod metrics;
+mod budget;
+mod timer;
+mod clock;
+mod readiness;
+mod affinity;
+mod trace;
+mod shared;
+mod project;
+mod remote;
+mod transport;
+#[cfg(not(feature="no_deadlines"))] mod stream;
+mod listener;
+mod ratelimit;
+#[cfg(not(feature="no_deadlines"))] mod conn_pool;
+mod snapshot;
+mod handoff;
+mod uniform;
+#[cfg(not(feature="no_deadlines"))] mod accept;
+#[cfg(not(feature="no_deadlines"))] mod persistent;
+mod backoff;
+mod diagnostics;
+mod reuseport;
+mod buffer_pool;
+mod dual_stack;
+#[cfg(all(unix, not(feature="no_deadlines")))] mod control;
+#[cfg(unix)] mod waker;
+#[cfg(unix)] mod passfd;
+mod named_pipe;
+#[cfg(all(unix, not(feature="no_deadlines")))] mod process;
+#[cfg(unix)] mod handover;
+#[cfg(unix)] mod serial;
+mod fswatch;
+mod netlink;
+mod packet;
+mod fileio;
+mod cancel;
+mod registry;
+mod store;
+mod embedded;
+mod loop_thread;
+mod fn_machine;
+mod outbuf;
+mod sockopt;
+mod sim;
+mod shuffle;
+#[cfg(not(feature="no_deadlines"))] mod prometheus;
+#[cfg(not(feature="no_deadlines"))] mod metric_sink;
+#[cfg(feature="futures")] mod future_machine;
+#[cfg(all(unix, feature="futures"))] mod loop_future;
+#[cfg(all(feature="dns", not(feature="no_deadlines")))]
+mod dns;
+#[cfg(all(feature="dns", not(feature="no_deadlines")))]
+mod eyeballs;
 
-pub use machine::Machine;
-pub use scope::{Scope, EarlyScope, GenericScope};
+pub use machine::{Machine, NoSpawnMachine};
+#[cfg(feature="derive")] pub use rotor_derive::Machine;
+pub use scope::{Scope, EarlyScope, GenericScope, TimeoutKind, ParentExit};
+pub use scope::LoopScope;
 pub use scope::{scope as _scope, early_scope as _early_scope};
-pub use notify::{Notifier, WakeupError};
-pub use config::Config;
+pub use notify::{Notifier, WakeupError, ShutdownHandle, FullPolicy};
+#[cfg(unix)] pub use waker::Waker;
+#[cfg(unix)] pub use passfd::{FdReceiver, send_fd};
+pub use named_pipe::{NamedPipe, NamedPipeListener};
+#[cfg(all(unix, not(feature="no_deadlines")))]
+pub use process::{ProcessMachine, ProcessHandler, ProcessIo, Output};
+#[cfg(unix)]
+pub use handover::{Handover, inherited_listeners, adopt_listener};
+#[cfg(unix)] pub use handover::{Activated, activated_sockets};
+#[cfg(unix)] pub use serial::{SerialPort, SerialSettings, Parity};
+pub use fswatch::{FileWatcher, WatchHandler, FileEvent, FileEventKind};
+pub use netlink::{NetlinkWatcher, NetlinkHandler, NetlinkEvent};
+pub use packet::{RawCapture, CaptureHandler, Frames, FramesIter};
+pub use config::{Config, SlabGrowth, SlabFull, Unresponsive};
+pub use config::QueueOverflow;
 pub use creator::{LoopCreator as Loop, LoopInstance};
-pub use error::SpawnError;
+pub use error::{SpawnError, RemoteSpawnError, LoopError, PollErrors};
+pub use error::{MigrateError, LinkedExit, JoinError};
 pub use loop_time::Time;
+pub use clock::{Clock, SystemClock, ManualClock};
+pub use readiness::Readiness;
+pub use loop_state::MachineMeta;
+pub use metrics::{Histogram, TimerMetric, LoopLatency, SlabOccupancy};
+pub use buffer_pool::BufferPool;
+#[cfg(feature="machine_stats")] pub use metrics::MachineStats;
+pub use future::{Future, Port};
+pub use promise::{Promise, Answer};
+pub use channel::{Sender, Receiver, Drain};
+pub use rpc::{Caller, Request, Responder, Reply, RequestId};
+pub use budget::ConnectSlot;
+pub use trace::{DispatchEvent, EventCursor, EventKind, TraceReader};
+pub use shared::SharedContext;
+pub use project::{SubContext, Projected};
+pub use remote::{LoopHandle, SpawnResult, MigrateResult, UniformResult};
+pub use remote::{ContextHandle, ContextAction};
+pub use transport::{Transport, Handshake, TlsSession, TlsTransport};
+#[cfg(not(feature="no_deadlines"))]
+pub use stream::{Stream, Protocol, Intent, Expectation, Exception, Buffers};
+pub use listener::{LimitedListener, AcceptGuard, Listener};
+pub use ratelimit::{RateLimiter, KeyedRateLimiter};
+#[cfg(not(feature="no_deadlines"))]
+pub use conn_pool::{ConnectionPool, PoolMachine, Connector, Lease};
+pub use snapshot::StateSerializer;
+pub use handoff::{Handoff, HandoffSender};
+pub use uniform::{Uniform, SeedQueue};
+pub use cancel::{CancelToken, CancelGuard};
+pub use registry::Registry;
+pub use store::{MachineStore, VecStore};
+pub use embedded::{Embedded, EmbeddedTimeout};
+pub use loop_thread::LoopThread;
+pub use fn_machine::{FnMachine, FnEvent, BoxedFn};
+pub use outbuf::OutBuf;
+pub use sockopt::SocketOptions;
+pub use sim::{SimStream, SimPeer, SimEvent};
+#[cfg(not(feature="no_deadlines"))]
+pub use prometheus::{Prometheus, PrometheusListener};
+#[cfg(not(feature="no_deadlines"))]
+pub use metric_sink::{MetricSink, SinkMachine};
+#[cfg(not(feature="no_deadlines"))]
+pub use accept::Accept;
+#[cfg(not(feature="no_deadlines"))]
+pub use persistent::{Persistent, Client};
+pub use backoff::Backoff;
+pub use diagnostics::{Diagnostics, Diagnostic};
+pub use reuseport::{bind_reuseport, reuseport_loops};
+pub use dual_stack::bind_dual_stack;
+#[cfg(all(unix, not(feature="no_deadlines")))]
+pub use control::{Control, ControlContext, JsonValue};
+#[cfg(feature="futures")] pub use future_machine::FutureMachine;
+#[cfg(all(unix, feature="futures"))] pub use loop_future::LoopFuture;
+#[cfg(all(feature="dns", not(feature="no_deadlines")))]
+pub use dns::{Resolver, ResolverMachine, ResolveError, ResolveResult};
+#[cfg(all(feature="dns", unix, not(feature="no_deadlines")))]
+pub use dns::system_name_servers;
+#[cfg(all(feature="dns", not(feature="no_deadlines")))]
+pub use eyeballs::{HappyEyeballs, EyeballsConfig};
 pub use handler::{Timeo as _Timeo, Notify as _Notify};
 pub use loop_api::{LoopApi as _LoopApi};
 
@@ -38,7 +178,8 @@ pub use compose::{Compose2};
 
 // Re-export mio types used in rotor
 pub use mio::{Ready as EventSet, Evented, PollOpt};
-pub use mio::timer::{Timeout, TimerError};
+pub use timer::Timeout;
+pub use mio::timer::TimerError;
 pub use mio_original as mio;
 // Re-export void too
 pub use void::{Void};
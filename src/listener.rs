@@ -0,0 +1,373 @@
+use std::io;
+use std::rc::Rc;
+use std::cell::RefCell;
+use std::net::SocketAddr;
+use std::error::Error;
+
+use mio::Token;
+use mio::tcp::{TcpListener, TcpStream};
+#[cfg(unix)] use mio::deprecated::TryAccept;
+#[cfg(unix)] use mio::deprecated::unix::{UnixListener, UnixStream};
+use void::Void;
+
+use {Machine, Scope, GenericScope, Response, EventSet, PollOpt, Notifier};
+use {SpawnError, LinkedExit, SocketOptions, Evented};
+
+
+/// A listening socket for the `Accept` state machine
+pub trait Listener: Evented {
+    type Stream;
+    type Address;
+    /// Accept a single connection, returns `None` on `WouldBlock`
+    fn accept(&self) -> io::Result<Option<(Self::Stream, Self::Address)>>;
+    /// Set the options on the accepted connection
+    ///
+    /// Does nothing by default, the options are applied to TCP streams
+    /// only.
+    fn configure(&self, _stream: &Self::Stream, _options: &SocketOptions)
+        -> io::Result<()>
+    {
+        Ok(())
+    }
+}
+
+impl Listener for TcpListener {
+    type Stream = TcpStream;
+    type Address = SocketAddr;
+    fn accept(&self) -> io::Result<Option<(TcpStream, SocketAddr)>> {
+        match TcpListener::accept(self) {
+            Ok(pair) => Ok(Some(pair)),
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+    fn configure(&self, stream: &TcpStream, options: &SocketOptions)
+        -> io::Result<()>
+    {
+        options.apply(stream)
+    }
+}
+
+#[cfg(unix)]
+impl Listener for UnixListener {
+    type Stream = UnixStream;
+    type Address = ();
+    fn accept(&self) -> io::Result<Option<(UnixStream, ())>> {
+        TryAccept::accept(self).map(|x| x.map(|sock| (sock, ())))
+    }
+}
+
+struct Limit {
+    active: usize,
+    limit: usize,
+    paused: bool,
+    notifier: Notifier,
+}
+
+/// A slot of the `LimitedListener`
+///
+/// Passed to the seed of every accepted connection. Keep it in the child
+/// state machine: the slot is freed when the guard is dropped, and the
+/// listener resumes accepting if it was paused.
+pub struct AcceptGuard(Rc<RefCell<Limit>>);
+
+struct Accepting<S> {
+    sock: TcpListener,
+    limit: Rc<RefCell<Limit>>,
+    make_seed: fn(TcpStream, SocketAddr, AcceptGuard) -> S,
+    options: SocketOptions,
+    /// Slab is full and the last seed is queued by the loop
+    slab_full: bool,
+}
+
+enum Inner<M: Machine> {
+    Listener(Accepting<M::Seed>),
+    Child(M),
+}
+
+/// A listener which limits the number of concurrent connections
+///
+/// When `limit` connections are active (or the state machine slab is full)
+/// the listener stops accepting, i.e. connections wait in the kernel's
+/// backlog. Accepting is resumed when some child drops its `AcceptGuard`.
+///
+/// Accepted connections are converted into seeds of the child state
+/// machine `M` by the `make_seed` function. The listener closes its socket
+/// when it's retiring (see `Scope::is_retiring`) or the loop is shutting
+/// down.
+pub struct LimitedListener<M: Machine>(Inner<M>);
+
+impl Drop for AcceptGuard {
+    fn drop(&mut self) {
+        let mut limit = self.0.borrow_mut();
+        limit.active -= 1;
+        if limit.paused && limit.active < limit.limit {
+            // Wakeup errors mean the loop is shutting down
+            limit.notifier.wakeup().ok();
+        }
+    }
+}
+
+impl<M: Machine> LimitedListener<M> {
+    pub fn new<S: GenericScope>(sock: TcpListener, limit: usize,
+        make_seed: fn(TcpStream, SocketAddr, AcceptGuard) -> M::Seed,
+        scope: &mut S)
+        -> Response<LimitedListener<M>, Void>
+    {
+        LimitedListener::with_options(sock, limit, SocketOptions::new(),
+                                      make_seed, scope)
+    }
+    /// Create the listener setting the `options` on every connection
+    ///
+    /// The connections which fail to set the options are closed.
+    pub fn with_options<S: GenericScope>(sock: TcpListener, limit: usize,
+        options: SocketOptions,
+        make_seed: fn(TcpStream, SocketAddr, AcceptGuard) -> M::Seed,
+        scope: &mut S)
+        -> Response<LimitedListener<M>, Void>
+    {
+        if let Err(e) = scope.register(&sock, EventSet::readable(),
+                                       PollOpt::level())
+        {
+            return Response::error(Box::new(e));
+        }
+        let limit = Limit {
+            active: 0,
+            limit: limit,
+            paused: false,
+            notifier: scope.notifier(),
+        };
+        Response::ok(LimitedListener(Inner::Listener(Accepting {
+            sock: sock,
+            limit: Rc::new(RefCell::new(limit)),
+            make_seed: make_seed,
+            options: options,
+            slab_full: false,
+        })))
+    }
+}
+
+impl<S> Accepting<S> {
+    fn pause<C>(&mut self, scope: &mut Scope<C>) -> io::Result<()> {
+        let mut limit = self.limit.borrow_mut();
+        if !limit.paused {
+            try!(scope.deregister(&self.sock));
+            limit.paused = true;
+        }
+        Ok(())
+    }
+    fn resume<C>(&mut self, scope: &mut Scope<C>) -> io::Result<()> {
+        let mut limit = self.limit.borrow_mut();
+        if limit.paused && !self.slab_full && limit.active < limit.limit &&
+            scope.queued_spawns() == 0
+        {
+            try!(scope.register(&self.sock, EventSet::readable(),
+                                PollOpt::level()));
+            limit.paused = false;
+        }
+        Ok(())
+    }
+}
+
+fn accept<M: Machine>(mut me: Accepting<M::Seed>,
+    scope: &mut Scope<M::Context>)
+    -> Response<LimitedListener<M>, M::Seed>
+{
+    let full = {
+        let limit = me.limit.borrow();
+        // Nothing is accepted while connections wait for the slab space
+        limit.active >= limit.limit || scope.queued_spawns() > 0
+    };
+    if full {
+        return match me.pause(scope) {
+            Ok(()) => Response::ok(LimitedListener(Inner::Listener(me))),
+            Err(e) => Response::error(Box::new(e)),
+        };
+    }
+    if me.limit.borrow().paused {
+        return Response::ok(LimitedListener(Inner::Listener(me)));
+    }
+    match me.sock.accept() {
+        Ok((sock, addr)) => {
+            if let Err(e) = me.options.apply(&sock) {
+                debug!("Error setting socket options: {}", e);
+                return Response::ok(LimitedListener(Inner::Listener(me)));
+            }
+            me.limit.borrow_mut().active += 1;
+            let guard = AcceptGuard(me.limit.clone());
+            let seed = (me.make_seed)(sock, addr, guard);
+            Response::spawn(LimitedListener(Inner::Listener(me)), seed)
+        }
+        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+            Response::ok(LimitedListener(Inner::Listener(me)))
+        }
+        Err(e) => {
+            debug!("Error accepting connection: {}", e);
+            Response::ok(LimitedListener(Inner::Listener(me)))
+        }
+    }
+}
+
+fn resume<M: Machine>(mut me: Accepting<M::Seed>,
+    scope: &mut Scope<M::Context>)
+    -> Response<LimitedListener<M>, M::Seed>
+{
+    match me.resume(scope) {
+        Ok(()) => accept(me, scope),
+        Err(e) => Response::error(Box::new(e)),
+    }
+}
+
+impl<M: Machine> Machine for LimitedListener<M> {
+    type Context = M::Context;
+    type Seed = M::Seed;
+
+    fn create(seed: Self::Seed, scope: &mut Scope<M::Context>)
+        -> Response<Self, Self::Seed>
+    {
+        M::create(seed, scope).wrap(|m| LimitedListener(Inner::Child(m)))
+    }
+    fn ready(self, events: EventSet, scope: &mut Scope<M::Context>)
+        -> Response<Self, Self::Seed>
+    {
+        match self.0 {
+            Inner::Listener(me) => accept(me, scope),
+            Inner::Child(m) => {
+                m.ready(events, scope)
+                    .wrap(|m| LimitedListener(Inner::Child(m)))
+            }
+        }
+    }
+    fn spawned(self, scope: &mut Scope<M::Context>)
+        -> Response<Self, Self::Seed>
+    {
+        match self.0 {
+            Inner::Listener(mut me) => {
+                me.slab_full = false;
+                resume(me, scope)
+            }
+            Inner::Child(m) => {
+                m.spawned(scope).wrap(|m| LimitedListener(Inner::Child(m)))
+            }
+        }
+    }
+    fn spawn_error(self, scope: &mut Scope<M::Context>,
+                   error: SpawnError<Self::Seed>)
+        -> Response<Self, Self::Seed>
+    {
+        match self.0 {
+            Inner::Listener(mut me) => match error {
+                SpawnError::NoSlabSpace(seed) => {
+                    // The seed is queued by the loop, and `spawned` is
+                    // called when it's finally created
+                    me.slab_full = true;
+                    match me.pause(scope) {
+                        Ok(()) => Response::spawn(
+                            LimitedListener(Inner::Listener(me)), seed),
+                        Err(e) => Response::error(Box::new(e)),
+                    }
+                }
+                e => {
+                    debug!("Error creating connection: {}", e);
+                    accept(me, scope)
+                }
+            },
+            Inner::Child(m) => {
+                m.spawn_error(scope, error)
+                    .wrap(|m| LimitedListener(Inner::Child(m)))
+            }
+        }
+    }
+    fn destroyed(self, scope: &mut Scope<M::Context>) {
+        match self.0 {
+            Inner::Listener(..) => {}
+            Inner::Child(m) => m.destroyed(scope),
+        }
+    }
+    fn freeze(self, scope: &mut Scope<M::Context>)
+        -> Result<Self::Seed, Self>
+    {
+        match self.0 {
+            // The listener is bound to the limit of the current loop
+            me @ Inner::Listener(..) => Err(LimitedListener(me)),
+            Inner::Child(m) => {
+                m.freeze(scope).map_err(|m| LimitedListener(Inner::Child(m)))
+            }
+        }
+    }
+    fn thaw(seed: Self::Seed, scope: &mut Scope<M::Context>)
+        -> Response<Self, Self::Seed>
+    {
+        M::thaw(seed, scope).wrap(|m| LimitedListener(Inner::Child(m)))
+    }
+    fn timeout(self, scope: &mut Scope<M::Context>)
+        -> Response<Self, Self::Seed>
+    {
+        match self.0 {
+            me @ Inner::Listener(..) => Response::ok(LimitedListener(me)),
+            Inner::Child(m) => {
+                m.timeout(scope).wrap(|m| LimitedListener(Inner::Child(m)))
+            }
+        }
+    }
+    fn wakeup(self, scope: &mut Scope<M::Context>)
+        -> Response<Self, Self::Seed>
+    {
+        match self.0 {
+            // Connections in progress are served by their own machines
+            Inner::Listener(_)
+                if scope.is_retiring() || scope.is_shutting_down()
+                => Response::done(),
+            Inner::Listener(me) => resume(me, scope),
+            Inner::Child(m) => {
+                m.wakeup(scope).wrap(|m| LimitedListener(Inner::Child(m)))
+            }
+        }
+    }
+    fn exit_signal(self, peer: Token, scope: &mut Scope<M::Context>)
+        -> Response<Self, Self::Seed>
+    {
+        match self.0 {
+            Inner::Listener(..) => Response::error(Box::new(LinkedExit(peer))),
+            Inner::Child(m) => {
+                m.exit_signal(peer, scope)
+                    .wrap(|m| LimitedListener(Inner::Child(m)))
+            }
+        }
+    }
+    fn tick(self, scope: &mut Scope<M::Context>)
+        -> Response<Self, Self::Seed>
+    {
+        match self.0 {
+            me @ Inner::Listener(..) => Response::ok(LimitedListener(me)),
+            Inner::Child(m) => {
+                m.tick(scope).wrap(|m| LimitedListener(Inner::Child(m)))
+            }
+        }
+    }
+    fn config_changed(self, scope: &mut Scope<M::Context>)
+        -> Response<Self, Self::Seed>
+    {
+        match self.0 {
+            me @ Inner::Listener(..) => Response::ok(LimitedListener(me)),
+            Inner::Child(m) => {
+                m.config_changed(scope)
+                    .wrap(|m| LimitedListener(Inner::Child(m)))
+            }
+        }
+    }
+    fn interest(&self) -> Option<EventSet> {
+        match self.0 {
+            Inner::Listener(..) => None,
+            Inner::Child(ref m) => m.interest(),
+        }
+    }
+    fn error(self, err: Box<Error>, scope: &mut Scope<M::Context>)
+        -> Box<Error>
+    {
+        match self.0 {
+            Inner::Listener(..) => err,
+            Inner::Child(m) => m.error(err, scope),
+        }
+    }
+}
@@ -1,12 +1,15 @@
 use std::io;
 use std::time::Duration;
+#[cfg(unix)] use std::os::unix::io::RawFd;
 
 use mio::Token;
-use mio::deprecated::EventLoop;
+use mio::deprecated::{EventLoop, Handler};
+use mio::timer::{Timeout as MioTimeout, TimerError};
 
-use handler::{Handler, Timeo};
-use {Machine};
-use {Evented, EventSet, PollOpt, Timeout, TimerError};
+use handler::Timeo;
+use timer::{Timers, arm_timer};
+#[cfg(unix)] use mio::unix::EventedFd;
+use {Evented, EventSet, PollOpt, Timeout};
 
 
 #[doc(hidden)]
@@ -16,41 +19,178 @@ pub trait LoopApi {
     fn reregister(&mut self, io: &Evented, token: Token,
         interest: EventSet, opt: PollOpt) -> io::Result<()>;
     fn deregister(&mut self, io: &Evented) -> io::Result<()>;
-    fn timeout_ms(&mut self, token: Token, delay: u64)
-        -> Result<Timeout, TimerError>;
+    /// Never fails, timers have no capacity limit
+    fn timeout_ms(&mut self, token: Token, generation: u64, delay: u64)
+        -> Timeout;
+    /// Same as `timeout_ms` but for the idle timer of the state machine
+    fn idle_timeout_ms(&mut self, token: Token, generation: u64, delay: u64)
+        -> Timeout;
     fn clear_timeout(&mut self, token: Timeout) -> bool;
+    /// Moves the timeout keeping the handle, returns false if it's expired
+    fn reschedule_timeout_ms(&mut self, token: Timeout, delay: u64) -> bool;
+    #[cfg(unix)]
+    fn resume_timeout_ms(&mut self, token: Token, generation: u64,
+        fd: RawFd, interest: EventSet, opt: PollOpt, delay: u64)
+        -> Timeout;
+    /// Registers the waker socket with the `token` of mio
+    ///
+    /// The token is not offset by `Config::token_range`, it's either the
+    /// reserved one or the one of the embedded loop.
+    #[cfg(unix)]
+    fn register_waker(&mut self, fd: RawFd, token: Token)
+        -> io::Result<()>;
     fn shutdown(&mut self);
 }
 
-impl<'a, M: Machine> LoopApi for EventLoop<Handler<M>>
+/// The operations of the mio loop used by the handler
+///
+/// Implemented by the `EventLoop` of any handler whose timeouts can carry
+/// the timeouts of rotor, so the handler may run in the loop owned by
+/// other application (see `Embedded`).
+#[doc(hidden)]
+pub trait MioLoop {
+    fn register(&mut self, io: &Evented, token: Token,
+        interest: EventSet, opt: PollOpt) -> io::Result<()>;
+    fn reregister(&mut self, io: &Evented, token: Token,
+        interest: EventSet, opt: PollOpt) -> io::Result<()>;
+    fn deregister(&mut self, io: &Evented) -> io::Result<()>;
+    fn timeout(&mut self, timeo: Timeo, delay: Duration)
+        -> Result<MioTimeout, TimerError>;
+    fn clear_timeout(&mut self, timeout: &MioTimeout) -> bool;
+    /// Called when the loop of rotor is finished
+    fn shutdown(&mut self);
+}
+
+impl<H: Handler> MioLoop for EventLoop<H>
+    where H::Timeout: From<Timeo>,
 {
     fn register(&mut self, io: &Evented, token: Token,
         interest: EventSet, opt: PollOpt) -> io::Result<()>
     {
-        self.register(io, token, interest, opt)
+        EventLoop::register(self, io, token, interest, opt)
+    }
+    fn reregister(&mut self, io: &Evented, token: Token,
+        interest: EventSet, opt: PollOpt) -> io::Result<()>
+    {
+        EventLoop::reregister(self, io, token, interest, opt)
+    }
+    fn deregister(&mut self, io: &Evented) -> io::Result<()> {
+        EventLoop::deregister(self, io)
+    }
+    fn timeout(&mut self, timeo: Timeo, delay: Duration)
+        -> Result<MioTimeout, TimerError>
+    {
+        EventLoop::timeout(self, From::from(timeo), delay)
+    }
+    fn clear_timeout(&mut self, timeout: &MioTimeout) -> bool {
+        EventLoop::clear_timeout(self, timeout)
+    }
+    fn shutdown(&mut self) {
+        EventLoop::shutdown(self)
+    }
+}
+
+/// The event loop together with the timers of the loop
+#[doc(hidden)]
+pub struct LoopHandle<'a, E: MioLoop + 'a> {
+    pub eloop: &'a mut E,
+    pub timers: &'a mut Timers,
+    /// Added to the tokens registered in mio, see `Config::token_range`
+    pub token_base: usize,
+}
+
+pub fn loop_handle<'a, E: MioLoop>(eloop: &'a mut E,
+    timers: &'a mut Timers, token_base: usize)
+    -> LoopHandle<'a, E>
+{
+    LoopHandle { eloop: eloop, timers: timers, token_base: token_base }
+}
+
+/// Reports the socket registered twice, e.g. in `create` and then again
+/// in the first action instead of `reregister`
+///
+/// Only epoll detects it, kqueue silently updates the registration.
+#[cfg(debug_assertions)]
+fn check_register(token: Token, res: &io::Result<()>) {
+    if let Err(ref e) = *res {
+        if e.kind() == io::ErrorKind::AlreadyExists {
+            error!("State machine {:?} registers the socket which is \
+                already registered by it or other state machine, use \
+                `reregister` to change the interest", token);
+        }
+    }
+}
+
+impl<'a, E: MioLoop> LoopApi for LoopHandle<'a, E>
+{
+    fn register(&mut self, io: &Evented, token: Token,
+        interest: EventSet, opt: PollOpt) -> io::Result<()>
+    {
+        let res = self.eloop.register(io, Token(self.token_base + token.0),
+            interest, opt);
+        #[cfg(debug_assertions)]
+        check_register(token, &res);
+        res
     }
 
     fn reregister(&mut self, io: &Evented, token: Token,
         interest: EventSet, opt: PollOpt) -> io::Result<()>
     {
-        self.reregister(io, token, interest, opt)
+        self.eloop.reregister(io, Token(self.token_base + token.0),
+            interest, opt)
     }
 
     fn deregister(&mut self, io: &Evented) -> io::Result<()>
     {
-        self.deregister(io)
+        self.eloop.deregister(io)
     }
 
-    fn timeout_ms(&mut self, token: Token, delay: u64)
-        -> Result<Timeout, TimerError>
+    fn timeout_ms(&mut self, token: Token, generation: u64, delay: u64)
+        -> Timeout
     {
-        self.timeout( Timeo::Fsm(token), Duration::from_millis(delay))
+        let timeout = self.timers.insert(delay, Timeo::Fsm(token, generation));
+        arm_timer(self.timers, self.eloop);
+        timeout
+    }
+    fn idle_timeout_ms(&mut self, token: Token, generation: u64, delay: u64)
+        -> Timeout
+    {
+        let timeout = self.timers.insert(delay,
+                                         Timeo::Idle(token, generation));
+        arm_timer(self.timers, self.eloop);
+        timeout
     }
     fn clear_timeout(&mut self, token: Timeout) -> bool
     {
-        self.clear_timeout(&token)
+        self.timers.cancel(token)
+    }
+    fn reschedule_timeout_ms(&mut self, token: Timeout, delay: u64) -> bool
+    {
+        if self.timers.reschedule(token, delay) {
+            arm_timer(self.timers, self.eloop);
+            true
+        } else {
+            false
+        }
+    }
+    #[cfg(unix)]
+    fn resume_timeout_ms(&mut self, token: Token, generation: u64,
+        fd: RawFd, interest: EventSet, opt: PollOpt, delay: u64)
+        -> Timeout
+    {
+        let timeout = self.timers.insert(delay,
+            Timeo::Resume(token, generation, fd, interest, opt));
+        arm_timer(self.timers, self.eloop);
+        timeout
+    }
+    #[cfg(unix)]
+    fn register_waker(&mut self, fd: RawFd, token: Token)
+        -> io::Result<()>
+    {
+        self.eloop.register(&EventedFd(&fd), token,
+                            EventSet::readable(), PollOpt::level())
     }
     fn shutdown(&mut self) {
-        self.shutdown()
+        self.eloop.shutdown()
     }
 }
@@ -0,0 +1,411 @@
+use std::io;
+use std::thread;
+use std::time::Duration;
+use std::sync::mpsc;
+use std::os::unix::io::AsRawFd;
+
+use futures::{Future, Poll as FuturesPoll, Async};
+use futures::task::{self, Task};
+use mio::{Poll, Events, Token, Ready, PollOpt, Evented};
+use mio::{Registration, SetReadiness};
+use mio::unix::EventedFd;
+use mio::timer::{Timer, Timeout as MioTimeout, TimerError};
+use mio::channel::{sync_channel, Receiver};
+use void::Void;
+
+use config::{create_slab, create_timer, validate, pin_thread};
+use config::{notify_capacity, messages_per_tick};
+use handler::{Handler, Timeo, Notify, create_handler};
+use loop_api::MioLoop;
+use loop_state::LoopState;
+use notify::{Channel, ShutdownHandle};
+use remote::{RemoteQueue, ActionQueue, LoopHandle, ContextHandle};
+use store::MachineStore;
+use timer::Timers;
+use {Machine, Config, Scope, Response, SpawnError, LoopError, Notifier};
+use {Slab, Registry};
+
+
+/// Tokens of the poll of the loop, `Token(usize::MAX)` is used by mio
+const NOTIFY_TOKEN: Token = Token(::std::usize::MAX - 1);
+const TIMER_TOKEN: Token = Token(::std::usize::MAX - 2);
+
+/// Tokens of the poll of the waiting thread
+const LOOP_TOKEN: Token = Token(0);
+const REARM_TOKEN: Token = Token(1);
+
+/// A rotor loop driven by a futures executor
+///
+/// The loop is a `futures::Future` which resolves to the context when the
+/// loop is shut down, so rotor state machines may run on the reactor of
+/// tokio (e.g. with `Core::run` of `tokio-core`) together with the code
+/// written with futures, and the code may be moved from one model to the
+/// other piece by piece. Use `FutureMachine` for the other direction.
+///
+/// ```ignore
+/// let mut lp = try!(LoopFuture::new(&Config::new(), context));
+/// lp.add_machine_with(|scope| Server::new(listener, scope)).ok();
+/// let context = try!(core.run(lp));
+/// ```
+///
+/// The state machines are run in `poll`, in the thread of the executor,
+/// so the future isn't `Send` and needs a single-threaded executor. The
+/// loop has its own mio poll: each `poll` of the future dispatches the
+/// events which are ready without blocking, and a helper thread waits for
+/// the next ones and notifies the task.
+///
+/// Only available with the `futures` feature on unix.
+pub struct LoopFuture<M: Machine, S = Slab<Option<M>>>
+    where S: MachineStore<Option<M>>,
+{
+    /// Taken when the loop is finished to return the context
+    handler: Option<Handler<M, S>>,
+    poll: Poll,
+    events: Events,
+    timer: Timer<Timeo>,
+    receiver: Receiver<Notify>,
+    messages_per_tick: usize,
+    finished: bool,
+    waiter: Option<Waiter>,
+}
+
+/// Passes the operations of the handler to the poll of the loop
+struct Own<'a> {
+    poll: &'a Poll,
+    timer: &'a mut Timer<Timeo>,
+    finished: &'a mut bool,
+}
+
+impl<'a> MioLoop for Own<'a> {
+    fn register(&mut self, io: &Evented, token: Token,
+        interest: Ready, opt: PollOpt)
+        -> io::Result<()>
+    {
+        self.poll.register(io, token, interest, opt)
+    }
+    fn reregister(&mut self, io: &Evented, token: Token,
+        interest: Ready, opt: PollOpt)
+        -> io::Result<()>
+    {
+        self.poll.reregister(io, token, interest, opt)
+    }
+    fn deregister(&mut self, io: &Evented) -> io::Result<()> {
+        self.poll.deregister(io)
+    }
+    fn timeout(&mut self, timeo: Timeo, delay: Duration)
+        -> Result<MioTimeout, TimerError>
+    {
+        self.timer.set_timeout(delay, timeo)
+    }
+    fn clear_timeout(&mut self, timeout: &MioTimeout) -> bool {
+        self.timer.cancel_timeout(timeout).is_some()
+    }
+    fn shutdown(&mut self) {
+        *self.finished = true;
+    }
+}
+
+/// The thread waiting for the events of the loop on behalf of the task
+struct Waiter {
+    /// Dropped first, so the thread finds it closed when woken up
+    requests: Option<mpsc::Sender<(Task, Option<Duration>)>>,
+    rearm: SetReadiness,
+}
+
+impl Waiter {
+    fn start(poll: &Poll) -> io::Result<Waiter> {
+        let outer = try!(Poll::new());
+        try!(outer.register(&EventedFd(&poll.as_raw_fd()), LOOP_TOKEN,
+            Ready::readable(), PollOpt::level()));
+        let (registration, rearm) = Registration::new2();
+        try!(outer.register(&registration, REARM_TOKEN,
+            Ready::readable(), PollOpt::edge()));
+        let (tx, rx) = mpsc::channel();
+        let reset = rearm.clone();
+        try!(thread::Builder::new()
+            .name(String::from("rotor-waiter"))
+            .spawn(move || wait(outer, registration, reset, rx)));
+        Ok(Waiter {
+            requests: Some(tx),
+            rearm: rearm,
+        })
+    }
+    /// Notify the task when the loop has events or after the timeout
+    fn request(&self, task: Task, timeout: Option<Duration>) {
+        if let Some(ref requests) = self.requests {
+            requests.send((task, timeout)).ok();
+        }
+        // The thread may be waiting with the stale timeout
+        self.rearm.set_readiness(Ready::readable()).ok();
+    }
+}
+
+impl Drop for Waiter {
+    fn drop(&mut self) {
+        // The thread exits when it finds the channel closed
+        self.requests.take();
+        self.rearm.set_readiness(Ready::readable()).ok();
+    }
+}
+
+fn wait(outer: Poll, _registration: Registration, rearm: SetReadiness,
+    requests: mpsc::Receiver<(Task, Option<Duration>)>)
+{
+    let mut events = Events::with_capacity(2);
+    let mut current = match requests.recv() {
+        Ok(request) => request,
+        Err(_) => return,
+    };
+    loop {
+        // Only the latest request matters, the older tasks are polled by
+        // the executor anyway
+        loop {
+            match requests.try_recv() {
+                Ok(request) => current = request,
+                Err(mpsc::TryRecvError::Empty) => break,
+                Err(mpsc::TryRecvError::Disconnected) => return,
+            }
+        }
+        if let Err(e) = outer.poll(&mut events, current.1) {
+            if e.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            // The loop reports the error when the task polls it
+            warn!("Error waiting for the events of the loop: {}", e);
+        }
+        let mut rearmed = false;
+        let mut ready = events.is_empty();
+        for i in 0..events.len() {
+            match events.get(i).map(|ev| ev.token()) {
+                Some(REARM_TOKEN) => rearmed = true,
+                Some(_) => ready = true,
+                None => {}
+            }
+        }
+        if rearmed {
+            rearm.set_readiness(Ready::empty()).ok();
+        }
+        if ready {
+            current.0.notify();
+            current = match requests.recv() {
+                Ok(request) => request,
+                Err(_) => return,
+            };
+        }
+    }
+}
+
+impl<M: Machine> LoopFuture<M> {
+    /// Create the loop
+    pub fn new(cfg: &Config, context: M::Context)
+        -> Result<LoopFuture<M>, io::Error>
+    {
+        LoopFuture::with_store(cfg, context, create_slab(cfg))
+    }
+}
+
+impl<M, S> LoopFuture<M, S>
+    where M: Machine, S: MachineStore<Option<M>>,
+{
+    /// Create the loop keeping state machines in the `slab`
+    ///
+    /// The thread is pinned with `Config::pin_to_cpu`, so create the loop
+    /// in the thread of the executor.
+    pub fn with_store(cfg: &Config, context: M::Context, slab: S)
+        -> Result<LoopFuture<M, S>, io::Error>
+    {
+        try!(validate(cfg));
+        try!(pin_thread(cfg));
+        let poll = try!(Poll::new());
+        let (tx, rx) = sync_channel(notify_capacity(cfg));
+        try!(poll.register(&rx, NOTIFY_TOKEN,
+            Ready::readable(), PollOpt::level()));
+        let timer = create_timer(cfg);
+        try!(poll.register(&timer, TIMER_TOKEN,
+            Ready::readable(), PollOpt::edge()));
+        let state = LoopState::new(Channel::new_embedded(tx), cfg);
+        let timers = Timers::new(state.start,
+            state.time_source.clone());
+        let mut lp = LoopFuture {
+            handler: Some(create_handler(slab, context, state, timers,
+                RemoteQueue::new(), ActionQueue::new())),
+            poll: poll,
+            events: Events::with_capacity(1024),
+            timer: timer,
+            receiver: rx,
+            messages_per_tick: messages_per_tick(cfg),
+            finished: false,
+            waiter: None,
+        };
+        {
+            let LoopFuture { ref mut handler, ref poll, ref mut timer,
+                             ref mut finished, .. } = lp;
+            let ref mut own = Own {
+                poll: poll,
+                timer: timer,
+                finished: finished,
+            };
+            handler.as_mut().expect("loop is not finished")
+                .start_watchdog(own);
+        }
+        Ok(lp)
+    }
+
+    /// Add the state machine created by `fun`
+    ///
+    /// Returns the notifier of the new state machine.
+    pub fn add_machine_with<F>(&mut self, fun: F)
+        -> Result<Notifier, SpawnError<()>>
+        where F: FnOnce(&mut Scope<M::Context>) -> Response<M, Void>
+    {
+        let LoopFuture { ref mut handler, ref poll, ref mut timer,
+                         ref mut finished, .. } = *self;
+        let handler = handler.as_mut().expect("loop is finished");
+        let res = handler.add_machine_with(&mut Own {
+            poll: poll,
+            timer: timer,
+            finished: finished,
+        }, fun);
+        // Called between the polls, so the time must not be cached
+        handler.reset_clock();
+        res
+    }
+
+    /// Create a state machine from the seed
+    ///
+    /// Returns the notifier of the new state machine, see
+    /// `LoopInstance::add_seed`.
+    pub fn add_seed(&mut self, seed: M::Seed)
+        -> Result<Option<Notifier>, SpawnError<M::Seed>>
+    {
+        let LoopFuture { ref mut handler, ref poll, ref mut timer,
+                         ref mut finished, .. } = *self;
+        let handler = handler.as_mut().expect("loop is finished");
+        let res = handler.add_seed(&mut Own {
+            poll: poll,
+            timer: timer,
+            finished: finished,
+        }, seed);
+        handler.reset_clock();
+        res
+    }
+
+    /// Get the handle to control the loop from other threads
+    pub fn handle(&self) -> LoopHandle<M::Seed> {
+        self.handler().remote_handle()
+    }
+
+    /// Get the handle to shut down the loop from other threads
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.handler().shutdown_handle()
+    }
+
+    /// Get the handle to change the context from other threads
+    pub fn context_handle(&self) -> ContextHandle<M::Context> {
+        self.handler().context_handle()
+    }
+
+    /// Get the registry of the state machines of the loop
+    pub fn registry(&self) -> Registry {
+        self.handler().registry()
+    }
+
+    pub fn context(&self) -> &M::Context {
+        self.handler().context()
+    }
+
+    fn handler(&self) -> &Handler<M, S> {
+        self.handler.as_ref().expect("loop is finished")
+    }
+
+    /// Dispatches the events which are ready, doesn't block
+    fn turn(&mut self) -> Result<(), LoopError> {
+        let LoopFuture { ref mut handler, ref poll, ref mut events,
+                         ref mut timer, ref receiver, messages_per_tick,
+                         ref mut finished, .. } = *self;
+        let handler = handler.as_mut().expect("loop is finished");
+        handler.reset_clock();
+        handler.before_poll();
+        if let Err(e) = poll.poll(events, Some(Duration::new(0, 0))) {
+            if !handler.poll_error(&e) {
+                return Err(LoopError::Io(e));
+            }
+        }
+        let ref mut own = Own {
+            poll: poll,
+            timer: timer,
+            finished: finished,
+        };
+        for i in 0..events.len() {
+            let event = match events.get(i) {
+                Some(event) => event,
+                None => continue,
+            };
+            match event.token() {
+                NOTIFY_TOKEN => {
+                    // The receiver is level-triggered, so the rest is left
+                    // for the next turn
+                    for _ in 0..messages_per_tick {
+                        match receiver.try_recv() {
+                            Ok(msg) => handler.on_notify(own, msg),
+                            Err(_) => break,
+                        }
+                    }
+                }
+                TIMER_TOKEN => {
+                    while let Some(timeo) = own.timer.poll() {
+                        handler.on_timeout(own, timeo);
+                    }
+                }
+                token => handler.on_ready(token, event.kind()),
+            }
+        }
+        handler.on_tick(own);
+        Ok(())
+    }
+
+    /// Destroys the state machines and returns the context
+    fn finish(&mut self) -> Result<M::Context, LoopError> {
+        let mut handler = self.handler.take().expect("loop is finished");
+        handler.destroy_all(&mut Own {
+            poll: &self.poll,
+            timer: &mut self.timer,
+            finished: &mut self.finished,
+        });
+        self.waiter = None;
+        if let Some((token, err)) = handler.take_fatal_error() {
+            return Err(LoopError::Machine(token, err));
+        }
+        Ok(handler.into_context())
+    }
+}
+
+impl<M, S> Future for LoopFuture<M, S>
+    where M: Machine, S: MachineStore<Option<M>>,
+{
+    type Item = M::Context;
+    type Error = LoopError;
+    fn poll(&mut self) -> FuturesPoll<M::Context, LoopError> {
+        if !self.finished {
+            try!(self.turn());
+        }
+        if self.finished {
+            return self.finish().map(Async::Ready);
+        }
+        let timeout = self.handler().poll_timeout(None);
+        if timeout == Some(Duration::new(0, 0)) {
+            // Some state machines yielded, they run on the next poll
+            task::current().notify();
+            return Ok(Async::NotReady);
+        }
+        if self.waiter.is_none() {
+            self.waiter = Some(try!(Waiter::start(&self.poll)
+                .map_err(LoopError::Io)));
+        }
+        if let Some(ref waiter) = self.waiter {
+            waiter.request(task::current(), timeout);
+        }
+        Ok(Async::NotReady)
+    }
+}
+
@@ -0,0 +1,1349 @@
+use std::io;
+use std::mem;
+use std::cmp::max;
+use std::any::Any;
+use std::time::{Duration, Instant, SystemTime};
+use std::rc::Rc;
+use std::cell::{Cell, RefCell};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::collections::{HashMap, VecDeque};
+use std::error::Error;
+
+use mio::Token;
+
+#[cfg(not(feature="no_deadlines"))] use handler::set_timeout_opt;
+use notify::{Channel, LocalWakeups, create_notifier};
+use notify::{WAKEUP_PENDING, MACHINE_DEAD};
+use loop_api::LoopApi;
+use pool::ThreadPool;
+use config::{Config, create_pool, create_file_pool, create_budget};
+use config::{retry_transient_poll_errors, slow_callback_threshold};
+use config::{create_event_log, create_latency, create_shuffler};
+use config::{transition_budget, loop_id, create_buffer_pool};
+use trace::{EventLog, TraceWriter};
+use buffer_pool::BufferPool;
+use shuffle::Shuffler;
+use clock::{Clock, SystemClock};
+use response::DeferredFn;
+use loop_time::{millis, make_time, time_diff_ms};
+#[cfg(not(feature="no_deadlines"))] use loop_time::{mio_timeout_ms, round_up};
+use error::{PollErrors, is_transient};
+use budget::ConnectBudget;
+use registry::{Registry, register, unregister_all};
+use metrics::{TimerMetric, LoopLatency, SlabOccupancy};
+use diagnostics::{Diagnostics, Diagnostic, log_event};
+#[cfg(unix)] use std::os::unix::io::RawFd;
+#[cfg(unix)] use mio::unix::EventedFd;
+#[cfg(unix)] use waker::{Wakers, WAKER_TOKEN};
+#[cfg(unix)] use PollOpt;
+#[cfg(feature="machine_stats")] use metrics::{MachineStats, create_stats};
+#[cfg(feature="machine_stats")] use trace::EventKind;
+use {Time, Timeout, Notifier, ParentExit, EventSet};
+
+
+/// Information that loop tracks for every state machine
+///
+/// This is what `Scope::for_each_machine` exposes. The structure is
+/// maintained by the loop itself, so it doesn't alias state machines.
+#[derive(Debug)]
+pub struct MachineMeta {
+    generation: u64,
+    #[cfg(not(feature="no_deadlines"))]
+    timeout: Option<(Timeout, Time)>,
+    /// Set by `Scope::set_idle_timeout`
+    idle: Option<Duration>,
+    /// Rearmed on every ready event
+    idle_timer: Option<Timeout>,
+    paused: Option<Timeout>,
+    /// Set by `Scope::pause`: the ready events held until `Scope::resume`
+    held: Option<EventSet>,
+    /// Registered with `Scope::register_primary`: the socket, and the
+    /// interest and options it's registered with
+    #[cfg(unix)]
+    primary: Option<(RawFd, EventSet, PollOpt)>,
+    /// Registered with `Scope::register_tracked`
+    #[cfg(unix)]
+    tracked: Vec<RawFd>,
+    /// Receives the write events of the socket, see `Scope::register_split`
+    writer: Option<(Token, u64)>,
+    /// The loop iteration and the number of actions in it, see
+    /// `Config::transition_budget`
+    transitions: (u64, u32),
+    /// Set by `Scope::set_lateness_metric`
+    lateness: Option<TimerMetric>,
+    label: Option<&'static str>,
+    priority: i8,
+    retiring: bool,
+    /// Shared with the notifiers: set to `WAKEUP_PENDING` while a wakeup
+    /// is in the queue, and to `MACHINE_DEAD` when the meta is dropped
+    wakeup_pending: Arc<AtomicUsize>,
+    /// Token and generation of the state machine which has spawned this one
+    parent: Option<(Token, u64)>,
+    /// Set by `Scope::set_parent_exit`
+    parent_exit: ParentExit,
+    /// The parent is removed
+    orphaned: bool,
+    /// Number of live state machines spawned by this one
+    children: usize,
+    /// Seeds spawned by this one and waiting for the slab space
+    queued: usize,
+    /// Set by `Scope::suspend_creation`
+    creating: bool,
+    /// Enrolled with `Scope::enable_watchdog`
+    watchdog: bool,
+    /// When the unacknowledged watchdog ping was sent
+    ping: Option<Time>,
+    /// State machines which are woken when this one is removed
+    watchers: Vec<(Token, u64)>,
+    /// Watched state machines which are removed, see `Scope::watch`
+    gone: Vec<Token>,
+    /// State machines linked with `Scope::link`
+    links: Vec<(Token, u64)>,
+    /// Registered with `Scope::register_name`
+    named: bool,
+    /// Values set by `Scope::set_local`, one for each type
+    locals: Vec<Box<Any>>,
+    /// Deadlines are rounded up to this number of milliseconds, see
+    /// `Scope::set_coarse_deadline`
+    coarse: Option<u64>,
+    #[cfg(feature="machine_stats")]
+    stats: MachineStats,
+    created: Time,
+    last_activity: Time,
+}
+
+/// The end of the creation suspended with `Scope::suspend_creation`
+#[doc(hidden)]
+pub struct Creation {
+    pub parent: Token,
+    pub generation: u64,
+    pub child: Token,
+    /// `Err(None)` if the state machine returned `Response::done()`
+    pub result: Result<(), Option<Box<Error>>>,
+}
+
+/// Parts of the loop that don't depend on the state machine type
+///
+/// It's created with the `LoopCreator` and is accessible from any scope.
+#[doc(hidden)]
+pub struct LoopState {
+    pub channel: Channel,
+    /// Wakeups sent by the notifiers in the thread of the loop
+    pub local_wakeups: LocalWakeups,
+    pub pool: ThreadPool,
+    pub file_pool: ThreadPool,
+    pub config: Config,
+    pub connects: Rc<RefCell<ConnectBudget>>,
+    pub machines: Vec<Option<MachineMeta>>,
+    pub generations: Vec<u64>,
+    /// Generation of the tokens above `generations`, see `compact`
+    pub generation_floor: u64,
+    /// The capacity is updated by the owner of the slab
+    pub occupancy: SlabOccupancy,
+    pub timer_metrics: HashMap<&'static str, TimerMetric>,
+    pub fatal_filter: Option<Box<Fn(Token, &Error) -> bool>>,
+    pub eviction_filter: Option<Box<Fn(Token, &MachineMeta) -> bool>>,
+    pub watchdog_hook: Option<Box<Fn(Token, &MachineMeta)>>,
+    pub fatal_error: Option<(Token, Box<Error>)>,
+    pub error_hook: Option<Box<FnMut(Token, &Error)>>,
+    pub diagnostics: Option<Box<Diagnostics>>,
+    /// See `Config::loop_id`
+    pub loop_id: String,
+    pub poll_errors: PollErrors,
+    pub poll_error_hook: Option<Box<FnMut(&io::Error)>>,
+    pub slow_threshold: Option<Duration>,
+    pub slow_hook: Option<Box<FnMut(Token, Option<&'static str>, Duration)>>,
+    pub events: EventLog,
+    pub buffers: BufferPool,
+    /// Enabled by `LoopInstance::record_events`
+    pub recorder: Option<TraceWriter>,
+    /// Enabled by `Config::latency_histograms`
+    pub latency: Option<LoopLatency>,
+    /// Enabled by `Config::shuffle_dispatch`
+    pub shuffler: Option<Shuffler>,
+    /// When the first event of the current poll is received
+    pub poll_returned: Option<Instant>,
+    /// Ready events are dispatched starting from this token within the
+    /// same priority, so that the lowest tokens are not always the first
+    pub ready_cursor: usize,
+    pub registry: Registry,
+    /// State machines which returned `Response::yield_`
+    pub yielded: Vec<(Token, u64)>,
+    /// State machines which called `Scope::run_again`, in order
+    pub immediate: VecDeque<(Token, u64)>,
+    /// Number of the loop iteration, for `Config::transition_budget`
+    pub iteration: u64,
+    /// Exit signals to deliver: the linked state machine and the peer
+    /// which has failed
+    pub exits: Vec<(Token, u64, Token)>,
+    /// Events held while the state machine was paused, dispatched on the
+    /// next iteration
+    pub released: Vec<(Token, u64, EventSet)>,
+    /// State machines subscribed with `Scope::set_ticking`
+    pub tickers: Vec<(Token, u64)>,
+    /// Set while the idle timeout is dispatched, see `Scope::timeout_kind`
+    pub idle_fired: bool,
+    /// Set while the watchdog ping is dispatched, see `Scope::is_ping`
+    pub ping_fired: bool,
+    /// Set while `Machine::spawned` is dispatched, see
+    /// `Scope::spawned_children`
+    pub spawned_children: Vec<Token>,
+    /// Closures passed to `Response::defer`, run after the action
+    pub deferred: Vec<(Token, DeferredFn)>,
+    /// Suspended creations which are finished or aborted, the parents are
+    /// notified after the action
+    pub creations: Vec<Creation>,
+    pub shutting_down: bool,
+    /// Loop time is counted from this instant
+    pub start: Instant,
+    /// Source of the time, see `LoopCreator::set_clock`
+    pub time_source: Rc<Clock>,
+    /// Clocks read in the current loop iteration, see `clock()`
+    pub clock: Cell<Option<(Instant, SystemTime)>>,
+    /// Created by the first `Scope::waker`
+    #[cfg(unix)]
+    pub wakers: Option<Wakers>,
+    /// The mio token of the waker socket, the embedded loop uses one in
+    /// its token range
+    #[cfg(unix)]
+    pub waker_token: Token,
+    /// Owners of the sockets registered with `Scope::register_tracked`
+    #[cfg(unix)]
+    pub tracked: HashMap<RawFd, Token>,
+}
+
+impl Drop for MachineMeta {
+    fn drop(&mut self) {
+        // Covers both removing the state machine and dropping the loop
+        self.wakeup_pending.fetch_or(MACHINE_DEAD, Ordering::AcqRel);
+    }
+}
+
+impl MachineMeta {
+    /// The label set by `Scope::set_label`
+    pub fn label(&self) -> Option<&'static str> {
+        self.label
+    }
+    /// The deadline of the state machine if any
+    #[cfg(not(feature="no_deadlines"))]
+    pub fn deadline(&self) -> Option<Time> {
+        self.timeout.as_ref().map(|&(_, time)| time)
+    }
+    /// Always `None` with the `no_deadlines` feature
+    #[cfg(feature="no_deadlines")]
+    pub fn deadline(&self) -> Option<Time> {
+        None
+    }
+    /// The idle timeout set by `Scope::set_idle_timeout` if any
+    pub fn idle_timeout(&self) -> Option<Duration> {
+        self.idle
+    }
+    /// The priority set by `Scope::set_priority`, zero by default
+    pub fn priority(&self) -> i8 {
+        self.priority
+    }
+    /// Returns true if the state machine is replaced by a newer one, see
+    /// `Scope::is_retiring`
+    pub fn is_retiring(&self) -> bool {
+        self.retiring
+    }
+    /// The state machine which has spawned this one, if it's still alive
+    pub fn parent(&self) -> Option<Token> {
+        if self.orphaned {
+            None
+        } else {
+            self.parent.map(|(token, _)| token)
+        }
+    }
+    /// Returns true if the parent is removed, see `Scope::is_orphaned`
+    pub fn is_orphaned(&self) -> bool {
+        self.orphaned
+    }
+    /// What happens when the parent is removed
+    pub fn parent_exit(&self) -> ParentExit {
+        self.parent_exit
+    }
+    /// Time when the state machine was created
+    pub fn created(&self) -> Time {
+        self.created
+    }
+    /// Time of the last action of the state machine
+    pub fn last_activity(&self) -> Time {
+        self.last_activity
+    }
+    /// Number of seeds spawned by the state machine which wait for the
+    /// slab space, see `Scope::queued_spawns`
+    pub fn queued_spawns(&self) -> usize {
+        self.queued
+    }
+    /// Runtime statistics of the state machine
+    #[cfg(feature="machine_stats")]
+    pub fn stats(&self) -> &MachineStats {
+        &self.stats
+    }
+    /// Returns true if reading is paused by `Scope::pause_reading_for`
+    pub fn is_reading_paused(&self) -> bool {
+        self.paused.is_some()
+    }
+    /// Returns true if ready events are held by `Scope::pause`
+    pub fn is_paused(&self) -> bool {
+        self.held.is_some()
+    }
+    #[doc(hidden)]
+    pub fn set_local<T: Any>(&mut self, value: T) -> Option<T> {
+        let old = self.take_local();
+        self.locals.push(Box::new(value));
+        old
+    }
+    #[doc(hidden)]
+    pub fn local<T: Any>(&mut self) -> Option<&mut T> {
+        self.locals.iter_mut()
+            .filter_map(|x| x.downcast_mut::<T>())
+            .next()
+    }
+    #[doc(hidden)]
+    pub fn take_local<T: Any>(&mut self) -> Option<T> {
+        let pos = self.locals.iter().position(|x| x.is::<T>());
+        pos.and_then(|pos| self.locals.swap_remove(pos).downcast().ok())
+            .map(|x| *x)
+    }
+    #[doc(hidden)]
+    pub fn take_pause(&mut self) -> Option<Timeout> {
+        self.paused.take()
+    }
+}
+
+impl LoopState {
+    /// Queues the closures passed to `Response::defer`
+    pub fn defer(&mut self, token: Token, deferred: Vec<DeferredFn>) {
+        self.deferred.extend(deferred.into_iter().map(|f| (token, f)));
+    }
+    /// Shuffles the events if `Config::shuffle_dispatch` is enabled
+    pub fn shuffle<T>(&mut self, items: &mut [T]) {
+        if let Some(ref mut shuffler) = self.shuffler {
+            shuffler.shuffle(items);
+        }
+    }
+    pub fn new(channel: Channel, cfg: &Config) -> LoopState {
+        let loop_id = match loop_id(cfg) {
+            Some(id) => id.to_string(),
+            None => format!("loop-{}", channel.loop_id()),
+        };
+        let shuffler = create_shuffler(cfg, &loop_id);
+        LoopState {
+            local_wakeups: LocalWakeups::new(&channel),
+            channel: channel,
+            pool: create_pool(cfg),
+            file_pool: create_file_pool(cfg),
+            config: cfg.clone(),
+            connects: Rc::new(RefCell::new(create_budget(cfg))),
+            machines: Vec::new(),
+            generations: Vec::new(),
+            generation_floor: 0,
+            occupancy: SlabOccupancy::default(),
+            timer_metrics: HashMap::new(),
+            fatal_filter: None,
+            eviction_filter: None,
+            watchdog_hook: None,
+            fatal_error: None,
+            error_hook: None,
+            diagnostics: None,
+            loop_id: loop_id,
+            poll_errors: PollErrors::default(),
+            poll_error_hook: None,
+            slow_threshold: slow_callback_threshold(cfg),
+            slow_hook: None,
+            events: create_event_log(cfg),
+            buffers: create_buffer_pool(cfg),
+            recorder: None,
+            latency: create_latency(cfg),
+            shuffler: shuffler,
+            poll_returned: None,
+            ready_cursor: 0,
+            registry: Registry::new(),
+            yielded: Vec::new(),
+            immediate: VecDeque::new(),
+            iteration: 0,
+            exits: Vec::new(),
+            released: Vec::new(),
+            tickers: Vec::new(),
+            idle_fired: false,
+            ping_fired: false,
+            spawned_children: Vec::new(),
+            deferred: Vec::new(),
+            creations: Vec::new(),
+            shutting_down: false,
+            start: Instant::now(),
+            time_source: Rc::new(SystemClock),
+            clock: Cell::new(None),
+            #[cfg(unix)]
+            wakers: None,
+            #[cfg(unix)]
+            waker_token: WAKER_TOKEN,
+            #[cfg(unix)]
+            tracked: HashMap::new(),
+        }
+    }
+    pub fn add(&mut self, token: Token, now: Time) {
+        while self.machines.len() <= token.0 {
+            self.machines.push(None);
+        }
+        if self.machines[token.0].is_none() {
+            let occupancy = &mut self.occupancy;
+            occupancy.live += 1;
+            occupancy.high_water = max(occupancy.high_water, occupancy.live);
+        }
+        self.machines[token.0] = Some(MachineMeta {
+            generation: self.generation(token),
+            #[cfg(not(feature="no_deadlines"))]
+            timeout: None,
+            idle: None,
+            idle_timer: None,
+            paused: None,
+            held: None,
+            #[cfg(unix)]
+            primary: None,
+            #[cfg(unix)]
+            tracked: Vec::new(),
+            writer: None,
+            transitions: (0, 0),
+            lateness: None,
+            label: None,
+            priority: 0,
+            retiring: false,
+            wakeup_pending: Arc::new(AtomicUsize::new(0)),
+            parent: None,
+            parent_exit: ParentExit::Ignore,
+            orphaned: false,
+            children: 0,
+            queued: 0,
+            creating: false,
+            watchdog: false,
+            ping: None,
+            watchers: Vec::new(),
+            gone: Vec::new(),
+            links: Vec::new(),
+            named: false,
+            locals: Vec::new(),
+            coarse: None,
+            #[cfg(feature="machine_stats")]
+            stats: create_stats(now),
+            created: now,
+            last_activity: now,
+        });
+    }
+    pub fn get(&self, token: Token) -> Option<&MachineMeta> {
+        self.machines.get(token.0).and_then(|x| x.as_ref())
+    }
+    pub fn get_mut(&mut self, token: Token) -> Option<&mut MachineMeta> {
+        self.machines.get_mut(token.0).and_then(|x| x.as_mut())
+    }
+    pub fn remove(&mut self, token: Token) -> Option<MachineMeta> {
+        let meta = self.machines.get_mut(token.0).and_then(|x| x.take());
+        if let Some((parent, generation)) =
+            meta.as_ref().and_then(|m| m.parent)
+        {
+            if self.is_current(parent, generation) {
+                if let Some(parent) = self.get_mut(parent) {
+                    parent.children -= 1;
+                }
+            }
+        }
+        if let Some(ref meta) = meta {
+            if meta.named {
+                unregister_all(&self.registry, token, meta.generation);
+            }
+            for &(watcher, generation) in &meta.watchers {
+                if self.is_current(watcher, generation) {
+                    if let Some(w) = self.get_mut(watcher) {
+                        w.gone.push(token);
+                    }
+                    self.add_yielded(watcher);
+                }
+            }
+        }
+        if meta.is_some() {
+            self.occupancy.live -= 1;
+            // Events sent to the old state machine will be discarded
+            while self.generations.len() <= token.0 {
+                self.generations.push(self.generation_floor);
+            }
+            self.generations[token.0] = self.generations[token.0]
+                .wrapping_add(1);
+        }
+        meta
+    }
+    /// Generation of the state machine at the token
+    ///
+    /// Incremented each time a state machine is removed, so events
+    /// addressed to the old state machine can be told apart when the token
+    /// is reused.
+    pub fn generation(&self, token: Token) -> u64 {
+        self.generations.get(token.0).map(|x| *x)
+            .unwrap_or(self.generation_floor)
+    }
+    /// Release the metadata above the highest used token
+    ///
+    /// The generations of the released tokens are folded into the floor,
+    /// so the state machines created there later get a generation newer
+    /// than any of the removed ones had.
+    pub fn compact(&mut self) {
+        let end = self.machines.iter().rposition(|m| m.is_some())
+            .map(|idx| idx + 1).unwrap_or(0);
+        self.machines.truncate(end);
+        self.machines.shrink_to_fit();
+        if self.generations.len() > end {
+            let floor = self.generations[end..].iter().cloned()
+                .fold(self.generation_floor, max);
+            self.generation_floor = floor;
+            self.generations.truncate(end);
+            self.generations.shrink_to_fit();
+        }
+    }
+    /// Remembers the socket to deregister when the state machine is removed
+    #[cfg(unix)]
+    pub fn track(&mut self, token: Token, fd: RawFd) {
+        if let Some(owner) = self.tracked.insert(fd, token) {
+            // The descriptor is reused, so the old socket is closed
+            if let Some(meta) = self.get_mut(owner) {
+                meta.tracked.retain(|&x| x != fd);
+            }
+        }
+        if let Some(meta) = self.get_mut(token) {
+            meta.tracked.push(fd);
+        }
+    }
+    #[cfg(unix)]
+    pub fn untrack(&mut self, token: Token, fd: RawFd) {
+        if self.tracked.get(&fd) == Some(&token) {
+            self.tracked.remove(&fd);
+        }
+        if let Some(meta) = self.get_mut(token) {
+            meta.tracked.retain(|&x| x != fd);
+        }
+    }
+    /// Deregisters the tracked sockets of the removed state machine
+    #[cfg(unix)]
+    pub fn deregister_tracked<L: LoopApi + ?Sized>(&mut self,
+        meta: &mut MachineMeta, loop_api: &mut L)
+    {
+        for fd in meta.tracked.drain(..) {
+            self.tracked.remove(&fd);
+            // Fails if the socket is closed already, which is fine
+            loop_api.deregister(&EventedFd(&fd)).ok();
+        }
+    }
+    #[cfg(not(unix))]
+    pub fn deregister_tracked<L: LoopApi + ?Sized>(&mut self,
+        _meta: &mut MachineMeta, _loop_api: &mut L)
+    {
+    }
+    /// Monotonic and wall clock time of the current loop iteration
+    ///
+    /// Clocks are read on first use and cached until `reset_clock`
+    pub fn clock(&self) -> (Instant, SystemTime) {
+        match self.clock.get() {
+            Some(clock) => clock,
+            None => {
+                let clock = (self.time_source.now(),
+                             self.time_source.system_time());
+                self.clock.set(Some(clock));
+                clock
+            }
+        }
+    }
+    /// Called at the end of the loop iteration
+    pub fn reset_clock(&self) {
+        self.clock.set(None);
+    }
+    /// Create a notifier for the state machine
+    pub fn notifier(&self, token: Token) -> Notifier {
+        create_notifier(token, self.generation(token),
+            self.get(token).map(|m| &m.wakeup_pending), &self.channel)
+    }
+    /// Marks the wakeup as delivered, so the next one is sent to the queue
+    pub fn clear_wakeup(&self, token: Token) {
+        if let Some(meta) = self.get(token) {
+            meta.wakeup_pending.fetch_and(!WAKEUP_PENDING, Ordering::AcqRel);
+        }
+    }
+    /// Returns true if the event of the generation is for a live machine
+    pub fn is_current(&self, token: Token, generation: u64) -> bool {
+        self.get(token).map(|m| m.generation == generation).unwrap_or(false)
+    }
+    /// Records that the state machine is spawned by `parent`
+    /// See `Scope::enable_watchdog`
+    pub fn enable_watchdog(&mut self, token: Token, enable: bool) {
+        if let Some(meta) = self.get_mut(token) {
+            meta.watchdog = enable;
+            meta.ping = None;
+        }
+    }
+    /// See `Scope::ack_ping`
+    pub fn ack_ping(&mut self, token: Token) {
+        if let Some(meta) = self.get_mut(token) {
+            meta.ping = None;
+        }
+    }
+    /// Starts the round of the watchdog
+    ///
+    /// Returns the state machines to ping, and the ones which haven't
+    /// acknowledged the previous ping. All of them are marked as pinged.
+    pub fn watchdog_round(&mut self, now: Time)
+        -> (Vec<(Token, u64)>, Vec<Token>)
+    {
+        let mut ping = Vec::new();
+        let mut stuck = Vec::new();
+        for (idx, meta) in self.machines.iter_mut().enumerate() {
+            if let Some(ref mut meta) = *meta {
+                if !meta.watchdog {
+                    continue;
+                }
+                if meta.ping.is_some() {
+                    stuck.push(Token(idx));
+                }
+                meta.ping = Some(now);
+                ping.push((Token(idx), meta.generation));
+            }
+        }
+        (ping, stuck)
+    }
+    /// Reports the state machine which hasn't acknowledged the ping
+    pub fn report_stuck(&self, token: Token) {
+        let meta = match self.get(token) {
+            Some(meta) => meta,
+            None => return,
+        };
+        match self.watchdog_hook {
+            Some(ref hook) => hook(token, meta),
+            None => {
+                if cfg!(feature="log_errors") {
+                    warn!("{}: State machine {:?} ({:?}) hasn't \
+                        acknowledged the watchdog ping",
+                        self.loop_id, token, meta.label);
+                }
+            }
+        }
+    }
+    /// See `Scope::suspend_creation`
+    pub fn suspend_creation(&mut self, token: Token) {
+        if let Some(meta) = self.get_mut(token) {
+            meta.creating = true;
+        }
+    }
+    pub fn is_creating(&self, token: Token) -> bool {
+        self.get(token).map(|meta| meta.creating).unwrap_or(false)
+    }
+    /// Ends the suspended creation, the parent is notified after the action
+    ///
+    /// Does nothing if the creation isn't suspended.
+    pub fn finish_creation(&mut self, token: Token,
+        result: Result<(), Option<Box<Error>>>)
+    {
+        let parent = match self.get_mut(token) {
+            Some(ref mut meta) if meta.creating => {
+                meta.creating = false;
+                meta.parent
+            }
+            _ => return,
+        };
+        if let Some((parent, generation)) = parent {
+            self.creations.push(Creation {
+                parent: parent,
+                generation: generation,
+                child: token,
+                result: result,
+            });
+        }
+    }
+    /// The seed of the state machine is waiting for the slab space
+    pub fn queue_seed(&mut self, token: Token) {
+        if let Some(meta) = self.get_mut(token) {
+            meta.queued += 1;
+        }
+    }
+    /// The queued seed of the state machine is taken for spawning
+    pub fn unqueue_seed(&mut self, token: Token) {
+        if let Some(meta) = self.get_mut(token) {
+            meta.queued = meta.queued.saturating_sub(1);
+        }
+    }
+    pub fn set_parent(&mut self, token: Token, parent: Token) {
+        let generation = match self.get_mut(parent) {
+            Some(meta) => {
+                meta.children += 1;
+                meta.generation
+            }
+            None => return,
+        };
+        if let Some(meta) = self.get_mut(token) {
+            meta.parent = Some((parent, generation));
+        }
+    }
+    /// Marks the children of the removed state machine as orphans
+    ///
+    /// Returns the tokens of the children with their `ParentExit` policy.
+    pub fn orphan_children(&mut self, parent: Token, generation: u64)
+        -> Vec<(Token, ParentExit)>
+    {
+        let mut children = Vec::new();
+        for (idx, meta) in self.machines.iter_mut().enumerate() {
+            if let Some(ref mut meta) = *meta {
+                if meta.parent == Some((parent, generation)) &&
+                    !meta.orphaned
+                {
+                    meta.orphaned = true;
+                    children.push((Token(idx), meta.parent_exit));
+                }
+            }
+        }
+        children
+    }
+    /// Wake up `watcher` when the `target` state machine is removed
+    ///
+    /// If the target is already gone, the watcher is woken up right away.
+    pub fn watch(&mut self, watcher: Token, target: Token, generation: u64)
+    {
+        if !self.is_current(target, generation) {
+            if let Some(meta) = self.get_mut(watcher) {
+                meta.gone.push(target);
+            }
+            self.add_yielded(watcher);
+            return;
+        }
+        let me = (watcher, self.generation(watcher));
+        // Drop the watchers removed in the meantime
+        let mut watchers: Vec<_> = self.get(target)
+            .map(|meta| meta.watchers.iter().cloned()
+                .filter(|&(t, g)| self.is_current(t, g)).collect())
+            .unwrap_or_else(Vec::new);
+        if !watchers.contains(&me) {
+            watchers.push(me);
+        }
+        if let Some(meta) = self.get_mut(target) {
+            meta.watchers = watchers;
+        }
+    }
+    /// Sends the write events of the reader's socket to the writer, and
+    /// makes each of them watch the other one
+    pub fn split(&mut self, reader: Token, writer: Token, generation: u64)
+        -> bool
+    {
+        if !self.is_current(writer, generation) || reader == writer {
+            return false;
+        }
+        match self.get_mut(reader) {
+            Some(meta) => meta.writer = Some((writer, generation)),
+            None => return false,
+        }
+        let reader_generation = self.generation(reader);
+        self.watch(reader, writer, generation);
+        self.watch(writer, reader, reader_generation);
+        true
+    }
+    /// The writer of the split socket, if it's still alive
+    pub fn split_writer(&self, reader: Token) -> Option<(Token, u64)> {
+        match self.get(reader).and_then(|meta| meta.writer) {
+            Some((token, generation)) if self.is_current(token, generation)
+            => Some((token, generation)),
+            _ => None,
+        }
+    }
+    /// Cancels the `watch`
+    pub fn unwatch(&mut self, watcher: Token, target: Token, generation: u64)
+    {
+        let me = (watcher, self.generation(watcher));
+        if self.is_current(target, generation) {
+            if let Some(meta) = self.get_mut(target) {
+                meta.watchers.retain(|&w| w != me);
+            }
+        }
+    }
+    /// Registers the state machine as `name`, see `Scope::register_name`
+    pub fn register_name(&mut self, token: Token, name: &str) -> bool {
+        let notifier = self.notifier(token);
+        if !register(&self.registry, name, notifier) {
+            return false;
+        }
+        if let Some(meta) = self.get_mut(token) {
+            meta.named = true;
+        }
+        true
+    }
+    /// Subscribes the state machine to `Machine::tick`
+    pub fn set_ticking(&mut self, token: Token, enable: bool) {
+        let me = (token, self.generation(token));
+        self.prune_tickers();
+        self.tickers.retain(|&t| t != me);
+        if enable && self.get(token).is_some() {
+            self.tickers.push(me);
+        }
+    }
+    /// Drops the removed state machines from the tick subscribers
+    pub fn prune_tickers(&mut self) {
+        let ref machines = self.machines;
+        self.tickers.retain(|&(t, g)| {
+            machines.get(t.0).and_then(|m| m.as_ref())
+                .map(|m| m.generation == g).unwrap_or(false)
+        });
+    }
+    /// Links two state machines, see `Scope::link`
+    ///
+    /// Returns false if the `peer` is already gone.
+    pub fn link(&mut self, token: Token, peer: Token, generation: u64)
+        -> bool
+    {
+        if !self.is_current(peer, generation) || token == peer {
+            return false;
+        }
+        let me = (token, self.generation(token));
+        if let Some(meta) = self.get_mut(token) {
+            if !meta.links.contains(&(peer, generation)) {
+                meta.links.push((peer, generation));
+            }
+        }
+        if let Some(meta) = self.get_mut(peer) {
+            if !meta.links.contains(&me) {
+                meta.links.push(me);
+            }
+        }
+        true
+    }
+    /// Removes the link in both directions
+    pub fn unlink(&mut self, token: Token, peer: Token, generation: u64) {
+        let me = (token, self.generation(token));
+        if let Some(meta) = self.get_mut(token) {
+            meta.links.retain(|&l| l != (peer, generation));
+        }
+        if self.is_current(peer, generation) {
+            if let Some(meta) = self.get_mut(peer) {
+                meta.links.retain(|&l| l != me);
+            }
+        }
+    }
+    /// Queues exit signals for the state machines linked with the failed
+    /// one
+    pub fn signal_links(&mut self, token: Token) {
+        let links = match self.get_mut(token) {
+            Some(meta) => mem::replace(&mut meta.links, Vec::new()),
+            None => return,
+        };
+        for (peer, generation) in links {
+            if self.is_current(peer, generation) {
+                self.exits.push((peer, generation, token));
+            }
+        }
+    }
+    /// Returns the watched state machines which are removed, and clears
+    /// the list
+    pub fn take_gone(&mut self, watcher: Token) -> Vec<Token> {
+        self.get_mut(watcher)
+            .map(|meta| mem::replace(&mut meta.gone, Vec::new()))
+            .unwrap_or_else(Vec::new)
+    }
+    /// Starts holding the ready events of the state machine, returns
+    /// false if it's removed or paused already
+    pub fn pause<L: LoopApi + ?Sized>(&mut self, token: Token,
+        generation: u64, loop_api: &mut L)
+        -> bool
+    {
+        if !self.is_current(token, generation) {
+            return false;
+        }
+        let meta = match self.get_mut(token) {
+            Some(meta) => meta,
+            None => return false,
+        };
+        if meta.held.is_some() {
+            return false;
+        }
+        meta.held = Some(EventSet::none());
+        // Level-triggered socket would wake up the loop over and over
+        mask_primary(meta, token, loop_api);
+        true
+    }
+    /// Delivers the held events on the next iteration, returns false if
+    /// the state machine is removed or isn't paused
+    pub fn resume<L: LoopApi + ?Sized>(&mut self, token: Token,
+        generation: u64, loop_api: &mut L)
+        -> bool
+    {
+        if !self.is_current(token, generation) {
+            return false;
+        }
+        let events = match self.get_mut(token) {
+            Some(meta) => match meta.held.take() {
+                Some(events) => {
+                    unmask_primary(meta, token, loop_api);
+                    events
+                }
+                None => return false,
+            },
+            None => return false,
+        };
+        if events != EventSet::none() {
+            self.released.push((token, generation, events));
+        }
+        true
+    }
+    /// Keeps the events if the state machine is paused
+    pub fn hold(&mut self, token: Token, generation: u64, events: EventSet)
+        -> bool
+    {
+        if !self.is_current(token, generation) {
+            return false;
+        }
+        match self.get_mut(token).and_then(|meta| meta.held.as_mut()) {
+            Some(held) => {
+                *held = *held | events;
+                true
+            }
+            None => false,
+        }
+    }
+    /// Schedules a wakeup of the state machine on the next iteration
+    pub fn add_yielded(&mut self, token: Token) {
+        let generation = self.generation(token);
+        self.yielded.push((token, generation));
+    }
+    /// Same as `add_yielded` unless the wakeup is already scheduled
+    pub fn schedule_wakeup(&mut self, token: Token) {
+        let item = (token, self.generation(token));
+        if !self.yielded.contains(&item) {
+            self.yielded.push(item);
+        }
+    }
+    /// Schedules a wakeup of the state machine before the next poll
+    /// Sets the metric recording how late the deadlines of the state
+    /// machine fire
+    pub fn set_lateness_metric(&mut self, token: Token,
+        label: Option<&'static str>)
+    {
+        let metric = label.map(|label| {
+            self.timer_metrics.entry(label)
+                .or_insert_with(TimerMetric::new).clone()
+        });
+        if let Some(meta) = self.get_mut(token) {
+            meta.lateness = metric;
+        }
+    }
+    /// Records how late the deadline of the state machine fires
+    ///
+    /// Called right before `Machine::timeout`, so the clock is read again
+    /// rather than taken from the start of the iteration: the lateness
+    /// includes the actions dispatched earlier in the same iteration.
+    pub fn record_lateness(&mut self, token: Token, loop_start: Instant) {
+        let meta = match self.machines.get(token.0) {
+            Some(&Some(ref meta)) => meta,
+            _ => return,
+        };
+        let deadline = match meta.deadline() {
+            Some(deadline) => deadline,
+            None => return,
+        };
+        if self.latency.is_none() && meta.lateness.is_none() {
+            return;
+        }
+        let now = make_time(loop_start, self.time_source.now());
+        let late = time_diff_ms(deadline, now);
+        if let Some(ref metric) = meta.lateness {
+            metric.record_value(late);
+        }
+        if let Some(ref mut latency) = self.latency {
+            latency.timer_lateness.record(late);
+        }
+    }
+    /// Counts the action of the state machine in the current iteration
+    pub fn count_transition(&mut self, token: Token) {
+        let iteration = self.iteration;
+        if let Some(meta) = self.get_mut(token) {
+            if meta.transitions.0 == iteration {
+                meta.transitions.1 = meta.transitions.1.saturating_add(1);
+            } else {
+                meta.transitions = (iteration, 1);
+            }
+        }
+    }
+    /// The state machine has spent `Config::transition_budget` in the
+    /// current iteration
+    pub fn over_budget(&self, token: Token) -> bool {
+        let budget = match transition_budget(&self.config) {
+            Some(budget) => budget,
+            None => return false,
+        };
+        match self.get(token) {
+            Some(meta) => meta.transitions.0 == self.iteration &&
+                          meta.transitions.1 >= budget,
+            None => false,
+        }
+    }
+    pub fn schedule_immediate(&mut self, token: Token) {
+        let item = (token, self.generation(token));
+        if !self.immediate.contains(&item) {
+            self.immediate.push_back(item);
+        }
+    }
+    /// Counts the event dispatched to the state machine
+    #[cfg(feature="machine_stats")]
+    pub fn record_event(&mut self, token: Token, kind: &EventKind) {
+        if let Some(meta) = self.get_mut(token) {
+            match *kind {
+                EventKind::Ready(_) => meta.stats.ready_events += 1,
+                EventKind::Wakeup => meta.stats.wakeups += 1,
+                EventKind::Timeout => meta.stats.timeouts += 1,
+            }
+        }
+    }
+    /// Adds the duration of the action to the state machine's busy time
+    #[cfg(feature="machine_stats")]
+    pub fn record_busy(&mut self, token: Token, start: Instant) {
+        if let Some(meta) = self.get_mut(token) {
+            meta.stats.busy_time += start.elapsed();
+        }
+    }
+    /// Statistics of all the state machines
+    #[cfg(feature="machine_stats")]
+    pub fn machine_stats(&self) -> Vec<(Token, MachineStats)> {
+        self.machines.iter().enumerate()
+            .filter_map(|(idx, meta)| {
+                meta.as_ref().map(|meta| (Token(idx), meta.stats))
+            })
+            .collect()
+    }
+    /// Passes the event to the diagnostics or logs it
+    pub fn diagnose(&mut self, time: Time, event: Diagnostic) {
+        match self.diagnostics {
+            Some(ref mut diagnostics) => diagnostics.event(time, event),
+            None => log_event(&self.loop_id, event),
+        }
+    }
+    pub fn set_diagnostics(&mut self, mut diagnostics: Box<Diagnostics>) {
+        diagnostics.attach(&self.loop_id);
+        self.diagnostics = Some(diagnostics);
+    }
+    /// Reports the error to the hook and stores it if it's fatal, returns
+    /// true if so
+    pub fn check_fatal(&mut self, token: Token, err: Box<Error>) -> bool {
+        if let Some(ref mut hook) = self.error_hook {
+            hook(token, &*err);
+        }
+        let fatal = match self.fatal_filter {
+            Some(ref filter) => filter(token, &*err),
+            None => false,
+        };
+        if fatal && self.fatal_error.is_none() {
+            self.fatal_error = Some((token, err));
+        }
+        fatal
+    }
+    /// Reports the action of the state machine if it was too slow
+    pub fn check_slow(&mut self, token: Token, start: Instant) {
+        let threshold = match self.slow_threshold {
+            Some(x) => x,
+            None => return,
+        };
+        let elapsed = start.elapsed();
+        if elapsed < threshold {
+            return;
+        }
+        let label = self.get(token).and_then(|m| m.label());
+        match self.slow_hook {
+            Some(ref mut hook) => hook(token, label, elapsed),
+            None => {
+                if cfg!(feature = "log_errors") {
+                    warn!("{}: State machine {:?} ({}) blocked the loop \
+                        for {}ms", self.loop_id, token,
+                        label.unwrap_or("unlabeled"), millis(elapsed));
+                }
+            }
+        }
+    }
+    /// Counts the poll error and calls the hook, returns true to continue
+    pub fn poll_error(&mut self, err: &io::Error) -> bool {
+        if err.kind() == io::ErrorKind::Interrupted {
+            // mio handles it itself, but we're being defensive
+            self.poll_errors.interrupted += 1;
+            return true;
+        }
+        let retry = if is_transient(err) {
+            self.poll_errors.transient += 1;
+            retry_transient_poll_errors(&self.config)
+        } else {
+            self.poll_errors.fatal += 1;
+            false
+        };
+        if let Some(ref mut hook) = self.poll_error_hook {
+            hook(err);
+        }
+        if cfg!(feature = "log_errors") && retry {
+            warn!("{}: Transient poll error: {}", self.loop_id, err);
+        }
+        retry
+    }
+}
+
+pub fn set_label(meta: &mut MachineMeta, label: &'static str) {
+    meta.label = Some(label);
+}
+
+pub fn set_priority(meta: &mut MachineMeta, priority: i8) {
+    meta.priority = priority;
+}
+
+pub fn set_parent_exit(meta: &mut MachineMeta, policy: ParentExit) {
+    meta.parent_exit = policy;
+}
+
+/// Number of live state machines spawned by this one
+pub fn children(meta: &MachineMeta) -> usize {
+    meta.children
+}
+
+pub fn set_coarse(meta: &mut MachineMeta, granularity: Option<u64>) {
+    meta.coarse = granularity;
+}
+
+pub fn set_retiring(meta: &mut MachineMeta) {
+    meta.retiring = true;
+}
+
+pub fn touch(meta: &mut MachineMeta, now: Time) {
+    meta.last_activity = now;
+}
+
+/// Rearms the timer of the state machine if deadline has changed
+#[cfg(not(feature="no_deadlines"))]
+pub fn replace_timeout<L: LoopApi + ?Sized>(meta: &mut MachineMeta,
+    token: Token, deadline: Option<Time>, now: Time, loop_api: &mut L)
+{
+    let deadline = match (deadline, meta.coarse) {
+        (Some(time), Some(granularity)) => Some(round_up(time, granularity)),
+        (deadline, _) => deadline,
+    };
+    if deadline == meta.deadline() {
+        return;
+    }
+    // The timer entry is reused in the common case of moving the deadline
+    if let (Some(new), Some((tok, _))) = (deadline, meta.timeout) {
+        if loop_api.reschedule_timeout_ms(tok, mio_timeout_ms(now, new)) {
+            meta.timeout = Some((tok, new));
+            return;
+        }
+    }
+    clear_timeout(meta, loop_api);
+    meta.timeout = set_timeout_opt(token, meta.generation,
+        deadline, now, loop_api);
+}
+
+/// Forgets the timer of the state machine which has just fired
+#[cfg(not(feature="no_deadlines"))]
+pub fn expire_timeout(meta: &mut MachineMeta) {
+    meta.timeout = None;
+}
+
+#[cfg(not(feature="no_deadlines"))]
+pub fn clear_timeout<L: LoopApi + ?Sized>(meta: &mut MachineMeta,
+    loop_api: &mut L)
+{
+    if let Some((tok, _)) = meta.timeout.take() {
+        loop_api.clear_timeout(tok);
+    }
+}
+
+// There is no deadline to rearm with the `no_deadlines` feature, so these
+// compile to nothing on the transition path
+#[cfg(feature="no_deadlines")]
+#[inline(always)]
+pub fn replace_timeout<L: LoopApi + ?Sized>(_meta: &mut MachineMeta,
+    _token: Token, _deadline: Option<Time>, _now: Time, _loop_api: &mut L)
+{
+}
+
+#[cfg(feature="no_deadlines")]
+#[inline(always)]
+pub fn expire_timeout(_meta: &mut MachineMeta) {}
+
+#[cfg(feature="no_deadlines")]
+#[inline(always)]
+pub fn clear_timeout<L: LoopApi + ?Sized>(_meta: &mut MachineMeta,
+    _loop_api: &mut L)
+{
+}
+
+/// Sets the idle timeout of the state machine and arms it from now
+pub fn set_idle_timeout<L: LoopApi + ?Sized>(meta: &mut MachineMeta,
+    token: Token, idle: Option<Duration>, loop_api: &mut L)
+{
+    meta.idle = idle;
+    match idle {
+        Some(_) => reset_idle_timeout(meta, token, loop_api),
+        None => clear_idle_timeout(meta, loop_api),
+    }
+}
+
+/// Rearms the idle timer, called on every ready event
+pub fn reset_idle_timeout<L: LoopApi + ?Sized>(meta: &mut MachineMeta,
+    token: Token, loop_api: &mut L)
+{
+    let ms = match meta.idle {
+        Some(idle) => millis(idle),
+        None => return,
+    };
+    if let Some(tok) = meta.idle_timer {
+        if loop_api.reschedule_timeout_ms(tok, ms) {
+            return;
+        }
+    }
+    meta.idle_timer = Some(loop_api.idle_timeout_ms(token, meta.generation,
+                                                    ms));
+}
+
+/// Forgets the idle timer which has just fired
+pub fn expire_idle_timeout(meta: &mut MachineMeta) {
+    meta.idle_timer = None;
+}
+
+pub fn clear_idle_timeout<L: LoopApi + ?Sized>(meta: &mut MachineMeta,
+    loop_api: &mut L)
+{
+    if let Some(tok) = meta.idle_timer.take() {
+        loop_api.clear_timeout(tok);
+    }
+}
+
+/// Remembers the timer which resumes reading, cancels the previous one
+pub fn set_pause<L: LoopApi + ?Sized>(meta: &mut MachineMeta,
+    timeout: Timeout, loop_api: &mut L)
+{
+    clear_pause(meta, loop_api);
+    meta.paused = Some(timeout);
+}
+
+/// Remembers the socket which follows `Machine::interest`
+#[cfg(unix)]
+pub fn set_primary(meta: &mut MachineMeta, fd: RawFd, interest: EventSet,
+    opt: PollOpt)
+{
+    meta.primary = Some((fd, interest, opt));
+}
+
+#[cfg(unix)]
+pub fn clear_primary(meta: &mut MachineMeta) {
+    meta.primary = None;
+}
+
+/// The interest reading of the socket is resumed with, if the socket is
+/// the primary one
+#[cfg(unix)]
+pub fn primary_interest(meta: &MachineMeta, fd: RawFd) -> Option<EventSet> {
+    match meta.primary {
+        Some((primary, interest, _)) if primary == fd => Some(interest),
+        _ => None,
+    }
+}
+
+/// Reregisters the primary socket if `Machine::interest` has changed
+#[cfg(unix)]
+pub fn update_interest<L: LoopApi + ?Sized>(meta: &mut MachineMeta,
+    token: Token, interest: Option<EventSet>, loop_api: &mut L)
+{
+    let interest = match interest {
+        Some(interest) => interest,
+        None => return,
+    };
+    let (fd, current, opt) = match meta.primary {
+        Some(primary) => primary,
+        None => return,
+    };
+    if interest == current {
+        return;
+    }
+    // Paused reading is resumed with the new interest
+    let effective = if meta.held.is_some() {
+        EventSet::none()
+    } else if meta.paused.is_some() {
+        interest - EventSet::readable()
+    } else {
+        interest
+    };
+    match loop_api.reregister(&EventedFd(&fd), token, effective, opt) {
+        Ok(()) => meta.primary = Some((fd, interest, opt)),
+        // Retried after the next action
+        Err(e) => {
+            if cfg!(feature = "log_errors") {
+                warn!("Can't reregister the primary socket of state \
+                       machine {:?}: {}", token, e);
+            }
+        }
+    }
+}
+
+/// Stops the events of the primary socket while the machine is paused
+#[cfg(unix)]
+fn mask_primary<L: LoopApi + ?Sized>(meta: &mut MachineMeta,
+    token: Token, loop_api: &mut L)
+{
+    if let Some((fd, _, opt)) = meta.primary {
+        let res = loop_api.reregister(&EventedFd(&fd), token,
+            EventSet::none(), opt);
+        if let Err(e) = res {
+            if cfg!(feature = "log_errors") {
+                warn!("Can't pause the primary socket of state \
+                       machine {:?}: {}", token, e);
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+fn unmask_primary<L: LoopApi + ?Sized>(meta: &mut MachineMeta,
+    token: Token, loop_api: &mut L)
+{
+    if let Some((fd, interest, opt)) = meta.primary {
+        let effective = if meta.paused.is_some() {
+            interest - EventSet::readable()
+        } else {
+            interest
+        };
+        let res = loop_api.reregister(&EventedFd(&fd), token, effective, opt);
+        if let Err(e) = res {
+            if cfg!(feature = "log_errors") {
+                warn!("Can't resume the primary socket of state \
+                       machine {:?}: {}", token, e);
+            }
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn mask_primary<L: LoopApi + ?Sized>(_meta: &mut MachineMeta,
+    _token: Token, _loop_api: &mut L)
+{
+}
+
+#[cfg(not(unix))]
+fn unmask_primary<L: LoopApi + ?Sized>(_meta: &mut MachineMeta,
+    _token: Token, _loop_api: &mut L)
+{
+}
+
+#[cfg(not(unix))]
+pub fn update_interest<L: LoopApi + ?Sized>(_meta: &mut MachineMeta,
+    _token: Token, _interest: Option<::EventSet>, _loop_api: &mut L)
+{
+}
+
+/// Cancels the timer which resumes reading, returns true if there was one
+pub fn clear_pause<L: LoopApi + ?Sized>(meta: &mut MachineMeta,
+    loop_api: &mut L)
+    -> bool
+{
+    match meta.paused.take() {
+        Some(tok) => {
+            loop_api.clear_timeout(tok);
+            true
+        }
+        None => false,
+    }
+}
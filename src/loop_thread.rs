@@ -0,0 +1,123 @@
+use std::io;
+use std::sync::mpsc;
+use std::thread::{self, JoinHandle, Thread};
+
+use store::MachineStore;
+use creator::LoopInstance;
+use remote::{LoopHandle, ContextHandle};
+use notify::{ShutdownHandle, WakeupError};
+use {Machine, Registry, JoinError};
+
+
+/// The loop running in its own thread
+///
+/// Created by `LoopInstance::spawn_thread`. Holds the handles of the loop,
+/// so the thread which has started it may spawn state machines, run
+/// actions on the context, and stop the loop:
+///
+/// ```ignore
+/// let thread = try!(LoopInstance::spawn_thread(|| {
+///     let mut inst = try!(Loop::new(&Config::new())).instantiate(ctx);
+///     inst.add_machine_with(|scope| Server::new(listener, scope)).ok();
+///     Ok(inst)
+/// }));
+/// thread.handle().spawn(seed).ok();
+/// thread.shutdown().ok();
+/// let ctx = try!(thread.join());
+/// ```
+pub struct LoopThread<M: Machine> {
+    handle: LoopHandle<M::Seed>,
+    shutdown: ShutdownHandle,
+    context: ContextHandle<M::Context>,
+    registry: Registry,
+    thread: JoinHandle<Result<M::Context, JoinError>>,
+}
+
+#[doc(hidden)]
+pub fn spawn_thread<M, S, F>(build: F) -> io::Result<LoopThread<M>>
+    where M: Machine + 'static,
+          M::Context: Send + 'static,
+          M::Seed: Send + 'static,
+          S: MachineStore<Option<M>> + 'static,
+          F: FnOnce() -> io::Result<LoopInstance<M, S>> + Send + 'static,
+{
+    let (tx, rx) = mpsc::channel();
+    let thread = try!(thread::Builder::new()
+    .name(String::from("rotor-loop"))
+    .spawn(move || {
+        let inst = match build() {
+            Ok(inst) => inst,
+            Err(e) => {
+                tx.send(Err(e)).ok();
+                // Nobody joins the thread if the loop is not created
+                return Err(JoinError::Io(io::Error::new(
+                    io::ErrorKind::Other, "the loop is not created")));
+            }
+        };
+        tx.send(Ok((inst.handle(), inst.shutdown_handle(),
+                    inst.context_handle(), inst.registry()))).ok();
+        inst.run().map_err(JoinError::from)
+    }));
+    match rx.recv() {
+        Ok(Ok((handle, shutdown, context, registry))) => Ok(LoopThread {
+            handle: handle,
+            shutdown: shutdown,
+            context: context,
+            registry: registry,
+            thread: thread,
+        }),
+        Ok(Err(e)) => {
+            thread.join().ok();
+            Err(e)
+        }
+        // The sender is dropped without sending, so `build` has panicked
+        Err(_) => {
+            thread.join().ok();
+            Err(io::Error::new(io::ErrorKind::Other,
+                "the loop thread has panicked while creating the loop"))
+        }
+    }
+}
+
+impl<M: Machine> LoopThread<M> {
+    /// Returns a handle that may be used to spawn state machines
+    pub fn handle(&self) -> LoopHandle<M::Seed> {
+        self.handle.clone()
+    }
+    /// Returns a handle that may be used to stop the loop
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+    /// Returns a handle that may be used to change the context
+    pub fn context_handle(&self) -> ContextHandle<M::Context> {
+        self.context.clone()
+    }
+    /// Returns the registry of the named state machines
+    pub fn registry(&self) -> Registry {
+        self.registry.clone()
+    }
+    /// The thread running the loop
+    pub fn thread(&self) -> &Thread {
+        self.thread.thread()
+    }
+    /// Stop the loop as soon as possible, see `ShutdownHandle::shutdown`
+    pub fn shutdown(&self) -> Result<(), WakeupError> {
+        self.shutdown.shutdown()
+    }
+    /// Ask the state machines to finish, see
+    /// `ShutdownHandle::graceful_shutdown`
+    pub fn graceful_shutdown(&self) -> Result<(), WakeupError> {
+        self.shutdown.graceful_shutdown()
+    }
+    /// Wait for the loop to finish and return its context
+    ///
+    /// Blocks until the loop is shut down (or runs out of state
+    /// machines), so call `shutdown` first unless the loop stops by
+    /// itself.
+    pub fn join(self) -> Result<M::Context, JoinError> {
+        match self.thread.join() {
+            Ok(result) => result,
+            Err(panic) => Err(JoinError::Panicked(panic)),
+        }
+    }
+}
@@ -1,5 +1,5 @@
 
-use std::ops::Add;
+use std::ops::{Add, Sub, AddAssign, SubAssign};
 use std::time::{Duration, Instant, SystemTime};
 
 /// The current time
@@ -25,7 +25,7 @@ use std::time::{Duration, Instant, SystemTime};
 pub struct Time(u64);
 
 
-fn millis(dur: Duration) -> u64 {
+pub fn millis(dur: Duration) -> u64 {
     dur.as_secs()*1000 + (dur.subsec_nanos()/1000000) as u64
 }
 
@@ -36,6 +36,34 @@ impl Add<Duration> for Time {
     }
 }
 
+impl AddAssign<Duration> for Time {
+    fn add_assign(&mut self, rhs: Duration) {
+        self.0 += millis(rhs);
+    }
+}
+
+/// Subtracting saturates at the start of the loop
+impl Sub<Duration> for Time {
+    type Output = Time;
+    fn sub(self, rhs: Duration) -> Time {
+        Time(self.0.saturating_sub(millis(rhs)).max(1))
+    }
+}
+
+impl SubAssign<Duration> for Time {
+    fn sub_assign(&mut self, rhs: Duration) {
+        *self = *self - rhs;
+    }
+}
+
+/// Difference between two points in time, zero if `rhs` is later
+impl Sub<Time> for Time {
+    type Output = Duration;
+    fn sub(self, rhs: Time) -> Duration {
+        self.duration_since(rhs)
+    }
+}
+
 impl Time {
     /// Zero time value, should be used only as a starting point for unit
     /// tests
@@ -44,14 +72,37 @@ impl Time {
         // implement NonZero in the future
         Time(1)
     }
+    /// Time elapsed from `earlier` to this time
+    ///
+    /// Returns zero duration if `earlier` is actually later than `self`.
+    pub fn duration_since(&self, earlier: Time) -> Duration {
+        Duration::from_millis(self.0.saturating_sub(earlier.0))
+    }
 }
 
 pub fn make_time(base: Instant, now: Instant) -> Time {
+    if now < base {
+        return Time::zero();
+    }
     Time(millis(now.duration_since(base))
          // Time starts with 1 not with zero
          + 1)
 }
 
+/// Milliseconds since the start of the loop, for the event traces
+pub fn time_ms(time: Time) -> u64 {
+    time.0
+}
+
+pub fn make_time_ms(ms: u64) -> Time {
+    Time(ms.max(1))
+}
+
+pub fn make_instant(base: Instant, time: Time) -> Instant {
+    base + Duration::from_millis(time.0 - 1)
+}
+
+#[cfg_attr(feature="no_deadlines", allow(dead_code))]
 pub fn mio_timeout_ms(now: Time, event: Time) -> u64 {
     if event.0 > now.0 {
         // We need +1 because we truncate both old and new timeouts to
@@ -64,14 +115,38 @@ pub fn mio_timeout_ms(now: Time, event: Time) -> u64 {
     }
 }
 
+pub fn time_diff_ms(start: Time, end: Time) -> u64 {
+    end.0.saturating_sub(start.0)
+}
+
+/// Rounds the time up to the multiple of `granularity` milliseconds
+#[cfg_attr(feature="no_deadlines", allow(dead_code))]
+pub fn round_up(time: Time, granularity: u64) -> Time {
+    match time.0 % granularity {
+        0 => time,
+        rem => Time(time.0.saturating_add(granularity - rem)),
+    }
+}
+
 pub fn estimate_system_time(now: Time, value: Time) -> SystemTime {
-    SystemTime::now() + Duration::from_millis(value.0 - now.0)
+    if value >= now {
+        SystemTime::now() + value.duration_since(now)
+    } else {
+        SystemTime::now() - now.duration_since(value)
+    }
+}
+
+pub fn estimate_time(now: Time, value: SystemTime) -> Time {
+    match value.duration_since(SystemTime::now()) {
+        Ok(ahead) => now + ahead,
+        Err(e) => now - e.duration(),
+    }
 }
 
 
 #[cfg(test)]
 mod test {
-    use super::Time;
+    use super::{Time, round_up};
     use std::time::Duration;
 
 
@@ -85,4 +160,25 @@ mod test {
                    Time(5021));
     }
 
+    #[test]
+    fn test_sub() {
+        let tm = Time::zero() + Duration::from_millis(1000);
+        assert_eq!(tm - Duration::from_millis(300), Time(701));
+        assert_eq!(tm - Duration::new(5, 0), Time::zero());
+        assert_eq!(tm - Time::zero(), Duration::from_millis(1000));
+        assert_eq!(Time::zero() - tm, Duration::from_millis(0));
+        let mut x = tm;
+        x -= Duration::from_millis(1);
+        x += Duration::from_millis(2);
+        assert_eq!(x.duration_since(tm), Duration::from_millis(1));
+    }
+
+    #[test]
+    fn test_round_up() {
+        assert_eq!(round_up(Time(1), 1000), Time(1000));
+        assert_eq!(round_up(Time(1000), 1000), Time(1000));
+        assert_eq!(round_up(Time(1001), 1000), Time(2000));
+        assert_eq!(round_up(Time(1234), 1), Time(1234));
+    }
+
 }
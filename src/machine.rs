@@ -1,6 +1,9 @@
-use void::Void;
+use std::error::Error;
 
-use {Response, Scope, EventSet, SpawnError};
+use mio::Token;
+use void::{Void, unreachable};
+
+use {Response, Scope, EventSet, PollOpt, Evented, SpawnError, LinkedExit};
 
 
 /// A trait that every state machine in the loop must implement
@@ -30,11 +33,38 @@ pub trait Machine: Sized {
     /// **not** be used to create machine by external code. Create a
     /// machine-specific `Type::new` method for the purpose.
     ///
-    /// Note: we don't support spawning more state machines in create handler
+    /// If `Response::error()` is returned, the error is passed to the
+    /// `spawn_error` of the parent as `SpawnError::ConstructorError` (or
+    /// returned from `add_machine_with`).
+    /// `Response::done()` means there is nothing to create.
+    ///
+    /// The new state machine may spawn children right away, e.g. a session
+    /// needing an upstream connection: the seeds of `Response::spawn` are
+    /// created after it, and `Machine::spawned` is called as usual.
     fn create(seed: Self::Seed, scope: &mut Scope<Self::Context>)
-        -> Response<Self, Void>;
+        -> Response<Self, Self::Seed>;
+
+    /// Returns the socket of the seed to register it before `create`
+    ///
+    /// When this returns a socket, the loop registers it with the token
+    /// of the new state machine right before calling `create`. So there is
+    /// no gap between accepting a socket and registering it, even in
+    /// edge-triggered mode. `create` must not `register` such socket again,
+    /// but may `reregister` it.
+    ///
+    /// If registration fails, the parent gets `spawn_error`.
+    ///
+    /// Default implementation returns `None`, i.e. `create` registers
+    /// the socket itself.
+    fn register_seed(_seed: &Self::Seed)
+        -> Option<(&Evented, EventSet, PollOpt)>
+    {
+        None
+    }
 
     /// Socket readiness notification
+    ///
+    /// See `Readiness` for checking the `events` for a closed connection.
     fn ready(self, events: EventSet, scope: &mut Scope<Self::Context>)
         -> Response<Self, Self::Seed>;
 
@@ -43,8 +73,18 @@ pub trait Machine: Sized {
     /// This is mostly a continuation event. I.e. when you accept a socket
     /// and return a new state machine from `ready()`. You may wish to accept
     /// another socket right now. This is what `spawned` event is for.
-    fn spawned(self, scope: &mut Scope<Self::Context>)
-        -> Response<Self, Self::Seed>;
+    ///
+    /// For `Response::spawn_many` the event is called once after the
+    /// whole batch is processed. Use `Scope::spawned_children` to get the
+    /// notifiers of the created state machines.
+    ///
+    /// Default implementation does nothing, which is fine for the state
+    /// machines which never spawn.
+    fn spawned(self, _scope: &mut Scope<Self::Context>)
+        -> Response<Self, Self::Seed>
+    {
+        Response::ok(self)
+    }
 
     /// Called instead of spawned, if there is no slab space
     ///
@@ -52,18 +92,74 @@ pub trait Machine: Sized {
     /// into temporary storage, stop accepting and wait until slot is empty
     /// again.
     ///
-    /// Note: it's useless to spawn from here if the failure was , it almost certainly will fail
-    /// again, but may use a timeout
+    /// If the failure was `NoSlabSpace` and you return `Response::spawn`
+    /// from here, and there is still no slab space, the seed is queued
+    /// in the loop. The spawn is retried when any state machine is removed
+    /// (i.e. capacity frees up), followed by `spawned` or `spawn_error` as
+    /// usual. Queued seeds are dropped if this state machine is removed,
+    /// and when there are too many of them, see `Config::spawn_queue`.
+    ///
+    /// Default implementation drops the seed (logging the error with the
+    /// `log_errors` feature) and continues.
     fn spawn_error(self, _scope: &mut Scope<Self::Context>,
                    error: SpawnError<Self::Seed>)
         -> Response<Self, Self::Seed>
     {
-        panic!("Error spawning state machine: {}", error);
+        if cfg!(feature = "log_errors") {
+            warn!("Error spawning state machine: {}", error);
+        }
+        Response::ok(self)
+    }
+
+    /// The state machine is removed from the loop because the loop is
+    /// shut down
+    ///
+    /// Called for every state machine still in the loop when `Loop::run`
+    /// finishes (including graceful shutdown). It's not called when the
+    /// state machine returns `Response::done()` or `Response::error()`
+    /// itself, because it has the scope at hand to clean up at that moment.
+    /// Also called when the state machine is removed by `Notifier::abort`.
+    fn destroyed(self, _scope: &mut Scope<Self::Context>) {}
+
+    /// Convert the state machine into a seed to move it to another loop
+    ///
+    /// Called when the state machine is migrated (see
+    /// `LoopHandle::migrate`). The state machine must deregister all its
+    /// sockets and put them with the rest of its state into the seed, which
+    /// is passed to `thaw` in the other loop. Timers are cancelled by the
+    /// loop. Return `Err(self)` to refuse migration and continue running
+    /// in the current loop.
+    ///
+    /// Default implementation refuses the migration.
+    fn freeze(self, _scope: &mut Scope<Self::Context>)
+        -> Result<Self::Seed, Self>
+    {
+        Err(self)
+    }
+
+    /// Create a state machine from the seed returned by `freeze`
+    ///
+    /// Called in the loop the state machine is migrated to. Default
+    /// implementation calls `create`.
+    fn thaw(seed: Self::Seed, scope: &mut Scope<Self::Context>)
+        -> Response<Self, Self::Seed>
+    {
+        Self::create(seed, scope)
     }
 
     /// Timeout happened
-    fn timeout(self, scope: &mut Scope<Self::Context>)
-        -> Response<Self, Self::Seed>;
+    ///
+    /// Timeouts expired at the same loop iteration are dispatched in the
+    /// order of their deadlines, and the ones with the same deadline (in
+    /// milliseconds) in the order of tokens of the state machines.
+    ///
+    /// Default implementation does nothing, override it if the state
+    /// machine sets deadlines.
+    fn timeout(self, _scope: &mut Scope<Self::Context>)
+        -> Response<Self, Self::Seed>
+    {
+        Response::ok(self)
+    }
 
     /// Message received
     ///
@@ -72,6 +168,213 @@ pub trait Machine: Sized {
     /// Tokens are reused quickly.
     ///
     /// So never make this `unreachable!()` or `unimplemented!()`
+    ///
+    /// Default implementation ignores the wakeup, acknowledging the
+    /// ping of the watchdog (see `Config::watchdog`).
     fn wakeup(self, scope: &mut Scope<Self::Context>)
-        -> Response<Self, Self::Seed>;
+        -> Response<Self, Self::Seed>
+    {
+        scope.ack_ping();
+        Response::ok(self)
+    }
+
+    /// The linked state machine has exited with an error
+    ///
+    /// See `Scope::link`. Override it to trap the signal, e.g. to restart
+    /// the peer or to clean up and continue.
+    ///
+    /// Default implementation exits with the `LinkedExit` error, so the
+    /// signal propagates to the links of this state machine too.
+    fn exit_signal(self, peer: Token, _scope: &mut Scope<Self::Context>)
+        -> Response<Self, Self::Seed>
+    {
+        Response::error(Box::new(LinkedExit(peer)))
+    }
+
+    /// Called after every iteration of the loop
+    ///
+    /// Only for the state machines which subscribed with
+    /// `Scope::set_ticking`. Useful for the workloads which need a periodic
+    /// callback regardless of I/O (e.g. to flush aggregated data), without
+    /// the overhead of a timer per iteration.
+    fn tick(self, _scope: &mut Scope<Self::Context>)
+        -> Response<Self, Self::Seed>
+    {
+        Response::ok(self)
+    }
+
+    /// The configuration in the context has changed
+    ///
+    /// Called for every state machine of the loop once the closures sent
+    /// with `ContextHandle::reconfigure` are run, so listeners may rebind
+    /// and connections may pick up the new limits. The state machines
+    /// created later see the new configuration anyway and aren't called.
+    ///
+    /// Default implementation does nothing.
+    fn config_changed(self, _scope: &mut Scope<Self::Context>)
+        -> Response<Self, Self::Seed>
+    {
+        Response::ok(self)
+    }
+
+    /// The interest the primary socket should be registered with
+    ///
+    /// Checked after every action of the state machine, and if it differs
+    /// from the current one, the socket registered with
+    /// `Scope::register_primary` is reregistered. So a connection may
+    /// return `readable | writable` while it has something to send, and
+    /// never reregister by hand.
+    ///
+    /// Default implementation returns `None`, which leaves the
+    /// registration as is.
+    fn interest(&self) -> Option<EventSet> {
+        None
+    }
+
+    /// The state machine stops with `Response::fail`
+    ///
+    /// The last chance to report the error to the peer, e.g. to write an
+    /// error response to the socket, before the state machine is removed.
+    /// Returns the error the loop handles as if it's returned with
+    /// `Response::error` (logged, passed to the error hook and the linked
+    /// state machines), so it may be replaced too.
+    ///
+    /// Default implementation returns the error as is.
+    fn error(self, err: Box<Error>, _scope: &mut Scope<Self::Context>)
+        -> Box<Error>
+    {
+        err
+    }
+}
+
+/// A state machine which never spawns children
+///
+/// Most leaf state machines (e.g. a connection) have `Seed = Void` and
+/// are created with `add_machine_with` or from the seed of another state
+/// machine. Implement this trait instead of `Machine` for them, `Machine`
+/// is implemented on top of it, so `create` and the spawning events are
+/// not needed.
+///
+/// Such state machines can't be migrated to another loop, as there is
+/// no seed to `freeze` them into: `LoopHandle::migrate` is always refused.
+///
+/// Don't import both traits into the module which calls the methods of
+/// such a state machine directly, the method names are the same.
+pub trait NoSpawnMachine: Sized {
+    /// Context type for the state machine, see `Machine::Context`
+    type Context;
+
+    /// Socket readiness notification
+    fn ready(self, events: EventSet, scope: &mut Scope<Self::Context>)
+        -> Response<Self, Void>;
+
+    /// Timeout happened, see `Machine::timeout`
+    ///
+    /// Default implementation does nothing.
+    fn timeout(self, _scope: &mut Scope<Self::Context>)
+        -> Response<Self, Void>
+    {
+        Response::ok(self)
+    }
+
+    /// Message received, see `Machine::wakeup`
+    ///
+    /// Default implementation ignores the wakeup, acknowledging the
+    /// ping of the watchdog.
+    fn wakeup(self, scope: &mut Scope<Self::Context>)
+        -> Response<Self, Void>
+    {
+        scope.ack_ping();
+        Response::ok(self)
+    }
+
+    /// The linked state machine has exited, see `Machine::exit_signal`
+    ///
+    /// Default implementation exits with the `LinkedExit` error.
+    fn exit_signal(self, peer: Token, _scope: &mut Scope<Self::Context>)
+        -> Response<Self, Void>
+    {
+        Response::error(Box::new(LinkedExit(peer)))
+    }
+
+    /// Called after every iteration of the loop, see `Machine::tick`
+    fn tick(self, _scope: &mut Scope<Self::Context>)
+        -> Response<Self, Void>
+    {
+        Response::ok(self)
+    }
+
+    /// The configuration has changed, see `Machine::config_changed`
+    fn config_changed(self, _scope: &mut Scope<Self::Context>)
+        -> Response<Self, Void>
+    {
+        Response::ok(self)
+    }
+
+    /// The state machine is removed on shutdown, see `Machine::destroyed`
+    fn destroyed(self, _scope: &mut Scope<Self::Context>) {}
+    /// See `Machine::interest`
+    fn interest(&self) -> Option<EventSet> {
+        None
+    }
+    /// See `Machine::error`
+    fn error(self, err: Box<Error>, _scope: &mut Scope<Self::Context>)
+        -> Box<Error>
+    {
+        err
+    }
+}
+
+impl<T: NoSpawnMachine> Machine for T {
+    type Context = T::Context;
+    type Seed = Void;
+
+    fn create(seed: Void, _scope: &mut Scope<T::Context>)
+        -> Response<Self, Void>
+    {
+        unreachable(seed)
+    }
+    fn ready(self, events: EventSet, scope: &mut Scope<T::Context>)
+        -> Response<Self, Void>
+    {
+        NoSpawnMachine::ready(self, events, scope)
+    }
+    fn timeout(self, scope: &mut Scope<T::Context>) -> Response<Self, Void> {
+        NoSpawnMachine::timeout(self, scope)
+    }
+    fn wakeup(self, scope: &mut Scope<T::Context>) -> Response<Self, Void> {
+        NoSpawnMachine::wakeup(self, scope)
+    }
+    fn exit_signal(self, peer: Token, scope: &mut Scope<T::Context>)
+        -> Response<Self, Void>
+    {
+        NoSpawnMachine::exit_signal(self, peer, scope)
+    }
+    fn tick(self, scope: &mut Scope<T::Context>) -> Response<Self, Void> {
+        NoSpawnMachine::tick(self, scope)
+    }
+    fn config_changed(self, scope: &mut Scope<T::Context>)
+        -> Response<Self, Void>
+    {
+        NoSpawnMachine::config_changed(self, scope)
+    }
+    fn destroyed(self, scope: &mut Scope<T::Context>) {
+        NoSpawnMachine::destroyed(self, scope)
+    }
+    fn freeze(self, _scope: &mut Scope<T::Context>) -> Result<Void, Self> {
+        Err(self)
+    }
+    fn thaw(seed: Void, _scope: &mut Scope<T::Context>)
+        -> Response<Self, Void>
+    {
+        unreachable(seed)
+    }
+    fn interest(&self) -> Option<EventSet> {
+        NoSpawnMachine::interest(self)
+    }
+    fn error(self, err: Box<Error>, scope: &mut Scope<T::Context>)
+        -> Box<Error>
+    {
+        NoSpawnMachine::error(self, err, scope)
+    }
 }
@@ -19,6 +19,9 @@
 /// This creates a an `Fsm` state machine type which is enum with two options.
 /// And `Seed` state machine type, which is also enum with same option names
 /// but uses `<HttpMachine as rotor::Machine>::Seed` for the wrapped type.
+///
+/// With the `derive` feature the same is done by `#[derive(Machine)]` on
+/// the enum, which doesn't require naming the context type.
 #[macro_export]
 macro_rules! rotor_compose {
     /* TODO(tailhook) make and check generic combinators
@@ -59,13 +62,22 @@ macro_rules! rotor_compose {
             type Context = $ctx_typ;
             type Seed = $cname;
             fn create(seed: $cname, scope: &mut $crate::Scope<$ctx_typ>)
-                -> $crate::Response<Self, $crate::Void>
+                -> $crate::Response<Self, Self::Seed>
             {
                 match seed {
                     $( $cname::$iname (x)
                         => $crate::Machine::create(x, scope)
-                            .map($name::$iname,
-                                 |x| $crate::void::unreachable(x)),
+                            .map_many($name::$iname, $cname::$iname),
+                    )*
+                }
+            }
+            fn register_seed(seed: &$cname)
+                -> Option<(&$crate::Evented, $crate::EventSet,
+                           $crate::PollOpt)>
+            {
+                match *seed {
+                    $( $cname::$iname (ref x)
+                        => <$itype as $crate::Machine>::register_seed(x),
                     )*
                 }
             }
@@ -77,7 +89,7 @@ macro_rules! rotor_compose {
                     $(
                         $name::$iname(m) => {
                             m.ready(events, scope)
-                                .map($name::$iname, $cname::$iname)
+                                .map_many($name::$iname, $cname::$iname)
                         }
                     )*
                 }
@@ -89,11 +101,41 @@ macro_rules! rotor_compose {
                     $(
                         $name::$iname(m) => {
                             m.spawned(scope)
-                                .map($name::$iname, $cname::$iname)
+                                .map_many($name::$iname, $cname::$iname)
                         }
                     )*
                 }
             }
+            fn destroyed(self, scope: &mut $crate::Scope<$ctx_typ>) {
+                match self {
+                    $(
+                        $name::$iname(m) => m.destroyed(scope),
+                    )*
+                }
+            }
+            fn freeze(self, scope: &mut $crate::Scope<$ctx_typ>)
+                -> Result<$cname, Self>
+            {
+                match self {
+                    $(
+                        $name::$iname(m) => {
+                            m.freeze(scope)
+                                .map($cname::$iname)
+                                .map_err($name::$iname)
+                        }
+                    )*
+                }
+            }
+            fn thaw(seed: $cname, scope: &mut $crate::Scope<$ctx_typ>)
+                -> $crate::Response<Self, Self::Seed>
+            {
+                match seed {
+                    $( $cname::$iname (x)
+                        => <$itype as $crate::Machine>::thaw(x, scope)
+                            .map_many($name::$iname, $cname::$iname),
+                    )*
+                }
+            }
             fn timeout(self, scope: &mut $crate::Scope<$ctx_typ>)
                 -> $crate::Response<Self, Self::Seed>
             {
@@ -101,7 +143,7 @@ macro_rules! rotor_compose {
                     $(
                         $name::$iname(m) => {
                             m.timeout(scope)
-                                .map($name::$iname, $cname::$iname)
+                                .map_many($name::$iname, $cname::$iname)
                         }
                     )*
                 }
@@ -113,11 +155,65 @@ macro_rules! rotor_compose {
                     $(
                         $name::$iname(m) => {
                             m.wakeup(scope)
-                                .map($name::$iname, $cname::$iname)
+                                .map_many($name::$iname, $cname::$iname)
                         }
                     )*
                 }
             }
+            fn exit_signal(self, peer: $crate::mio::Token,
+                scope: &mut $crate::Scope<$ctx_typ>)
+                -> $crate::Response<Self, Self::Seed>
+            {
+                match self {
+                    $(
+                        $name::$iname(m) => {
+                            m.exit_signal(peer, scope)
+                                .map_many($name::$iname, $cname::$iname)
+                        }
+                    )*
+                }
+            }
+            fn tick(self, scope: &mut $crate::Scope<$ctx_typ>)
+                -> $crate::Response<Self, Self::Seed>
+            {
+                match self {
+                    $(
+                        $name::$iname(m) => {
+                            m.tick(scope)
+                                .map_many($name::$iname, $cname::$iname)
+                        }
+                    )*
+                }
+            }
+            fn config_changed(self, scope: &mut $crate::Scope<$ctx_typ>)
+                -> $crate::Response<Self, Self::Seed>
+            {
+                match self {
+                    $(
+                        $name::$iname(m) => {
+                            m.config_changed(scope)
+                                .map_many($name::$iname, $cname::$iname)
+                        }
+                    )*
+                }
+            }
+            fn interest(&self) -> Option<$crate::EventSet> {
+                match *self {
+                    $(
+                        $name::$iname(ref m) => m.interest(),
+                    )*
+                }
+            }
+            fn error(self, err: Box<::std::error::Error>,
+                scope: &mut $crate::Scope<$ctx_typ>)
+                -> Box<::std::error::Error>
+            {
+                match self {
+                    $(
+                        $name::$iname(m) => m.error(err, scope),
+                    )*
+                }
+            }
         }
 
     }
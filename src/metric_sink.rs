@@ -0,0 +1,491 @@
+use std::io;
+use std::io::Write;
+use std::mem;
+use std::fmt::Display;
+use std::rc::Rc;
+use std::cell::RefCell;
+use std::marker::PhantomData;
+use std::net::{SocketAddr, IpAddr, Ipv4Addr, Ipv6Addr};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use mio::deprecated::{TryRead, TryWrite};
+use mio::tcp::TcpStream;
+use mio::udp::UdpSocket;
+use void::{Void, unreachable};
+
+use {Machine, Scope, GenericScope, Response, EventSet, PollOpt, Time};
+use {Backoff};
+
+
+/// Metrics are dropped when this many bytes are waiting to be sent
+const MAX_BUFFER: usize = 1 << 20;
+/// Size of the statsd datagram which is safe to send over the internet
+const MAX_DATAGRAM: usize = 512;
+/// Time to establish the carbon connection
+const CONNECT_TIMEOUT_MS: u64 = 10000;
+const BACKOFF_MIN_MS: u64 = 1000;
+const BACKOFF_MAX_MS: u64 = 60000;
+const BACKOFF_JITTER: f64 = 0.5;
+
+#[derive(Clone, Copy, PartialEq)]
+enum Format {
+    Statsd,
+    Carbon,
+}
+
+struct Buffer {
+    format: Format,
+    /// Complete lines, except the first one may be partially sent
+    data: Vec<u8>,
+    /// The first line of `data` is partially sent to the connection
+    mid_line: bool,
+    dropped: u64,
+    closed: bool,
+}
+
+/// A handle to feed metrics into the `SinkMachine`
+///
+/// Created with the state machine by `SinkMachine::statsd` or
+/// `SinkMachine::carbon`. It's cheap to clone and is usually kept in the
+/// context, so every state machine of the loop can record metrics. Values
+/// are buffered and sent by the state machine on the next flush, recording
+/// never blocks.
+#[derive(Clone)]
+pub struct MetricSink {
+    buffer: Rc<RefCell<Buffer>>,
+}
+
+/// Closes the buffer when the state machine is gone
+struct Shared(Rc<RefCell<Buffer>>);
+
+enum Connection {
+    Sleeping(Time),
+    Connecting(TcpStream, Time),
+    Connected(TcpStream, EventSet),
+}
+
+enum Transport {
+    Udp(UdpSocket),
+    Tcp(Connection),
+}
+
+/// A state machine which sends metrics to statsd (UDP) or carbon (TCP)
+///
+/// Metrics recorded with the `MetricSink` are sent in batches every
+/// `interval`. Statsd metrics are packed into datagrams of up to 512
+/// bytes, a datagram which can't be sent is dropped. The carbon
+/// connection is reestablished when it fails, with delays growing from a
+/// second up to a minute, and metrics are kept in the meantime. When more
+/// than a megabyte of metrics is waiting, new ones are dropped, see
+/// `MetricSink::dropped`.
+pub struct SinkMachine<C> {
+    buffer: Shared,
+    addr: SocketAddr,
+    interval: Duration,
+    next_flush: Time,
+    transport: Transport,
+    /// Reset when the carbon connection is established
+    backoff: Backoff,
+    phantom: PhantomData<*const C>,
+}
+
+impl MetricSink {
+    fn push<F: FnOnce(&mut Vec<u8>) -> io::Result<()>>(&self, fun: F) {
+        let mut buffer = self.buffer.borrow_mut();
+        if buffer.closed || buffer.data.len() >= MAX_BUFFER {
+            buffer.dropped += 1;
+            return;
+        }
+        fun(&mut buffer.data).expect("writing to a vector never fails");
+    }
+    fn record<V: Display>(&self, name: &str, value: V, kind: &str) {
+        let format = self.buffer.borrow().format;
+        match format {
+            Format::Statsd => self.push(|buf| {
+                writeln!(buf, "{}:{}|{}", name, value, kind)
+            }),
+            Format::Carbon => {
+                let now = SystemTime::now().duration_since(UNIX_EPOCH)
+                    .map(|x| x.as_secs()).unwrap_or(0);
+                self.push(|buf| writeln!(buf, "{} {} {}", name, value, now))
+            }
+        }
+    }
+    /// Add the value to the counter
+    ///
+    /// Carbon has no counters, the value is sent as is.
+    pub fn counter(&self, name: &str, value: i64) {
+        self.record(name, value, "c")
+    }
+    /// Set the gauge
+    pub fn gauge(&self, name: &str, value: f64) {
+        self.record(name, value, "g")
+    }
+    /// Record the duration in milliseconds
+    pub fn timing(&self, name: &str, millis: u64) {
+        self.record(name, millis, "ms")
+    }
+    /// Number of metrics dropped because too many are waiting or the
+    /// state machine is gone
+    pub fn dropped(&self) -> u64 {
+        self.buffer.borrow().dropped
+    }
+}
+
+impl Drop for Shared {
+    fn drop(&mut self) {
+        let mut buffer = self.0.borrow_mut();
+        buffer.closed = true;
+        buffer.data.clear();
+    }
+}
+
+fn create<C, S: GenericScope>(format: Format, addr: SocketAddr,
+    interval: Duration, transport: io::Result<Transport>, scope: &mut S)
+    -> (Response<SinkMachine<C>, Void>, MetricSink)
+{
+    let buffer = Rc::new(RefCell::new(Buffer {
+        format: format,
+        data: Vec::new(),
+        mid_line: false,
+        dropped: 0,
+        closed: false,
+    }));
+    let sink = MetricSink { buffer: buffer.clone() };
+    let transport = match transport {
+        Ok(transport) => transport,
+        Err(e) => {
+            buffer.borrow_mut().closed = true;
+            return (Response::error(Box::new(e)), sink);
+        }
+    };
+    let next_flush = scope.now() + interval;
+    let machine = SinkMachine {
+        buffer: Shared(buffer),
+        addr: addr,
+        interval: interval,
+        next_flush: next_flush,
+        transport: transport,
+        backoff: Backoff::new(Duration::from_millis(BACKOFF_MIN_MS),
+                              Duration::from_millis(BACKOFF_MAX_MS))
+                 .jitter(BACKOFF_JITTER),
+        phantom: PhantomData,
+    };
+    (Response::ok(machine).deadline(next_flush), sink)
+}
+
+impl<C> SinkMachine<C> {
+    /// Create a state machine sending metrics to statsd at `addr`
+    pub fn statsd<S: GenericScope>(addr: SocketAddr, interval: Duration,
+        scope: &mut S)
+        -> (Response<SinkMachine<C>, Void>, MetricSink)
+    {
+        let bind = match addr {
+            SocketAddr::V4(..) => {
+                SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 0)
+            }
+            SocketAddr::V6(..) => {
+                SocketAddr::new(IpAddr::V6(Ipv6Addr::new(
+                    0, 0, 0, 0, 0, 0, 0, 0)), 0)
+            }
+        };
+        let sock = UdpSocket::bind(&bind).map(Transport::Udp);
+        create(Format::Statsd, addr, interval, sock, scope)
+    }
+    /// Create a state machine sending metrics to carbon at `addr`
+    ///
+    /// Uses the plaintext protocol, the timestamp of each value is the
+    /// time it's recorded.
+    pub fn carbon<S: GenericScope>(addr: SocketAddr, interval: Duration,
+        scope: &mut S)
+        -> (Response<SinkMachine<C>, Void>, MetricSink)
+    {
+        let conn = connect(addr, scope);
+        create(Format::Carbon, addr, interval, Ok(Transport::Tcp(conn)),
+               scope)
+    }
+}
+
+fn connect<S: GenericScope>(addr: SocketAddr, scope: &mut S) -> Connection {
+    let res = TcpStream::connect(&addr).and_then(|sock| {
+        try!(scope.register(&sock, EventSet::writable(), PollOpt::level()));
+        Ok(sock)
+    });
+    match res {
+        Ok(sock) => Connection::Connecting(sock, scope.now()),
+        Err(e) => {
+            debug!("Error connecting to carbon at {}: {}", addr, e);
+            // Retried on the next flush
+            Connection::Sleeping(scope.now())
+        }
+    }
+}
+
+/// Returns the end of the next datagram starting at `start`
+///
+/// Lines are never split, a line longer than the datagram is sent alone.
+fn datagram_end(data: &[u8], start: usize) -> usize {
+    let mut end = start;
+    for (idx, &byte) in data[start..].iter().enumerate() {
+        if byte == b'\n' {
+            if idx + 1 > MAX_DATAGRAM && end > start {
+                break;
+            }
+            end = start + idx + 1;
+        }
+    }
+    end
+}
+
+/// Sends the metrics in datagrams, stops when the socket buffer is full
+fn send_datagrams(sock: &UdpSocket, addr: &SocketAddr, data: &mut Vec<u8>) {
+    let mut start = 0;
+    while start < data.len() {
+        let end = datagram_end(data, start);
+        // Trailing newline is not needed in the datagram
+        match sock.send_to(&data[start..end-1], addr) {
+            Ok(_) => {}
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+            Err(e) => {
+                debug!("Error sending metrics to statsd at {}: {}", addr, e);
+            }
+        }
+        start = end;
+    }
+    data.drain(..start);
+}
+
+fn write_buffer(sock: &mut TcpStream, buffer: &mut Buffer) -> io::Result<()>
+{
+    while !buffer.data.is_empty() {
+        match try!(sock.try_write(&buffer.data)) {
+            Some(0) => {
+                return Err(io::Error::new(io::ErrorKind::WriteZero,
+                    "carbon doesn't accept data"));
+            }
+            Some(x) => {
+                buffer.mid_line = buffer.data[x-1] != b'\n';
+                buffer.data.drain(..x);
+            }
+            None => break,
+        }
+    }
+    Ok(())
+}
+
+fn drain_input(sock: &mut TcpStream) -> io::Result<()> {
+    let mut buf = [0u8; 1024];
+    loop {
+        match try!(sock.try_read(&mut buf)) {
+            Some(0) => {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+                    "connection closed"));
+            }
+            Some(_) => continue,
+            None => return Ok(()),
+        }
+    }
+}
+
+impl<C> SinkMachine<C> {
+    fn response(self) -> Response<Self, Void> {
+        let deadline = self.next_flush;
+        Response::ok(self).deadline(deadline)
+    }
+    /// Drops the connection (if any) and schedules the reconnect
+    fn disconnect(mut self, error: io::Error, scope: &mut Scope<C>)
+        -> Response<Self, Void>
+    {
+        debug!("Error sending metrics to carbon at {}: {}",
+            self.addr, error);
+        {
+            let mut buffer = self.buffer.0.borrow_mut();
+            if buffer.mid_line {
+                // The rest of the line is useless on the new connection
+                let end = buffer.data.iter().position(|&x| x == b'\n')
+                    .map(|x| x + 1).unwrap_or(buffer.data.len());
+                buffer.data.drain(..end);
+                buffer.mid_line = false;
+            }
+        }
+        let retry_at = scope.after_backoff(&mut self.backoff);
+        self.transport = Transport::Tcp(Connection::Sleeping(retry_at));
+        self.response()
+    }
+    /// Writes the buffer to the established connection
+    fn write(mut self, mut sock: TcpStream, interest: EventSet,
+        scope: &mut Scope<C>)
+        -> Response<Self, Void>
+    {
+        let res = write_buffer(&mut sock, &mut self.buffer.0.borrow_mut())
+            .and_then(|()| {
+                // Readable to notice the closed connection, carbon sends
+                // nothing
+                let mut new = EventSet::readable();
+                if !self.buffer.0.borrow().data.is_empty() {
+                    new = new | EventSet::writable();
+                }
+                if new != interest {
+                    try!(scope.reregister(&sock, new, PollOpt::level()));
+                }
+                Ok(new)
+            });
+        match res {
+            Ok(new) => {
+                self.transport = Transport::Tcp(
+                    Connection::Connected(sock, new));
+                self.response()
+            }
+            Err(e) => self.disconnect(e, scope),
+        }
+    }
+    fn flush(mut self, scope: &mut Scope<C>) -> Response<Self, Void> {
+        match self.transport {
+            Transport::Udp(ref sock) => {
+                send_datagrams(sock, &self.addr,
+                               &mut self.buffer.0.borrow_mut().data);
+            }
+            Transport::Tcp(Connection::Sleeping(retry_at)) => {
+                if scope.now() >= retry_at {
+                    let conn = connect(self.addr, scope);
+                    self.transport = Transport::Tcp(conn);
+                }
+            }
+            Transport::Tcp(Connection::Connecting(_, started)) => {
+                let timeout = Duration::from_millis(CONNECT_TIMEOUT_MS);
+                if scope.now() >= started + timeout {
+                    // Socket is deregistered when closed
+                    return self.disconnect(io::Error::new(
+                        io::ErrorKind::TimedOut, "connection timed out"),
+                        scope);
+                }
+            }
+            Transport::Tcp(Connection::Connected(..)) => {
+                // Same as if the socket became writable
+                return self.ready(EventSet::writable(), scope);
+            }
+        }
+        self.response()
+    }
+}
+
+impl<C> Machine for SinkMachine<C> {
+    type Context = C;
+    type Seed = Void;
+
+    fn create(seed: Void, _scope: &mut Scope<C>) -> Response<Self, Void> {
+        unreachable(seed)
+    }
+    fn ready(mut self, events: EventSet, scope: &mut Scope<C>)
+        -> Response<Self, Void>
+    {
+        // Replaced by every branch below
+        let now = scope.now();
+        let transport = mem::replace(&mut self.transport,
+            Transport::Tcp(Connection::Sleeping(now)));
+        match transport {
+            Transport::Tcp(Connection::Connecting(sock, started)) => {
+                // Only a connected socket has the peer address
+                let res = match sock.take_error() {
+                    Ok(Some(e)) | Err(e) => Err(e),
+                    Ok(None) if events.is_writable() => {
+                        sock.peer_addr().map(|_| true)
+                    }
+                    Ok(None) => Ok(false),
+                };
+                match res {
+                    Ok(true) => {
+                        self.backoff.reset();
+                        self.write(sock, EventSet::writable(), scope)
+                    }
+                    Ok(false) => {
+                        self.transport = Transport::Tcp(
+                            Connection::Connecting(sock, started));
+                        self.response()
+                    }
+                    Err(e) => self.disconnect(e, scope),
+                }
+            }
+            Transport::Tcp(Connection::Connected(mut sock, interest)) => {
+                if events.is_readable() {
+                    if let Err(e) = drain_input(&mut sock) {
+                        return self.disconnect(e, scope);
+                    }
+                }
+                self.write(sock, interest, scope)
+            }
+            transport => {
+                // Spurious event of the previous socket
+                self.transport = transport;
+                self.response()
+            }
+        }
+    }
+    fn spawned(self, _scope: &mut Scope<C>) -> Response<Self, Void> {
+        unreachable!();
+    }
+    fn timeout(mut self, scope: &mut Scope<C>) -> Response<Self, Void> {
+        if scope.now() < self.next_flush {
+            return self.response();
+        }
+        self.next_flush = scope.now() + self.interval;
+        self.flush(scope)
+    }
+    fn wakeup(self, _scope: &mut Scope<C>) -> Response<Self, Void> {
+        self.response()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::rc::Rc;
+    use std::cell::RefCell;
+    use super::{MetricSink, Buffer, Format, MAX_DATAGRAM, datagram_end};
+
+    fn sink(format: Format) -> MetricSink {
+        MetricSink { buffer: Rc::new(RefCell::new(Buffer {
+            format: format,
+            data: Vec::new(),
+            mid_line: false,
+            dropped: 0,
+            closed: false,
+        }))}
+    }
+
+    #[test]
+    fn statsd_format() {
+        let sink = sink(Format::Statsd);
+        sink.counter("requests", 3);
+        sink.gauge("connections", 12.5);
+        sink.timing("handshake", 40);
+        assert_eq!(String::from_utf8(sink.buffer.borrow().data.clone())
+                   .unwrap(),
+                   "requests:3|c\nconnections:12.5|g\nhandshake:40|ms\n");
+    }
+
+    #[test]
+    fn datagrams() {
+        let data = b"a:1|c\nb:2|c\n";
+        assert_eq!(datagram_end(data, 0), data.len());
+        assert_eq!(datagram_end(data, 6), data.len());
+        let mut long = vec![b'x'; MAX_DATAGRAM + 10];
+        long.push(b'\n');
+        long.extend_from_slice(data);
+        assert_eq!(datagram_end(&long, 0), MAX_DATAGRAM + 11);
+        let mut many = Vec::new();
+        while many.len() < MAX_DATAGRAM {
+            many.extend_from_slice(data);
+        }
+        let end = datagram_end(&many, 0);
+        assert!(end <= MAX_DATAGRAM && end > MAX_DATAGRAM - data.len());
+    }
+
+    #[test]
+    fn dropped_when_closed() {
+        let sink = sink(Format::Carbon);
+        sink.buffer.borrow_mut().closed = true;
+        sink.gauge("connections", 1.);
+        assert_eq!(sink.dropped(), 1);
+        assert!(sink.buffer.borrow().data.is_empty());
+    }
+}
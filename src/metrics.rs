@@ -0,0 +1,309 @@
+use std::rc::Rc;
+use std::cell::{RefCell, Ref};
+#[cfg(feature="machine_stats")] use std::time::Duration;
+
+use Time;
+use loop_time::time_diff_ms;
+
+/// Number of bits of a value kept exactly
+///
+/// Gives 16 buckets per power of two, i.e. a relative error is within 6%
+const SUB_BUCKET_BITS: u32 = 5;
+const HALF_BUCKET: u64 = 1 << (SUB_BUCKET_BITS - 1);
+
+
+/// A histogram of values with bounded relative error
+///
+/// This is a simplified version of HDR histogram: small values
+/// (below 32) are kept exactly, larger values are grouped in logarithmic
+/// buckets with 16 linear sub-buckets each. Memory is allocated for the
+/// range of recorded values only.
+#[derive(Debug, Clone)]
+pub struct Histogram {
+    counts: Vec<u64>,
+    count: u64,
+    sum: u64,
+    min: u64,
+    max: u64,
+}
+
+/// Occupancy of the state machine slab
+///
+/// Available from `Scope::slab_occupancy`, `LoopInstance::slab_occupancy`
+/// and `LoopHandle::slab_occupancy`. Compare the `high_water` with the
+/// `Config::slab_capacity` to size the slab.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SlabOccupancy {
+    /// Number of state machines in the loop
+    pub live: usize,
+    /// Number of state machines the slab may keep before growing (see
+    /// `Config::slab_growth`) or rejecting new ones
+    pub capacity: usize,
+    /// The largest number of state machines the loop had at once
+    pub high_water: usize,
+}
+
+/// A shared handle to a latency histogram of the loop
+///
+/// Returned by `Scope::timer_metric`. All handles with the same label
+/// refer to the same histogram. The handle is cheap to clone and may be
+/// stored in a state machine, but can't leave the loop thread.
+#[derive(Debug, Clone)]
+pub struct TimerMetric(Rc<RefCell<Histogram>>);
+
+/// Latency histograms of the loop itself
+///
+/// Collected when `Config::latency_histograms` is enabled. Available from
+/// `LoopInstance::latency`, `LoopHandle::latency` and `Scope::latency`.
+/// Growing values mean the loop is overloaded: events wait for the
+/// actions of other state machines before being dispatched.
+#[derive(Debug, Clone)]
+pub struct LoopLatency {
+    /// Time from the return of poll to dispatching the ready event, in
+    /// microseconds
+    pub dispatch: Histogram,
+    /// How late the deadlines of the state machines fire, in milliseconds
+    ///
+    /// Measured when `Machine::timeout` is called, so it includes the
+    /// coarse deadlines rounded up, the resolution of the timer, and the
+    /// actions dispatched before in the same iteration. See also
+    /// `Scope::set_lateness_metric`.
+    pub timer_lateness: Histogram,
+}
+
+impl LoopLatency {
+    pub fn new() -> LoopLatency {
+        LoopLatency {
+            dispatch: Histogram::new(),
+            timer_lateness: Histogram::new(),
+        }
+    }
+}
+
+/// Runtime statistics of a single state machine
+///
+/// Collected only when rotor is compiled with the `machine_stats` feature.
+/// Available from `MachineMeta::stats` (e.g. in `Scope::for_each_machine`),
+/// `Scope::machine_stats`, and for the whole loop from
+/// `LoopInstance::machine_stats` and `LoopHandle::machine_stats`.
+#[cfg(feature="machine_stats")]
+#[derive(Debug, Clone, Copy)]
+pub struct MachineStats {
+    /// Number of `ready()` calls
+    pub ready_events: u64,
+    /// Number of `wakeup()` calls
+    pub wakeups: u64,
+    /// Number of `timeout()` calls
+    pub timeouts: u64,
+    /// Total time spent in the actions of the state machine
+    pub busy_time: Duration,
+    /// Time when the state machine was created
+    pub created: Time,
+}
+
+#[cfg(feature="machine_stats")]
+pub fn create_stats(now: Time) -> MachineStats {
+    MachineStats {
+        ready_events: 0,
+        wakeups: 0,
+        timeouts: 0,
+        busy_time: Duration::new(0, 0),
+        created: now,
+    }
+}
+
+fn bucket_index(value: u64) -> usize {
+    if value < 2*HALF_BUCKET {
+        return value as usize;
+    }
+    let msb = 63 - value.leading_zeros();
+    let shift = msb - SUB_BUCKET_BITS + 1;
+    (shift as u64 * HALF_BUCKET + (value >> shift)) as usize
+}
+
+fn bucket_value(index: usize) -> u64 {
+    let index = index as u64;
+    if index < 2*HALF_BUCKET {
+        return index;
+    }
+    let shift = index / HALF_BUCKET - 1;
+    (index - shift*HALF_BUCKET) << shift
+}
+
+fn bucket_width(index: usize) -> u64 {
+    let index = index as u64;
+    if index < 2*HALF_BUCKET {
+        return 1;
+    }
+    1 << (index / HALF_BUCKET - 1)
+}
+
+impl Histogram {
+    pub fn new() -> Histogram {
+        Histogram {
+            counts: Vec::new(),
+            count: 0,
+            sum: 0,
+            min: 0,
+            max: 0,
+        }
+    }
+    /// Record a single value
+    pub fn record(&mut self, value: u64) {
+        let idx = bucket_index(value);
+        if self.counts.len() <= idx {
+            self.counts.resize(idx+1, 0);
+        }
+        self.counts[idx] += 1;
+        if self.count == 0 || value < self.min {
+            self.min = value;
+        }
+        if value > self.max {
+            self.max = value;
+        }
+        self.count += 1;
+        self.sum = self.sum.saturating_add(value);
+    }
+    /// Add all values of other histogram to this one
+    pub fn merge(&mut self, other: &Histogram) {
+        if other.count == 0 {
+            return;
+        }
+        if self.counts.len() < other.counts.len() {
+            self.counts.resize(other.counts.len(), 0);
+        }
+        for (dst, src) in self.counts.iter_mut().zip(other.counts.iter()) {
+            *dst += *src;
+        }
+        if self.count == 0 || other.min < self.min {
+            self.min = other.min;
+        }
+        if other.max > self.max {
+            self.max = other.max;
+        }
+        self.count += other.count;
+        self.sum = self.sum.saturating_add(other.sum);
+    }
+    /// Remove all recorded values
+    pub fn reset(&mut self) {
+        *self = Histogram::new();
+    }
+    /// Number of recorded values
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+    /// Sum of all the recorded values
+    pub fn sum(&self) -> u64 {
+        self.sum
+    }
+    /// Minimum recorded value (exact), zero if nothing is recorded
+    pub fn min(&self) -> u64 {
+        self.min
+    }
+    /// Maximum recorded value (exact), zero if nothing is recorded
+    pub fn max(&self) -> u64 {
+        self.max
+    }
+    /// Mean of the recorded values, zero if nothing is recorded
+    pub fn mean(&self) -> f64 {
+        if self.count == 0 {
+            return 0.;
+        }
+        self.sum as f64 / self.count as f64
+    }
+    /// Returns the value at the percentile (0..100)
+    ///
+    /// The value is the highest value of the bucket which contains the
+    /// percentile, but never above the maximum recorded value.
+    pub fn percentile(&self, percentile: f64) -> u64 {
+        if self.count == 0 {
+            return 0;
+        }
+        let rank = (percentile / 100. * self.count as f64).ceil() as u64;
+        let rank = if rank == 0 { 1 } else { rank };
+        let mut seen = 0;
+        for (idx, &cnt) in self.counts.iter().enumerate() {
+            seen += cnt;
+            if seen >= rank {
+                let upper = bucket_value(idx) + (bucket_width(idx) - 1);
+                return if upper > self.max { self.max } else { upper };
+            }
+        }
+        self.max
+    }
+}
+
+impl TimerMetric {
+    pub fn new() -> TimerMetric {
+        TimerMetric(Rc::new(RefCell::new(Histogram::new())))
+    }
+    /// Record a time interval (in milliseconds) between two timestamps
+    pub fn record(&self, start: Time, end: Time) {
+        self.0.borrow_mut().record(time_diff_ms(start, end));
+    }
+    /// Record an arbitrary value
+    pub fn record_value(&self, value: u64) {
+        self.0.borrow_mut().record(value);
+    }
+    /// Access the underlying histogram
+    pub fn histogram(&self) -> Ref<Histogram> {
+        self.0.borrow()
+    }
+    /// Remove all recorded values
+    pub fn reset(&self) {
+        self.0.borrow_mut().reset();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Histogram, bucket_index, bucket_value, bucket_width};
+
+    #[test]
+    fn buckets() {
+        for i in 0..100000 {
+            let idx = bucket_index(i);
+            assert!(bucket_value(idx) <= i);
+            assert!(bucket_value(idx+1) > i);
+            assert_eq!(bucket_value(idx) + bucket_width(idx),
+                       bucket_value(idx+1));
+        }
+        assert_eq!(bucket_index(31), 31);
+        assert_eq!(bucket_index(32), 32);
+        assert_eq!(bucket_index(63), 47);
+        assert_eq!(bucket_index(64), 48);
+        assert_eq!(bucket_value(bucket_index(::std::u64::MAX)),
+                   0xF800_0000_0000_0000);
+    }
+
+    #[test]
+    fn percentiles() {
+        let mut h = Histogram::new();
+        for i in 1..101 {
+            h.record(i);
+        }
+        assert_eq!(h.count(), 100);
+        assert_eq!(h.min(), 1);
+        assert_eq!(h.max(), 100);
+        assert_eq!(h.percentile(10.), 10);
+        assert_eq!(h.percentile(100.), 100);
+        h.record(::std::u64::MAX);
+        assert_eq!(h.percentile(100.), ::std::u64::MAX);
+        let p50 = h.percentile(50.);
+        assert!(p50 >= 50 && p50 <= 53);
+    }
+
+    #[test]
+    fn merge() {
+        let mut a = Histogram::new();
+        let mut b = Histogram::new();
+        a.record(5);
+        b.record(1000);
+        b.record(1);
+        a.merge(&b);
+        assert_eq!(a.count(), 3);
+        assert_eq!(a.min(), 1);
+        assert_eq!(a.max(), 1000);
+        assert_eq!(a.sum(), 1006);
+    }
+}
@@ -0,0 +1,794 @@
+use std::io::{self, Read, Write};
+use std::mem;
+use std::cmp::min;
+use std::cell::RefCell;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::collections::VecDeque;
+
+use mio::{Evented, Poll, Token, Ready, PollOpt, Registration, SetReadiness};
+
+use {Transport, Listener};
+
+
+/// Bytes buffered in each direction, reads and writes of the state machine
+/// return `WouldBlock` beyond it
+const BUFFER_SIZE: usize = 65536;
+
+/// The state of the pipe shared by the state machine and the I/O thread
+struct Buffers {
+    /// Received from the peer and not read yet
+    incoming: VecDeque<u8>,
+    /// Written by the state machine and not sent yet
+    outgoing: VecDeque<u8>,
+    /// The instance of `NamedPipeListener` waiting for a client
+    listening: bool,
+    connected: bool,
+    /// The peer has closed the pipe
+    eof: bool,
+    /// Returned by every read and write after the failure
+    error: Option<(io::ErrorKind, String)>,
+    /// The `NamedPipe` is dropped, the I/O thread sends the rest and exits
+    closed: bool,
+    /// The I/O thread is woken up and hasn't looked at the buffers yet
+    woken: bool,
+}
+
+struct Shared {
+    buffers: Buffers,
+    /// Replaced when the instance is accepted from the listener
+    readiness: SetReadiness,
+}
+
+/// A named pipe (Windows) opened in overlapped mode
+///
+/// Implements `Transport`, so protocols written for TCP run over the pipe
+/// with the `Stream` state machine, with the same deadlines and
+/// registration rules:
+///
+/// ```ignore
+/// let pipe = try!(NamedPipe::connect(r"\\.\pipe\rotor"));
+/// Stream::<Client>::new(seed, pipe, scope)
+/// ```
+///
+/// Servers accept clients with `NamedPipeListener` and the `Accept`
+/// state machine.
+///
+/// The I/O is done by a helper thread per pipe, which keeps up to 64KiB
+/// in each direction buffered and updates the readiness of the pipe. Data
+/// written before the pipe is dropped is still sent.
+///
+/// Requires the `named_pipes` feature on Windows, elsewhere `connect`
+/// and `NamedPipeListener::bind` return an error.
+pub struct NamedPipe {
+    shared: Arc<Mutex<Shared>>,
+    registration: Registration,
+    io: sys::Io,
+}
+
+/// The server side of the named pipe, accepts clients one by one
+///
+/// A new instance of the pipe is created for every accepted client, so
+/// any number of clients may connect with `NamedPipe::connect`:
+///
+/// ```ignore
+/// let listener = try!(NamedPipeListener::bind(r"\\.\pipe\rotor"));
+/// Accept::<NamedPipeListener, Server>::new(listener,
+///     |pipe, ()| Seed(pipe), scope)
+/// ```
+pub struct NamedPipeListener {
+    name: String,
+    /// The instance waiting for the client
+    pending: RefCell<NamedPipe>,
+    registration: Registration,
+    readiness: SetReadiness,
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<T> {
+    match mutex.lock() {
+        Ok(guard) => guard,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
+
+fn update(shared: &Shared) {
+    // Fails only when the registration is dropped
+    shared.readiness.set_readiness(shared.buffers.ready()).ok();
+}
+
+/// Errors meaning the peer has closed the pipe (`ERROR_BROKEN_PIPE`,
+/// `ERROR_NO_DATA`, `ERROR_PIPE_NOT_CONNECTED` and `ERROR_HANDLE_EOF`)
+fn is_closed(err: &io::Error) -> bool {
+    match err.raw_os_error() {
+        Some(109) | Some(232) | Some(233) | Some(38) => true,
+        _ => false,
+    }
+}
+
+#[cfg_attr(not(all(windows, feature="named_pipes")), allow(dead_code))]
+impl Buffers {
+    fn new(listening: bool) -> Buffers {
+        Buffers {
+            incoming: VecDeque::new(),
+            outgoing: VecDeque::new(),
+            listening: listening,
+            connected: !listening,
+            eof: false,
+            error: None,
+            closed: false,
+            woken: false,
+        }
+    }
+    fn ready(&self) -> Ready {
+        if self.listening {
+            // Readable when there is something to accept
+            return if self.connected || self.error.is_some() {
+                Ready::readable()
+            } else {
+                Ready::empty()
+            };
+        }
+        if self.error.is_some() {
+            return Ready::readable() | Ready::writable() | Ready::error();
+        }
+        let mut ready = Ready::empty();
+        if !self.incoming.is_empty() || self.eof {
+            ready = ready | Ready::readable();
+        }
+        if self.connected && !self.eof && self.outgoing.len() < BUFFER_SIZE {
+            ready = ready | Ready::writable();
+        }
+        if self.eof {
+            ready = ready | Ready::hup();
+        }
+        ready
+    }
+    fn error(&self) -> Option<io::Error> {
+        self.error.as_ref()
+            .map(|&(kind, ref msg)| io::Error::new(kind, msg.clone()))
+    }
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.incoming.is_empty() {
+            return match self.error() {
+                Some(e) => Err(e),
+                None if self.eof => Ok(0),
+                None => Err(io::ErrorKind::WouldBlock.into()),
+            };
+        }
+        let n = min(buf.len(), self.incoming.len());
+        for (dst, src) in buf.iter_mut().zip(self.incoming.drain(..n)) {
+            *dst = src;
+        }
+        Ok(n)
+    }
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if let Some(e) = self.error() {
+            return Err(e);
+        }
+        if self.eof {
+            return Err(io::ErrorKind::BrokenPipe.into());
+        }
+        if !self.connected {
+            return Err(io::ErrorKind::NotConnected.into());
+        }
+        let n = min(buf.len(), BUFFER_SIZE - self.outgoing.len());
+        if n == 0 && !buf.is_empty() {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        self.outgoing.extend(&buf[..n]);
+        Ok(n)
+    }
+    /// Number of bytes the I/O thread may read from the pipe
+    fn read_room(&self) -> usize {
+        if self.closed || self.eof || self.error.is_some() {
+            0
+        } else {
+            BUFFER_SIZE - self.incoming.len()
+        }
+    }
+    /// Returns true if the I/O thread has something to send
+    fn has_outgoing(&self) -> bool {
+        !self.outgoing.is_empty() && !self.eof && self.error.is_none()
+    }
+    /// Moves the next chunk to send into `buf`
+    fn take_outgoing(&mut self, buf: &mut Vec<u8>) {
+        let n = min(self.outgoing.len(), BUFFER_SIZE);
+        buf.clear();
+        buf.extend(self.outgoing.drain(..n));
+    }
+    /// Puts back the part of the chunk the pipe hasn't accepted
+    fn unsent(&mut self, data: &[u8]) {
+        for &byte in data.iter().rev() {
+            self.outgoing.push_front(byte);
+        }
+    }
+    fn received(&mut self, data: &[u8]) {
+        self.incoming.extend(data);
+    }
+    fn fail(&mut self, err: io::Error) {
+        if is_closed(&err) {
+            self.eof = true;
+        } else if self.error.is_none() {
+            self.error = Some((err.kind(), err.to_string()));
+        }
+    }
+}
+
+impl NamedPipe {
+    /// Connect to the pipe created by the server, e.g. `\\.\pipe\name`
+    ///
+    /// Fails with `ERROR_PIPE_BUSY` if all the instances of the pipe are
+    /// in use, retry later in this case (e.g. with `Persistent`).
+    pub fn connect(name: &str) -> io::Result<NamedPipe> {
+        let pipe = try!(sys::open(name));
+        let (registration, readiness) = Registration::new2();
+        NamedPipe::spawn(pipe, false, registration, readiness)
+    }
+    fn spawn(pipe: sys::Pipe, listening: bool, registration: Registration,
+        readiness: SetReadiness)
+        -> io::Result<NamedPipe>
+    {
+        let shared = Arc::new(Mutex::new(Shared {
+            buffers: Buffers::new(listening),
+            readiness: readiness,
+        }));
+        update(&lock(&shared));
+        let io = try!(sys::spawn(pipe, listening, shared.clone()));
+        Ok(NamedPipe {
+            shared: shared,
+            registration: registration,
+            io: io,
+        })
+    }
+    /// Lets the I/O thread continue after the buffers are changed
+    fn wakeup(&self, shared: &mut Shared) {
+        if !shared.buffers.woken {
+            shared.buffers.woken = true;
+            self.io.wakeup();
+        }
+    }
+}
+
+impl NamedPipeListener {
+    /// Create the first instance of the pipe, e.g. `\\.\pipe\name`
+    ///
+    /// Fails if the pipe with this name is already created (by this or
+    /// some other process).
+    pub fn bind(name: &str) -> io::Result<NamedPipeListener> {
+        let (registration, readiness) = Registration::new2();
+        let pending = try!(listen(name, true, &readiness));
+        Ok(NamedPipeListener {
+            name: name.to_string(),
+            pending: RefCell::new(pending),
+            registration: registration,
+            readiness: readiness,
+        })
+    }
+}
+
+/// Creates the instance of the pipe reporting to the listener's readiness
+fn listen(name: &str, first: bool, readiness: &SetReadiness)
+    -> io::Result<NamedPipe>
+{
+    let pipe = try!(sys::create(name, first));
+    // The registration is replaced when the instance is accepted
+    let (registration, _) = Registration::new2();
+    NamedPipe::spawn(pipe, true, registration, readiness.clone())
+}
+
+impl Listener for NamedPipeListener {
+    type Stream = NamedPipe;
+    type Address = ();
+    fn accept(&self) -> io::Result<Option<(NamedPipe, ())>> {
+        {
+            let pending = self.pending.borrow();
+            let shared = lock(&pending.shared);
+            if !shared.buffers.connected && shared.buffers.error.is_none() {
+                return Ok(None);
+            }
+        }
+        // Cleared first, so the next client connecting right away is not
+        // missed
+        self.readiness.set_readiness(Ready::empty()).ok();
+        let next = match listen(&self.name, false, &self.readiness) {
+            Ok(next) => next,
+            Err(e) => {
+                update(&lock(&self.pending.borrow().shared));
+                return Err(e);
+            }
+        };
+        let mut pipe = mem::replace(&mut *self.pending.borrow_mut(), next);
+        let (registration, readiness) = Registration::new2();
+        pipe.registration = registration;
+        let error = {
+            let mut shared = lock(&pipe.shared);
+            shared.buffers.listening = false;
+            shared.readiness = readiness;
+            update(&shared);
+            shared.buffers.error()
+        };
+        match error {
+            // The failed instance is closed when dropped
+            Some(e) => Err(e),
+            None => Ok(Some((pipe, ()))),
+        }
+    }
+}
+
+impl Read for NamedPipe {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut shared = lock(&self.shared);
+        let res = shared.buffers.read(buf);
+        if let Ok(n) = res {
+            if n > 0 {
+                self.wakeup(&mut shared);
+            }
+        }
+        update(&shared);
+        res
+    }
+}
+
+impl Write for NamedPipe {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut shared = lock(&self.shared);
+        let res = shared.buffers.write(buf);
+        if let Ok(n) = res {
+            if n > 0 {
+                self.wakeup(&mut shared);
+            }
+        }
+        update(&shared);
+        res
+    }
+    /// The data is sent by the I/O thread, so does nothing
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for NamedPipe {
+    fn drop(&mut self) {
+        let mut shared = lock(&self.shared);
+        shared.buffers.closed = true;
+        self.wakeup(&mut shared);
+    }
+}
+
+impl Evented for NamedPipe {
+    fn register(&self, poll: &Poll, token: Token, interest: Ready,
+        opts: PollOpt)
+        -> io::Result<()>
+    {
+        self.registration.register(poll, token, interest, opts)
+    }
+    fn reregister(&self, poll: &Poll, token: Token, interest: Ready,
+        opts: PollOpt)
+        -> io::Result<()>
+    {
+        self.registration.reregister(poll, token, interest, opts)
+    }
+    fn deregister(&self, poll: &Poll) -> io::Result<()> {
+        self.registration.deregister(poll)
+    }
+}
+
+impl Evented for NamedPipeListener {
+    fn register(&self, poll: &Poll, token: Token, interest: Ready,
+        opts: PollOpt)
+        -> io::Result<()>
+    {
+        self.registration.register(poll, token, interest, opts)
+    }
+    fn reregister(&self, poll: &Poll, token: Token, interest: Ready,
+        opts: PollOpt)
+        -> io::Result<()>
+    {
+        self.registration.reregister(poll, token, interest, opts)
+    }
+    fn deregister(&self, poll: &Poll) -> io::Result<()> {
+        self.registration.deregister(poll)
+    }
+}
+
+impl Transport for NamedPipe {}
+
+#[cfg(all(windows, feature="named_pipes"))]
+mod sys {
+    use std::io;
+    use std::mem;
+    use std::ptr;
+    use std::thread;
+    use std::fs::OpenOptions;
+    use std::sync::{Arc, Mutex};
+    use std::os::windows::fs::OpenOptionsExt;
+    use std::os::windows::io::{FromRawHandle, IntoRawHandle};
+
+    use miow::Overlapped;
+    use miow::iocp::{CompletionPort, CompletionStatus};
+    use miow::pipe::{NamedPipe, NamedPipeBuilder};
+
+    use super::{Shared, BUFFER_SIZE, lock, update};
+
+    pub type Pipe = NamedPipe;
+
+    const FILE_FLAG_OVERLAPPED: u32 = 0x40000000;
+    /// Completion key of the pipe, wakeups are posted with `WAKEUP`
+    const PIPE: usize = 0;
+    const WAKEUP: usize = 1;
+
+    /// Wakes up the I/O thread of the pipe
+    pub struct Io {
+        port: Arc<CompletionPort>,
+    }
+
+    /// The I/O thread, runs a completion port of its own
+    ///
+    /// At most a single read and a single write are in flight. The
+    /// buffers and the `Overlapped` structures are used by the kernel
+    /// until the operations complete, so they are boxed and kept until
+    /// the completions of the cancelled operations are received.
+    struct IoThread {
+        pipe: NamedPipe,
+        port: Arc<CompletionPort>,
+        shared: Arc<Mutex<Shared>>,
+        read_buf: Vec<u8>,
+        write_buf: Vec<u8>,
+        read_ov: Box<Overlapped>,
+        write_ov: Box<Overlapped>,
+        connect_ov: Box<Overlapped>,
+        reading: bool,
+        writing: bool,
+        connecting: bool,
+    }
+
+    impl Io {
+        pub fn wakeup(&self) {
+            // Fails only if the thread has exited
+            self.port.post(CompletionStatus::new(0, WAKEUP, ptr::null_mut()))
+                .ok();
+        }
+    }
+
+    pub fn create(name: &str, first: bool) -> io::Result<NamedPipe> {
+        NamedPipeBuilder::new(name)
+            .first(first)
+            .inbound(true)
+            .outbound(true)
+            .in_buffer_size(BUFFER_SIZE as u32)
+            .out_buffer_size(BUFFER_SIZE as u32)
+            .create()
+    }
+
+    pub fn open(name: &str) -> io::Result<NamedPipe> {
+        let file = try!(OpenOptions::new().read(true).write(true)
+            .custom_flags(FILE_FLAG_OVERLAPPED)
+            .open(name));
+        Ok(unsafe { NamedPipe::from_raw_handle(file.into_raw_handle()) })
+    }
+
+    pub fn spawn(pipe: NamedPipe, listening: bool,
+        shared: Arc<Mutex<Shared>>)
+        -> io::Result<Io>
+    {
+        let port = Arc::new(try!(CompletionPort::new(1)));
+        try!(port.add_handle(PIPE, &pipe));
+        let thread_port = port.clone();
+        try!(thread::Builder::new()
+        .name(String::from("rotor-pipe"))
+        .spawn(move || {
+            // `Overlapped` is not `Send`, so it's created in the thread
+            IoThread {
+                pipe: pipe,
+                port: thread_port,
+                shared: shared,
+                read_buf: vec![0; BUFFER_SIZE],
+                write_buf: Vec::with_capacity(BUFFER_SIZE),
+                read_ov: Box::new(Overlapped::zero()),
+                write_ov: Box::new(Overlapped::zero()),
+                connect_ov: Box::new(Overlapped::zero()),
+                reading: false,
+                writing: false,
+                connecting: false,
+            }.run(listening)
+        }));
+        Ok(Io { port: port })
+    }
+
+    impl IoThread {
+        fn run(mut self, listening: bool) {
+            if listening {
+                let res = unsafe {
+                    self.pipe.connect_overlapped(self.connect_ov.raw())
+                };
+                let mut shared = lock(&self.shared);
+                match res {
+                    // Either connected right away, or the client has
+                    // connected before `ConnectNamedPipe`
+                    Ok(true) => shared.buffers.connected = true,
+                    Ok(false) => self.connecting = true,
+                    Err(e) => shared.buffers.fail(e),
+                }
+                update(&shared);
+            }
+            let mut statuses = [CompletionStatus::zero(); 4];
+            while self.start() {
+                let res = self.port.get_many(&mut statuses, None)
+                    .map(|list| list.len());
+                match res {
+                    Ok(n) => {
+                        for status in &statuses[..n] {
+                            self.complete(status);
+                        }
+                    }
+                    Err(e) => {
+                        let mut shared = lock(&self.shared);
+                        shared.buffers.fail(e);
+                        update(&shared);
+                        break;
+                    }
+                }
+            }
+            self.drain();
+        }
+        /// Starts the operations the buffers allow, returns false when the
+        /// pipe is closed and everything is sent
+        fn start(&mut self) -> bool {
+            let mut shared = lock(&self.shared);
+            shared.buffers.woken = false;
+            if self.connecting {
+                return !shared.buffers.closed;
+            }
+            if !self.writing && shared.buffers.has_outgoing() {
+                shared.buffers.take_outgoing(&mut self.write_buf);
+                let res = unsafe {
+                    self.pipe.write_overlapped(&self.write_buf,
+                                               self.write_ov.raw())
+                };
+                match res {
+                    Ok(_) => self.writing = true,
+                    Err(e) => shared.buffers.fail(e),
+                }
+            }
+            let room = shared.buffers.read_room();
+            if !self.reading && room > 0 {
+                let res = unsafe {
+                    self.pipe.read_overlapped(&mut self.read_buf[..room],
+                                              self.read_ov.raw())
+                };
+                match res {
+                    Ok(_) => self.reading = true,
+                    Err(e) => shared.buffers.fail(e),
+                }
+            }
+            update(&shared);
+            !(shared.buffers.closed && !self.writing &&
+              !shared.buffers.has_outgoing())
+        }
+        fn complete(&mut self, status: &CompletionStatus) {
+            let ov = status.overlapped();
+            let mut shared = lock(&self.shared);
+            if self.reading && ov == self.read_ov.raw() {
+                self.reading = false;
+                match unsafe { self.pipe.result(ov) } {
+                    Ok(n) => shared.buffers.received(&self.read_buf[..n]),
+                    Err(e) => shared.buffers.fail(e),
+                }
+            } else if self.writing && ov == self.write_ov.raw() {
+                self.writing = false;
+                match unsafe { self.pipe.result(ov) } {
+                    Ok(n) if n < self.write_buf.len() => {
+                        shared.buffers.unsent(&self.write_buf[n..]);
+                    }
+                    Ok(_) => {}
+                    Err(e) => shared.buffers.fail(e),
+                }
+            } else if self.connecting && ov == self.connect_ov.raw() {
+                self.connecting = false;
+                match unsafe { self.pipe.result(ov) } {
+                    Ok(_) => shared.buffers.connected = true,
+                    Err(e) => shared.buffers.fail(e),
+                }
+            }
+            // Wakeups only make `start` run again
+            update(&shared);
+        }
+        /// Closes the pipe and waits for the completions of the cancelled
+        /// operations, so the kernel doesn't write to the freed buffers
+        fn drain(self) {
+            let IoThread { pipe, port, read_buf, write_buf,
+                           read_ov, write_ov, connect_ov,
+                           mut reading, mut writing, mut connecting,
+                           .. } = self;
+            drop(pipe);
+            let mut statuses = [CompletionStatus::zero(); 4];
+            while reading || writing || connecting {
+                match port.get_many(&mut statuses, None) {
+                    Ok(list) => {
+                        for status in list.iter() {
+                            let ov = status.overlapped();
+                            if ov == read_ov.raw() {
+                                reading = false;
+                            } else if ov == write_ov.raw() {
+                                writing = false;
+                            } else if ov == connect_ov.raw() {
+                                connecting = false;
+                            }
+                        }
+                    }
+                    Err(_) => {
+                        // Leaked rather than freed while still in use
+                        mem::forget((read_buf, write_buf,
+                                     read_ov, write_ov, connect_ov));
+                        return;
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(not(all(windows, feature="named_pipes")))]
+mod sys {
+    use std::io;
+    use std::sync::{Arc, Mutex};
+
+    use void::{Void, unreachable};
+
+    use super::Shared;
+
+    pub type Pipe = Void;
+
+    pub struct Io;
+
+    impl Io {
+        pub fn wakeup(&self) {}
+    }
+
+    fn unsupported() -> io::Error {
+        io::Error::new(io::ErrorKind::Other,
+            "named pipes require Windows and the `named_pipes` feature")
+    }
+
+    pub fn create(_name: &str, _first: bool) -> io::Result<Void> {
+        Err(unsupported())
+    }
+
+    pub fn open(_name: &str) -> io::Result<Void> {
+        Err(unsupported())
+    }
+
+    pub fn spawn(pipe: Void, _listening: bool, _shared: Arc<Mutex<Shared>>)
+        -> io::Result<Io>
+    {
+        unreachable(pipe)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io;
+    use mio::Ready;
+    use super::{Buffers, BUFFER_SIZE};
+
+    #[test]
+    fn read_and_eof() {
+        let mut b = Buffers::new(false);
+        let mut buf = [0u8; 4];
+        assert_eq!(b.read(&mut buf).unwrap_err().kind(),
+                   io::ErrorKind::WouldBlock);
+        b.received(b"hello");
+        assert!(b.ready().is_readable());
+        assert_eq!(b.read(&mut buf).unwrap(), 4);
+        assert_eq!(&buf, b"hell");
+        b.fail(io::Error::from_raw_os_error(109));
+        assert!(b.ready().is_hup());
+        assert_eq!(b.read(&mut buf).unwrap(), 1);
+        assert_eq!(b.read(&mut buf).unwrap(), 0);
+        assert_eq!(b.write(b"x").unwrap_err().kind(),
+                   io::ErrorKind::BrokenPipe);
+    }
+
+    #[test]
+    fn write_capacity() {
+        let mut b = Buffers::new(false);
+        let data = vec![1u8; BUFFER_SIZE + 10];
+        assert_eq!(b.write(&data).unwrap(), BUFFER_SIZE);
+        assert!(!b.ready().is_writable());
+        assert_eq!(b.write(&data).unwrap_err().kind(),
+                   io::ErrorKind::WouldBlock);
+        let mut chunk = Vec::new();
+        b.take_outgoing(&mut chunk);
+        assert_eq!(chunk.len(), BUFFER_SIZE);
+        b.unsent(&chunk[BUFFER_SIZE-3..]);
+        assert_eq!(b.outgoing.len(), 3);
+        assert!(b.ready().is_writable());
+    }
+
+    #[test]
+    fn error_is_sticky() {
+        let mut b = Buffers::new(false);
+        b.received(b"ab");
+        b.fail(io::Error::new(io::ErrorKind::PermissionDenied, "denied"));
+        assert!(b.ready().is_error());
+        let mut buf = [0u8; 4];
+        assert_eq!(b.read(&mut buf).unwrap(), 2);
+        for _ in 0..2 {
+            assert_eq!(b.read(&mut buf).unwrap_err().kind(),
+                       io::ErrorKind::PermissionDenied);
+            assert_eq!(b.write(b"x").unwrap_err().kind(),
+                       io::ErrorKind::PermissionDenied);
+        }
+        assert_eq!(b.read_room(), 0);
+        assert!(!b.has_outgoing());
+    }
+
+    #[test]
+    fn listening() {
+        let mut b = Buffers::new(true);
+        assert_eq!(b.ready(), Ready::empty());
+        assert_eq!(b.write(b"x").unwrap_err().kind(),
+                   io::ErrorKind::NotConnected);
+        b.connected = true;
+        assert_eq!(b.ready(), Ready::readable());
+        b.listening = false;
+        assert_eq!(b.ready(), Ready::writable());
+    }
+
+    /// Retries the operation of the pipe until it's not `WouldBlock`
+    #[cfg(all(windows, feature="named_pipes"))]
+    fn retry<T, F>(mut op: F) -> io::Result<T>
+        where F: FnMut() -> io::Result<T>
+    {
+        use std::thread;
+        use std::time::{Duration, Instant};
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop {
+            match op() {
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock &&
+                              Instant::now() < deadline
+                => thread::sleep(Duration::from_millis(10)),
+                res => return res,
+            }
+        }
+    }
+
+    #[cfg(all(windows, feature="named_pipes"))]
+    fn read_all(pipe: &mut super::NamedPipe, len: usize) -> Vec<u8> {
+        use std::io::Read;
+        let mut data = Vec::new();
+        while data.len() < len {
+            let mut buf = [0u8; 16];
+            let n = retry(|| pipe.read(&mut buf)).unwrap();
+            assert!(n > 0, "unexpected end of the pipe");
+            data.extend_from_slice(&buf[..n]);
+        }
+        data
+    }
+
+    #[test]
+    #[cfg(all(windows, feature="named_pipes"))]
+    fn round_trip() {
+        use std::io::{Read, Write};
+        use Listener;
+        use super::{NamedPipe, NamedPipeListener, lock};
+
+        let name = r"\\.\pipe\rotor-test-round-trip";
+        let listener = NamedPipeListener::bind(name).unwrap();
+        let mut client = NamedPipe::connect(name).unwrap();
+        let (mut server, ()) = retry(|| match listener.accept() {
+            Ok(Some(pair)) => Ok(pair),
+            Ok(None) => Err(io::ErrorKind::WouldBlock.into()),
+            Err(e) => Err(e),
+        }).unwrap();
+
+        assert_eq!(retry(|| client.write(b"ping")).unwrap(), 4);
+        assert_eq!(read_all(&mut server, 4), b"ping");
+        assert_eq!(retry(|| server.write(b"pong")).unwrap(), 4);
+        assert_eq!(read_all(&mut client, 4), b"pong");
+
+        drop(server);
+        let mut buf = [0u8; 4];
+        assert_eq!(retry(|| client.read(&mut buf)).unwrap(), 0);
+        assert!(lock(&client.shared).buffers.ready().is_hup());
+    }
+}
@@ -0,0 +1,495 @@
+use std::net::IpAddr;
+
+use void::{Void, unreachable};
+
+use {Machine, Scope, GenericScope, Response, EventSet};
+
+
+/// A change reported by the kernel
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NetlinkEvent {
+    /// The network interface is added or its state has changed
+    Link {
+        index: u32,
+        name: Option<String>,
+        /// The interface is administratively up (`IFF_UP`)
+        up: bool,
+        /// The interface has carrier (`IFF_RUNNING`)
+        running: bool,
+    },
+    /// The network interface is removed
+    LinkRemoved { index: u32, name: Option<String> },
+    /// The address is assigned to the interface
+    Address { index: u32, addr: IpAddr, prefix: u8 },
+    /// The address is removed from the interface
+    AddressRemoved { index: u32, addr: IpAddr, prefix: u8 },
+    /// The device event of udev, e.g. `add@/devices/...`
+    Device {
+        action: String,
+        devpath: String,
+        /// Other variables, like `SUBSYSTEM` and `DEVNAME`
+        vars: Vec<(String, String)>,
+    },
+    /// Some events are lost, rescan the current state
+    Overflow,
+}
+
+/// Callbacks of the `NetlinkWatcher`
+pub trait NetlinkHandler: Sized {
+    type Context;
+    /// Events read from the socket within a single ready event
+    fn changed(self, events: &[NetlinkEvent],
+        scope: &mut Scope<Self::Context>)
+        -> Self;
+    /// The state machine is woken up
+    fn wakeup(self, _scope: &mut Scope<Self::Context>) -> Self {
+        self
+    }
+}
+
+/// A state machine receiving notifications of the linux kernel
+///
+/// `route` subscribes to the changes of the network interfaces and their
+/// addresses (the `NETLINK_ROUTE` family), so a daemon can, for example,
+/// bind the listeners to the addresses as they appear. `uevent`
+/// subscribes to the device events (`NETLINK_KOBJECT_UEVENT`), the same
+/// ones udev receives. The socket is registered in the loop, so no
+/// monitoring thread is needed.
+///
+/// Only the changes are reported, the state at the start should be read
+/// separately. The kernel drops the events when the socket buffer is
+/// full, which is reported as `NetlinkEvent::Overflow`.
+///
+/// Requires the `netlink` feature and linux, otherwise the constructors
+/// return an error.
+pub struct NetlinkWatcher<H> {
+    sys: sys::Socket,
+    handler: H,
+}
+
+impl<H: NetlinkHandler> NetlinkWatcher<H> {
+    /// Watch the network interfaces and their IPv4 and IPv6 addresses
+    pub fn route<S: GenericScope>(handler: H, scope: &mut S)
+        -> Response<NetlinkWatcher<H>, Void>
+    {
+        NetlinkWatcher::start(sys::Socket::route(), handler, scope)
+    }
+    /// Watch the device events
+    pub fn uevent<S: GenericScope>(handler: H, scope: &mut S)
+        -> Response<NetlinkWatcher<H>, Void>
+    {
+        NetlinkWatcher::start(sys::Socket::uevent(), handler, scope)
+    }
+    fn start<S: GenericScope>(sock: ::std::io::Result<sys::Socket>,
+        handler: H, scope: &mut S)
+        -> Response<NetlinkWatcher<H>, Void>
+    {
+        let res = sock.and_then(|sys| {
+            try!(sys.register(scope));
+            Ok(sys)
+        });
+        match res {
+            Ok(sys) => {
+                Response::ok(NetlinkWatcher { sys: sys, handler: handler })
+            }
+            Err(e) => Response::error(Box::new(e)),
+        }
+    }
+}
+
+impl<H: NetlinkHandler> Machine for NetlinkWatcher<H> {
+    type Context = H::Context;
+    type Seed = Void;
+
+    fn create(seed: Void, _scope: &mut Scope<H::Context>)
+        -> Response<Self, Void>
+    {
+        unreachable(seed)
+    }
+    fn ready(self, _events: EventSet, scope: &mut Scope<H::Context>)
+        -> Response<Self, Void>
+    {
+        let NetlinkWatcher { mut sys, handler } = self;
+        let mut events = Vec::new();
+        if let Err(e) = sys.read(&mut events) {
+            return Response::error(Box::new(e));
+        }
+        let handler = if events.is_empty() {
+            handler
+        } else {
+            handler.changed(&events, scope)
+        };
+        Response::ok(NetlinkWatcher { sys: sys, handler: handler })
+    }
+    fn spawned(self, _scope: &mut Scope<H::Context>) -> Response<Self, Void>
+    {
+        unreachable!();
+    }
+    fn timeout(self, _scope: &mut Scope<H::Context>) -> Response<Self, Void>
+    {
+        Response::ok(self)
+    }
+    fn wakeup(self, scope: &mut Scope<H::Context>) -> Response<Self, Void> {
+        let NetlinkWatcher { sys, handler } = self;
+        Response::ok(NetlinkWatcher {
+            sys: sys,
+            handler: handler.wakeup(scope),
+        })
+    }
+}
+
+/// Parsers of the messages, which don't need the socket
+#[cfg(any(test, all(feature="netlink", target_os="linux")))]
+mod parse {
+    use std::ptr;
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+    use super::NetlinkEvent;
+
+    const NLMSG_HDRLEN: usize = 16;
+    const NLMSG_OVERRUN: u16 = 4;
+    const RTM_NEWLINK: u16 = 16;
+    const RTM_DELLINK: u16 = 17;
+    const RTM_NEWADDR: u16 = 20;
+    const RTM_DELADDR: u16 = 21;
+    const IFINFOMSG_LEN: usize = 16;
+    const IFADDRMSG_LEN: usize = 8;
+    const IFLA_IFNAME: u16 = 3;
+    const IFA_ADDRESS: u16 = 1;
+    const IFA_LOCAL: u16 = 2;
+    const IFF_UP: u32 = 0x1;
+    const IFF_RUNNING: u32 = 0x40;
+    const AF_INET: u8 = 2;
+    const AF_INET6: u8 = 10;
+
+    fn align(len: usize) -> usize {
+        (len + 3) & !3
+    }
+
+    /// Reads the native-endian integer, the caller checks the bounds
+    fn read_u16(buf: &[u8], offset: usize) -> u16 {
+        assert!(offset + 2 <= buf.len());
+        unsafe { ptr::read_unaligned(buf[offset..].as_ptr() as *const u16) }
+    }
+
+    fn read_u32(buf: &[u8], offset: usize) -> u32 {
+        assert!(offset + 4 <= buf.len());
+        unsafe { ptr::read_unaligned(buf[offset..].as_ptr() as *const u32) }
+    }
+
+    /// Iterates over the `(type, payload)` of the route attributes
+    fn attributes<F: FnMut(u16, &[u8])>(mut buf: &[u8], mut f: F) {
+        while buf.len() >= 4 {
+            let len = read_u16(buf, 0) as usize;
+            if len < 4 || len > buf.len() {
+                return;
+            }
+            f(read_u16(buf, 2), &buf[4..len]);
+            buf = &buf[align(len).min(buf.len())..];
+        }
+    }
+
+    fn parse_link(kind: u16, body: &[u8]) -> Option<NetlinkEvent> {
+        if body.len() < IFINFOMSG_LEN {
+            return None;
+        }
+        let index = read_u32(body, 4);
+        let flags = read_u32(body, 8);
+        let mut name = None;
+        attributes(&body[IFINFOMSG_LEN..], |typ, value| {
+            if typ == IFLA_IFNAME {
+                let len = value.iter().position(|&x| x == 0)
+                    .unwrap_or(value.len());
+                let value = String::from_utf8_lossy(&value[..len]);
+                name = Some(value.into_owned());
+            }
+        });
+        if kind == RTM_NEWLINK {
+            Some(NetlinkEvent::Link {
+                index: index,
+                name: name,
+                up: flags & IFF_UP != 0,
+                running: flags & IFF_RUNNING != 0,
+            })
+        } else {
+            Some(NetlinkEvent::LinkRemoved { index: index, name: name })
+        }
+    }
+
+    fn parse_address(kind: u16, body: &[u8]) -> Option<NetlinkEvent> {
+        if body.len() < IFADDRMSG_LEN {
+            return None;
+        }
+        let family = body[0];
+        let prefix = body[1];
+        let index = read_u32(body, 4);
+        let (mut local, mut address) = (None, None);
+        attributes(&body[IFADDRMSG_LEN..], |typ, value| {
+            let addr = match (family, value.len()) {
+                (AF_INET, 4) => IpAddr::V4(Ipv4Addr::new(
+                    value[0], value[1], value[2], value[3])),
+                (AF_INET6, 16) => {
+                    let mut octets = [0u8; 16];
+                    octets.copy_from_slice(value);
+                    IpAddr::V6(Ipv6Addr::from(octets))
+                }
+                _ => return,
+            };
+            match typ {
+                IFA_LOCAL => local = Some(addr),
+                IFA_ADDRESS => address = Some(addr),
+                _ => {}
+            }
+        });
+        // On point-to-point links IFA_ADDRESS is the address of the peer
+        let addr = match local.or(address) {
+            Some(addr) => addr,
+            None => return None,
+        };
+        if kind == RTM_NEWADDR {
+            Some(NetlinkEvent::Address {
+                index: index, addr: addr, prefix: prefix })
+        } else {
+            Some(NetlinkEvent::AddressRemoved {
+                index: index, addr: addr, prefix: prefix })
+        }
+    }
+
+    /// Parses the messages of a single datagram of the route family
+    pub fn parse_route(mut buf: &[u8], events: &mut Vec<NetlinkEvent>) {
+        while buf.len() >= NLMSG_HDRLEN {
+            let len = read_u32(buf, 0) as usize;
+            if len < NLMSG_HDRLEN || len > buf.len() {
+                return;
+            }
+            let kind = read_u16(buf, 4);
+            let body = &buf[NLMSG_HDRLEN..len];
+            let event = match kind {
+                RTM_NEWLINK | RTM_DELLINK => parse_link(kind, body),
+                RTM_NEWADDR | RTM_DELADDR => parse_address(kind, body),
+                NLMSG_OVERRUN => Some(NetlinkEvent::Overflow),
+                // Errors only come in reply to requests, which aren't sent
+                _ => None,
+            };
+            events.extend(event);
+            buf = &buf[align(len).min(buf.len())..];
+        }
+    }
+
+    /// Parses the datagram of the uevent family
+    ///
+    /// Kernel messages are `action@devpath` followed by the `KEY=value`
+    /// variables, separated by zero bytes. The messages of udev itself
+    /// (starting with `libudev`) are skipped.
+    pub fn parse_uevent(buf: &[u8]) -> Option<NetlinkEvent> {
+        let mut parts = buf.split(|&x| x == 0)
+            .filter(|x| !x.is_empty())
+            .map(|x| String::from_utf8_lossy(x).into_owned());
+        let header = match parts.next() {
+            Some(header) => header,
+            None => return None,
+        };
+        let at = match header.find('@') {
+            Some(at) => at,
+            None => return None,
+        };
+        let vars = parts.filter_map(|var| {
+            var.find('=').map(|eq| {
+                (var[..eq].to_string(), var[eq+1..].to_string())
+            })
+        }).collect();
+        Some(NetlinkEvent::Device {
+            action: header[..at].to_string(),
+            devpath: header[at+1..].to_string(),
+            vars: vars,
+        })
+    }
+}
+
+#[cfg(all(feature="netlink", target_os="linux"))]
+mod sys {
+    use std::io::{self, Read};
+    use std::fs::File;
+    use std::mem;
+    use std::os::unix::io::{AsRawFd, FromRawFd};
+
+    use libc;
+    use mio::unix::EventedFd;
+
+    use {GenericScope, EventSet, PollOpt};
+    use super::NetlinkEvent;
+    use super::parse::{parse_route, parse_uevent};
+
+    const NETLINK_ROUTE: libc::c_int = 0;
+    const NETLINK_KOBJECT_UEVENT: libc::c_int = 15;
+    const RTMGRP_LINK: u32 = 0x1;
+    const RTMGRP_IPV4_IFADDR: u32 = 0x10;
+    const RTMGRP_IPV6_IFADDR: u32 = 0x100;
+    /// Events sent by the kernel, rather than by udev
+    const UEVENT_KERNEL: u32 = 0x1;
+
+    pub struct Socket {
+        file: File,
+        uevent: bool,
+    }
+
+    impl Socket {
+        fn open(protocol: libc::c_int, groups: u32) -> io::Result<File> {
+            let fd = unsafe {
+                libc::socket(libc::AF_NETLINK,
+                    libc::SOCK_RAW | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC,
+                    protocol)
+            };
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            // Owns the descriptor, so it's closed on errors
+            let file = unsafe { File::from_raw_fd(fd) };
+            let mut addr: libc::sockaddr_nl = unsafe { mem::zeroed() };
+            addr.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+            addr.nl_groups = groups;
+            let res = unsafe {
+                libc::bind(fd, &addr as *const _ as *const libc::sockaddr,
+                    mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t)
+            };
+            if res < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(file)
+        }
+        pub fn route() -> io::Result<Socket> {
+            let file = try!(Socket::open(NETLINK_ROUTE,
+                RTMGRP_LINK | RTMGRP_IPV4_IFADDR | RTMGRP_IPV6_IFADDR));
+            Ok(Socket { file: file, uevent: false })
+        }
+        pub fn uevent() -> io::Result<Socket> {
+            let file = try!(Socket::open(NETLINK_KOBJECT_UEVENT,
+                                         UEVENT_KERNEL));
+            Ok(Socket { file: file, uevent: true })
+        }
+        pub fn register<S: GenericScope>(&self, scope: &mut S)
+            -> io::Result<()>
+        {
+            scope.register(&EventedFd(&self.file.as_raw_fd()),
+                           EventSet::readable(), PollOpt::level())
+        }
+        pub fn read(&mut self, events: &mut Vec<NetlinkEvent>)
+            -> io::Result<()>
+        {
+            let mut buf = vec![0u8; 65536];
+            loop {
+                let n = match self.file.read(&mut buf) {
+                    Ok(n) => n,
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                        return Ok(());
+                    }
+                    Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {
+                        continue;
+                    }
+                    Err(ref e) if e.raw_os_error() == Some(libc::ENOBUFS)
+                    => {
+                        events.push(NetlinkEvent::Overflow);
+                        continue;
+                    }
+                    Err(e) => return Err(e),
+                };
+                if self.uevent {
+                    events.extend(parse_uevent(&buf[..n]));
+                } else {
+                    parse_route(&buf[..n], events);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(not(all(feature="netlink", target_os="linux")))]
+mod sys {
+    use std::io;
+
+    use GenericScope;
+    use super::NetlinkEvent;
+
+    pub enum Socket {}
+
+    fn unsupported() -> io::Error {
+        io::Error::new(io::ErrorKind::Other,
+            "netlink requires the `netlink` feature (linux only)")
+    }
+
+    impl Socket {
+        pub fn route() -> io::Result<Socket> {
+            Err(unsupported())
+        }
+        pub fn uevent() -> io::Result<Socket> {
+            Err(unsupported())
+        }
+        pub fn register<S: GenericScope>(&self, _scope: &mut S)
+            -> io::Result<()>
+        {
+            match *self {}
+        }
+        pub fn read(&mut self, _events: &mut Vec<NetlinkEvent>)
+            -> io::Result<()>
+        {
+            match *self {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::ptr;
+    use std::net::{IpAddr, Ipv4Addr};
+    use super::NetlinkEvent;
+    use super::parse::{parse_route, parse_uevent};
+
+    fn u16b(x: u16) -> [u8; 2] {
+        let mut buf = [0u8; 2];
+        unsafe { ptr::write_unaligned(buf.as_mut_ptr() as *mut u16, x) };
+        buf
+    }
+
+    fn u32b(x: u32) -> [u8; 4] {
+        let mut buf = [0u8; 4];
+        unsafe { ptr::write_unaligned(buf.as_mut_ptr() as *mut u32, x) };
+        buf
+    }
+
+    #[test]
+    fn new_address() {
+        let mut msg = Vec::new();
+        msg.extend(&u32b(16 + 8 + 8));
+        msg.extend(&u16b(20));  // RTM_NEWADDR
+        msg.extend(&[0; 10]);
+        msg.extend(&[2, 24, 0, 0]);  // AF_INET, /24
+        msg.extend(&u32b(3));
+        msg.extend(&u16b(8));
+        msg.extend(&u16b(2));  // IFA_LOCAL
+        msg.extend(&[10, 0, 0, 7]);
+        let mut events = Vec::new();
+        parse_route(&msg, &mut events);
+        assert_eq!(events, vec![NetlinkEvent::Address {
+            index: 3,
+            addr: IpAddr::V4(Ipv4Addr::new(10, 0, 0, 7)),
+            prefix: 24,
+        }]);
+        // Truncated message is skipped
+        parse_route(&msg[..20], &mut events);
+        assert_eq!(events.len(), 1);
+    }
+
+    #[test]
+    fn uevent() {
+        let msg = b"add@/devices/usb1/1-1\0ACTION=add\0SUBSYSTEM=usb\0";
+        assert_eq!(parse_uevent(msg), Some(NetlinkEvent::Device {
+            action: "add".to_string(),
+            devpath: "/devices/usb1/1-1".to_string(),
+            vars: vec![
+                ("ACTION".to_string(), "add".to_string()),
+                ("SUBSYSTEM".to_string(), "usb".to_string()),
+            ],
+        }));
+        assert_eq!(parse_uevent(b"libudev\0"), None);
+    }
+}
@@ -1,4 +1,14 @@
+use std::fmt;
+use std::mem;
+use std::thread;
+use std::time::{Duration, Instant};
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
 use mio::Token;
+use mio::channel::SyncSender;
 use mio::deprecated::Sender;
 
 use handler::{Notify};
@@ -26,37 +36,393 @@ quick_error! {
         Closed {
             description("Notification queue is close")
         }
+        /// The state machine is removed, the notifier may be dropped
+        Dead {
+            description("The state machine is gone")
+        }
     }
 }
 
+/// Set in the state shared by the notifiers while the wakeup is queued
+pub const WAKEUP_PENDING: usize = 1;
+/// Set in the state shared by the notifiers when the machine is removed
+pub const MACHINE_DEAD: usize = 2;
+
+/// Pause between the attempts of `FullPolicy::Block`
+const BLOCK_PAUSE_US: u64 = 100;
+
+/// Identifies the loop in `LOCAL_WAKEUPS`
+static NEXT_LOOP_ID: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    /// Wakeups sent by the thread to its own loops, by the id of the loop
+    static LOCAL_WAKEUPS: RefCell<HashMap<usize, Vec<(Token, u64)>>> =
+        RefCell::new(HashMap::new());
+    /// The loop and the state machine running the action, for the misuse
+    /// checks of the debug builds
+    static DISPATCHING: Cell<Option<(usize, Token)>> = Cell::new(None);
+}
+
+
+/// What `Notifier::wakeup` does when the notification queue is full
+///
+/// The queue is usually full for a moment only, when a burst of wakeups
+/// from other threads outpaces the loop. Either way, the wakeups which are
+/// finally dropped are counted, see `Scope::dropped_wakeups`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FullPolicy {
+    /// Return `WakeupError::Full` right away, the default
+    Fail,
+    /// Retry up to the number of times, yielding the thread in between
+    Spin(u32),
+    /// Retry until the time passes, sleeping a little in between
+    ///
+    /// Blocks the calling thread, so don't use it in other loops.
+    Block(Duration),
+}
 
 /// The object used to wakeup unrelated state machine
 ///
-/// You may use a notifiers between multiple threads
+/// You may use a notifiers between multiple threads. Wakeups sent before
+/// the state machine receives the previous one are coalesced, i.e. the
+/// state machine receives a single `wakeup()` however many times the
+/// notifier is used in the meantime.
 #[derive(Clone, Debug)]
 pub struct Notifier {
     token: Token,
-    channel: Sender<Notify>,
+    generation: u64,
+    /// `WAKEUP_PENDING` and `MACHINE_DEAD` flags, absent for the notifiers
+    /// created by the loop itself
+    state: Option<Arc<AtomicUsize>>,
+    channel: Channel,
+    policy: FullPolicy,
+}
+
+/// A handle to stop the loop from other threads
+///
+/// Created by `Loop::shutdown_handle()` or `LoopInstance::shutdown_handle()`
+#[derive(Clone, Debug)]
+pub struct ShutdownHandle {
+    channel: Channel,
+}
+
+/// The channel delivering notifications to the loop
+///
+/// It's the channel of the mio loop, unless the loop is `Embedded` into
+/// the mio loop of other application. Wakeups sent from the thread of
+/// the loop bypass it, see `LocalWakeups`.
+#[doc(hidden)]
+#[derive(Clone)]
+pub struct Channel {
+    sender: Sender_,
+    loop_id: usize,
+    /// Messages dropped because the queue is full
+    dropped: Arc<AtomicUsize>,
+}
+
+#[derive(Clone)]
+enum Sender_ {
+    Loop(Sender<Notify>),
+    Embedded(SyncSender<Notify>),
+}
+
+enum SendError {
+    Full(Notify),
+    Failed(WakeupError),
+}
+
+/// The queue of the wakeups sent from the thread of the loop
+///
+/// Such wakeups are delivered before the next poll without going through
+/// the channel. The queue is registered in the thread which creates the
+/// loop, and is removed when it's dropped.
+#[doc(hidden)]
+pub struct LocalWakeups {
+    loop_id: usize,
 }
 
-pub fn create_notifier(token: Token, channel: &Sender<Notify>) -> Notifier {
+impl LocalWakeups {
+    pub fn new(channel: &Channel) -> LocalWakeups {
+        LOCAL_WAKEUPS.with(|w| {
+            w.borrow_mut().insert(channel.loop_id, Vec::new());
+        });
+        LocalWakeups { loop_id: channel.loop_id }
+    }
+    pub fn is_empty(&self) -> bool {
+        LOCAL_WAKEUPS.with(|w| {
+            w.borrow().get(&self.loop_id).map(|q| q.is_empty())
+                .unwrap_or(true)
+        })
+    }
+    /// Marks the state machine running the action, returns the previous one
+    #[cfg(debug_assertions)]
+    pub fn dispatch(&self, token: Option<Token>) -> Option<Token> {
+        let loop_id = self.loop_id;
+        DISPATCHING.try_with(|d| {
+            let old = d.get();
+            d.set(token.map(|t| (loop_id, t)));
+            match old {
+                Some((id, t)) if id == loop_id => Some(t),
+                _ => None,
+            }
+        }).unwrap_or(None)
+    }
+    #[cfg(not(debug_assertions))]
+    pub fn dispatch(&self, _token: Option<Token>) -> Option<Token> {
+        None
+    }
+    /// Returns the queued wakeups in the order they are sent
+    pub fn take(&self) -> Vec<(Token, u64)> {
+        LOCAL_WAKEUPS.with(|w| {
+            w.borrow_mut().get_mut(&self.loop_id)
+                .map(|q| mem::replace(q, Vec::new()))
+                .unwrap_or_else(Vec::new)
+        })
+    }
+}
+
+impl Drop for LocalWakeups {
+    fn drop(&mut self) {
+        // The thread local may be destroyed already when the loop is
+        // dropped at the thread exit
+        LOCAL_WAKEUPS.try_with(|w| {
+            w.borrow_mut().remove(&self.loop_id);
+        }).ok();
+    }
+}
+
+impl Channel {
+    pub fn new_loop(sender: Sender<Notify>) -> Channel {
+        Channel {
+            sender: Sender_::Loop(sender),
+            loop_id: NEXT_LOOP_ID.fetch_add(1, Ordering::Relaxed),
+            dropped: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+    pub fn new_embedded(sender: SyncSender<Notify>) -> Channel {
+        Channel {
+            sender: Sender_::Embedded(sender),
+            loop_id: NEXT_LOOP_ID.fetch_add(1, Ordering::Relaxed),
+            dropped: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+    /// The number of the loop in the process, the default `Scope::loop_id`
+    pub fn loop_id(&self) -> usize {
+        self.loop_id
+    }
+    /// Queues the wakeup if called in the thread of the loop
+    ///
+    /// Returns false if the loop is in another thread (or is gone).
+    fn send_local(&self, token: Token, generation: u64) -> bool {
+        LOCAL_WAKEUPS.try_with(|w| {
+            match w.borrow_mut().get_mut(&self.loop_id) {
+                Some(queue) => {
+                    queue.push((token, generation));
+                    true
+                }
+                None => false,
+            }
+        }).unwrap_or(false)
+    }
+    /// Number of messages dropped because the queue was full
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed) as u64
+    }
+    pub fn send(&self, msg: Notify) -> Result<(), WakeupError> {
+        self.send_with(msg, FullPolicy::Fail)
+    }
+    /// Send the message retrying according to the `policy`
+    pub fn send_with(&self, msg: Notify, policy: FullPolicy)
+        -> Result<(), WakeupError>
+    {
+        let mut msg = msg;
+        let mut attempts = 0;
+        let mut deadline = None;
+        loop {
+            msg = match self.try_send(msg) {
+                Ok(()) => return Ok(()),
+                Err(SendError::Full(msg)) => msg,
+                Err(SendError::Failed(e)) => return Err(e),
+            };
+            let retry = match policy {
+                FullPolicy::Fail => false,
+                FullPolicy::Spin(max) => {
+                    attempts += 1;
+                    attempts <= max
+                }
+                FullPolicy::Block(timeout) => {
+                    let now = Instant::now();
+                    now < *deadline.get_or_insert(now + timeout)
+                }
+            };
+            if !retry {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+                return Err(WakeupError::Full);
+            }
+            match policy {
+                FullPolicy::Block(..) => {
+                    thread::sleep(Duration::from_micros(BLOCK_PAUSE_US));
+                }
+                _ => thread::yield_now(),
+            }
+        }
+    }
+    fn try_send(&self, msg: Notify) -> Result<(), SendError> {
+        match self.sender {
+            Sender_::Loop(ref channel) => {
+                use mio::deprecated::NotifyError::*;
+                match channel.send(msg) {
+                    Ok(()) => Ok(()),
+                    Err(Closed(_)) => {
+                        Err(SendError::Failed(WakeupError::Closed))
+                    }
+                    Err(Io(_)) => Err(SendError::Failed(WakeupError::Io)),
+                    Err(Full(msg)) => Err(SendError::Full(msg)),
+                }
+            }
+            Sender_::Embedded(ref channel) => {
+                use mio::channel::TrySendError::*;
+                match channel.try_send(msg) {
+                    Ok(()) => Ok(()),
+                    Err(Disconnected(_)) => {
+                        Err(SendError::Failed(WakeupError::Closed))
+                    }
+                    Err(Io(_)) => Err(SendError::Failed(WakeupError::Io)),
+                    Err(Full(msg)) => Err(SendError::Full(msg)),
+                }
+            }
+        }
+    }
+}
+
+impl fmt::Debug for Channel {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.sender {
+            Sender_::Loop(..) => write!(f, "Channel::Loop"),
+            Sender_::Embedded(..) => write!(f, "Channel::Embedded"),
+        }
+    }
+}
+
+pub fn create_shutdown_handle(channel: &Channel) -> ShutdownHandle {
+    ShutdownHandle { channel: channel.clone() }
+}
+
+pub fn create_notifier(token: Token, generation: u64,
+    state: Option<&Arc<AtomicUsize>>, channel: &Channel)
+    -> Notifier
+{
     Notifier {
         token: token,
-        channel: channel.clone()
+        generation: generation,
+        state: state.cloned(),
+        channel: channel.clone(),
+        policy: FullPolicy::Fail,
     }
 }
 
+/// Token and generation of the state machine woken by the notifier
+#[doc(hidden)]
+pub fn notifier_target(notifier: &Notifier) -> (Token, u64) {
+    (notifier.token, notifier.generation)
+}
+
 impl Notifier {
+    /// Token of the state machine woken by the notifier
+    pub fn token(&self) -> Token {
+        self.token
+    }
+    /// Set what `wakeup` does when the notification queue is full
+    pub fn full_policy(mut self, policy: FullPolicy) -> Notifier {
+        self.policy = policy;
+        self
+    }
     /// Wakeup a state machine
     ///
+    /// Returns `Ok` without sending anything if the previous wakeup is not
+    /// delivered yet, and `WakeupError::Dead` if the state machine is
+    /// known to be removed.
     ///
+    /// When called in the thread of the loop (e.g. by other state machine)
+    /// the wakeup is queued in the loop itself, and is delivered before
+    /// the next poll.
     pub fn wakeup(&self) -> Result<(), WakeupError> {
-        use mio::deprecated::NotifyError::*;
-        match self.channel.send(Notify::Fsm(self.token)) {
-            Ok(()) => Ok(()),
-            Err(Closed(_)) => Err(WakeupError::Closed),
-            Err(Io(_)) => Err(WakeupError::Io),
-            Err(Full(_)) => Err(WakeupError::Full),
+        #[cfg(debug_assertions)]
+        self.check_self_wakeup();
+        if let Some(ref state) = self.state {
+            let old = state.fetch_or(WAKEUP_PENDING, Ordering::AcqRel);
+            if old & MACHINE_DEAD != 0 {
+                return Err(WakeupError::Dead);
+            }
+            if old & WAKEUP_PENDING != 0 {
+                return Ok(());
+            }
+        }
+        if self.channel.send_local(self.token, self.generation) {
+            return Ok(());
+        }
+        let msg = Notify::Fsm(self.token, self.generation);
+        let res = self.channel.send_with(msg, self.policy);
+        if res.is_err() {
+            if let Some(ref state) = self.state {
+                // Nothing is queued, so the next wakeup must be sent
+                state.fetch_and(!WAKEUP_PENDING, Ordering::AcqRel);
+            }
         }
+        res
+    }
+    /// Logs the state machine waking itself up with the notifier
+    ///
+    /// The wakeup isn't coalesced with the ones of `Scope::wakeup_self`
+    /// and may fail, so it's usually a mistake. But it's fine for the
+    /// promise answered by its owner or the future notifying its own
+    /// task, so it's only logged on the debug level.
+    #[cfg(debug_assertions)]
+    fn check_self_wakeup(&self) {
+        let target = (self.channel.loop_id, self.token);
+        let own = DISPATCHING.try_with(|d| d.get() == Some(target))
+            .unwrap_or(false);
+        if own {
+            debug!("State machine {:?} wakes itself up with the notifier, \
+                use `Scope::wakeup_self` or `Scope::run_again` instead",
+                self.token);
+        }
+    }
+    /// Returns false if the state machine is known to be removed
+    ///
+    /// This is a best-effort check: the state machine may be removed right
+    /// after it returns true, and the notifiers created before the state
+    /// machine is added to the loop always return true. Producers may use
+    /// it to drop the notifiers of the finished state machines.
+    pub fn is_alive(&self) -> bool {
+        self.state.as_ref()
+            .map(|s| s.load(Ordering::Acquire) & MACHINE_DEAD == 0)
+            .unwrap_or(true)
+    }
+    /// Remove the state machine
+    ///
+    /// The state machine is removed on the next loop iteration, and its
+    /// `destroyed` method is called. Nothing happens if the state machine
+    /// is already gone. Useful to terminate a connection from other state
+    /// machine or thread, e.g. when the peer is found abusive.
+    pub fn abort(&self) -> Result<(), WakeupError> {
+        self.channel.send(Notify::Abort(self.token, self.generation))
+    }
+}
+
+impl ShutdownHandle {
+    /// Stop the loop as soon as possible
+    ///
+    /// State machines are dropped without any notification.
+    pub fn shutdown(&self) -> Result<(), WakeupError> {
+        self.channel.send(Notify::Shutdown)
+    }
+    /// Ask all state machines to finish and stop the loop when they do
+    ///
+    /// Every state machine receives a `wakeup` and sees
+    /// `Scope::is_shutting_down()` returning true. The loop stops when the
+    /// last state machine is removed.
+    pub fn graceful_shutdown(&self) -> Result<(), WakeupError> {
+        self.channel.send(Notify::GracefulShutdown)
     }
 }
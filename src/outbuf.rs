@@ -0,0 +1,201 @@
+use std::io::{self, Write, IoSlice};
+use std::collections::VecDeque;
+
+use {Scope, EventSet, PollOpt, Evented};
+
+
+/// Maximum number of segments written with a single call
+const MAX_SEGMENTS: usize = 64;
+
+/// Writes the segments followed by the `tail` with a single
+/// `write_vectored` call
+///
+/// Returns the number of bytes written, which are not removed yet.
+pub fn write_segments<W>(sock: &mut W, segments: &VecDeque<Vec<u8>>,
+    tail: &[u8])
+    -> io::Result<usize>
+    where W: Write + ?Sized
+{
+    let slices = segments.iter().map(|x| &x[..]).chain(Some(tail))
+        .filter(|x| !x.is_empty())
+        .take(MAX_SEGMENTS)
+        .map(IoSlice::new)
+        .collect::<Vec<_>>();
+    sock.write_vectored(&slices)
+}
+
+/// Removes `bytes` from the start of the segments
+///
+/// Returns the number of bytes left when all the segments are removed,
+/// i.e. the part of the tail written by `write_segments`.
+pub fn consume(segments: &mut VecDeque<Vec<u8>>, mut bytes: usize)
+    -> usize
+{
+    while bytes > 0 {
+        let front = match segments.front_mut() {
+            Some(front) => front,
+            None => return bytes,
+        };
+        if front.len() > bytes {
+            front.drain(..bytes);
+            return 0;
+        }
+        bytes -= front.len();
+        segments.pop_front();
+    }
+    0
+}
+
+/// An output buffer of the socket handling the backpressure
+///
+/// Push the data at any time and call `flush` from every action of the
+/// state machine (usually `ready`). It writes as much as the socket
+/// accepts, keeps the rest, and reregisters the socket: with `writable`
+/// added while there is something to write, and with the base interest
+/// only when the buffer is drained. The socket is registered in level
+/// triggered mode.
+///
+/// To be notified when everything is written, check the result of `flush`
+/// or call `wake_on_flush` to get a `wakeup` when the buffer is drained.
+///
+/// Data is kept in segments, and `push_segment` adds the buffer without
+/// copying (e.g. the body after the headers). Segments are written with
+/// `Write::write_vectored`, which is a single `writev` for the sockets
+/// supporting it.
+#[derive(Debug)]
+pub struct OutBuf {
+    segments: VecDeque<Vec<u8>>,
+    len: usize,
+    /// Interest that doesn't depend on the buffer, e.g. readable
+    base: EventSet,
+    /// The interest the socket is registered with by the last `flush`
+    interest: Option<EventSet>,
+    wake_on_flush: bool,
+}
+
+impl OutBuf {
+    /// Create a buffer for the socket registered with the `base` interest
+    ///
+    /// The socket must be registered with the token of the state machine
+    /// already, `flush` only reregisters it.
+    pub fn new(base: EventSet) -> OutBuf {
+        OutBuf {
+            segments: VecDeque::new(),
+            len: 0,
+            base: base,
+            interest: Some(base),
+            wake_on_flush: false,
+        }
+    }
+    /// Change the interest which doesn't depend on the buffer
+    ///
+    /// Applied on the next `flush`.
+    pub fn set_base(&mut self, base: EventSet) {
+        self.base = base;
+    }
+    /// Append the data to the buffer
+    pub fn push(&mut self, data: &[u8]) {
+        if data.is_empty() {
+            return;
+        }
+        match self.segments.back_mut() {
+            Some(last) => last.extend_from_slice(data),
+            None => self.segments.push_back(data.to_vec()),
+        }
+        self.len += data.len();
+    }
+    /// Append the buffer as a separate segment, without copying
+    ///
+    /// The data pushed later is appended to this segment.
+    pub fn push_segment(&mut self, data: Vec<u8>) {
+        if data.is_empty() {
+            return;
+        }
+        self.len += data.len();
+        self.segments.push_back(data);
+    }
+    /// Number of bytes not written to the socket yet
+    pub fn len(&self) -> usize {
+        self.len
+    }
+    /// Returns true if everything is written to the socket
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+    /// Wake up the state machine when the buffer is drained by `flush`
+    ///
+    /// The wakeup is sent once, call it again for the next one. If the
+    /// buffer is empty already, the wakeup is sent by the next `flush`.
+    pub fn wake_on_flush(&mut self) {
+        self.wake_on_flush = true;
+    }
+    /// Write as much as the socket accepts and update the interest
+    ///
+    /// Returns true if the buffer is drained.
+    pub fn flush<S, C>(&mut self, sock: &mut S, scope: &mut Scope<C>)
+        -> io::Result<bool>
+        where S: Write + Evented
+    {
+        while !self.segments.is_empty() {
+            match write_segments(sock, &self.segments, &[]) {
+                Ok(0) => {
+                    return Err(io::Error::new(io::ErrorKind::WriteZero,
+                        "can't write to the socket"));
+                }
+                Ok(bytes) => {
+                    let rest = consume(&mut self.segments, bytes);
+                    debug_assert_eq!(rest, 0);
+                    self.len -= bytes;
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        let interest = if self.segments.is_empty() {
+            self.base
+        } else {
+            self.base | EventSet::writable()
+        };
+        if self.interest != Some(interest) {
+            // Forget the interest on error, so it's retried the next time
+            self.interest = None;
+            try!(scope.reregister(sock, interest, PollOpt::level()));
+            self.interest = Some(interest);
+        }
+        if self.segments.is_empty() && self.wake_on_flush {
+            self.wake_on_flush = false;
+            scope.wakeup_self();
+        }
+        Ok(self.segments.is_empty())
+    }
+}
+
+impl Write for OutBuf {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.push(buf);
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::VecDeque;
+    use super::consume;
+
+    #[test]
+    fn consume_segments() {
+        let mut segments = VecDeque::new();
+        segments.push_back(b"head".to_vec());
+        segments.push_back(b"body".to_vec());
+        assert_eq!(consume(&mut segments, 2), 0);
+        assert_eq!(segments, [b"ad".to_vec(), b"body".to_vec()]);
+        assert_eq!(consume(&mut segments, 3), 0);
+        assert_eq!(segments, [b"ody".to_vec()]);
+        assert_eq!(consume(&mut segments, 5), 2);
+        assert!(segments.is_empty());
+    }
+}
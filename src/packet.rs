@@ -0,0 +1,506 @@
+use std::slice;
+
+use void::{Void, unreachable};
+
+use {Machine, Scope, GenericScope, Response, EventSet};
+
+
+/// Frames read by the `RawCapture` (up to the `batch`) in one ready event
+///
+/// All the frames are kept in a single buffer, which is reused for the
+/// next batch, so no allocations are made in the steady state.
+#[derive(Debug)]
+pub struct Frames {
+    buf: Vec<u8>,
+    /// Offset and length of each frame in the buffer
+    frames: Vec<(usize, usize)>,
+}
+
+/// Iterator over the `Frames`
+pub struct FramesIter<'a> {
+    buf: &'a [u8],
+    frames: slice::Iter<'a, (usize, usize)>,
+}
+
+/// Callbacks of the `RawCapture`
+pub trait CaptureHandler: Sized {
+    type Context;
+    /// A batch of frames is received
+    fn received(self, frames: &Frames, scope: &mut Scope<Self::Context>)
+        -> Self;
+    /// The state machine is woken up
+    fn wakeup(self, _scope: &mut Scope<Self::Context>) -> Self {
+        self
+    }
+}
+
+/// A state machine receiving the raw link-layer frames
+///
+/// Uses an `AF_PACKET` socket on linux and a BPF device on BSD and macOS.
+/// Frames include the link-layer (e.g. Ethernet) header. On every ready
+/// event the descriptor is read until it's drained or `batch` frames are
+/// received, and the whole batch is passed to the handler at once. The
+/// rest is read on the next loop iteration, so a busy interface can't
+/// starve the other state machines.
+///
+/// The interface is not switched to promiscuous mode. Opening the
+/// capture requires `CAP_NET_RAW` on linux and access to `/dev/bpf*`
+/// on BSD.
+///
+/// Requires the `packet` feature, on other platforms (or without the
+/// feature) `RawCapture::new` returns an error.
+pub struct RawCapture<H> {
+    sys: sys::Capture,
+    frames: Frames,
+    batch: usize,
+    handler: H,
+}
+
+impl Frames {
+    fn new() -> Frames {
+        Frames {
+            buf: Vec::new(),
+            frames: Vec::new(),
+        }
+    }
+    fn clear(&mut self) {
+        self.buf.clear();
+        self.frames.clear();
+    }
+    // Each platform uses either `push` or `read_with`
+    #[allow(dead_code)]
+    fn push(&mut self, frame: &[u8]) {
+        self.frames.push((self.buf.len(), frame.len()));
+        self.buf.extend_from_slice(frame);
+    }
+    /// Reads the frame with `f` right into the buffer
+    #[allow(dead_code)]
+    fn read_with<F>(&mut self, max_len: usize, f: F)
+        -> ::std::io::Result<usize>
+        where F: FnOnce(&mut [u8]) -> ::std::io::Result<usize>
+    {
+        let start = self.buf.len();
+        self.buf.resize(start + max_len, 0);
+        match f(&mut self.buf[start..]) {
+            Ok(n) => {
+                self.buf.truncate(start + n);
+                self.frames.push((start, n));
+                Ok(n)
+            }
+            Err(e) => {
+                self.buf.truncate(start);
+                Err(e)
+            }
+        }
+    }
+    pub fn len(&self) -> usize {
+        self.frames.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+    pub fn get(&self, index: usize) -> Option<&[u8]> {
+        self.frames.get(index).map(|&(off, len)| &self.buf[off..off+len])
+    }
+    pub fn iter(&self) -> FramesIter {
+        FramesIter {
+            buf: &self.buf,
+            frames: self.frames.iter(),
+        }
+    }
+}
+
+impl<'a> Iterator for FramesIter<'a> {
+    type Item = &'a [u8];
+    fn next(&mut self) -> Option<&'a [u8]> {
+        let buf = self.buf;
+        self.frames.next().map(|&(off, len)| &buf[off..off+len])
+    }
+}
+
+impl<'a> IntoIterator for &'a Frames {
+    type Item = &'a [u8];
+    type IntoIter = FramesIter<'a>;
+    fn into_iter(self) -> FramesIter<'a> {
+        self.iter()
+    }
+}
+
+impl<H: CaptureHandler> RawCapture<H> {
+    /// Start capturing the frames of the interface, e.g. `eth0`
+    ///
+    /// At most `batch` frames are passed to the handler at once. On
+    /// linux the `interface` may be `None` to capture on all of them.
+    pub fn new<S: GenericScope>(interface: Option<&str>, batch: usize,
+        handler: H, scope: &mut S)
+        -> Response<RawCapture<H>, Void>
+    {
+        let res = sys::Capture::new(interface).and_then(|sys| {
+            try!(sys.register(scope));
+            Ok(sys)
+        });
+        match res {
+            Ok(sys) => {
+                Response::ok(RawCapture {
+                    sys: sys,
+                    frames: Frames::new(),
+                    batch: batch,
+                    handler: handler,
+                })
+            }
+            Err(e) => Response::error(Box::new(e)),
+        }
+    }
+}
+
+impl<H: CaptureHandler> Machine for RawCapture<H> {
+    type Context = H::Context;
+    type Seed = Void;
+
+    fn create(seed: Void, _scope: &mut Scope<H::Context>)
+        -> Response<Self, Void>
+    {
+        unreachable(seed)
+    }
+    fn ready(self, _events: EventSet, scope: &mut Scope<H::Context>)
+        -> Response<Self, Void>
+    {
+        let RawCapture { mut sys, mut frames, batch, handler } = self;
+        frames.clear();
+        if let Err(e) = sys.read(&mut frames, batch) {
+            return Response::error(Box::new(e));
+        }
+        let handler = if frames.is_empty() {
+            handler
+        } else {
+            handler.received(&frames, scope)
+        };
+        Response::ok(RawCapture {
+            sys: sys,
+            frames: frames,
+            batch: batch,
+            handler: handler,
+        })
+    }
+    fn spawned(self, _scope: &mut Scope<H::Context>) -> Response<Self, Void>
+    {
+        unreachable!();
+    }
+    fn timeout(self, _scope: &mut Scope<H::Context>) -> Response<Self, Void>
+    {
+        Response::ok(self)
+    }
+    fn wakeup(self, scope: &mut Scope<H::Context>) -> Response<Self, Void> {
+        let RawCapture { sys, frames, batch, handler } = self;
+        Response::ok(RawCapture {
+            sys: sys,
+            frames: frames,
+            batch: batch,
+            handler: handler.wakeup(scope),
+        })
+    }
+}
+
+/// Splits the buffer read from the BPF device into the frames
+///
+/// Each frame is preceded by the `bpf_hdr`: the timestamp of
+/// `tstamp_len` bytes, the captured and the original length (`u32`), and
+/// the length of the header itself (`u16`). Records are aligned to
+/// `align` bytes.
+#[cfg(any(test, all(feature="packet", any(target_os="macos",
+    target_os="ios", target_os="freebsd", target_os="dragonfly",
+    target_os="openbsd", target_os="netbsd"))))]
+fn parse_bpf(mut buf: &[u8], tstamp_len: usize, align: usize,
+    frames: &mut Frames)
+{
+    use std::ptr;
+    let header = tstamp_len + 10;
+    while buf.len() >= header {
+        let (caplen, hdrlen) = unsafe {
+            (ptr::read_unaligned(buf[tstamp_len..].as_ptr() as *const u32),
+             ptr::read_unaligned(buf[tstamp_len+8..].as_ptr() as *const u16))
+        };
+        let end = hdrlen as usize + caplen as usize;
+        if (hdrlen as usize) < header || end > buf.len() {
+            return;
+        }
+        frames.push(&buf[hdrlen as usize..end]);
+        let next = (end + align - 1) / align * align;
+        buf = &buf[next.min(buf.len())..];
+    }
+}
+
+#[cfg(all(feature="packet", target_os="linux"))]
+mod sys {
+    use std::io;
+    use std::fs::File;
+    use std::mem;
+    use std::ffi::CString;
+    use std::os::unix::io::{AsRawFd, FromRawFd};
+
+    use libc;
+    use mio::unix::EventedFd;
+
+    use {GenericScope, EventSet, PollOpt};
+    use super::Frames;
+
+    const ETH_P_ALL: u16 = 0x0003;
+    /// Enough for any frame, including the ones coalesced by GRO
+    const MAX_FRAME: usize = 65536;
+
+    pub struct Capture {
+        file: File,
+    }
+
+    impl Capture {
+        pub fn new(interface: Option<&str>) -> io::Result<Capture> {
+            let fd = unsafe {
+                libc::socket(libc::AF_PACKET,
+                    libc::SOCK_RAW | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC,
+                    ETH_P_ALL.to_be() as libc::c_int)
+            };
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            // Owns the descriptor, so it's closed on errors
+            let file = unsafe { File::from_raw_fd(fd) };
+            if let Some(name) = interface {
+                let cname = try!(CString::new(name)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput,
+                                                e)));
+                let index = unsafe { libc::if_nametoindex(cname.as_ptr()) };
+                if index == 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                let mut addr: libc::sockaddr_ll = unsafe { mem::zeroed() };
+                addr.sll_family = libc::AF_PACKET as libc::c_ushort;
+                addr.sll_protocol = ETH_P_ALL.to_be();
+                addr.sll_ifindex = index as libc::c_int;
+                let res = unsafe {
+                    libc::bind(fd,
+                        &addr as *const _ as *const libc::sockaddr,
+                        mem::size_of::<libc::sockaddr_ll>()
+                            as libc::socklen_t)
+                };
+                if res < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+            Ok(Capture { file: file })
+        }
+        pub fn register<S: GenericScope>(&self, scope: &mut S)
+            -> io::Result<()>
+        {
+            scope.register(&EventedFd(&self.file.as_raw_fd()),
+                           EventSet::readable(), PollOpt::level())
+        }
+        pub fn read(&mut self, frames: &mut Frames, batch: usize)
+            -> io::Result<()>
+        {
+            let fd = self.file.as_raw_fd();
+            while frames.len() < batch {
+                let res = frames.read_with(MAX_FRAME, |buf| {
+                    let n = unsafe {
+                        libc::recv(fd, buf.as_mut_ptr() as *mut libc::c_void,
+                                   buf.len(), 0)
+                    };
+                    if n < 0 {
+                        Err(io::Error::last_os_error())
+                    } else {
+                        Ok(n as usize)
+                    }
+                });
+                match res {
+                    Ok(_) => {}
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                        return Ok(());
+                    }
+                    Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {
+                        continue;
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+            Ok(())
+        }
+    }
+}
+
+#[cfg(all(feature="packet", any(target_os="macos", target_os="ios",
+    target_os="freebsd", target_os="dragonfly", target_os="openbsd",
+    target_os="netbsd")))]
+mod sys {
+    use std::io::{self, Read};
+    use std::fs::{File, OpenOptions};
+    use std::mem;
+    use std::os::unix::fs::OpenOptionsExt;
+    use std::os::unix::io::AsRawFd;
+
+    use libc;
+    use mio::unix::EventedFd;
+
+    use {GenericScope, EventSet, PollOpt};
+    use super::{Frames, parse_bpf};
+
+    /// `struct ifreq`: the name and the union of the request data
+    #[repr(C)]
+    struct IfReq {
+        name: [u8; 16],
+        data: [u8; 16],
+    }
+
+    #[cfg(any(target_os="macos", target_os="ios"))]
+    const TSTAMP_LEN: usize = 8;  // struct timeval32
+    #[cfg(not(any(target_os="macos", target_os="ios")))]
+    const TSTAMP_LEN: usize = 2 * 8;  // struct timeval on 64-bit
+
+    pub struct Capture {
+        file: File,
+        buf: Vec<u8>,
+    }
+
+    fn open_device() -> io::Result<File> {
+        let mut opts = OpenOptions::new();
+        opts.read(true).custom_flags(libc::O_NONBLOCK);
+        // Cloning device on newer systems, numbered ones on the rest
+        match opts.open("/dev/bpf") {
+            Ok(file) => return Ok(file),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+        let mut last = None;
+        for i in 0..256 {
+            match opts.open(format!("/dev/bpf{}", i)) {
+                Ok(file) => return Ok(file),
+                Err(e) => {
+                    if e.raw_os_error() != Some(libc::EBUSY) {
+                        return Err(e);
+                    }
+                    last = Some(e);
+                }
+            }
+        }
+        Err(last.expect("tried at least one device"))
+    }
+
+    impl Capture {
+        pub fn new(interface: Option<&str>) -> io::Result<Capture> {
+            let name = try!(interface.ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidInput,
+                    "BPF requires the interface name")
+            }));
+            if name.len() >= 16 {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                    "interface name is too long"));
+            }
+            let file = try!(open_device());
+            let fd = file.as_raw_fd();
+            let mut blen: libc::c_uint = 0;
+            let mut req: IfReq = unsafe { mem::zeroed() };
+            req.name[..name.len()].copy_from_slice(name.as_bytes());
+            let immediate: libc::c_uint = 1;
+            unsafe {
+                if libc::ioctl(fd, libc::BIOCSETIF, &req) < 0 ||
+                    libc::ioctl(fd, libc::BIOCIMMEDIATE, &immediate) < 0 ||
+                    libc::ioctl(fd, libc::BIOCGBLEN, &mut blen) < 0
+                {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+            Ok(Capture { file: file, buf: vec![0; blen as usize] })
+        }
+        pub fn register<S: GenericScope>(&self, scope: &mut S)
+            -> io::Result<()>
+        {
+            scope.register(&EventedFd(&self.file.as_raw_fd()),
+                           EventSet::readable(), PollOpt::level())
+        }
+        pub fn read(&mut self, frames: &mut Frames, batch: usize)
+            -> io::Result<()>
+        {
+            // A single read returns the whole buffer of the device, so the
+            // batch may be exceeded by the frames of the last read
+            while frames.len() < batch {
+                let n = match self.file.read(&mut self.buf) {
+                    Ok(n) => n,
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                        return Ok(());
+                    }
+                    Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {
+                        continue;
+                    }
+                    Err(e) => return Err(e),
+                };
+                parse_bpf(&self.buf[..n], TSTAMP_LEN,
+                          mem::size_of::<libc::c_long>(), frames);
+            }
+            Ok(())
+        }
+    }
+}
+
+#[cfg(not(all(feature="packet", any(target_os="linux", target_os="macos",
+    target_os="ios", target_os="freebsd", target_os="dragonfly",
+    target_os="openbsd", target_os="netbsd"))))]
+mod sys {
+    use std::io;
+
+    use GenericScope;
+    use super::Frames;
+
+    pub enum Capture {}
+
+    impl Capture {
+        pub fn new(_interface: Option<&str>) -> io::Result<Capture> {
+            Err(io::Error::new(io::ErrorKind::Other,
+                "raw capture requires the `packet` feature \
+                 (linux, BSD or macOS)"))
+        }
+        pub fn register<S: GenericScope>(&self, _scope: &mut S)
+            -> io::Result<()>
+        {
+            match *self {}
+        }
+        pub fn read(&mut self, _frames: &mut Frames, _batch: usize)
+            -> io::Result<()>
+        {
+            match *self {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::ptr;
+    use super::{Frames, parse_bpf};
+
+    fn record(buf: &mut Vec<u8>, frame: &[u8]) {
+        let mut header = [0u8; 18];
+        unsafe {
+            let caplen = header[8..].as_mut_ptr() as *mut u32;
+            ptr::write_unaligned(caplen, frame.len() as u32);
+            ptr::write_unaligned(caplen.offset(1), frame.len() as u32);
+            ptr::write_unaligned(header[16..].as_mut_ptr() as *mut u16, 18);
+        }
+        buf.extend(&header);
+        buf.extend(frame);
+        while buf.len() % 4 != 0 {
+            buf.push(0);
+        }
+    }
+
+    #[test]
+    fn bpf_records() {
+        let mut buf = Vec::new();
+        record(&mut buf, b"first");
+        record(&mut buf, b"second frame");
+        let mut frames = Frames::new();
+        parse_bpf(&buf, 8, 4, &mut frames);
+        assert_eq!(frames.iter().collect::<Vec<_>>(),
+                   vec![&b"first"[..], &b"second frame"[..]]);
+        // Truncated record is skipped
+        frames.clear();
+        parse_bpf(&buf[..30], 8, 4, &mut frames);
+        assert_eq!(frames.len(), 1);
+    }
+}
@@ -0,0 +1,238 @@
+use std::io;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::error::Error;
+
+use mio::Token;
+use mio::deprecated::unix::UnixStream;
+use void::Void;
+
+use {Machine, Scope, GenericScope, Response, EventSet, PollOpt};
+use {SpawnError, LinkedExit};
+
+
+/// Send the file descriptor over the unix socket
+///
+/// The descriptor is attached to a single byte message, so descriptors
+/// sent in a row are received one by one by the `FdReceiver` on the other
+/// side. The descriptor is duplicated by the kernel, close your copy when
+/// it's no longer needed. Returns `Ok(false)` if the socket buffer is full
+/// and nothing is sent, so wait for the socket to become writable.
+pub fn send_fd<F: AsRawFd>(sock: &mut UnixStream, fd: &F)
+    -> io::Result<bool>
+{
+    match try!(sock.try_write_send_fd(b"f", fd.as_raw_fd())) {
+        Some(_) => Ok(true),
+        None => Ok(false),
+    }
+}
+
+struct Receiving<S> {
+    sock: UnixStream,
+    make_seed: fn(RawFd) -> S,
+}
+
+enum Inner<M: Machine> {
+    Receiver(Receiving<M::Seed>),
+    Child(M),
+}
+
+/// A state machine which receives file descriptors from a unix socket
+/// and spawns a child for each
+///
+/// This is how a master process passes accepted connections (or listening
+/// sockets) to the worker processes running their own loops. Every
+/// received descriptor is turned into the seed of the child state machine
+/// `M` with the `make_seed` function, which owns the descriptor from then
+/// on (usually it's wrapped with `FromRawFd`). Messages without a
+/// descriptor are skipped.
+///
+/// The receiver is done when the other side closes the socket.
+pub struct FdReceiver<M: Machine>(Inner<M>);
+
+impl<M: Machine> FdReceiver<M> {
+    pub fn new<S: GenericScope>(sock: UnixStream,
+        make_seed: fn(RawFd) -> M::Seed, scope: &mut S)
+        -> Response<FdReceiver<M>, Void>
+    {
+        if let Err(e) = scope.register(&sock, EventSet::readable(),
+                                       PollOpt::level())
+        {
+            return Response::error(Box::new(e));
+        }
+        Response::ok(FdReceiver(Inner::Receiver(Receiving {
+            sock: sock,
+            make_seed: make_seed,
+        })))
+    }
+}
+
+fn receive<M: Machine>(mut me: Receiving<M::Seed>)
+    -> Response<FdReceiver<M>, M::Seed>
+{
+    let mut seeds = Vec::new();
+    // A byte at a time, so every descriptor is received separately
+    let mut buf = [0u8; 1];
+    loop {
+        match me.sock.try_read_recv_fd(&mut buf) {
+            Ok(Some((0, _))) => {
+                if seeds.is_empty() {
+                    return Response::done();
+                }
+                // The end of stream is read again on the next event
+                break;
+            }
+            Ok(Some((_, Some(fd)))) => seeds.push((me.make_seed)(fd)),
+            Ok(Some((_, None))) => continue,
+            Ok(None) => break,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => {
+                if seeds.is_empty() {
+                    return Response::error(Box::new(e));
+                }
+                debug!("Error receiving descriptor: {}", e);
+                break;
+            }
+        }
+    }
+    Response::spawn_many(FdReceiver(Inner::Receiver(me)), seeds)
+}
+
+impl<M: Machine> Machine for FdReceiver<M> {
+    type Context = M::Context;
+    type Seed = M::Seed;
+
+    fn create(seed: Self::Seed, scope: &mut Scope<M::Context>)
+        -> Response<Self, Self::Seed>
+    {
+        M::create(seed, scope).wrap(|m| FdReceiver(Inner::Child(m)))
+    }
+    fn ready(self, events: EventSet, scope: &mut Scope<M::Context>)
+        -> Response<Self, Self::Seed>
+    {
+        match self.0 {
+            Inner::Receiver(me) => receive(me),
+            Inner::Child(m) => {
+                m.ready(events, scope).wrap(|m| FdReceiver(Inner::Child(m)))
+            }
+        }
+    }
+    fn spawned(self, scope: &mut Scope<M::Context>)
+        -> Response<Self, Self::Seed>
+    {
+        match self.0 {
+            me @ Inner::Receiver(..) => Response::ok(FdReceiver(me)),
+            Inner::Child(m) => {
+                m.spawned(scope).wrap(|m| FdReceiver(Inner::Child(m)))
+            }
+        }
+    }
+    fn spawn_error(self, scope: &mut Scope<M::Context>,
+                   error: SpawnError<Self::Seed>)
+        -> Response<Self, Self::Seed>
+    {
+        match self.0 {
+            Inner::Receiver(me) => match error {
+                // Queued by the loop until some state machine is removed
+                SpawnError::NoSlabSpace(seed) => {
+                    Response::spawn(FdReceiver(Inner::Receiver(me)), seed)
+                }
+                e => {
+                    debug!("Error creating state machine from descriptor: \
+                            {}", e);
+                    Response::ok(FdReceiver(Inner::Receiver(me)))
+                }
+            },
+            Inner::Child(m) => {
+                m.spawn_error(scope, error)
+                    .wrap(|m| FdReceiver(Inner::Child(m)))
+            }
+        }
+    }
+    fn destroyed(self, scope: &mut Scope<M::Context>) {
+        match self.0 {
+            Inner::Receiver(..) => {}
+            Inner::Child(m) => m.destroyed(scope),
+        }
+    }
+    fn freeze(self, scope: &mut Scope<M::Context>)
+        -> Result<Self::Seed, Self>
+    {
+        match self.0 {
+            me @ Inner::Receiver(..) => Err(FdReceiver(me)),
+            Inner::Child(m) => {
+                m.freeze(scope).map_err(|m| FdReceiver(Inner::Child(m)))
+            }
+        }
+    }
+    fn thaw(seed: Self::Seed, scope: &mut Scope<M::Context>)
+        -> Response<Self, Self::Seed>
+    {
+        M::thaw(seed, scope).wrap(|m| FdReceiver(Inner::Child(m)))
+    }
+    fn timeout(self, scope: &mut Scope<M::Context>)
+        -> Response<Self, Self::Seed>
+    {
+        match self.0 {
+            me @ Inner::Receiver(..) => Response::ok(FdReceiver(me)),
+            Inner::Child(m) => {
+                m.timeout(scope).wrap(|m| FdReceiver(Inner::Child(m)))
+            }
+        }
+    }
+    fn wakeup(self, scope: &mut Scope<M::Context>)
+        -> Response<Self, Self::Seed>
+    {
+        match self.0 {
+            me @ Inner::Receiver(..) => Response::ok(FdReceiver(me)),
+            Inner::Child(m) => {
+                m.wakeup(scope).wrap(|m| FdReceiver(Inner::Child(m)))
+            }
+        }
+    }
+    fn exit_signal(self, peer: Token, scope: &mut Scope<M::Context>)
+        -> Response<Self, Self::Seed>
+    {
+        match self.0 {
+            Inner::Receiver(..) => Response::error(Box::new(LinkedExit(peer))),
+            Inner::Child(m) => {
+                m.exit_signal(peer, scope)
+                    .wrap(|m| FdReceiver(Inner::Child(m)))
+            }
+        }
+    }
+    fn tick(self, scope: &mut Scope<M::Context>)
+        -> Response<Self, Self::Seed>
+    {
+        match self.0 {
+            me @ Inner::Receiver(..) => Response::ok(FdReceiver(me)),
+            Inner::Child(m) => {
+                m.tick(scope).wrap(|m| FdReceiver(Inner::Child(m)))
+            }
+        }
+    }
+    fn config_changed(self, scope: &mut Scope<M::Context>)
+        -> Response<Self, Self::Seed>
+    {
+        match self.0 {
+            me @ Inner::Receiver(..) => Response::ok(FdReceiver(me)),
+            Inner::Child(m) => {
+                m.config_changed(scope)
+                    .wrap(|m| FdReceiver(Inner::Child(m)))
+            }
+        }
+    }
+    fn interest(&self) -> Option<EventSet> {
+        match self.0 {
+            Inner::Receiver(..) => None,
+            Inner::Child(ref m) => m.interest(),
+        }
+    }
+    fn error(self, err: Box<Error>, scope: &mut Scope<M::Context>)
+        -> Box<Error>
+    {
+        match self.0 {
+            Inner::Receiver(..) => err,
+            Inner::Child(m) => m.error(err, scope),
+        }
+    }
+}
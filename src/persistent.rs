@@ -0,0 +1,372 @@
+use std::io;
+use std::net::SocketAddr;
+use std::time::Duration;
+use std::error::Error;
+
+use mio::tcp::TcpStream;
+use void::Void;
+
+use {Machine, Scope, GenericScope, Response, EventSet, PollOpt, Time};
+use {Backoff, SocketOptions};
+use response::call_error_hook;
+
+
+/// Delay before reconnecting after the first failure, doubled on every
+/// subsequent failure
+const BACKOFF_MIN_MS: u64 = 100;
+const BACKOFF_MAX_MS: u64 = 30000;
+const BACKOFF_JITTER: f64 = 0.5;
+
+/// A client state machine which is run by `Persistent`
+///
+/// The state machine is created for every established connection, and
+/// when it's stopped (with `Response::done()` or `Response::error()`)
+/// `Persistent` reconnects.
+pub trait Client: Machine<Seed=Void> {
+    /// Create the state machine for the established connection
+    ///
+    /// The socket is not registered in the loop, register it here just
+    /// like in `Machine::create`.
+    fn connected(sock: TcpStream, scope: &mut Scope<Self::Context>)
+        -> Response<Self, Void>;
+}
+
+struct Retry {
+    addresses: Vec<SocketAddr>,
+    /// Index of the address for the next connection attempt
+    next: usize,
+    connect_timeout: Duration,
+    options: SocketOptions,
+    /// Reset when connection is established
+    backoff: Backoff,
+}
+
+enum State<M> {
+    Connecting(TcpStream, Time),
+    Sleeping(Time),
+    Connected(M),
+}
+
+/// A client connection which is reestablished on failure
+///
+/// Connects to the addresses in turn. When the connection is established
+/// the client state machine `M` is created (see `Client::connected`).
+/// When connection fails, times out, or the client is stopped, the next
+/// attempt is made after a delay which doubles on every failure in a row
+/// (from 100 ms up to 30 s) and is randomized by up to a half to spread
+/// the reconnecting clients over time.
+///
+/// Addresses are not resolved by `Persistent`, use `ToSocketAddrs` or
+/// the `Resolver` beforehand.
+pub struct Persistent<M>(Retry, State<M>);
+
+impl Retry {
+    fn new(addresses: Vec<SocketAddr>, connect_timeout: Duration,
+        options: SocketOptions)
+        -> Retry
+    {
+        Retry {
+            addresses: addresses,
+            next: 0,
+            connect_timeout: connect_timeout,
+            options: options,
+            backoff: Backoff::new(Duration::from_millis(BACKOFF_MIN_MS),
+                                  Duration::from_millis(BACKOFF_MAX_MS))
+                     .jitter(BACKOFF_JITTER),
+        }
+    }
+    fn next_address(&mut self) -> SocketAddr {
+        let addr = self.addresses[self.next % self.addresses.len()];
+        self.next = self.next.wrapping_add(1);
+        addr
+    }
+    /// Connection is established, so the next failure is the first one
+    fn established(&mut self) {
+        self.backoff.reset();
+    }
+    fn connect<M, S: GenericScope>(mut self, scope: &mut S)
+        -> Response<Persistent<M>, Void>
+    {
+        let addr = self.next_address();
+        let res = TcpStream::connect(&addr).and_then(|sock| {
+            try!(self.options.apply(&sock));
+            try!(scope.register(&sock, EventSet::writable(),
+                                PollOpt::level()));
+            Ok(sock)
+        });
+        match res {
+            Ok(sock) => {
+                let deadline = scope.now() + self.connect_timeout;
+                Response::ok(Persistent(self, State::Connecting(sock,
+                    deadline))).deadline(deadline)
+            }
+            Err(e) => {
+                debug!("Error connecting to {}: {}", addr, e);
+                self.sleep(scope)
+            }
+        }
+    }
+    fn sleep<M, S: GenericScope>(mut self, scope: &mut S)
+        -> Response<Persistent<M>, Void>
+    {
+        let deadline = scope.now() + self.backoff.next_delay();
+        Response::ok(Persistent(self, State::Sleeping(deadline)))
+            .deadline(deadline)
+    }
+}
+
+impl<M: Client> Persistent<M> {
+    /// Start connecting to the first address
+    ///
+    /// # Panics
+    ///
+    /// When `addresses` is empty
+    pub fn new<S: GenericScope>(addresses: Vec<SocketAddr>,
+        connect_timeout: Duration, scope: &mut S)
+        -> Response<Persistent<M>, Void>
+    {
+        Persistent::with_options(addresses, connect_timeout,
+                                 SocketOptions::new(), scope)
+    }
+    /// Start connecting setting the `options` on every socket
+    ///
+    /// # Panics
+    ///
+    /// When `addresses` is empty
+    pub fn with_options<S: GenericScope>(addresses: Vec<SocketAddr>,
+        connect_timeout: Duration, options: SocketOptions, scope: &mut S)
+        -> Response<Persistent<M>, Void>
+    {
+        assert!(!addresses.is_empty(), "no addresses to connect to");
+        Retry::new(addresses, connect_timeout, options).connect(scope)
+    }
+}
+
+fn connection_result(sock: &TcpStream, events: EventSet)
+    -> Option<io::Result<()>>
+{
+    connect_status(sock.take_error().unwrap_or_else(Some), events,
+        // Only a connected socket has the peer address
+        || sock.peer_addr().map(|_| ()))
+}
+
+/// Returns `None` while the connection is still in progress
+fn connect_status<F>(error: Option<io::Error>, events: EventSet, peer: F)
+    -> Option<io::Result<()>>
+    where F: FnOnce() -> io::Result<()>
+{
+    if let Some(e) = error {
+        return Some(Err(e));
+    }
+    if events.is_error() || events.is_hup() {
+        return Some(Err(io::Error::new(io::ErrorKind::ConnectionRefused,
+            "connection failed")));
+    }
+    if !events.is_writable() {
+        return None;
+    }
+    Some(peer())
+}
+
+fn child<M: Client>(retry: Retry, res: Response<M, Void>,
+    scope: &mut Scope<M::Context>)
+    -> Response<Persistent<M>, Void>
+{
+    let res = call_error_hook(res, scope);
+    if res.is_stopped() {
+        if let Some(e) = res.cause() {
+            debug!("Client connection failed: {}", e);
+        }
+        return retry.sleep(scope);
+    }
+    res.wrap(|m| Persistent(retry, State::Connected(m)))
+}
+
+impl<M: Client> Machine for Persistent<M> {
+    type Context = M::Context;
+    type Seed = Void;
+
+    fn create(seed: Void, _scope: &mut Scope<M::Context>)
+        -> Response<Self, Void>
+    {
+        ::void::unreachable(seed)
+    }
+    fn ready(self, events: EventSet, scope: &mut Scope<M::Context>)
+        -> Response<Self, Void>
+    {
+        match self {
+            Persistent(retry, State::Connecting(sock, deadline)) => {
+                match connection_result(&sock, events) {
+                    None => {
+                        Response::ok(Persistent(retry,
+                            State::Connecting(sock, deadline)))
+                        .deadline(deadline)
+                    }
+                    Some(Ok(())) => {
+                        let mut retry = retry;
+                        retry.established();
+                        if let Err(e) = scope.deregister(&sock) {
+                            debug!("Error deregistering socket: {}", e);
+                            return retry.sleep(scope);
+                        }
+                        let res = M::connected(sock, scope);
+                        child(retry, res, scope)
+                    }
+                    Some(Err(e)) => {
+                        debug!("Error connecting: {}", e);
+                        retry.sleep(scope)
+                    }
+                }
+            }
+            Persistent(retry, State::Sleeping(deadline)) => {
+                // Spurious event of the previous socket
+                Response::ok(Persistent(retry, State::Sleeping(deadline)))
+                    .deadline(deadline)
+            }
+            Persistent(retry, State::Connected(m)) => {
+                let res = m.ready(events, scope);
+                child(retry, res, scope)
+            }
+        }
+    }
+    fn spawned(self, _scope: &mut Scope<M::Context>) -> Response<Self, Void>
+    {
+        unreachable!();
+    }
+    fn destroyed(self, scope: &mut Scope<M::Context>) {
+        if let State::Connected(m) = self.1 {
+            m.destroyed(scope);
+        }
+    }
+    fn timeout(self, scope: &mut Scope<M::Context>) -> Response<Self, Void> {
+        match self {
+            Persistent(retry, State::Connecting(sock, deadline)) => {
+                if scope.now() < deadline {
+                    return Response::ok(Persistent(retry,
+                        State::Connecting(sock, deadline)))
+                        .deadline(deadline);
+                }
+                debug!("Timed out connecting");
+                // Socket is deregistered when closed
+                drop(sock);
+                retry.sleep(scope)
+            }
+            Persistent(retry, State::Sleeping(deadline)) => {
+                if scope.now() < deadline {
+                    return Response::ok(Persistent(retry,
+                        State::Sleeping(deadline))).deadline(deadline);
+                }
+                retry.connect(scope)
+            }
+            Persistent(retry, State::Connected(m)) => {
+                let res = m.timeout(scope);
+                child(retry, res, scope)
+            }
+        }
+    }
+    fn wakeup(self, scope: &mut Scope<M::Context>) -> Response<Self, Void> {
+        match self {
+            Persistent(retry, State::Connecting(sock, deadline)) => {
+                Response::ok(Persistent(retry,
+                    State::Connecting(sock, deadline))).deadline(deadline)
+            }
+            Persistent(retry, State::Sleeping(deadline)) => {
+                Response::ok(Persistent(retry, State::Sleeping(deadline)))
+                    .deadline(deadline)
+            }
+            Persistent(retry, State::Connected(m)) => {
+                let res = m.wakeup(scope);
+                child(retry, res, scope)
+            }
+        }
+    }
+    fn interest(&self) -> Option<EventSet> {
+        match self.1 {
+            State::Connected(ref m) => m.interest(),
+            _ => None,
+        }
+    }
+    fn error(self, err: Box<Error>, scope: &mut Scope<M::Context>)
+        -> Box<Error>
+    {
+        match self.1 {
+            State::Connected(m) => m.error(err, scope),
+            _ => err,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io;
+    use std::time::Duration;
+    use std::net::SocketAddr;
+    use {EventSet, SocketOptions};
+    use super::{Retry, connect_status};
+
+    fn not_connected() -> io::Result<()> {
+        Err(io::Error::new(io::ErrorKind::NotConnected, "not connected"))
+    }
+
+    #[test]
+    fn pending_error() {
+        let err = io::Error::new(io::ErrorKind::ConnectionReset, "reset");
+        let res = connect_status(Some(err), EventSet::writable(),
+                                 || Ok(())).unwrap();
+        assert_eq!(res.unwrap_err().kind(), io::ErrorKind::ConnectionReset);
+    }
+
+    #[test]
+    fn hup() {
+        let res = connect_status(None,
+            EventSet::writable() | EventSet::hup(), || Ok(())).unwrap();
+        assert_eq!(res.unwrap_err().kind(),
+                   io::ErrorKind::ConnectionRefused);
+        let res = connect_status(None, EventSet::error(),
+                                 || Ok(())).unwrap();
+        assert_eq!(res.unwrap_err().kind(),
+                   io::ErrorKind::ConnectionRefused);
+    }
+
+    #[test]
+    fn in_progress() {
+        assert!(connect_status(None, EventSet::readable(),
+                               not_connected).is_none());
+    }
+
+    #[test]
+    fn writable_not_connected() {
+        let res = connect_status(None, EventSet::writable(),
+                                 not_connected).unwrap();
+        assert_eq!(res.unwrap_err().kind(), io::ErrorKind::NotConnected);
+        let res = connect_status(None, EventSet::writable(), || Ok(()));
+        assert!(res.unwrap().is_ok());
+    }
+
+    #[test]
+    fn address_rotation() {
+        let addrs: Vec<SocketAddr> = vec![
+            "127.0.0.1:1".parse().unwrap(),
+            "127.0.0.1:2".parse().unwrap(),
+            "127.0.0.1:3".parse().unwrap(),
+        ];
+        let mut retry = Retry::new(addrs.clone(), Duration::from_secs(1),
+                                   SocketOptions::new());
+        let order: Vec<_> = (0..5).map(|_| retry.next_address()).collect();
+        assert_eq!(order,
+                   vec![addrs[0], addrs[1], addrs[2], addrs[0], addrs[1]]);
+    }
+
+    #[test]
+    fn backoff_reset() {
+        let mut retry = Retry::new(vec!["127.0.0.1:1".parse().unwrap()],
+            Duration::from_secs(1), SocketOptions::new());
+        for _ in 0..5 {
+            retry.backoff.next_delay();
+        }
+        assert!(retry.backoff.next_delay() > Duration::from_millis(1000));
+        retry.established();
+        assert_eq!(retry.backoff.attempts(), 0);
+        assert!(retry.backoff.next_delay() <= Duration::from_millis(100));
+    }
+}
@@ -0,0 +1,77 @@
+use std::thread;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{channel, Sender, Receiver};
+
+
+trait Job: Send {
+    fn run(self: Box<Self>);
+}
+
+impl<F: FnOnce() + Send> Job for F {
+    fn run(self: Box<Self>) {
+        (*self)()
+    }
+}
+
+/// A pool of threads used to offload blocking work from the loop
+///
+/// Threads are started lazily on the first `execute`, so loops which never
+/// offload anything don't pay for them.
+pub struct ThreadPool {
+    size: usize,
+    /// Prefix of the thread names
+    name: &'static str,
+    /// Option of the `Config` which sets the size, for the panic message
+    option: &'static str,
+    sender: Option<Sender<Box<Job>>>,
+}
+
+impl ThreadPool {
+    pub fn new(size: usize, name: &'static str, option: &'static str)
+        -> ThreadPool
+    {
+        ThreadPool {
+            size: size,
+            name: name,
+            option: option,
+            sender: None,
+        }
+    }
+
+    pub fn execute<F>(&mut self, fun: F)
+        where F: FnOnce() + Send + 'static
+    {
+        if self.sender.is_none() {
+            self.sender = Some(self.start());
+        }
+        self.sender.as_ref().unwrap().send(Box::new(fun))
+            .expect("All offload threads are dead");
+    }
+
+    fn start(&self) -> Sender<Box<Job>> {
+        assert!(self.size > 0, "Thread pool {} is disabled. \
+            Set `Config::{}` to a non-zero value", self.name, self.option);
+        let (tx, rx) = channel();
+        let rx = Arc::new(Mutex::new(rx));
+        for i in 0..self.size {
+            let rx = rx.clone();
+            thread::Builder::new()
+                .name(format!("{}-{}", self.name, i))
+                .spawn(move || worker(rx))
+                .expect("Can't start offload thread");
+        }
+        tx
+    }
+}
+
+fn worker(queue: Arc<Mutex<Receiver<Box<Job>>>>) {
+    loop {
+        // Note: the lock is released before running a job
+        let job = queue.lock().expect("Offload queue is poisoned").recv();
+        match job {
+            Ok(job) => job.run(),
+            // The pool is dropped, i.e. the loop is finished
+            Err(_) => break,
+        }
+    }
+}
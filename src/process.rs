@@ -0,0 +1,334 @@
+use std::io::{self, Read, Write};
+use std::process::{Command, Child, Stdio, ExitStatus};
+use std::os::unix::io::{FromRawFd, IntoRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::time::Duration;
+
+use mio::deprecated::unix::{PipeReader, PipeWriter};
+use void::{Void, unreachable};
+
+use {Machine, Scope, GenericScope, Response, EventSet, PollOpt};
+
+
+/// Size of a single read from the output pipes
+const READ_CHUNK: usize = 16384;
+/// How often the exit status is checked after the output pipes are closed
+const EXIT_POLL_MS: u64 = 100;
+
+/// The output pipe of the child process
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Output {
+    Stdout,
+    Stderr,
+}
+
+/// The child process and its standard input
+///
+/// Data written here is buffered and sent to the child when its stdin is
+/// writable. Check `buffered()` to limit the amount of data in flight,
+/// `ProcessHandler::drained` is called when the buffer is flushed.
+pub struct ProcessIo {
+    child: Child,
+    stdin: Option<PipeWriter>,
+    buffer: Vec<u8>,
+    /// Stdin is registered in the loop while there is data to write
+    writing: bool,
+    /// Stdin is closed when the buffer is flushed
+    closing: bool,
+}
+
+/// Callbacks of the `ProcessMachine`
+pub trait ProcessHandler: Sized {
+    type Context;
+    /// A chunk of data is read from stdout or stderr of the child
+    fn output(self, stream: Output, data: &[u8], io: &mut ProcessIo,
+        scope: &mut Scope<Self::Context>)
+        -> Self;
+    /// All the data written to stdin is sent to the child
+    fn drained(self, _io: &mut ProcessIo, _scope: &mut Scope<Self::Context>)
+        -> Self
+    {
+        self
+    }
+    /// The state machine is woken up, e.g. by another state machine
+    /// which has some data for the child
+    fn wakeup(self, _io: &mut ProcessIo, _scope: &mut Scope<Self::Context>)
+        -> Self
+    {
+        self
+    }
+    /// The child has exited, this is the last callback
+    fn exited(self, status: ExitStatus, scope: &mut Scope<Self::Context>);
+}
+
+/// A state machine which runs a child process
+///
+/// The child is spawned with stdin, stdout and stderr connected to
+/// non-blocking pipes registered in the loop, so there are no blocking
+/// waits and no helper threads. Output is delivered to the
+/// `ProcessHandler` as it's read. When both output pipes are closed, the
+/// exit status is checked every 100 ms until the child exits, then
+/// `ProcessHandler::exited` is called and the state machine is done.
+///
+/// If the state machine is destroyed (e.g. the loop is shut down) before
+/// the child has exited, the child is killed.
+pub struct ProcessMachine<H> {
+    io: ProcessIo,
+    stdout: Option<PipeReader>,
+    stderr: Option<PipeReader>,
+    handler: H,
+}
+
+impl ProcessIo {
+    /// Queue the data to write to stdin of the child
+    ///
+    /// The data is discarded if stdin is closed.
+    pub fn write(&mut self, data: &[u8]) {
+        if self.stdin.is_some() && !self.closing {
+            self.buffer.extend_from_slice(data);
+        }
+    }
+    /// Number of bytes not yet sent to the child
+    pub fn buffered(&self) -> usize {
+        self.buffer.len()
+    }
+    /// Close stdin of the child when the buffered data is sent
+    pub fn close_stdin(&mut self) {
+        self.closing = true;
+    }
+    /// Send `SIGKILL` to the child
+    pub fn kill(&mut self) -> io::Result<()> {
+        self.child.kill()
+    }
+    /// The process id of the child
+    pub fn id(&self) -> u32 {
+        self.child.id()
+    }
+    fn flush<C>(&mut self, scope: &mut Scope<C>) -> io::Result<()> {
+        if let Some(ref mut stdin) = self.stdin {
+            while !self.buffer.is_empty() {
+                match stdin.write(&self.buffer) {
+                    Ok(0) => {
+                        return Err(io::Error::new(io::ErrorKind::WriteZero,
+                            "child process doesn't accept input"));
+                    }
+                    Ok(n) => {
+                        self.buffer.drain(..n);
+                    }
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                        break;
+                    }
+                    Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {
+                        continue;
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+            if !self.buffer.is_empty() && !self.writing {
+                try!(scope.register(stdin, EventSet::writable(),
+                                    PollOpt::level()));
+                self.writing = true;
+            } else if self.buffer.is_empty() && self.writing {
+                try!(scope.deregister(stdin));
+                self.writing = false;
+            }
+        }
+        if self.closing && self.buffer.is_empty() {
+            // The child sees the end of input when the pipe is closed
+            self.stdin = None;
+        }
+        Ok(())
+    }
+}
+
+/// Switches the descriptor to the non-blocking mode
+///
+/// Pipes of the child are blocking, and there is no portable way to change
+/// it in `std` except going through the socket type. The call works on
+/// any descriptor.
+fn nonblocking<F: IntoRawFd>(pipe: F) -> io::Result<RawFd> {
+    let sock = unsafe { UnixStream::from_raw_fd(pipe.into_raw_fd()) };
+    try!(sock.set_nonblocking(true));
+    Ok(sock.into_raw_fd())
+}
+
+fn take_pipes(child: &mut Child)
+    -> io::Result<(PipeWriter, PipeReader, PipeReader)>
+{
+    let stdin = child.stdin.take().expect("stdin is piped");
+    let stdout = child.stdout.take().expect("stdout is piped");
+    let stderr = child.stderr.take().expect("stderr is piped");
+    unsafe {
+        Ok((PipeWriter::from_raw_fd(try!(nonblocking(stdin))),
+            PipeReader::from_raw_fd(try!(nonblocking(stdout))),
+            PipeReader::from_raw_fd(try!(nonblocking(stderr)))))
+    }
+}
+
+impl<H: ProcessHandler> ProcessMachine<H> {
+    /// Spawn the child process
+    ///
+    /// Standard streams of the `command` are replaced by pipes.
+    pub fn spawn<S: GenericScope>(command: &mut Command, handler: H,
+        scope: &mut S)
+        -> Response<ProcessMachine<H>, Void>
+    {
+        command.stdin(Stdio::piped())
+               .stdout(Stdio::piped())
+               .stderr(Stdio::piped());
+        let mut child = match command.spawn() {
+            Ok(child) => child,
+            Err(e) => return Response::error(Box::new(e)),
+        };
+        let res = take_pipes(&mut child).and_then(|(stdin, out, err)| {
+            try!(scope.register(&out, EventSet::readable(),
+                                PollOpt::level()));
+            try!(scope.register(&err, EventSet::readable(),
+                                PollOpt::level()));
+            Ok((stdin, out, err))
+        });
+        match res {
+            Ok((stdin, stdout, stderr)) => {
+                Response::ok(ProcessMachine {
+                    io: ProcessIo {
+                        child: child,
+                        stdin: Some(stdin),
+                        buffer: Vec::new(),
+                        writing: false,
+                        closing: false,
+                    },
+                    stdout: Some(stdout),
+                    stderr: Some(stderr),
+                    handler: handler,
+                })
+            }
+            Err(e) => {
+                child.kill().ok();
+                child.wait().ok();
+                Response::error(Box::new(e))
+            }
+        }
+    }
+}
+
+fn read_output<H: ProcessHandler>(pipe: &mut Option<PipeReader>,
+    stream: Output, mut handler: H, io: &mut ProcessIo,
+    scope: &mut Scope<H::Context>)
+    -> H
+{
+    let mut buf = [0u8; READ_CHUNK];
+    let closed = match *pipe {
+        Some(ref mut sock) => loop {
+            match sock.read(&mut buf) {
+                Ok(0) => break true,
+                Ok(n) => {
+                    handler = handler.output(stream, &buf[..n], io, scope);
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    break false;
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => {
+                    debug!("Error reading {:?} of child process: {}",
+                        stream, e);
+                    break true;
+                }
+            }
+        },
+        None => false,
+    };
+    if closed {
+        // Closing the pipe removes it from the loop
+        *pipe = None;
+    }
+    handler
+}
+
+impl<H: ProcessHandler> ProcessMachine<H> {
+    fn flush(mut self, scope: &mut Scope<H::Context>)
+        -> Response<Self, Void>
+    {
+        let had_data = self.io.buffered() > 0;
+        if let Err(e) = self.io.flush(scope) {
+            debug!("Error writing to child process: {}", e);
+            // The child doesn't read input anymore, but may still run
+            self.io.stdin = None;
+            self.io.buffer.clear();
+        }
+        if had_data && self.io.buffered() == 0 && self.io.stdin.is_some() {
+            self.handler = self.handler.drained(&mut self.io, scope);
+            // The handler might have written more data
+            if let Err(e) = self.io.flush(scope) {
+                debug!("Error writing to child process: {}", e);
+                self.io.stdin = None;
+                self.io.buffer.clear();
+            }
+        }
+        self.check_exit(scope)
+    }
+    fn check_exit(mut self, scope: &mut Scope<H::Context>)
+        -> Response<Self, Void>
+    {
+        if self.stdout.is_some() || self.stderr.is_some() {
+            return Response::ok(self);
+        }
+        match self.io.child.try_wait() {
+            Ok(Some(status)) => {
+                self.handler.exited(status, scope);
+                Response::done()
+            }
+            Ok(None) => {
+                let deadline = scope.now() +
+                    Duration::from_millis(EXIT_POLL_MS);
+                Response::ok(self).deadline(deadline)
+            }
+            Err(e) => Response::error(Box::new(e)),
+        }
+    }
+}
+
+impl<H: ProcessHandler> Machine for ProcessMachine<H> {
+    type Context = H::Context;
+    type Seed = Void;
+
+    fn create(seed: Void, _scope: &mut Scope<H::Context>)
+        -> Response<Self, Void>
+    {
+        unreachable(seed)
+    }
+    fn ready(self, _events: EventSet, scope: &mut Scope<H::Context>)
+        -> Response<Self, Void>
+    {
+        let ProcessMachine { mut io, mut stdout, mut stderr, handler } = self;
+        let handler = read_output(&mut stdout, Output::Stdout,
+            handler, &mut io, scope);
+        let handler = read_output(&mut stderr, Output::Stderr,
+            handler, &mut io, scope);
+        ProcessMachine {
+            io: io,
+            stdout: stdout,
+            stderr: stderr,
+            handler: handler,
+        }.flush(scope)
+    }
+    fn spawned(self, _scope: &mut Scope<H::Context>) -> Response<Self, Void>
+    {
+        unreachable!();
+    }
+    fn timeout(self, scope: &mut Scope<H::Context>) -> Response<Self, Void> {
+        self.check_exit(scope)
+    }
+    fn wakeup(mut self, scope: &mut Scope<H::Context>)
+        -> Response<Self, Void>
+    {
+        self.handler = self.handler.wakeup(&mut self.io, scope);
+        self.flush(scope)
+    }
+    fn destroyed(mut self, _scope: &mut Scope<H::Context>) {
+        if let Ok(None) = self.io.child.try_wait() {
+            self.io.child.kill().ok();
+            // Reap the child, it exits right after the signal
+            self.io.child.wait().ok();
+        }
+    }
+}
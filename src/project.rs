@@ -0,0 +1,116 @@
+use std::marker::PhantomData;
+use std::error::Error;
+
+use mio::{Ready, PollOpt, Evented, Token};
+
+use {Machine, Scope, Response, SpawnError};
+
+
+/// A part of the context that is visible to some state machine
+///
+/// Implement `SubContext<Lib>` for your application's context for every
+/// library whose state machines need their own context type `Lib`, and
+/// mount such machines with the `Projected` wrapper. The state machine sees
+/// a `Scope<Lib>` then, i.e. only its own part of the context.
+///
+/// Every context is a sub-context of itself.
+pub trait SubContext<C> {
+    fn sub_context(&self) -> &C;
+    fn sub_context_mut(&mut self) -> &mut C;
+}
+
+/// A state machine that runs in the loop with a larger context
+///
+/// The wrapped machine receives a scope projected to its own context type
+/// (see `SubContext`). Usually used as a variant of the `Compose2` or of
+/// the `rotor_compose!` enum.
+pub struct Projected<M, C>(pub M, PhantomData<*const C>);
+
+impl<C> SubContext<C> for C {
+    fn sub_context(&self) -> &C {
+        self
+    }
+    fn sub_context_mut(&mut self) -> &mut C {
+        self
+    }
+}
+
+impl<M, C> Projected<M, C> {
+    pub fn new(machine: M) -> Projected<M, C> {
+        Projected(machine, PhantomData)
+    }
+    /// Returns the wrapped state machine
+    pub fn into_inner(self) -> M {
+        self.0
+    }
+}
+
+impl<M, C> Machine for Projected<M, C>
+    where M: Machine, C: SubContext<M::Context>
+{
+    type Context = C;
+    type Seed = M::Seed;
+
+    fn create(seed: Self::Seed, scope: &mut Scope<C>)
+        -> Response<Self, Self::Seed>
+    {
+        M::create(seed, &mut scope.project())
+            .wrap(Projected::new)
+    }
+    fn register_seed(seed: &Self::Seed) -> Option<(&Evented, Ready, PollOpt)>
+    {
+        M::register_seed(seed)
+    }
+    fn ready(self, events: Ready, scope: &mut Scope<C>)
+        -> Response<Self, Self::Seed>
+    {
+        self.0.ready(events, &mut scope.project()).wrap(Projected::new)
+    }
+    fn spawned(self, scope: &mut Scope<C>) -> Response<Self, Self::Seed> {
+        self.0.spawned(&mut scope.project()).wrap(Projected::new)
+    }
+    fn spawn_error(self, scope: &mut Scope<C>,
+                   error: SpawnError<Self::Seed>)
+        -> Response<Self, Self::Seed>
+    {
+        self.0.spawn_error(&mut scope.project(), error)
+            .wrap(Projected::new)
+    }
+    fn destroyed(self, scope: &mut Scope<C>) {
+        self.0.destroyed(&mut scope.project())
+    }
+    fn freeze(self, scope: &mut Scope<C>) -> Result<Self::Seed, Self> {
+        self.0.freeze(&mut scope.project()).map_err(Projected::new)
+    }
+    fn thaw(seed: Self::Seed, scope: &mut Scope<C>)
+        -> Response<Self, Self::Seed>
+    {
+        M::thaw(seed, &mut scope.project())
+            .wrap(Projected::new)
+    }
+    fn timeout(self, scope: &mut Scope<C>) -> Response<Self, Self::Seed> {
+        self.0.timeout(&mut scope.project()).wrap(Projected::new)
+    }
+    fn wakeup(self, scope: &mut Scope<C>) -> Response<Self, Self::Seed> {
+        self.0.wakeup(&mut scope.project()).wrap(Projected::new)
+    }
+    fn exit_signal(self, peer: Token, scope: &mut Scope<C>)
+        -> Response<Self, Self::Seed>
+    {
+        self.0.exit_signal(peer, &mut scope.project()).wrap(Projected::new)
+    }
+    fn tick(self, scope: &mut Scope<C>) -> Response<Self, Self::Seed> {
+        self.0.tick(&mut scope.project()).wrap(Projected::new)
+    }
+    fn config_changed(self, scope: &mut Scope<C>)
+        -> Response<Self, Self::Seed>
+    {
+        self.0.config_changed(&mut scope.project()).wrap(Projected::new)
+    }
+    fn interest(&self) -> Option<Ready> {
+        self.0.interest()
+    }
+    fn error(self, err: Box<Error>, scope: &mut Scope<C>) -> Box<Error> {
+        self.0.error(err, &mut scope.project())
+    }
+}
@@ -0,0 +1,288 @@
+use std::io;
+use std::fmt::Write;
+use std::marker::PhantomData;
+use std::net::SocketAddr;
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use mio::deprecated::{TryRead, TryWrite};
+use mio::tcp::{TcpListener, TcpStream};
+use void::Void;
+
+use {Machine, Scope, GenericScope, Response, EventSet, PollOpt, Evented};
+use {Accept, Histogram, Time};
+
+
+/// Maximum size of the request headers, larger requests are dropped
+const MAX_REQUEST: usize = 8192;
+/// Time given to the client to send the request and read the response
+const CONNECTION_TIMEOUT_MS: u64 = 10000;
+/// Quantiles of the summaries
+const QUANTILES: &[f64] = &[0.5, 0.9, 0.99];
+
+/// A connection to the Prometheus metrics exporter
+///
+/// Answers any HTTP request with the statistics of the loop in the
+/// Prometheus text format, and closes the connection. Exported metrics:
+///
+/// * `rotor_loop_info` -- always 1, the `loop` label is `Scope::loop_id`
+/// * `rotor_machines` -- number of state machines by label
+/// * `rotor_poll_errors_total` -- poll errors by kind, see `PollErrors`
+/// * `rotor_dropped_wakeups_total` -- notifications dropped on the full
+///   queue, see `FullPolicy`
+/// * `rotor_timer_metric` -- summaries of `Scope::timer_metric` by label
+/// * `rotor_dispatch_latency_microseconds` and
+///   `rotor_timer_lateness_milliseconds` -- summaries of `LoopLatency`, if
+///   `Config::latency_histograms` is enabled
+///
+/// Create the listener with `Prometheus::bind` (or `Prometheus::new`),
+/// it's an `Accept` state machine spawning a `Prometheus` for every
+/// connection:
+///
+/// ```ignore
+/// loop_creator.add_machine_with(|scope| {
+///     Prometheus::bind(&"127.0.0.1:9100".parse().unwrap(), scope)
+/// });
+/// ```
+pub struct Prometheus<C> {
+    sock: TcpStream,
+    input: Vec<u8>,
+    output: Vec<u8>,
+    deadline: Time,
+    phantom: PhantomData<*const C>,
+}
+
+/// The listener of the Prometheus metrics exporter
+pub type PrometheusListener<C> = Accept<TcpListener, Prometheus<C>>;
+
+fn make_seed(sock: TcpStream, _addr: SocketAddr) -> TcpStream {
+    sock
+}
+
+impl<C> Prometheus<C> {
+    /// Create a state machine accepting metrics requests on the socket
+    pub fn new<S: GenericScope>(sock: TcpListener, scope: &mut S)
+        -> Response<PrometheusListener<C>, Void>
+    {
+        Accept::new(sock, make_seed, scope)
+    }
+    /// Bind the address and accept metrics requests on it
+    pub fn bind<S: GenericScope>(addr: &SocketAddr, scope: &mut S)
+        -> Response<PrometheusListener<C>, Void>
+    {
+        match TcpListener::bind(addr) {
+            Ok(sock) => Prometheus::new(sock, scope),
+            Err(e) => Response::error(Box::new(e)),
+        }
+    }
+    fn read(&mut self) -> io::Result<bool> {
+        let mut buf = [0u8; 4096];
+        loop {
+            match try!(self.sock.try_read(&mut buf)) {
+                Some(0) => return Ok(true),
+                Some(x) => self.input.extend_from_slice(&buf[..x]),
+                None => return Ok(false),
+            }
+        }
+    }
+    fn write(&mut self) -> io::Result<()> {
+        while !self.output.is_empty() {
+            match try!(self.sock.try_write(&self.output)) {
+                Some(x) => {
+                    self.output.drain(..x);
+                }
+                None => break,
+            }
+        }
+        Ok(())
+    }
+    fn request_complete(&self) -> bool {
+        self.input.windows(4).any(|x| x == b"\r\n\r\n")
+    }
+}
+
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+fn write_summary(out: &mut String, name: &str, labels: &str,
+    hist: &Histogram)
+{
+    let sep = if labels.is_empty() { "" } else { "," };
+    for &q in QUANTILES {
+        writeln!(out, "{}{{{}{}quantile=\"{}\"}} {}", name, labels, sep, q,
+            hist.percentile(q * 100.)).unwrap();
+    }
+    let braces = if labels.is_empty() {
+        String::new()
+    } else {
+        format!("{{{}}}", labels)
+    };
+    writeln!(out, "{}_sum{} {}", name, braces, hist.sum()).unwrap();
+    writeln!(out, "{}_count{} {}", name, braces, hist.count()).unwrap();
+}
+
+fn render<C>(scope: &Scope<C>) -> String {
+    let mut out = String::new();
+    out.push_str("# HELP rotor_loop_info The loop serving the metrics\n");
+    out.push_str("# TYPE rotor_loop_info gauge\n");
+    writeln!(out, "rotor_loop_info{{loop=\"{}\"}} 1",
+        escape(scope.loop_id())).unwrap();
+    let mut labels = BTreeMap::new();
+    scope.for_each_machine(|_, meta| {
+        *labels.entry(meta.label().unwrap_or("")).or_insert(0u64) += 1;
+    });
+    out.push_str("# HELP rotor_machines Number of state machines\n");
+    out.push_str("# TYPE rotor_machines gauge\n");
+    for (label, count) in labels {
+        writeln!(out, "rotor_machines{{label=\"{}\"}} {}",
+            escape(label), count).unwrap();
+    }
+    let errors = scope.poll_errors();
+    out.push_str("# HELP rotor_poll_errors_total Errors of the poll\n");
+    out.push_str("# TYPE rotor_poll_errors_total counter\n");
+    for &(kind, value) in &[("interrupted", errors.interrupted),
+                            ("transient", errors.transient),
+                            ("fatal", errors.fatal)]
+    {
+        writeln!(out, "rotor_poll_errors_total{{kind=\"{}\"}} {}",
+            kind, value).unwrap();
+    }
+    out.push_str("# HELP rotor_dropped_wakeups_total \
+        Notifications dropped on the full queue\n");
+    out.push_str("# TYPE rotor_dropped_wakeups_total counter\n");
+    writeln!(out, "rotor_dropped_wakeups_total {}",
+        scope.dropped_wakeups()).unwrap();
+    out.push_str("# HELP rotor_timer_metric Timer metrics of the loop\n");
+    out.push_str("# TYPE rotor_timer_metric summary\n");
+    scope.for_each_timer_metric(|label, hist| {
+        let labels = format!("label=\"{}\"", escape(label));
+        write_summary(&mut out, "rotor_timer_metric", &labels, hist);
+    });
+    if let Some(latency) = scope.latency() {
+        out.push_str("# HELP rotor_dispatch_latency_microseconds \
+                      Time from poll to dispatching the event\n");
+        out.push_str("# TYPE rotor_dispatch_latency_microseconds summary\n");
+        write_summary(&mut out, "rotor_dispatch_latency_microseconds", "",
+            &latency.dispatch);
+        out.push_str("# HELP rotor_timer_lateness_milliseconds \
+                      How late the deadlines fire\n");
+        out.push_str("# TYPE rotor_timer_lateness_milliseconds summary\n");
+        write_summary(&mut out, "rotor_timer_lateness_milliseconds", "",
+            &latency.timer_lateness);
+    }
+    out
+}
+
+impl<C> Machine for Prometheus<C> {
+    type Context = C;
+    type Seed = TcpStream;
+
+    fn register_seed(sock: &TcpStream)
+        -> Option<(&Evented, EventSet, PollOpt)>
+    {
+        Some((sock, EventSet::readable(), PollOpt::level()))
+    }
+    fn create(sock: TcpStream, scope: &mut Scope<C>)
+        -> Response<Self, Self::Seed>
+    {
+        let deadline = scope.now() +
+            Duration::from_millis(CONNECTION_TIMEOUT_MS);
+        Response::ok(Prometheus {
+            sock: sock,
+            input: Vec::new(),
+            output: Vec::new(),
+            deadline: deadline,
+            phantom: PhantomData,
+        }).deadline(deadline)
+    }
+    fn ready(mut self, events: EventSet, scope: &mut Scope<C>)
+        -> Response<Self, TcpStream>
+    {
+        if events.is_readable() && self.output.is_empty() {
+            let eof = match self.read() {
+                Ok(eof) => eof,
+                Err(e) => {
+                    debug!("Error reading metrics request: {}", e);
+                    return Response::done();
+                }
+            };
+            if !self.request_complete() {
+                if self.input.len() > MAX_REQUEST {
+                    debug!("Metrics request is too large");
+                    return Response::done();
+                }
+                if eof {
+                    return Response::done();
+                }
+                let deadline = self.deadline;
+                return Response::ok(self).deadline(deadline);
+            }
+            let body = render(scope);
+            let head = format!("HTTP/1.0 200 OK\r\n\
+                Content-Type: text/plain; version=0.0.4\r\n\
+                Content-Length: {}\r\n\
+                Connection: close\r\n\r\n", body.len());
+            self.output.extend_from_slice(head.as_bytes());
+            self.output.extend_from_slice(body.as_bytes());
+            if let Err(e) = scope.reregister(&self.sock,
+                EventSet::writable(), PollOpt::level())
+            {
+                debug!("Error reregistering metrics connection: {}", e);
+                return Response::done();
+            }
+        }
+        if let Err(e) = self.write() {
+            debug!("Error writing metrics response: {}", e);
+            return Response::done();
+        }
+        if self.output.is_empty() {
+            return Response::done();
+        }
+        let deadline = self.deadline;
+        Response::ok(self).deadline(deadline)
+    }
+    fn spawned(self, _scope: &mut Scope<C>) -> Response<Self, TcpStream> {
+        unreachable!();
+    }
+    fn timeout(self, _scope: &mut Scope<C>) -> Response<Self, TcpStream> {
+        Response::done()
+    }
+    fn wakeup(self, _scope: &mut Scope<C>) -> Response<Self, TcpStream> {
+        let deadline = self.deadline;
+        Response::ok(self).deadline(deadline)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use Histogram;
+    use super::{escape, write_summary};
+
+    #[test]
+    fn summary() {
+        let mut hist = Histogram::new();
+        for x in 1..11 {
+            hist.record(x);
+        }
+        let mut out = String::new();
+        write_summary(&mut out, "m", "label=\"a\"", &hist);
+        write_summary(&mut out, "n", "", &hist);
+        assert_eq!(out, "\
+            m{label=\"a\",quantile=\"0.5\"} 5\n\
+            m{label=\"a\",quantile=\"0.9\"} 9\n\
+            m{label=\"a\",quantile=\"0.99\"} 10\n\
+            m_sum{label=\"a\"} 55\n\
+            m_count{label=\"a\"} 10\n\
+            n{quantile=\"0.5\"} 5\n\
+            n{quantile=\"0.9\"} 9\n\
+            n{quantile=\"0.99\"} 10\n\
+            n_sum 55\n\
+            n_count 10\n");
+    }
+
+    #[test]
+    fn escape_label() {
+        assert_eq!(escape("a\"b\\c\nd"), "a\\\"b\\\\c\\nd");
+    }
+}
@@ -0,0 +1,119 @@
+use std::rc::Rc;
+use std::cell::RefCell;
+
+use Notifier;
+
+
+enum State<T> {
+    Waiting,
+    Answered(T),
+    Taken,
+    Abandoned,
+}
+
+/// The receiving side of a single-threaded request/response pair
+///
+/// Created by `Scope::promise()`. The state machine that holds the promise
+/// receives a `wakeup` when the value is sent through the `Answer` (or when
+/// the `Answer` is dropped without sending anything).
+///
+/// Unlike `Future` this type uses no locks or atomics and can't leave
+/// the loop thread.
+pub struct Promise<T> {
+    state: Rc<RefCell<State<T>>>,
+}
+
+/// The sending side of the `Promise`
+///
+/// Hand it to another state machine in the same loop (in a seed or via the
+/// context). Calling `send()` wakes up the machine that owns the promise.
+pub struct Answer<T> {
+    state: Rc<RefCell<State<T>>>,
+    notifier: Notifier,
+}
+
+pub fn create_promise<T>(notifier: Notifier) -> (Promise<T>, Answer<T>) {
+    let state = Rc::new(RefCell::new(State::Waiting));
+    let answer = Answer {
+        state: state.clone(),
+        notifier: notifier,
+    };
+    (Promise { state: state }, answer)
+}
+
+impl<T> Promise<T> {
+    /// Returns true if the value has been sent and not taken yet
+    pub fn is_ready(&self) -> bool {
+        match *self.state.borrow() {
+            State::Answered(_) => true,
+            _ => false,
+        }
+    }
+    /// Returns true if the `Answer` was dropped without sending a value
+    pub fn is_abandoned(&self) -> bool {
+        match *self.state.borrow() {
+            State::Abandoned => true,
+            _ => false,
+        }
+    }
+    /// Take the value if it's sent
+    ///
+    /// Returns `None` if the value is not ready yet, or was already taken.
+    /// Spurious wakeups are possible so always check the result.
+    pub fn take(&mut self) -> Option<T> {
+        let mut state = self.state.borrow_mut();
+        match *state {
+            State::Answered(_) => {}
+            _ => return None,
+        }
+        match ::std::mem::replace(&mut *state, State::Taken) {
+            State::Answered(x) => Some(x),
+            _ => unreachable!(),
+        }
+    }
+}
+
+/// Allows awaiting the value from the futures running in `FutureMachine`
+///
+/// Only available with the `futures` feature. The promise must be created
+/// by the scope of the `FutureMachine`, so that its wakeup polls the task.
+/// Fails with `()` if the `Answer` is dropped without sending the value.
+#[cfg(feature="futures")]
+impl<T> ::futures::Future for Promise<T> {
+    type Item = T;
+    type Error = ();
+    fn poll(&mut self) -> ::futures::Poll<T, ()> {
+        if self.is_abandoned() {
+            return Err(());
+        }
+        match self.take() {
+            Some(value) => Ok(::futures::Async::Ready(value)),
+            None => Ok(::futures::Async::NotReady),
+        }
+    }
+}
+
+impl<T> Answer<T> {
+    /// Send the value and wake up the state machine owning the promise
+    ///
+    /// Wakeup errors are ignored: the value is stored anyway, and most
+    /// probably the owner will be woken up by something else (or the loop
+    /// is shutting down).
+    pub fn send(self, value: T) {
+        *self.state.borrow_mut() = State::Answered(value);
+        self.notifier.wakeup().ok();
+    }
+}
+
+impl<T> Drop for Answer<T> {
+    fn drop(&mut self) {
+        let abandoned = match *self.state.borrow() {
+            State::Waiting => true,
+            _ => false,
+        };
+        if abandoned {
+            *self.state.borrow_mut() = State::Abandoned;
+            self.notifier.wakeup().ok();
+        }
+    }
+}
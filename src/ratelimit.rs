@@ -0,0 +1,169 @@
+use std::hash::Hash;
+use std::collections::HashMap;
+use std::time::Duration;
+
+use Time;
+
+
+/// A token-bucket rate limiter
+///
+/// Allows `burst` operations at once and then one operation per `interval`.
+/// Usually kept in the context, so that all the state machines of some
+/// kind share the budget. Timestamps are taken from `Scope::now()`, and when
+/// the budget is exceeded the limiter returns the time when the operation
+/// may be retried, which is the natural value for `Response::deadline`:
+///
+/// ```ignore
+/// match scope.rate_limiter.check(scope.now()) {
+///     Ok(()) => { /* do the work */ }
+///     Err(retry) => return Response::ok(self).deadline(retry),
+/// }
+/// ```
+///
+/// The limiter has millisecond precision (the precision of `Time`), so the
+/// interval should be at least a millisecond.
+#[derive(Debug, Clone)]
+pub struct RateLimiter {
+    interval: Duration,
+    tolerance: Duration,
+    /// The time when the bucket is full again
+    full_at: Option<Time>,
+}
+
+/// A set of rate limiters keyed by e.g. an IP address
+///
+/// Each key has its own bucket with the same parameters. Call `cleanup`
+/// from time to time to forget keys that have full buckets.
+#[derive(Debug, Clone)]
+pub struct KeyedRateLimiter<K: Hash + Eq> {
+    interval: Duration,
+    tolerance: Duration,
+    keys: HashMap<K, Time>,
+}
+
+fn check(interval: Duration, tolerance: Duration, full_at: Option<Time>,
+    now: Time)
+    -> Result<Time, Time>
+{
+    let full_at = match full_at {
+        Some(x) if x > now => x,
+        _ => now,
+    };
+    let allowed_at = full_at - tolerance;
+    if allowed_at > now {
+        Err(allowed_at)
+    } else {
+        Ok(full_at + interval)
+    }
+}
+
+fn tolerance(interval: Duration, burst: u32) -> Duration {
+    assert!(burst > 0, "burst must be positive");
+    interval * (burst - 1)
+}
+
+impl RateLimiter {
+    /// Create a limiter allowing `burst` operations at once and one more
+    /// operation every `interval`
+    ///
+    /// # Panics
+    ///
+    /// When `burst` is zero
+    pub fn new(interval: Duration, burst: u32) -> RateLimiter {
+        RateLimiter {
+            interval: interval,
+            tolerance: tolerance(interval, burst),
+            full_at: None,
+        }
+    }
+    /// Takes a token from the bucket
+    ///
+    /// Returns the time when the operation may be retried if the bucket is
+    /// empty. Failed checks don't consume anything.
+    pub fn check(&mut self, now: Time) -> Result<(), Time> {
+        let full_at = try!(check(self.interval, self.tolerance,
+                                 self.full_at, now));
+        self.full_at = Some(full_at);
+        Ok(())
+    }
+    /// Returns true if the `check` would succeed at the time `now`
+    pub fn is_allowed(&self, now: Time) -> bool {
+        check(self.interval, self.tolerance, self.full_at, now).is_ok()
+    }
+}
+
+impl<K: Hash + Eq> KeyedRateLimiter<K> {
+    /// Create a limiter with a bucket per key, see `RateLimiter::new`
+    ///
+    /// # Panics
+    ///
+    /// When `burst` is zero
+    pub fn new(interval: Duration, burst: u32) -> KeyedRateLimiter<K> {
+        KeyedRateLimiter {
+            interval: interval,
+            tolerance: tolerance(interval, burst),
+            keys: HashMap::new(),
+        }
+    }
+    /// Takes a token from the bucket of the `key`
+    ///
+    /// Returns the time when the operation may be retried if the bucket is
+    /// empty.
+    pub fn check(&mut self, key: K, now: Time) -> Result<(), Time> {
+        let full_at = try!(check(self.interval, self.tolerance,
+                                 self.keys.get(&key).cloned(), now));
+        self.keys.insert(key, full_at);
+        Ok(())
+    }
+    /// Forget the keys which have their buckets full at the time `now`
+    pub fn cleanup(&mut self, now: Time) {
+        self.keys.retain(|_, full_at| *full_at > now);
+    }
+    /// Number of keys tracked
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+    use Time;
+    use super::{RateLimiter, KeyedRateLimiter};
+
+    #[test]
+    fn burst() {
+        let start = Time::zero() + Duration::from_secs(10);
+        let mut r = RateLimiter::new(Duration::from_millis(100), 3);
+        assert_eq!(r.check(start), Ok(()));
+        assert_eq!(r.check(start), Ok(()));
+        assert_eq!(r.check(start), Ok(()));
+        let retry = start + Duration::from_millis(100);
+        assert_eq!(r.check(start), Err(retry));
+        assert!(!r.is_allowed(start + Duration::from_millis(99)));
+        assert_eq!(r.check(retry), Ok(()));
+        assert_eq!(r.check(retry), Err(start + Duration::from_millis(200)));
+        // The bucket is refilled after a pause
+        let later = start + Duration::from_secs(1);
+        assert_eq!(r.check(later), Ok(()));
+        assert_eq!(r.check(later), Ok(()));
+        assert_eq!(r.check(later), Ok(()));
+        assert!(r.check(later).is_err());
+    }
+
+    #[test]
+    fn keyed() {
+        let start = Time::zero();
+        let mut r = KeyedRateLimiter::new(Duration::from_millis(50), 1);
+        assert_eq!(r.check("a", start), Ok(()));
+        assert_eq!(r.check("b", start), Ok(()));
+        assert_eq!(r.check("a", start),
+                   Err(start + Duration::from_millis(50)));
+        assert_eq!(r.len(), 2);
+        r.cleanup(start + Duration::from_millis(50));
+        assert!(r.is_empty());
+    }
+}
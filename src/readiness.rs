@@ -0,0 +1,113 @@
+use EventSet;
+
+
+/// Predicates for the events passed to `Machine::ready`
+///
+/// Platforms report a closed or failed connection with different
+/// combinations of flags: epoll sets HUP when both directions are shut
+/// down and RDHUP (reported as HUP too) when the peer has closed its
+/// side, kqueue reports EOF as HUP along with readable, and a failed
+/// socket gets ERR, usually with readable and writable. So checking
+/// `is_readable()` alone misses some of them, and treating every HUP as
+/// an error loses the data the peer has sent before closing.
+///
+/// `Scope::register_default` adds HUP and ERR to the interest on unix,
+/// so these flags are always delivered. Sockets registered with
+/// `Scope::register` get them only if asked for (epoll reports them
+/// anyway, kqueue doesn't).
+///
+/// ```ignore
+/// fn ready(self, events: EventSet, scope: &mut Scope<C>)
+///     -> Response<Self, Void>
+/// {
+///     if events.is_failed() {
+///         return Response::error(/* sock.take_error() */);
+///     }
+///     if events.has_input() {
+///         // read until `WouldBlock` or EOF, the data sent before
+///         // closing is still there
+///     }
+///     // ...
+/// }
+/// ```
+pub trait Readiness {
+    /// The socket has failed, `take_error()` returns the reason
+    fn is_failed(&self) -> bool;
+    /// The peer has closed the connection, at least its sending side
+    ///
+    /// Reading returns the data left in the buffer and then EOF. Writing
+    /// may still succeed if the connection is only half-closed.
+    fn is_hangup(&self) -> bool;
+    /// The socket is either failed or hung up
+    fn is_closed(&self) -> bool {
+        self.is_failed() || self.is_hangup()
+    }
+    /// Reading makes progress: returns data, EOF or the error
+    ///
+    /// Unlike `is_readable()` it's true for all the ways platforms
+    /// report a closed connection.
+    fn has_input(&self) -> bool;
+}
+
+impl Readiness for EventSet {
+    fn is_failed(&self) -> bool {
+        self.is_error()
+    }
+    fn is_hangup(&self) -> bool {
+        self.is_hup()
+    }
+    fn has_input(&self) -> bool {
+        self.is_readable() || self.is_hup() || self.is_error()
+    }
+}
+
+/// Adds the HUP and ERR flags to the interest on the platforms that
+/// report them
+#[cfg(unix)]
+pub fn with_hangup(interest: EventSet) -> EventSet {
+    interest | EventSet::hup() | EventSet::error()
+}
+
+#[cfg(not(unix))]
+pub fn with_hangup(interest: EventSet) -> EventSet {
+    interest
+}
+
+/// Divides the events of the split socket between the reader and the
+/// writer, both of them get hangup and error
+pub fn split_events(events: EventSet) -> (EventSet, EventSet) {
+    let closed = events & (EventSet::hup() | EventSet::error());
+    let reader = events - EventSet::writable();
+    let writer = (events & EventSet::writable()) | closed;
+    (reader, writer)
+}
+
+#[cfg(test)]
+mod test {
+    use EventSet;
+    use super::{Readiness, split_events};
+
+    #[test]
+    fn closed_connection() {
+        let eof = EventSet::readable() | EventSet::hup();
+        assert!(eof.is_hangup() && eof.has_input() && !eof.is_failed());
+        assert!(EventSet::hup().has_input());
+        assert!(EventSet::error().is_closed());
+        assert!(!EventSet::writable().has_input());
+        assert!(!EventSet::readable().is_closed());
+    }
+
+    #[test]
+    fn split() {
+        let none = EventSet::none();
+        let both = EventSet::readable() | EventSet::writable();
+        assert_eq!(split_events(both),
+                   (EventSet::readable(), EventSet::writable()));
+        assert_eq!(split_events(EventSet::writable()),
+                   (none, EventSet::writable()));
+        assert_eq!(split_events(EventSet::readable() | EventSet::hup()),
+                   (EventSet::readable() | EventSet::hup(), EventSet::hup()));
+        assert_eq!(split_events(EventSet::error()),
+                   (EventSet::error(), EventSet::error()));
+    }
+}
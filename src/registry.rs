@@ -0,0 +1,78 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use mio::Token;
+
+use Notifier;
+use notify::notifier_target;
+
+
+/// Well-known state machines of the loop by name
+///
+/// State machines register themselves with `Scope::register_name`, and
+/// other state machines find them with `Scope::lookup_name`, so there is
+/// no need to thread notifiers through every constructor. Names are
+/// removed automatically when the state machine is removed.
+///
+/// The registry is cloneable and may be sent to other threads, get it with
+/// `LoopCreator::registry()` or `LoopInstance::registry()`.
+#[derive(Clone, Debug)]
+pub struct Registry {
+    names: Arc<Mutex<HashMap<String, Notifier>>>,
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<T> {
+    match mutex.lock() {
+        Ok(guard) => guard,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
+
+impl Registry {
+    #[doc(hidden)]
+    pub fn new() -> Registry {
+        Registry { names: Arc::new(Mutex::new(HashMap::new())) }
+    }
+    /// Returns the notifier of the state machine registered as `name`
+    pub fn lookup(&self, name: &str) -> Option<Notifier> {
+        lock(&self.names).get(name).cloned()
+    }
+    /// Names of all the registered state machines
+    pub fn names(&self) -> Vec<String> {
+        lock(&self.names).keys().cloned().collect()
+    }
+}
+
+/// Registers the state machine, returns false if the name is taken
+#[doc(hidden)]
+pub fn register(registry: &Registry, name: &str, notifier: Notifier)
+    -> bool
+{
+    let mut names = lock(&registry.names);
+    if let Some(old) = names.get(name) {
+        return notifier_target(old) == notifier_target(&notifier);
+    }
+    names.insert(name.to_string(), notifier);
+    true
+}
+
+/// Removes the name if it's registered by the state machine
+#[doc(hidden)]
+pub fn unregister(registry: &Registry, name: &str, token: Token,
+    generation: u64)
+{
+    let mut names = lock(&registry.names);
+    let owned = names.get(name)
+        .map(|n| notifier_target(n) == (token, generation))
+        .unwrap_or(false);
+    if owned {
+        names.remove(name);
+    }
+}
+
+/// Removes all the names of the state machine
+#[doc(hidden)]
+pub fn unregister_all(registry: &Registry, token: Token, generation: u64) {
+    lock(&registry.names)
+        .retain(|_, n| notifier_target(n) != (token, generation));
+}
@@ -0,0 +1,466 @@
+use std::io;
+use std::time::Duration;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, Sender as Reply, Receiver};
+
+use mio::Token;
+
+use handler::Notify;
+use notify::Channel;
+use Notifier;
+use error::{RemoteSpawnError, MigrateError};
+use metrics::{LoopLatency, SlabOccupancy};
+#[cfg(feature="machine_stats")] use MachineStats;
+
+
+/// Result of the spawn requested by `LoopHandle::spawn`
+pub type SpawnResult<S> = Result<(), RemoteSpawnError<S>>;
+
+/// Result of the group spawned by `LoopHandle::spawn_uniform`
+///
+/// On success contains the notifiers of the new state machines, in the
+/// order of the seeds.
+pub type UniformResult<S> = Result<Vec<Notifier>, RemoteSpawnError<S>>;
+
+/// Result of the migration requested by `LoopHandle::migrate`
+///
+/// On success contains the receiver of the result of `Machine::thaw` in
+/// the target loop.
+pub type MigrateResult<S> = Result<Receiver<SpawnResult<S>>, MigrateError>;
+
+struct Request<S> {
+    id: usize,
+    seed: S,
+    /// The seed is made by `Machine::freeze`
+    thaw: bool,
+    reply: Reply<SpawnResult<S>>,
+}
+
+struct Migration<S> {
+    token: Token,
+    target: LoopHandle<S>,
+    reply: Reply<MigrateResult<S>>,
+}
+
+/// A closure sent by `ContextHandle::send`
+pub type ContextAction<C> = Box<FnOnce(&mut C) + Send>;
+
+struct GroupRequest<S> {
+    id: usize,
+    label: &'static str,
+    seeds: Vec<S>,
+    reply: Reply<Vec<SpawnResult<S>>>,
+}
+
+struct UniformRequest<S> {
+    id: usize,
+    seeds: Vec<S>,
+    reply: Reply<UniformResult<S>>,
+}
+
+/// Seeds sent to the loop from other threads
+#[doc(hidden)]
+pub struct RemoteQueue<S> {
+    requests: Arc<Mutex<Vec<Request<S>>>>,
+    groups: Arc<Mutex<Vec<GroupRequest<S>>>>,
+    uniform: Arc<Mutex<Vec<UniformRequest<S>>>>,
+    migrations: Arc<Mutex<Vec<Migration<S>>>>,
+    next_id: Arc<AtomicUsize>,
+}
+
+/// Closures sent to the loop from other threads, the flag is set for the
+/// ones sent with `ContextHandle::reconfigure`
+#[doc(hidden)]
+pub struct ActionQueue<C> {
+    actions: Arc<Mutex<Vec<(usize, ContextAction<C>, bool)>>>,
+    next_id: Arc<AtomicUsize>,
+}
+
+/// A handle to change the context of the loop from other threads
+///
+/// Created by `Loop::context_handle()` or `LoopInstance::context_handle()`.
+/// The handle is cloneable and may be sent to other threads. Unlike
+/// `SharedContext` the context isn't locked, the closures are run on the
+/// loop thread between the events.
+pub struct ContextHandle<C> {
+    queue: ActionQueue<C>,
+    channel: Channel,
+}
+
+/// A handle to the loop which can be used from other threads
+///
+/// Created by `Loop::handle()` or `LoopInstance::handle()`. The handle is
+/// cloneable and may be sent to other threads if the seed type is `Send`.
+pub struct LoopHandle<S> {
+    queue: RemoteQueue<S>,
+    channel: Channel,
+}
+
+impl<S> RemoteQueue<S> {
+    pub fn new() -> RemoteQueue<S> {
+        RemoteQueue {
+            requests: Arc::new(Mutex::new(Vec::new())),
+            groups: Arc::new(Mutex::new(Vec::new())),
+            uniform: Arc::new(Mutex::new(Vec::new())),
+            migrations: Arc::new(Mutex::new(Vec::new())),
+            next_id: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+    fn lock(&self) -> MutexGuard<Vec<Request<S>>> {
+        lock(&self.requests)
+    }
+    /// Takes all the queued seeds, the flag is set for the frozen ones
+    pub fn take(&self) -> Vec<(S, bool, Reply<SpawnResult<S>>)> {
+        self.lock().drain(..).map(|r| (r.seed, r.thaw, r.reply)).collect()
+    }
+    /// Takes all the queued migrations
+    pub fn take_migrations(&self)
+        -> Vec<(Token, LoopHandle<S>, Reply<MigrateResult<S>>)>
+    {
+        lock(&self.migrations).drain(..)
+            .map(|r| (r.token, r.target, r.reply))
+            .collect()
+    }
+    /// Takes all the queued group replacements
+    pub fn take_groups(&self)
+        -> Vec<(&'static str, Vec<S>, Reply<Vec<SpawnResult<S>>>)>
+    {
+        lock(&self.groups).drain(..)
+            .map(|r| (r.label, r.seeds, r.reply))
+            .collect()
+    }
+    /// Takes all the queued groups of uniform state machines
+    pub fn take_uniform(&self) -> Vec<(Vec<S>, Reply<UniformResult<S>>)> {
+        lock(&self.uniform).drain(..).map(|r| (r.seeds, r.reply)).collect()
+    }
+}
+
+impl<C> ActionQueue<C> {
+    pub fn new() -> ActionQueue<C> {
+        ActionQueue {
+            actions: Arc::new(Mutex::new(Vec::new())),
+            next_id: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+    /// Takes all the queued closures in the order of sending
+    pub fn take(&self) -> Vec<(ContextAction<C>, bool)> {
+        lock(&self.actions).drain(..)
+            .map(|(_, action, reconfigure)| (action, reconfigure))
+            .collect()
+    }
+}
+
+impl<C> Clone for ActionQueue<C> {
+    fn clone(&self) -> ActionQueue<C> {
+        ActionQueue {
+            actions: self.actions.clone(),
+            next_id: self.next_id.clone(),
+        }
+    }
+}
+
+pub fn create_context_handle<C>(queue: &ActionQueue<C>, channel: &Channel)
+    -> ContextHandle<C>
+{
+    ContextHandle {
+        queue: queue.clone(),
+        channel: channel.clone(),
+    }
+}
+
+impl<C> ContextHandle<C> {
+    /// Run the closure with the context on the loop thread
+    ///
+    /// Closures are run in the order of sending, on the next loop
+    /// iteration. Returns the closure back if the loop can't be notified.
+    pub fn send<F>(&self, fun: F) -> Result<(), ContextAction<C>>
+        where F: FnOnce(&mut C) + Send + 'static
+    {
+        self.push(Box::new(fun), false)
+    }
+    /// Install the new configuration into the context and notify all the
+    /// state machines
+    ///
+    /// The closure is run like the one passed to `send`, then every state
+    /// machine of the loop is called with `Machine::config_changed`. The
+    /// closures sent together are followed by a single round of calls.
+    ///
+    /// ```ignore
+    /// let limits = Limits::load(&path)?;
+    /// handle.reconfigure(move |ctx| ctx.limits = limits).ok();
+    /// ```
+    pub fn reconfigure<F>(&self, fun: F) -> Result<(), ContextAction<C>>
+        where F: FnOnce(&mut C) + Send + 'static
+    {
+        self.push(Box::new(fun), true)
+    }
+    fn push(&self, fun: ContextAction<C>, reconfigure: bool)
+        -> Result<(), ContextAction<C>>
+    {
+        let id = self.queue.next_id.fetch_add(1, Ordering::SeqCst);
+        lock(&self.queue.actions).push((id, fun, reconfigure));
+        if self.channel.send(Notify::Context).is_ok() {
+            return Ok(());
+        }
+        let mut actions = lock(&self.queue.actions);
+        match actions.iter().position(|&(x, _, _)| x == id) {
+            Some(pos) => Err(actions.remove(pos).1),
+            // Taken by the loop with some earlier notification
+            None => Ok(()),
+        }
+    }
+}
+
+impl<C> Clone for ContextHandle<C> {
+    fn clone(&self) -> ContextHandle<C> {
+        ContextHandle {
+            queue: self.queue.clone(),
+            channel: self.channel.clone(),
+        }
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<T> {
+    match mutex.lock() {
+        Ok(guard) => guard,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
+
+impl<S> Clone for RemoteQueue<S> {
+    fn clone(&self) -> RemoteQueue<S> {
+        RemoteQueue {
+            requests: self.requests.clone(),
+            groups: self.groups.clone(),
+            uniform: self.uniform.clone(),
+            migrations: self.migrations.clone(),
+            next_id: self.next_id.clone(),
+        }
+    }
+}
+
+pub fn create_handle<S>(queue: &RemoteQueue<S>, channel: &Channel)
+    -> LoopHandle<S>
+{
+    LoopHandle {
+        queue: queue.clone(),
+        channel: channel.clone(),
+    }
+}
+
+impl<S> LoopHandle<S> {
+    /// Create a state machine from the seed on the loop thread
+    ///
+    /// The state machine is created the same way as the ones spawned by
+    /// other state machines (`Machine::create` is called with the seed).
+    /// The result of the creation is sent to the returned receiver.
+    ///
+    /// Returns the seed back if the loop can't be notified (i.e. it's not
+    /// running any more or its notification queue is full).
+    pub fn spawn(&self, seed: S) -> Result<Receiver<SpawnResult<S>>, S> {
+        self.send_seed(seed, false)
+    }
+    /// Create a state machine from the seed returned by `Machine::freeze`
+    ///
+    /// The same as `spawn` but calls `Machine::thaw` instead of `create`.
+    /// Usually the `migrate` method is used instead.
+    pub fn thaw(&self, seed: S) -> Result<Receiver<SpawnResult<S>>, S> {
+        self.send_seed(seed, true)
+    }
+    /// Move the state machine to another loop
+    ///
+    /// On the loop thread, the state machine is converted into a seed with
+    /// `Machine::freeze`, and the seed is sent to the `target` loop where
+    /// the state machine is restored with `Machine::thaw`. Both loops must
+    /// have the same state machine type (contexts are usually similar
+    /// but separate).
+    ///
+    /// The result is sent to the returned receiver. Returns `None` if the
+    /// loop can't be notified.
+    pub fn migrate(&self, token: Token, target: &LoopHandle<S>)
+        -> Option<Receiver<MigrateResult<S>>>
+    {
+        let (tx, rx) = channel();
+        lock(&self.queue.migrations).push(Migration {
+            token: token,
+            target: target.clone(),
+            reply: tx,
+        });
+        // If notification fails the request is dropped with the loop
+        self.channel.send(Notify::Spawn).ok().map(|()| rx)
+    }
+    fn send_seed(&self, seed: S, thaw: bool)
+        -> Result<Receiver<SpawnResult<S>>, S>
+    {
+        let (tx, rx) = channel();
+        let id = self.queue.next_id.fetch_add(1, Ordering::SeqCst);
+        self.queue.lock().push(Request {
+            id: id,
+            seed: seed,
+            thaw: thaw,
+            reply: tx,
+        });
+        if self.channel.send(Notify::Spawn).is_ok() {
+            return Ok(rx);
+        }
+        let mut requests = self.queue.lock();
+        match requests.iter().position(|r| r.id == id) {
+            Some(pos) => Err(requests.remove(pos).seed),
+            // Taken by the loop with some earlier notification
+            None => Ok(rx),
+        }
+    }
+    /// Create `n` state machines from the seeds made by the `factory`
+    ///
+    /// The factory is called with the index of each state machine (`0`
+    /// to `n - 1`) on the calling thread. On the loop thread the state
+    /// machines are created in order, and their notifiers are sent to the
+    /// returned receiver, e.g. to distribute the work between the workers.
+    /// State machines which are done right in `Machine::create` are left
+    /// out.
+    ///
+    /// If the slab can't fit all `n` state machines, none is created and
+    /// `NoSlabSpace` is sent with the first seed. Otherwise they are
+    /// created one by one, and if some seed fails, the state machines
+    /// already created are aborted (their `create` has run by then) and
+    /// the error is sent with that seed, the rest of the seeds are
+    /// dropped.
+    ///
+    /// Returns the seeds back if the loop can't be notified.
+    pub fn spawn_uniform<F>(&self, n: usize, mut factory: F)
+        -> Result<Receiver<UniformResult<S>>, Vec<S>>
+        where F: FnMut(usize) -> S
+    {
+        let (tx, rx) = channel();
+        let id = self.queue.next_id.fetch_add(1, Ordering::SeqCst);
+        lock(&self.queue.uniform).push(UniformRequest {
+            id: id,
+            seeds: (0..n).map(|idx| factory(idx)).collect(),
+            reply: tx,
+        });
+        if self.channel.send(Notify::Spawn).is_ok() {
+            return Ok(rx);
+        }
+        let mut uniform = lock(&self.queue.uniform);
+        match uniform.iter().position(|r| r.id == id) {
+            Some(pos) => Err(uniform.remove(pos).seeds),
+            // Taken by the loop with some earlier notification
+            None => Ok(rx),
+        }
+    }
+    /// Replace the group of state machines with the new ones
+    ///
+    /// On the loop thread, all the state machines having the `label` (see
+    /// `Scope::set_label`) are marked as retiring and woken up (see
+    /// `Scope::is_retiring`), and then the state machines are created from
+    /// the `seeds`. So the retiring listeners may close their sockets
+    /// before the new ones bind the same addresses. The new state machines
+    /// should set the same label to be replaced on the next reload.
+    ///
+    /// The results of creation are sent to the returned receiver in the
+    /// order of the seeds. Returns the seeds back if the loop can't be
+    /// notified.
+    pub fn replace_group(&self, label: &'static str, seeds: Vec<S>)
+        -> Result<Receiver<Vec<SpawnResult<S>>>, Vec<S>>
+    {
+        let (tx, rx) = channel();
+        let id = self.queue.next_id.fetch_add(1, Ordering::SeqCst);
+        lock(&self.queue.groups).push(GroupRequest {
+            id: id,
+            label: label,
+            seeds: seeds,
+            reply: tx,
+        });
+        if self.channel.send(Notify::Spawn).is_ok() {
+            return Ok(rx);
+        }
+        let mut groups = lock(&self.queue.groups);
+        match groups.iter().position(|r| r.id == id) {
+            Some(pos) => Err(groups.remove(pos).seeds),
+            // Taken by the loop with some earlier notification
+            None => Ok(rx),
+        }
+    }
+}
+
+impl<S> LoopHandle<S> {
+    /// Stop the loop gracefully, waiting at most `grace` for state machines
+    ///
+    /// Same as `ShutdownHandle::graceful_shutdown`: every state machine
+    /// receives a `wakeup` and sees `Scope::is_shutting_down()` returning
+    /// true, listeners (`Accept`, `LimitedListener`) stop accepting right
+    /// away. The state machines which are still there when `grace` is
+    /// over are removed (calling `Machine::destroyed`) and the loop stops.
+    /// Returns false if the loop can't be notified.
+    pub fn drain(&self, grace: Duration) -> bool {
+        self.channel.send(Notify::Drain(grace)).is_ok()
+    }
+    /// Request a debug dump of all the state machines of the loop
+    ///
+    /// The dump has a line per state machine with its token, metadata and
+    /// the `Debug` representation of the state. It's sent to the returned
+    /// receiver on the next loop iteration. The loop must allow this with
+    /// `LoopInstance::enable_remote_dump`. Returns `None` if the loop can't
+    /// be notified.
+    pub fn dump_machines(&self) -> Option<Receiver<String>> {
+        let (tx, rx) = channel();
+        self.channel.send(Notify::Dump(tx)).ok().map(|()| rx)
+    }
+    /// Request a snapshot of the loop
+    ///
+    /// The snapshot (see `LoopInstance::write_snapshot`) is sent to the
+    /// returned receiver on the next loop iteration. Returns `None` if the
+    /// loop can't be notified.
+    pub fn snapshot(&self) -> Option<Receiver<io::Result<Vec<u8>>>> {
+        let (tx, rx) = channel();
+        self.channel.send(Notify::Snapshot(tx)).ok().map(|()| rx)
+    }
+    /// Request statistics of all the state machines of the loop
+    ///
+    /// The statistics are sent to the returned receiver on the next loop
+    /// iteration. Returns `None` if the loop can't be notified.
+    #[cfg(feature="machine_stats")]
+    pub fn machine_stats(&self)
+        -> Option<Receiver<Vec<(Token, MachineStats)>>>
+    {
+        let (tx, rx) = channel();
+        self.channel.send(Notify::Stats(tx)).ok().map(|()| rx)
+    }
+    /// Request the latency histograms of the loop
+    ///
+    /// The histograms are sent to the returned receiver on the next loop
+    /// iteration, `None` is sent if `Config::latency_histograms` is not
+    /// enabled. Returns `None` if the loop can't be notified.
+    pub fn latency(&self) -> Option<Receiver<Option<LoopLatency>>> {
+        let (tx, rx) = channel();
+        self.channel.send(Notify::Latency(tx)).ok().map(|()| rx)
+    }
+    /// Request the occupancy of the state machine slab
+    ///
+    /// The occupancy is sent to the returned receiver on the next loop
+    /// iteration. Returns `None` if the loop can't be notified.
+    pub fn slab_occupancy(&self) -> Option<Receiver<SlabOccupancy>> {
+        let (tx, rx) = channel();
+        self.channel.send(Notify::Occupancy(tx)).ok().map(|()| rx)
+    }
+    /// Make the loop return from the poll right away
+    ///
+    /// Nothing is dispatched to the state machines, but the poll hooks are
+    /// run and `LoopInstance::run_once` returns. Useful to run the work
+    /// of the embedding application (see `Config::max_poll_timeout`)
+    /// without waiting for the IO. Returns false if the loop can't be
+    /// notified.
+    pub fn interrupt(&self) -> bool {
+        self.channel.send(Notify::Interrupt).is_ok()
+    }
+}
+
+impl<S> Clone for LoopHandle<S> {
+    fn clone(&self) -> LoopHandle<S> {
+        LoopHandle {
+            queue: self.queue.clone(),
+            channel: self.channel.clone(),
+        }
+    }
+}
@@ -1,18 +1,96 @@
-use std::fmt::Debug;
+use std::fmt::{self, Debug};
 use std::error::Error;
 
 use mio::Token;
-
-use {Response, Time};
+use {Response, Time, SpawnError, EarlyScope, Machine, Scope};
 
 
 #[derive(Debug)]
 pub enum ResponseImpl<M, N> {
     Normal(M),
+    #[cfg(not(feature="no_deadlines"))]
     Deadline(M, Time),
+    Yield(M),
     Spawn(M, N),
+    SpawnLink(M, N),
+    // Boxed to keep the `Response` small, batches are rare anyway
+    SpawnMany(M, Box<Vec<N>>),
     Error(Box<Error>),
+    // The state machine is passed to `Machine::error`
+    Fail(Box<(M, Box<Error>)>),
     Done,
+    // Boxed too, deferring is rare
+    Deferred(Box<WithDeferred<M, N>>),
+}
+
+/// A closure passed to `Response::defer`
+pub type DeferredFn = Box<FnOnce(&mut EarlyScope)>;
+
+pub struct WithDeferred<M, N> {
+    response: ResponseImpl<M, N>,
+    closures: Vec<DeferredFn>,
+}
+
+impl<M: Debug, N: Debug> Debug for WithDeferred<M, N> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("WithDeferred")
+            .field("response", &self.response)
+            .field("closures", &self.closures.len())
+            .finish()
+    }
+}
+
+/// Looks through the closures passed to `Response::defer`, they are never
+/// nested
+fn without_deferred<M, N>(imp: &ResponseImpl<M, N>) -> &ResponseImpl<M, N> {
+    match *imp {
+        ResponseImpl::Deferred(ref deferred) => &deferred.response,
+        ref imp => imp,
+    }
+}
+
+/// Separates the closures passed to `Response::defer`
+fn strip_deferred<M, N>(imp: ResponseImpl<M, N>)
+    -> (ResponseImpl<M, N>, Vec<DeferredFn>)
+{
+    match imp {
+        ResponseImpl::Deferred(deferred) => {
+            let WithDeferred { response, closures } = *deferred;
+            (response, closures)
+        }
+        imp => (imp, Vec::new()),
+    }
+}
+
+/// Replaces the response keeping the closures passed to `Response::defer`
+fn keep_deferred<M, N, T, U, F>(deferred: Box<WithDeferred<M, N>>, f: F)
+    -> ResponseImpl<T, U>
+    where F: FnOnce(Response<M, N>) -> Response<T, U>
+{
+    let WithDeferred { response, closures } = *deferred;
+    ResponseImpl::Deferred(Box::new(WithDeferred {
+        response: f(Response(response)).0,
+        closures: closures,
+    }))
+}
+
+/// Seeds returned from a state machine action
+pub enum Seeds<N> {
+    One(Option<N>),
+    /// The parent watches the child, see `Response::spawn_link`
+    Linked(Option<N>),
+    Many(::std::vec::IntoIter<N>),
+}
+
+impl<N> Iterator for Seeds<N> {
+    type Item = N;
+    fn next(&mut self) -> Option<N> {
+        match *self {
+            Seeds::One(ref mut x) => x.take(),
+            Seeds::Linked(ref mut x) => x.take(),
+            Seeds::Many(ref mut iter) => iter.next(),
+        }
+    }
 }
 
 impl<M: Sized, N:Sized> Response<M, N> {
@@ -22,6 +100,74 @@ impl<M: Sized, N:Sized> Response<M, N> {
     pub fn spawn(machine: M, result: N) -> Response<M, N> {
         Response(ResponseImpl::Spawn(machine, result))
     }
+    /// Spawn a state machine and watch it
+    ///
+    /// Same as `spawn`, and if the child is created, the parent watches it
+    /// (see `Scope::watch`) from the start. So the parent receives a
+    /// single `wakeup` when the child is removed, and the token of the
+    /// child is returned from `Scope::take_gone_peers`, even if the child
+    /// exits in its first action. If the child isn't created, the parent
+    /// gets `spawn_error` as usual and no wakeup.
+    pub fn spawn_link(machine: M, result: N) -> Response<M, N> {
+        Response(ResponseImpl::SpawnLink(machine, result))
+    }
+    /// Spawn multiple state machines at once
+    ///
+    /// All the seeds are passed to `Machine::create` in order, then
+    /// `Machine::spawned` is called once for the whole batch (if at least
+    /// one state machine has been created). Each seed that failed is
+    /// reported to `Machine::spawn_error` as usual.
+    ///
+    /// This is useful for listeners accepting multiple connections per
+    /// `ready()` call. Empty vector is equivalent to `Response::ok`.
+    pub fn spawn_many(machine: M, seeds: Vec<N>) -> Response<M, N> {
+        if seeds.is_empty() {
+            Response(ResponseImpl::Normal(machine))
+        } else {
+            Response(ResponseImpl::SpawnMany(machine, Box::new(seeds)))
+        }
+    }
+    /// Continue the state machine on the next loop iteration
+    ///
+    /// The state machine receives a `wakeup()` after the events received
+    /// in the current iteration are processed, and the loop doesn't block
+    /// in poll until that. This is useful to split a large chunk of work
+    /// (e.g. parsing a big buffer) so that other state machines aren't
+    /// delayed. Use `Scope::run_again` to continue before the next poll
+    /// instead.
+    ///
+    /// The deadline of the state machine is cleared, the same as with
+    /// `Response::ok`.
+    pub fn yield_(machine: M) -> Response<M, N> {
+        Response(ResponseImpl::Yield(machine))
+    }
+    /// Run the closure after the action of the state machine completes
+    ///
+    /// The closure is called with a new scope of the same state machine
+    /// when the action has returned, the state machine is put back into
+    /// the loop (or removed), and the seeds are spawned. So it may do what
+    /// can't be done while the state machine is dispatched, e.g. wake up
+    /// the state machines found in the `Registry` knowing that the state
+    /// of this one is already updated. Closures are called in order,
+    /// before any other event is dispatched, even if the state machine
+    /// has stopped.
+    ///
+    /// The scope has no context, the closure must capture what it needs.
+    pub fn defer<F>(self, f: F) -> Response<M, N>
+        where F: FnOnce(&mut EarlyScope) + 'static
+    {
+        let imp = match self.0 {
+            ResponseImpl::Deferred(mut deferred) => {
+                deferred.closures.push(Box::new(f));
+                ResponseImpl::Deferred(deferred)
+            }
+            imp => ResponseImpl::Deferred(Box::new(WithDeferred {
+                response: imp,
+                closures: vec![Box::new(f)],
+            })),
+        };
+        Response(imp)
+    }
     pub fn done() -> Response<M, N> {
         Response::<M, N>(ResponseImpl::Done)
     }
@@ -38,11 +184,33 @@ impl<M: Sized, N:Sized> Response<M, N> {
         Response::<M, N>(ResponseImpl::Error(e))
     }
 
+    /// Stop the state machine with an error, after `Machine::error`
+    ///
+    /// Same as `Response::error`, but the state machine is passed to
+    /// `Machine::error` before it's removed, so it may report the error to
+    /// the peer (e.g. write an error response to the socket) or replace
+    /// the error.
+    pub fn fail(machine: M, e: Box<Error>) -> Response<M, N> {
+        Response::<M, N>(ResponseImpl::Fail(Box::new((machine, e))))
+    }
+
+    /// Call `Machine::timeout` at `time` unless the state machine returns
+    /// another deadline before
+    ///
+    /// Not available with the `no_deadlines` feature, which removes the
+    /// deadline of every state machine. Use `Scope::timeout_ms` instead.
+    #[cfg(not(feature="no_deadlines"))]
     pub fn deadline(self, time: Time) -> Response<M, N> {
         let imp = match self.0 {
             ResponseImpl::Normal(x) => ResponseImpl::Deadline(x, time),
             ResponseImpl::Deadline(x, _) => ResponseImpl::Deadline(x, time),
-            ResponseImpl::Spawn(..) => {
+            ResponseImpl::Yield(..) => {
+                panic!("You can't attach a deadline/timeout to the \
+                    Response::yield_(). The state machine is woken up on \
+                    the next loop iteration anyway, set a deadline there.");
+            }
+            ResponseImpl::Spawn(..) | ResponseImpl::SpawnLink(..) |
+            ResponseImpl::SpawnMany(..) => {
                 panic!("You can't attach a deadline/timeout to the \
                     Response::spawn(). The `spawn` action is synchronous \
                     you must set a deadline in the `spawned` handler."); }
@@ -51,11 +219,14 @@ impl<M: Sized, N:Sized> Response<M, N> {
                     Response::done() as it's useless. \
                     Timeout will never happen");
             }
-            ResponseImpl::Error(_) => {
+            ResponseImpl::Error(_) | ResponseImpl::Fail(_) => {
                 panic!("You can't attach a deadline/timeout to \
                     Response::error(_) as it's useless. \
                     Timeout will never happen");
             }
+            ResponseImpl::Deferred(deferred) => {
+                keep_deferred(deferred, |r| r.deadline(time))
+            }
         };
         Response(imp)
     }
@@ -63,6 +234,12 @@ impl<M: Sized, N:Sized> Response<M, N> {
     ///
     /// Usually it's okay to use constructor of wrapper state machine
     /// here as a mapper
+    ///
+    /// # Panics
+    ///
+    /// The `result_mapper` is called at most once, so it can't map the
+    /// seeds of `Response::spawn_many`. The method panics on such
+    /// response, use `map_many` if the state machine may spawn a batch.
     pub fn map<T, U,  S, R>(self, self_mapper: S, result_mapper: R)
         -> Response<T, U>
         where S: FnOnce(M) -> T,
@@ -71,10 +248,57 @@ impl<M: Sized, N:Sized> Response<M, N> {
         use self::ResponseImpl::*;
         let imp = match self.0 {
             Normal(m) => Normal(self_mapper(m)),
+            #[cfg(not(feature="no_deadlines"))]
+            Deadline(m, time) => Deadline(self_mapper(m), time),
+            Yield(m) => Yield(self_mapper(m)),
+            Spawn(m, n) => Spawn(self_mapper(m), result_mapper(n)),
+            SpawnLink(m, n) => SpawnLink(self_mapper(m), result_mapper(n)),
+            SpawnMany(..) => {
+                panic!("You can't map Response::spawn_many() with \
+                    Response::map(), the seeds mapper is called once. \
+                    Use Response::map_many() instead");
+            }
+            Done => Done,
+            Error(e) => Error(e),
+            Fail(failed) => {
+                let (m, e) = *failed;
+                Fail(Box::new((self_mapper(m), e)))
+            }
+            Deferred(deferred) => keep_deferred(deferred,
+                |r| r.map(self_mapper, result_mapper)),
+        };
+        Response(imp)
+    }
+    /// Same as `map` but the seeds mapper may be called multiple times
+    ///
+    /// This maps every seed of `Response::spawn_many`, so state machine
+    /// combinators should use this method rather than `map`.
+    pub fn map_many<T, U,  S, R>(self, self_mapper: S, result_mapper: R)
+        -> Response<T, U>
+        where S: FnOnce(M) -> T,
+              R: FnMut(N) -> U,
+    {
+        use self::ResponseImpl::*;
+        let mut result_mapper = result_mapper;
+        let imp = match self.0 {
+            Normal(m) => Normal(self_mapper(m)),
+            #[cfg(not(feature="no_deadlines"))]
             Deadline(m, time) => Deadline(self_mapper(m), time),
+            Yield(m) => Yield(self_mapper(m)),
             Spawn(m, n) => Spawn(self_mapper(m), result_mapper(n)),
+            SpawnLink(m, n) => SpawnLink(self_mapper(m), result_mapper(n)),
+            SpawnMany(m, seeds) => {
+                let seeds = (*seeds).into_iter().map(result_mapper).collect();
+                SpawnMany(self_mapper(m), Box::new(seeds))
+            }
             Done => Done,
             Error(e) => Error(e),
+            Fail(failed) => {
+                let (m, e) = *failed;
+                Fail(Box::new((self_mapper(m), e)))
+            }
+            Deferred(deferred) => keep_deferred(deferred,
+                |r| r.map_many(self_mapper, result_mapper)),
         };
         Response(imp)
     }
@@ -88,13 +312,48 @@ impl<M: Sized, N:Sized> Response<M, N> {
         use self::ResponseImpl::*;
         let imp = match self.0 {
             Normal(m) => Normal(self_mapper(m)),
+            #[cfg(not(feature="no_deadlines"))]
             Deadline(m, time) => Deadline(self_mapper(m), time),
+            Yield(m) => Yield(self_mapper(m)),
             Spawn(m, n) => Spawn(self_mapper(m), n),
+            SpawnLink(m, n) => SpawnLink(self_mapper(m), n),
+            SpawnMany(m, seeds) => SpawnMany(self_mapper(m), seeds),
             Done => Done,
             Error(e) => Error(e),
+            Fail(failed) => {
+                let (m, e) = *failed;
+                Fail(Box::new((self_mapper(m), e)))
+            }
+            Deferred(deferred) => {
+                keep_deferred(deferred, |r| r.wrap(self_mapper))
+            }
         };
         Response(imp)
     }
+    /// Similar to `map` but only maps the seed
+    ///
+    /// Panics on `Response::spawn_many` as `map` does, use
+    /// `map_seeds` for it.
+    pub fn map_seed<U, R>(self, result_mapper: R) -> Response<M, U>
+        where R: FnOnce(N) -> U,
+    {
+        self.map(|m| m, result_mapper)
+    }
+    /// Similar to `map_many` but only maps the seeds
+    pub fn map_seeds<U, R>(self, result_mapper: R) -> Response<M, U>
+        where R: FnMut(N) -> U,
+    {
+        self.map_many(|m| m, result_mapper)
+    }
+    /// Same as `wrap` with the `Into` conversion of the state machine
+    ///
+    /// Useful when the wrapper state machine implements `From` for the
+    /// wrapped ones.
+    pub fn wrap_into<T>(self) -> Response<T, N>
+        where M: Into<T>
+    {
+        self.wrap(Into::into)
+    }
 
     /// Returns true if state machine is stopped
     ///
@@ -102,12 +361,18 @@ impl<M: Sized, N:Sized> Response<M, N> {
     /// `Response::done` or `Response::error`
     pub fn is_stopped(&self) -> bool {
         use self::ResponseImpl::*;
-        match self.0 {
+        match *without_deferred(&self.0) {
             Normal(..) => false,
+            #[cfg(not(feature="no_deadlines"))]
             Deadline(..) => false,
+            Yield(..) => false,
             Spawn(..) => false,
+            SpawnLink(..) => false,
+            SpawnMany(..) => false,
             Done => true,
             Error(..) => true,
+            Fail(..) => true,
+            Deferred(..) => unreachable!(),
         }
     }
 
@@ -118,12 +383,18 @@ impl<M: Sized, N:Sized> Response<M, N> {
     /// This is mostly useful for printing the error.
     pub fn cause(&self) -> Option<&Error> {
         use self::ResponseImpl::*;
-        match self.0 {
+        match *without_deferred(&self.0) {
             Normal(..) => None,
+            #[cfg(not(feature="no_deadlines"))]
             Deadline(..) => None,
+            Yield(..) => None,
             Spawn(..) => None,
+            SpawnLink(..) => None,
+            SpawnMany(..) => None,
             Done => None,
             Error(ref e) => Some(&**e),
+            Fail(ref failed) => Some(&*failed.1),
+            Deferred(..) => unreachable!(),
         }
     }
 }
@@ -135,44 +406,95 @@ impl<M: Sized + Debug, N: Sized + Debug> Response<M, N> {
     ///
     /// If the response is not okay, the function panics.
     pub fn expect_machine(self) -> M {
-        match self.0 {
+        match strip_deferred(self.0).0 {
             ResponseImpl::Normal(x) => x,
+            #[cfg(not(feature="no_deadlines"))]
             ResponseImpl::Deadline(x, _) => x,
             me => panic!("expected machine (`Response::ok(x)`), \
                 got {:?} instead", me),
         }
     }
+    /// Return state machine if response created with `Response::yield_(..)`
+    ///
+    /// *Use only for unit tests*
+    ///
+    /// If the response is not `yield_`, the function panics.
+    pub fn expect_yield(self) -> M {
+        match strip_deferred(self.0).0 {
+            ResponseImpl::Yield(x) => x,
+            me => panic!("expected yield (`Response::yield_(x)`), \
+                got {:?} instead", me),
+        }
+    }
     /// Return a tuple if response created with `Response::spawn(..)`
     ///
     /// *Use only for unit tests*
     ///
     /// If the response is not `spawn`, the function panics.
     pub fn expect_spawn(self) -> (M, N) {
-        match self.0 {
+        match strip_deferred(self.0).0 {
             ResponseImpl::Spawn(x, y) => (x, y),
             me => panic!("expected spawn (`Response::spawn(x)`), \
                 got {:?} instead", me),
         }
     }
+    /// Return a tuple if response created with `Response::spawn_link(..)`
+    ///
+    /// *Use only for unit tests*
+    ///
+    /// If the response is not `spawn_link`, the function panics.
+    pub fn expect_spawn_link(self) -> (M, N) {
+        match strip_deferred(self.0).0 {
+            ResponseImpl::SpawnLink(x, y) => (x, y),
+            me => panic!("expected spawn_link (`Response::spawn_link(x)`), \
+                got {:?} instead", me),
+        }
+    }
+    /// Return a machine and seeds if response created with
+    /// `Response::spawn_many(..)`
+    ///
+    /// *Use only for unit tests*
+    ///
+    /// If the response is not `spawn_many`, the function panics.
+    pub fn expect_spawn_many(self) -> (M, Vec<N>) {
+        match strip_deferred(self.0).0 {
+            ResponseImpl::SpawnMany(x, y) => (x, *y),
+            me => panic!("expected spawn_many (`Response::spawn_many(x)`), \
+                got {:?} instead", me),
+        }
+    }
     /// Returns if response created with `Response::done()`
     ///
     /// *Use only for unit tests*
     ///
     /// If the response is not done, the function panics.
     pub fn expect_done(self) {
-        match self.0 {
+        match strip_deferred(self.0).0 {
             ResponseImpl::Done => {}
             me => panic!("expected done (`Response::done()`), \
                 got {:?} instead", me),
         }
     }
+    /// Returns a machine and an error if response created with
+    /// `Response::fail(..)`
+    ///
+    /// *Use only for unit tests*
+    ///
+    /// If the response is not `fail`, the function panics.
+    pub fn expect_fail(self) -> (M, Box<Error>) {
+        match strip_deferred(self.0).0 {
+            ResponseImpl::Fail(failed) => *failed,
+            me => panic!("expected fail (`Response::fail(m, e)`), \
+                got {:?} instead", me),
+        }
+    }
     /// Returns an error if response created with `Response::error(..)`
     ///
     /// *Use only for unit tests*
     ///
     /// If the response does not contain error, the function panics.
     pub fn expect_error(self) -> Box<Error> {
-        match self.0 {
+        match strip_deferred(self.0).0 {
             ResponseImpl::Error(e) => e,
             me => panic!("expected error (`Response::error(e)`), \
                 got {:?} instead", me),
@@ -180,29 +502,145 @@ impl<M: Sized + Debug, N: Sized + Debug> Response<M, N> {
     }
 }
 
-pub fn decompose<M, N>(token: Token, res: Response<M, N>)
-    -> (Result<M, Option<Box<Error>>>, Option<N>, Option<Time>)
+/// Calls `Machine::error` if the state machine stops with `Response::fail`
+pub fn call_error_hook<M: Machine, N>(res: Response<M, N>,
+    scope: &mut Scope<M::Context>)
+    -> Response<M, N>
 {
     match res.0 {
-        ResponseImpl::Normal(m) => (Ok(m), None, None),
-        ResponseImpl::Deadline(m, time) => (Ok(m), None, Some(time)),
-        ResponseImpl::Spawn(m, n) => (Ok(m), Some(n), None),
-        ResponseImpl::Done => (Err(None), None, None),
-        ResponseImpl::Error(e) => {
-            if cfg!(feature = "log_errors") {
-                warn!("State machine {:?} exited with error: {}", token, e);
-            }
-            (Err(Some(e)), None, None)
+        ResponseImpl::Fail(failed) => {
+            let (m, e) = *failed;
+            Response::error(m.error(e, scope))
+        }
+        ResponseImpl::Deferred(mut deferred) => {
+            deferred.response = call_error_hook(Response(deferred.response),
+                                                scope).0;
+            Response(ResponseImpl::Deferred(deferred))
         }
+        imp => Response(imp),
+    }
+}
+
+/// Takes the closures passed to `Response::defer` out of the response
+pub fn take_deferred<M, N>(res: Response<M, N>)
+    -> (Response<M, N>, Vec<DeferredFn>)
+{
+    let (imp, closures) = strip_deferred(res.0);
+    (Response(imp), closures)
+}
+
+/// Splits the response into the machine, seeds, deadline, and the flag
+/// which is set if the machine has yielded
+pub fn decompose<M, N>(res: Response<M, N>)
+    -> (Result<M, Option<Box<Error>>>, Option<Seeds<N>>, Option<Time>, bool)
+{
+    // The closures are taken by `take_deferred` beforehand
+    match strip_deferred(res.0).0 {
+        ResponseImpl::Normal(m) => (Ok(m), None, None, false),
+        #[cfg(not(feature="no_deadlines"))]
+        ResponseImpl::Deadline(m, time) => (Ok(m), None, Some(time), false),
+        ResponseImpl::Yield(m) => (Ok(m), None, None, true),
+        ResponseImpl::Spawn(m, n) => {
+            (Ok(m), Some(Seeds::One(Some(n))), None, false)
+        }
+        ResponseImpl::SpawnLink(m, n) => {
+            (Ok(m), Some(Seeds::Linked(Some(n))), None, false)
+        }
+        ResponseImpl::SpawnMany(m, seeds) => {
+            (Ok(m), Some(Seeds::Many((*seeds).into_iter())), None, false)
+        }
+        ResponseImpl::Done => (Err(None), None, None, false),
+        // The error is reported by the caller (see `Diagnostics`)
+        ResponseImpl::Error(e) => (Err(Some(e)), None, None, false),
+        // Only when `Machine::error` can't be called, i.e. in `EarlyScope`
+        ResponseImpl::Fail(failed) => (Err(Some(failed.1)), None, None, false),
+        ResponseImpl::Deferred(..) => unreachable!(),
+    }
+}
+
+/// Converts the stopped response of the constructor into the spawn result
+///
+/// `Response::done()` from the constructor is fine: nothing is created.
+pub fn constructor_error<S>(token: Token, err: Option<Box<Error>>)
+    -> Result<(), SpawnError<S>>
+{
+    match err {
+        Some(e) => Err(SpawnError::ConstructorError(token, e)),
+        None => Ok(()),
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::super::Response;
+    use super::take_deferred;
 
     #[test]
     fn size_of_response() {
         assert_eq!(::std::mem::size_of::<Response<u64, u64>>(), 24)
     }
+
+    #[test]
+    fn map_seed() {
+        let seed = String::from("seed");
+        let (m, seed) = Response::<u8, u8>::spawn(1, 2)
+            .map_seed(move |x| (x, seed))
+            .expect_spawn();
+        assert_eq!((m, seed), (1, (2, String::from("seed"))));
+    }
+
+    #[test]
+    fn map_seeds() {
+        let (m, seeds) = Response::<u8, u8>::spawn_many(1, vec![2, 3])
+            .map_seeds(|x| x as u64 * 10)
+            .expect_spawn_many();
+        assert_eq!((m, seeds), (1, vec![20, 30]));
+        let (m, seeds) = Response::<u8, u8>::spawn_many(1, vec![2, 3])
+            .map_many(|m| m as u64, |x| x as u64 + 1)
+            .expect_spawn_many();
+        assert_eq!((m, seeds), (1, vec![3, 4]));
+    }
+
+    #[test]
+    #[should_panic]
+    fn map_spawn_many() {
+        Response::<u8, u8>::spawn_many(1, vec![2, 3]).map(|m| m, |x| x);
+    }
+
+    #[test]
+    fn wrap_into() {
+        let (m, seed) = Response::<u8, u8>::spawn(1, 2)
+            .wrap_into::<u64>()
+            .expect_spawn();
+        assert_eq!((m, seed), (1u64, 2));
+        Response::<u8, u8>::done().wrap_into::<u64>().expect_done();
+        let (m, seed) = Response::<u8, u8>::spawn_link(1, 2)
+            .wrap_into::<u64>()
+            .expect_spawn_link();
+        assert_eq!((m, seed), (1u64, 2));
+    }
+
+    #[test]
+    fn fail() {
+        let err = ::std::io::Error::new(::std::io::ErrorKind::Other, "oops");
+        let resp = Response::<u8, u8>::fail(1, Box::new(err))
+            .wrap(|m| m as u64 + 1);
+        assert!(resp.is_stopped());
+        assert_eq!(resp.cause().map(|e| e.to_string()),
+                   Some("oops".to_string()));
+        let (m, err) = resp.expect_fail();
+        assert_eq!((m, err.to_string()), (2, "oops".to_string()));
+    }
+
+    #[test]
+    fn defer() {
+        let resp = Response::<u8, u8>::ok(1)
+            .defer(|_| {})
+            .wrap(|m| m as u64 + 1)
+            .defer(|_| {});
+        assert!(!resp.is_stopped());
+        let (resp, closures) = take_deferred(resp);
+        assert_eq!(closures.len(), 2);
+        assert_eq!(resp.expect_machine(), 2);
+    }
 }
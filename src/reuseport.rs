@@ -0,0 +1,121 @@
+use std::io;
+use std::net::SocketAddr;
+use std::thread::JoinHandle;
+
+use mio::tcp::TcpListener;
+
+use {Machine, Config};
+use creator::fork_loop;
+use config::indexed_config;
+
+
+/// Bind a listening socket with `SO_REUSEPORT` (and `SO_REUSEADDR`) set
+///
+/// Many sockets may be bound to the same address this way, and the kernel
+/// distributes incoming connections between them. Requires the `reuseport`
+/// feature and works on unix only, otherwise an error is returned.
+#[cfg(all(feature="reuseport", unix))]
+pub fn bind_reuseport(addr: &SocketAddr) -> io::Result<TcpListener> {
+    use std::mem;
+    use std::net;
+    use std::os::unix::io::FromRawFd;
+    use libc;
+
+    fn cvt(res: libc::c_int) -> io::Result<()> {
+        if res < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    unsafe {
+        let family = match *addr {
+            SocketAddr::V4(..) => libc::AF_INET,
+            SocketAddr::V6(..) => libc::AF_INET6,
+        };
+        let fd = libc::socket(family, libc::SOCK_STREAM, 0);
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // Owns the descriptor, so it's closed on errors
+        let sock = net::TcpListener::from_raw_fd(fd);
+        let one: libc::c_int = 1;
+        for &opt in &[libc::SO_REUSEADDR, libc::SO_REUSEPORT] {
+            try!(cvt(libc::setsockopt(fd, libc::SOL_SOCKET, opt,
+                &one as *const libc::c_int as *const libc::c_void,
+                mem::size_of::<libc::c_int>() as libc::socklen_t)));
+        }
+        let res = match *addr {
+            SocketAddr::V4(ref a) => {
+                let mut sa: libc::sockaddr_in = mem::zeroed();
+                sa.sin_family = libc::AF_INET as libc::sa_family_t;
+                sa.sin_port = a.port().to_be();
+                sa.sin_addr = libc::in_addr {
+                    s_addr: u32::from(*a.ip()).to_be(),
+                };
+                libc::bind(fd,
+                    &sa as *const libc::sockaddr_in as *const libc::sockaddr,
+                    mem::size_of::<libc::sockaddr_in>() as libc::socklen_t)
+            }
+            SocketAddr::V6(ref a) => {
+                let mut sa: libc::sockaddr_in6 = mem::zeroed();
+                sa.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+                sa.sin6_port = a.port().to_be();
+                sa.sin6_addr = libc::in6_addr { s6_addr: a.ip().octets() };
+                sa.sin6_flowinfo = a.flowinfo();
+                sa.sin6_scope_id = a.scope_id();
+                libc::bind(fd,
+                    &sa as *const libc::sockaddr_in6 as *const libc::sockaddr,
+                    mem::size_of::<libc::sockaddr_in6>() as libc::socklen_t)
+            }
+        };
+        try!(cvt(res));
+        try!(cvt(libc::listen(fd, 1024)));
+        TcpListener::from_listener(sock, addr)
+    }
+}
+
+/// Bind a listening socket with `SO_REUSEPORT` (and `SO_REUSEADDR`) set
+#[cfg(not(all(feature="reuseport", unix)))]
+pub fn bind_reuseport(_addr: &SocketAddr) -> io::Result<TcpListener> {
+    Err(io::Error::new(io::ErrorKind::Other,
+        "SO_REUSEPORT requires the `reuseport` feature (unix only)"))
+}
+
+/// Start `loops` loops in new threads, each accepting on its own socket
+/// bound to the same address
+///
+/// The sockets are bound with `bind_reuseport`, so the kernel balances
+/// connections between the loops. Every loop gets the context returned by
+/// `context(index)` and a single state machine created from the seed
+/// `seed(listener)`, usually some listener machine, e.g. `LimitedListener`.
+///
+/// All the sockets are bound before any thread is started, so address
+/// errors are returned without starting anything. If port of the `addr` is
+/// zero, the port chosen for the first socket is used for the rest.
+///
+/// If `Config::loop_id` is set, the index of the loop is appended to it.
+///
+/// Returns thread handles just like `Scope::fork_loop`.
+pub fn reuseport_loops<M, F>(cfg: &Config, addr: &SocketAddr, loops: usize,
+    mut context: F, seed: fn(TcpListener) -> M::Seed)
+    -> io::Result<Vec<JoinHandle<Option<M::Context>>>>
+    where M: Machine + 'static,
+          M::Context: Send + 'static,
+          M::Seed: Send + 'static,
+          F: FnMut(usize) -> M::Context,
+{
+    let mut addr = *addr;
+    let mut listeners = Vec::with_capacity(loops);
+    for _ in 0..loops {
+        let sock = try!(bind_reuseport(&addr));
+        if addr.port() == 0 {
+            addr = try!(sock.local_addr());
+        }
+        listeners.push(sock);
+    }
+    listeners.into_iter().enumerate().map(|(idx, sock)| {
+        fork_loop::<M>(indexed_config(cfg, idx), context(idx),
+            vec![seed(sock)])
+    }).collect()
+}
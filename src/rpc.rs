@@ -0,0 +1,143 @@
+use std::fmt;
+
+use channel::Sender;
+
+
+/// Identifies the request made with `Caller::call`
+///
+/// Ids are unique for the `Caller` which has made the request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct RequestId(u64);
+
+/// The request received by the serving state machine
+///
+/// Create the channel for the requests with `Scope::channel()`, and pass
+/// the `Sender<Request<Q, R>>` to the callers. Answer with `reply` right
+/// away, or `split` the request and keep the `Responder` to reply later.
+pub struct Request<Q, R> {
+    body: Q,
+    responder: Responder<R>,
+}
+
+/// Sends the reply of a single request back to the caller
+///
+/// May be kept by the state machine until the reply is ready, or sent to
+/// another thread if the value is `Send`.
+pub struct Responder<R> {
+    id: RequestId,
+    reply_to: Sender<Reply<R>>,
+}
+
+/// The reply received by the calling state machine, see `Caller`
+#[derive(Debug)]
+pub struct Reply<R> {
+    /// The id returned by `Caller::call`
+    pub id: RequestId,
+    pub value: R,
+}
+
+/// Makes requests to other state machines and routes the replies back
+///
+/// Created by `Scope::caller()` together with the `Receiver` of the
+/// replies. Every reply wakes up the calling state machine, which should
+/// drain the receiver on `wakeup` and match the replies by the id:
+///
+/// ```ignore
+/// let id = try!(self.caller.call(&self.storage, Get(key)));
+/// self.waiting.insert(id, client);
+/// // ... then in wakeup
+/// for reply in self.replies.drain() {
+///     if let Some(client) = self.waiting.remove(&reply.id) {
+///         // ...
+///     }
+/// }
+/// ```
+///
+/// The reply never comes if the serving state machine drops the request
+/// without answering, so set a deadline for the outstanding requests.
+pub struct Caller<R> {
+    reply_to: Sender<Reply<R>>,
+    next_id: u64,
+}
+
+pub fn create_caller<R>(reply_to: Sender<Reply<R>>) -> Caller<R> {
+    Caller {
+        reply_to: reply_to,
+        next_id: 0,
+    }
+}
+
+impl<R> Caller<R> {
+    /// Send the request to the state machine owning the `target` channel
+    ///
+    /// Returns the request body back if the receiver is dropped.
+    pub fn call<Q>(&mut self, target: &Sender<Request<Q, R>>, body: Q)
+        -> Result<RequestId, Q>
+    {
+        let id = RequestId(self.next_id);
+        self.next_id += 1;
+        let request = Request {
+            body: body,
+            responder: Responder {
+                id: id,
+                reply_to: self.reply_to.clone(),
+            },
+        };
+        match target.send(request) {
+            Ok(()) => Ok(id),
+            Err(request) => Err(request.body),
+        }
+    }
+}
+
+impl<Q, R> Request<Q, R> {
+    /// The id of the request, as returned to the caller
+    pub fn id(&self) -> RequestId {
+        self.responder.id
+    }
+    pub fn body(&self) -> &Q {
+        &self.body
+    }
+    /// Separate the body from the means to reply
+    pub fn split(self) -> (Q, Responder<R>) {
+        (self.body, self.responder)
+    }
+    /// Reply to the caller, see `Responder::reply`
+    pub fn reply(self, value: R) -> Result<(), R> {
+        self.responder.reply(value)
+    }
+}
+
+impl<R> Responder<R> {
+    /// The id of the request, as returned to the caller
+    pub fn id(&self) -> RequestId {
+        self.id
+    }
+    /// Send the reply and wake up the caller
+    ///
+    /// Returns the value back if the caller's receiver is dropped.
+    pub fn reply(self, value: R) -> Result<(), R> {
+        let reply = Reply {
+            id: self.id,
+            value: value,
+        };
+        self.reply_to.send(reply).map_err(|reply| reply.value)
+    }
+}
+
+impl<Q: fmt::Debug, R> fmt::Debug for Request<Q, R> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Request")
+            .field("id", &self.responder.id)
+            .field("body", &self.body)
+            .finish()
+    }
+}
+
+impl<R> fmt::Debug for Responder<R> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Responder")
+            .field("id", &self.id)
+            .finish()
+    }
+}
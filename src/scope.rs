@@ -1,16 +1,41 @@
 use std::io;
+use std::mem;
+use std::any::Any;
 use std::ops::{Deref, DerefMut};
-use std::time::{SystemTime};
+use std::time::{SystemTime, Instant};
+use std::thread::JoinHandle;
+use std::time::Duration;
+use std::path::PathBuf;
+#[cfg(unix)] use std::os::unix::io::AsRawFd;
 
 use mio::Token;
-use mio::deprecated::Sender;
 
-use handler::Notify;
 use loop_api::LoopApi;
-use loop_time::{estimate_system_time};
-use notify::create_notifier;
-use {Notifier, Time};
-use {Evented, EventSet, PollOpt, Timeout, TimerError};
+use loop_state::{LoopState, MachineMeta, set_label, clear_pause};
+use loop_state::{set_priority, set_idle_timeout, set_parent_exit};
+use loop_state::set_coarse;
+#[cfg(unix)] use loop_state::{set_pause, set_primary, clear_primary};
+#[cfg(unix)] use waker::{Waker, Wakers};
+use loop_time::{estimate_system_time, estimate_time, millis};
+use loop_time::{make_time, make_instant};
+use future::create_future;
+use notify::notifier_target;
+use registry::unregister;
+use fileio::{read_at, write_at};
+use cancel::{CancelToken, CancelGuard, create_guard};
+use metrics::{TimerMetric, Histogram, LoopLatency, SlabOccupancy};
+use promise::{Promise, Answer, create_promise};
+use channel::{Sender, Receiver, create_channel};
+use rpc::{Caller, Reply, create_caller};
+use creator::fork_loop;
+use config::{fork_config, poll_opt, coarse_granularity_ms};
+use trace::{EventCursor, DispatchEvent};
+use budget::{ConnectSlot, create_slot};
+use readiness::with_hangup;
+use diagnostics::Diagnostic;
+use {Notifier, WakeupError, Time, Future, Machine, SubContext, Backoff};
+use {Evented, EventSet, PollOpt, Timeout, TimerError, PollErrors};
+#[cfg(feature="machine_stats")] use MachineStats;
 
 /// The structure passed to every action handler
 ///
@@ -20,6 +45,7 @@ use {Evented, EventSet, PollOpt, Timeout, TimerError};
 /// 2. Register timeouts
 /// 3. Create a special `Notifier` object to wakeup sibling state machines
 /// 4. Access to global state of the loop (Context)
+/// 5. Offload blocking work to a thread pool
 ///
 /// All methods here operate on **enclosed state machine**, which means the
 /// state machine that was called with this scope. Or in other words the
@@ -34,11 +60,36 @@ use {Evented, EventSet, PollOpt, Timeout, TimerError};
 pub struct Scope<'a, C:Sized+'a>{
     token: Token,
     ctx: &'a mut C,
-    channel: &'a mut Sender<Notify>,
+    state: &'a mut LoopState,
     loop_api: &'a mut LoopApi,
     time: Time,
 }
 
+/// The timer which has fired, see `Scope::timeout_kind`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimeoutKind {
+    /// The deadline returned with the `Response`
+    Deadline,
+    /// The idle timeout set by `Scope::set_idle_timeout`
+    Idle,
+}
+
+/// What happens to the state machine when its parent is removed
+///
+/// The parent is the state machine which has spawned this one with the
+/// `Response::spawn` or `Response::spawn_many`. See
+/// `Scope::set_parent_exit`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParentExit {
+    /// Nothing happens, `Scope::is_orphaned` returns true afterwards
+    Ignore,
+    /// The state machine receives a `wakeup` and should check
+    /// `Scope::is_orphaned`
+    Notify,
+    /// The state machine is removed with `Machine::destroyed`
+    Stop,
+}
+
 /// This is a structure that works similarly to Scope, but doesn't
 /// have a context
 ///
@@ -47,8 +98,21 @@ pub struct Scope<'a, C:Sized+'a>{
 /// is useful if you want to put a `Notifier` of the FSM to a context itself.
 pub struct EarlyScope<'a> {
     token: Token,
-    channel: &'a mut Sender<Notify>,
+    state: &'a mut LoopState,
+    loop_api: &'a mut LoopApi,
+}
+
+/// The scope passed to the timers of the loop
+///
+/// Loop timers are added with `LoopInstance::add_loop_timer` and don't
+/// belong to any state machine, so there is no socket to register and no
+/// `Notifier` of its own. The scope gives access to the time and the
+/// registry of the loop, and controls the timer itself.
+pub struct LoopScope<'a> {
+    state: &'a mut LoopState,
     loop_api: &'a mut LoopApi,
+    time: Time,
+    interval: &'a mut Option<Duration>,
 }
 
 /// A common part of `Scope` and `EarlyScope`
@@ -99,12 +163,17 @@ pub trait GenericScope {
 
 impl<'a, C:Sized+'a> Scope<'a, C> {
 
+    /// Register the socket in the loop for the enclosed state machine
     pub fn register(&mut self, io: &Evented, interest: EventSet, opt: PollOpt)
         -> io::Result<()>
     {
         self.loop_api.register(io, self.token, interest, opt)
     }
 
+    /// Change interest set or poll options of the registered socket
+    ///
+    /// For example, switch from `readable` to `writable` when there is
+    /// some data to send.
     pub fn reregister(&mut self, io: &Evented,
         interest: EventSet, opt: PollOpt)
         -> io::Result<()>
@@ -112,18 +181,85 @@ impl<'a, C:Sized+'a> Scope<'a, C> {
         self.loop_api.reregister(io, self.token, interest, opt)
     }
 
+    /// Remove the socket from the loop
+    ///
+    /// No events are delivered for the socket until it's registered again.
+    /// Sockets must be deregistered before they are passed to another
+    /// state machine.
     pub fn deregister(&mut self, io: &Evented) -> io::Result<()>
     {
         self.loop_api.deregister(io)
     }
 
+    /// Register the socket and deregister it when the machine is removed
+    ///
+    /// Same as `register`, and then the loop remembers the file
+    /// descriptor. When the state machine is done, fails or is aborted,
+    /// the socket is deregistered even if it's still alive somewhere
+    /// (e.g. kept in the context), so it can't deliver events to the
+    /// token reused by another state machine.
+    ///
+    /// Close the socket only after `deregister_tracked`, or after the
+    /// machine is removed: if the descriptor is reused by another
+    /// tracked socket in the meantime, the stale entry is dropped, but an
+    /// untracked one would be deregistered by mistake.
+    #[cfg(unix)]
+    pub fn register_tracked<E>(&mut self, io: &E,
+        interest: EventSet, opt: PollOpt)
+        -> io::Result<()>
+        where E: Evented + AsRawFd
+    {
+        try!(self.loop_api.register(io, self.token, interest, opt));
+        self.state.track(self.token, io.as_raw_fd());
+        Ok(())
+    }
+
+    /// Deregister the socket registered with `register_tracked`
+    #[cfg(unix)]
+    pub fn deregister_tracked<E>(&mut self, io: &E) -> io::Result<()>
+        where E: Evented + AsRawFd
+    {
+        self.state.untrack(self.token, io.as_raw_fd());
+        self.loop_api.deregister(io)
+    }
+
+    /// Default poll options of the loop, see `Config::poll_opt`
+    pub fn poll_opt(&self) -> PollOpt {
+        poll_opt(&self.state.config)
+    }
+
+    /// Register the socket with the default poll options of the loop
+    ///
+    /// On unix the hangup and error events are added to the `interest`,
+    /// see `Readiness`.
+    pub fn register_default(&mut self, io: &Evented, interest: EventSet)
+        -> io::Result<()>
+    {
+        let opt = self.poll_opt();
+        self.loop_api.register(io, self.token, with_hangup(interest), opt)
+    }
+
+    /// Reregister the socket with the default poll options of the loop
+    ///
+    /// Adds hangup and error events the same way as `register_default`.
+    pub fn reregister_default(&mut self, io: &Evented, interest: EventSet)
+        -> io::Result<()>
+    {
+        let opt = self.poll_opt();
+        self.loop_api.reregister(io, self.token, with_hangup(interest), opt)
+    }
+
     /// Add timeout
     ///
     /// This method is **deprecated** use return value of your state machine's
     /// action to set a timeout
+    ///
+    /// Never returns an error, as timers have no capacity limit. The
+    /// `Result` is kept for backwards compatibility.
     pub fn timeout_ms(&mut self, delay: u64) -> Result<Timeout, TimerError>
     {
-        self.loop_api.timeout_ms(self.token, delay)
+        let generation = self.state.generation(self.token);
+        Ok(self.loop_api.timeout_ms(self.token, generation, delay))
     }
 
     /// Clear timeout
@@ -135,9 +271,842 @@ impl<'a, C:Sized+'a> Scope<'a, C> {
         self.loop_api.clear_timeout(token)
     }
 
+    /// Stop reading from the socket for the specified duration
+    ///
+    /// The socket is reregistered with `interest` without the readable
+    /// flag, and reregistered with the full `interest` when the duration
+    /// expires. So `interest` and `opt` are what the socket should be
+    /// registered with after the pause. This is useful for read throttling.
+    ///
+    /// Calling the method again while paused restarts the pause. The
+    /// socket must be kept registered until reading is resumed, or
+    /// `resume_reading` must be called before the socket is closed.
+    #[cfg(unix)]
+    pub fn pause_reading_for<E>(&mut self, io: &E,
+        interest: EventSet, opt: PollOpt, duration: Duration)
+        -> io::Result<()>
+        where E: Evented + AsRawFd
+    {
+        try!(self.loop_api.reregister(io, self.token,
+            interest - EventSet::readable(), opt));
+        let generation = self.state.generation(self.token);
+        let timeout = self.loop_api.resume_timeout_ms(self.token, generation,
+            io.as_raw_fd(), interest, opt, millis(duration));
+        let ref mut loop_api = self.loop_api;
+        if let Some(meta) = self.state.get_mut(self.token) {
+            set_pause(meta, timeout, *loop_api);
+        }
+        Ok(())
+    }
+
+    /// Resume reading paused by `pause_reading_for` before timer expires
+    ///
+    /// The socket is reregistered with `interest` (even if reading wasn't
+    /// paused, for simplicity).
+    pub fn resume_reading(&mut self, io: &Evented,
+        interest: EventSet, opt: PollOpt)
+        -> io::Result<()>
+    {
+        let ref mut loop_api = self.loop_api;
+        if let Some(meta) = self.state.get_mut(self.token) {
+            clear_pause(meta, *loop_api);
+        }
+        loop_api.reregister(io, self.token, interest, opt)
+    }
+
+    /// Share the socket between this state machine and the `writer`
+    ///
+    /// Full-duplex protocols may read and write with independent state
+    /// machines. The socket is registered once, for this (the reader)
+    /// state machine, with both readable and writable interest,
+    /// edge-triggered. The loop sends the writable events to the writer,
+    /// the rest to the reader, and hangup and error to both of them. So
+    /// each side keeps reading or writing until `WouldBlock`, and waits
+    /// for the next event.
+    ///
+    /// The writer needs a handle to the same socket, e.g. the one
+    /// created with `TcpStream::try_clone`, or an `Rc` shared by both.
+    ///
+    /// The two state machines watch each other (see `watch`): when either
+    /// of them is removed, the other one is woken up and finds the token
+    /// in `take_gone_peers`, so it can shut down its half of the
+    /// connection. The socket is tracked (see `register_tracked`) and
+    /// deregistered together with the reader.
+    ///
+    /// Fails with `NotFound` if the writer is removed already.
+    ///
+    /// ```ignore
+    /// let writer = try!(sock.try_clone());
+    /// let notifier = /* spawn the writer owning the `writer` socket */;
+    /// try!(scope.register_split(&sock, &notifier));
+    /// ```
+    #[cfg(unix)]
+    pub fn register_split<E>(&mut self, io: &E, writer: &Notifier)
+        -> io::Result<()>
+        where E: Evented + AsRawFd
+    {
+        let (target, generation) = notifier_target(writer);
+        if !self.state.is_current(target, generation) || target == self.token
+        {
+            return Err(io::Error::new(io::ErrorKind::NotFound,
+                "writer state machine is removed"));
+        }
+        let interest = with_hangup(EventSet::readable() |
+                                   EventSet::writable());
+        try!(self.register_tracked(io, interest, PollOpt::edge()));
+        self.state.split(self.token, target, generation);
+        Ok(())
+    }
+
+    /// Register the primary socket of the state machine
+    ///
+    /// Same as `register`, and then the loop keeps the registration in
+    /// sync with `Machine::interest`: after every action the socket is
+    /// reregistered if the interest returned differs from the current
+    /// one. Only one socket is the primary one, the new call replaces the
+    /// previous socket.
+    ///
+    /// Call `clear_primary` before the socket is closed or deregistered,
+    /// the loop keeps the file descriptor only.
+    #[cfg(unix)]
+    pub fn register_primary<E>(&mut self, io: &E,
+        interest: EventSet, opt: PollOpt)
+        -> io::Result<()>
+        where E: Evented + AsRawFd
+    {
+        try!(self.loop_api.register(io, self.token, interest, opt));
+        if let Some(meta) = self.state.get_mut(self.token) {
+            set_primary(meta, io.as_raw_fd(), interest, opt);
+        }
+        Ok(())
+    }
+
+    /// Stop reregistering the socket set by `register_primary`
+    #[cfg(unix)]
+    pub fn clear_primary(&mut self) {
+        if let Some(meta) = self.state.get_mut(self.token) {
+            clear_primary(meta);
+        }
+    }
+
     /// Create a `Notifier` that may be used to `wakeup` enclosed state machine
     pub fn notifier(&self) -> Notifier {
-        create_notifier(self.token, self.channel)
+        self.state.notifier(self.token)
+    }
+
+    /// Wake up the enclosed state machine on the next loop iteration
+    ///
+    /// Same as returning `Response::yield_` but may be called from any
+    /// action, e.g. when the machine is also waiting for a deadline. It
+    /// doesn't go through the notification channel, so it can't fail.
+    /// Multiple calls before the wakeup result in a single one.
+    pub fn wakeup_self(&mut self) {
+        self.state.schedule_wakeup(self.token);
+    }
+
+    /// Wake up the enclosed state machine before the next poll
+    ///
+    /// Unlike `wakeup_self` (and `Response::yield_`), the wakeup is
+    /// delivered in the current loop iteration: after the ready events,
+    /// timeouts, yielded wakeups and ticks of the iteration are processed,
+    /// and before the loop polls again. The requests are served in the
+    /// order they are made, and the ones made during the wakeups are
+    /// appended to the same queue, so the loop doesn't poll while some
+    /// state machine keeps asking to run again. Multiple calls before the
+    /// wakeup result in a single one. The deadline is kept.
+    ///
+    /// With `Config::transition_budget` the wakeup is delivered on the
+    /// next iteration when the state machine has spent its budget.
+    pub fn run_again(&mut self) {
+        self.state.schedule_immediate(self.token);
+    }
+
+    /// Stop delivering ready events to the enclosed state machine
+    ///
+    /// The events are held (merged together) until `resume` is called,
+    /// and then delivered in a single `ready` on the next iteration. The
+    /// state machine is kept alive, and timeouts and wakeups are delivered
+    /// as usual. This is useful for flow control, e.g. a proxy stops
+    /// reading from one side until the other side drains its buffer.
+    ///
+    /// The primary socket (see `register_primary`) is reregistered with
+    /// empty interest while paused. Other sockets are left registered,
+    /// so the level-triggered ones make the loop spin: register them
+    /// edge-triggered or deregister them before pausing.
+    ///
+    /// Returns false if the state machine is paused already.
+    pub fn pause(&mut self) -> bool {
+        let generation = self.state.generation(self.token);
+        self.state.pause(self.token, generation, self.loop_api)
+    }
+
+    /// Deliver the events held since `pause`
+    ///
+    /// Returns false if the state machine isn't paused.
+    pub fn resume(&mut self) -> bool {
+        let generation = self.state.generation(self.token);
+        self.state.resume(self.token, generation, self.loop_api)
+    }
+
+    /// Returns true if the state machine is paused by `pause`
+    pub fn is_paused(&self) -> bool {
+        self.state.get(self.token).map(|m| m.is_paused()).unwrap_or(false)
+    }
+
+    /// Pause other state machine of the loop, see `pause`
+    ///
+    /// Returns false if the state machine is removed or already paused.
+    pub fn pause_machine(&mut self, notifier: &Notifier) -> bool {
+        let (target, generation) = notifier_target(notifier);
+        self.state.pause(target, generation, self.loop_api)
+    }
+
+    /// Resume other state machine paused by `pause` or `pause_machine`
+    ///
+    /// Returns false if the state machine is removed or isn't paused.
+    pub fn resume_machine(&mut self, notifier: &Notifier) -> bool {
+        let (target, generation) = notifier_target(notifier);
+        self.state.resume(target, generation, self.loop_api)
+    }
+
+    /// Remove other state machine of the loop
+    ///
+    /// Same as `Notifier::abort`, the state machine is removed on the next
+    /// loop iteration.
+    pub fn abort(&mut self, notifier: &Notifier) -> Result<(), WakeupError> {
+        notifier.abort()
+    }
+
+    /// Watch the cancellation of the token
+    ///
+    /// The enclosed state machine receives a `wakeup` when the token is
+    /// cancelled while the guard is alive (or right away if it's already
+    /// cancelled). Check `CancelGuard::is_cancelled()` there.
+    pub fn cancel_guard(&mut self, token: &CancelToken) -> CancelGuard {
+        create_guard(token, self)
+    }
+
+    /// Create a `Waker` that may be used to `wakeup` enclosed state machine
+    /// from a signal handler
+    ///
+    /// The first call creates a socket pair owned by the loop.
+    #[cfg(unix)]
+    pub fn waker(&mut self) -> io::Result<Waker> {
+        if self.state.wakers.is_none() {
+            let wakers = try!(Wakers::new());
+            try!(self.loop_api.register_waker(wakers.fd(),
+                                              self.state.waker_token));
+            self.state.wakers = Some(wakers);
+        }
+        let generation = self.state.generation(self.token);
+        let wakers = self.state.wakers.as_mut()
+            .expect("wakers are just created");
+        Ok(wakers.create(self.token, generation))
+    }
+
+    /// Run a blocking function in the offload thread pool
+    ///
+    /// When the function returns, the enclosed state machine receives a
+    /// `wakeup` and the result is available from the returned `Future`.
+    /// Use this for disk IO, password hashing and other work that would
+    /// otherwise stall the whole loop.
+    ///
+    /// # Panics
+    ///
+    /// Panics if offloading is disabled (`Config::offload_threads` is zero)
+    pub fn execute<T, F>(&mut self, fun: F) -> Future<T>
+        where T: Send + 'static,
+              F: FnOnce() -> T + Send + 'static,
+    {
+        let (port, future) = create_future(self.notifier());
+        self.state.pool.execute(move || {
+            // Nobody to report the error to, the loop is probably shut down
+            port.try_set(fun()).ok();
+        });
+        future
+    }
+
+    /// Run a blocking file operation in the file IO thread pool
+    ///
+    /// Same as `execute`, but uses the pool sized by
+    /// `Config::file_io_threads`, so disk operations don't occupy the
+    /// offload threads.
+    pub fn execute_file_io<T, F>(&mut self, fun: F) -> Future<T>
+        where T: Send + 'static,
+              F: FnOnce() -> T + Send + 'static,
+    {
+        let (port, future) = create_future(self.notifier());
+        self.state.file_pool.execute(move || {
+            port.try_set(fun()).ok();
+        });
+        future
+    }
+
+    /// Read up to `len` bytes of the file starting at `offset`
+    ///
+    /// The file is read in the file IO thread pool, the enclosed state
+    /// machine receives a `wakeup` when the data is ready. Less data is
+    /// returned only at the end of file.
+    pub fn read_file<P: Into<PathBuf>>(&mut self, path: P, offset: u64,
+        len: usize)
+        -> Future<io::Result<Vec<u8>>>
+    {
+        let path = path.into();
+        self.execute_file_io(move || read_at(&path, offset, len))
+    }
+
+    /// Write the data to the file at `offset`
+    ///
+    /// The file is created if it doesn't exist. The write is done in the
+    /// file IO thread pool, the enclosed state machine receives a `wakeup`
+    /// when it's finished. The buffer is returned along with the result,
+    /// so it may be reused.
+    pub fn write_file<P: Into<PathBuf>>(&mut self, path: P, offset: u64,
+        data: Vec<u8>)
+        -> Future<(Vec<u8>, io::Result<()>)>
+    {
+        let path = path.into();
+        self.execute_file_io(move || {
+            let res = write_at(&path, offset, &data);
+            (data, res)
+        })
+    }
+
+    /// Start another loop in a new thread with the specified seeds
+    ///
+    /// The new loop uses the same `Config` as this one (except the CPU
+    /// pinning, see `fork_loop_pinned`) and its own `context` (usually
+    /// cloned or derived from this one). State machines are created from
+    /// the `seeds` just like spawned ones. This is useful to move a noisy
+    /// workload out of the current loop at runtime.
+    ///
+    /// The thread returns the context when the loop is finished, or `None`
+    /// if the loop failed (errors are logged with `log_errors` feature).
+    /// Seeds that can't be turned into state machines are dropped.
+    pub fn fork_loop<M>(&self, context: M::Context, seeds: Vec<M::Seed>)
+        -> io::Result<JoinHandle<Option<M::Context>>>
+        where M: Machine + 'static,
+              M::Context: Send + 'static,
+              M::Seed: Send + 'static,
+    {
+        fork_loop::<M>(fork_config(&self.state.config, None), context, seeds)
+    }
+
+    /// Same as `fork_loop` but pins the new loop thread to the CPU
+    ///
+    /// See `Config::pin_to_cpu` for more info.
+    pub fn fork_loop_pinned<M>(&self, cpu: usize,
+        context: M::Context, seeds: Vec<M::Seed>)
+        -> io::Result<JoinHandle<Option<M::Context>>>
+        where M: Machine + 'static,
+              M::Context: Send + 'static,
+              M::Seed: Send + 'static,
+    {
+        fork_loop::<M>(fork_config(&self.state.config, Some(cpu)),
+            context, seeds)
+    }
+
+    /// Take a slot for an outgoing connection to the destination
+    ///
+    /// The `destination` is any string identifying the backend, e.g.
+    /// `"10.0.0.1:80"`. Limits are set by `Config::connect_limit` and
+    /// `Config::destination_connect_limit`.
+    ///
+    /// Returns `None` if the limit is reached. In this case the enclosed
+    /// state machine is queued and receives a `wakeup` when a slot for the
+    /// destination is freed, so it should call this method again on
+    /// wakeup (the slot isn't reserved for it). Also, wakeups can be
+    /// spurious, as usual.
+    pub fn connect_slot(&mut self, destination: &str) -> Option<ConnectSlot> {
+        create_slot(&self.state.connects, &self.state.channel,
+            self.token, self.state.generation(self.token), destination)
+    }
+
+    /// Take a buffer from the pool of the loop
+    ///
+    /// The buffer is empty and preallocated in the NUMA node of the loop
+    /// if `Config::buffer_pool` is set. If the pool is exhausted (or not
+    /// configured) the buffer is allocated, so this never fails.
+    pub fn take_buffer(&mut self) -> Vec<u8> {
+        self.state.buffers.take()
+    }
+
+    /// Give the buffer taken with `take_buffer` back to the pool
+    pub fn return_buffer(&mut self, buf: Vec<u8>) {
+        self.state.buffers.give_back(buf)
+    }
+
+    /// Create a cursor to read the history of dispatched events
+    ///
+    /// The cursor starts `replay` events back (limited by what is still
+    /// in the history), so a diagnostics client attaching to a live loop
+    /// can see what has been happening recently. Subsequent events are read
+    /// with `read_events`. The history is disabled unless
+    /// `Config::event_history` is set.
+    pub fn event_cursor(&self, replay: usize) -> EventCursor {
+        self.state.events.cursor(replay)
+    }
+
+    /// Read events dispatched since the last read of the cursor
+    ///
+    /// There is no notification of new events, so read it periodically
+    /// (e.g. on timeout of the subscribed state machine).
+    pub fn read_events(&self, cursor: &mut EventCursor) -> Vec<DispatchEvent> {
+        self.state.events.read(cursor)
+    }
+
+    /// Create a single-threaded promise resolved by another state machine
+    ///
+    /// Keep the `Promise` and pass the `Answer` to the other state machine
+    /// in the same loop. When it sends the value, the enclosed state
+    /// machine receives a `wakeup`.
+    pub fn promise<T>(&self) -> (Promise<T>, Answer<T>) {
+        create_promise(self.notifier())
+    }
+
+    /// Create a channel to send values to the enclosed state machine
+    ///
+    /// The `Sender` may be cloned and sent to other state machines or
+    /// threads. Every message wakes up the enclosed state machine, which
+    /// should drain the `Receiver` on `wakeup`.
+    pub fn channel<T>(&self) -> (Sender<T>, Receiver<T>) {
+        create_channel(self.notifier())
+    }
+
+    /// Create a `Caller` to make requests to other state machines
+    ///
+    /// Replies to the requests wake up the enclosed state machine and are
+    /// received from the returned `Receiver` with the id of the request.
+    pub fn caller<R>(&self) -> (Caller<R>, Receiver<Reply<R>>) {
+        let (tx, rx) = create_channel(self.notifier());
+        (create_caller(tx), rx)
+    }
+
+    /// Latency histograms of the loop
+    ///
+    /// Returns `None` unless `Config::latency_histograms` is enabled.
+    pub fn latency(&self) -> Option<&LoopLatency> {
+        self.state.latency.as_ref()
+    }
+
+    /// Set a label for the enclosed state machine
+    ///
+    /// The label is visible in `for_each_machine` and is useful to
+    /// distinguish kinds of machines in maintenance and statistics code.
+    pub fn set_label(&mut self, label: &'static str) {
+        if let Some(meta) = self.state.get_mut(self.token) {
+            set_label(meta, label);
+        }
+    }
+
+    /// The deadline currently armed for the enclosed state machine
+    ///
+    /// This is the deadline of the previous `Response`, the one returned
+    /// from the current action replaces it. Returns `None` if there is no
+    /// deadline, or it has just fired (i.e. in `Machine::timeout`).
+    pub fn current_deadline(&self) -> Option<Time> {
+        self.state.get(self.token).and_then(|meta| meta.deadline())
+    }
+
+    /// Allow rounding of the deadlines of the enclosed state machine
+    ///
+    /// Deadlines returned with the `Response` are rounded up to
+    /// `Config::coarse_timer_granularity` (a second by default). Useful for
+    /// long timeouts which are moved often, e.g. a keep-alive timeout
+    /// moved on every request: the timer is touched once per interval
+    /// instead. Applies to the following deadlines.
+    pub fn set_coarse_deadline(&mut self, coarse: bool) {
+        let granularity = if coarse {
+            Some(coarse_granularity_ms(&self.state.config))
+        } else {
+            None
+        };
+        if let Some(meta) = self.state.get_mut(self.token) {
+            set_coarse(meta, granularity);
+        }
+    }
+
+    /// Set the idle timeout of the enclosed state machine
+    ///
+    /// Unlike the deadline returned with the `Response`, which is a fixed
+    /// point in time, the idle timer is restarted on every ready event of
+    /// the state machine. So it fires when there is no activity on the
+    /// sockets for `idle`, e.g. to close the inactive keep-alive
+    /// connections. Both timers call `Machine::timeout`, use
+    /// `timeout_kind` to tell them apart. `None` disables the timer.
+    pub fn set_idle_timeout(&mut self, idle: Option<Duration>) {
+        let ref mut loop_api = self.loop_api;
+        if let Some(meta) = self.state.get_mut(self.token) {
+            set_idle_timeout(meta, self.token, idle, *loop_api);
+        }
+    }
+
+    /// The state machine which has spawned the enclosed one
+    ///
+    /// Returns `None` for the state machines added to the loop directly,
+    /// and after the parent is removed.
+    pub fn parent(&self) -> Option<Token> {
+        self.state.get(self.token).and_then(|meta| meta.parent())
+    }
+
+    /// Postpone the creation of the enclosed state machine until
+    /// `finish_creation` is called
+    ///
+    /// Call it in `Machine::create` when the state machine needs to
+    /// connect or resolve something before it's usable. Until then the
+    /// state machine receives events as usual, but it's not reported to
+    /// the parent: the parent's `Machine::spawned` is called with the
+    /// child when the creation is finished. If the state machine stops
+    /// before that, the parent receives `Machine::spawn_error` with
+    /// `SpawnError::ConstructorError` for `Response::error()`, and nothing
+    /// for `Response::done()`. The state machines added to the loop
+    /// directly have nobody to notify, so this only changes
+    /// `is_creating`.
+    pub fn suspend_creation(&mut self) {
+        self.state.suspend_creation(self.token);
+    }
+
+    /// Finish the creation suspended with `suspend_creation`
+    ///
+    /// Does nothing if the creation isn't suspended. Called in `create`
+    /// it just cancels `suspend_creation`.
+    pub fn finish_creation(&mut self) {
+        self.state.finish_creation(self.token, Ok(()));
+    }
+
+    /// Returns true between `suspend_creation` and `finish_creation`
+    pub fn is_creating(&self) -> bool {
+        self.state.is_creating(self.token)
+    }
+
+    /// Returns true if the parent of the enclosed state machine is removed
+    pub fn is_orphaned(&self) -> bool {
+        self.state.get(self.token).map(|meta| meta.is_orphaned())
+            .unwrap_or(false)
+    }
+
+    /// Set what happens when the parent of the enclosed state machine is
+    /// removed
+    ///
+    /// By default (`ParentExit::Ignore`) children outlive their parent.
+    /// Use `ParentExit::Stop` to tear down the connections of a
+    /// listener together with it, for example. The parent is removed when
+    /// it returns `Response::done()` or `Response::error()`, or is moved
+    /// to another loop. Usually called in `Machine::create`.
+    pub fn set_parent_exit(&mut self, policy: ParentExit) {
+        if let Some(meta) = self.state.get_mut(self.token) {
+            set_parent_exit(meta, policy);
+        }
+    }
+
+    /// Wake up the enclosed state machine when another one is removed
+    ///
+    /// The `notifier` identifies the watched state machine, which must be
+    /// in the same loop. When it returns `Response::done()` or
+    /// `Response::error()`, or is removed in any other way, the enclosed
+    /// state machine receives a `wakeup` and its token is returned from
+    /// `take_gone_peers`. If the peer is already gone, the wakeup is
+    /// delivered right away. Useful for proxies and request/response
+    /// pairs where one side must clean up when the other disappears.
+    pub fn watch(&mut self, notifier: &Notifier) {
+        let (target, generation) = notifier_target(notifier);
+        self.state.watch(self.token, target, generation);
+    }
+
+    /// Stop watching the state machine, see `watch`
+    pub fn unwatch(&mut self, notifier: &Notifier) {
+        let (target, generation) = notifier_target(notifier);
+        self.state.unwatch(self.token, target, generation);
+    }
+
+    /// Tokens of the watched state machines removed since the last call
+    ///
+    /// Call it on `wakeup` of the watching state machine.
+    pub fn take_gone_peers(&mut self) -> Vec<Token> {
+        self.state.take_gone(self.token)
+    }
+
+    /// Attach a value of type `T` to the enclosed state machine
+    ///
+    /// There is a single slot for every type, the previous value is
+    /// returned. Useful for wrappers which need to keep auxiliary data
+    /// without changing the type of the inner state machine. The values
+    /// are dropped when the state machine is removed.
+    pub fn set_local<T: Any>(&mut self, value: T) -> Option<T> {
+        self.state.get_mut(self.token)
+            .and_then(|meta| meta.set_local(value))
+    }
+
+    /// The value of type `T` attached with `set_local`
+    pub fn local<T: Any>(&mut self) -> Option<&mut T> {
+        self.state.get_mut(self.token).and_then(|meta| meta.local())
+    }
+
+    /// Detach the value of type `T` from the enclosed state machine
+    pub fn take_local<T: Any>(&mut self) -> Option<T> {
+        self.state.get_mut(self.token).and_then(|meta| meta.take_local())
+    }
+
+    /// Register the enclosed state machine under the well-known name
+    ///
+    /// Other state machines find it with `lookup_name`, other threads with
+    /// the `Registry` of the loop. The name is removed when the state
+    /// machine is removed. Returns false if the name is taken by another
+    /// state machine.
+    pub fn register_name(&mut self, name: &str) -> bool {
+        self.state.register_name(self.token, name)
+    }
+
+    /// Remove the name registered by the enclosed state machine
+    pub fn unregister_name(&mut self, name: &str) {
+        let generation = self.state.generation(self.token);
+        unregister(&self.state.registry, name, self.token, generation);
+    }
+
+    /// Returns the notifier of the state machine registered as `name`
+    pub fn lookup_name(&self, name: &str) -> Option<Notifier> {
+        self.state.registry.lookup(name)
+    }
+
+    /// Call `Machine::tick` of the enclosed state machine after every
+    /// iteration of the loop
+    ///
+    /// Ticks are delivered after all the events of the iteration, until
+    /// disabled or the state machine is removed.
+    pub fn set_ticking(&mut self, enable: bool) {
+        self.state.set_ticking(self.token, enable);
+    }
+
+    /// Link the enclosed state machine with another one
+    ///
+    /// Links are bidirectional: when either state machine exits with
+    /// `Response::error()`, the other one receives
+    /// `Machine::exit_signal`. By default it exits with the `LinkedExit`
+    /// error too, so the failure propagates along the links. Override
+    /// `exit_signal` to trap it. Exiting with `Response::done()` doesn't
+    /// send signals.
+    ///
+    /// The `notifier` identifies the peer, which must be in the same loop.
+    /// Returns false if the peer is already gone.
+    pub fn link(&mut self, notifier: &Notifier) -> bool {
+        let (peer, generation) = notifier_target(notifier);
+        self.state.link(self.token, peer, generation)
+    }
+
+    /// Remove the link created by `link`
+    pub fn unlink(&mut self, notifier: &Notifier) {
+        let (peer, generation) = notifier_target(notifier);
+        self.state.unlink(self.token, peer, generation);
+    }
+
+    /// Enroll the enclosed state machine into the watchdog
+    ///
+    /// See `Config::watchdog`. Does nothing if the watchdog isn't
+    /// configured.
+    pub fn enable_watchdog(&mut self, enable: bool) {
+        self.state.enable_watchdog(self.token, enable);
+    }
+
+    /// Returns true if the wakeup is the ping of the watchdog
+    pub fn is_ping(&self) -> bool {
+        self.state.ping_fired
+    }
+
+    /// Acknowledge the ping of the watchdog
+    ///
+    /// Called by the default `Machine::wakeup`. May be called later than
+    /// the ping itself, but before the next round of the watchdog.
+    pub fn ack_ping(&mut self) {
+        self.state.ack_ping(self.token);
+    }
+
+    /// Returns which timer has fired, valid in `Machine::timeout`
+    pub fn timeout_kind(&self) -> TimeoutKind {
+        if self.state.idle_fired {
+            TimeoutKind::Idle
+        } else {
+            TimeoutKind::Deadline
+        }
+    }
+
+    /// Notifiers of the children created by the batch, valid in
+    /// `Machine::spawned`
+    ///
+    /// Children are in the order of the seeds. The seeds for which
+    /// `Machine::create` returned `Response::done()` are skipped. Outside
+    /// of `spawned` the list is empty.
+    pub fn spawned_children(&self) -> Vec<Notifier> {
+        self.state.spawned_children.iter()
+            .map(|&token| self.state.notifier(token))
+            .collect()
+    }
+
+    /// Number of seeds spawned by this state machine which wait for the
+    /// slab space
+    ///
+    /// Seeds are queued with `SlabFull::Queue` and when returned from
+    /// `Machine::spawn_error`. Each of them is created when some state
+    /// machine is removed, and `Machine::spawned` is called.
+    pub fn queued_spawns(&self) -> usize {
+        self.state.get(self.token)
+            .map(|meta| meta.queued_spawns())
+            .unwrap_or(0)
+    }
+
+    /// Set the dispatch priority of the enclosed state machine
+    ///
+    /// Ready events received in a single poll are dispatched to the state
+    /// machines with higher priority first, and round-robin among the ones
+    /// with the same priority. The default is zero. E.g. set a negative
+    /// priority for a busy listener, so that it doesn't delay the
+    /// established connections. Can also be called in `Machine::create`.
+    pub fn set_priority(&mut self, priority: i8) {
+        if let Some(meta) = self.state.get_mut(self.token) {
+            set_priority(meta, priority);
+        }
+    }
+
+    /// Iterate over metadata of all state machines in the loop
+    ///
+    /// This is useful for maintenance state machines (reapers, statistics
+    /// collectors). The enclosed state machine is also visited. Metadata of
+    /// the machines created in the current iteration may not be visible yet.
+    pub fn for_each_machine<F>(&self, mut fun: F)
+        where F: FnMut(Token, &MachineMeta)
+    {
+        for (idx, meta) in self.state.machines.iter().enumerate() {
+            if let Some(ref meta) = *meta {
+                fun(Token(idx), meta);
+            }
+        }
+    }
+
+    /// Runtime statistics of the enclosed state machine
+    ///
+    /// Statistics of other state machines are visible in
+    /// `for_each_machine`.
+    #[cfg(feature="machine_stats")]
+    pub fn machine_stats(&self) -> Option<MachineStats> {
+        self.state.get(self.token).map(|meta| *meta.stats())
+    }
+
+    /// Returns a latency histogram of the loop identified by the label
+    ///
+    /// Histogram is created on first use. All state machines using the
+    /// same label record values into the same histogram. For example:
+    ///
+    /// ```ignore
+    /// scope.timer_metric("handshake").record(self.started, scope.now());
+    /// ```
+    pub fn timer_metric(&mut self, label: &'static str) -> TimerMetric {
+        self.state.timer_metrics.entry(label)
+            .or_insert_with(TimerMetric::new).clone()
+    }
+
+    /// Record how late the deadlines of the state machine fire
+    ///
+    /// The lateness of every deadline of the enclosed state machine, in
+    /// milliseconds from the deadline to the call of `Machine::timeout`,
+    /// is recorded into `timer_metric(label)`, so the state machines of
+    /// the same kind share the distribution. Unlike
+    /// `LoopLatency::timer_lateness` it doesn't need
+    /// `Config::latency_histograms`. `None` stops recording.
+    ///
+    /// ```ignore
+    /// scope.set_lateness_metric(Some("keepalive"));
+    /// ```
+    pub fn set_lateness_metric(&mut self, label: Option<&'static str>) {
+        self.state.set_lateness_metric(self.token, label);
+    }
+
+    /// Iterate over all latency histograms of the loop
+    pub fn for_each_timer_metric<F>(&self, mut fun: F)
+        where F: FnMut(&'static str, &Histogram)
+    {
+        for (label, metric) in self.state.timer_metrics.iter() {
+            fun(label, &*metric.histogram());
+        }
+    }
+
+    /// Pass the event to the `Diagnostics` of the loop
+    ///
+    /// Without diagnostics installed the event is logged the same way as
+    /// the events of the loop itself.
+    pub fn diagnose(&mut self, event: Diagnostic) {
+        self.state.diagnose(self.time, event);
+    }
+
+    /// Name of the loop, see `Config::loop_id`
+    ///
+    /// Include it in the log messages of the state machines when the
+    /// process runs multiple loops.
+    pub fn loop_id(&self) -> &str {
+        &self.state.loop_id
+    }
+
+    /// Counters of errors returned by the poll of the loop
+    pub fn poll_errors(&self) -> PollErrors {
+        self.state.poll_errors
+    }
+
+    /// Number of state machines in the loop
+    ///
+    /// Together with `machines_capacity` allows admission control, e.g. a
+    /// listener may stop accepting when the slab is almost full.
+    pub fn machines_live(&self) -> usize {
+        self.state.occupancy.live
+    }
+
+    /// Number of state machines the loop may have before the slab grows
+    /// or spawning fails, see `Config::slab_growth`
+    pub fn machines_capacity(&self) -> usize {
+        self.state.occupancy.capacity
+    }
+
+    /// Occupancy of the slab including the high-water mark
+    pub fn slab_occupancy(&self) -> SlabOccupancy {
+        self.state.occupancy
+    }
+
+    /// Number of notifications dropped because the queue of the loop was
+    /// full, see `FullPolicy`
+    pub fn dropped_wakeups(&self) -> u64 {
+        self.state.channel.dropped()
+    }
+
+    /// Returns a scope with the part of the context, see `SubContext`
+    ///
+    /// The scope refers to the same state machine, only the context
+    /// differs.
+    pub fn project<D>(&mut self) -> Scope<D>
+        where C: SubContext<D>
+    {
+        Scope {
+            token: self.token,
+            ctx: self.ctx.sub_context_mut(),
+            state: &mut *self.state,
+            loop_api: &mut *self.loop_api,
+            time: self.time,
+        }
+    }
+
+    /// Returns true if graceful shutdown of the loop is in progress
+    ///
+    /// See `ShutdownHandle::graceful_shutdown`. State machines should
+    /// finish their work and return `Response::done()` when it's true.
+    pub fn is_shutting_down(&self) -> bool {
+        self.state.shutting_down
+    }
+
+    /// Returns true if the state machine is being replaced
+    ///
+    /// Set for every machine of the group (i.e. with the same label)
+    /// replaced by `LoopHandle::replace_group`, and the state machines
+    /// receive a `wakeup` when it's set. A retiring machine should stop
+    /// taking new work (e.g. a listener closes its socket right away), finish
+    /// the work in progress and return `Response::done()`.
+    pub fn is_retiring(&self) -> bool {
+        self.state.get(self.token).map(|m| m.is_retiring()).unwrap_or(false)
     }
 
     /// Shutdown the event loop
@@ -152,6 +1121,13 @@ impl<'a, C:Sized+'a> Scope<'a, C> {
         self.time
     }
 
+    /// Time of the next attempt according to the `backoff`
+    ///
+    /// Advances the backoff, see `Backoff::next_delay`.
+    pub fn after_backoff(&self, backoff: &mut Backoff) -> Time {
+        self.time + backoff.next_delay()
+    }
+
     /// Returns the SystemTime that corresponds to the Time in this loop
     ///
     /// Note: this is an estimate, because we use *monotonic* time under the
@@ -163,6 +1139,57 @@ impl<'a, C:Sized+'a> Scope<'a, C> {
     pub fn estimate_system_time(&self, time: Time) -> SystemTime {
         estimate_system_time(self.now(), time)
     }
+
+    /// Converts the system time to the loop time
+    ///
+    /// This is an estimate, see `estimate_system_time`
+    pub fn estimate_time(&self, time: SystemTime) -> Time {
+        estimate_time(self.now(), time)
+    }
+
+    /// Returns the `Instant` that corresponds to the loop time
+    ///
+    /// Unlike system time conversions this one is exact (to the
+    /// millisecond precision of the `Time`).
+    pub fn instant(&self, time: Time) -> Instant {
+        make_instant(self.state.start, time)
+    }
+
+    /// Returns the loop time of the `Instant`
+    ///
+    /// Instants before the start of the loop are clamped to the start.
+    pub fn time_of(&self, instant: Instant) -> Time {
+        make_time(self.state.start, instant)
+    }
+
+    /// Read the clock again and return the fresh time
+    ///
+    /// The time of the loop is read once per poll, which is usually enough.
+    /// But state machines doing long computations, or measuring time
+    /// precisely, may use this to update `now()` (and `now_instant()`) for
+    /// the rest of the action and for the state machines run after it.
+    pub fn refresh_now(&mut self) -> Time {
+        self.state.reset_clock();
+        self.time = make_time(self.state.start, self.state.clock().0);
+        self.time
+    }
+
+    /// Monotonic time of the current loop iteration
+    ///
+    /// The clock is read once per loop iteration, so this is cheap to call
+    /// many times. Unlike `now()` it has full precision of the `Instant`.
+    /// (Use `instant()` to convert a `Time` value.)
+    pub fn now_instant(&self) -> Instant {
+        self.state.clock().0
+    }
+
+    /// Wall clock time of the current loop iteration
+    ///
+    /// The clock is read once per loop iteration, so this is cheap to call
+    /// many times. Useful for log lines and timestamps in protocols.
+    pub fn wall_clock(&self) -> SystemTime {
+        self.state.clock().1
+    }
 }
 
 impl<'a, C:Sized+'a> GenericScope for Scope<'a, C> {
@@ -231,12 +1258,17 @@ impl<'a, C> DerefMut for Scope<'a, C> {
 
 impl<'a> EarlyScope<'a> {
 
+    /// Register the socket in the loop for the enclosed state machine
     pub fn register(&mut self, io: &Evented, interest: EventSet, opt: PollOpt)
         -> io::Result<()>
     {
         self.loop_api.register(io, self.token, interest, opt)
     }
 
+    /// Change interest set or poll options of the registered socket
+    ///
+    /// For example, switch from `readable` to `writable` when there is
+    /// some data to send.
     pub fn reregister(&mut self, io: &Evented,
         interest: EventSet, opt: PollOpt)
         -> io::Result<()>
@@ -244,18 +1276,89 @@ impl<'a> EarlyScope<'a> {
         self.loop_api.reregister(io, self.token, interest, opt)
     }
 
+    /// Remove the socket from the loop
+    ///
+    /// No events are delivered for the socket until it's registered again.
+    /// Sockets must be deregistered before they are passed to another
+    /// state machine.
     pub fn deregister(&mut self, io: &Evented) -> io::Result<()>
     {
         self.loop_api.deregister(io)
     }
 
+    /// Register the socket and deregister it when the machine is removed
+    ///
+    /// Same as `register`, and then the loop remembers the file
+    /// descriptor. When the state machine is done, fails or is aborted,
+    /// the socket is deregistered even if it's still alive somewhere
+    /// (e.g. kept in the context), so it can't deliver events to the
+    /// token reused by another state machine.
+    ///
+    /// Close the socket only after `deregister_tracked`, or after the
+    /// machine is removed: if the descriptor is reused by another
+    /// tracked socket in the meantime, the stale entry is dropped, but an
+    /// untracked one would be deregistered by mistake.
+    #[cfg(unix)]
+    pub fn register_tracked<E>(&mut self, io: &E,
+        interest: EventSet, opt: PollOpt)
+        -> io::Result<()>
+        where E: Evented + AsRawFd
+    {
+        try!(self.loop_api.register(io, self.token, interest, opt));
+        self.state.track(self.token, io.as_raw_fd());
+        Ok(())
+    }
+
+    /// Deregister the socket registered with `register_tracked`
+    #[cfg(unix)]
+    pub fn deregister_tracked<E>(&mut self, io: &E) -> io::Result<()>
+        where E: Evented + AsRawFd
+    {
+        self.state.untrack(self.token, io.as_raw_fd());
+        self.loop_api.deregister(io)
+    }
+
+    /// Default poll options of the loop, see `Config::poll_opt`
+    pub fn poll_opt(&self) -> PollOpt {
+        poll_opt(&self.state.config)
+    }
+
+    /// Register the socket with the default poll options of the loop
+    ///
+    /// On unix the hangup and error events are added to the `interest`,
+    /// see `Readiness`.
+    pub fn register_default(&mut self, io: &Evented, interest: EventSet)
+        -> io::Result<()>
+    {
+        let opt = self.poll_opt();
+        self.loop_api.register(io, self.token, with_hangup(interest), opt)
+    }
+
+    /// Reregister the socket with the default poll options of the loop
+    ///
+    /// Adds hangup and error events the same way as `register_default`.
+    pub fn reregister_default(&mut self, io: &Evented, interest: EventSet)
+        -> io::Result<()>
+    {
+        let opt = self.poll_opt();
+        self.loop_api.reregister(io, self.token, with_hangup(interest), opt)
+    }
+
     /// Add timeout
     ///
     /// This method is **deprecated** use return value of your state machine's
     /// action to set a timeout
+    ///
+    /// Never returns an error, as timers have no capacity limit. The
+    /// `Result` is kept for backwards compatibility.
     pub fn timeout_ms(&mut self, delay: u64) -> Result<Timeout, TimerError>
     {
-        self.loop_api.timeout_ms(self.token, delay)
+        debug_assert!(self.state.get(self.token).is_some(),
+            "State machine {:?} arms a timeout in the deferred action \
+             after it's removed (e.g. with `Response::done()`), the \
+             timeout would never fire", self.token);
+        let generation = self.state.generation(self.token);
+        Ok(self.loop_api.timeout_ms(self.token, generation, delay))
     }
 
     /// Clear timeout
@@ -269,7 +1372,12 @@ impl<'a> EarlyScope<'a> {
 
     /// Create a `Notifier` that may be used to `wakeup` enclosed state machine
     pub fn notifier(&self) -> Notifier {
-        create_notifier(self.token, self.channel)
+        self.state.notifier(self.token)
+    }
+
+    /// Name of the loop, see `Config::loop_id`
+    pub fn loop_id(&self) -> &str {
+        &self.state.loop_id
     }
 
     /// Time of the current loop iteration
@@ -294,6 +1402,61 @@ impl<'a> EarlyScope<'a> {
     }
 }
 
+impl<'a> LoopScope<'a> {
+    /// Time of the current loop iteration
+    pub fn now(&self) -> Time {
+        self.time
+    }
+
+    /// Name of the loop, see `Config::loop_id`
+    pub fn loop_id(&self) -> &str {
+        &self.state.loop_id
+    }
+
+    /// Find the state machine registered with `Scope::register_name`
+    pub fn lookup_name(&self, name: &str) -> Option<Notifier> {
+        self.state.registry.lookup(name)
+    }
+
+    /// Number of state machines currently in the loop
+    pub fn machines_live(&self) -> usize {
+        self.state.occupancy.live
+    }
+
+    /// Usage of the slab of the loop, see `SlabOccupancy`
+    pub fn slab_occupancy(&self) -> SlabOccupancy {
+        self.state.occupancy
+    }
+
+    /// Returns true if the loop is shutting down gracefully
+    pub fn is_shutting_down(&self) -> bool {
+        self.state.shutting_down
+    }
+
+    /// Shutdown the event loop
+    pub fn shutdown_loop(&mut self) {
+        self.loop_api.shutdown()
+    }
+
+    /// The interval of the timer, `None` if it's cancelled
+    pub fn interval(&self) -> Option<Duration> {
+        *self.interval
+    }
+
+    /// Fire the timer next time after `interval` instead of the current
+    /// one
+    pub fn set_interval(&mut self, interval: Duration) {
+        *self.interval = Some(interval);
+    }
+
+    /// Don't fire the timer anymore
+    ///
+    /// The closure is dropped right after it returns.
+    pub fn cancel(&mut self) {
+        *self.interval = None;
+    }
+}
+
 impl<'a> GenericScope for EarlyScope<'a> {
 
     fn register(&mut self, io: &Evented, interest: EventSet, opt: PollOpt)
@@ -348,13 +1511,13 @@ impl<'a> GenericScope for EarlyScope<'a> {
 
 #[doc(hidden)]
 pub fn scope<'x, C, L:LoopApi>(time: Time, token: Token, ctx: &'x mut C,
-    channel: &'x mut Sender<Notify>, loop_api: &'x mut L)
+    state: &'x mut LoopState, loop_api: &'x mut L)
     -> Scope<'x, C>
 {
     Scope {
         token: token,
         ctx: ctx,
-        channel: channel,
+        state: state,
         loop_api: loop_api,
         time: time,
     }
@@ -362,12 +1525,34 @@ pub fn scope<'x, C, L:LoopApi>(time: Time, token: Token, ctx: &'x mut C,
 
 #[doc(hidden)]
 pub fn early_scope<'x, L:LoopApi>(token: Token,
-    channel: &'x mut Sender<Notify>, loop_api: &'x mut L)
+    state: &'x mut LoopState, loop_api: &'x mut L)
     -> EarlyScope<'x>
 {
     EarlyScope {
         token: token,
-        channel: channel,
+        state: state,
         loop_api: loop_api,
     }
 }
+
+#[doc(hidden)]
+pub fn loop_scope<'x, L:LoopApi>(time: Time,
+    interval: &'x mut Option<Duration>,
+    state: &'x mut LoopState, loop_api: &'x mut L)
+    -> LoopScope<'x>
+{
+    LoopScope {
+        state: state,
+        loop_api: loop_api,
+        time: time,
+        interval: interval,
+    }
+}
+
+/// Runs the closures passed to `Response::defer`
+pub fn run_deferred<L: LoopApi>(state: &mut LoopState, loop_api: &mut L) {
+    let deferred = mem::replace(&mut state.deferred, Vec::new());
+    for (token, fun) in deferred {
+        fun(&mut early_scope(token, state, loop_api));
+    }
+}
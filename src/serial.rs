@@ -0,0 +1,279 @@
+use std::io::{self, Read, Write};
+use std::fs::File;
+use std::path::Path;
+use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd, RawFd};
+
+use mio::{Evented, Poll, Token, Ready, PollOpt};
+use mio::unix::EventedFd;
+
+use Transport;
+
+
+/// Parity bit of the serial line
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Parity {
+    None,
+    Odd,
+    Even,
+}
+
+/// Line settings of the `SerialPort`
+#[derive(Debug, Clone)]
+#[cfg_attr(not(feature="serial"), allow(dead_code))]
+pub struct SerialSettings {
+    baud_rate: u32,
+    data_bits: u8,
+    parity: Parity,
+    stop_bits: u8,
+    flow_control: bool,
+}
+
+/// A serial device (UART) opened in non-blocking mode
+///
+/// Implements `Transport`, so line protocols over the serial port are
+/// written as a `Protocol` and run by the `Stream` state machine, with
+/// the same expectations and deadlines as TCP connections in the same
+/// loop:
+///
+/// ```ignore
+/// let settings = SerialSettings::new(115200);
+/// let port = try!(SerialPort::open("/dev/ttyUSB0", &settings));
+/// // `Modbus` is a `Protocol` with `type Socket = SerialPort`
+/// Stream::<Modbus>::new(seed, port, scope)
+/// ```
+///
+/// The device is put into raw mode: no echo, no line editing, no
+/// translation of the characters. Unplugging a USB adapter is reported
+/// as hangup (see `Readiness`).
+///
+/// Requires the `serial` feature, without it `open` and `configure`
+/// return an error, but the descriptors configured elsewhere (e.g. by
+/// `mio-serial`) may still be wrapped with `from_raw_fd`.
+#[derive(Debug)]
+pub struct SerialPort {
+    file: File,
+}
+
+impl SerialSettings {
+    /// 8 data bits, no parity, one stop bit, no flow control
+    pub fn new(baud_rate: u32) -> SerialSettings {
+        SerialSettings {
+            baud_rate: baud_rate,
+            data_bits: 8,
+            parity: Parity::None,
+            stop_bits: 1,
+            flow_control: false,
+        }
+    }
+    /// Set the number of data bits, 5 to 8
+    pub fn data_bits(mut self, bits: u8) -> SerialSettings {
+        self.data_bits = bits;
+        self
+    }
+    pub fn parity(mut self, parity: Parity) -> SerialSettings {
+        self.parity = parity;
+        self
+    }
+    /// Set the number of stop bits, 1 or 2
+    pub fn stop_bits(mut self, bits: u8) -> SerialSettings {
+        self.stop_bits = bits;
+        self
+    }
+    /// Enable the RTS/CTS hardware flow control
+    pub fn flow_control(mut self, enable: bool) -> SerialSettings {
+        self.flow_control = enable;
+        self
+    }
+}
+
+impl SerialPort {
+    /// Open the device, e.g. `/dev/ttyS0`, and apply the settings
+    ///
+    /// The device doesn't become the controlling terminal of the process.
+    pub fn open<P: AsRef<Path>>(path: P, settings: &SerialSettings)
+        -> io::Result<SerialPort>
+    {
+        let file = try!(sys::open(path.as_ref()));
+        let port = SerialPort { file: file };
+        try!(port.configure(settings));
+        Ok(port)
+    }
+    /// Change the line settings of the open port
+    ///
+    /// Data which is not transmitted yet may be sent with the new settings.
+    pub fn configure(&self, settings: &SerialSettings) -> io::Result<()> {
+        sys::configure(self.file.as_raw_fd(), settings)
+    }
+}
+
+impl FromRawFd for SerialPort {
+    /// Wrap the descriptor of the configured device
+    ///
+    /// The descriptor must be in non-blocking mode.
+    unsafe fn from_raw_fd(fd: RawFd) -> SerialPort {
+        SerialPort { file: File::from_raw_fd(fd) }
+    }
+}
+
+impl AsRawFd for SerialPort {
+    fn as_raw_fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+}
+
+impl IntoRawFd for SerialPort {
+    fn into_raw_fd(self) -> RawFd {
+        self.file.into_raw_fd()
+    }
+}
+
+impl Read for SerialPort {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.file.read(buf)
+    }
+}
+
+impl Write for SerialPort {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file.write(buf)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl Evented for SerialPort {
+    fn register(&self, poll: &Poll, token: Token, interest: Ready,
+        opts: PollOpt)
+        -> io::Result<()>
+    {
+        EventedFd(&self.file.as_raw_fd()).register(poll, token, interest, opts)
+    }
+    fn reregister(&self, poll: &Poll, token: Token, interest: Ready,
+        opts: PollOpt)
+        -> io::Result<()>
+    {
+        EventedFd(&self.file.as_raw_fd())
+            .reregister(poll, token, interest, opts)
+    }
+    fn deregister(&self, poll: &Poll) -> io::Result<()> {
+        EventedFd(&self.file.as_raw_fd()).deregister(poll)
+    }
+}
+
+impl Transport for SerialPort {}
+
+#[cfg(feature="serial")]
+mod sys {
+    use std::io;
+    use std::mem;
+    use std::fs::{File, OpenOptions};
+    use std::path::Path;
+    use std::os::unix::fs::OpenOptionsExt;
+    use std::os::unix::io::RawFd;
+
+    use libc;
+    use super::{SerialSettings, Parity};
+
+    fn invalid(msg: &'static str) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidInput, msg)
+    }
+
+    fn speed(baud_rate: u32) -> io::Result<libc::speed_t> {
+        Ok(match baud_rate {
+            1200 => libc::B1200,
+            2400 => libc::B2400,
+            4800 => libc::B4800,
+            9600 => libc::B9600,
+            19200 => libc::B19200,
+            38400 => libc::B38400,
+            57600 => libc::B57600,
+            115200 => libc::B115200,
+            230400 => libc::B230400,
+            #[cfg(target_os="linux")]
+            460800 => libc::B460800,
+            #[cfg(target_os="linux")]
+            921600 => libc::B921600,
+            _ => return Err(invalid("unsupported baud rate")),
+        })
+    }
+
+    pub fn open(path: &Path) -> io::Result<File> {
+        OpenOptions::new().read(true).write(true)
+            .custom_flags(libc::O_NOCTTY | libc::O_NONBLOCK)
+            .open(path)
+    }
+
+    pub fn configure(fd: RawFd, settings: &SerialSettings)
+        -> io::Result<()>
+    {
+        let size = match settings.data_bits {
+            5 => libc::CS5,
+            6 => libc::CS6,
+            7 => libc::CS7,
+            8 => libc::CS8,
+            _ => return Err(invalid("data bits must be 5 to 8")),
+        };
+        if settings.stop_bits != 1 && settings.stop_bits != 2 {
+            return Err(invalid("stop bits must be 1 or 2"));
+        }
+        let speed = try!(speed(settings.baud_rate));
+        let mut tio: libc::termios = unsafe { mem::zeroed() };
+        if unsafe { libc::tcgetattr(fd, &mut tio) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        unsafe { libc::cfmakeraw(&mut tio) };
+        tio.c_cflag |= libc::CLOCAL | libc::CREAD;
+        tio.c_cflag &= !(libc::CSIZE | libc::PARENB | libc::PARODD |
+                         libc::CSTOPB | libc::CRTSCTS);
+        tio.c_cflag |= size;
+        match settings.parity {
+            Parity::None => {}
+            Parity::Odd => tio.c_cflag |= libc::PARENB | libc::PARODD,
+            Parity::Even => tio.c_cflag |= libc::PARENB,
+        }
+        if settings.stop_bits == 2 {
+            tio.c_cflag |= libc::CSTOPB;
+        }
+        if settings.flow_control {
+            tio.c_cflag |= libc::CRTSCTS;
+        }
+        // Reads return what's available, the descriptor is non-blocking
+        tio.c_cc[libc::VMIN] = 1;
+        tio.c_cc[libc::VTIME] = 0;
+        unsafe {
+            if libc::cfsetispeed(&mut tio, speed) < 0 ||
+                libc::cfsetospeed(&mut tio, speed) < 0 ||
+                libc::tcsetattr(fd, libc::TCSANOW, &tio) < 0
+            {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(not(feature="serial"))]
+mod sys {
+    use std::io;
+    use std::fs::File;
+    use std::path::Path;
+    use std::os::unix::io::RawFd;
+
+    use super::SerialSettings;
+
+    fn unsupported() -> io::Error {
+        io::Error::new(io::ErrorKind::Other,
+            "serial ports require the `serial` feature")
+    }
+
+    pub fn open(_path: &Path) -> io::Result<File> {
+        Err(unsupported())
+    }
+
+    pub fn configure(_fd: RawFd, _settings: &SerialSettings)
+        -> io::Result<()>
+    {
+        Err(unsupported())
+    }
+}
@@ -0,0 +1,48 @@
+use std::sync::{Arc, Mutex, MutexGuard};
+
+
+/// A context which can be accessed from outside of the loop
+///
+/// Use `SharedContext<C>` as the `Context` of the state machine and keep
+/// a clone of it in other threads (admin endpoints, metrics scrapers).
+/// Both the loop and the other threads access the value by locking it, so
+/// keep the lock for short periods of time, the loop is blocked while
+/// another thread holds it.
+///
+/// Create it with `Loop::instantiate_shared` or with `SharedContext::new`
+/// passing a clone to `Loop::instantiate`.
+pub struct SharedContext<C>(Arc<Mutex<C>>);
+
+impl<C> SharedContext<C> {
+    pub fn new(context: C) -> SharedContext<C> {
+        SharedContext(Arc::new(Mutex::new(context)))
+    }
+    /// Lock the context
+    ///
+    /// If some thread has panicked while holding the lock the value is
+    /// returned anyway, the context is expected to be consistent after
+    /// every single operation.
+    pub fn lock(&self) -> MutexGuard<C> {
+        match self.0.lock() {
+            Ok(guard) => guard,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+    /// Run the function with the locked context
+    pub fn with<R, F: FnOnce(&mut C) -> R>(&self, fun: F) -> R {
+        fun(&mut *self.lock())
+    }
+}
+
+impl<C: Clone> SharedContext<C> {
+    /// Returns a copy of the current value of the context
+    pub fn snapshot(&self) -> C {
+        self.lock().clone()
+    }
+}
+
+impl<C> Clone for SharedContext<C> {
+    fn clone(&self) -> SharedContext<C> {
+        SharedContext(self.0.clone())
+    }
+}
@@ -0,0 +1,62 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+
+
+/// Seeded random generator for `Config::shuffle_dispatch`
+///
+/// It's a splitmix64, which is good enough to permute events and gives the
+/// same sequence for the same seed on every platform.
+#[doc(hidden)]
+#[derive(Debug, Clone)]
+pub struct Shuffler {
+    seed: u64,
+    state: u64,
+}
+
+/// Picks a seed when it isn't set explicitly
+pub fn random_seed() -> u64 {
+    RandomState::new().build_hasher().finish()
+}
+
+impl Shuffler {
+    pub fn new(seed: u64) -> Shuffler {
+        Shuffler {
+            seed: seed,
+            state: seed,
+        }
+    }
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+    fn next(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+    /// Fisher-Yates shuffle
+    pub fn shuffle<T>(&mut self, items: &mut [T]) {
+        for i in (1..items.len()).rev() {
+            let j = (self.next() % (i as u64 + 1)) as usize;
+            items.swap(i, j);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Shuffler;
+
+    #[test]
+    fn same_seed_same_order() {
+        let mut a = (0..20).collect::<Vec<u32>>();
+        let mut b = a.clone();
+        Shuffler::new(12345).shuffle(&mut a);
+        Shuffler::new(12345).shuffle(&mut b);
+        assert_eq!(a, b);
+        assert!(a != (0..20).collect::<Vec<u32>>());
+        a.sort();
+        assert_eq!(a, (0..20).collect::<Vec<u32>>());
+    }
+}
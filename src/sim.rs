@@ -0,0 +1,398 @@
+use std::io::{self, Read, Write};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Instant;
+use std::collections::VecDeque;
+
+use mio::{Evented, Poll, Token, Ready, PollOpt, Registration, SetReadiness};
+
+use {Clock, Transport};
+
+
+struct Shared {
+    /// Bytes received "from the network" and not read yet
+    incoming: VecDeque<u8>,
+    /// Bytes written by the state machine
+    written: Vec<u8>,
+    /// Number of bytes the network accepts before `WouldBlock`
+    write_capacity: Option<usize>,
+    /// The peer has closed its side, reads return EOF after the data
+    eof: bool,
+    /// The connection is reset, reads and writes fail
+    reset: bool,
+    /// Readiness raised by `SimPeer::trigger`, cleared by the next read or
+    /// write
+    forced: Ready,
+    /// Events of `SimPeer::schedule` ordered by time
+    scheduled: VecDeque<(Instant, SimEvent)>,
+}
+
+/// A change of the network side of the `SimStream`
+///
+/// Scheduled with `SimPeer::schedule`, each one is the same as calling
+/// the respective method of `SimPeer`.
+#[derive(Debug, Clone)]
+pub enum SimEvent {
+    /// Data received from the network, see `SimPeer::push`
+    Data(Vec<u8>),
+    /// See `SimPeer::close`
+    Close,
+    /// See `SimPeer::reset`
+    Reset,
+    /// See `SimPeer::set_write_capacity`
+    WriteCapacity(Option<usize>),
+    /// See `SimPeer::trigger`
+    Trigger(Ready),
+}
+
+/// An in-memory stream for testing protocol state machines
+///
+/// Behaves like a non-blocking socket registered in the loop: reads
+/// return `WouldBlock` when there is no data, writes return `WouldBlock`
+/// when the write capacity is exhausted, and the readiness is updated
+/// accordingly. The other end, `SimPeer`, is driven by the test. Create
+/// the pair with `SimStream::pair`:
+///
+/// ```ignore
+/// let (stream, peer) = SimStream::pair();
+/// loop_inst.add_machine_with(|scope| Proto::new(stream, scope)).unwrap();
+/// peer.push(b"GET / HTTP/1.0\r\n\r\n");
+/// loop_inst.run_for(Duration::from_millis(10)).unwrap();
+/// assert_eq!(peer.take_written(), b"HTTP/1.0 200 OK\r\n\r\n");
+/// peer.close();
+/// ```
+///
+/// Changes may also be scheduled for the virtual time of the loop (see
+/// `ManualClock`) with `SimPeer::schedule`, and applied when the clock
+/// reaches them with `SimPeer::fire_due`.
+pub struct SimStream {
+    shared: Arc<Mutex<Shared>>,
+    registration: Registration,
+    readiness: SetReadiness,
+}
+
+/// The network side of the `SimStream`
+///
+/// May be cloned and sent to other threads.
+#[derive(Clone)]
+pub struct SimPeer {
+    shared: Arc<Mutex<Shared>>,
+    readiness: SetReadiness,
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<T> {
+    match mutex.lock() {
+        Ok(guard) => guard,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
+
+fn update(shared: &Shared, readiness: &SetReadiness) {
+    let mut ready = shared.forced;
+    if shared.reset {
+        ready = ready | Ready::readable() | Ready::writable() | Ready::hup();
+    } else {
+        if !shared.incoming.is_empty() || shared.eof {
+            ready = ready | Ready::readable();
+        }
+        if shared.write_capacity != Some(0) {
+            ready = ready | Ready::writable();
+        }
+        if shared.eof {
+            ready = ready | Ready::hup();
+        }
+    }
+    // Fails only when the registration is dropped
+    readiness.set_readiness(ready).ok();
+}
+
+impl Shared {
+    fn apply(&mut self, event: SimEvent) {
+        match event {
+            SimEvent::Data(data) => self.incoming.extend(data),
+            SimEvent::Close => self.eof = true,
+            SimEvent::Reset => self.reset = true,
+            SimEvent::WriteCapacity(capacity) => {
+                self.write_capacity = capacity;
+            }
+            SimEvent::Trigger(ready) => self.forced = ready,
+        }
+    }
+}
+
+fn reset_error() -> io::Error {
+    io::Error::new(io::ErrorKind::ConnectionReset, "connection reset")
+}
+
+impl SimStream {
+    /// Create the stream and its network side
+    ///
+    /// The stream is writable without limit and has nothing to read.
+    pub fn pair() -> (SimStream, SimPeer) {
+        let (registration, readiness) = Registration::new2();
+        let shared = Arc::new(Mutex::new(Shared {
+            incoming: VecDeque::new(),
+            written: Vec::new(),
+            write_capacity: None,
+            eof: false,
+            reset: false,
+            forced: Ready::empty(),
+            scheduled: VecDeque::new(),
+        }));
+        update(&lock(&shared), &readiness);
+        let peer = SimPeer {
+            shared: shared.clone(),
+            readiness: readiness.clone(),
+        };
+        let stream = SimStream {
+            shared: shared,
+            registration: registration,
+            readiness: readiness,
+        };
+        (stream, peer)
+    }
+}
+
+impl SimPeer {
+    fn apply(&self, event: SimEvent) {
+        let mut shared = lock(&self.shared);
+        shared.apply(event);
+        update(&shared, &self.readiness);
+    }
+    /// Send the data to the stream, as if it's received from the network
+    pub fn push(&self, data: &[u8]) {
+        self.apply(SimEvent::Data(data.to_vec()));
+    }
+    /// Close the sending side, the stream reads EOF after the data
+    pub fn close(&self) {
+        self.apply(SimEvent::Close);
+    }
+    /// Reset the connection, reads and writes of the stream fail
+    pub fn reset(&self) {
+        self.apply(SimEvent::Reset);
+    }
+    /// Take the data written by the state machine so far
+    pub fn take_written(&self) -> Vec<u8> {
+        let mut shared = lock(&self.shared);
+        ::std::mem::replace(&mut shared.written, Vec::new())
+    }
+    /// Limit the number of bytes the stream accepts, `None` for no limit
+    ///
+    /// Writes return `WouldBlock` when the capacity is exhausted, and the
+    /// stream becomes writable again when it's raised.
+    pub fn set_write_capacity(&self, capacity: Option<usize>) {
+        self.apply(SimEvent::WriteCapacity(capacity));
+    }
+    /// Raise the readiness regardless of the state of the stream
+    ///
+    /// Useful to deliver spurious events. The readiness is cleared by the
+    /// next read or write of the stream.
+    pub fn trigger(&self, ready: Ready) {
+        self.apply(SimEvent::Trigger(ready));
+    }
+    /// Schedule the event for the time `at` of the loop clock
+    ///
+    /// Nothing happens until `fire_due` is called with the clock at or
+    /// past `at`. Events scheduled for the same time are applied in the
+    /// order they were scheduled.
+    pub fn schedule(&self, at: Instant, event: SimEvent) {
+        let mut shared = lock(&self.shared);
+        let pos = shared.scheduled.iter()
+            .position(|&(time, _)| time > at)
+            .unwrap_or(shared.scheduled.len());
+        shared.scheduled.insert(pos, (at, event));
+    }
+    /// Apply the scheduled events which are due by the `clock`
+    ///
+    /// Call it after moving the clock and before running the loop, so the
+    /// state machine gets the readiness in the next iteration:
+    ///
+    /// ```ignore
+    /// peer.schedule(clock.now() + Duration::from_secs(5), SimEvent::Close);
+    /// clock.advance(Duration::from_secs(5));
+    /// peer.fire_due(&clock);
+    /// loop_inst.run_for(Duration::from_millis(1)).unwrap();
+    /// ```
+    ///
+    /// Returns the number of events applied.
+    pub fn fire_due(&self, clock: &Clock) -> usize {
+        let now = clock.now();
+        let mut shared = lock(&self.shared);
+        let mut fired = 0;
+        while shared.scheduled.front().map(|&(time, _)| time <= now)
+            .unwrap_or(false)
+        {
+            let (_, event) = shared.scheduled.pop_front().unwrap();
+            shared.apply(event);
+            fired += 1;
+        }
+        update(&shared, &self.readiness);
+        fired
+    }
+}
+
+impl Read for SimStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut shared = lock(&self.shared);
+        shared.forced = Ready::empty();
+        let res = if shared.reset {
+            Err(reset_error())
+        } else if shared.incoming.is_empty() {
+            if shared.eof {
+                Ok(0)
+            } else {
+                Err(io::ErrorKind::WouldBlock.into())
+            }
+        } else {
+            let n = buf.len().min(shared.incoming.len());
+            for (dst, src) in buf.iter_mut().zip(shared.incoming.drain(..n)) {
+                *dst = src;
+            }
+            Ok(n)
+        };
+        update(&shared, &self.readiness);
+        res
+    }
+}
+
+impl Write for SimStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut shared = lock(&self.shared);
+        shared.forced = Ready::empty();
+        let res = if shared.reset {
+            Err(reset_error())
+        } else {
+            let n = shared.write_capacity.map(|c| c.min(buf.len()))
+                .unwrap_or(buf.len());
+            if n == 0 && !buf.is_empty() {
+                Err(io::ErrorKind::WouldBlock.into())
+            } else {
+                shared.written.extend_from_slice(&buf[..n]);
+                if let Some(ref mut capacity) = shared.write_capacity {
+                    *capacity -= n;
+                }
+                Ok(n)
+            }
+        };
+        update(&shared, &self.readiness);
+        res
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Evented for SimStream {
+    fn register(&self, poll: &Poll, token: Token, interest: Ready,
+        opts: PollOpt)
+        -> io::Result<()>
+    {
+        self.registration.register(poll, token, interest, opts)
+    }
+    fn reregister(&self, poll: &Poll, token: Token, interest: Ready,
+        opts: PollOpt)
+        -> io::Result<()>
+    {
+        self.registration.reregister(poll, token, interest, opts)
+    }
+    fn deregister(&self, poll: &Poll) -> io::Result<()> {
+        self.registration.deregister(poll)
+    }
+}
+
+impl Transport for SimStream {}
+
+#[cfg(test)]
+mod test {
+    use std::io::{self, Read, Write};
+    use std::time::Duration;
+    use mio::Ready;
+    use {Clock, ManualClock};
+    use super::{SimStream, SimEvent};
+
+    fn would_block<T>(res: io::Result<T>) -> bool {
+        res.err().map(|e| e.kind() == io::ErrorKind::WouldBlock)
+            .unwrap_or(false)
+    }
+
+    #[test]
+    fn write_capacity() {
+        let (mut stream, peer) = SimStream::pair();
+        assert!(stream.readiness.readiness().is_writable());
+        peer.set_write_capacity(Some(3));
+        assert_eq!(stream.write(b"hello").unwrap(), 3);
+        assert!(would_block(stream.write(b"lo")));
+        assert!(!stream.readiness.readiness().is_writable());
+        assert_eq!(peer.take_written(), b"hel");
+        peer.set_write_capacity(None);
+        assert!(stream.readiness.readiness().is_writable());
+        assert_eq!(stream.write(b"lo").unwrap(), 2);
+        assert_eq!(peer.take_written(), b"lo");
+    }
+
+    #[test]
+    fn eof_after_data() {
+        let (mut stream, peer) = SimStream::pair();
+        let mut buf = [0u8; 4];
+        assert!(would_block(stream.read(&mut buf)));
+        assert!(!stream.readiness.readiness().is_readable());
+        peer.push(b"hello");
+        peer.close();
+        assert_eq!(stream.read(&mut buf).unwrap(), 4);
+        assert_eq!(&buf, b"hell");
+        assert_eq!(stream.read(&mut buf).unwrap(), 1);
+        assert_eq!(buf[0], b'o');
+        assert_eq!(stream.read(&mut buf).unwrap(), 0);
+        assert!(stream.readiness.readiness().is_hup());
+    }
+
+    #[test]
+    fn reset() {
+        let (mut stream, peer) = SimStream::pair();
+        peer.push(b"data");
+        peer.reset();
+        let mut buf = [0u8; 4];
+        assert_eq!(stream.read(&mut buf).unwrap_err().kind(),
+                   io::ErrorKind::ConnectionReset);
+        assert_eq!(stream.write(b"x").unwrap_err().kind(),
+                   io::ErrorKind::ConnectionReset);
+        assert!(stream.readiness.readiness().is_hup());
+    }
+
+    #[test]
+    fn trigger() {
+        let (mut stream, peer) = SimStream::pair();
+        peer.trigger(Ready::readable());
+        assert!(stream.readiness.readiness().is_readable());
+        let mut buf = [0u8; 4];
+        assert!(would_block(stream.read(&mut buf)));
+        assert!(!stream.readiness.readiness().is_readable());
+        peer.set_write_capacity(Some(0));
+        peer.trigger(Ready::writable());
+        assert!(stream.readiness.readiness().is_writable());
+        assert!(would_block(stream.write(b"x")));
+        assert!(!stream.readiness.readiness().is_writable());
+    }
+
+    #[test]
+    fn scheduled() {
+        let clock = ManualClock::new();
+        let start = clock.now();
+        let (mut stream, peer) = SimStream::pair();
+        peer.schedule(start + Duration::from_secs(2), SimEvent::Close);
+        peer.schedule(start + Duration::from_secs(1),
+                      SimEvent::Data(b"a".to_vec()));
+        peer.schedule(start + Duration::from_secs(1),
+                      SimEvent::Data(b"b".to_vec()));
+        assert_eq!(peer.fire_due(&clock), 0);
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(peer.fire_due(&clock), 2);
+        let mut buf = [0u8; 4];
+        assert_eq!(stream.read(&mut buf).unwrap(), 2);
+        assert_eq!(&buf[..2], b"ab");
+        assert!(would_block(stream.read(&mut buf)));
+        clock.advance(Duration::from_secs(5));
+        assert_eq!(peer.fire_due(&clock), 1);
+        assert_eq!(stream.read(&mut buf).unwrap(), 0);
+        assert_eq!(peer.fire_due(&clock), 0);
+    }
+}
@@ -0,0 +1,116 @@
+use std::io::{self, Write};
+
+use {Machine, Time};
+use store::MachineStore;
+use loop_state::LoopState;
+use loop_time::time_diff_ms;
+
+
+/// Version of the snapshot format, bumped on incompatible changes
+const SNAPSHOT_VERSION: u32 = 1;
+
+/// Serializer of the state machine for the loop snapshot
+///
+/// Must write a single JSON value (e.g. an object) describing the state
+/// machine. See `LoopInstance::set_snapshot_serializer`.
+pub type StateSerializer<M> = Box<FnMut(&M, &mut Write) -> io::Result<()>>;
+
+fn write_string(out: &mut Write, value: &str) -> io::Result<()> {
+    try!(out.write_all(b"\""));
+    for c in value.chars() {
+        match c {
+            '"' => try!(out.write_all(b"\\\"")),
+            '\\' => try!(out.write_all(b"\\\\")),
+            '\n' => try!(out.write_all(b"\\n")),
+            c if (c as u32) < 0x20 => {
+                try!(write!(out, "\\u{:04x}", c as u32))
+            }
+            c => try!(write!(out, "{}", c)),
+        }
+    }
+    out.write_all(b"\"")
+}
+
+/// Milliseconds since the start of the loop
+fn millis(time: Time) -> u64 {
+    time_diff_ms(Time::zero(), time)
+}
+
+fn write_opt(out: &mut Write, time: Option<Time>) -> io::Result<()> {
+    match time {
+        Some(time) => write!(out, "{}", millis(time)),
+        None => out.write_all(b"null"),
+    }
+}
+
+/// Writes the snapshot of the loop as a JSON object
+///
+/// Times are in milliseconds since the start of the loop.
+pub fn write_snapshot<M, S>(out: &mut Write, now: Time,
+    slab: &S, state: &LoopState, pending_seeds: usize,
+    timers: usize, next_timer: Option<u64>,
+    mut serializer: Option<&mut StateSerializer<M>>)
+    -> io::Result<()>
+    where M: Machine, S: MachineStore<Option<M>>,
+{
+    try!(write!(out, "{{\"version\":{},\"now\":{},", SNAPSHOT_VERSION,
+        millis(now)));
+    try!(write!(out,
+        "\"slab\":{{\"len\":{},\"capacity\":{},\"high_water\":{}}},",
+        slab.len(), slab.capacity(), state.occupancy.high_water));
+    try!(write!(out, "\"pending_seeds\":{},\"yielded\":{},",
+        pending_seeds, state.yielded.len()));
+    try!(write!(out, "\"shutting_down\":{},", state.shutting_down));
+    try!(write!(out, "\"timers\":{{\"pending\":{},\"next\":", timers));
+    match next_timer {
+        // Wheel time is counted the same way as `Time`
+        Some(ms) => try!(write!(out, "{}", ms.saturating_sub(1))),
+        None => try!(out.write_all(b"null")),
+    }
+    try!(out.write_all(b"},\"machines\":["));
+    let mut first = true;
+    for (idx, meta) in state.machines.iter().enumerate() {
+        let meta = match *meta {
+            Some(ref meta) => meta,
+            None => continue,
+        };
+        if !first {
+            try!(out.write_all(b","));
+        }
+        first = false;
+        try!(write!(out, "{{\"token\":{},\"label\":", idx));
+        match meta.label() {
+            Some(label) => try!(write_string(out, label)),
+            None => try!(out.write_all(b"null")),
+        }
+        try!(write!(out, ",\"priority\":{},\"created\":{},\
+            \"last_activity\":{},\"deadline\":",
+            meta.priority(), millis(meta.created()),
+            millis(meta.last_activity())));
+        try!(write_opt(out, meta.deadline()));
+        try!(write!(out, ",\"reading_paused\":{},\"state\":",
+            meta.is_reading_paused()));
+        match (slab.get(::mio::Token(idx)), serializer.as_mut()) {
+            (Some(&Some(ref machine)), Some(serializer)) => {
+                try!(serializer(machine, out));
+            }
+            // The state machine is being dispatched right now, or there is
+            // no serializer
+            _ => try!(out.write_all(b"null")),
+        }
+        try!(out.write_all(b"}"));
+    }
+    out.write_all(b"]}")
+}
+
+#[cfg(test)]
+mod test {
+    use super::write_string;
+
+    #[test]
+    fn escape() {
+        let mut buf = Vec::new();
+        write_string(&mut buf, "a\"b\\c\n\x01").unwrap();
+        assert_eq!(&buf[..], &b"\"a\\\"b\\\\c\\n\\u0001\""[..]);
+    }
+}
@@ -0,0 +1,79 @@
+use std::io;
+use std::time::Duration;
+
+use mio::tcp::TcpStream;
+
+
+/// Options set on every socket created by `Accept`, `LimitedListener` and
+/// `Persistent`
+///
+/// Only the options which are set explicitly are changed, the rest are
+/// left at the system defaults:
+///
+/// ```ignore
+/// let options = SocketOptions::new()
+///     .nodelay(true)
+///     .keepalive(Some(Duration::from_secs(60)));
+/// Accept::with_options(listener, options, Seed::new, scope)
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct SocketOptions {
+    nodelay: Option<bool>,
+    keepalive: Option<Option<Duration>>,
+    recv_buffer_size: Option<usize>,
+    send_buffer_size: Option<usize>,
+    linger: Option<Option<Duration>>,
+}
+
+impl SocketOptions {
+    /// Create the options which change nothing
+    pub fn new() -> SocketOptions {
+        SocketOptions::default()
+    }
+    /// Set `TCP_NODELAY`, i.e. disable the Nagle's algorithm
+    pub fn nodelay(mut self, nodelay: bool) -> SocketOptions {
+        self.nodelay = Some(nodelay);
+        self
+    }
+    /// Enable `SO_KEEPALIVE` with the idle time, or disable with `None`
+    pub fn keepalive(mut self, keepalive: Option<Duration>)
+        -> SocketOptions
+    {
+        self.keepalive = Some(keepalive);
+        self
+    }
+    /// Set `SO_RCVBUF`
+    pub fn recv_buffer_size(mut self, size: usize) -> SocketOptions {
+        self.recv_buffer_size = Some(size);
+        self
+    }
+    /// Set `SO_SNDBUF`
+    pub fn send_buffer_size(mut self, size: usize) -> SocketOptions {
+        self.send_buffer_size = Some(size);
+        self
+    }
+    /// Set `SO_LINGER`, `None` disables lingering
+    pub fn linger(mut self, linger: Option<Duration>) -> SocketOptions {
+        self.linger = Some(linger);
+        self
+    }
+    /// Set the options on the socket
+    pub fn apply(&self, sock: &TcpStream) -> io::Result<()> {
+        if let Some(nodelay) = self.nodelay {
+            try!(sock.set_nodelay(nodelay));
+        }
+        if let Some(keepalive) = self.keepalive {
+            try!(sock.set_keepalive(keepalive));
+        }
+        if let Some(size) = self.recv_buffer_size {
+            try!(sock.set_recv_buffer_size(size));
+        }
+        if let Some(size) = self.send_buffer_size {
+            try!(sock.set_send_buffer_size(size));
+        }
+        if let Some(linger) = self.linger {
+            try!(sock.set_linger(linger));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,199 @@
+use std::cmp::max;
+
+use mio::Token;
+
+use Slab;
+
+
+/// Storage of the state machines of the loop
+///
+/// The loop keeps every state machine in the store under its token. By
+/// default it's the `Slab`, which preallocates `Config::slab_capacity`
+/// slots. Implement the trait to use a different backend and pass it to
+/// `Loop::with_store`.
+///
+/// Tokens returned by `vacancy` must be below the reserved range (see
+/// `Config::token_range`), and should be reused after `remove` so that the
+/// metadata of the loop, which is indexed by token, stays compact.
+pub trait MachineStore<T> {
+    /// Returns the token the next `insert` puts the value at
+    ///
+    /// Returns `None` if the store is full. The store must not change
+    /// until the `insert` with this token.
+    fn vacancy(&mut self) -> Option<Token>;
+    /// Put the value at the token returned by the last `vacancy`
+    fn insert(&mut self, token: Token, value: T);
+    /// Remove the value, the token may be returned by `vacancy` again
+    fn remove(&mut self, token: Token) -> Option<T>;
+    fn get(&self, token: Token) -> Option<&T>;
+    fn get_mut(&mut self, token: Token) -> Option<&mut T>;
+    /// Number of the values in the store
+    fn len(&self) -> usize;
+    /// Returns true if there are no values in the store
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    /// Number of values the store may keep before it's full
+    fn capacity(&self) -> usize;
+    /// Allow `additional` more values
+    ///
+    /// Called when the store is full and `Config::slab_growth` allows.
+    fn reserve(&mut self, additional: usize);
+    /// Lower the capacity and release the memory of the unused slots
+    ///
+    /// The `capacity` is never below `len()`. The values keep their
+    /// tokens, so the memory up to the highest used token stays. Called
+    /// when `Config::slab_shrink` is enabled. Default does nothing.
+    fn shrink(&mut self, capacity: usize) {
+        let _ = capacity;
+    }
+}
+
+impl<T> MachineStore<T> for Slab<T> {
+    fn vacancy(&mut self) -> Option<Token> {
+        self.vacant_entry().map(|entry| entry.index())
+    }
+    fn insert(&mut self, token: Token, value: T) {
+        let entry = self.vacant_entry().expect("the vacancy is checked");
+        debug_assert_eq!(entry.index(), token);
+        entry.insert(value);
+    }
+    fn remove(&mut self, token: Token) -> Option<T> {
+        Slab::remove(self, token)
+    }
+    fn get(&self, token: Token) -> Option<&T> {
+        Slab::get(self, token)
+    }
+    fn get_mut(&mut self, token: Token) -> Option<&mut T> {
+        Slab::get_mut(self, token)
+    }
+    fn len(&self) -> usize {
+        Slab::len(self)
+    }
+    fn capacity(&self) -> usize {
+        Slab::capacity(self)
+    }
+    fn reserve(&mut self, additional: usize) {
+        self.reserve_exact(additional)
+    }
+}
+
+/// A store which allocates the slots on demand
+///
+/// Unlike the `Slab` the memory isn't allocated upfront, so the capacity
+/// may be large when the number of state machines is usually small. Freed
+/// tokens are reused, the most recently freed first.
+#[derive(Debug)]
+pub struct VecStore<T> {
+    slots: Vec<Option<T>>,
+    free: Vec<usize>,
+    len: usize,
+    capacity: usize,
+}
+
+impl<T> VecStore<T> {
+    /// Create a store which keeps at most `capacity` values
+    pub fn with_capacity(capacity: usize) -> VecStore<T> {
+        VecStore {
+            slots: Vec::new(),
+            free: Vec::new(),
+            len: 0,
+            capacity: capacity,
+        }
+    }
+}
+
+impl<T> MachineStore<T> for VecStore<T> {
+    fn vacancy(&mut self) -> Option<Token> {
+        if self.len >= self.capacity {
+            return None;
+        }
+        Some(Token(self.free.last().cloned().unwrap_or(self.slots.len())))
+    }
+    fn insert(&mut self, token: Token, value: T) {
+        if self.free.last() == Some(&token.0) {
+            self.free.pop();
+        } else {
+            debug_assert_eq!(token.0, self.slots.len());
+            self.slots.push(None);
+        }
+        self.slots[token.0] = Some(value);
+        self.len += 1;
+    }
+    fn remove(&mut self, token: Token) -> Option<T> {
+        let value = self.slots.get_mut(token.0).and_then(|x| x.take());
+        if value.is_some() {
+            self.free.push(token.0);
+            self.len -= 1;
+        }
+        value
+    }
+    fn get(&self, token: Token) -> Option<&T> {
+        self.slots.get(token.0).and_then(|x| x.as_ref())
+    }
+    fn get_mut(&mut self, token: Token) -> Option<&mut T> {
+        self.slots.get_mut(token.0).and_then(|x| x.as_mut())
+    }
+    fn len(&self) -> usize {
+        self.len
+    }
+    fn capacity(&self) -> usize {
+        self.capacity
+    }
+    fn reserve(&mut self, additional: usize) {
+        self.capacity = self.capacity.saturating_add(additional);
+    }
+    fn shrink(&mut self, capacity: usize) {
+        self.capacity = max(capacity, self.len);
+        while let Some(&None) = self.slots.last() {
+            self.slots.pop();
+        }
+        let end = self.slots.len();
+        self.free.retain(|&token| token < end);
+        self.slots.shrink_to_fit();
+        self.free.shrink_to_fit();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use mio::Token;
+    use super::{MachineStore, VecStore};
+
+    #[test]
+    fn reuse_tokens() {
+        let mut store = VecStore::with_capacity(2);
+        let a = store.vacancy().unwrap();
+        store.insert(a, "a");
+        let b = store.vacancy().unwrap();
+        store.insert(b, "b");
+        assert_eq!((a, b), (Token(0), Token(1)));
+        assert_eq!(store.vacancy(), None);
+        assert_eq!(store.remove(a), Some("a"));
+        assert_eq!(store.remove(a), None);
+        assert_eq!(store.vacancy(), Some(Token(0)));
+        store.insert(Token(0), "c");
+        assert_eq!(store.get(Token(0)), Some(&"c"));
+        assert_eq!(store.len(), 2);
+        store.reserve(1);
+        assert_eq!(store.vacancy(), Some(Token(2)));
+    }
+
+    #[test]
+    fn shrink() {
+        let mut store = VecStore::with_capacity(4);
+        for name in &["a", "b", "c", "d"] {
+            let token = store.vacancy().unwrap();
+            store.insert(token, *name);
+        }
+        store.remove(Token(3));
+        store.remove(Token(1));
+        store.shrink(2);
+        assert_eq!(store.capacity(), 2);
+        assert_eq!(store.vacancy(), None);
+        store.remove(Token(2));
+        assert_eq!(store.vacancy(), Some(Token(2)));
+        store.shrink(2);
+        assert_eq!(store.vacancy(), Some(Token(1)));
+    }
+}
@@ -0,0 +1,549 @@
+use std::io;
+use std::mem;
+use std::error::Error;
+use std::collections::VecDeque;
+#[cfg(unix)] use std::os::unix::io::RawFd;
+
+
+use {Machine, Scope, Response, EventSet, PollOpt, Time, Transport};
+use transport::Handshake;
+use outbuf::{write_segments, consume};
+
+
+/// Size of a single read from the socket
+const READ_CHUNK: usize = 16384;
+
+/// What the protocol waits for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Expectation {
+    /// At least this number of bytes in the input buffer
+    Bytes(usize),
+    /// The delimiter in the input buffer, found within the max length
+    Delimiter(&'static [u8], usize),
+    /// All the output written to the socket
+    Flush,
+    /// The output written, then the range of the file: the descriptor,
+    /// the offset and the number of bytes left
+    #[cfg(unix)]
+    SendFile(RawFd, u64, u64),
+    /// Nothing, only a wakeup or a timeout
+    Sleep,
+}
+
+/// An abnormal condition of the stream
+#[derive(Debug)]
+pub enum Exception {
+    /// The peer has closed the connection before expectation is satisfied
+    EndOfStream,
+    /// The delimiter is not found within the max length
+    LimitReached,
+    /// I/O error on the socket
+    Io(io::Error),
+}
+
+/// The result of the protocol action
+///
+/// Created by `expect_bytes`, `expect_delimiter`, `expect_flush`, `sleep`,
+/// `done` or `error` and optionally amended with `deadline`.
+pub struct Intent<P>(Result<P, Option<Box<Error>>>, Expectation,
+                     Option<Time>);
+
+/// Input and output buffers of the stream
+pub struct Buffers {
+    input: Vec<u8>,
+    /// Written before the `output`
+    segments: VecDeque<Vec<u8>>,
+    output: Vec<u8>,
+}
+
+/// A protocol running in the `Stream` state machine
+///
+/// The stream owns the socket and the buffers and calls the protocol
+/// only when its expectation is satisfied (or something abnormal
+/// happened), so the protocol never deals with partial reads, partial
+/// writes and `WouldBlock`.
+pub trait Protocol: Sized {
+    type Context;
+    type Socket: Transport;
+    type Seed;
+
+    /// Create the protocol for the new connection
+    fn create(seed: Self::Seed, sock: &mut Self::Socket,
+        scope: &mut Scope<Self::Context>)
+        -> Intent<Self>;
+
+    /// The input expectation is satisfied
+    ///
+    /// The `end` is the number of bytes the expectation covers: the byte
+    /// count for `Bytes` or the end of the delimiter for `Delimiter`. Call
+    /// `Buffers::consume` for the processed part of the input.
+    fn bytes_read(self, io: &mut Buffers, end: usize,
+        scope: &mut Scope<Self::Context>)
+        -> Intent<Self>;
+
+    /// All the output has been written (`Expectation::Flush`), or the
+    /// file is sent (`Expectation::SendFile`)
+    fn bytes_flushed(self, io: &mut Buffers,
+        scope: &mut Scope<Self::Context>)
+        -> Intent<Self>;
+
+    /// The deadline is reached
+    fn timeout(self, io: &mut Buffers, scope: &mut Scope<Self::Context>)
+        -> Intent<Self>;
+
+    /// The state machine is woken up by a notifier
+    fn wakeup(self, io: &mut Buffers, scope: &mut Scope<Self::Context>)
+        -> Intent<Self>;
+
+    /// The expectation can't be satisfied any more
+    ///
+    /// By default the connection is closed.
+    fn exception(self, _io: &mut Buffers, _reason: Exception,
+        _scope: &mut Scope<Self::Context>)
+        -> Intent<Self>
+    {
+        Intent::done()
+    }
+}
+
+/// A state machine which drives a `Protocol` over a `Transport`
+///
+/// Seeds of the machine are `(protocol seed, socket)` pairs, so usually
+/// the listening state machine spawns the connections.
+pub struct Stream<P: Protocol> {
+    sock: P::Socket,
+    protocol: P,
+    expectation: Expectation,
+    deadline: Option<Time>,
+    buffers: Buffers,
+    connected: bool,
+    interest: Option<EventSet>,
+}
+
+impl<P> Intent<P> {
+    pub fn expect_bytes(protocol: P, bytes: usize) -> Intent<P> {
+        Intent(Ok(protocol), Expectation::Bytes(bytes), None)
+    }
+    pub fn expect_delimiter(protocol: P, delimiter: &'static [u8],
+        max_bytes: usize)
+        -> Intent<P>
+    {
+        Intent(Ok(protocol), Expectation::Delimiter(delimiter, max_bytes),
+               None)
+    }
+    pub fn expect_flush(protocol: P) -> Intent<P> {
+        Intent(Ok(protocol), Expectation::Flush, None)
+    }
+    /// Write the output, then `len` bytes of the file from the `offset`
+    ///
+    /// Uses `sendfile` on Linux with the `sendfile` feature, if the socket
+    /// allows (see `Transport::raw_fd`), otherwise the file is read and
+    /// written in chunks. The descriptor must stay open until
+    /// `bytes_flushed` is called, so keep the file in the protocol. If the
+    /// file is shorter, the exception is `Io` with `UnexpectedEof`.
+    #[cfg(unix)]
+    pub fn send_file(protocol: P, fd: RawFd, offset: u64, len: u64)
+        -> Intent<P>
+    {
+        Intent(Ok(protocol), Expectation::SendFile(fd, offset, len), None)
+    }
+    pub fn sleep(protocol: P) -> Intent<P> {
+        Intent(Ok(protocol), Expectation::Sleep, None)
+    }
+    /// Close the connection
+    ///
+    /// The output that isn't flushed yet is discarded, so use
+    /// `expect_flush` before closing the connection if needed.
+    pub fn done() -> Intent<P> {
+        Intent(Err(None), Expectation::Sleep, None)
+    }
+    pub fn error(e: Box<Error>) -> Intent<P> {
+        Intent(Err(Some(e)), Expectation::Sleep, None)
+    }
+    pub fn deadline(self, deadline: Time) -> Intent<P> {
+        Intent(self.0, self.1, Some(deadline))
+    }
+}
+
+impl Buffers {
+    fn new() -> Buffers {
+        Buffers {
+            input: Vec::new(),
+            segments: VecDeque::new(),
+            output: Vec::new(),
+        }
+    }
+    /// Data read from the socket and not consumed yet
+    pub fn input(&self) -> &[u8] {
+        &self.input
+    }
+    /// Remove the bytes from the start of the input buffer
+    pub fn consume(&mut self, bytes: usize) {
+        self.input.drain(..bytes);
+    }
+    /// The buffer of data to be written to the socket
+    pub fn output(&mut self) -> &mut Vec<u8> {
+        &mut self.output
+    }
+    /// Queue the buffer to write after the output, without copying
+    ///
+    /// The output written after this call goes after the buffer. Queued
+    /// buffers are written with a single `writev` where the socket allows,
+    /// so use it for the large bodies following the headers.
+    pub fn push_segment(&mut self, data: Vec<u8>) {
+        if data.is_empty() {
+            return;
+        }
+        if !self.output.is_empty() {
+            let head = mem::replace(&mut self.output, Vec::new());
+            self.segments.push_back(head);
+        }
+        self.segments.push_back(data);
+    }
+    /// Returns true if there is no data to write
+    fn is_flushed(&self) -> bool {
+        self.segments.is_empty() && self.output.is_empty()
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    if needle.is_empty() {
+        return Some(0);
+    }
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+/// Sends a part of the file to the socket, returns the number of bytes
+#[cfg(unix)]
+fn send_file<T: Transport>(sock: &mut T, fd: RawFd, offset: u64, len: u64)
+    -> io::Result<u64>
+{
+    #[cfg(all(target_os="linux", feature="sendfile"))]
+    {
+        if let Some(out) = sock.raw_fd() {
+            use libc;
+            let mut off = offset as libc::off_t;
+            let count = ::std::cmp::min(len, 0x7fff_f000) as libc::size_t;
+            let res = unsafe { libc::sendfile(out, fd, &mut off, count) };
+            if res < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            return Ok(res as u64);
+        }
+    }
+    copy_file(sock, fd, offset, len)
+}
+
+/// Sends a chunk of the file with `read_at` and `write`
+#[cfg(unix)]
+fn copy_file<T: Transport>(sock: &mut T, fd: RawFd, offset: u64, len: u64)
+    -> io::Result<u64>
+{
+    use std::fs::File;
+    use std::mem::ManuallyDrop;
+    use std::os::unix::fs::FileExt;
+    use std::os::unix::io::FromRawFd;
+
+    // The descriptor is owned by the protocol, so it's never closed here
+    let file = ManuallyDrop::new(unsafe { File::from_raw_fd(fd) });
+    let mut buf = [0u8; READ_CHUNK];
+    let chunk = ::std::cmp::min(len, READ_CHUNK as u64) as usize;
+    let bytes = try!(file.read_at(&mut buf[..chunk], offset));
+    if bytes == 0 {
+        return Ok(0);
+    }
+    // The rest of the chunk is read again with the next call
+    match try!(io::Write::write(sock, &buf[..bytes])) {
+        0 => Err(io::Error::new(io::ErrorKind::WriteZero,
+            "can't write to the socket")),
+        written => Ok(written as u64),
+    }
+}
+
+/// Result of the stream action when the protocol is not called
+enum Step {
+    /// Expectation is satisfied with `end` bytes
+    Read(usize),
+    Flushed,
+    Exception(Exception),
+    Wait,
+}
+
+impl<P: Protocol> Stream<P> {
+    /// Create a state machine for the connection
+    pub fn new<N>(seed: P::Seed, mut sock: P::Socket,
+        scope: &mut Scope<P::Context>)
+        -> Response<Stream<P>, N>
+    {
+        match P::create(seed, &mut sock, scope) {
+            Intent(Ok(protocol), expectation, deadline) => {
+                Stream {
+                    sock: sock,
+                    protocol: protocol,
+                    expectation: expectation,
+                    deadline: deadline,
+                    buffers: Buffers::new(),
+                    connected: false,
+                    interest: None,
+                }.drive(scope)
+            }
+            Intent(Err(None), _, _) => Response::done(),
+            Intent(Err(Some(e)), _, _) => Response::error(e),
+        }
+    }
+    /// Checks the expectation, doing all the I/O needed
+    fn step(&mut self) -> Step {
+        loop {
+            if let Err(e) = self.flush() {
+                return Step::Exception(Exception::Io(e));
+            }
+            match self.expectation {
+                Expectation::Bytes(bytes) => {
+                    if self.buffers.input.len() >= bytes {
+                        return Step::Read(bytes);
+                    }
+                }
+                Expectation::Delimiter(delimiter, max) => {
+                    let limit = ::std::cmp::min(self.buffers.input.len(),
+                                                max + delimiter.len());
+                    if let Some(x) = find(&self.buffers.input[..limit],
+                                          delimiter)
+                    {
+                        return Step::Read(x + delimiter.len());
+                    }
+                    if self.buffers.input.len() >= max + delimiter.len() {
+                        return Step::Exception(Exception::LimitReached);
+                    }
+                }
+                Expectation::Flush => {
+                    if self.buffers.is_flushed() &&
+                        !self.sock.wants_write()
+                    {
+                        return Step::Flushed;
+                    }
+                    return Step::Wait;
+                }
+                #[cfg(unix)]
+                Expectation::SendFile(fd, offset, len) => {
+                    if !self.buffers.is_flushed() ||
+                        self.sock.wants_write()
+                    {
+                        return Step::Wait;
+                    }
+                    if len == 0 {
+                        return Step::Flushed;
+                    }
+                    match send_file(&mut self.sock, fd, offset, len) {
+                        Ok(0) => {
+                            return Step::Exception(Exception::Io(
+                                io::Error::new(io::ErrorKind::UnexpectedEof,
+                                    "file is shorter than expected")));
+                        }
+                        Ok(bytes) => {
+                            self.expectation = Expectation::SendFile(fd,
+                                offset + bytes, len - bytes);
+                            continue;
+                        }
+                        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock
+                        => return Step::Wait,
+                        Err(e) => return Step::Exception(Exception::Io(e)),
+                    }
+                }
+                Expectation::Sleep => return Step::Wait,
+            }
+            match self.read() {
+                Ok(true) => continue,
+                Ok(false) => return Step::Exception(Exception::EndOfStream),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    return Step::Wait;
+                }
+                Err(e) => return Step::Exception(Exception::Io(e)),
+            }
+        }
+    }
+    /// Reads a chunk of data, returns false on end of stream
+    fn read(&mut self) -> io::Result<bool> {
+        let start = self.buffers.input.len();
+        self.buffers.input.resize(start + READ_CHUNK, 0);
+        let res = io::Read::read(&mut self.sock,
+                                 &mut self.buffers.input[start..]);
+        let bytes = *res.as_ref().unwrap_or(&0);
+        self.buffers.input.truncate(start + bytes);
+        res.map(|bytes| bytes > 0)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        while !self.buffers.is_flushed() {
+            match write_segments(&mut self.sock, &self.buffers.segments,
+                                 &self.buffers.output)
+            {
+                Ok(0) => {
+                    return Err(io::Error::new(io::ErrorKind::WriteZero,
+                        "can't write to the socket"));
+                }
+                Ok(bytes) => {
+                    let rest = consume(&mut self.buffers.segments, bytes);
+                    self.buffers.output.drain(..rest);
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    return Ok(());
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        match io::Write::flush(&mut self.sock) {
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok(()),
+            res => res,
+        }
+    }
+    fn drive<N>(mut self, scope: &mut Scope<P::Context>)
+        -> Response<Stream<P>, N>
+    {
+        if !self.connected {
+            match self.sock.handshake() {
+                Ok(Handshake::Done) => self.connected = true,
+                Ok(Handshake::WantRead) => {
+                    return self.wait(EventSet::readable(), scope);
+                }
+                Ok(Handshake::WantWrite) => {
+                    return self.wait(EventSet::writable(), scope);
+                }
+                Err(e) => {
+                    return self.call(scope, |p, io, scope| {
+                        p.exception(io, Exception::Io(e), scope)
+                    });
+                }
+            }
+        }
+        loop {
+            let res = match self.step() {
+                Step::Read(end) => self.invoke(scope, |p, io, scope| {
+                    p.bytes_read(io, end, scope)
+                }),
+                Step::Flushed => self.invoke(scope, |p, io, scope| {
+                    p.bytes_flushed(io, scope)
+                }),
+                Step::Exception(e) => self.invoke(scope, |p, io, scope| {
+                    p.exception(io, e, scope)
+                }),
+                Step::Wait => break,
+            };
+            self = match res {
+                Ok(me) => me,
+                Err(None) => return Response::done(),
+                Err(Some(e)) => return Response::error(e),
+            };
+        }
+        let mut interest = EventSet::none();
+        match self.expectation {
+            Expectation::Bytes(..) | Expectation::Delimiter(..) => {
+                interest = interest | EventSet::readable();
+            }
+            #[cfg(unix)]
+            Expectation::SendFile(..) => {
+                interest = interest | EventSet::writable();
+            }
+            Expectation::Flush | Expectation::Sleep => {}
+        }
+        if !self.buffers.is_flushed() || self.sock.wants_write() {
+            interest = interest | EventSet::writable();
+        }
+        self.wait(interest, scope)
+    }
+    /// Calls the protocol and continues the I/O
+    fn call<N, F>(self, scope: &mut Scope<P::Context>, fun: F)
+        -> Response<Stream<P>, N>
+        where F: FnOnce(P, &mut Buffers, &mut Scope<P::Context>) -> Intent<P>
+    {
+        match self.invoke(scope, fun) {
+            Ok(me) => me.drive(scope),
+            Err(None) => Response::done(),
+            Err(Some(e)) => Response::error(e),
+        }
+    }
+    fn invoke<F>(self, scope: &mut Scope<P::Context>, fun: F)
+        -> Result<Stream<P>, Option<Box<Error>>>
+        where F: FnOnce(P, &mut Buffers, &mut Scope<P::Context>) -> Intent<P>
+    {
+        let Stream { sock, protocol, mut buffers, connected, interest, .. }
+            = self;
+        let Intent(protocol, expectation, deadline) =
+            fun(protocol, &mut buffers, scope);
+        Ok(Stream {
+            sock: sock,
+            protocol: try!(protocol),
+            expectation: expectation,
+            deadline: deadline,
+            buffers: buffers,
+            connected: connected,
+            interest: interest,
+        })
+    }
+    fn wait<N>(mut self, interest: EventSet, scope: &mut Scope<P::Context>)
+        -> Response<Stream<P>, N>
+    {
+        let res = match self.interest {
+            Some(old) if old == interest => Ok(()),
+            Some(_) => scope.reregister(&self.sock, interest,
+                                        PollOpt::level()),
+            None => scope.register(&self.sock, interest, PollOpt::level()),
+        };
+        if let Err(e) = res {
+            return Response::error(Box::new(e));
+        }
+        self.interest = Some(interest);
+        match self.deadline {
+            Some(deadline) => Response::ok(self).deadline(deadline),
+            None => Response::ok(self),
+        }
+    }
+}
+
+impl<P: Protocol> Machine for Stream<P> {
+    type Context = P::Context;
+    type Seed = (P::Seed, P::Socket);
+
+    fn create((seed, sock): Self::Seed, scope: &mut Scope<P::Context>)
+        -> Response<Self, Self::Seed>
+    {
+        Stream::new(seed, sock, scope)
+    }
+    fn ready(self, _events: EventSet, scope: &mut Scope<P::Context>)
+        -> Response<Self, Self::Seed>
+    {
+        self.drive(scope)
+    }
+    fn spawned(self, _scope: &mut Scope<P::Context>)
+        -> Response<Self, Self::Seed>
+    {
+        unreachable!();
+    }
+    fn timeout(self, scope: &mut Scope<P::Context>)
+        -> Response<Self, Self::Seed>
+    {
+        let expired = self.deadline.map(|x| x <= scope.now())
+            .unwrap_or(false);
+        if expired {
+            self.call(scope, |p, io, scope| p.timeout(io, scope))
+        } else {
+            self.drive(scope)
+        }
+    }
+    fn wakeup(self, scope: &mut Scope<P::Context>)
+        -> Response<Self, Self::Seed>
+    {
+        self.call(scope, |p, io, scope| p.wakeup(io, scope))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::find;
+
+    #[test]
+    fn find_delimiter() {
+        assert_eq!(find(b"GET / HTTP/1.1\r\n\r\n", b"\r\n\r\n"), Some(14));
+        assert_eq!(find(b"abc", b"cd"), None);
+        assert_eq!(find(b"", b"\n"), None);
+        assert_eq!(find(b"abc", b""), Some(0));
+    }
+}
@@ -0,0 +1,467 @@
+use std::mem;
+use std::rc::Rc;
+use std::time::{Instant, Duration};
+
+use mio;
+use clock::Clock;
+use handler::Timeo;
+use loop_api::MioLoop;
+use loop_time::millis;
+
+/// Bits of the deadline handled by each level of the wheel
+const LEVEL_BITS: u32 = 6;
+const SLOTS: usize = 1 << LEVEL_BITS;
+/// Enough levels to cover the whole range of `u64` milliseconds
+const LEVELS: usize = 11;
+
+
+/// A handle of the timeout in the loop
+///
+/// Returned by `Scope::timeout_ms` and used to cancel the timeout with
+/// `Scope::clear_timeout`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timeout {
+    index: usize,
+    generation: u64,
+}
+
+struct Entry<T> {
+    deadline: u64,
+    slot: usize,
+    pos: usize,
+    value: T,
+}
+
+/// Hierarchical timer wheel with millisecond resolution
+///
+/// Each level has 64 slots, a slot on level `N` covers `64^N` milliseconds.
+/// Timeouts are put into the level by the highest bit in which the deadline
+/// differs from the current time, and are moved to lower levels when
+/// the time reaches their slot. Inserting and cancelling is O(1), and there
+/// is no capacity limit.
+pub struct TimerWheel<T> {
+    entries: Vec<(u64, Option<Entry<T>>)>,
+    free: Vec<usize>,
+    slots: Vec<Vec<usize>>,
+    now: u64,
+    len: usize,
+}
+
+/// Timers of the loop
+///
+/// All the timeouts of the loop are kept in the wheel, and only a single
+/// mio timeout is armed for the earliest of them.
+#[doc(hidden)]
+pub struct Timers {
+    wheel: TimerWheel<Timeo>,
+    start: Instant,
+    clock: Rc<Clock>,
+    armed: Option<(mio::timer::Timeout, u64)>,
+}
+
+fn level_of(now: u64, deadline: u64) -> usize {
+    let diff = now ^ deadline;
+    ((63 - diff.leading_zeros()) / LEVEL_BITS) as usize
+}
+
+fn slot_index(level: usize, deadline: u64) -> usize {
+    let shift = level as u32 * LEVEL_BITS;
+    level*SLOTS + ((deadline >> shift) as usize & (SLOTS-1))
+}
+
+/// Start time of the slot on the level, which is after the current time
+fn slot_start(level: usize, slot: usize, now: u64) -> u64 {
+    let shift = level as u32 * LEVEL_BITS;
+    let upper = shift + LEVEL_BITS;
+    let prefix = if upper >= 64 { 0 } else { (now >> upper) << upper };
+    prefix | ((slot as u64) << shift)
+}
+
+impl<T> TimerWheel<T> {
+    pub fn new(now: u64) -> TimerWheel<T> {
+        TimerWheel {
+            entries: Vec::new(),
+            free: Vec::new(),
+            slots: (0..SLOTS*LEVELS).map(|_| Vec::new()).collect(),
+            now: now,
+            len: 0,
+        }
+    }
+    /// Insert a timeout, deadlines in the past expire on the next tick
+    pub fn insert(&mut self, deadline: u64, value: T) -> Timeout {
+        let deadline = if deadline > self.now { deadline } else { self.now+1 };
+        let index = match self.free.pop() {
+            Some(index) => index,
+            None => {
+                self.entries.push((0, None));
+                self.entries.len() - 1
+            }
+        };
+        let generation = self.entries[index].0;
+        self.entries[index].1 = Some(Entry {
+            deadline: deadline,
+            slot: 0,
+            pos: 0,
+            value: value,
+        });
+        self.link(index);
+        self.len += 1;
+        Timeout { index: index, generation: generation }
+    }
+    /// Cancel the timeout, returns false if it's already expired
+    pub fn cancel(&mut self, timeout: Timeout) -> bool {
+        match self.entries.get(timeout.index) {
+            Some(&(gen, Some(_))) if gen == timeout.generation => {}
+            _ => return false,
+        }
+        self.unlink(timeout.index);
+        self.release(timeout.index);
+        true
+    }
+    /// Move the timeout to the new deadline keeping the handle valid
+    ///
+    /// Returns false if the timeout is already expired or cancelled.
+    pub fn reschedule(&mut self, timeout: Timeout, deadline: u64) -> bool {
+        match self.entries.get(timeout.index) {
+            Some(&(gen, Some(_))) if gen == timeout.generation => {}
+            _ => return false,
+        }
+        let deadline = if deadline > self.now { deadline } else { self.now+1 };
+        let slot = slot_index(level_of(self.now, deadline), deadline);
+        let same_slot = {
+            let entry = self.entries[timeout.index].1.as_mut()
+                .expect("linked timeout exists");
+            entry.deadline = deadline;
+            entry.slot == slot
+        };
+        if !same_slot {
+            self.unlink(timeout.index);
+            self.link(timeout.index);
+        }
+        true
+    }
+    /// The time at which `advance` should be called next
+    ///
+    /// It's exact for timeouts close to the current time and a lower bound
+    /// for the further ones.
+    pub fn next_deadline(&self) -> Option<u64> {
+        if self.len == 0 {
+            return None;
+        }
+        for level in 0..LEVELS {
+            let cur = slot_index(level, self.now) - level*SLOTS;
+            for slot in cur+1..SLOTS {
+                if !self.slots[level*SLOTS + slot].is_empty() {
+                    return Some(slot_start(level, slot, self.now));
+                }
+            }
+        }
+        None
+    }
+    /// Advance time, and return values of the expired timeouts
+    #[cfg(test)]
+    pub fn advance(&mut self, now: u64) -> Vec<T> {
+        self.advance_deadlines(now).into_iter().map(|(_, v)| v).collect()
+    }
+    /// Same as `advance` but returns the deadlines too
+    ///
+    /// Timeouts are ordered by deadline, the order of the ones with the
+    /// same deadline is unspecified.
+    pub fn advance_deadlines(&mut self, now: u64) -> Vec<(u64, T)> {
+        let mut expired = Vec::new();
+        loop {
+            let next = match self.next_deadline() {
+                Some(next) if next <= now => next,
+                _ => break,
+            };
+            self.now = next;
+            for level in (0..LEVELS).rev() {
+                let shift = level as u32 * LEVEL_BITS;
+                if shift < 64 && next & ((1 << shift) - 1) != 0 {
+                    continue;
+                }
+                let slot = slot_index(level, next);
+                let items = mem::replace(&mut self.slots[slot], Vec::new());
+                for index in items {
+                    let deadline = self.entries[index].1.as_ref()
+                        .expect("linked timeout exists").deadline;
+                    if deadline <= next {
+                        let entry = self.release(index);
+                        expired.push((entry.deadline, entry.value));
+                    } else {
+                        self.link(index);
+                    }
+                }
+            }
+        }
+        if now > self.now {
+            self.now = now;
+        }
+        expired
+    }
+    fn link(&mut self, index: usize) {
+        let now = self.now;
+        let entry = self.entries[index].1.as_mut()
+            .expect("linked timeout exists");
+        let slot = slot_index(level_of(now, entry.deadline), entry.deadline);
+        entry.slot = slot;
+        entry.pos = self.slots[slot].len();
+        self.slots[slot].push(index);
+    }
+    fn unlink(&mut self, index: usize) {
+        let (slot, pos) = {
+            let entry = self.entries[index].1.as_ref()
+                .expect("linked timeout exists");
+            (entry.slot, entry.pos)
+        };
+        self.slots[slot].swap_remove(pos);
+        if let Some(&moved) = self.slots[slot].get(pos) {
+            self.entries[moved].1.as_mut()
+                .expect("linked timeout exists").pos = pos;
+        }
+    }
+    fn release(&mut self, index: usize) -> Entry<T> {
+        let item = &mut self.entries[index];
+        item.0 = item.0.wrapping_add(1);
+        self.free.push(index);
+        self.len -= 1;
+        item.1.take().expect("released timeout exists")
+    }
+}
+
+impl Timers {
+    pub fn new(start: Instant, clock: Rc<Clock>) -> Timers {
+        Timers {
+            // Loop time starts with 1, see `make_time`
+            wheel: TimerWheel::new(1),
+            start: start,
+            clock: clock,
+            armed: None,
+        }
+    }
+    pub fn start(&self) -> Instant {
+        self.start
+    }
+    /// Replace the clock, the loop time starts over from its current time
+    pub fn set_clock(&mut self, clock: Rc<Clock>) {
+        self.start = clock.now();
+        self.clock = clock;
+    }
+    fn now(&self) -> u64 {
+        millis(self.clock.now().duration_since(self.start)) + 1
+    }
+    pub fn insert(&mut self, delay: u64, value: Timeo) -> Timeout {
+        let now = self.now();
+        self.wheel.insert(now.saturating_add(delay), value)
+    }
+    pub fn cancel(&mut self, timeout: Timeout) -> bool {
+        self.wheel.cancel(timeout)
+    }
+    pub fn reschedule(&mut self, timeout: Timeout, delay: u64) -> bool {
+        let now = self.now();
+        self.wheel.reschedule(timeout, now.saturating_add(delay))
+    }
+    /// Number of timeouts in the wheel
+    pub fn pending(&self) -> usize {
+        self.wheel.len
+    }
+    /// Loop time (in milliseconds) of the earliest timeout, see
+    /// `TimerWheel::next_deadline`
+    pub fn next_deadline(&self) -> Option<u64> {
+        self.wheel.next_deadline()
+    }
+    /// How long the poll may block when arming the mio timer has failed
+    ///
+    /// Nothing wakes up the loop for the timeouts then, so the poll must
+    /// return by the earliest of them, and `timers_due` fires it. Returns
+    /// `None` if the timer is armed or there are no timeouts.
+    pub fn unarmed_delay(&self) -> Option<Duration> {
+        if self.armed.is_some() {
+            return None;
+        }
+        self.wheel.next_deadline().map(|next| {
+            // Loop time is one millisecond ahead, see `Timers::new`
+            let deadline = self.start + Duration::from_millis(next - 1);
+            self.clock.sleep_until(deadline)
+        })
+    }
+    /// Returns expired timeouts, the mio timer must be rearmed afterwards
+    ///
+    /// Timeouts are in the order of their deadlines, the ones expiring in
+    /// the same millisecond are ordered by token.
+    pub fn expire(&mut self) -> Vec<Timeo> {
+        self.armed = None;
+        let now = self.now();
+        dispatch_order(self.wheel.advance_deadlines(now))
+    }
+}
+
+fn dispatch_order(mut expired: Vec<(u64, Timeo)>) -> Vec<Timeo> {
+    expired.sort_by_key(|&(deadline, ref timeo)| (deadline, timeo.token()));
+    expired.into_iter().map(|(_, timeo)| timeo).collect()
+}
+
+/// Arms the mio timer for the earliest timeout if it's not armed yet
+pub fn arm_timer<E: MioLoop>(timers: &mut Timers, eloop: &mut E) {
+    let next = match timers.wheel.next_deadline() {
+        Some(next) => next,
+        None => return,
+    };
+    if timers.armed.as_ref().map(|&(_, time)| time <= next).unwrap_or(false) {
+        return;
+    }
+    if let Some((old, _)) = timers.armed.take() {
+        eloop.clear_timeout(&old);
+    }
+    // Loop time is one millisecond ahead, see `Timers::new`
+    let deadline = timers.start + Duration::from_millis(next - 1);
+    let delay = timers.clock.sleep_until(deadline);
+    match eloop.timeout(Timeo::Wheel, delay) {
+        Ok(tok) => timers.armed = Some((tok, next)),
+        // Left unarmed, retried on the next tick by `rearm_timer`
+        Err(e) => warn!("Can't arm the timer: {:?}", e),
+    }
+}
+
+/// Returns true if the earliest timeout is due by the clock
+///
+/// The mio timer sleeps for the real time, which is late for a clock
+/// moved by hand (or one coarser than the timer), so the loop checks
+/// the deadline on every tick. The armed mio timer is cleared then.
+pub fn timers_due<E: MioLoop>(timers: &mut Timers, eloop: &mut E) -> bool {
+    let due = match timers.wheel.next_deadline() {
+        Some(next) => next <= timers.now(),
+        None => false,
+    };
+    if due {
+        if let Some((old, _)) = timers.armed.take() {
+            eloop.clear_timeout(&old);
+        }
+    }
+    due
+}
+
+/// Arms the mio timer if arming has failed before
+pub fn rearm_timer<E: MioLoop>(timers: &mut Timers, eloop: &mut E) {
+    if timers.armed.is_none() && timers.wheel.len > 0 {
+        arm_timer(timers, eloop);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::rc::Rc;
+    use std::time::Duration;
+    use mio::Token;
+    use handler::Timeo;
+    use clock::{Clock, ManualClock};
+    use super::{TimerWheel, Timers, dispatch_order};
+
+    #[test]
+    fn expire_in_order() {
+        let mut w = TimerWheel::new(1);
+        w.insert(100, 'b');
+        w.insert(5, 'a');
+        w.insert(100000, 'c');
+        w.insert(1 << 40, 'd');
+        assert_eq!(w.len, 4);
+        assert_eq!(w.next_deadline(), Some(5));
+        assert_eq!(w.advance(4), vec![]);
+        assert_eq!(w.advance(5), vec!['a']);
+        assert_eq!(w.advance(99999), vec!['b']);
+        assert_eq!(w.advance(100000), vec!['c']);
+        assert_eq!(w.advance(1 << 39), vec![]);
+        assert_eq!(w.advance((1 << 40) + 1), vec!['d']);
+        assert_eq!(w.len, 0);
+        assert_eq!(w.next_deadline(), None);
+    }
+
+    #[test]
+    fn cancel() {
+        let mut w = TimerWheel::new(1);
+        let a = w.insert(10, 'a');
+        let b = w.insert(10, 'b');
+        w.insert(10, 'c');
+        assert!(w.cancel(a));
+        assert!(!w.cancel(a));
+        assert_eq!(w.advance(10), vec!['c', 'b']);
+        assert!(!w.cancel(b));
+        // slot is reused, but the old handle is not valid
+        w.insert(20, 'd');
+        assert!(!w.cancel(b));
+        assert_eq!(w.len, 1);
+    }
+
+    #[test]
+    fn reschedule() {
+        let mut w = TimerWheel::new(1);
+        let a = w.insert(10, 'a');
+        w.insert(20, 'b');
+        assert!(w.reschedule(a, 30));
+        assert_eq!(w.advance(25), vec!['b']);
+        assert!(w.reschedule(a, 5000));
+        assert_eq!(w.advance(4999), vec![]);
+        assert_eq!(w.advance(5000), vec!['a']);
+        assert!(!w.reschedule(a, 6000));
+        assert_eq!(w.len, 0);
+    }
+
+    #[test]
+    fn past_deadline() {
+        let mut w = TimerWheel::new(1000);
+        w.insert(10, 'a');
+        assert_eq!(w.next_deadline(), Some(1001));
+        assert_eq!(w.advance(1001), vec!['a']);
+    }
+
+    #[test]
+    fn many() {
+        let mut w = TimerWheel::new(1);
+        for i in 0..10000u64 {
+            w.insert(2 + i*i % 77777, i);
+        }
+        let mut prev = 0;
+        let mut total = 0;
+        for now in (2..80000).filter(|x| x % 333 == 0) {
+            for value in w.advance(now) {
+                let deadline = 2 + value*value % 77777;
+                assert!(deadline <= now);
+                assert!(deadline > prev);
+                total += 1;
+            }
+            prev = now;
+        }
+        for value in w.advance(80000) {
+            assert!(2 + value*value % 77777 > prev);
+            total += 1;
+        }
+        assert_eq!(total, 10000);
+    }
+
+    #[test]
+    fn same_tick_by_token() {
+        let mut w = TimerWheel::new(1);
+        w.insert(10, Timeo::Fsm(Token(7), 0));
+        w.insert(10, Timeo::Idle(Token(2), 0));
+        w.insert(9, Timeo::Fsm(Token(9), 0));
+        let a = w.insert(10, Timeo::Fsm(Token(1), 0));
+        w.insert(10, Timeo::Fsm(Token(5), 0));
+        w.cancel(a);
+        let tokens: Vec<_> = dispatch_order(w.advance_deadlines(10))
+            .iter().map(|t| t.token()).collect();
+        assert_eq!(tokens, vec![9, 2, 5, 7]);
+    }
+
+    #[test]
+    fn unarmed_delay() {
+        let clock = ManualClock::new();
+        let mut timers = Timers::new(clock.now(), Rc::new(clock.clone()));
+        assert_eq!(timers.unarmed_delay(), None);
+        timers.insert(250, Timeo::Wheel);
+        let delay = timers.unarmed_delay().unwrap();
+        assert!(delay > Duration::new(0, 0));
+        assert!(delay <= Duration::from_millis(250));
+        clock.advance(Duration::from_millis(300));
+        assert_eq!(timers.unarmed_delay(), Some(Duration::new(0, 0)));
+    }
+}
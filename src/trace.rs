@@ -0,0 +1,336 @@
+use std::io::{self, Read, Write, BufWriter};
+use std::collections::VecDeque;
+
+use mio::Token;
+
+use loop_time::{make_time_ms, time_ms};
+use {Time, EventSet};
+
+
+/// Written at the start of the trace, the last byte is the version
+const TRACE_MAGIC: &'static [u8; 8] = b"rotortr1";
+/// Kind, readiness, time and token
+const RECORD_SIZE: usize = 18;
+
+
+/// Kind of the event dispatched to a state machine
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventKind {
+    Ready(EventSet),
+    Timeout,
+    Wakeup,
+}
+
+/// A single entry of the loop's dispatch history
+///
+/// See `Scope::event_cursor`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DispatchEvent {
+    /// Sequence number of the event, starts with zero for every loop
+    pub seq: u64,
+    /// Loop time when the event was dispatched
+    pub time: Time,
+    /// State machine which has received the event
+    pub token: Token,
+    pub kind: EventKind,
+}
+
+/// A position in the dispatch history of the loop
+///
+/// Created by `Scope::event_cursor` and advanced by `Scope::read_events`.
+#[derive(Debug, Clone)]
+pub struct EventCursor {
+    next: u64,
+    missed: u64,
+}
+
+/// Ring buffer of recently dispatched events
+#[doc(hidden)]
+pub struct EventLog {
+    events: VecDeque<DispatchEvent>,
+    capacity: usize,
+    next_seq: u64,
+}
+
+/// Writes the dispatched events to the trace, see `Loop::record_events`
+///
+/// Every event is 18 bytes: the kind, the readiness flags, the loop time
+/// in milliseconds and the token, the numbers are little endian.
+#[doc(hidden)]
+pub struct TraceWriter {
+    out: BufWriter<Box<Write>>,
+    failed: bool,
+}
+
+/// Reads the trace written by `Loop::record_events`
+///
+/// Iterates over the events, see `LoopInstance::replay`.
+pub struct TraceReader<R> {
+    input: R,
+    next_seq: u64,
+}
+
+fn encode_ready(events: EventSet) -> u8 {
+    (events.is_readable() as u8) |
+    (events.is_writable() as u8) << 1 |
+    (events.is_error() as u8) << 2 |
+    (events.is_hup() as u8) << 3
+}
+
+fn decode_ready(flags: u8) -> EventSet {
+    let mut events = EventSet::empty();
+    for &(bit, flag) in &[(1, EventSet::readable()),
+                          (2, EventSet::writable()),
+                          (4, EventSet::error()),
+                          (8, EventSet::hup())]
+    {
+        if flags & bit != 0 {
+            events = events | flag;
+        }
+    }
+    events
+}
+
+fn encode(time: Time, token: Token, kind: EventKind)
+    -> [u8; RECORD_SIZE]
+{
+    let mut buf = [0u8; RECORD_SIZE];
+    match kind {
+        EventKind::Ready(events) => {
+            buf[0] = 0;
+            buf[1] = encode_ready(events);
+        }
+        EventKind::Timeout => buf[0] = 1,
+        EventKind::Wakeup => buf[0] = 2,
+    }
+    buf[2..10].copy_from_slice(&time_ms(time).to_le_bytes());
+    buf[10..18].copy_from_slice(&(token.0 as u64).to_le_bytes());
+    buf
+}
+
+fn decode(buf: &[u8; RECORD_SIZE], seq: u64) -> io::Result<DispatchEvent> {
+    let mut num = [0u8; 8];
+    num.copy_from_slice(&buf[2..10]);
+    let time = make_time_ms(u64::from_le_bytes(num));
+    num.copy_from_slice(&buf[10..18]);
+    let token = Token(u64::from_le_bytes(num) as usize);
+    let kind = match buf[0] {
+        0 => EventKind::Ready(decode_ready(buf[1])),
+        1 => EventKind::Timeout,
+        2 => EventKind::Wakeup,
+        _ => {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                "unknown event kind in the trace"));
+        }
+    };
+    Ok(DispatchEvent {
+        seq: seq,
+        time: time,
+        token: token,
+        kind: kind,
+    })
+}
+
+impl TraceWriter {
+    pub fn new(out: Box<Write>) -> io::Result<TraceWriter> {
+        let mut out = BufWriter::new(out);
+        try!(out.write_all(TRACE_MAGIC));
+        Ok(TraceWriter {
+            out: out,
+            failed: false,
+        })
+    }
+    /// Errors stop the recording, so the trace is never inconsistent
+    pub fn record(&mut self, time: Time, token: Token, kind: EventKind) {
+        if self.failed {
+            return;
+        }
+        if let Err(e) = self.out.write_all(&encode(time, token, kind)) {
+            warn!("Error writing event trace, recording stopped: {}", e);
+            self.failed = true;
+        }
+    }
+    /// Called at the end of every loop iteration
+    pub fn flush(&mut self) {
+        if self.failed {
+            return;
+        }
+        if let Err(e) = self.out.flush() {
+            warn!("Error writing event trace, recording stopped: {}", e);
+            self.failed = true;
+        }
+    }
+}
+
+impl<R: Read> TraceReader<R> {
+    /// Check the header of the trace
+    pub fn new(mut input: R) -> io::Result<TraceReader<R>> {
+        let mut magic = [0u8; 8];
+        try!(input.read_exact(&mut magic));
+        if &magic != TRACE_MAGIC {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                "not a rotor event trace"));
+        }
+        Ok(TraceReader {
+            input: input,
+            next_seq: 0,
+        })
+    }
+}
+
+impl<R: Read> Iterator for TraceReader<R> {
+    type Item = io::Result<DispatchEvent>;
+    fn next(&mut self) -> Option<io::Result<DispatchEvent>> {
+        let mut buf = [0u8; RECORD_SIZE];
+        let mut filled = 0;
+        while filled < RECORD_SIZE {
+            match self.input.read(&mut buf[filled..]) {
+                Ok(0) if filled == 0 => return None,
+                Ok(0) => {
+                    return Some(Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "truncated event trace")));
+                }
+                Ok(n) => filled += n,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Some(Err(e)),
+            }
+        }
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        Some(decode(&buf, seq))
+    }
+}
+
+impl EventCursor {
+    /// Number of events dropped from the history before they were read
+    ///
+    /// Means the cursor is read too rarely for the history size
+    /// (`Config::event_history`).
+    pub fn missed(&self) -> u64 {
+        self.missed
+    }
+}
+
+impl EventLog {
+    pub fn new(capacity: usize) -> EventLog {
+        EventLog {
+            events: VecDeque::with_capacity(capacity),
+            capacity: capacity,
+            next_seq: 0,
+        }
+    }
+    pub fn push(&mut self, time: Time, token: Token, kind: EventKind) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.events.len() >= self.capacity {
+            self.events.pop_front();
+        }
+        self.events.push_back(DispatchEvent {
+            seq: self.next_seq,
+            time: time,
+            token: token,
+            kind: kind,
+        });
+        self.next_seq += 1;
+    }
+    /// Create a cursor which starts `replay` events back from now
+    pub fn cursor(&self, replay: usize) -> EventCursor {
+        let replay = if replay > self.events.len() {
+            self.events.len()
+        } else {
+            replay
+        };
+        EventCursor {
+            next: self.next_seq - replay as u64,
+            missed: 0,
+        }
+    }
+    /// Returns all the events after the cursor, and advances it
+    pub fn read(&self, cursor: &mut EventCursor) -> Vec<DispatchEvent> {
+        let first = self.next_seq - self.events.len() as u64;
+        if cursor.next < first {
+            cursor.missed += first - cursor.next;
+            cursor.next = first;
+        }
+        let skip = (cursor.next - first) as usize;
+        let result: Vec<_> = self.events.iter().skip(skip).cloned().collect();
+        cursor.next = self.next_seq;
+        result
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io;
+    use std::time::Duration;
+
+    use mio::Token;
+    use super::{EventLog, EventKind, TraceReader, TRACE_MAGIC, encode};
+    use {Time, EventSet};
+
+    fn seqs(log: &EventLog, cur: &mut super::EventCursor) -> Vec<u64> {
+        log.read(cur).iter().map(|e| e.seq).collect()
+    }
+
+    #[test]
+    fn replay() {
+        let mut log = EventLog::new(3);
+        for _ in 0..5 {
+            log.push(Time::zero(), Token(1), EventKind::Wakeup);
+        }
+        let mut all = log.cursor(100);
+        let mut last = log.cursor(1);
+        let mut fresh = log.cursor(0);
+        assert_eq!(seqs(&log, &mut all), vec![2, 3, 4]);
+        assert_eq!(seqs(&log, &mut last), vec![4]);
+        assert_eq!(seqs(&log, &mut fresh), vec![]);
+        log.push(Time::zero(), Token(2), EventKind::Timeout);
+        assert_eq!(seqs(&log, &mut fresh), vec![5]);
+        assert_eq!(seqs(&log, &mut all), vec![5]);
+    }
+
+    #[test]
+    fn missed() {
+        let mut log = EventLog::new(2);
+        let mut cur = log.cursor(0);
+        for _ in 0..5 {
+            log.push(Time::zero(), Token(1), EventKind::Wakeup);
+        }
+        assert_eq!(seqs(&log, &mut cur), vec![3, 4]);
+        assert_eq!(cur.missed(), 3);
+    }
+
+    #[test]
+    fn encode_decode() {
+        let events = [
+            (Time::zero(), Token(0), EventKind::Wakeup),
+            (Time::zero() + Duration::from_millis(1500), Token(7),
+             EventKind::Ready(EventSet::readable() | EventSet::hup())),
+            (Time::zero() + Duration::from_secs(3), Token(!0 >> 8),
+             EventKind::Timeout),
+        ];
+        let mut trace = TRACE_MAGIC.to_vec();
+        for &(time, token, kind) in &events {
+            trace.extend_from_slice(&encode(time, token, kind));
+        }
+        let decoded = TraceReader::new(&trace[..]).unwrap()
+            .map(|e| e.unwrap())
+            .map(|e| (e.time, e.token, e.kind))
+            .collect::<Vec<_>>();
+        assert_eq!(&decoded[..], &events[..]);
+        trace.pop();
+        let last = TraceReader::new(&trace[..]).unwrap().last().unwrap();
+        assert_eq!(last.unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn disabled() {
+        let mut log = EventLog::new(0);
+        let mut cur = log.cursor(10);
+        log.push(Time::zero(), Token(1), EventKind::Wakeup);
+        assert_eq!(seqs(&log, &mut cur), vec![]);
+        assert_eq!(cur.missed(), 0);
+    }
+}
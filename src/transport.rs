@@ -0,0 +1,201 @@
+use std::io::{self, Read, Write};
+#[cfg(unix)] use std::os::unix::io::{AsRawFd, RawFd};
+
+use mio::{Evented, Poll, Token, Ready, PollOpt};
+use mio::tcp::TcpStream;
+#[cfg(unix)] use mio::deprecated::unix::UnixStream;
+
+
+/// Progress of the transport handshake
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Handshake {
+    /// Handshake is complete, application data may be exchanged
+    Done,
+    /// The transport waits for the socket to become readable
+    WantRead,
+    /// The transport waits for the socket to become writable
+    WantWrite,
+}
+
+/// A byte stream which a protocol state machine runs over
+///
+/// Reads and writes follow the non-blocking conventions of the sockets:
+/// `WouldBlock` errors mean "try again on the next event". Protocol state
+/// machines written against `Transport` work the same over plain TCP
+/// (implemented for `TcpStream` and `UnixStream`) and over TLS (see
+/// `TlsTransport`).
+pub trait Transport: Read + Write + Evented {
+    /// Drive the handshake, it's called until it returns `Done`
+    ///
+    /// No data is read or written by the application before handshake
+    /// is done. Plain sockets have no handshake.
+    fn handshake(&mut self) -> io::Result<Handshake> {
+        Ok(Handshake::Done)
+    }
+    /// Returns true if the transport has buffered data to write to the
+    /// socket, i.e. `flush()` should be called when it becomes writable
+    fn wants_write(&self) -> bool {
+        false
+    }
+    /// The descriptor to write the files to directly, with `sendfile`
+    ///
+    /// Returns `None` if the data must go through `Write`, e.g. for TLS.
+    #[cfg(unix)]
+    fn raw_fd(&self) -> Option<RawFd> {
+        None
+    }
+}
+
+impl Transport for TcpStream {
+    #[cfg(unix)]
+    fn raw_fd(&self) -> Option<RawFd> {
+        Some(self.as_raw_fd())
+    }
+}
+
+#[cfg(unix)]
+impl Transport for UnixStream {
+    fn raw_fd(&self) -> Option<RawFd> {
+        Some(self.as_raw_fd())
+    }
+}
+
+/// The integration point for TLS libraries
+///
+/// Mirrors the session interface of rustls (`read_tls`, `write_tls`, ...):
+/// plaintext is read and written through the `Read` and `Write` traits and
+/// the encrypted records are exchanged with the socket by `read_tls` and
+/// `write_tls`. Implement it for the session type of the TLS library and
+/// wrap the socket into the `TlsTransport`.
+pub trait TlsSession: Read + Write {
+    /// Read TLS records from the socket
+    fn read_tls(&mut self, rd: &mut Read) -> io::Result<usize>;
+    /// Write TLS records to the socket
+    fn write_tls(&mut self, wr: &mut Write) -> io::Result<usize>;
+    /// Process the records received by `read_tls`
+    fn process_new_packets(&mut self) -> io::Result<()>;
+    fn is_handshaking(&self) -> bool;
+    fn wants_read(&self) -> bool;
+    fn wants_write(&self) -> bool;
+}
+
+/// A TLS transport over some other (usually plain) transport
+pub struct TlsTransport<S, T> {
+    session: S,
+    sock: T,
+}
+
+impl<S: TlsSession, T: Transport> TlsTransport<S, T> {
+    pub fn new(session: S, sock: T) -> TlsTransport<S, T> {
+        TlsTransport {
+            session: session,
+            sock: sock,
+        }
+    }
+    pub fn session(&self) -> &S {
+        &self.session
+    }
+    pub fn get_ref(&self) -> &T {
+        &self.sock
+    }
+    pub fn into_inner(self) -> (S, T) {
+        (self.session, self.sock)
+    }
+    /// Reads records from the socket, returns false on end of stream
+    fn read_records(&mut self) -> io::Result<bool> {
+        if try!(self.session.read_tls(&mut self.sock)) == 0 {
+            return Ok(false);
+        }
+        try!(self.session.process_new_packets());
+        Ok(true)
+    }
+    fn write_records(&mut self) -> io::Result<()> {
+        while self.session.wants_write() {
+            try!(self.session.write_tls(&mut self.sock));
+        }
+        Ok(())
+    }
+}
+
+fn would_block(err: &io::Error) -> bool {
+    err.kind() == io::ErrorKind::WouldBlock
+}
+
+impl<S: TlsSession, T: Transport> Transport for TlsTransport<S, T> {
+    fn handshake(&mut self) -> io::Result<Handshake> {
+        loop {
+            match self.write_records() {
+                Ok(()) => {}
+                Err(ref e) if would_block(e) => {
+                    return Ok(Handshake::WantWrite);
+                }
+                Err(e) => return Err(e),
+            }
+            if !self.session.is_handshaking() {
+                return Ok(Handshake::Done);
+            }
+            match self.read_records() {
+                Ok(true) => {}
+                Ok(false) => {
+                    return Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+                        "connection closed during TLS handshake"));
+                }
+                Err(ref e) if would_block(e) => return Ok(Handshake::WantRead),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+    fn wants_write(&self) -> bool {
+        self.session.wants_write()
+    }
+}
+
+impl<S: TlsSession, T: Transport> Read for TlsTransport<S, T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            match self.session.read(buf) {
+                Ok(0) => {}
+                Ok(bytes) => return Ok(bytes),
+                Err(ref e) if would_block(e) => {}
+                Err(e) => return Err(e),
+            }
+            if !try!(self.read_records()) {
+                return Ok(0);
+            }
+        }
+    }
+}
+
+impl<S: TlsSession, T: Transport> Write for TlsTransport<S, T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let bytes = try!(self.session.write(buf));
+        // Records not written now are written by `flush`
+        match self.write_records() {
+            Ok(()) => Ok(bytes),
+            Err(ref e) if would_block(e) => Ok(bytes),
+            Err(e) => Err(e),
+        }
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        try!(self.session.flush());
+        self.write_records()
+    }
+}
+
+impl<S, T: Evented> Evented for TlsTransport<S, T> {
+    fn register(&self, poll: &Poll, token: Token, interest: Ready,
+        opts: PollOpt)
+        -> io::Result<()>
+    {
+        self.sock.register(poll, token, interest, opts)
+    }
+    fn reregister(&self, poll: &Poll, token: Token, interest: Ready,
+        opts: PollOpt)
+        -> io::Result<()>
+    {
+        self.sock.reregister(poll, token, interest, opts)
+    }
+    fn deregister(&self, poll: &Poll) -> io::Result<()> {
+        self.sock.deregister(poll)
+    }
+}
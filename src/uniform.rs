@@ -0,0 +1,278 @@
+use std::mem;
+use std::rc::Rc;
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::error::Error;
+
+use mio::Token;
+use void::Void;
+
+use {Machine, Scope, GenericScope, Response, EventSet, Notifier};
+use {SpawnError, LinkedExit};
+
+
+struct Queue<T> {
+    items: VecDeque<T>,
+    closed: bool,
+}
+
+/// The producing side of the `Uniform` state machine
+///
+/// Created by `Uniform::new`. May be cloned and kept by other state
+/// machines of the same loop (or in the context).
+pub struct SeedQueue<T> {
+    queue: Rc<RefCell<Queue<T>>>,
+    notifier: Notifier,
+}
+
+struct Spawner<T> {
+    queue: Rc<RefCell<Queue<T>>>,
+    /// The rest of the batch after `NoSlabSpace`, spawned first
+    held: Vec<T>,
+    /// The seed which failed with `NoSlabSpace` is queued in the loop
+    blocked: bool,
+}
+
+enum Inner<M: Machine> {
+    Spawner(Spawner<M::Seed>),
+    Child(M),
+}
+
+/// A state machine which spawns children from a queue of seeds
+///
+/// Producers push the seeds into the `SeedQueue` and forget about them.
+/// The children `M` are spawned as the slab capacity allows: when the slab
+/// is full, the rest of the seeds stay in the queue until some state
+/// machine is removed. Seeds which fail to create for other reasons are
+/// logged and dropped.
+///
+/// Unlike `Handoff` it works within a single loop, so the seeds don't
+/// need to be `Send`.
+pub struct Uniform<M: Machine>(Inner<M>);
+
+impl<T> SeedQueue<T> {
+    /// Queue the seed for spawning
+    ///
+    /// Returns the seed back if the `Uniform` state machine is gone.
+    pub fn push(&self, seed: T) -> Result<(), T> {
+        {
+            let mut queue = self.queue.borrow_mut();
+            if queue.closed {
+                return Err(seed);
+            }
+            queue.items.push_back(seed);
+        }
+        // Wakeups are coalesced, and if the queue of notifications is full,
+        // the seed is spawned with the next wakeup
+        self.notifier.wakeup().ok();
+        Ok(())
+    }
+    /// Number of seeds waiting to be spawned
+    pub fn len(&self) -> usize {
+        self.queue.borrow().items.len()
+    }
+    /// Returns true if no seeds are waiting to be spawned
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<T> Clone for SeedQueue<T> {
+    fn clone(&self) -> SeedQueue<T> {
+        SeedQueue {
+            queue: self.queue.clone(),
+            notifier: self.notifier.clone(),
+        }
+    }
+}
+
+impl<T> Drop for Spawner<T> {
+    fn drop(&mut self) {
+        let mut queue = self.queue.borrow_mut();
+        queue.closed = true;
+        queue.items.clear();
+    }
+}
+
+impl<M: Machine> Uniform<M> {
+    /// Create a spawning state machine and a queue for it
+    pub fn new<S: GenericScope>(scope: &mut S)
+        -> (Response<Uniform<M>, Void>, SeedQueue<M::Seed>)
+    {
+        let queue = Rc::new(RefCell::new(Queue {
+            items: VecDeque::new(),
+            closed: false,
+        }));
+        let sender = SeedQueue {
+            queue: queue.clone(),
+            notifier: scope.notifier(),
+        };
+        let me = Spawner { queue: queue, held: Vec::new(), blocked: false };
+        (Response::ok(Uniform(Inner::Spawner(me))), sender)
+    }
+}
+
+fn spawn<M: Machine>(mut me: Spawner<M::Seed>)
+    -> Response<Uniform<M>, M::Seed>
+{
+    if me.blocked {
+        // Resumed in `spawned` when the queued seed is created
+        return Response::ok(Uniform(Inner::Spawner(me)));
+    }
+    let mut seeds = mem::replace(&mut me.held, Vec::new());
+    seeds.extend(me.queue.borrow_mut().items.drain(..));
+    Response::spawn_many(Uniform(Inner::Spawner(me)), seeds)
+}
+
+impl<M: Machine> Machine for Uniform<M> {
+    type Context = M::Context;
+    type Seed = M::Seed;
+
+    fn create(seed: Self::Seed, scope: &mut Scope<M::Context>)
+        -> Response<Self, Self::Seed>
+    {
+        M::create(seed, scope).wrap(|m| Uniform(Inner::Child(m)))
+    }
+    fn ready(self, events: EventSet, scope: &mut Scope<M::Context>)
+        -> Response<Self, Self::Seed>
+    {
+        match self.0 {
+            me @ Inner::Spawner(..) => Response::ok(Uniform(me)),
+            Inner::Child(m) => {
+                m.ready(events, scope).wrap(|m| Uniform(Inner::Child(m)))
+            }
+        }
+    }
+    fn spawned(self, scope: &mut Scope<M::Context>)
+        -> Response<Self, Self::Seed>
+    {
+        match self.0 {
+            // Seeds might be pushed while the batch was created
+            Inner::Spawner(mut me) => {
+                me.blocked = false;
+                spawn(me)
+            }
+            Inner::Child(m) => {
+                m.spawned(scope).wrap(|m| Uniform(Inner::Child(m)))
+            }
+        }
+    }
+    fn spawn_error(self, scope: &mut Scope<M::Context>,
+                   error: SpawnError<Self::Seed>)
+        -> Response<Self, Self::Seed>
+    {
+        match self.0 {
+            Inner::Spawner(mut me) => match error {
+                SpawnError::NoSlabSpace(seed) => {
+                    if me.blocked {
+                        // The rest of the batch waits for `spawned` too
+                        me.held.push(seed);
+                        Response::ok(Uniform(Inner::Spawner(me)))
+                    } else {
+                        // Queued by the loop until some state machine is
+                        // removed, then `spawned` is called
+                        me.blocked = true;
+                        Response::spawn(Uniform(Inner::Spawner(me)), seed)
+                    }
+                }
+                e => {
+                    debug!("Error creating state machine from queue: {}", e);
+                    // The seed queued in the loop might be the one failed,
+                    // then `spawned` is not called
+                    me.blocked = false;
+                    spawn(me)
+                }
+            },
+            Inner::Child(m) => {
+                m.spawn_error(scope, error)
+                    .wrap(|m| Uniform(Inner::Child(m)))
+            }
+        }
+    }
+    fn destroyed(self, scope: &mut Scope<M::Context>) {
+        match self.0 {
+            Inner::Spawner(..) => {}
+            Inner::Child(m) => m.destroyed(scope),
+        }
+    }
+    fn freeze(self, scope: &mut Scope<M::Context>)
+        -> Result<Self::Seed, Self>
+    {
+        match self.0 {
+            me @ Inner::Spawner(..) => Err(Uniform(me)),
+            Inner::Child(m) => {
+                m.freeze(scope).map_err(|m| Uniform(Inner::Child(m)))
+            }
+        }
+    }
+    fn thaw(seed: Self::Seed, scope: &mut Scope<M::Context>)
+        -> Response<Self, Self::Seed>
+    {
+        M::thaw(seed, scope).wrap(|m| Uniform(Inner::Child(m)))
+    }
+    fn timeout(self, scope: &mut Scope<M::Context>)
+        -> Response<Self, Self::Seed>
+    {
+        match self.0 {
+            me @ Inner::Spawner(..) => Response::ok(Uniform(me)),
+            Inner::Child(m) => {
+                m.timeout(scope).wrap(|m| Uniform(Inner::Child(m)))
+            }
+        }
+    }
+    fn wakeup(self, scope: &mut Scope<M::Context>)
+        -> Response<Self, Self::Seed>
+    {
+        match self.0 {
+            Inner::Spawner(me) => spawn(me),
+            Inner::Child(m) => {
+                m.wakeup(scope).wrap(|m| Uniform(Inner::Child(m)))
+            }
+        }
+    }
+    fn exit_signal(self, peer: Token, scope: &mut Scope<M::Context>)
+        -> Response<Self, Self::Seed>
+    {
+        match self.0 {
+            Inner::Spawner(..) => Response::error(Box::new(LinkedExit(peer))),
+            Inner::Child(m) => {
+                m.exit_signal(peer, scope).wrap(|m| Uniform(Inner::Child(m)))
+            }
+        }
+    }
+    fn tick(self, scope: &mut Scope<M::Context>)
+        -> Response<Self, Self::Seed>
+    {
+        match self.0 {
+            me @ Inner::Spawner(..) => Response::ok(Uniform(me)),
+            Inner::Child(m) => {
+                m.tick(scope).wrap(|m| Uniform(Inner::Child(m)))
+            }
+        }
+    }
+    fn config_changed(self, scope: &mut Scope<M::Context>)
+        -> Response<Self, Self::Seed>
+    {
+        match self.0 {
+            me @ Inner::Spawner(..) => Response::ok(Uniform(me)),
+            Inner::Child(m) => {
+                m.config_changed(scope)
+                    .wrap(|m| Uniform(Inner::Child(m)))
+            }
+        }
+    }
+    fn interest(&self) -> Option<EventSet> {
+        match self.0 {
+            Inner::Spawner(..) => None,
+            Inner::Child(ref m) => m.interest(),
+        }
+    }
+    fn error(self, err: Box<Error>, scope: &mut Scope<M::Context>)
+        -> Box<Error>
+    {
+        match self.0 {
+            Inner::Spawner(..) => err,
+            Inner::Child(m) => m.error(err, scope),
+        }
+    }
+}
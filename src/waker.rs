@@ -0,0 +1,97 @@
+use std::io::{self, Read, Write};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::os::unix::net::UnixStream;
+use std::os::unix::io::{AsRawFd, RawFd};
+
+use mio::Token;
+
+
+/// The mio token of the waker socket of a standalone loop
+///
+/// The deprecated mio loop uses the two topmost tokens for its notify
+/// channel and timer. The embedded loop uses a token in its range.
+pub const WAKER_TOKEN: Token = Token(::std::usize::MAX - 3);
+
+/// A handle to wake up the state machine which is safe to use in signal
+/// handlers
+///
+/// Created by `Scope::waker`. Unlike `Notifier` it doesn't allocate or
+/// lock anything: `wake` is an atomic store and a single `write(2)` to the
+/// socket pair owned by the loop, so it may be called from a signal handler or
+/// from an FFI callback in any thread. Wakes are coalesced until the state
+/// machine receives a `wakeup`.
+#[derive(Clone, Debug)]
+pub struct Waker {
+    pending: Arc<AtomicBool>,
+    sock: Arc<UnixStream>,
+}
+
+/// The socket pair and the wakers created in the loop
+#[doc(hidden)]
+pub struct Wakers {
+    reader: UnixStream,
+    writer: Arc<UnixStream>,
+    wakers: Vec<(Token, u64, Arc<AtomicBool>)>,
+}
+
+impl Waker {
+    /// Wake up the state machine
+    ///
+    /// Errors are ignored: a full socket buffer means the loop is woken up
+    /// anyway, and the socket is closed only when the loop is gone.
+    pub fn wake(&self) {
+        if !self.pending.swap(true, Ordering::AcqRel) {
+            let _ = (&*self.sock).write(b"w");
+        }
+    }
+}
+
+impl Wakers {
+    pub fn new() -> io::Result<Wakers> {
+        let (reader, writer) = try!(UnixStream::pair());
+        try!(reader.set_nonblocking(true));
+        try!(writer.set_nonblocking(true));
+        Ok(Wakers {
+            reader: reader,
+            writer: Arc::new(writer),
+            wakers: Vec::new(),
+        })
+    }
+    pub fn fd(&self) -> RawFd {
+        self.reader.as_raw_fd()
+    }
+    pub fn create(&mut self, token: Token, generation: u64) -> Waker {
+        let pending = Arc::new(AtomicBool::new(false));
+        self.wakers.push((token, generation, pending.clone()));
+        Waker {
+            pending: pending,
+            sock: self.writer.clone(),
+        }
+    }
+    /// Drains the socket and returns the state machines to wake up
+    pub fn take(&mut self) -> Vec<(Token, u64)> {
+        let mut buf = [0u8; 64];
+        loop {
+            match self.reader.read(&mut buf) {
+                Ok(0) => break,
+                Ok(_) => continue,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {
+                    continue;
+                }
+                Err(_) => break,
+            }
+        }
+        // Wakers which were all dropped will never fire
+        self.wakers.retain(|&(_, _, ref flag)| {
+            Arc::strong_count(flag) > 1 || flag.load(Ordering::Acquire)
+        });
+        let mut result = Vec::new();
+        for &(token, generation, ref flag) in &self.wakers {
+            if flag.swap(false, Ordering::AcqRel) {
+                result.push((token, generation));
+            }
+        }
+        result
+    }
+}